@@ -0,0 +1,2 @@
+ALTER TABLE envelopes
+ADD COLUMN codec INTEGER DEFAULT 0 NOT NULL;
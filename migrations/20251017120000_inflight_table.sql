@@ -0,0 +1,11 @@
+CREATE TABLE IF NOT EXISTS inflight (
+  token           INTEGER PRIMARY KEY AUTOINCREMENT,
+  deadline        INTEGER, -- milliseconds since epoch
+  received_at     INTEGER, -- milliseconds since epoch
+  own_key         TEXT,
+  sampling_key    TEXT,
+  codec           INTEGER DEFAULT 0 NOT NULL,
+  envelope        BLOB
+);
+
+CREATE INDEX IF NOT EXISTS inflight_deadline ON inflight (deadline);
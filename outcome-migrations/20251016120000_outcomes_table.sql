@@ -0,0 +1,4 @@
+CREATE TABLE IF NOT EXISTS outcomes (
+  id              INTEGER PRIMARY KEY AUTOINCREMENT,
+  outcome         BLOB
+);
@@ -2,7 +2,7 @@ use std::collections::{BTreeMap, HashMap};
 use std::error::Error;
 use std::io::Write;
 use std::net::{IpAddr, SocketAddr, ToSocketAddrs};
-use std::num::NonZeroU8;
+use std::num::{NonZeroU32, NonZeroU8, NonZeroUsize};
 use std::path::{Path, PathBuf};
 use std::str::FromStr;
 use std::time::Duration;
@@ -22,6 +22,7 @@ use uuid::Uuid;
 
 use crate::aggregator::{AggregatorServiceConfig, ScopedAggregatorConfig};
 use crate::byte_size::ByteSize;
+use crate::cors::{CorsPolicy, CorsRouteGroup};
 use crate::upstream::UpstreamDescriptor;
 use crate::{build_redis_configs, RedisConfig, RedisConfigs, RedisConfigsRef};
 
@@ -218,7 +219,7 @@ trait ConfigObject: DeserializeOwned + Serialize {
 
 /// Structure used to hold information about configuration overrides via
 /// CLI parameters or environment variables
-#[derive(Debug, Default)]
+#[derive(Debug, Default, Clone)]
 pub struct OverridableConfig {
     /// The operation mode of this relay.
     pub mode: Option<String>,
@@ -551,6 +552,22 @@ pub struct Metrics {
     ///
     /// Defaults to `true`.
     pub aggregate: bool,
+    /// Whether the envelope buffer metrics are exposed in the Prometheus text format.
+    ///
+    /// If enabled, the number of stacks, envelopes, the storage size and the age of the oldest
+    /// stack of every buffer partition can be scraped at `/api/relay/metrics/`. Scrapers cannot
+    /// sign requests, so unlike other internal endpoints, this endpoint is not restricted to
+    /// internal Relays. Only enable it if the endpoint is not reachable from untrusted networks.
+    ///
+    /// Defaults to `false`, in which case the endpoint responds with `404 Not Found`.
+    pub prometheus_buffer_endpoint: bool,
+    /// Prefix of the `partition_id` tag on metrics of the envelope buffer.
+    ///
+    /// Allows to distinguish the buffers of multiple Relays that report to the same statsd
+    /// server. For example, a prefix of `"edge-"` reports the first partition as `edge-0`.
+    ///
+    /// Defaults to `None`.
+    pub buffer_prefix: Option<String>,
 }
 
 impl Default for Metrics {
@@ -563,6 +580,8 @@ impl Default for Metrics {
             sample_rate: 1.0,
             periodic_secs: 5,
             aggregate: true,
+            prometheus_buffer_endpoint: false,
+            buffer_prefix: None,
         }
     }
 }
@@ -610,6 +629,13 @@ pub struct Limits {
     pub max_envelope_size: ByteSize,
     /// The maximum number of session items per envelope.
     pub max_session_count: usize,
+    /// The maximum number of items in an envelope.
+    ///
+    /// Envelopes with more items are rejected at ingest with `400 Bad Request` before they are
+    /// buffered.
+    ///
+    /// By default there is no limit.
+    pub max_items_per_envelope: Option<usize>,
     /// The maximum payload size for general API requests.
     pub max_api_payload_size: ByteSize,
     /// The maximum payload size for file uploads and chunks.
@@ -676,6 +702,72 @@ pub struct Limits {
     ///
     /// Defaults to `1024`, a value [google has been using for a long time](https://git.kernel.org/pub/scm/linux/kernel/git/torvalds/linux.git/commit/?id=19f92a030ca6d772ab44b22ee6a01378a8cb32d4).
     pub tcp_listen_backlog: u32,
+    /// Hard limit on the number of envelopes accepted per DSN public key over a rolling window.
+    ///
+    /// Envelopes above the limit are rejected at ingest with `429 Too Many Requests` before they
+    /// are buffered. Only keys of enabled projects are limited, and only envelopes that are
+    /// accepted into the buffer count towards the limit. In contrast to rate limits, this limit is
+    /// enforced by every Relay on its own.
+    ///
+    /// By default there is no limit.
+    pub ingest_quota: Option<KeyIngestQuota>,
+    /// Maximum number of seconds added to the `Retry-After` header of rejected requests.
+    ///
+    /// Responses with `429 Too Many Requests` or `503 Service Unavailable` that ask clients to
+    /// retry receive a random delay between zero and this value on top of their `Retry-After`.
+    /// This spreads out the retries of clients that were rejected at the same time.
+    ///
+    /// Defaults to `0`, which does not add jitter.
+    pub retry_after_jitter: u64,
+    /// Limits on the buckets of a single request to the batch metrics endpoint.
+    pub metrics: MetricsLimits,
+}
+
+/// Limits on batched metric buckets sent by internal Relays, see [`Limits::metrics`].
+///
+/// Requests exceeding one of the limits are rejected with `400 Bad Request`. All limits are
+/// disabled by default.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct MetricsLimits {
+    /// Maximum number of buckets across all projects and metric types.
+    pub max_buckets: Option<usize>,
+    /// Maximum number of counter buckets.
+    pub max_counters: Option<usize>,
+    /// Maximum number of distribution buckets.
+    pub max_distributions: Option<usize>,
+    /// Maximum number of set buckets.
+    pub max_sets: Option<usize>,
+    /// Maximum number of gauge buckets.
+    pub max_gauges: Option<usize>,
+}
+
+/// Per-key volume limit enforced at ingest, see [`Limits::ingest_quota`].
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct KeyIngestQuota {
+    /// Maximum number of envelopes accepted per public key within the window.
+    pub max_envelopes: u64,
+    /// Length of the rolling window in seconds.
+    ///
+    /// Defaults to one day.
+    #[serde(default = "ingest_quota_window_secs")]
+    pub window_secs: u64,
+    /// Maximum number of public keys tracked at the same time.
+    ///
+    /// If more keys submit envelopes, the least recently used key is no longer tracked and starts
+    /// with a fresh quota.
+    ///
+    /// Defaults to `10000`.
+    #[serde(default = "ingest_quota_max_keys")]
+    pub max_keys: usize,
+}
+
+fn ingest_quota_window_secs() -> u64 {
+    24 * 60 * 60
+}
+
+fn ingest_quota_max_keys() -> usize {
+    10_000
 }
 
 impl Default for Limits {
@@ -690,6 +782,7 @@ impl Default for Limits {
             max_check_in_size: ByteSize::kibibytes(100),
             max_envelope_size: ByteSize::mebibytes(100),
             max_session_count: 100,
+            max_items_per_envelope: None,
             max_api_payload_size: ByteSize::mebibytes(20),
             max_api_file_upload_size: ByteSize::mebibytes(40),
             max_api_chunk_upload_size: ByteSize::mebibytes(100),
@@ -709,6 +802,9 @@ impl Default for Limits {
             idle_timeout: None,
             max_connections: None,
             tcp_listen_backlog: 1024,
+            ingest_quota: None,
+            retry_after_jitter: 0,
+            metrics: MetricsLimits::default(),
         }
     }
 }
@@ -727,6 +823,117 @@ pub struct Routing {
     /// Defaults to `true` for all Relay modes other than processing mode. In processing mode, this
     /// is disabled by default since the item cannot be handled.
     pub accept_unknown_items: Option<bool>,
+    /// Filters that drop matching envelopes at ingest, before they are buffered.
+    ///
+    /// Dropped envelopes are reported with a `filtered` outcome, using the [`EnvelopeFilter::id`]
+    /// as reason.
+    ///
+    /// Defaults to an empty list.
+    pub envelope_filters: Vec<EnvelopeFilter>,
+}
+
+/// Declarative filter that drops envelopes at ingest.
+///
+/// A filter matches an envelope if all of its configured conditions match. Filters without any
+/// condition never match.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct EnvelopeFilter {
+    /// Identifier of the filter, reported as reason of the `filtered` outcome.
+    pub id: String,
+    /// Matches the release of the envelope's dynamic sampling context.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub release: Option<String>,
+    /// Matches the environment of the envelope's dynamic sampling context.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub environment: Option<String>,
+    /// Matches if any item of the envelope declares this platform in its headers.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub platform: Option<String>,
+}
+
+/// Controls the upstreams of the forward endpoint.
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct Forwarding {
+    /// Upstreams to which the forward endpoint proxies requests, in order of preference.
+    ///
+    /// Requests go to the first healthy upstream. If it cannot be reached or responds with a
+    /// server error, the request is retried with the next upstream. Failed upstreams are skipped
+    /// for [`failover_cooldown`](Self::failover_cooldown) before they are tried again.
+    ///
+    /// Defaults to an empty list, which forwards all requests to `relay.upstream`.
+    pub upstreams: Vec<UpstreamDescriptor<'static>>,
+    /// Time in seconds for which a failed upstream is skipped.
+    ///
+    /// Defaults to `30` seconds.
+    pub failover_cooldown: u64,
+    /// Maximum number of requests that are forwarded concurrently.
+    ///
+    /// Once reached, the forward endpoint responds with `503 Service Unavailable` and a
+    /// `Retry-After` header instead of queueing further requests.
+    ///
+    /// Defaults to `None`, which does not limit concurrent requests.
+    pub max_concurrent: Option<usize>,
+    /// Settings of the HTTP client that sends forwarded requests.
+    pub client: ForwardingClient,
+}
+
+impl Default for Forwarding {
+    fn default() -> Self {
+        Self {
+            upstreams: Vec::new(),
+            failover_cooldown: 30,
+            max_concurrent: None,
+            client: ForwardingClient::default(),
+        }
+    }
+}
+
+/// Controls the connection pool of the HTTP client that sends forwarded requests.
+///
+/// Forwarded requests use a separate connection pool from other requests to the upstream, so that
+/// proxied traffic does not compete with Relay's own requests for idle connections.
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct ForwardingClient {
+    /// Time in seconds after which idle connections to an upstream are closed.
+    ///
+    /// Must be greater than zero. Defaults to `90` seconds.
+    pub pool_idle_timeout: u64,
+    /// Maximum number of idle connections kept open per upstream host.
+    ///
+    /// Set to `0` to open a new connection for every forwarded request. Defaults to `None`, which
+    /// keeps all idle connections open until they time out.
+    pub pool_max_idle_per_host: Option<usize>,
+    /// Interval in seconds between TCP keep-alive probes on connections to upstreams.
+    ///
+    /// Must be greater than zero. Defaults to `None`, which disables TCP keep-alive.
+    pub tcp_keepalive: Option<u64>,
+}
+
+impl ForwardingClient {
+    /// Checks that the settings are within their valid ranges.
+    ///
+    /// Returns the name of the first invalid setting.
+    fn validate(&self) -> Result<(), &'static str> {
+        if self.pool_idle_timeout == 0 {
+            return Err("pool_idle_timeout");
+        }
+        if self.tcp_keepalive == Some(0) {
+            return Err("tcp_keepalive");
+        }
+        Ok(())
+    }
+}
+
+impl Default for ForwardingClient {
+    fn default() -> Self {
+        Self {
+            pool_idle_timeout: 90,
+            pool_max_idle_per_host: None,
+            tcp_keepalive: None,
+        }
+    }
 }
 
 /// Http content encoding for both incoming and outgoing web requests.
@@ -917,6 +1124,151 @@ fn spool_envelopes_partitions() -> NonZeroU8 {
     NonZeroU8::new(1).unwrap()
 }
 
+/// Default time in seconds during which hot projects are drained first after a restart.
+fn spool_envelopes_hot_projects_ttl() -> u64 {
+    10 * 60
+}
+
+fn spool_envelopes_load_concurrency() -> NonZeroUsize {
+    NonZeroUsize::new(1).unwrap()
+}
+
+fn spool_envelopes_pop_retries() -> u32 {
+    3
+}
+
+fn spool_envelopes_pop_retry_backoff_ms() -> u64 {
+    10
+}
+
+fn spool_envelopes_body_size_sample_rate() -> f32 {
+    1.0
+}
+
+fn spool_envelopes_full_block_timeout_ms() -> u64 {
+    500
+}
+
+fn spool_envelopes_default_ready() -> bool {
+    true
+}
+
+fn spool_envelopes_report_initialized() -> bool {
+    true
+}
+
+fn spool_envelopes_max_pop_batch() -> NonZeroUsize {
+    NonZeroUsize::new(100).unwrap()
+}
+
+/// Default for the memory overflow limit, 64 MiB.
+fn spool_envelopes_memory_overflow_limit() -> ByteSize {
+    ByteSize::mebibytes(64)
+}
+
+fn spool_envelopes_flush_yield_interval() -> NonZeroUsize {
+    NonZeroUsize::new(100).unwrap()
+}
+
+/// How envelopes are handled when no partition of the buffer has capacity.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EnvelopeBufferFullPolicy {
+    /// Rejects the request with `503 Service Unavailable`.
+    #[default]
+    Reject,
+    /// Rejects the request with `429 Too Many Requests`, asking clients to back off.
+    #[serde(rename = "reject_429")]
+    Reject429,
+    /// Accepts the request, but drops the envelope and emits an outcome for it.
+    DropWithOutcome,
+    /// Waits for a partition to regain capacity, and rejects the request with
+    /// `503 Service Unavailable` if none does in time.
+    BlockUntilCapacity,
+}
+
+/// How envelopes are handled when the database of the buffer stays locked.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EnvelopeSpoolBusyFallback {
+    /// Fails the write, which drops the envelopes that were about to be spooled.
+    #[default]
+    Reject,
+    /// Keeps the envelopes in memory and retries spooling them on the next push.
+    Memory,
+}
+
+/// Handling of a locked SQLite database in the envelope buffer.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct EnvelopeSpoolSqlite {
+    /// Time in milliseconds SQLite waits for a lock held by another connection before it fails
+    /// with `SQLITE_BUSY`.
+    ///
+    /// Defaults to 5000ms.
+    pub busy_timeout: u64,
+    /// Number of times an operation that failed because the database is locked is retried.
+    ///
+    /// Defaults to 3.
+    pub busy_retries: u32,
+    /// Initial backoff in milliseconds between retries of a locked operation.
+    ///
+    /// The backoff doubles with every retry and is randomized by up to 50% in either direction,
+    /// so that contending operations do not retry in lockstep.
+    ///
+    /// Defaults to 10ms.
+    pub busy_retry_backoff_ms: u64,
+    /// How envelopes are handled when the database is still locked after all retries.
+    ///
+    /// The `memory` fallback is bounded by `spool.envelopes.memory_overflow_limit`.
+    ///
+    /// Defaults to `reject`.
+    pub busy_fallback: EnvelopeSpoolBusyFallback,
+    /// Interval in seconds at which the database is compacted.
+    ///
+    /// New databases are created with incremental auto-vacuum when this is set. Compaction then
+    /// returns free pages to the file system in small steps, so that operations of the buffer only
+    /// wait for a single step. It waits until the database has not been accessed for a second and
+    /// stops early once the buffer accesses the database again.
+    ///
+    /// Defaults to `None`, which disables compaction.
+    pub maintenance_interval: Option<u64>,
+}
+
+impl Default for EnvelopeSpoolSqlite {
+    fn default() -> Self {
+        Self {
+            busy_timeout: 5000,
+            busy_retries: 3,
+            busy_retry_backoff_ms: 10,
+            busy_fallback: EnvelopeSpoolBusyFallback::default(),
+            maintenance_interval: None,
+        }
+    }
+}
+
+/// How Relay handles a disk-based envelope buffer that cannot be opened at startup.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EnvelopeSpoolInitErrorPolicy {
+    /// Fails the startup of Relay.
+    #[default]
+    Fail,
+    /// Logs an error and buffers envelopes of the affected partition in memory instead.
+    FallbackMemory,
+}
+
+/// The encoding used to persist envelopes in the on-disk buffer.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum EnvelopeSpoolCodec {
+    /// The envelope wire format compressed with zstd.
+    #[default]
+    Default,
+    /// Envelope headers encoded as MessagePack followed by the raw items, compressed with zstd.
+    Msgpack,
+}
+
 /// Persistent buffering configuration for incoming envelopes.
 #[derive(Debug, Serialize, Deserialize)]
 pub struct EnvelopeSpool {
@@ -949,6 +1301,18 @@ pub struct EnvelopeSpool {
     /// Defaults to 24h.
     #[serde(default = "spool_envelopes_max_envelope_delay_secs")]
     pub max_envelope_delay_secs: u64,
+    /// Maximum time in seconds between receiving an envelope and processing its items, by item
+    /// type.
+    ///
+    /// Keys are item types as they appear in the envelope item headers, for example
+    /// `transaction` or `event`. When an envelope leaves the buffer, items that are older than
+    /// the maximum age of their type are removed from the envelope with an outcome, while the
+    /// remaining items are processed. Since envelopes are dropped entirely after
+    /// `max_envelope_delay_secs`, larger values have no effect.
+    ///
+    /// Defaults to no per-type maximum age.
+    #[serde(default)]
+    pub item_max_age: BTreeMap<String, u64>,
     /// The refresh frequency in ms of how frequently disk usage is updated by querying SQLite
     /// internal page stats.
     ///
@@ -999,6 +1363,388 @@ pub struct EnvelopeSpool {
     /// Defaults to 1.
     #[serde(default = "spool_envelopes_partitions")]
     pub partitions: NonZeroU8,
+    /// Relative weights of the partitions, indexed by partition id.
+    ///
+    /// Workers that pull envelopes from the buffer serve partitions with a backlog in proportion
+    /// to their weights, so a partition with weight `3` is served three times as often as a
+    /// partition with weight `1`. Partitions without a weight in this list have a weight of `1`.
+    ///
+    /// Defaults to an empty list, which serves all partitions equally.
+    #[serde(default)]
+    pub partition_weights: Vec<NonZeroU32>,
+    /// The codec used to encode envelopes before writing them to disk.
+    ///
+    /// Every row on disk records the codec it was written with, so switching the codec does not
+    /// invalidate envelopes that were spooled before the switch.
+    ///
+    /// Defaults to `default`.
+    #[serde(default)]
+    pub codec: EnvelopeSpoolCodec,
+    /// How Relay handles a disk-based buffer partition that cannot be opened at startup, for
+    /// example because the path is not writable.
+    ///
+    /// With `fallback_memory`, the partition buffers envelopes in memory until the next restart,
+    /// so they are lost if Relay shuts down before they are processed.
+    ///
+    /// Defaults to `fail`.
+    #[serde(default)]
+    pub on_init_error: EnvelopeSpoolInitErrorPolicy,
+    /// Exposes the buffer partition an envelope was routed to in the `X-Relay-Partition` response
+    /// header of the envelope and store endpoints.
+    ///
+    /// This is meant for debugging partition imbalance and should not be enabled on public
+    /// facing Relays.
+    ///
+    /// Defaults to `false`.
+    #[serde(default)]
+    pub debug_partition_header: bool,
+    /// Routes envelopes to the buffer partition requested in the `X-Relay-Partition` request
+    /// header.
+    ///
+    /// This allows gateways in front of Relay to pin envelopes to a partition. Header values that
+    /// are not a valid partition id fall back to the partition derived from the project keys.
+    ///
+    /// Defaults to `false`.
+    #[serde(default)]
+    pub partition_routing_header: bool,
+    /// Maximum number of envelopes in a single stack of the buffer.
+    ///
+    /// A stack holds all envelopes of one project and sampling project combination. When a push
+    /// exceeds this depth, the oldest envelope of the stack is dropped to make room for the new
+    /// one. This bounds the share of the buffer a single project can take up.
+    ///
+    /// Defaults to `None`, which does not limit the depth of stacks.
+    #[serde(default)]
+    pub max_stack_depth: Option<NonZeroUsize>,
+    /// Project keys whose envelopes are drained first after a restart.
+    ///
+    /// Stacks of these projects are prioritized over all other stacks that are loaded from disk
+    /// during the initialization of the buffer, until they are drained or
+    /// [`Self::hot_projects_ttl`] has passed.
+    ///
+    /// Defaults to an empty list.
+    #[serde(default)]
+    pub hot_projects: Vec<String>,
+    /// Time in seconds after the initialization of the buffer during which the stacks of
+    /// [`Self::hot_projects`] are prioritized.
+    ///
+    /// Once this has passed, hot stacks that are not drained yet are ordered like all other
+    /// stacks, so that a hot project that keeps receiving envelopes does not starve the others.
+    ///
+    /// Defaults to `600`.
+    #[serde(default = "spool_envelopes_hot_projects_ttl")]
+    pub hot_projects_ttl: u64,
+    /// Persists the most recently active projects on shutdown.
+    ///
+    /// The project keys are written to a file next to the database and are treated like
+    /// [`Self::hot_projects`] on the next start of Relay. This requires a `path` to be set.
+    ///
+    /// Defaults to `false`.
+    #[serde(default)]
+    pub persist_hot_projects: bool,
+    /// Maximum time in seconds the buffer may go without popping an envelope.
+    ///
+    /// If the next-in-line stack stays not ready for longer than this, the oldest envelope of the
+    /// stack is forwarded for processing without a dynamic sampling decision. This guarantees
+    /// forward progress if a sampling project config never becomes available. Envelopes whose own
+    /// project config is not available are kept in the buffer, and the config is requested again.
+    ///
+    /// Defaults to `None`, which never forces progress.
+    #[serde(default)]
+    pub max_stall: Option<u64>,
+    /// Maximum size of attachments in the buffer before the attachments endpoint is throttled.
+    ///
+    /// Once the attachments buffered across all partitions exceed this size, the attachments
+    /// endpoint responds with `429 Too Many Requests`, while other endpoints keep accepting
+    /// envelopes. This protects the buffer from being dominated by large attachment uploads.
+    /// Attachments that were already on disk at startup are not counted.
+    ///
+    /// Defaults to `None`, which does not limit buffered attachments.
+    #[serde(default)]
+    pub max_attachment_bytes: Option<ByteSize>,
+    /// Maximum size of attachments in a single envelope pushed into the buffer.
+    ///
+    /// Envelopes whose attachments exceed this size are rejected when they are pushed into the
+    /// buffer. Unlike [`Self::max_attachment_bytes`], this applies to each envelope separately.
+    ///
+    /// Defaults to `None`, which does not limit the attachments of an envelope.
+    #[serde(default)]
+    pub max_envelope_attachment_bytes: Option<ByteSize>,
+    /// Prefers stacks whose next envelope is held in memory over stacks that need to read from
+    /// disk.
+    ///
+    /// This only breaks ties between ready stacks with the same priority and reduces the latency of
+    /// popping from the buffer.
+    ///
+    /// Defaults to `false`.
+    #[serde(default)]
+    pub prefer_memory_resident_stacks: bool,
+    /// Project keys whose envelopes are never evicted from the buffer.
+    ///
+    /// Stacks of these projects are skipped by all eviction policies of the buffer. For example,
+    /// a protected stack may grow beyond [`Self::max_stack_depth`].
+    ///
+    /// Defaults to an empty list.
+    #[serde(default)]
+    pub protected_projects: Vec<String>,
+    /// Maximum number of stacks that are loaded concurrently during the initialization of the
+    /// buffer.
+    ///
+    /// Stacks that access the disk still share the connection pool of their partition's store,
+    /// which bounds the effective parallelism of disk reads.
+    ///
+    /// Defaults to 1, which loads stacks sequentially.
+    #[serde(default = "spool_envelopes_load_concurrency")]
+    pub load_concurrency: NonZeroUsize,
+    /// Number of times a failed pop from the buffer is retried before the error is surfaced.
+    ///
+    /// A failed read from disk leaves the envelopes on disk, so retrying a pop does not lose data.
+    ///
+    /// Defaults to 3.
+    #[serde(default = "spool_envelopes_pop_retries")]
+    pub pop_retries: u32,
+    /// Initial backoff in milliseconds between retries of a failed pop.
+    ///
+    /// The backoff doubles with every retry.
+    ///
+    /// Defaults to 10ms.
+    #[serde(default = "spool_envelopes_pop_retry_backoff_ms")]
+    pub pop_retry_backoff_ms: u64,
+    /// Fraction of pushes into the buffer that report the body size of the pushed envelope.
+    ///
+    /// Pushes are sampled randomly, so the reported distribution of body sizes stays
+    /// representative. Lower this to reduce the metrics overhead at high throughput.
+    ///
+    /// Defaults to 1.0, which reports every push.
+    #[serde(default = "spool_envelopes_body_size_sample_rate")]
+    pub body_size_sample_rate: f32,
+    /// How envelopes are handled when no partition of the buffer has capacity.
+    ///
+    /// An envelope is pushed into the partition of its project first. If that partition is full,
+    /// the remaining partitions are tried in ascending order. Only if all partitions are full, this
+    /// policy applies. It is the same for all ingestion endpoints.
+    ///
+    /// Defaults to `reject`.
+    #[serde(default)]
+    pub full_policy: EnvelopeBufferFullPolicy,
+    /// Maximum time in milliseconds to wait for capacity with the `block_until_capacity` policy.
+    ///
+    /// Defaults to 500ms.
+    #[serde(default = "spool_envelopes_full_block_timeout_ms")]
+    pub full_block_timeout_ms: u64,
+    /// Number of reported pop failures after which a stack is quarantined.
+    ///
+    /// A stack whose envelopes repeatedly fail to be forwarded, for example because of a
+    /// malformed envelope, is moved behind all other stacks of the buffer. It is only popped when
+    /// no other stack is buffered, so it cannot block the drain of other projects. Quarantined
+    /// stacks are listed by the `/api/relay/spool/queue/` endpoint for inspection.
+    ///
+    /// Defaults to `None`, which never quarantines stacks.
+    #[serde(default)]
+    pub quarantine_threshold: Option<NonZeroU32>,
+    /// Whether new stacks of the buffer are assumed to be ready.
+    ///
+    /// New stacks are optimistically treated as ready, since most of them are re-created after a
+    /// stack was emptied and their projects are still cached. If most stacks belong to projects
+    /// that Relay has not seen before, this causes premature attempts to process their envelopes.
+    /// When set to `false`, new stacks start out not ready and are only popped once their projects
+    /// have been marked ready.
+    ///
+    /// Defaults to `true`.
+    #[serde(default = "spool_envelopes_default_ready")]
+    pub default_ready: bool,
+    /// Whether the recovered stacks are verified against the store on startup.
+    ///
+    /// After the buffer has loaded its stacks from disk, the number of envelopes reachable
+    /// through the loaded stacks is compared with the number of envelopes in the store. A mismatch
+    /// indicates envelopes that cannot be recovered, for example because of corrupted project
+    /// keys. It is logged and reported in the `buffer.recovery_mismatch` metric.
+    ///
+    /// Defaults to `false`.
+    #[serde(default)]
+    pub verify_on_start: bool,
+    /// Maximum number of unrecoverable envelopes tolerated by the startup verification.
+    ///
+    /// If the verification finds more mismatching envelopes, the envelope buffer fails to start.
+    /// Has no effect unless `verify_on_start` is enabled.
+    ///
+    /// Defaults to `None`, which only reports mismatches.
+    #[serde(default)]
+    pub verify_max_mismatch: Option<u64>,
+    /// Maximum number of envelopes in a single partition of the buffer.
+    ///
+    /// A partition that holds this many envelopes reports that it has no capacity, regardless of
+    /// its disk or memory usage. The count includes envelopes loaded from disk on startup.
+    ///
+    /// Defaults to `None`, which does not limit the number of envelopes.
+    #[serde(default)]
+    pub max_total_count: Option<u64>,
+    /// Maximum number of envelopes popped from the buffer in a single batch.
+    ///
+    /// Requests for larger batches are clamped to this value, so that a single batch cannot drain
+    /// a large stack at once and starve the other stacks.
+    ///
+    /// Defaults to 100.
+    #[serde(default = "spool_envelopes_max_pop_batch")]
+    pub max_pop_batch: NonZeroUsize,
+    /// Whether the buffer reports the `buffer.initialized` metric once it finished loading.
+    ///
+    /// The metric contains the number of loaded stacks and envelopes, as well as the duration of
+    /// the initialization.
+    ///
+    /// Defaults to `true`.
+    #[serde(default = "spool_envelopes_report_initialized")]
+    pub report_initialized: bool,
+    /// Handling of a locked SQLite database.
+    #[serde(default)]
+    pub sqlite: EnvelopeSpoolSqlite,
+    /// Maximum number of bytes that fallbacks of the buffer may keep in memory.
+    ///
+    /// Fallbacks such as the `memory` busy fallback keep envelopes in memory instead of writing
+    /// them to disk. The limit is shared by all fallbacks and partitions. Once it is reached, or
+    /// once Relay exceeds its memory limits, the fallbacks reject envelopes instead.
+    ///
+    /// Defaults to 64 MiB.
+    #[serde(default = "spool_envelopes_memory_overflow_limit")]
+    pub memory_overflow_limit: ByteSize,
+    /// Duration in milliseconds after which an operation of the buffer is logged as slow.
+    ///
+    /// Pushes, pops, peeks and flushes that take longer are logged as a warning with the
+    /// partition, the project key pair and the depth of the stack, in addition to the timer
+    /// metrics that are always reported.
+    ///
+    /// Defaults to `None`, which does not log slow operations.
+    #[serde(default)]
+    pub slow_op_threshold_ms: Option<u64>,
+    /// Number of stacks flushed on shutdown before the buffer yields to other tasks.
+    ///
+    /// Flushing a large buffer can take a long time. Yielding regularly keeps other tasks, such
+    /// as health checks and metrics, responsive during the flush.
+    ///
+    /// Defaults to 100.
+    #[serde(default = "spool_envelopes_flush_yield_interval")]
+    pub flush_yield_interval: NonZeroUsize,
+    /// Whether envelopes are split into a separate stack per processing group.
+    ///
+    /// Items are grouped like for processing, for example errors with their attachments,
+    /// transactions, sessions, and logs. Every group gets its own stack, so groups are scheduled
+    /// and drained independently. The split envelopes count individually towards
+    /// `max_total_count` and `max_stack_depth`.
+    ///
+    /// This is only supported by the memory-based buffer and ignored if `path` is set.
+    ///
+    /// Defaults to `false`.
+    #[serde(default)]
+    pub split_processing_groups: bool,
+    /// Whether the buffer refuses to hand out envelopes before it is initialized.
+    ///
+    /// If enabled, peeking or popping before the initialization has completed fails instead of
+    /// serving envelopes from a partially loaded buffer. Envelopes can still be pushed.
+    ///
+    /// Defaults to `false`.
+    #[serde(default)]
+    pub require_initialization: bool,
+    /// Whether the buffer tests its stacks and store on startup.
+    ///
+    /// Before loading stacks, the buffer pushes a synthetic envelope into a stack, peeks and pops
+    /// it, writes it to the store and reads it back. Relay fails to start if any of these steps
+    /// fails. The synthetic envelope is never forwarded, and it is written in a transaction that
+    /// is rolled back, so that it does not remain in the store.
+    ///
+    /// Defaults to `false`.
+    #[serde(default)]
+    pub self_test: bool,
+    /// Pops ready stacks whose next envelope belongs to an unsampled trace after other ready
+    /// stacks, and evicts these envelopes first once `max_total_count` is reached.
+    ///
+    /// A trace is unsampled if the dynamic sampling context of the envelope has `sampled: false`.
+    /// Such envelopes are likely dropped after processing. Envelopes of sampled traces evict the
+    /// next envelope of an unsampled stack instead of being rejected by a full buffer. The
+    /// disk-based buffer only recognizes unsampled envelopes that have not been written to a batch
+    /// yet.
+    ///
+    /// Defaults to `false`.
+    #[serde(default)]
+    pub deprioritize_unsampled: bool,
+    /// Time in milliseconds after the creation of a stack during which its envelopes are not
+    /// evicted to make room for other envelopes.
+    ///
+    /// This gives the projects of a new stack time to resolve before it is chosen for eviction,
+    /// see `deprioritize_unsampled`, and avoids evicting envelopes that are refilled right away.
+    ///
+    /// Defaults to `0`, which makes new stacks evictable immediately.
+    #[serde(default)]
+    pub eviction_grace_period_ms: u64,
+    /// Whether envelopes of the same trace are popped from the stack that received the trace
+    /// first.
+    ///
+    /// Envelopes whose own project differs from the sampling project of their trace land in a
+    /// different stack than other envelopes of the trace, so they can be popped before envelopes
+    /// of the trace that arrived earlier. If enabled, the buffer tracks the stacks of every trace
+    /// by the trace id of the dynamic sampling context and pops a ready stack that received the
+    /// trace earlier first, as long as an envelope of the trace is on top of it.
+    ///
+    /// The disk-based buffer only links envelopes that have not been written to a batch yet.
+    ///
+    /// Defaults to `false`.
+    #[serde(default)]
+    pub preserve_trace_order: bool,
+    /// Maximum time in seconds that a stack loaded at startup may remain empty.
+    ///
+    /// Stacks are created for every project key pair found on disk at startup. A stack that has
+    /// not received an envelope since and holds no envelopes after this time is removed from the
+    /// buffer.
+    ///
+    /// Defaults to `None`, which keeps empty stacks until they are popped.
+    #[serde(default)]
+    pub empty_init_stack_lifetime_secs: Option<u64>,
+    /// Minimum time in milliseconds before the projects of a stack that is not ready are fetched
+    /// again.
+    ///
+    /// Shorter debounce intervals requested by the buffer are raised to this minimum, which
+    /// prevents tight loops of project fetches.
+    ///
+    /// Defaults to `0`, which applies the requested interval.
+    #[serde(default)]
+    pub min_fetch_debounce_ms: u64,
+    /// Maximum number of new stacks created per second in each partition of the buffer.
+    ///
+    /// Every new project and sampling project combination creates a stack. Envelopes that would
+    /// create a stack beyond this rate are rejected with an outcome, while existing stacks keep
+    /// accepting envelopes. This bounds the growth of the buffer under a flood of distinct
+    /// project keys.
+    ///
+    /// Defaults to `None`, which does not limit the creation of stacks.
+    #[serde(default)]
+    pub max_new_stacks_per_sec: Option<NonZeroU32>,
+    /// Age in seconds of served envelopes above which the buffer logs a warning.
+    ///
+    /// The buffer tracks the oldest envelope popped from each partition and reports it
+    /// periodically. If envelopes are served older than this threshold, stacks are likely starved
+    /// by the scheduling of the buffer.
+    ///
+    /// Defaults to `None`, which does not log warnings.
+    #[serde(default)]
+    pub max_served_age_alert: Option<u64>,
+    /// Number of recently received envelopes remembered to detect retries.
+    ///
+    /// The buffer computes a fingerprint of the event id and item bodies of every received
+    /// envelope with an event id. An envelope with the same fingerprint as one of the last
+    /// received envelopes is considered a retry of that envelope and rejected with a `duplicate`
+    /// outcome. Envelopes without an event id, such as sessions, are never considered retries.
+    ///
+    /// Defaults to `None`, which does not detect retries.
+    #[serde(default)]
+    pub fingerprint_window: Option<NonZeroUsize>,
+    /// Whether the buffer caches the next-in-line stack between changes.
+    ///
+    /// With the cache, repeated peeks and readiness checks of an unchanged buffer do not access
+    /// the priority queue or the stacks. Every push, pop and change of readiness resets the
+    /// cache.
+    ///
+    /// Defaults to `false`.
+    #[serde(default)]
+    pub peek_cache: bool,
 }
 
 impl Default for EnvelopeSpool {
@@ -1008,10 +1754,54 @@ impl Default for EnvelopeSpool {
             max_disk_size: spool_envelopes_max_disk_size(),
             batch_size_bytes: spool_envelopes_batch_size_bytes(),
             max_envelope_delay_secs: spool_envelopes_max_envelope_delay_secs(),
+            item_max_age: BTreeMap::new(),
             disk_usage_refresh_frequency_ms: spool_disk_usage_refresh_frequency_ms(),
             max_backpressure_envelopes: spool_max_backpressure_envelopes(),
             max_backpressure_memory_percent: spool_max_backpressure_memory_percent(),
             partitions: spool_envelopes_partitions(),
+            partition_weights: Vec::new(),
+            codec: EnvelopeSpoolCodec::default(),
+            on_init_error: EnvelopeSpoolInitErrorPolicy::default(),
+            debug_partition_header: false,
+            partition_routing_header: false,
+            max_stack_depth: None,
+            hot_projects: Vec::new(),
+            hot_projects_ttl: spool_envelopes_hot_projects_ttl(),
+            persist_hot_projects: false,
+            max_stall: None,
+            max_attachment_bytes: None,
+            max_envelope_attachment_bytes: None,
+            prefer_memory_resident_stacks: false,
+            protected_projects: Vec::new(),
+            load_concurrency: spool_envelopes_load_concurrency(),
+            pop_retries: spool_envelopes_pop_retries(),
+            pop_retry_backoff_ms: spool_envelopes_pop_retry_backoff_ms(),
+            body_size_sample_rate: spool_envelopes_body_size_sample_rate(),
+            full_policy: EnvelopeBufferFullPolicy::default(),
+            full_block_timeout_ms: spool_envelopes_full_block_timeout_ms(),
+            quarantine_threshold: None,
+            default_ready: spool_envelopes_default_ready(),
+            verify_on_start: false,
+            verify_max_mismatch: None,
+            max_total_count: None,
+            max_pop_batch: spool_envelopes_max_pop_batch(),
+            report_initialized: spool_envelopes_report_initialized(),
+            sqlite: EnvelopeSpoolSqlite::default(),
+            memory_overflow_limit: spool_envelopes_memory_overflow_limit(),
+            slow_op_threshold_ms: None,
+            flush_yield_interval: spool_envelopes_flush_yield_interval(),
+            split_processing_groups: false,
+            require_initialization: false,
+            self_test: false,
+            deprioritize_unsampled: false,
+            eviction_grace_period_ms: 0,
+            preserve_trace_order: false,
+            empty_init_stack_lifetime_secs: None,
+            min_fetch_debounce_ms: 0,
+            max_new_stacks_per_sec: None,
+            max_served_age_alert: None,
+            fingerprint_window: None,
+            peek_cache: false,
         }
     }
 }
@@ -1183,6 +1973,10 @@ impl Default for Processing {
 }
 
 /// Configuration for normalization in this Relay.
+///
+/// Normalization runs in the envelope processor after envelopes have been popped from the
+/// envelope buffer, since it requires the project config. Envelopes are therefore always buffered
+/// unnormalized, and envelopes dropped from the buffer are never normalized.
 #[derive(Debug, Default, Serialize, Deserialize)]
 #[serde(default)]
 pub struct Normalization {
@@ -1322,6 +2116,31 @@ pub struct Outcomes {
     pub source: Option<String>,
     /// Configures the outcome aggregator.
     pub aggregator: OutcomeAggregatorConfig,
+    /// Emits outcomes of envelopes rejected due to a full buffer synchronously.
+    ///
+    /// If enabled, ingest endpoints wait until the outcomes of such envelopes have been sent to the
+    /// upstream, written to the outcome spool, or produced to Kafka before responding, instead of
+    /// sending them through the outcome aggregator in the background.
+    pub synchronous_on_reject: bool,
+    /// The maximum time in milliseconds an ingest endpoint waits for synchronous outcomes.
+    ///
+    /// Synchronous outcomes are sent with the next batch of outcomes, so this should exceed
+    /// `batch_interval`. Once the time has passed, the endpoint responds without waiting further,
+    /// and the outcomes are still sent in the background.
+    pub synchronous_timeout: u64,
+    /// Buffers outcomes on disk if they cannot be sent to the upstream.
+    ///
+    /// Buffered outcomes are sent again once the upstream accepts outcomes. They are stored next
+    /// to the envelope buffer, so this requires `spool.envelopes.path` to be set.
+    pub spool: bool,
+    /// The maximum number of outcomes buffered on disk.
+    ///
+    /// Outcomes that fail to send while the buffer is full are dropped.
+    pub max_spooled: usize,
+    /// The interval in seconds at which outcomes buffered on disk are sent again.
+    ///
+    /// Buffered outcomes are also sent after every successful request to the upstream.
+    pub spool_replay_interval: u64,
 }
 
 impl Default for Outcomes {
@@ -1333,6 +2152,11 @@ impl Default for Outcomes {
             batch_interval: 500,
             source: None,
             aggregator: OutcomeAggregatorConfig::default(),
+            synchronous_on_reject: false,
+            synchronous_timeout: 5_000,
+            spool: false,
+            max_spooled: 100_000,
+            spool_replay_interval: 10,
         }
     }
 }
@@ -1509,6 +2333,70 @@ impl Default for Health {
     }
 }
 
+/// How the envelope buffer handles envelopes whose sampling project does not exist.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum InvalidDscPolicy {
+    /// Buffers the envelope with its dynamic sampling context.
+    #[default]
+    Keep,
+    /// Removes the dynamic sampling context, so the envelope is processed without a dynamic
+    /// sampling decision.
+    Strip,
+    /// Rejects the envelope with an outcome.
+    Reject,
+}
+
+/// Dynamic sampling configuration.
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Sampling {
+    /// How envelopes are handled whose dynamic sampling context references a project that is
+    /// known not to exist or to be disabled.
+    ///
+    /// Such envelopes are buffered in a stack that waits for the sampling project, which never
+    /// provides a sampling decision. The check runs when the envelope is pushed into the buffer,
+    /// so it only applies if the state of the sampling project is already known.
+    ///
+    /// Defaults to `keep`.
+    pub on_invalid_dsc: InvalidDscPolicy,
+}
+
+/// How requests to ingest routes with a missing trailing slash are handled.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TrailingSlash {
+    /// Only routes registered without trailing slash accept requests without it.
+    ///
+    /// All other requests do not match an ingest route and are forwarded to the upstream like
+    /// requests to unknown routes.
+    #[default]
+    Strict,
+    /// Responds with `308 Permanent Redirect` to the path with a trailing slash.
+    ///
+    /// Unlike with `strict`, these requests are no longer forwarded to the upstream.
+    Redirect,
+    /// Handles the request as if it had a trailing slash.
+    ///
+    /// Unlike with `strict`, these requests are no longer forwarded to the upstream.
+    Lenient,
+}
+
+/// HTTP server configuration.
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Server {
+    /// How requests to ingest routes are handled if their path lacks the trailing slash.
+    ///
+    /// Most ingest routes are only registered with a trailing slash, for example
+    /// `/api/{project_id}/envelope/`. By default, requests to these routes without the trailing
+    /// slash are forwarded to the upstream like requests to unknown routes. With `redirect` or
+    /// `lenient`, they are redirected or handled by this Relay instead.
+    ///
+    /// Defaults to `strict`.
+    pub trailing_slash: TrailingSlash,
+}
+
 /// COGS configuration.
 #[derive(Serialize, Deserialize, Debug)]
 #[serde(default)]
@@ -1553,6 +2441,8 @@ struct ConfigValues {
     #[serde(default)]
     routing: Routing,
     #[serde(default)]
+    forwarding: Forwarding,
+    #[serde(default)]
     metrics: Metrics,
     #[serde(default)]
     sentry_metrics: SentryMetrics,
@@ -1578,6 +2468,12 @@ struct ConfigValues {
     health: Health,
     #[serde(default)]
     cogs: Cogs,
+    #[serde(default)]
+    sampling: Sampling,
+    #[serde(default)]
+    server: Server,
+    #[serde(default)]
+    cors: BTreeMap<CorsRouteGroup, CorsPolicy>,
 }
 
 impl ConfigObject for ConfigValues {
@@ -1595,6 +2491,8 @@ pub struct Config {
     values: ConfigValues,
     credentials: Option<Credentials>,
     path: PathBuf,
+    /// Overrides applied with [`Config::apply_override`], which [`Config::reload`] applies again.
+    overrides: Vec<OverridableConfig>,
 }
 
 impl fmt::Debug for Config {
@@ -1621,12 +2519,20 @@ impl Config {
                 None
             },
             path: path.clone(),
+            overrides: Vec::new(),
         };
 
         if cfg!(not(feature = "processing")) && config.processing_enabled() {
             return Err(ConfigError::file(ConfigErrorKind::ProcessingNotAvailable, &path).into());
         }
 
+        if let Err(field) = config.values.forwarding.client.validate() {
+            return Err(
+                anyhow::anyhow!("forwarding.client.{field} must be greater than zero")
+                    .context(ConfigError::file(ConfigErrorKind::InvalidValue, &path)),
+            );
+        }
+
         Ok(config)
     }
 
@@ -1639,15 +2545,29 @@ impl Config {
                 .with_context(|| ConfigError::new(ConfigErrorKind::BadJson))?,
             credentials: None,
             path: PathBuf::new(),
+            overrides: Vec::new(),
         })
     }
 
+    /// Loads the config again from its config folder and applies the same overrides.
+    ///
+    /// Overrides from environment variables or command line arguments that were applied with
+    /// [`Self::apply_override`] take precedence over the reloaded files, like at startup.
+    pub fn reload(&self) -> anyhow::Result<Config> {
+        let mut config = Self::from_path(&self.path)?;
+        for overrides in &self.overrides {
+            config.apply_override(overrides.clone())?;
+        }
+        Ok(config)
+    }
+
     /// Override configuration with values coming from other sources (e.g. env variables or
     /// command line parameters)
     pub fn apply_override(
         &mut self,
         mut overrides: OverridableConfig,
     ) -> anyhow::Result<&mut Self> {
+        let applied = overrides.clone();
         let relay = &mut self.values.relay;
 
         if let Some(mode) = overrides.mode {
@@ -1793,6 +2713,7 @@ impl Config {
             self.values.sentry.server_name = Some(server_name.into());
         }
 
+        self.overrides.push(applied);
         Ok(self)
     }
 
@@ -1893,6 +2814,46 @@ impl Config {
         &self.values.relay.upstream
     }
 
+    /// Returns the upstreams of the forward endpoint in order of preference.
+    ///
+    /// If empty, requests are forwarded to the [`upstream_descriptor`](Self::upstream_descriptor).
+    pub fn forwarding_upstreams(&self) -> &[UpstreamDescriptor<'static>] {
+        &self.values.forwarding.upstreams
+    }
+
+    /// Returns the time for which a failed upstream of the forward endpoint is skipped.
+    pub fn forwarding_failover_cooldown(&self) -> Duration {
+        Duration::from_secs(self.values.forwarding.failover_cooldown)
+    }
+
+    /// Returns the maximum number of concurrently forwarded requests, if limited.
+    pub fn forwarding_max_concurrent(&self) -> Option<usize> {
+        self.values.forwarding.max_concurrent
+    }
+
+    /// Returns the time after which idle connections of forwarded requests are closed.
+    pub fn forwarding_pool_idle_timeout(&self) -> Duration {
+        Duration::from_secs(self.values.forwarding.client.pool_idle_timeout)
+    }
+
+    /// Returns the maximum number of idle connections per host for forwarded requests.
+    pub fn forwarding_pool_max_idle_per_host(&self) -> usize {
+        self.values
+            .forwarding
+            .client
+            .pool_max_idle_per_host
+            .unwrap_or(usize::MAX)
+    }
+
+    /// Returns the TCP keep-alive interval of connections for forwarded requests, if enabled.
+    pub fn forwarding_tcp_keepalive(&self) -> Option<Duration> {
+        self.values
+            .forwarding
+            .client
+            .tcp_keepalive
+            .map(Duration::from_secs)
+    }
+
     /// Returns the custom HTTP "Host" header.
     pub fn http_host_header(&self) -> Option<&str> {
         self.values.http.host_header.as_deref()
@@ -2027,6 +2988,45 @@ impl Config {
         &self.values.outcomes.aggregator
     }
 
+    /// Returns `true` if outcomes of envelopes rejected due to a full buffer are emitted
+    /// synchronously.
+    pub fn outcome_synchronous_on_reject(&self) -> bool {
+        self.values.outcomes.synchronous_on_reject
+    }
+
+    /// Returns the maximum time an ingest endpoint waits for synchronous outcomes.
+    pub fn outcome_synchronous_timeout(&self) -> Duration {
+        Duration::from_millis(self.values.outcomes.synchronous_timeout)
+    }
+
+    /// Returns the path of the database that buffers outcomes which could not be sent upstream.
+    ///
+    /// This is `None` if outcome spooling is disabled or no spool path is configured.
+    pub fn outcome_spool_path(&self) -> Option<PathBuf> {
+        if !self.values.outcomes.spool {
+            return None;
+        }
+
+        let mut path = self.spool_envelopes_path(0)?;
+        let file_name = path.file_name().and_then(|f| f.to_str())?;
+        let new_file_name = format!("{file_name}.outcomes");
+        path.set_file_name(new_file_name);
+
+        Some(path)
+    }
+
+    /// Returns the maximum number of outcomes buffered on disk.
+    pub fn outcome_spool_max_count(&self) -> usize {
+        self.values.outcomes.max_spooled
+    }
+
+    /// Returns the interval at which outcomes buffered on disk are sent again.
+    ///
+    /// The interval is at least one second.
+    pub fn outcome_spool_replay_interval(&self) -> Duration {
+        Duration::from_secs(self.values.outcomes.spool_replay_interval.max(1))
+    }
+
     /// Returns logging configuration.
     pub fn logging(&self) -> &relay_log::LogConfig {
         &self.values.logging
@@ -2037,6 +3037,11 @@ impl Config {
         &self.values.sentry
     }
 
+    /// Returns the CORS policy of the given group of ingestion endpoints, if configured.
+    pub fn cors_policy(&self, group: CorsRouteGroup) -> Option<&CorsPolicy> {
+        self.values.cors.get(&group)
+    }
+
     /// Returns the socket addresses for statsd.
     ///
     /// If stats is disabled an empty vector is returned.
@@ -2078,6 +3083,16 @@ impl Config {
         self.values.metrics.aggregate
     }
 
+    /// Returns `true` if the envelope buffer metrics are exposed in the Prometheus text format.
+    pub fn metrics_prometheus_buffer_endpoint(&self) -> bool {
+        self.values.metrics.prometheus_buffer_endpoint
+    }
+
+    /// Returns the prefix of the partition tag on envelope buffer metrics, if configured.
+    pub fn metrics_buffer_prefix(&self) -> Option<&str> {
+        self.values.metrics.buffer_prefix.as_deref()
+    }
+
     /// Returns the interval for periodic metrics emitted from Relay.
     ///
     /// `None` if periodic metrics are disabled.
@@ -2199,6 +3214,17 @@ impl Config {
         Duration::from_secs(self.values.spool.envelopes.max_envelope_delay_secs)
     }
 
+    /// Returns the maximum age of items of the given type in the buffer, if configured.
+    pub fn spool_envelopes_item_max_age(&self, item_type: &str) -> Option<Duration> {
+        self.values
+            .spool
+            .envelopes
+            .item_max_age
+            .get(item_type)
+            .copied()
+            .map(Duration::from_secs)
+    }
+
     /// Returns the refresh frequency for disk usage monitoring as a [`Duration`] object.
     pub fn spool_disk_usage_refresh_frequency_ms(&self) -> Duration {
         Duration::from_millis(self.values.spool.envelopes.disk_usage_refresh_frequency_ms)
@@ -2219,6 +3245,292 @@ impl Config {
         self.values.spool.envelopes.partitions
     }
 
+    /// Returns the weight of the given buffer partition.
+    pub fn spool_partition_weight(&self, partition_id: u8) -> NonZeroU32 {
+        self.values
+            .spool
+            .envelopes
+            .partition_weights
+            .get(partition_id as usize)
+            .copied()
+            .unwrap_or(NonZeroU32::MIN)
+    }
+
+    /// Returns the codec used to encode envelopes written to the on-disk buffer.
+    pub fn spool_envelopes_codec(&self) -> EnvelopeSpoolCodec {
+        self.values.spool.envelopes.codec
+    }
+
+    /// Returns how a disk-based buffer partition that cannot be opened at startup is handled.
+    pub fn spool_envelopes_on_init_error(&self) -> EnvelopeSpoolInitErrorPolicy {
+        self.values.spool.envelopes.on_init_error
+    }
+
+    /// Returns `true` if the buffer partition of an envelope should be exposed in responses.
+    pub fn spool_envelopes_debug_partition_header(&self) -> bool {
+        self.values.spool.envelopes.debug_partition_header
+    }
+
+    /// Returns `true` if envelopes may request a buffer partition in a request header.
+    pub fn spool_envelopes_partition_routing_header(&self) -> bool {
+        self.values.spool.envelopes.partition_routing_header
+    }
+
+    /// Returns the maximum number of envelopes in a single stack of the buffer, if limited.
+    pub fn spool_envelopes_max_stack_depth(&self) -> Option<NonZeroUsize> {
+        self.values.spool.envelopes.max_stack_depth
+    }
+
+    /// Returns the project keys whose envelopes are drained first after a restart.
+    pub fn spool_envelopes_hot_projects(&self) -> &[String] {
+        &self.values.spool.envelopes.hot_projects
+    }
+
+    /// Returns the time after the initialization of the buffer during which hot projects are
+    /// prioritized.
+    pub fn spool_envelopes_hot_projects_ttl(&self) -> Duration {
+        Duration::from_secs(self.values.spool.envelopes.hot_projects_ttl)
+    }
+
+    /// Returns the project keys whose envelopes are never evicted from the buffer.
+    pub fn spool_envelopes_protected_projects(&self) -> &[String] {
+        &self.values.spool.envelopes.protected_projects
+    }
+
+    /// Returns the path of the file that stores the recently active projects of a partition.
+    ///
+    /// Returns `None` if persisting hot projects is disabled or the buffer is not backed by disk.
+    pub fn spool_envelopes_hot_projects_path(&self, partition_id: u8) -> Option<PathBuf> {
+        if !self.values.spool.envelopes.persist_hot_projects {
+            return None;
+        }
+
+        let mut path = self.spool_envelopes_path(partition_id)?;
+        let file_name = path.file_name().and_then(|f| f.to_str())?;
+        let new_file_name = format!("{file_name}.hot.json");
+        path.set_file_name(new_file_name);
+
+        Some(path)
+    }
+
+    /// Returns `true` if the buffer prefers stacks whose next envelope is held in memory.
+    pub fn spool_envelopes_prefer_memory_resident(&self) -> bool {
+        self.values.spool.envelopes.prefer_memory_resident_stacks
+    }
+
+    /// Returns the maximum number of stacks loaded concurrently during buffer initialization.
+    pub fn spool_envelopes_load_concurrency(&self) -> usize {
+        self.values.spool.envelopes.load_concurrency.get()
+    }
+
+    /// Returns the number of retries of a failed pop from the buffer.
+    pub fn spool_envelopes_pop_retries(&self) -> u32 {
+        self.values.spool.envelopes.pop_retries
+    }
+
+    /// Returns the initial backoff between retries of a failed pop from the buffer.
+    pub fn spool_envelopes_pop_retry_backoff(&self) -> Duration {
+        Duration::from_millis(self.values.spool.envelopes.pop_retry_backoff_ms)
+    }
+
+    /// Returns the fraction of buffer pushes that report the envelope body size.
+    pub fn spool_envelopes_body_size_sample_rate(&self) -> f32 {
+        self.values.spool.envelopes.body_size_sample_rate
+    }
+
+    /// Returns how envelopes are handled when no partition of the buffer has capacity.
+    pub fn spool_envelopes_full_policy(&self) -> EnvelopeBufferFullPolicy {
+        self.values.spool.envelopes.full_policy
+    }
+
+    /// Returns the maximum time to wait for capacity with the `block_until_capacity` policy.
+    pub fn spool_envelopes_full_block_timeout(&self) -> Duration {
+        Duration::from_millis(self.values.spool.envelopes.full_block_timeout_ms)
+    }
+
+    /// Returns the number of pop failures after which a stack is quarantined, if enabled.
+    pub fn spool_envelopes_quarantine_threshold(&self) -> Option<NonZeroU32> {
+        self.values.spool.envelopes.quarantine_threshold
+    }
+
+    /// Returns `true` if new stacks of the buffer are assumed to be ready.
+    pub fn spool_envelopes_default_ready(&self) -> bool {
+        self.values.spool.envelopes.default_ready
+    }
+
+    /// Returns `true` if the recovered stacks of the buffer are verified on startup.
+    pub fn spool_envelopes_verify_on_start(&self) -> bool {
+        self.values.spool.envelopes.verify_on_start
+    }
+
+    /// Returns the maximum number of unrecoverable envelopes tolerated on startup, if limited.
+    pub fn spool_envelopes_verify_max_mismatch(&self) -> Option<u64> {
+        self.values.spool.envelopes.verify_max_mismatch
+    }
+
+    /// Returns the maximum number of envelopes in a single buffer partition, if limited.
+    pub fn spool_envelopes_max_total_count(&self) -> Option<u64> {
+        self.values.spool.envelopes.max_total_count
+    }
+
+    /// Returns the maximum number of envelopes popped from the buffer in a single batch.
+    pub fn spool_envelopes_max_pop_batch(&self) -> usize {
+        self.values.spool.envelopes.max_pop_batch.get()
+    }
+
+    /// Returns `true` if the buffer reports a metric once its initialization is complete.
+    pub fn spool_envelopes_report_initialized(&self) -> bool {
+        self.values.spool.envelopes.report_initialized
+    }
+
+    /// Returns the time SQLite waits for a lock on the buffer database.
+    pub fn spool_envelopes_sqlite_busy_timeout(&self) -> Duration {
+        Duration::from_millis(self.values.spool.envelopes.sqlite.busy_timeout)
+    }
+
+    /// Returns the number of retries of a buffer database operation that failed with a lock.
+    pub fn spool_envelopes_sqlite_busy_retries(&self) -> u32 {
+        self.values.spool.envelopes.sqlite.busy_retries
+    }
+
+    /// Returns the initial backoff between retries of a locked buffer database operation.
+    pub fn spool_envelopes_sqlite_busy_retry_backoff(&self) -> Duration {
+        Duration::from_millis(self.values.spool.envelopes.sqlite.busy_retry_backoff_ms)
+    }
+
+    /// Returns how envelopes are handled when the buffer database stays locked.
+    pub fn spool_envelopes_sqlite_busy_fallback(&self) -> EnvelopeSpoolBusyFallback {
+        self.values.spool.envelopes.sqlite.busy_fallback
+    }
+
+    /// Returns the interval at which the buffer database is compacted, if enabled.
+    pub fn spool_envelopes_sqlite_maintenance_interval(&self) -> Option<Duration> {
+        self.values
+            .spool
+            .envelopes
+            .sqlite
+            .maintenance_interval
+            .map(Duration::from_secs)
+    }
+
+    /// Returns the maximum number of bytes that fallbacks of the buffer may keep in memory.
+    pub fn spool_envelopes_memory_overflow_limit(&self) -> usize {
+        self.values.spool.envelopes.memory_overflow_limit.as_bytes()
+    }
+
+    /// Returns the duration after which a buffer operation is logged as slow, if enabled.
+    pub fn spool_envelopes_slow_op_threshold(&self) -> Option<Duration> {
+        self.values
+            .spool
+            .envelopes
+            .slow_op_threshold_ms
+            .map(Duration::from_millis)
+    }
+
+    /// Returns the number of stacks flushed before the buffer yields to other tasks.
+    pub fn spool_envelopes_flush_yield_interval(&self) -> usize {
+        self.values.spool.envelopes.flush_yield_interval.get()
+    }
+
+    /// Returns `true` if the buffer splits envelopes into a stack per processing group.
+    pub fn spool_envelopes_split_processing_groups(&self) -> bool {
+        self.values.spool.envelopes.split_processing_groups
+    }
+
+    /// Returns `true` if the buffer refuses to peek or pop before it is initialized.
+    pub fn spool_envelopes_require_initialization(&self) -> bool {
+        self.values.spool.envelopes.require_initialization
+    }
+
+    /// Returns `true` if the buffer tests its stacks and store on startup.
+    pub fn spool_envelopes_self_test(&self) -> bool {
+        self.values.spool.envelopes.self_test
+    }
+
+    /// Returns `true` if stacks of unsampled traces are popped after other ready stacks and
+    /// evicted first from a full buffer.
+    pub fn spool_envelopes_deprioritize_unsampled(&self) -> bool {
+        self.values.spool.envelopes.deprioritize_unsampled
+    }
+
+    /// Returns `true` if envelopes of a trace are popped from the stack that received it first.
+    pub fn spool_envelopes_preserve_trace_order(&self) -> bool {
+        self.values.spool.envelopes.preserve_trace_order
+    }
+
+    /// Returns the time after the creation of a stack during which its envelopes are not evicted.
+    pub fn spool_envelopes_eviction_grace_period(&self) -> Duration {
+        Duration::from_millis(self.values.spool.envelopes.eviction_grace_period_ms)
+    }
+
+    /// Returns the minimum time before the projects of a non-ready stack are fetched again.
+    pub fn spool_envelopes_min_fetch_debounce(&self) -> Duration {
+        Duration::from_millis(self.values.spool.envelopes.min_fetch_debounce_ms)
+    }
+
+    /// Returns the maximum number of new stacks created per second in a buffer partition, if
+    /// limited.
+    pub fn spool_envelopes_max_new_stacks_per_sec(&self) -> Option<NonZeroU32> {
+        self.values.spool.envelopes.max_new_stacks_per_sec
+    }
+
+    /// Returns the age of served envelopes above which the buffer logs a warning, if enabled.
+    pub fn spool_envelopes_max_served_age_alert(&self) -> Option<Duration> {
+        self.values
+            .spool
+            .envelopes
+            .max_served_age_alert
+            .map(Duration::from_secs)
+    }
+
+    /// Returns the number of recently pushed envelopes remembered to detect retries, if enabled.
+    pub fn spool_envelopes_fingerprint_window(&self) -> Option<NonZeroUsize> {
+        self.values.spool.envelopes.fingerprint_window
+    }
+
+    /// Returns `true` if the envelope buffer caches the result of peeks between changes.
+    pub fn spool_envelopes_peek_cache(&self) -> bool {
+        self.values.spool.envelopes.peek_cache
+    }
+
+    /// Returns the time after which an empty stack loaded at startup is removed, if enabled.
+    pub fn spool_envelopes_empty_init_stack_lifetime(&self) -> Option<Duration> {
+        self.values
+            .spool
+            .envelopes
+            .empty_init_stack_lifetime_secs
+            .map(Duration::from_secs)
+    }
+
+    /// Returns the maximum size of buffered attachments before the attachments endpoint is
+    /// throttled, if limited.
+    pub fn spool_envelopes_max_attachment_bytes(&self) -> Option<usize> {
+        self.values
+            .spool
+            .envelopes
+            .max_attachment_bytes
+            .map(|size| size.as_bytes())
+    }
+
+    /// Returns the maximum size of attachments in a single envelope pushed into the buffer, if
+    /// limited.
+    pub fn spool_envelopes_max_envelope_attachment_bytes(&self) -> Option<usize> {
+        self.values
+            .spool
+            .envelopes
+            .max_envelope_attachment_bytes
+            .map(|size| size.as_bytes())
+    }
+
+    /// Returns the time after which a stalled buffer forces progress, if enabled.
+    pub fn spool_envelopes_max_stall(&self) -> Option<Duration> {
+        self.values
+            .spool
+            .envelopes
+            .max_stall
+            .map(Duration::from_secs)
+    }
+
     /// Returns the maximum size of an event payload in bytes.
     pub fn max_event_size(&self) -> usize {
         self.values.limits.max_event_size.as_bytes()
@@ -2267,6 +3579,11 @@ impl Config {
         self.values.limits.max_session_count
     }
 
+    /// Returns the maximum number of items in an envelope, if limited.
+    pub fn max_items_per_envelope(&self) -> Option<usize> {
+        self.values.limits.max_items_per_envelope
+    }
+
     /// Returns the maximum payload size of a statsd metric in bytes.
     pub fn max_statsd_size(&self) -> usize {
         self.values.limits.max_statsd_size.as_bytes()
@@ -2334,6 +3651,16 @@ impl Config {
         self.values.limits.max_concurrent_queries
     }
 
+    /// Returns the per-key volume limit enforced at ingest, if configured.
+    pub fn ingest_quota(&self) -> Option<&KeyIngestQuota> {
+        self.values.limits.ingest_quota.as_ref()
+    }
+
+    /// Returns the limits on buckets of a single request to the batch metrics endpoint.
+    pub fn metrics_limits(&self) -> &MetricsLimits {
+        &self.values.limits.metrics
+    }
+
     /// The maximum number of seconds a query is allowed to take across retries.
     pub fn query_timeout(&self) -> Duration {
         Duration::from_secs(self.values.limits.query_timeout)
@@ -2352,6 +3679,11 @@ impl Config {
         Duration::from_secs(self.values.limits.keepalive_timeout)
     }
 
+    /// Returns the maximum jitter added to the `Retry-After` header of rejected requests.
+    pub fn retry_after_jitter(&self) -> Duration {
+        Duration::from_secs(self.values.limits.retry_after_jitter)
+    }
+
     /// Returns the server idle timeout in seconds.
     pub fn idle_timeout(&self) -> Option<Duration> {
         self.values.limits.idle_timeout.map(Duration::from_secs)
@@ -2504,6 +3836,16 @@ impl Config {
         self.values.health.memory_stat_refresh_frequency_ms
     }
 
+    /// Returns how envelopes are handled whose sampling project does not exist.
+    pub fn sampling_on_invalid_dsc(&self) -> InvalidDscPolicy {
+        self.values.sampling.on_invalid_dsc
+    }
+
+    /// Returns how requests to ingest routes without trailing slash are handled.
+    pub fn server_trailing_slash(&self) -> TrailingSlash {
+        self.values.server.trailing_slash
+    }
+
     /// Maximum amount of COGS measurements buffered in memory.
     pub fn cogs_max_queue_size(&self) -> u64 {
         self.values.cogs.max_queue_size
@@ -2544,6 +3886,11 @@ impl Config {
         let forward = self.values.routing.accept_unknown_items;
         forward.unwrap_or_else(|| !self.processing_enabled())
     }
+
+    /// Returns the filters that drop envelopes at ingest.
+    pub fn envelope_filters(&self) -> &[EnvelopeFilter] {
+        &self.values.routing.envelope_filters
+    }
 }
 
 impl Default for Config {
@@ -2552,6 +3899,7 @@ impl Default for Config {
             values: ConfigValues::default(),
             credentials: None,
             path: PathBuf::new(),
+            overrides: Vec::new(),
         }
     }
 }
@@ -2575,6 +3923,32 @@ cache:
         assert_eq!(values.cache.envelope_expiry, 1800);
     }
 
+    #[test]
+    fn test_reload_keeps_overrides() {
+        let path = std::env::temp_dir().join(uuid::Uuid::new_v4().to_string());
+        fs::create_dir_all(&path).unwrap();
+        let write_config = |max_total_count: u64| {
+            let yaml = format!("spool:\n  envelopes:\n    max_total_count: {max_total_count}\n");
+            fs::write(path.join("config.yml"), yaml).unwrap();
+        };
+
+        write_config(10);
+        let mut config = Config::from_path(&path).unwrap();
+        config
+            .apply_override(OverridableConfig {
+                port: Some("3001".to_owned()),
+                ..Default::default()
+            })
+            .unwrap();
+
+        write_config(20);
+        let reloaded = config.reload().unwrap();
+        assert_eq!(reloaded.spool_envelopes_max_total_count(), Some(20));
+        assert_eq!(reloaded.listen_addr().port(), 3001);
+
+        fs::remove_dir_all(&path).unwrap();
+    }
+
     #[test]
     fn test_emit_outcomes() {
         for (serialized, deserialized) in &[
@@ -2592,4 +3966,49 @@ cache:
     fn test_emit_outcomes_invalid() {
         assert!(serde_json::from_str::<EmitOutcomes>("asdf").is_err());
     }
+
+    #[test]
+    fn test_forwarding_client() {
+        let config = Config::from_json_value(serde_json::json!({
+            "forwarding": {
+                "client": {
+                    "pool_idle_timeout": 30,
+                    "pool_max_idle_per_host": 4,
+                    "tcp_keepalive": 60,
+                }
+            }
+        }))
+        .unwrap();
+
+        assert!(config.values.forwarding.client.validate().is_ok());
+        assert_eq!(
+            config.forwarding_pool_idle_timeout(),
+            Duration::from_secs(30)
+        );
+        assert_eq!(config.forwarding_pool_max_idle_per_host(), 4);
+        assert_eq!(
+            config.forwarding_tcp_keepalive(),
+            Some(Duration::from_secs(60))
+        );
+
+        let config = Config::default();
+        assert!(config.values.forwarding.client.validate().is_ok());
+        assert_eq!(config.forwarding_pool_max_idle_per_host(), usize::MAX);
+        assert_eq!(config.forwarding_tcp_keepalive(), None);
+    }
+
+    #[test]
+    fn test_forwarding_client_invalid() {
+        let client = ForwardingClient {
+            pool_idle_timeout: 0,
+            ..Default::default()
+        };
+        assert_eq!(client.validate(), Err("pool_idle_timeout"));
+
+        let client = ForwardingClient {
+            tcp_keepalive: Some(0),
+            ..Default::default()
+        };
+        assert_eq!(client.validate(), Err("tcp_keepalive"));
+    }
 }
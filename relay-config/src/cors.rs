@@ -0,0 +1,165 @@
+use std::fmt;
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+use url::Url;
+
+/// Raised if a string cannot be parsed into a [`CorsOrigin`].
+#[derive(Debug, Eq, Hash, PartialEq, thiserror::Error)]
+pub enum CorsOriginParseError {
+    /// Raised if the origin could not be parsed as URL.
+    #[error("invalid CORS origin: bad URL format")]
+    BadUrl,
+    /// Raised if the origin contains a path, query or fragment.
+    #[error("invalid CORS origin: non origin URL given")]
+    NonOriginUrl,
+    /// Raised if an unknown or unsupported scheme is encountered.
+    #[error("invalid CORS origin: unknown or unsupported URL scheme")]
+    UnknownScheme,
+    /// Raised if no host was provided.
+    #[error("invalid CORS origin: no host")]
+    NoHost,
+}
+
+/// An origin that is allowed to send cross-origin requests to Relay.
+///
+/// Origins are either the wildcard `*` or a URL consisting only of a scheme, host and optional
+/// port, such as `https://example.com`.
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub enum CorsOrigin {
+    /// Allows requests from any origin.
+    Any,
+    /// Allows requests from a single origin, serialized as `scheme://host[:port]`.
+    Exact(String),
+}
+
+impl fmt::Display for CorsOrigin {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Any => write!(f, "*"),
+            Self::Exact(origin) => write!(f, "{origin}"),
+        }
+    }
+}
+
+impl FromStr for CorsOrigin {
+    type Err = CorsOriginParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s == "*" {
+            return Ok(Self::Any);
+        }
+
+        let url = Url::parse(s).map_err(|_| CorsOriginParseError::BadUrl)?;
+        if !matches!(url.scheme(), "http" | "https") {
+            return Err(CorsOriginParseError::UnknownScheme);
+        }
+        if url.host_str().is_none() {
+            return Err(CorsOriginParseError::NoHost);
+        }
+        if url.path() != "/" || url.query().is_some() || url.fragment().is_some() {
+            return Err(CorsOriginParseError::NonOriginUrl);
+        }
+
+        Ok(Self::Exact(url.origin().ascii_serialization()))
+    }
+}
+
+relay_common::impl_str_serde!(CorsOrigin, "a CORS origin URL or `*`");
+
+/// A group of ingestion endpoints that shares a CORS policy.
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CorsRouteGroup {
+    /// The envelope endpoint.
+    Envelope,
+    /// Endpoints for browser reports, including security, CSP and NEL reports.
+    Security,
+    /// All other ingestion endpoints, such as the store, minidump and attachment endpoints.
+    Store,
+}
+
+/// CORS policy of a [`CorsRouteGroup`].
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct CorsPolicy {
+    /// Origins that are allowed to send requests.
+    ///
+    /// Defaults to `["*"]`, which allows any origin.
+    #[serde(default = "default_allowed_origins")]
+    pub allowed_origins: Vec<CorsOrigin>,
+    /// HTTP methods that are allowed in cross-origin requests.
+    ///
+    /// Defaults to `["POST"]`.
+    #[serde(default = "default_allowed_methods")]
+    pub allowed_methods: Vec<String>,
+}
+
+impl Default for CorsPolicy {
+    fn default() -> Self {
+        Self {
+            allowed_origins: default_allowed_origins(),
+            allowed_methods: default_allowed_methods(),
+        }
+    }
+}
+
+fn default_allowed_origins() -> Vec<CorsOrigin> {
+    vec![CorsOrigin::Any]
+}
+
+fn default_allowed_methods() -> Vec<String> {
+    vec!["POST".to_owned()]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_origin() {
+        assert_eq!("*".parse(), Ok(CorsOrigin::Any));
+        assert_eq!(
+            "https://example.com".parse(),
+            Ok(CorsOrigin::Exact("https://example.com".to_owned()))
+        );
+        assert_eq!(
+            "http://example.com:8080/".parse(),
+            Ok(CorsOrigin::Exact("http://example.com:8080".to_owned()))
+        );
+    }
+
+    #[test]
+    fn test_parse_invalid_origin() {
+        assert_eq!(
+            "example.com".parse::<CorsOrigin>(),
+            Err(CorsOriginParseError::BadUrl)
+        );
+        assert_eq!(
+            "ftp://example.com".parse::<CorsOrigin>(),
+            Err(CorsOriginParseError::UnknownScheme)
+        );
+        assert_eq!(
+            "https://example.com/path".parse::<CorsOrigin>(),
+            Err(CorsOriginParseError::NonOriginUrl)
+        );
+    }
+
+    #[test]
+    fn test_deserialize_policy() {
+        let policy: CorsPolicy = serde_json::from_value(serde_json::json!({
+            "allowed_origins": ["https://example.com"]
+        }))
+        .unwrap();
+
+        assert_eq!(
+            policy.allowed_origins,
+            vec![CorsOrigin::Exact("https://example.com".to_owned())]
+        );
+        assert_eq!(policy.allowed_methods, vec!["POST"]);
+
+        let result = serde_json::from_value::<CorsPolicy>(serde_json::json!({
+            "allowed_origins": ["https://example.com/path"]
+        }));
+        assert!(result.is_err());
+    }
+}
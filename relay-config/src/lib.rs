@@ -9,11 +9,13 @@
 pub mod aggregator;
 mod byte_size;
 mod config;
+mod cors;
 mod redis;
 mod upstream;
 
 pub use crate::aggregator::{AggregatorServiceConfig, ScopedAggregatorConfig};
 pub use crate::byte_size::*;
 pub use crate::config::*;
+pub use crate::cors::*;
 pub use crate::redis::*;
 pub use crate::upstream::*;
@@ -12,7 +12,7 @@ use tokio::runtime::Runtime;
 
 use relay_base_schema::project::ProjectKey;
 use relay_server::{
-    Envelope, EnvelopeStack, MemoryChecker, MemoryStat, PolymorphicEnvelopeBuffer,
+    DefaultCodec, Envelope, EnvelopeStack, MemoryChecker, MemoryStat, PolymorphicEnvelopeBuffer,
     SqliteEnvelopeStack, SqliteEnvelopeStore,
 };
 
@@ -104,6 +104,7 @@ fn benchmark_sqlite_envelope_stack(c: &mut Criterion) {
                                 disk_batch_size,
                                 ProjectKey::parse("e12d836b15bb49d7bbf99e64295d995b").unwrap(),
                                 ProjectKey::parse("e12d836b15bb49d7bbf99e64295d995b").unwrap(),
+                                &DefaultCodec,
                                 true,
                             );
 
@@ -141,6 +142,7 @@ fn benchmark_sqlite_envelope_stack(c: &mut Criterion) {
                                     disk_batch_size,
                                     ProjectKey::parse("e12d836b15bb49d7bbf99e64295d995b").unwrap(),
                                     ProjectKey::parse("e12d836b15bb49d7bbf99e64295d995b").unwrap(),
+                                    &DefaultCodec,
                                     true,
                                 );
 
@@ -182,6 +184,7 @@ fn benchmark_sqlite_envelope_stack(c: &mut Criterion) {
                                 disk_batch_size,
                                 ProjectKey::parse("e12d836b15bb49d7bbf99e64295d995b").unwrap(),
                                 ProjectKey::parse("e12d836b15bb49d7bbf99e64295d995b").unwrap(),
+                                &DefaultCodec,
                                 true,
                             );
 
@@ -37,6 +37,12 @@ pub async fn handle(
     Path(path): Path<AttachmentPath>,
     Remote(multipart): Remote<Multipart<'static>>,
 ) -> Result<impl IntoResponse, BadStoreRequest> {
+    if let Some(max_bytes) = state.config().spool_envelopes_max_attachment_bytes() {
+        if state.envelope_buffers().attachment_bytes() >= max_bytes as u64 {
+            return Err(BadStoreRequest::AttachmentBacklog);
+        }
+    }
+
     let envelope = extract_envelope(meta, path, multipart).await?;
     common::handle_envelope(&state, envelope).await?;
     Ok(StatusCode::CREATED)
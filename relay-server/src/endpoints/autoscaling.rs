@@ -59,7 +59,12 @@ fn to_prometheus_string(data: &AutoscalingData) -> String {
     result
 }
 
-fn append_data_row(result: &mut String, label: &str, data: impl Display, tags: &[(&str, &str)]) {
+pub(super) fn append_data_row(
+    result: &mut String,
+    label: &str,
+    data: impl Display,
+    tags: &[(&str, &str)],
+) {
     // Metrics are automatically prefixed with "relay_"
     write!(result, "relay_{label}").unwrap();
     if !tags.is_empty() {
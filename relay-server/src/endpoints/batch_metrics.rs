@@ -1,14 +1,111 @@
+use std::collections::HashMap;
+
 use axum::http::StatusCode;
 use axum::response::IntoResponse;
+use relay_base_schema::project::ProjectKey;
+use relay_config::MetricsLimits;
+use relay_metrics::MetricType;
 use serde::{Deserialize, Serialize};
 
 use crate::extractors::{ReceivedAt, SignedBytes};
 use crate::service::ServiceState;
 use crate::services::processor::{BucketSource, ProcessBatchedMetrics};
+use crate::utils::ApiErrorResponse;
 
 #[derive(Debug, Serialize, Deserialize)]
 struct SendMetricsResponse {}
 
+/// A bucket of a batched metrics request, reduced to the fields required to check limits.
+#[derive(Debug, Deserialize)]
+struct BucketSummary {
+    #[serde(rename = "type")]
+    ty: MetricType,
+}
+
+/// The payload of a batched metrics request, see [`ProcessBatchedMetrics`].
+#[derive(Debug, Deserialize)]
+struct BatchSummary {
+    buckets: HashMap<ProjectKey, Vec<BucketSummary>>,
+}
+
+/// A batch of metrics exceeds one of the configured [`MetricsLimits`].
+#[derive(Debug, thiserror::Error)]
+#[error("batch contains {count} {kind}, exceeding limits.metrics.{option} of {limit}")]
+struct MetricsLimitExceeded {
+    kind: &'static str,
+    option: &'static str,
+    count: usize,
+    limit: usize,
+}
+
+/// Checks the number of buckets in a batched metrics payload against the configured limits.
+///
+/// Payloads that cannot be parsed are not checked, they are rejected during processing.
+fn check_limits(payload: &[u8], limits: &MetricsLimits) -> Result<(), MetricsLimitExceeded> {
+    let MetricsLimits {
+        max_buckets,
+        max_counters,
+        max_distributions,
+        max_sets,
+        max_gauges,
+    } = *limits;
+
+    if [
+        max_buckets,
+        max_counters,
+        max_distributions,
+        max_sets,
+        max_gauges,
+    ]
+    .iter()
+    .all(Option::is_none)
+    {
+        return Ok(());
+    }
+
+    let Ok(summary) = serde_json::from_slice::<BatchSummary>(payload) else {
+        return Ok(());
+    };
+
+    let mut total = 0;
+    let (mut counters, mut distributions, mut sets, mut gauges) = (0, 0, 0, 0);
+    for bucket in summary.buckets.values().flatten() {
+        total += 1;
+        match bucket.ty {
+            MetricType::Counter => counters += 1,
+            MetricType::Distribution => distributions += 1,
+            MetricType::Set => sets += 1,
+            MetricType::Gauge => gauges += 1,
+        }
+    }
+
+    let checks = [
+        ("buckets", "max_buckets", total, max_buckets),
+        ("counter buckets", "max_counters", counters, max_counters),
+        (
+            "distribution buckets",
+            "max_distributions",
+            distributions,
+            max_distributions,
+        ),
+        ("set buckets", "max_sets", sets, max_sets),
+        ("gauge buckets", "max_gauges", gauges, max_gauges),
+    ];
+
+    for (kind, option, count, limit) in checks {
+        if let Some(limit) = limit.filter(|&limit| count > limit) {
+            return Err(MetricsLimitExceeded {
+                kind,
+                option,
+                count,
+                limit,
+            });
+        }
+    }
+
+    Ok(())
+}
+
 pub async fn handle(
     state: ServiceState,
     ReceivedAt(received_at): ReceivedAt,
@@ -18,6 +115,15 @@ pub async fn handle(
         return StatusCode::FORBIDDEN.into_response();
     }
 
+    if let Err(error) = check_limits(&body.body, state.config().metrics_limits()) {
+        relay_log::debug!("rejected batched metrics: {error}");
+        return (
+            StatusCode::BAD_REQUEST,
+            ApiErrorResponse::from_error(&error),
+        )
+            .into_response();
+    }
+
     state.processor().send(ProcessBatchedMetrics {
         payload: body.body,
         source: BucketSource::Internal,
@@ -27,3 +133,111 @@ pub async fn handle(
 
     (StatusCode::ACCEPTED, axum::Json(SendMetricsResponse {})).into_response()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn payload() -> &'static [u8] {
+        br#"{
+            "buckets": {
+                "a94ae32be2584e0bbd7a4cbb95971fee": [
+                    {"timestamp": 1615889440, "width": 10, "name": "c:custom/a@none", "type": "c", "value": 1.0},
+                    {"timestamp": 1615889440, "width": 10, "name": "c:custom/b@none", "type": "c", "value": 1.0},
+                    {"timestamp": 1615889440, "width": 10, "name": "d:custom/c@none", "type": "d", "value": [1.0, 2.0]},
+                    {"timestamp": 1615889440, "width": 10, "name": "s:custom/d@none", "type": "s", "value": [1]}
+                ],
+                "b94ae32be2584e0bbd7a4cbb95971fee": [
+                    {"timestamp": 1615889440, "width": 10, "name": "g:custom/e@none", "type": "g", "value": {"last": 1.0, "min": 1.0, "max": 1.0, "sum": 1.0, "count": 1}},
+                    {"timestamp": 1615889440, "width": 10, "name": "d:custom/f@none", "type": "d", "value": [3.0]}
+                ]
+            }
+        }"#
+    }
+
+    fn check(limits: MetricsLimits) -> Result<(), String> {
+        check_limits(payload(), &limits).map_err(|error| error.to_string())
+    }
+
+    #[test]
+    fn test_within_limits() {
+        let limits = MetricsLimits {
+            max_buckets: Some(6),
+            max_counters: Some(2),
+            max_distributions: Some(2),
+            max_sets: Some(1),
+            max_gauges: Some(1),
+        };
+        assert!(check(limits).is_ok());
+        assert!(check(MetricsLimits::default()).is_ok());
+    }
+
+    #[test]
+    fn test_max_buckets() {
+        let limits = MetricsLimits {
+            max_buckets: Some(5),
+            ..Default::default()
+        };
+        assert_eq!(
+            check(limits).unwrap_err(),
+            "batch contains 6 buckets, exceeding limits.metrics.max_buckets of 5"
+        );
+    }
+
+    #[test]
+    fn test_max_counters() {
+        let limits = MetricsLimits {
+            max_counters: Some(1),
+            ..Default::default()
+        };
+        assert_eq!(
+            check(limits).unwrap_err(),
+            "batch contains 2 counter buckets, exceeding limits.metrics.max_counters of 1"
+        );
+    }
+
+    #[test]
+    fn test_max_distributions() {
+        let limits = MetricsLimits {
+            max_distributions: Some(1),
+            ..Default::default()
+        };
+        assert_eq!(
+            check(limits).unwrap_err(),
+            "batch contains 2 distribution buckets, exceeding limits.metrics.max_distributions of 1"
+        );
+    }
+
+    #[test]
+    fn test_max_sets() {
+        let limits = MetricsLimits {
+            max_sets: Some(0),
+            ..Default::default()
+        };
+        assert_eq!(
+            check(limits).unwrap_err(),
+            "batch contains 1 set buckets, exceeding limits.metrics.max_sets of 0"
+        );
+    }
+
+    #[test]
+    fn test_max_gauges() {
+        let limits = MetricsLimits {
+            max_gauges: Some(0),
+            ..Default::default()
+        };
+        assert_eq!(
+            check(limits).unwrap_err(),
+            "batch contains 1 gauge buckets, exceeding limits.metrics.max_gauges of 0"
+        );
+    }
+
+    #[test]
+    fn test_invalid_payload_not_checked() {
+        let limits = MetricsLimits {
+            max_buckets: Some(0),
+            ..Default::default()
+        };
+        assert!(check_limits(b"{invalid", &limits).is_ok());
+    }
+}
@@ -0,0 +1,66 @@
+//! Returns and resets the envelope counts of the buffer partitions.
+
+use axum::http::StatusCode;
+use axum::response::IntoResponse;
+use serde::Serialize;
+
+use crate::endpoints::common::ServiceUnavailable;
+use crate::extractors::SignedBytes;
+use crate::service::ServiceState;
+use crate::services::buffer::CountDiagnostics;
+
+/// Envelope counts of a single buffer partition.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct PartitionCounts {
+    partition_id: usize,
+    #[serde(flatten)]
+    counts: CountDiagnostics,
+}
+
+/// Response of the buffer counts endpoints.
+#[derive(Debug, Serialize)]
+struct BufferCountsResponse {
+    partitions: Vec<PartitionCounts>,
+}
+
+impl From<Vec<CountDiagnostics>> for BufferCountsResponse {
+    fn from(diagnostics: Vec<CountDiagnostics>) -> Self {
+        let partitions = diagnostics
+            .into_iter()
+            .enumerate()
+            .map(|(partition_id, counts)| PartitionCounts {
+                partition_id,
+                counts,
+            })
+            .collect();
+
+        Self { partitions }
+    }
+}
+
+/// Returns the envelope counts of all buffer partitions.
+pub async fn handle(
+    state: ServiceState,
+    body: SignedBytes,
+) -> Result<impl IntoResponse, ServiceUnavailable> {
+    if !body.relay.internal {
+        return Ok(StatusCode::FORBIDDEN.into_response());
+    }
+
+    let diagnostics = state.envelope_buffers().count_diagnostics().await?;
+    Ok(axum::Json(BufferCountsResponse::from(diagnostics)).into_response())
+}
+
+/// Reloads the total envelope counts of all buffer partitions from their stores.
+pub async fn handle_reset(
+    state: ServiceState,
+    body: SignedBytes,
+) -> Result<impl IntoResponse, ServiceUnavailable> {
+    if !body.relay.internal {
+        return Ok(StatusCode::FORBIDDEN.into_response());
+    }
+
+    let diagnostics = state.envelope_buffers().reset_total_counts().await?;
+    Ok(axum::Json(BufferCountsResponse::from(diagnostics)).into_response())
+}
@@ -0,0 +1,51 @@
+//! Decommissions a partition of the envelope buffer.
+
+use axum::extract::Path;
+use axum::http::StatusCode;
+use axum::response::IntoResponse;
+use serde::Serialize;
+
+use crate::endpoints::common::ServiceUnavailable;
+use crate::extractors::SignedBytes;
+use crate::service::ServiceState;
+use crate::services::buffer::DecommissionError;
+
+/// Response of the decommission endpoint.
+#[derive(Debug, Serialize)]
+struct DecommissionResponse {
+    /// The number of envelopes moved to the remaining partitions.
+    moved: usize,
+}
+
+/// Moves all envelopes of a buffer partition to the remaining partitions.
+///
+/// The partition stops receiving envelopes. Responds once the partition is empty, which can take
+/// a while for large partitions. Decommissioning a partition again is a no-op.
+pub async fn handle(
+    state: ServiceState,
+    Path(partition_id): Path<u8>,
+    body: SignedBytes,
+) -> Result<impl IntoResponse, ServiceUnavailable> {
+    if !body.relay.internal {
+        return Ok(StatusCode::FORBIDDEN.into_response());
+    }
+
+    let moved = match state.envelope_buffers().decommission(partition_id).await {
+        Ok(moved) => moved,
+        Err(error @ DecommissionError::UnknownPartition(_)) => {
+            return Ok((StatusCode::NOT_FOUND, error.to_string()).into_response());
+        }
+        Err(error @ DecommissionError::LastPartition) => {
+            return Ok((StatusCode::CONFLICT, error.to_string()).into_response());
+        }
+        Err(error @ DecommissionError::Drain(_)) => {
+            relay_log::error!(
+                error = &error as &dyn std::error::Error,
+                "failed to decommission buffer partition"
+            );
+            return Err(ServiceUnavailable);
+        }
+    };
+
+    Ok(axum::Json(DecommissionResponse { moved }).into_response())
+}
@@ -0,0 +1,126 @@
+//! Exposes the envelope buffer metrics in the Prometheus text format.
+
+use axum::http::{header, StatusCode};
+use axum::response::{IntoResponse, Response};
+use chrono::{DateTime, Utc};
+
+use crate::endpoints::autoscaling::append_data_row;
+use crate::endpoints::common::ServiceUnavailable;
+use crate::service::ServiceState;
+use crate::services::buffer::BufferStats;
+
+/// Content type of the Prometheus text exposition format.
+const CONTENT_TYPE: &str = "text/plain; version=0.0.4";
+
+/// Returns the metrics of all buffer partitions, if enabled with
+/// `metrics.prometheus_buffer_endpoint`.
+///
+/// The endpoint is not authenticated, so it exposes nothing unless explicitly enabled. Requests
+/// are answered by this Relay in either case and never forwarded to the upstream.
+pub async fn handle(state: ServiceState) -> Result<Response, ServiceUnavailable> {
+    if !state.config().metrics_prometheus_buffer_endpoint() {
+        return Ok(StatusCode::NOT_FOUND.into_response());
+    }
+
+    let stats = state.envelope_buffers().stats().await?;
+    let body = to_prometheus_string(&stats, Utc::now());
+    Ok(([(header::CONTENT_TYPE, CONTENT_TYPE)], body).into_response())
+}
+
+/// Serializes the stats of all partitions, ordered by partition id, into a prometheus string.
+fn to_prometheus_string(stats: &[BufferStats], now: DateTime<Utc>) -> String {
+    let mut result = String::with_capacity(256 * stats.len());
+
+    for (partition_id, stats) in stats.iter().enumerate() {
+        let partition_id = partition_id.to_string();
+        let tags = [("partition_id", partition_id.as_str())];
+
+        append_data_row(&mut result, "buffer_stack_count", stats.stack_count, &tags);
+        append_data_row(
+            &mut result,
+            "buffer_envelope_count",
+            stats.envelope_count,
+            &tags,
+        );
+        if let Some(total_size) = stats.total_size {
+            append_data_row(&mut result, "buffer_total_size", total_size, &tags);
+        }
+        if let Some(oldest_received_at) = stats.oldest_received_at {
+            let age = (now - oldest_received_at).num_milliseconds().max(0) as f64 / 1000.0;
+            append_data_row(&mut result, "buffer_oldest_age_seconds", age, &tags);
+        }
+    }
+
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeDelta;
+
+    use super::*;
+
+    /// Checks a line of a sample against the Prometheus text format.
+    fn is_valid_sample(line: &str) -> bool {
+        let Some((metric, value)) = line.rsplit_once(' ') else {
+            return false;
+        };
+        if value.parse::<f64>().is_err() {
+            return false;
+        }
+
+        let (name, labels) = match metric.split_once('{') {
+            Some((name, labels)) => match labels.strip_suffix('}') {
+                Some(labels) => (name, Some(labels)),
+                None => return false,
+            },
+            None => (metric, None),
+        };
+
+        let is_name = |name: &str| {
+            name.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_' || c == ':')
+                && name
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == ':')
+        };
+
+        is_name(name)
+            && labels.is_none_or(|labels| {
+                labels.split(',').all(|label| {
+                    label.trim().split_once('=').is_some_and(|(key, value)| {
+                        is_name(key)
+                            && value.len() >= 2
+                            && value.starts_with('"')
+                            && value.ends_with('"')
+                    })
+                })
+            })
+    }
+
+    #[test]
+    fn test_prometheus_serialize() {
+        let now = Utc::now();
+        let stats = [
+            BufferStats {
+                stack_count: 3,
+                envelope_count: 10,
+                total_size: Some(4096),
+                oldest_received_at: Some(now - TimeDelta::milliseconds(1500)),
+            },
+            BufferStats::default(),
+        ];
+
+        let result = to_prometheus_string(&stats, now);
+        assert_eq!(
+            result,
+            r#"relay_buffer_stack_count{partition_id="0"} 3
+relay_buffer_envelope_count{partition_id="0"} 10
+relay_buffer_total_size{partition_id="0"} 4096
+relay_buffer_oldest_age_seconds{partition_id="0"} 1.5
+relay_buffer_stack_count{partition_id="1"} 0
+relay_buffer_envelope_count{partition_id="1"} 0
+"#
+        );
+        assert!(result.lines().all(is_valid_sample));
+    }
+}
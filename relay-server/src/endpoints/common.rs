@@ -1,21 +1,26 @@
 //! Common facilities for ingesting events through store-like endpoints.
 
 use axum::http::{header, StatusCode};
-use axum::response::IntoResponse;
-use relay_config::RelayMode;
+use axum::response::{AppendHeaders, IntoResponse};
+use relay_config::{Config, EnvelopeBufferFullPolicy, EnvelopeFilter, RelayMode};
 use relay_event_schema::protocol::{EventId, EventType};
-use relay_quotas::RateLimits;
+use relay_filter::FilterStatKey;
+use relay_quotas::{DataCategories, RateLimit, RateLimitScope, RateLimits, ReasonCode, RetryAfter};
 use relay_statsd::metric;
 use serde::Deserialize;
 
 use crate::envelope::{AttachmentType, Envelope, EnvelopeError, Item, ItemType, Items};
 use crate::service::ServiceState;
-use crate::services::buffer::{EnvelopeBuffer, ProjectKeyPair};
-use crate::services::outcome::{DiscardReason, Outcome};
+use crate::services::buffer::PushError;
+use crate::services::outcome::{DiscardReason, Outcome, TrackOutcomeSync};
 use crate::services::processor::{BucketSource, MetricData, ProcessMetrics, ProcessingGroup};
+use crate::services::projects::project::ProjectState;
 use crate::statsd::{RelayCounters, RelayHistograms};
 use crate::utils::{self, ApiErrorResponse, FormDataIter, ManagedEnvelope};
 
+/// Reason code of envelopes rejected by the per-key ingest quota, see `limits.ingest_quota`.
+const INGEST_QUOTA_REASON: &str = "ingest_quota";
+
 #[derive(Clone, Copy, Debug, thiserror::Error)]
 #[error("the service is overloaded")]
 pub struct ServiceUnavailable;
@@ -69,6 +74,9 @@ pub enum BadStoreRequest {
     #[error("missing prosperodump")]
     MissingProsperodump,
 
+    #[error("invalid unreal crash report: unrecognized format")]
+    InvalidUnrealReport,
+
     #[error("invalid compression container")]
     InvalidCompressionContainer(#[source] std::io::Error),
 
@@ -78,6 +86,15 @@ pub enum BadStoreRequest {
     #[error("failed to queue envelope")]
     QueueFailed,
 
+    #[error("too many attachments are buffered")]
+    AttachmentBacklog,
+
+    #[error("envelope buffer is full")]
+    BufferFull,
+
+    #[error("envelope contains {count} items, exceeding the limit of {limit}")]
+    TooManyItems { count: usize, limit: usize },
+
     #[error(
         "envelope exceeded size limits for type '{0}' (https://develop.sentry.dev/sdk/envelopes/#size-limits)"
     )]
@@ -115,6 +132,16 @@ impl IntoResponse for BadStoreRequest {
 
                 (StatusCode::TOO_MANY_REQUESTS, headers, body).into_response()
             }
+            BadStoreRequest::AttachmentBacklog => {
+                // The buffer holds too many attachments. Other data is still accepted, so only
+                // attachment uploads are asked to back off.
+                (StatusCode::TOO_MANY_REQUESTS, body).into_response()
+            }
+            BadStoreRequest::BufferFull => {
+                // All partitions of the buffer are full and Relay is configured to ask clients to
+                // back off rather than to report an unavailable service.
+                (StatusCode::TOO_MANY_REQUESTS, body).into_response()
+            }
             BadStoreRequest::QueueFailed => {
                 // These errors indicate that something's wrong with our service system, most likely
                 // mailbox congestion or a faulty shutdown. Indicate an unavailable service to the
@@ -260,12 +287,17 @@ pub fn event_id_from_items(items: &Items) -> Result<Option<EventId>, BadStoreReq
 /// - Metrics are directly sent to the [`crate::services::processor::EnvelopeProcessor`], bypassing the manager's queue and
 ///   going straight into metrics aggregation. See [`ProcessMetrics`] for a full description.
 ///
-/// Queueing can fail if the queue exceeds `envelope_buffer_size`. In this case, `Err` is
-/// returned and the envelope is not queued.
-fn queue_envelope(
+/// Queueing can fail if no partition of the envelope buffer has capacity. How this is handled
+/// depends on `spool.envelopes.full_policy`, see [`PartitionedEnvelopeBuffer::push`].
+///
+/// [`PartitionedEnvelopeBuffer::push`]: crate::services::buffer::PartitionedEnvelopeBuffer::push
+///
+/// Returns the buffer partition that received the first queued envelope, if any envelope was
+/// queued.
+async fn queue_envelope(
     state: &ServiceState,
     mut managed_envelope: ManagedEnvelope,
-) -> Result<(), BadStoreRequest> {
+) -> Result<Option<u8>, BadStoreRequest> {
     let envelope = managed_envelope.envelope_mut();
 
     if state.config().relay_mode() != RelayMode::Proxy {
@@ -289,7 +321,7 @@ fn queue_envelope(
     // Split off the envelopes by item type.
     let scoping = managed_envelope.scoping();
     let envelopes = ProcessingGroup::split_envelope(*managed_envelope.take_envelope());
-    for (group, envelope) in envelopes {
+    let mut envelopes = envelopes.into_iter().map(|(group, envelope)| {
         let mut envelope = ManagedEnvelope::new(
             envelope,
             state.outcome_aggregator().clone(),
@@ -297,26 +329,112 @@ fn queue_envelope(
             group,
         );
         envelope.scope(scoping);
+        envelope
+    });
 
-        let project_key_pair = ProjectKeyPair::from_envelope(envelope.envelope());
-        let buffer = state.envelope_buffer(project_key_pair);
-        if !buffer.has_capacity() {
-            return Err(BadStoreRequest::QueueFailed);
-        }
+    let policy = state.config().spool_envelopes_full_policy();
+    let block_timeout = state.config().spool_envelopes_full_block_timeout();
+    let mut queued_partition_id = None;
 
+    while let Some(envelope) = envelopes.next() {
         // NOTE: This assumes that a `prefetch` has already been scheduled for both the
         // envelope's projects. See `handle_check_envelope`.
         relay_log::trace!("Pushing envelope to V2 buffer");
 
-        buffer
-            .addr()
-            .send(EnvelopeBuffer::Push(envelope.into_envelope()));
+        let push = state
+            .envelope_buffers()
+            .push(envelope, policy, block_timeout);
+        match push.await {
+            Ok(partition_id) => {
+                queued_partition_id.get_or_insert(partition_id);
+            }
+            Err(PushError::Dropped) => (),
+            Err(PushError::Full(envelope)) => {
+                if state.config().outcome_synchronous_on_reject() {
+                    reject_synchronously(state, std::iter::once(envelope).chain(envelopes)).await;
+                }
+
+                return Err(match policy {
+                    EnvelopeBufferFullPolicy::Reject429 => BadStoreRequest::BufferFull,
+                    _ => BadStoreRequest::QueueFailed,
+                });
+            }
+        }
     }
     // The entire envelope is taken for a split above, and it's empty at this point, we can just
     // accept it without additional checks.
     managed_envelope.accept();
 
-    Ok(())
+    Ok(queued_partition_id)
+}
+
+/// Rejects envelopes that could not be queued and waits until their outcomes have been handed to
+/// the outcome producer.
+///
+/// Envelopes that are dropped instead emit their outcomes through the outcome aggregator in the
+/// background, which can lose them if Relay stops right after responding. The wait is bounded by
+/// `outcomes.synchronous_timeout`, after which the outcomes are still emitted in the background.
+async fn reject_synchronously(
+    state: &ServiceState,
+    envelopes: impl IntoIterator<Item = ManagedEnvelope>,
+) {
+    let outcomes = envelopes.into_iter().flat_map(|mut envelope| {
+        envelope.reject_and_collect(Outcome::Invalid(DiscardReason::Internal))
+    });
+
+    let acks = outcomes.map(|outcome| state.outcome_producer().send(TrackOutcomeSync(outcome)));
+    let timeout = state.config().outcome_synchronous_timeout();
+    let Ok(results) = tokio::time::timeout(timeout, futures::future::join_all(acks)).await else {
+        relay_log::warn!("timed out waiting for outcomes of rejected envelopes");
+        return;
+    };
+
+    for result in results {
+        if let Err(error) = result {
+            relay_log::error!(
+                error = &error as &dyn std::error::Error,
+                "failed to emit outcome of rejected envelope"
+            );
+        }
+    }
+}
+
+/// Name of the debug header that exposes the buffer partition an envelope was routed to.
+pub const PARTITION_HEADER: &str = "x-relay-partition";
+
+/// Response header exposing the buffer partition of an envelope, see [`partition_header`].
+pub type PartitionHeader = AppendHeaders<Option<(&'static str, String)>>;
+
+/// Reports the buffer partition that received an envelope.
+///
+/// The partition is always recorded as `partition` field of the request's access log entry. It is
+/// only returned as `X-Relay-Partition` response header if `spool.envelopes.debug_partition_header`
+/// is enabled, otherwise the returned header is empty. If no envelope was queued, neither is
+/// reported.
+pub fn partition_header(state: &ServiceState, partition_id: Option<u8>) -> PartitionHeader {
+    let Some(partition_id) = partition_id else {
+        return AppendHeaders(None);
+    };
+    tracing::Span::current().record("partition", partition_id);
+
+    let header = state
+        .config()
+        .spool_envelopes_debug_partition_header()
+        .then(|| (PARTITION_HEADER, partition_id.to_string()));
+
+    AppendHeaders(header)
+}
+
+/// Result of an envelope store request that was handled successfully.
+#[derive(Debug)]
+pub struct HandledEnvelope {
+    /// The id of the event in the envelope, see [`handle_envelope`].
+    pub event_id: Option<EventId>,
+    /// The buffer partition that received the envelope, if it was queued.
+    ///
+    /// Envelopes that are split by processing group may be spread across partitions, in which case
+    /// this is the partition of the first part.
+    pub partition_id: Option<u8>,
 }
 
 /// Handles an envelope store request.
@@ -331,6 +449,17 @@ pub async fn handle_envelope(
     state: &ServiceState,
     envelope: Box<Envelope>,
 ) -> Result<Option<EventId>, BadStoreRequest> {
+    let handled = handle_envelope_queued(state, envelope).await?;
+    Ok(handled.event_id)
+}
+
+/// Handles an envelope store request and reports the buffer partition that received it.
+///
+/// See [`handle_envelope`].
+pub async fn handle_envelope_queued(
+    state: &ServiceState,
+    envelope: Box<Envelope>,
+) -> Result<HandledEnvelope, BadStoreRequest> {
     emit_envelope_metrics(&envelope);
 
     if state.memory_checker().check_memory().is_exceeded() {
@@ -354,7 +483,26 @@ pub async fn handle_envelope(
     let event_id = managed_envelope.envelope().event_id();
     if managed_envelope.envelope().is_empty() {
         managed_envelope.reject(Outcome::Invalid(DiscardReason::EmptyEnvelope));
-        return Ok(event_id);
+        return Ok(HandledEnvelope {
+            event_id,
+            partition_id: None,
+        });
+    }
+
+    if let Err(error) = check_item_count(state.config(), managed_envelope.envelope()) {
+        managed_envelope.reject(Outcome::Invalid(DiscardReason::TooManyItems));
+        return Err(error);
+    }
+
+    if let Some(filter) = matching_envelope_filter(state.config(), managed_envelope.envelope()) {
+        relay_log::trace!("dropping envelope matching envelope filter '{}'", filter.id);
+        managed_envelope.reject(Outcome::Filtered(FilterStatKey::GenericFilter(
+            filter.id.clone(),
+        )));
+        return Ok(HandledEnvelope {
+            event_id,
+            partition_id: None,
+        });
     }
 
     let project_key = managed_envelope.envelope().meta().public_key();
@@ -368,9 +516,8 @@ pub async fn handle_envelope(
         }
     }
 
-    let checked = state
-        .project_cache_handle()
-        .get(project_key)
+    let project = state.project_cache_handle().get(project_key);
+    let checked = project
         .check_envelope(managed_envelope)
         .await
         .map_err(BadStoreRequest::EventRejected)?;
@@ -389,7 +536,29 @@ pub async fn handle_envelope(
         return Err(BadStoreRequest::Overflow(offender));
     }
 
-    queue_envelope(state, managed_envelope)?;
+    // Only keys of enabled projects are subject to the ingest quota. Unknown keys must not occupy
+    // the tracked keys of the limiter. The reservation is released if the envelope is not queued.
+    let quota_key = matches!(project.state(), ProjectState::Enabled(_)).then_some(project_key);
+    let reservation = quota_key.map(|key| state.ingest_quota().reserve(key));
+    if let Some(Err(retry_after)) = reservation {
+        let reason_code = ReasonCode::new(INGEST_QUOTA_REASON);
+        managed_envelope.reject(Outcome::RateLimited(Some(reason_code.clone())));
+
+        let mut rate_limits = RateLimits::new();
+        rate_limits.add(RateLimit {
+            categories: DataCategories::new(),
+            scope: RateLimitScope::Key(project_key),
+            reason_code: Some(reason_code),
+            retry_after: RetryAfter::from_secs(retry_after.as_secs().max(1)),
+            namespaces: Default::default(),
+        });
+        return Err(BadStoreRequest::RateLimited(rate_limits));
+    }
+
+    let partition_id = queue_envelope(state, managed_envelope).await?;
+    if let Some(Ok(reservation)) = reservation {
+        reservation.commit();
+    }
 
     if checked.rate_limits.is_limited() {
         // Even if some envelope items have been queued, there might be active rate limits on
@@ -398,10 +567,54 @@ pub async fn handle_envelope(
         // See `IntoResponse` implementation of `BadStoreRequest`.
         Err(BadStoreRequest::RateLimited(checked.rate_limits))
     } else {
-        Ok(event_id)
+        Ok(HandledEnvelope {
+            event_id,
+            partition_id,
+        })
     }
 }
 
+/// Checks the number of items in the envelope against `limits.max_items_per_envelope`.
+fn check_item_count(config: &Config, envelope: &Envelope) -> Result<(), BadStoreRequest> {
+    match config.max_items_per_envelope() {
+        Some(limit) if envelope.len() > limit => Err(BadStoreRequest::TooManyItems {
+            count: envelope.len(),
+            limit,
+        }),
+        _ => Ok(()),
+    }
+}
+
+/// Returns the first configured envelope filter that matches the envelope.
+///
+/// See `routing.envelope_filters`.
+fn matching_envelope_filter<'a>(
+    config: &'a Config,
+    envelope: &Envelope,
+) -> Option<&'a EnvelopeFilter> {
+    let dsc = envelope.dsc();
+    let matches = |condition: &Option<String>, value: Option<&str>| {
+        condition.as_deref().is_none_or(|c| value == Some(c))
+    };
+
+    config.envelope_filters().iter().find(|filter| {
+        let has_condition =
+            filter.release.is_some() || filter.environment.is_some() || filter.platform.is_some();
+
+        has_condition
+            && matches(&filter.release, dsc.and_then(|d| d.release.as_deref()))
+            && matches(
+                &filter.environment,
+                dsc.and_then(|d| d.environment.as_deref()),
+            )
+            && filter.platform.as_deref().is_none_or(|platform| {
+                envelope
+                    .items()
+                    .any(|item| item.platform() == Some(platform))
+            })
+    })
+}
+
 fn emit_envelope_metrics(envelope: &Envelope) {
     let client_name = envelope.meta().client_name();
     for item in envelope.items() {
@@ -493,4 +706,65 @@ mod tests {
             }
         );
     }
+
+    fn envelope_with_dsc(release: &str) -> Box<Envelope> {
+        let bytes = bytes::Bytes::from(format!(
+            r#"{{"dsn":"https://e12d836b15bb49d7bbf99e64295d995b:@sentry.io/42","trace":{{"trace_id":"89143b0763095bd9c9955e8175d1fb23","public_key":"e12d836b15bb49d7bbf99e64295d995b","release":"{release}","environment":"prod"}}}}"#
+        ));
+        Envelope::parse_bytes(bytes).unwrap()
+    }
+
+    fn filter_config() -> Config {
+        Config::from_json_value(serde_json::json!({
+            "routing": {
+                "envelope_filters": [
+                    {"id": "empty"},
+                    {"id": "blocked-release", "release": "1.0.0", "environment": "prod"}
+                ]
+            }
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn test_envelope_filter_matches() {
+        let config = filter_config();
+        let envelope = envelope_with_dsc("1.0.0");
+
+        let filter = matching_envelope_filter(&config, &envelope).unwrap();
+        assert_eq!(filter.id, "blocked-release");
+    }
+
+    #[test]
+    fn test_item_count_limit() {
+        let config = Config::from_json_value(serde_json::json!({
+            "limits": {
+                "max_items_per_envelope": 2
+            }
+        }))
+        .unwrap();
+
+        let mut envelope = envelope_with_dsc("1.0.0");
+        envelope.add_item(Item::new(ItemType::Event));
+        envelope.add_item(Item::new(ItemType::Attachment));
+        assert!(check_item_count(&config, &envelope).is_ok());
+
+        envelope.add_item(Item::new(ItemType::Attachment));
+        let error = check_item_count(&config, &envelope).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "envelope contains 3 items, exceeding the limit of 2"
+        );
+        assert_eq!(error.into_response().status(), StatusCode::BAD_REQUEST);
+
+        assert!(check_item_count(&Config::default(), &envelope).is_ok());
+    }
+
+    #[test]
+    fn test_envelope_filter_passes_non_matching() {
+        let config = filter_config();
+        let envelope = envelope_with_dsc("2.0.0");
+
+        assert!(matching_envelope_filter(&config, &envelope).is_none());
+    }
 }
@@ -114,8 +114,14 @@ async fn handle(
     params: EnvelopeParams,
 ) -> Result<impl IntoResponse, BadStoreRequest> {
     let envelope = params.extract_envelope()?;
-    let id = common::handle_envelope(&state, envelope).await?;
-    Ok(Json(StoreResponse { id }))
+    let handled = common::handle_envelope_queued(&state, envelope).await?;
+    let partition_header = common::partition_header(&state, handled.partition_id);
+    Ok((
+        partition_header,
+        Json(StoreResponse {
+            id: handled.event_id,
+        }),
+    ))
 }
 
 pub fn route(config: &Config) -> MethodRouter<ServiceState> {
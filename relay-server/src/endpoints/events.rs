@@ -1,19 +1,34 @@
 //! Returns captured events.
 
-use axum::extract::Path;
+use axum::extract::{FromRequest, Path, Query, Request};
 use axum::http::{header, StatusCode};
-use axum::response::IntoResponse;
+use axum::response::{IntoResponse, Response};
 use relay_event_schema::protocol::EventId;
+use serde::Deserialize;
 
 use crate::endpoints::common::ServiceUnavailable;
 use crate::envelope;
+use crate::extractors::SignedBytes;
 use crate::service::ServiceState;
 use crate::services::test_store::GetCapturedEnvelope;
 
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct EventQuery {
+    /// Returns a preview of the envelope if it is still buffered.
+    preview: bool,
+}
+
 pub async fn handle(
     state: ServiceState,
     Path(event_id): Path<EventId>,
-) -> Result<impl IntoResponse, ServiceUnavailable> {
+    Query(query): Query<EventQuery>,
+    request: Request,
+) -> Result<Response, ServiceUnavailable> {
+    if query.preview {
+        return handle_preview(state, event_id, request).await;
+    }
+
     let envelope_opt = state
         .test_store()
         .send(GetCapturedEnvelope { event_id })
@@ -28,3 +43,28 @@ pub async fn handle(
         None => StatusCode::NOT_FOUND.into_response(),
     })
 }
+
+/// Returns a preview of the buffered envelope with the given event id.
+///
+/// The preview only contains item types, sizes, and timestamps, but no payloads. Only internal
+/// Relays may request previews.
+async fn handle_preview(
+    state: ServiceState,
+    event_id: EventId,
+    request: Request,
+) -> Result<Response, ServiceUnavailable> {
+    let body = match SignedBytes::from_request(request, &state).await {
+        Ok(body) => body,
+        Err(rejection) => return Ok(rejection.into_response()),
+    };
+    if !body.relay.internal {
+        return Ok(StatusCode::FORBIDDEN.into_response());
+    }
+
+    Ok(
+        match state.envelope_buffers().event_preview(event_id).await? {
+            Some(preview) => axum::Json(preview).into_response(),
+            None => StatusCode::NOT_FOUND.into_response(),
+        },
+    )
+}
@@ -4,10 +4,14 @@
 //! (`X-Forwarded-For` and `Sentry-Relay-Id`). The response is then streamed back to the origin.
 
 use std::borrow::Cow;
+use std::collections::HashMap;
 use std::error::Error;
 use std::fmt;
 use std::future::Future;
 use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Mutex, PoisonError};
+use std::time::{Duration, Instant};
 
 use axum::extract::{DefaultBodyLimit, Request};
 use axum::handler::Handler;
@@ -16,7 +20,7 @@ use axum::response::{IntoResponse, Response};
 use bytes::Bytes;
 use once_cell::sync::Lazy;
 use relay_common::glob2::GlobMatcher;
-use relay_config::Config;
+use relay_config::{Config, UpstreamDescriptor};
 use tokio::sync::oneshot;
 use tokio::sync::oneshot::error::RecvError;
 
@@ -24,6 +28,7 @@ use crate::extractors::ForwardedFor;
 use crate::http::{HttpError, RequestBuilder, Response as UpstreamResponse};
 use crate::service::ServiceState;
 use crate::services::upstream::{Method, SendRequest, UpstreamRequest, UpstreamRequestError};
+use crate::statsd::{RelayCounters, RelayGauges};
 
 /// Headers that this endpoint must handle and cannot forward.
 static HOP_BY_HOP_HEADERS: &[HeaderName] = &[
@@ -46,6 +51,9 @@ static IGNORED_REQUEST_HEADERS: &[HeaderName] = &[
 /// Root path of all API endpoints.
 const API_PATH: &str = "/api/";
 
+/// `Retry-After` value in seconds for requests rejected by `forwarding.max_concurrent`.
+const SATURATED_RETRY_AFTER: &str = "1";
+
 /// A wrapper struct that allows conversion of UpstreamRequestError into a `dyn ResponseError`. The
 /// conversion logic is really only acceptable for blindly forwarded requests.
 #[derive(Debug, thiserror::Error)]
@@ -91,9 +99,145 @@ impl IntoResponse for ForwardError {
 
 type ForwardResponse = (StatusCode, HeaderMap<HeaderValue>, Vec<u8>);
 
+/// Tracks which upstreams of the forward endpoint failed recently.
+#[derive(Debug, Default)]
+struct UpstreamHealth {
+    /// Upstreams that failed, along with the time until which they are skipped.
+    failed: Mutex<HashMap<UpstreamDescriptor<'static>, Instant>>,
+}
+
+impl UpstreamHealth {
+    /// Returns the upstreams in the order in which they should be tried.
+    ///
+    /// Healthy upstreams keep their configured order. Upstreams that failed recently are moved to
+    /// the end, so they are only tried after all healthy upstreams failed.
+    fn order<'a>(
+        &self,
+        upstreams: &'a [UpstreamDescriptor<'static>],
+    ) -> Vec<&'a UpstreamDescriptor<'static>> {
+        let now = Instant::now();
+        let failed = self.failed.lock().unwrap_or_else(PoisonError::into_inner);
+
+        let (healthy, unhealthy): (Vec<_>, Vec<_>) = upstreams
+            .iter()
+            .partition(|upstream| failed.get(*upstream).is_none_or(|until| *until <= now));
+
+        healthy.into_iter().chain(unhealthy).collect()
+    }
+
+    /// Records the result of a request to an upstream.
+    ///
+    /// Returns `false` if the upstream failed, in which case it is skipped for `cooldown`.
+    fn report(
+        &self,
+        upstream: &UpstreamDescriptor<'static>,
+        result: &Result<ForwardResponse, UpstreamRequestError>,
+        cooldown: Duration,
+    ) -> bool {
+        let success = match result {
+            Ok((status, _, _)) => !status.is_server_error(),
+            Err(error) => !matches!(error, UpstreamRequestError::SendFailed(_)),
+        };
+
+        relay_statsd::metric!(
+            counter(RelayCounters::ForwardUpstreamRequest) += 1,
+            upstream = &upstream.to_string(),
+            result = if success { "success" } else { "failure" }
+        );
+
+        let mut failed = self.failed.lock().unwrap_or_else(PoisonError::into_inner);
+        if success {
+            failed.remove(upstream);
+        } else {
+            failed.insert(upstream.clone(), Instant::now() + cooldown);
+        }
+
+        success
+    }
+}
+
+/// Health of the upstreams configured in `forwarding.upstreams`.
+static UPSTREAM_HEALTH: Lazy<UpstreamHealth> = Lazy::new(UpstreamHealth::default);
+
+/// Tracks the number of requests that are currently forwarded.
+#[derive(Debug, Default)]
+struct InFlight {
+    count: AtomicUsize,
+}
+
+impl InFlight {
+    /// Reserves a slot for a forwarded request if fewer than `max` requests are in flight.
+    ///
+    /// The slot is released when the returned guard is dropped.
+    fn try_acquire(&self, max: Option<usize>) -> Option<InFlightGuard<'_>> {
+        let previous = self
+            .count
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |count| {
+                max.is_none_or(|max| count < max).then_some(count + 1)
+            })
+            .ok()?;
+
+        relay_statsd::metric!(gauge(RelayGauges::ForwardInFlight) = (previous + 1) as u64);
+        Some(InFlightGuard(self))
+    }
+}
+
+/// Releases a slot of [`InFlight`] when dropped.
+struct InFlightGuard<'a>(&'a InFlight);
+
+impl Drop for InFlightGuard<'_> {
+    fn drop(&mut self) {
+        let previous = self.0.count.fetch_sub(1, Ordering::AcqRel);
+        relay_statsd::metric!(gauge(RelayGauges::ForwardInFlight) = (previous - 1) as u64);
+    }
+}
+
+/// Requests currently in flight, limited by `forwarding.max_concurrent`.
+static IN_FLIGHT: InFlight = InFlight {
+    count: AtomicUsize::new(0),
+};
+
+/// Sends a request to the first upstream that responds without a server error.
+///
+/// `send` is called with each upstream in the order given by [`UpstreamHealth::order`] until one
+/// succeeds. The response of the last upstream is returned, even if it failed. Without upstreams,
+/// the request is sent once to the default upstream of this Relay.
+async fn forward_with_failover<F, Fut>(
+    health: &UpstreamHealth,
+    upstreams: &[UpstreamDescriptor<'static>],
+    cooldown: Duration,
+    mut send: F,
+) -> Result<ForwardResponse, ForwardError>
+where
+    F: FnMut(Option<&UpstreamDescriptor<'static>>) -> Fut,
+    Fut: Future<Output = Result<Result<ForwardResponse, UpstreamRequestError>, RecvError>>,
+{
+    let upstreams = health.order(upstreams);
+    let Some((last, preferred)) = upstreams.split_last() else {
+        return Ok(send(None).await??);
+    };
+
+    for upstream in preferred {
+        let result = send(Some(upstream)).await?;
+        if health.report(upstream, &result, cooldown) {
+            return Ok(result?);
+        }
+
+        relay_log::debug!(
+            upstream = %upstream,
+            "failed to forward request, trying next upstream"
+        );
+    }
+
+    let result = send(Some(last)).await?;
+    health.report(last, &result, cooldown);
+    Ok(result?)
+}
+
 struct ForwardRequest {
     method: Method,
     path: String,
+    upstream: Option<UpstreamDescriptor<'static>>,
     headers: HeaderMap<HeaderValue>,
     forwarded_for: ForwardedFor,
     data: Bytes,
@@ -119,6 +263,10 @@ impl UpstreamRequest for ForwardRequest {
         self.path.as_str().into()
     }
 
+    fn upstream(&self) -> Option<&UpstreamDescriptor<'static>> {
+        self.upstream.as_ref()
+    }
+
     fn retry(&self) -> bool {
         false
     }
@@ -131,6 +279,10 @@ impl UpstreamRequest for ForwardRequest {
         false
     }
 
+    fn forwarded(&self) -> bool {
+        true
+    }
+
     fn route(&self) -> &'static str {
         "forward"
     }
@@ -195,20 +347,40 @@ async fn handle(
         return Ok(StatusCode::NOT_FOUND.into_response());
     }
 
-    let (tx, rx) = oneshot::channel();
+    let Some(_in_flight) = IN_FLIGHT.try_acquire(state.config().forwarding_max_concurrent()) else {
+        relay_log::debug!("rejecting forward request, too many requests in flight");
+        let headers = [(header::RETRY_AFTER, SATURATED_RETRY_AFTER)];
+        return Ok((StatusCode::SERVICE_UNAVAILABLE, headers).into_response());
+    };
 
-    let request = ForwardRequest {
-        method,
-        path: uri.to_string(),
-        headers,
-        forwarded_for,
-        data,
-        max_response_size: state.config().max_api_payload_size(),
-        sender: tx,
+    let path = uri.to_string();
+    let max_response_size = state.config().max_api_payload_size();
+
+    let send = |upstream: Option<&UpstreamDescriptor<'static>>| {
+        let (tx, rx) = oneshot::channel();
+
+        let request = ForwardRequest {
+            method: method.clone(),
+            path: path.clone(),
+            upstream: upstream.cloned(),
+            headers: headers.clone(),
+            forwarded_for: forwarded_for.clone(),
+            data: data.clone(),
+            max_response_size,
+            sender: tx,
+        };
+
+        state.upstream_relay().send(SendRequest(request));
+        rx
     };
 
-    state.upstream_relay().send(SendRequest(request));
-    let (status, headers, body) = rx.await??;
+    let (status, headers, body) = forward_with_failover(
+        &UPSTREAM_HEALTH,
+        state.config().forwarding_upstreams(),
+        state.config().forwarding_failover_cooldown(),
+        send,
+    )
+    .await?;
 
     Ok(if headers.contains_key(header::CONTENT_TYPE) {
         (status, headers, body).into_response()
@@ -269,3 +441,95 @@ pub fn forward(state: ServiceState, req: Request) -> impl Future<Output = Respon
     let limit = get_limit_for_path(req.uri().path(), state.config());
     handle.layer(DefaultBodyLimit::max(limit)).call(req, state)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn upstream(url: &str) -> UpstreamDescriptor<'static> {
+        url.parse().unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_failover_to_secondary() {
+        let health = UpstreamHealth::default();
+        let primary = upstream("http://primary.invalid/");
+        let secondary = upstream("http://secondary.invalid/");
+        let upstreams = [primary.clone(), secondary.clone()];
+
+        let mut sent = Vec::new();
+        let (status, _, _) =
+            forward_with_failover(&health, &upstreams, Duration::from_secs(30), |upstream| {
+                let upstream = upstream.cloned().unwrap();
+                let status = if upstream == primary {
+                    StatusCode::SERVICE_UNAVAILABLE
+                } else {
+                    StatusCode::OK
+                };
+                sent.push(upstream);
+                async move { Ok(Ok((status, HeaderMap::new(), Vec::new()))) }
+            })
+            .await
+            .unwrap();
+
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(sent, [primary.clone(), secondary.clone()]);
+
+        // The primary is skipped until its cooldown expires.
+        assert_eq!(health.order(&upstreams), [&secondary, &primary]);
+    }
+
+    #[tokio::test]
+    async fn test_failover_returns_last_failure() {
+        let health = UpstreamHealth::default();
+        let upstreams = [
+            upstream("http://primary.invalid/"),
+            upstream("http://secondary.invalid/"),
+        ];
+
+        let mut attempts = 0;
+        let (status, _, _) =
+            forward_with_failover(&health, &upstreams, Duration::from_secs(30), |_| {
+                attempts += 1;
+                async { Ok(Ok((StatusCode::BAD_GATEWAY, HeaderMap::new(), Vec::new()))) }
+            })
+            .await
+            .unwrap();
+
+        assert_eq!(status, StatusCode::BAD_GATEWAY);
+        assert_eq!(attempts, 2);
+    }
+
+    #[test]
+    fn test_in_flight_limit() {
+        let in_flight = InFlight::default();
+
+        let first = in_flight.try_acquire(Some(2)).unwrap();
+        let _second = in_flight.try_acquire(Some(2)).unwrap();
+        assert!(in_flight.try_acquire(Some(2)).is_none());
+
+        // Releasing a slot admits the next request.
+        drop(first);
+        let _third = in_flight.try_acquire(Some(2)).unwrap();
+        assert!(in_flight.try_acquire(Some(2)).is_none());
+
+        // Without a limit, requests are always admitted.
+        assert!(in_flight.try_acquire(None).is_some());
+    }
+
+    #[tokio::test]
+    async fn test_no_upstreams() {
+        let health = UpstreamHealth::default();
+
+        let mut sent = Vec::new();
+        let (status, _, _) = forward_with_failover(&health, &[], Duration::ZERO, |upstream| {
+            sent.push(upstream.cloned());
+            async { Ok(Ok((StatusCode::OK, HeaderMap::new(), Vec::new()))) }
+        })
+        .await
+        .unwrap();
+
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(sent, [None]);
+    }
+}
@@ -0,0 +1,227 @@
+//! Endpoint for batches of structured log records.
+//!
+//! The endpoint accepts a JSON array of log records. Valid records are combined into a single log
+//! container item, while invalid records are rejected individually and reported in the response.
+
+use axum::extract::{DefaultBodyLimit, FromRequest};
+use axum::http::StatusCode;
+use axum::response::IntoResponse;
+use axum::routing::{post, MethodRouter};
+use bytes::Bytes;
+use relay_config::Config;
+use relay_event_schema::protocol::OurLog;
+use relay_protocol::Annotated;
+use serde::Serialize;
+use serde_json::value::RawValue;
+
+use crate::endpoints::common::{self, BadStoreRequest};
+use crate::envelope::{
+    ContainerItems, ContainerWriteError, Envelope, Item, ItemContainer, ItemType,
+};
+use crate::extractors::RequestMeta;
+use crate::service::ServiceState;
+
+#[derive(Debug, FromRequest)]
+#[from_request(state(ServiceState))]
+struct LogsParams {
+    meta: RequestMeta,
+    body: Bytes,
+}
+
+/// A log record that was not accepted.
+#[derive(Debug, PartialEq, Serialize)]
+struct RejectedLog {
+    /// Position of the record in the submitted array.
+    index: usize,
+    /// Why the record was rejected.
+    reason: &'static str,
+}
+
+/// Response of the logs endpoint.
+#[derive(Debug, Default, Serialize)]
+struct LogsResponse {
+    /// Number of log records that were accepted.
+    accepted: usize,
+    /// Log records that were rejected.
+    rejected: Vec<RejectedLog>,
+}
+
+/// Parses and validates a single log record.
+fn validate_log(record: &RawValue, max_log_size: usize) -> Result<Annotated<OurLog>, &'static str> {
+    if record.get().len() > max_log_size {
+        return Err("log record exceeds the maximum size");
+    }
+
+    let log = Annotated::<OurLog>::from_json(record.get()).map_err(|_| "invalid log record")?;
+    let Some(value) = log.value() else {
+        return Err("log record is not an object");
+    };
+
+    if value.timestamp.value().is_none() {
+        return Err("missing or invalid timestamp");
+    }
+    if value.trace_id.value().is_none() {
+        return Err("missing or invalid trace_id");
+    }
+    if value.level.value().is_none() {
+        return Err("missing or invalid level");
+    }
+    if value.body.value().is_none() {
+        return Err("missing or invalid body");
+    }
+
+    Ok(log)
+}
+
+/// Converts a batch of log records into a log container item.
+///
+/// Returns `None` instead of an item if none of the records are valid.
+fn extract_logs(
+    body: &[u8],
+    max_log_size: usize,
+) -> Result<(Option<Item>, LogsResponse), BadStoreRequest> {
+    let records: Vec<&RawValue> =
+        serde_json::from_slice(body).map_err(BadStoreRequest::InvalidJson)?;
+    if records.is_empty() {
+        return Err(BadStoreRequest::EmptyBody);
+    }
+
+    let mut logs = ContainerItems::new();
+    let mut response = LogsResponse::default();
+    for (index, record) in records.into_iter().enumerate() {
+        match validate_log(record, max_log_size) {
+            Ok(log) => logs.push(log),
+            Err(reason) => response.rejected.push(RejectedLog { index, reason }),
+        }
+    }
+
+    response.accepted = logs.len();
+    if logs.is_empty() {
+        return Ok((None, response));
+    }
+
+    let mut item = Item::new(ItemType::Log);
+    ItemContainer::from(logs)
+        .write_to(&mut item)
+        .map_err(|error| match error {
+            ContainerWriteError::Overflow => BadStoreRequest::Overflow(ItemType::Log),
+            ContainerWriteError::Serialize(error) => BadStoreRequest::InvalidJson(error),
+        })?;
+
+    Ok((Some(item), response))
+}
+
+async fn handle(
+    state: ServiceState,
+    params: LogsParams,
+) -> Result<impl IntoResponse, BadStoreRequest> {
+    let (item, response) = extract_logs(&params.body, state.config().max_log_size())?;
+
+    let Some(item) = item else {
+        return Ok((StatusCode::BAD_REQUEST, axum::Json(response)));
+    };
+
+    let mut envelope = Envelope::from_request(None, params.meta);
+    envelope.add_item(item);
+    common::handle_envelope(&state, envelope).await?;
+
+    Ok((StatusCode::OK, axum::Json(response)))
+}
+
+pub fn route(config: &Config) -> MethodRouter<ServiceState> {
+    post(handle).route_layer(DefaultBodyLimit::max(config.max_envelope_size()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MAX_LOG_SIZE: usize = 1024;
+
+    fn log_record(body: &str) -> serde_json::Value {
+        serde_json::json!({
+            "timestamp": 1544719860.0,
+            "trace_id": "5b8efff798038103d269b633813fc60c",
+            "span_id": "eee19b7ec3c1b174",
+            "level": "info",
+            "body": body,
+        })
+    }
+
+    #[test]
+    fn test_extract_logs() {
+        let body = serde_json::to_vec(&[log_record("first"), log_record("second")]).unwrap();
+
+        let (item, response) = extract_logs(&body, MAX_LOG_SIZE).unwrap();
+        assert_eq!(response.accepted, 2);
+        assert!(response.rejected.is_empty());
+
+        let item = item.unwrap();
+        assert_eq!(item.ty(), &ItemType::Log);
+        assert_eq!(item.item_count(), Some(2));
+
+        let logs = ItemContainer::<OurLog>::parse(&item).unwrap().into_items();
+        let bodies: Vec<_> = logs
+            .iter()
+            .map(|log| log.value().unwrap().body.value().unwrap().as_str())
+            .collect();
+        assert_eq!(bodies, ["first", "second"]);
+    }
+
+    #[test]
+    fn test_extract_logs_partially_invalid() {
+        let mut missing_level = log_record("no level");
+        missing_level.as_object_mut().unwrap().remove("level");
+
+        let body = serde_json::to_vec(&serde_json::json!([
+            log_record("valid"),
+            missing_level,
+            "not a log",
+            log_record(&"x".repeat(MAX_LOG_SIZE)),
+        ]))
+        .unwrap();
+
+        let (item, response) = extract_logs(&body, MAX_LOG_SIZE).unwrap();
+        assert_eq!(response.accepted, 1);
+        assert_eq!(
+            response.rejected,
+            [
+                RejectedLog {
+                    index: 1,
+                    reason: "missing or invalid level"
+                },
+                RejectedLog {
+                    index: 2,
+                    reason: "log record is not an object"
+                },
+                RejectedLog {
+                    index: 3,
+                    reason: "log record exceeds the maximum size"
+                },
+            ]
+        );
+        assert_eq!(item.unwrap().item_count(), Some(1));
+    }
+
+    #[test]
+    fn test_extract_logs_all_invalid() {
+        let body = br#"[{"body": "incomplete"}]"#;
+
+        let (item, response) = extract_logs(body, MAX_LOG_SIZE).unwrap();
+        assert!(item.is_none());
+        assert_eq!(response.accepted, 0);
+        assert_eq!(response.rejected.len(), 1);
+    }
+
+    #[test]
+    fn test_extract_logs_malformed() {
+        assert!(matches!(
+            extract_logs(b"{}", MAX_LOG_SIZE),
+            Err(BadStoreRequest::InvalidJson(_))
+        ));
+        assert!(matches!(
+            extract_logs(b"[]", MAX_LOG_SIZE),
+            Err(BadStoreRequest::EmptyBody)
+        ));
+    }
+}
@@ -163,6 +163,10 @@ async fn extract_embedded_minidump(payload: Bytes) -> Result<Option<Bytes>, BadS
     Ok(None)
 }
 
+/// Creates an envelope from a multipart minidump upload.
+///
+/// Every file part of the upload, including the minidump, becomes a separate attachment item. The
+/// items are associated through the event id of the envelope.
 async fn extract_multipart(
     multipart: Multipart<'static>,
     meta: RequestMeta,
@@ -7,26 +7,35 @@ mod attachments;
 mod autoscaling;
 mod batch_metrics;
 mod batch_outcomes;
+mod buffer_counts;
+mod buffer_decommission;
+mod buffer_metrics;
 mod common;
 mod envelope;
 mod events;
 mod forward;
 mod health_check;
+mod logs;
 mod minidump;
 mod monitor;
 mod nel;
 mod playstation;
 mod project_configs;
+mod project_refetch;
 mod public_keys;
 mod security_report;
+mod spool_config;
+mod spool_drain;
+mod spool_queue;
 mod statics;
 mod store;
 mod traces;
 mod unreal;
+mod validate;
 
 use axum::extract::DefaultBodyLimit;
-use axum::routing::{any, get, post, Router};
-use relay_config::Config;
+use axum::routing::{any, get, post, MethodRouter, Router};
+use relay_config::{Config, CorsRouteGroup, TrailingSlash};
 
 use crate::middlewares;
 use crate::service::ServiceState;
@@ -34,13 +43,54 @@ use crate::service::ServiceState;
 /// Size limit for internal batch endpoints.
 const BATCH_JSON_BODY_LIMIT: usize = 50_000_000; // 50 MB
 
+/// Registers routes along with their variant without trailing slash.
+trait RouterExt<S> {
+    /// Adds a route whose path ends with a slash.
+    ///
+    /// Depending on the [`TrailingSlash`] mode, the path without trailing slash is not registered,
+    /// redirects to the path with trailing slash, or is handled by the same route. Paths that are
+    /// not registered reach the fallback of the router, which forwards them to the upstream.
+    fn route_with_slash(self, path: &str, route: MethodRouter<S>, mode: TrailingSlash) -> Self;
+}
+
+impl<S> RouterExt<S> for Router<S>
+where
+    S: Clone + Send + Sync + 'static,
+{
+    fn route_with_slash(self, path: &str, route: MethodRouter<S>, mode: TrailingSlash) -> Self {
+        let router = self.route(path, route.clone());
+        let Some(stripped) = path.strip_suffix('/') else {
+            return router;
+        };
+
+        match mode {
+            TrailingSlash::Strict => router,
+            TrailingSlash::Redirect => {
+                router.route(stripped, any(statics::redirect_trailing_slash))
+            }
+            TrailingSlash::Lenient => router.route(stripped, route),
+        }
+    }
+}
+
 #[rustfmt::skip]
 pub fn routes(config: &Config) -> Router<ServiceState>{
+    let slash = config.server_trailing_slash();
+
     // Relay-internal routes pointing to /api/relay/
     let internal_routes = Router::new()
         .route("/api/relay/healthcheck/{kind}/", get(health_check::handle))
         .route("/api/relay/events/{event_id}/", get(events::handle))
         .route("/api/relay/autoscaling/", get(autoscaling::handle))
+        .route("/api/relay/buffer/counts/", get(buffer_counts::handle))
+        .route("/api/relay/buffer/counts/reset/", post(buffer_counts::handle_reset))
+        .route("/api/relay/buffer/partitions/{partition_id}/decommission/", post(buffer_decommission::handle))
+        .route("/api/relay/metrics/", get(buffer_metrics::handle))
+        .route("/api/relay/projects/{public_key}/refetch/", post(project_refetch::handle))
+        .route("/api/relay/spool/queue/", get(spool_queue::handle))
+        .route("/api/relay/spool/config/", post(spool_config::handle))
+        .route("/api/relay/spool/drain/", post(spool_drain::handle))
+        .route("/api/relay/spool/ack/", post(spool_drain::handle_ack))
         // Fallback route, but with a name, and just on `/api/relay/*`.
         .route("/api/relay/{*not_found}", any(statics::not_found));
 
@@ -60,24 +110,23 @@ pub fn routes(config: &Config) -> Router<ServiceState>{
     // Ingestion routes pointing to /api/:project_id/
     let store_routes = Router::new()
         // Legacy store path that is missing the project parameter.
-        .route("/api/store/", store::route(config))
+        .route_with_slash("/api/store/", store::route(config), slash)
         // cron monitor level routes.  These are user facing APIs and as such support trailing slashes.
         .route("/api/{project_id}/cron/{monitor_slug}/{sentry_key}", monitor::route(config))
         .route("/api/{project_id}/cron/{monitor_slug}/{sentry_key}/", monitor::route(config))
         .route("/api/{project_id}/cron/{monitor_slug}", monitor::route(config))
         .route("/api/{project_id}/cron/{monitor_slug}/", monitor::route(config))
 
-        .route("/api/{project_id}/store/", store::route(config))
-        .route("/api/{project_id}/envelope/", envelope::route(config))
-        .route("/api/{project_id}/security/", security_report::route(config))
-        .route("/api/{project_id}/csp-report/", security_report::route(config))
-        .route("/api/{project_id}/nel/", nel::route(config))
+        .route_with_slash("/api/{project_id}/store/", store::route(config), slash)
         // No mandatory trailing slash here because people already use it like this.
         .route("/api/{project_id}/minidump", minidump::route(config))
         .route("/api/{project_id}/minidump/", minidump::route(config))
-        .route("/api/{project_id}/playstation/", playstation::route(config))
-        .route("/api/{project_id}/events/{event_id}/attachments/", post(attachments::handle))
-        .route("/api/{project_id}/unreal/{sentry_key}/", unreal::route(config))
+        .route_with_slash("/api/{project_id}/playstation/", playstation::route(config), slash)
+        .route_with_slash("/api/{project_id}/events/{event_id}/attachments/", post(attachments::handle), slash)
+        .route_with_slash("/api/{project_id}/unreal/{sentry_key}/", unreal::route(config), slash)
+        .route_with_slash("/api/{project_id}/log/", logs::route(config), slash)
+        // Checks the DSN of SDK setups without ingesting data.
+        .route_with_slash("/api/{project_id}/validate/", get(validate::handle), slash)
         // The OTLP/HTTP transport defaults to a request suffix of /v1/traces (no trailing slash):
         // https://opentelemetry.io/docs/specs/otlp/#otlphttp-request
         // Because we initially released this endpoint with a trailing slash, keeping it for
@@ -86,12 +135,95 @@ pub fn routes(config: &Config) -> Router<ServiceState>{
         .route("/api/{project_id}/otlp/v1/traces/", traces::route(config))
         // NOTE: If you add a new (non-experimental) route here, please also list it in
         // https://github.com/getsentry/sentry-docs/blob/master/docs/product/relay/operating-guidelines.mdx
-        .route_layer(middlewares::cors());
+        .route_layer(middlewares::cors(config.cors_policy(CorsRouteGroup::Store)));
+
+    let envelope_routes = Router::new()
+        .route_with_slash("/api/{project_id}/envelope/", envelope::route(config), slash)
+        .route_layer(middlewares::cors(config.cors_policy(CorsRouteGroup::Envelope)));
+
+    // Browser report routes, which can have their own CORS policy.
+    let security_routes = Router::new()
+        .route_with_slash("/api/{project_id}/security/", security_report::route(config), slash)
+        .route_with_slash("/api/{project_id}/csp-report/", security_report::route(config), slash)
+        .route_with_slash("/api/{project_id}/nel/", nel::route(config), slash)
+        .route_layer(middlewares::cors(config.cors_policy(CorsRouteGroup::Security)));
 
     Router::new().merge(internal_routes)
         .merge(web_routes)
         .merge(batch_routes)
         .merge(store_routes)
+        .merge(envelope_routes)
+        .merge(security_routes)
         // Forward all other API routes to the upstream. This will 404 for non-API routes.
         .fallback(forward::forward)
 }
+
+#[cfg(test)]
+mod tests {
+    use axum::body::Body;
+    use axum::http::{header, Request, StatusCode};
+    use tower::ServiceExt;
+
+    use super::*;
+
+    async fn request(mode: TrailingSlash, uri: &str) -> (StatusCode, Option<String>) {
+        let router = Router::new().route_with_slash(
+            "/api/{project_id}/envelope/",
+            post(|| async { "ok" }),
+            mode,
+        );
+
+        let request = Request::post(uri).body(Body::empty()).unwrap();
+        let response = router.oneshot(request).await.unwrap();
+        let location = response
+            .headers()
+            .get(header::LOCATION)
+            .map(|location| location.to_str().unwrap().to_owned());
+
+        (response.status(), location)
+    }
+
+    #[tokio::test]
+    async fn test_trailing_slash_strict() {
+        let mode = TrailingSlash::Strict;
+        assert_eq!(
+            request(mode, "/api/42/envelope/").await,
+            (StatusCode::OK, None)
+        );
+        // The path is not registered. This router has no fallback and responds with 404, whereas
+        // `routes` forwards such requests to the upstream.
+        assert_eq!(
+            request(mode, "/api/42/envelope").await,
+            (StatusCode::NOT_FOUND, None)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_trailing_slash_redirect() {
+        let mode = TrailingSlash::Redirect;
+        assert_eq!(
+            request(mode, "/api/42/envelope/").await,
+            (StatusCode::OK, None)
+        );
+        assert_eq!(
+            request(mode, "/api/42/envelope?sentry_key=abc").await,
+            (
+                StatusCode::PERMANENT_REDIRECT,
+                Some("/api/42/envelope/?sentry_key=abc".to_owned())
+            )
+        );
+    }
+
+    #[tokio::test]
+    async fn test_trailing_slash_lenient() {
+        let mode = TrailingSlash::Lenient;
+        assert_eq!(
+            request(mode, "/api/42/envelope/").await,
+            (StatusCode::OK, None)
+        );
+        assert_eq!(
+            request(mode, "/api/42/envelope").await,
+            (StatusCode::OK, None)
+        );
+    }
+}
@@ -1,4 +1,4 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::sync::Arc;
 
 use axum::extract::{Query, Request};
@@ -8,6 +8,7 @@ use axum::response::{IntoResponse, Result};
 use axum::{Json, RequestExt};
 use relay_base_schema::project::ProjectKey;
 use relay_dynamic_config::{ErrorBoundary, GlobalConfig};
+use serde::de::IgnoredAny;
 use serde::{Deserialize, Serialize};
 
 use crate::endpoints::common::ServiceUnavailable;
@@ -28,6 +29,18 @@ use crate::utils::ApiErrorResponse;
 /// returned, or a further poll ensues.
 const ENDPOINT_V3: u16 = 3;
 
+/// V4 version of this endpoint.
+///
+/// This version behaves like V3, but rejects requests with unknown fields or invalid project keys
+/// instead of skipping them, see [`validate_request`].
+const ENDPOINT_V4: u16 = 4;
+
+/// Fields of the request that are accepted by V4 but not used by this endpoint.
+///
+/// Downstream Relays send `noCache`, but this endpoint always serves project configs from the
+/// cache.
+const IGNORED_FIELDS: &[&str] = &["noCache"];
+
 #[derive(Debug, Clone, Copy, thiserror::Error)]
 #[error("This API version is no longer supported, upgrade your Relay or Client")]
 struct VersionOutdatedError;
@@ -38,6 +51,28 @@ impl IntoResponse for VersionOutdatedError {
     }
 }
 
+/// A request to V4 of this endpoint is invalid.
+#[derive(Debug, thiserror::Error)]
+enum InvalidRequestError {
+    /// The request contains a field that is not known to this endpoint.
+    #[error("unknown field `{0}`")]
+    UnknownField(String),
+    /// A requested project key could not be parsed.
+    #[error("invalid project key in field `publicKeys[{index}]`")]
+    InvalidProjectKey {
+        /// Position of the invalid key in the list of requested project keys.
+        index: usize,
+        #[source]
+        source: Arc<dyn std::error::Error + Send + Sync + 'static>,
+    },
+}
+
+impl IntoResponse for InvalidRequestError {
+    fn into_response(self) -> axum::response::Response {
+        (StatusCode::BAD_REQUEST, ApiErrorResponse::from_error(&self)).into_response()
+    }
+}
+
 /// Helper to deserialize the `version` query parameter.
 #[derive(Clone, Copy, Debug, Deserialize)]
 struct VersionQuery {
@@ -96,7 +131,9 @@ struct GetProjectStatesResponseWrapper {
 /// Request payload of the project config endpoint.
 ///
 /// This is a replica of [`GetProjectStates`](crate::services::projects::source::upstream::GetProjectStates)
-/// which allows skipping invalid project keys.
+/// which allows skipping invalid project keys and unknown fields. Requests to V4 of this endpoint
+/// are checked with [`validate_request`] instead, so that the error can point at the offending
+/// field.
 #[derive(Debug, Deserialize)]
 #[serde(rename_all = "camelCase")]
 struct GetProjectStatesRequest {
@@ -111,6 +148,33 @@ struct GetProjectStatesRequest {
     full_config: bool,
     #[serde(default)]
     global: bool,
+    /// All fields that are not known to this endpoint.
+    #[serde(flatten)]
+    unknown: BTreeMap<String, IgnoredAny>,
+}
+
+/// Checks that the request has no unknown fields and that all requested project keys are valid.
+///
+/// Returns an error for the first unknown field or invalid project key.
+fn validate_request(request: &GetProjectStatesRequest) -> Result<(), InvalidRequestError> {
+    if let Some(field) = request
+        .unknown
+        .keys()
+        .find(|field| !IGNORED_FIELDS.contains(&field.as_str()))
+    {
+        return Err(InvalidRequestError::UnknownField(field.clone()));
+    }
+
+    for (index, public_key) in request.public_keys.iter().enumerate() {
+        if let ErrorBoundary::Err(source) = public_key {
+            return Err(InvalidRequestError::InvalidProjectKey {
+                index,
+                source: source.clone(),
+            });
+        }
+    }
+
+    Ok(())
 }
 
 fn into_valid_keys(
@@ -143,12 +207,22 @@ fn into_valid_keys(
 
 async fn inner(
     state: ServiceState,
+    Query(query): Query<VersionQuery>,
     body: SignedJson<GetProjectStatesRequest>,
-) -> Result<impl IntoResponse, ServiceUnavailable> {
+) -> Result<impl IntoResponse> {
     let SignedJson { inner, relay } = body;
 
+    if query.version >= ENDPOINT_V4 {
+        validate_request(&inner)?;
+    }
+
     let (global, global_status) = if inner.global {
-        match state.global_config().send(global_config::Get).await? {
+        let status = state
+            .global_config()
+            .send(global_config::Get)
+            .await
+            .map_err(ServiceUnavailable::from)?;
+        match status {
             global_config::Status::Ready(config) => (Some(config), Some(StatusResponse::Ready)),
             // Old relays expect to get a global config no matter what, even if it's not ready
             // yet. We therefore give them a default global config.
@@ -230,7 +304,7 @@ fn is_outdated(Query(query): Query<VersionQuery>) -> bool {
 
 /// Returns `true` if the `?version` query parameter is compatible with this implementation.
 fn is_compatible(Query(query): Query<VersionQuery>) -> bool {
-    query.version == ENDPOINT_V3
+    matches!(query.version, ENDPOINT_V3 | ENDPOINT_V4)
 }
 
 /// Endpoint handler for the project configs endpoint.
@@ -254,3 +328,64 @@ pub async fn handle(state: ServiceState, mut req: Request) -> Result<impl IntoRe
         Ok(forward::forward(state, req).await)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_request(json: &str) -> GetProjectStatesRequest {
+        serde_json::from_str(json).unwrap()
+    }
+
+    #[test]
+    fn test_valid_request() {
+        let request = parse_request(
+            r#"{
+                "publicKeys": ["a94ae32be2584e0bbd7a4cbb95971fee"],
+                "revisions": ["123"],
+                "fullConfig": true,
+                "noCache": false,
+                "global": true
+            }"#,
+        );
+
+        assert!(request.full_config);
+        assert!(request.global);
+        validate_request(&request).unwrap();
+    }
+
+    #[test]
+    fn test_unknown_field() {
+        let request = parse_request(
+            r#"{"publicKeys": ["a94ae32be2584e0bbd7a4cbb95971fee"], "fullConfigs": true}"#,
+        );
+
+        let error = validate_request(&request).unwrap_err();
+        assert_eq!(error.to_string(), "unknown field `fullConfigs`");
+    }
+
+    #[test]
+    fn test_bad_key() {
+        let request = parse_request(
+            r#"{
+                "publicKeys": ["a94ae32be2584e0bbd7a4cbb95971fee", "deadbeef"],
+                "revisions": [null, "123"]
+            }"#,
+        );
+
+        let error = validate_request(&request).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "invalid project key in field `publicKeys[1]`"
+        );
+
+        // Before V4, invalid keys are skipped without shifting the revisions of other keys.
+        let keys: Vec<_> = into_valid_keys(request.public_keys, request.revisions).collect();
+        assert_eq!(keys.len(), 1);
+        assert_eq!(
+            keys[0].0,
+            ProjectKey::parse("a94ae32be2584e0bbd7a4cbb95971fee").unwrap()
+        );
+        assert_eq!(keys[0].1.as_str(), None);
+    }
+}
@@ -0,0 +1,80 @@
+//! Forces a refetch of a project config from the upstream.
+
+use axum::extract::Path;
+use axum::http::StatusCode;
+use axum::response::IntoResponse;
+use relay_base_schema::project::ProjectKey;
+use serde::Serialize;
+use tokio::sync::broadcast::error::RecvError;
+
+use crate::endpoints::common::ServiceUnavailable;
+use crate::extractors::SignedBytes;
+use crate::service::ServiceState;
+use crate::services::projects::cache::ProjectChange;
+use crate::services::projects::project::ProjectState;
+
+/// Response of the project refetch endpoint.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct RefetchResponse {
+    /// Whether this request triggered a fetch. `false` if the project was refetched recently.
+    refetched: bool,
+    /// Whether the project state is available after the refetch.
+    ready: bool,
+    /// The project state after the refetch, one of `enabled`, `disabled` or `pending`.
+    state: &'static str,
+}
+
+pub async fn handle(
+    state: ServiceState,
+    Path(project_key): Path<ProjectKey>,
+    body: SignedBytes,
+) -> Result<impl IntoResponse, ServiceUnavailable> {
+    if !body.relay.internal {
+        return Ok(StatusCode::FORBIDDEN.into_response());
+    }
+
+    let project_cache = state.project_cache_handle();
+
+    // Subscribe before requesting the refetch, to not miss the completion of the fetch.
+    let mut changes = project_cache.changes();
+    let refetched = project_cache.refetch(project_key).await?;
+
+    if refetched {
+        // The envelope buffer receives the same change and marks the stacks of the project ready.
+        let completed = async {
+            loop {
+                match changes.recv().await {
+                    Ok(ProjectChange::Ready(key)) if key == project_key => break,
+                    Err(RecvError::Closed) => break,
+                    _ => continue,
+                }
+            }
+        };
+
+        if tokio::time::timeout(state.config().query_timeout(), completed)
+            .await
+            .is_err()
+        {
+            relay_log::debug!(
+                tags.project_key = project_key.as_str(),
+                "project refetch did not complete in time"
+            );
+        }
+    }
+
+    let project = project_cache.get(project_key);
+    let project_state = project.state();
+
+    let response = RefetchResponse {
+        refetched,
+        ready: !project_state.is_pending(),
+        state: match project_state {
+            ProjectState::Enabled(_) => "enabled",
+            ProjectState::Disabled => "disabled",
+            ProjectState::Pending => "pending",
+        },
+    };
+
+    Ok(axum::Json(response).into_response())
+}
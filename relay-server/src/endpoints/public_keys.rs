@@ -1,26 +1,74 @@
 use std::collections::HashMap;
 
-use axum::response::IntoResponse;
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
 use futures::future;
 
-use crate::endpoints::common::ServiceUnavailable;
-use crate::extractors::SignedJson;
+use crate::extractors::{SignatureError, SignedJson};
 use crate::service::ServiceState;
 use crate::services::relays::{GetRelay, GetRelays, GetRelaysResponse};
+use crate::utils::ApiErrorResponse;
+
+/// Errors returned by the Relay public keys endpoint.
+#[derive(Debug, thiserror::Error)]
+pub enum PublicKeysError {
+    /// The request did not list any Relays.
+    #[error("malformed request: no relay ids requested")]
+    EmptyRequest,
+    /// The request body could not be read or parsed.
+    #[error("malformed request")]
+    MalformedRequest(#[source] SignatureError),
+    /// The request could not be authenticated.
+    #[error(transparent)]
+    Signature(SignatureError),
+    /// The Relay cache could not be reached to resolve Relay information.
+    #[error("service unavailable")]
+    ServiceUnavailable(#[from] relay_system::SendError),
+}
+
+impl From<SignatureError> for PublicKeysError {
+    fn from(error: SignatureError) -> Self {
+        match error {
+            SignatureError::InvalidJson(_) | SignatureError::MalformedBody(_) => {
+                Self::MalformedRequest(error)
+            }
+            SignatureError::ServiceUnavailable(error) => Self::ServiceUnavailable(error),
+            error => Self::Signature(error),
+        }
+    }
+}
+
+impl IntoResponse for PublicKeysError {
+    fn into_response(self) -> Response {
+        let status = match self {
+            Self::EmptyRequest | Self::MalformedRequest(_) => StatusCode::BAD_REQUEST,
+            Self::Signature(error) => return error.into_response(),
+            Self::ServiceUnavailable(_) => StatusCode::SERVICE_UNAVAILABLE,
+        };
+
+        (status, ApiErrorResponse::from_error(&self)).into_response()
+    }
+}
 
 /// Handles the Relay public keys endpoint.
 ///
 /// Note that this has nothing to do with Sentry public keys, which refer to the public key portion
 /// of a DSN used for authenticating event submission. This endpoint is for Relay's public keys,
 /// which authenticate entire Relays.
+///
+/// Unknown Relays are listed with a `null` value. The endpoint responds with `200` even if none of
+/// the requested Relays are known, which downstream Relays rely on.
 pub async fn handle(
     state: ServiceState,
-    body: SignedJson<GetRelays>,
-) -> Result<impl IntoResponse, ServiceUnavailable> {
-    let relay_cache = &state.relay_cache();
+    body: Result<SignedJson<GetRelays>, SignatureError>,
+) -> Result<impl IntoResponse, PublicKeysError> {
+    let relay_ids = body?.inner.relay_ids;
+    if relay_ids.is_empty() {
+        return Err(PublicKeysError::EmptyRequest);
+    }
 
-    let relay_ids = body.inner.relay_ids.into_iter();
-    let futures = relay_ids.map(|relay_id| {
+    let relay_cache = &state.relay_cache();
+    let futures = relay_ids.into_iter().map(|relay_id| {
         let inner = relay_cache.send(GetRelay { relay_id });
         async move { (relay_id, inner.await) }
     });
@@ -33,3 +81,54 @@ pub async fn handle(
 
     Ok(axum::Json(GetRelaysResponse { relays }))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn response_parts(error: PublicKeysError) -> (StatusCode, serde_json::Value) {
+        let response = error.into_response();
+        let status = response.status();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        (status, serde_json::from_slice(&body).unwrap())
+    }
+
+    #[tokio::test]
+    async fn test_empty_request() {
+        let (status, body) = response_parts(PublicKeysError::EmptyRequest).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body["detail"], "malformed request: no relay ids requested");
+    }
+
+    #[tokio::test]
+    async fn test_malformed_request() {
+        let json_error = serde_json::from_str::<GetRelays>("{}").unwrap_err();
+        let error = PublicKeysError::from(SignatureError::InvalidJson(json_error));
+
+        let (status, body) = response_parts(error).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body["detail"], "malformed request");
+        assert_eq!(body["causes"][0], "invalid JSON data");
+    }
+
+    #[tokio::test]
+    async fn test_service_unavailable() {
+        let error =
+            PublicKeysError::from(SignatureError::ServiceUnavailable(relay_system::SendError));
+
+        let (status, body) = response_parts(error).await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(body["detail"], "service unavailable");
+    }
+
+    #[tokio::test]
+    async fn test_unauthorized() {
+        let error = PublicKeysError::from(SignatureError::UnknownRelay);
+
+        let (status, body) = response_parts(error).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        assert_eq!(body["detail"], "unknown relay id");
+    }
+}
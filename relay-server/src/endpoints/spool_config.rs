@@ -0,0 +1,30 @@
+//! Changes settings of the envelope buffer without a restart.
+
+use axum::http::StatusCode;
+use axum::response::IntoResponse;
+
+use crate::endpoints::common::ServiceUnavailable;
+use crate::extractors::SignedBytes;
+use crate::service::ServiceState;
+use crate::services::buffer::LiveSettings;
+
+/// Applies the settings in the request body to all buffer partitions.
+///
+/// Only the settings of [`LiveSettings`] can be changed. Requests with other or invalid settings
+/// are rejected without applying any of them.
+pub async fn handle(
+    state: ServiceState,
+    body: SignedBytes,
+) -> Result<impl IntoResponse, ServiceUnavailable> {
+    if !body.relay.internal {
+        return Ok(StatusCode::FORBIDDEN.into_response());
+    }
+
+    let settings = match serde_json::from_slice::<LiveSettings>(&body.body) {
+        Ok(settings) => settings,
+        Err(error) => return Ok((StatusCode::BAD_REQUEST, error.to_string()).into_response()),
+    };
+
+    state.envelope_buffers().update_settings(settings).await?;
+    Ok(StatusCode::OK.into_response())
+}
@@ -0,0 +1,163 @@
+//! Pops envelopes from the buffer and hands them to an external worker.
+
+use std::time::Duration;
+
+use axum::extract::Query;
+use axum::http::{header, StatusCode};
+use axum::response::IntoResponse;
+use serde::{Deserialize, Serialize};
+
+use crate::endpoints::common::ServiceUnavailable;
+use crate::envelope::Envelope;
+use crate::extractors::SignedBytes;
+use crate::service::ServiceState;
+use crate::services::buffer::DrainAck;
+use crate::services::outcome::{DiscardReason, Outcome};
+use crate::services::processor::ProcessingGroup;
+use crate::utils::ManagedEnvelope;
+
+/// Number of envelopes drained if no count is requested.
+const DEFAULT_COUNT: usize = 100;
+
+/// Maximum number of envelopes drained in a single request.
+const MAX_COUNT: usize = 1000;
+
+/// Time in seconds after which drained envelopes are delivered again if no timeout is requested.
+const DEFAULT_VISIBILITY_TIMEOUT: u64 = 300;
+
+#[derive(Debug, Deserialize)]
+struct DrainQuery {
+    /// The number of envelopes to drain, capped at [`MAX_COUNT`].
+    count: Option<usize>,
+    /// The time in seconds until envelopes that were not acknowledged are delivered again.
+    visibility_timeout: Option<u64>,
+}
+
+/// Serializes a drained envelope into a frame.
+///
+/// The frame starts with the partition id as `u8` and the acknowledgement token as big-endian
+/// `i64`, followed by the length of the serialized envelope as big-endian `u32` and the envelope.
+fn encode_frame(envelope: &Envelope, ack: DrainAck, frames: &mut Vec<u8>) -> Option<()> {
+    let serialized = envelope.to_vec().ok()?;
+    let length = u32::try_from(serialized.len()).ok()?;
+
+    frames.push(ack.partition_id);
+    frames.extend_from_slice(&ack.token.value().to_be_bytes());
+    frames.extend_from_slice(&length.to_be_bytes());
+    frames.extend_from_slice(&serialized);
+    Some(())
+}
+
+/// Pops up to `count` envelopes from the buffer and returns them in the response body.
+///
+/// Every envelope is serialized in the envelope format and framed with its acknowledgement, see
+/// [`encode_frame`]. An empty body means that the buffer is empty. The envelopes remain in the
+/// buffer until the worker acknowledges them with [`handle_ack`]. Envelopes that are not
+/// acknowledged within the visibility timeout are delivered again, so envelopes are delivered at
+/// least once. This requires the disk-based buffer.
+pub async fn handle(
+    state: ServiceState,
+    Query(query): Query<DrainQuery>,
+    body: SignedBytes,
+) -> Result<impl IntoResponse, ServiceUnavailable> {
+    if !body.relay.internal {
+        return Ok(StatusCode::FORBIDDEN.into_response());
+    }
+
+    let count = query.count.unwrap_or(DEFAULT_COUNT).min(MAX_COUNT);
+    let visibility_timeout = Duration::from_secs(
+        query
+            .visibility_timeout
+            .unwrap_or(DEFAULT_VISIBILITY_TIMEOUT),
+    );
+    let buffers = state.envelope_buffers();
+
+    let drained = buffers
+        .drain_with_ack(count, visibility_timeout)
+        .await
+        .map_err(|error| {
+            relay_log::error!(
+                error = &error as &dyn std::error::Error,
+                "failed to drain envelopes"
+            );
+            ServiceUnavailable
+        })?;
+
+    let mut frames = Vec::new();
+    let mut failed = Vec::new();
+    for (envelope, ack) in drained {
+        if encode_frame(&envelope, ack, &mut frames).is_some() {
+            continue;
+        }
+
+        relay_log::error!("failed to serialize drained envelope");
+        ManagedEnvelope::new(
+            envelope,
+            state.outcome_aggregator().clone(),
+            state.test_store().clone(),
+            ProcessingGroup::Ungrouped,
+        )
+        .reject(Outcome::Invalid(DiscardReason::Internal));
+        failed.push(ack);
+    }
+
+    // Rejected envelopes must not be delivered again.
+    if !failed.is_empty() {
+        buffers.ack(failed).await.map_err(|error| {
+            relay_log::error!(
+                error = &error as &dyn std::error::Error,
+                "failed to acknowledge rejected envelopes"
+            );
+            ServiceUnavailable
+        })?;
+    }
+
+    let headers = [(header::CONTENT_TYPE, "application/octet-stream")];
+    Ok((headers, frames).into_response())
+}
+
+/// Request body of [`handle_ack`].
+#[derive(Debug, Deserialize)]
+struct AckRequest {
+    /// Acknowledgements of drained envelopes, as framed by [`handle`].
+    acks: Vec<DrainAck>,
+}
+
+/// Response body of [`handle_ack`].
+#[derive(Debug, Serialize)]
+struct AckResponse {
+    /// The number of envelopes that were acknowledged before they were delivered again.
+    acked: usize,
+}
+
+/// Removes envelopes drained with [`handle`] from the buffer.
+///
+/// Envelopes whose visibility timeout passed are not acknowledged, since they may have been
+/// delivered again.
+pub async fn handle_ack(
+    state: ServiceState,
+    body: SignedBytes,
+) -> Result<impl IntoResponse, ServiceUnavailable> {
+    if !body.relay.internal {
+        return Ok(StatusCode::FORBIDDEN.into_response());
+    }
+
+    let request = match serde_json::from_slice::<AckRequest>(&body.body) {
+        Ok(request) => request,
+        Err(error) => return Ok((StatusCode::BAD_REQUEST, error.to_string()).into_response()),
+    };
+
+    let acked = state
+        .envelope_buffers()
+        .ack(request.acks)
+        .await
+        .map_err(|error| {
+            relay_log::error!(
+                error = &error as &dyn std::error::Error,
+                "failed to acknowledge drained envelopes"
+            );
+            ServiceUnavailable
+        })?;
+
+    Ok(axum::Json(AckResponse { acked }).into_response())
+}
@@ -0,0 +1,69 @@
+//! Returns the order in which the buffer partitions pop their stacks and how balanced they are.
+
+use axum::extract::Query;
+use axum::http::StatusCode;
+use axum::response::IntoResponse;
+use serde::{Deserialize, Serialize};
+
+use crate::endpoints::common::ServiceUnavailable;
+use crate::extractors::SignedBytes;
+use crate::service::ServiceState;
+use crate::services::buffer::{BalanceStats, StackSnapshot};
+
+/// Number of stacks returned per partition if no limit is requested.
+const DEFAULT_LIMIT: usize = 100;
+
+/// Maximum number of stacks returned per partition.
+const MAX_LIMIT: usize = 1000;
+
+#[derive(Debug, Deserialize)]
+struct QueueQuery {
+    /// The number of stacks to return per partition, capped at [`MAX_LIMIT`].
+    limit: Option<usize>,
+}
+
+/// The first stacks of a single buffer partition in priority order.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct PartitionQueue {
+    partition_id: usize,
+    stacks: Vec<StackSnapshot>,
+    balance: BalanceStats,
+}
+
+/// Response of the spool queue endpoint.
+#[derive(Debug, Serialize)]
+struct QueueResponse {
+    partitions: Vec<PartitionQueue>,
+}
+
+/// Returns the first stacks of all buffer partitions in the order in which they are popped.
+///
+/// Every partition also reports the [`BalanceStats`] of its envelopes across projects.
+pub async fn handle(
+    state: ServiceState,
+    Query(query): Query<QueueQuery>,
+    body: SignedBytes,
+) -> Result<impl IntoResponse, ServiceUnavailable> {
+    if !body.relay.internal {
+        return Ok(StatusCode::FORBIDDEN.into_response());
+    }
+
+    let limit = query.limit.unwrap_or(DEFAULT_LIMIT).min(MAX_LIMIT);
+    let buffers = state.envelope_buffers();
+    let snapshots = buffers.queue_snapshots(limit).await?;
+    let balances = buffers.balance_stats().await?;
+
+    let partitions = snapshots
+        .into_iter()
+        .zip(balances)
+        .enumerate()
+        .map(|(partition_id, (stacks, balance))| PartitionQueue {
+            partition_id,
+            stacks,
+            balance,
+        })
+        .collect();
+
+    Ok(axum::Json(QueueResponse { partitions }).into_response())
+}
@@ -1,7 +1,19 @@
-use axum::http::StatusCode;
-use axum::response::IntoResponse;
+use axum::http::{StatusCode, Uri};
+use axum::response::{IntoResponse, Redirect};
 
 /// An endpoint function that always responds with `404 Not Found`.
 pub async fn not_found() -> impl IntoResponse {
     StatusCode::NOT_FOUND
 }
+
+/// An endpoint function that redirects to the request path with a trailing slash.
+///
+/// Responds with `308 Permanent Redirect`, so clients repeat the request with the same method and
+/// body. The query string is preserved.
+pub async fn redirect_trailing_slash(uri: Uri) -> impl IntoResponse {
+    let location = match uri.query() {
+        Some(query) => format!("{}/?{query}", uri.path()),
+        None => format!("{}/", uri.path()),
+    };
+    Redirect::permanent(&location)
+}
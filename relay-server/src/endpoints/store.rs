@@ -2,21 +2,25 @@
 
 use std::io::{self, Read};
 
+use axum::extract::rejection::BytesRejection;
 use axum::extract::{DefaultBodyLimit, Query};
-use axum::http::header;
-use axum::response::IntoResponse;
+use axum::http::{header, StatusCode};
+use axum::response::{IntoResponse, Response};
 use axum::routing::{post, MethodRouter};
 use bytes::Bytes;
 use data_encoding::BASE64;
 use flate2::bufread::ZlibDecoder;
 use relay_config::Config;
 use relay_event_schema::protocol::EventId;
+use relay_quotas::DataCategory;
 use serde::{Deserialize, Serialize};
 
 use crate::endpoints::common::{self, BadStoreRequest};
 use crate::envelope::{self, ContentType, Envelope, Item, ItemType};
 use crate::extractors::{RawContentType, RequestMeta};
 use crate::service::ServiceState;
+use crate::services::outcome::{DiscardItemType, DiscardReason, Outcome, TrackOutcome};
+use crate::statsd::RelayCounters;
 
 /// Decodes a base64-encoded zlib compressed request body.
 ///
@@ -96,6 +100,21 @@ fn parse_event(
     Ok(envelope)
 }
 
+/// Creates the outcome for a store request whose body exceeds the maximum event size.
+///
+/// The body is never read, so the outcome is attributed to the project key of the request.
+fn oversized_outcome(meta: &RequestMeta) -> TrackOutcome {
+    TrackOutcome {
+        timestamp: meta.received_at(),
+        scoping: meta.get_partial_scoping(),
+        outcome: Outcome::Invalid(DiscardReason::TooLarge(DiscardItemType::Event)),
+        event_id: None,
+        remote_addr: meta.client_addr(),
+        category: DataCategory::Error,
+        quantity: 1,
+    }
+}
+
 #[derive(Serialize)]
 struct PostResponse {
     #[serde(skip_serializing_if = "Option::is_none")]
@@ -107,15 +126,34 @@ async fn handle_post(
     state: ServiceState,
     meta: RequestMeta,
     content_type: RawContentType,
-    body: Bytes,
-) -> Result<impl IntoResponse, BadStoreRequest> {
+    body: Result<Bytes, BytesRejection>,
+) -> Result<Response, BadStoreRequest> {
+    let body = match body {
+        Ok(body) => body,
+        Err(rejection) => {
+            if rejection.status() == StatusCode::PAYLOAD_TOO_LARGE {
+                relay_log::debug!(
+                    project_key = meta.public_key().as_str(),
+                    "store request exceeds the maximum event size"
+                );
+                relay_statsd::metric!(counter(RelayCounters::StoreOversized) += 1);
+                state.outcome_aggregator().send(oversized_outcome(&meta));
+            }
+            return Ok(rejection.into_response());
+        }
+    };
+
     let envelope = match content_type.as_ref() {
         envelope::CONTENT_TYPE => Envelope::parse_request(body, meta)?,
         _ => parse_event(body, meta, state.config())?,
     };
 
-    let id = common::handle_envelope(&state, envelope).await?;
-    Ok(axum::Json(PostResponse { id }).into_response())
+    let handled = common::handle_envelope_queued(&state, envelope).await?;
+    let partition_header = common::partition_header(&state, handled.partition_id);
+    let response = PostResponse {
+        id: handled.event_id,
+    };
+    Ok((partition_header, axum::Json(response)).into_response())
 }
 
 /// Query params of the GET store endpoint.
@@ -139,10 +177,42 @@ async fn handle_get(
     Query(query): Query<GetQuery>,
 ) -> Result<impl IntoResponse, BadStoreRequest> {
     let envelope = parse_event(query.sentry_data.into(), meta, state.config())?;
-    common::handle_envelope(&state, envelope).await?;
-    Ok(([(header::CONTENT_TYPE, "image/gif")], PIXEL))
+    let handled = common::handle_envelope_queued(&state, envelope).await?;
+    let partition_header = common::partition_header(&state, handled.partition_id);
+    Ok((
+        partition_header,
+        [(header::CONTENT_TYPE, "image/gif")],
+        PIXEL,
+    ))
 }
 
 pub fn route(config: &Config) -> MethodRouter<ServiceState> {
     (post(handle_post).get(handle_get)).route_layer(DefaultBodyLimit::max(config.max_event_size()))
 }
+
+#[cfg(test)]
+mod tests {
+    use relay_base_schema::project::{ProjectId, ProjectKey};
+
+    use super::*;
+
+    #[test]
+    fn test_oversized_outcome() {
+        let dsn = "https://e12d836b15bb49d7bbf99e64295d995b:@sentry.io/42"
+            .parse()
+            .unwrap();
+        let meta = RequestMeta::new(dsn);
+
+        let outcome = oversized_outcome(&meta);
+        assert_eq!(
+            outcome.scoping.project_key,
+            ProjectKey::parse("e12d836b15bb49d7bbf99e64295d995b").unwrap()
+        );
+        assert_eq!(outcome.scoping.project_id, ProjectId::new(42));
+        assert_eq!(
+            outcome.outcome,
+            Outcome::Invalid(DiscardReason::TooLarge(DiscardItemType::Event))
+        );
+        assert_eq!(outcome.category, DataCategory::Error);
+    }
+}
@@ -1,16 +1,345 @@
+//! Endpoint for OTLP trace data.
+//!
+//! Protobuf payloads are read incrementally. The `TracesData` message is split into batches of
+//! resource spans while the body is received, without decoding the spans. Resource spans that are
+//! too large for a single batch are decoded and split on a blocking thread once the body is
+//! complete. Every batch is queued as a separate envelope, which bounds the size of the items that
+//! are later converted into spans. Nothing is queued unless the entire payload is valid.
+
+use axum::body::Body;
 use axum::extract::{DefaultBodyLimit, Request};
-use axum::http::StatusCode;
+use axum::http::{header, StatusCode};
 use axum::response::IntoResponse;
 use axum::routing::{post, MethodRouter};
 use axum::RequestExt;
-use bytes::Bytes;
+use bytes::{Bytes, BytesMut};
+use futures::StreamExt;
+use prost::Message;
 use relay_config::Config;
 use relay_dynamic_config::Feature;
+use relay_spans::otel_trace::{ResourceSpans, ScopeSpans, TracesData};
 
 use crate::endpoints::common;
 use crate::envelope::{ContentType, Envelope, Item, ItemType};
 use crate::extractors::{RawContentType, RequestMeta};
 use crate::service::ServiceState;
+use crate::utils::ApiErrorResponse;
+
+/// Field number of `resource_spans` in the OTLP `TracesData` message.
+const RESOURCE_SPANS_FIELD: u64 = 1;
+
+/// Protobuf wire type of variable length integers.
+const WIRE_TYPE_VARINT: u64 = 0;
+/// Protobuf wire type of 64-bit fixed size values.
+const WIRE_TYPE_I64: u64 = 1;
+/// Protobuf wire type of length-delimited values, such as embedded messages.
+const WIRE_TYPE_LEN: u64 = 2;
+/// Protobuf wire type of 32-bit fixed size values.
+const WIRE_TYPE_I32: u64 = 5;
+
+/// The maximum length of a protobuf varint.
+const MAX_VARINT_LEN: usize = 10;
+
+/// Upper bound of the tag and length prefix of an embedded message.
+const MAX_FIELD_OVERHEAD: usize = 1 + MAX_VARINT_LEN;
+
+/// An error raised while reading a protobuf trace payload.
+#[derive(Debug, PartialEq, thiserror::Error)]
+enum TracesError {
+    #[error("failed to read request body")]
+    Body,
+
+    #[error("trace data exceeds the maximum size")]
+    TooLarge,
+
+    #[error("invalid protobuf trace data")]
+    InvalidProtobuf,
+
+    #[error("failed to split trace data")]
+    Split,
+}
+
+impl IntoResponse for TracesError {
+    fn into_response(self) -> axum::response::Response {
+        let status = match self {
+            TracesError::TooLarge => StatusCode::PAYLOAD_TOO_LARGE,
+            TracesError::Body | TracesError::InvalidProtobuf => StatusCode::BAD_REQUEST,
+            TracesError::Split => StatusCode::INTERNAL_SERVER_ERROR,
+        };
+
+        (status, ApiErrorResponse::from_error(&self)).into_response()
+    }
+}
+
+/// Splits a protobuf encoded `TracesData` message into smaller `TracesData` messages.
+///
+/// `TracesData` only consists of repeated `resource_spans` fields, so every sequence of these
+/// fields is a valid `TracesData` message in itself. The splitter groups consecutive fields into
+/// batches of at most `max_batch_size` bytes without decoding the spans they contain. Fields
+/// larger than that, up to `max_field_size`, are kept until [`Self::finish`], which decodes and
+/// splits them by their spans, see [`split_resource_spans`]. Unknown fields are skipped.
+#[derive(Debug)]
+struct TracesDataSplitter {
+    /// Received bytes that do not form a complete field yet.
+    pending: BytesMut,
+    /// Complete fields that have not been emitted yet.
+    batch: BytesMut,
+    /// Fields larger than the maximum batch size, which are split in [`Self::finish`].
+    oversized: Vec<Bytes>,
+    /// The maximum size of a batch.
+    max_batch_size: usize,
+    /// The maximum size of a single field.
+    max_field_size: usize,
+}
+
+impl TracesDataSplitter {
+    fn new(max_batch_size: usize, max_field_size: usize) -> Self {
+        Self {
+            pending: BytesMut::new(),
+            batch: BytesMut::new(),
+            oversized: Vec::new(),
+            max_batch_size,
+            max_field_size,
+        }
+    }
+
+    /// Adds the next chunk of the payload and returns all batches that are complete.
+    ///
+    /// Fails as soon as a field is known to exceed the maximum field size, before the field has
+    /// been received entirely. This does not decode any fields, so it is cheap enough to run on
+    /// the async runtime.
+    fn push(&mut self, chunk: &[u8]) -> Result<Vec<Bytes>, TracesError> {
+        self.pending.extend_from_slice(chunk);
+
+        let mut batches = Vec::new();
+        while let Some((field, wire_type, len)) = self.next_field()? {
+            let field_bytes = self.pending.split_to(len);
+            if field != RESOURCE_SPANS_FIELD {
+                continue;
+            }
+            if wire_type != WIRE_TYPE_LEN {
+                return Err(TracesError::InvalidProtobuf);
+            }
+
+            if !self.batch.is_empty() && self.batch.len() + field_bytes.len() > self.max_batch_size
+            {
+                batches.push(self.batch.split().freeze());
+            }
+
+            if field_bytes.len() > self.max_batch_size {
+                self.oversized.push(field_bytes.freeze());
+            } else {
+                self.batch.unsplit(field_bytes);
+            }
+        }
+
+        Ok(batches)
+    }
+
+    /// Returns the remaining batches once the entire payload has been pushed.
+    ///
+    /// This includes the last batch and the batches split from oversized fields. Fails if the
+    /// payload ends within a field, or if an oversized field cannot be split. Since splitting
+    /// decodes the fields, this should run on a blocking thread.
+    fn finish(mut self) -> Result<Vec<Bytes>, TracesError> {
+        if !self.pending.is_empty() {
+            return Err(TracesError::InvalidProtobuf);
+        }
+
+        let mut batches = Vec::new();
+        if !self.batch.is_empty() {
+            batches.push(self.batch.split().freeze());
+        }
+        for field in &self.oversized {
+            batches.extend(split_resource_spans(field, self.max_batch_size)?);
+        }
+
+        Ok(batches)
+    }
+
+    /// Returns the field number, wire type and encoded length of the next complete field.
+    ///
+    /// Returns `None` if the next field has not been received entirely.
+    fn next_field(&self) -> Result<Option<(u64, u64, usize)>, TracesError> {
+        let Some((tag, tag_len)) = decode_varint(&self.pending)? else {
+            return Ok(None);
+        };
+
+        let (field, wire_type) = (tag >> 3, tag & 0x7);
+        if field == 0 {
+            return Err(TracesError::InvalidProtobuf);
+        }
+
+        let value = &self.pending[tag_len..];
+        let len = match wire_type {
+            WIRE_TYPE_VARINT => match decode_varint(value)? {
+                Some((_, len)) => tag_len + len,
+                None => return Ok(None),
+            },
+            WIRE_TYPE_I64 => tag_len + 8,
+            WIRE_TYPE_LEN => match decode_varint(value)? {
+                Some((payload_len, len)) => usize::try_from(payload_len)
+                    .ok()
+                    .and_then(|payload_len| (tag_len + len).checked_add(payload_len))
+                    .ok_or(TracesError::TooLarge)?,
+                None => return Ok(None),
+            },
+            WIRE_TYPE_I32 => tag_len + 4,
+            _ => return Err(TracesError::InvalidProtobuf),
+        };
+
+        if len > self.max_field_size {
+            return Err(TracesError::TooLarge);
+        }
+
+        Ok((self.pending.len() >= len).then_some((field, wire_type, len)))
+    }
+}
+
+/// Splits an encoded `TracesData` message into messages of at most `max_batch_size` bytes.
+///
+/// Unlike [`TracesDataSplitter`], this decodes the message and splits resource spans by their
+/// spans. Every resulting message repeats the resource and instrumentation scope of the spans it
+/// contains. Fails if a single span does not fit into a message.
+fn split_resource_spans(payload: &[u8], max_batch_size: usize) -> Result<Vec<Bytes>, TracesError> {
+    let traces_data = TracesData::decode(payload).map_err(|_| TracesError::InvalidProtobuf)?;
+
+    let mut batches = Vec::new();
+    for resource_spans in traces_data.resource_spans {
+        let empty_resource = ResourceSpans {
+            scope_spans: Vec::new(),
+            ..resource_spans
+        };
+        let resource_size = empty_resource.encoded_len() + MAX_FIELD_OVERHEAD;
+
+        let mut batch = empty_resource.clone();
+        let mut batch_size = resource_size;
+        for scope_spans in resource_spans.scope_spans {
+            let empty_scope = ScopeSpans {
+                spans: Vec::new(),
+                ..scope_spans
+            };
+            let scope_size = empty_scope.encoded_len() + MAX_FIELD_OVERHEAD;
+            // Whether the last scope spans of the batch belong to the current scope.
+            let mut in_scope = false;
+
+            for span in scope_spans.spans {
+                let span_size = span.encoded_len() + MAX_FIELD_OVERHEAD;
+                if resource_size + scope_size + span_size > max_batch_size {
+                    return Err(TracesError::TooLarge);
+                }
+
+                let added_size = span_size + if in_scope { 0 } else { scope_size };
+                if batch_size + added_size > max_batch_size {
+                    let full = std::mem::replace(&mut batch, empty_resource.clone());
+                    batches.push(encode_resource_spans(full));
+                    batch_size = resource_size;
+                    in_scope = false;
+                }
+
+                if !in_scope {
+                    batch.scope_spans.push(empty_scope.clone());
+                    batch_size += scope_size;
+                    in_scope = true;
+                }
+
+                if let Some(current) = batch.scope_spans.last_mut() {
+                    current.spans.push(span);
+                }
+                batch_size += span_size;
+            }
+        }
+
+        if !batch.scope_spans.is_empty() {
+            batches.push(encode_resource_spans(batch));
+        }
+    }
+
+    Ok(batches)
+}
+
+/// Encodes resource spans as a `TracesData` message.
+fn encode_resource_spans(resource_spans: ResourceSpans) -> Bytes {
+    let traces_data = TracesData {
+        resource_spans: vec![resource_spans],
+    };
+    traces_data.encode_to_vec().into()
+}
+
+/// Decodes the varint at the start of `buf` into its value and encoded length.
+///
+/// Returns `None` if `buf` ends before the varint.
+fn decode_varint(buf: &[u8]) -> Result<Option<(u64, usize)>, TracesError> {
+    let mut value = 0;
+    for (index, byte) in buf.iter().take(MAX_VARINT_LEN).enumerate() {
+        value |= u64::from(byte & 0x7f) << (7 * index);
+        if byte & 0x80 == 0 {
+            return Ok(Some((value, index + 1)));
+        }
+    }
+
+    if buf.len() >= MAX_VARINT_LEN {
+        return Err(TracesError::InvalidProtobuf);
+    }
+
+    Ok(None)
+}
+
+/// Queues a single payload of trace data as an envelope.
+async fn queue_traces(
+    state: &ServiceState,
+    meta: RequestMeta,
+    content_type: ContentType,
+    payload: Bytes,
+) -> axum::response::Result<()> {
+    let mut envelope = Envelope::from_request(None, meta);
+    envelope.require_feature(Feature::OtelEndpoint);
+
+    let mut item = Item::new(ItemType::OtelTracesData);
+    item.set_payload(content_type, payload);
+    envelope.add_item(item);
+
+    common::handle_envelope(state, envelope).await?;
+    Ok(())
+}
+
+/// Reads a protobuf `TracesData` body and queues its batches of resource spans.
+///
+/// The batches are queued only after the entire body has been read and split, so that an invalid
+/// or too large body is rejected without queuing any of its spans.
+async fn queue_protobuf_batches(
+    state: &ServiceState,
+    meta: RequestMeta,
+    body: Body,
+) -> axum::response::Result<()> {
+    let config = state.config();
+    let max_size = config.max_envelope_size();
+    let mut stream = body.into_data_stream();
+    let mut splitter = TracesDataSplitter::new(config.max_event_size(), max_size);
+    let mut received = 0;
+    let mut batches = Vec::new();
+
+    while let Some(chunk) = stream.next().await {
+        let chunk = chunk.map_err(|_| TracesError::Body)?;
+        received += chunk.len();
+        if received > max_size {
+            return Err(TracesError::TooLarge.into());
+        }
+
+        batches.extend(splitter.push(&chunk)?);
+    }
+
+    let remaining = tokio::task::spawn_blocking(move || splitter.finish())
+        .await
+        .map_err(|_| TracesError::Split)??;
+    batches.extend(remaining);
+
+    for batch in batches {
+        queue_traces(state, meta.clone(), ContentType::Protobuf, batch).await?;
+    }
+
+    Ok(())
+}
 
 async fn handle(
     state: ServiceState,
@@ -23,17 +352,20 @@ async fn handle(
     else {
         return Ok(StatusCode::UNSUPPORTED_MEDIA_TYPE);
     };
-    let payload: Bytes = request.extract().await?;
-    let mut envelope = Envelope::from_request(None, meta);
-    envelope.require_feature(Feature::OtelEndpoint);
 
-    envelope.add_item({
-        let mut item = Item::new(ItemType::OtelTracesData);
-        item.set_payload(content_type, payload);
-        item
-    });
+    let config = state.config();
+    let content_length = request
+        .headers()
+        .get(header::CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok()?.parse::<usize>().ok());
+    if content_length.is_some_and(|len| len > config.max_envelope_size()) {
+        return Ok(StatusCode::PAYLOAD_TOO_LARGE);
+    }
 
-    common::handle_envelope(&state, envelope).await?;
+    match content_type {
+        ContentType::Protobuf => queue_protobuf_batches(&state, meta, request.into_body()).await?,
+        _ => queue_traces(&state, meta, content_type, request.extract().await?).await?,
+    }
 
     Ok(StatusCode::ACCEPTED)
 }
@@ -41,3 +373,134 @@ async fn handle(
 pub fn route(config: &Config) -> MethodRouter<ServiceState> {
     post(handle).route_layer(DefaultBodyLimit::max(config.max_envelope_size()))
 }
+
+#[cfg(test)]
+mod tests {
+    use relay_spans::otel_trace::Span;
+
+    use super::*;
+
+    fn traces_data(resources: usize, spans_per_resource: usize) -> TracesData {
+        let resource_spans = (0..resources)
+            .map(|resource| ResourceSpans {
+                scope_spans: vec![ScopeSpans {
+                    spans: (0..spans_per_resource)
+                        .map(|span| Span {
+                            name: format!("span {resource}/{span}"),
+                            trace_id: vec![1; 16],
+                            span_id: vec![2; 8],
+                            ..Default::default()
+                        })
+                        .collect(),
+                    ..Default::default()
+                }],
+                ..Default::default()
+            })
+            .collect();
+
+        TracesData { resource_spans }
+    }
+
+    fn span_count(batch: &[u8]) -> usize {
+        TracesData::decode(batch)
+            .unwrap()
+            .resource_spans
+            .iter()
+            .flat_map(|resource_spans| &resource_spans.scope_spans)
+            .map(|scope_spans| scope_spans.spans.len())
+            .sum()
+    }
+
+    #[test]
+    fn test_split_large_payload() {
+        const MAX_BATCH_SIZE: usize = 16 * 1024;
+        const CHUNK_SIZE: usize = 1000;
+
+        let payload = traces_data(100, 50).encode_to_vec();
+        assert!(payload.len() > 10 * MAX_BATCH_SIZE);
+
+        let mut splitter = TracesDataSplitter::new(MAX_BATCH_SIZE, MAX_BATCH_SIZE);
+        let mut batches = Vec::new();
+        for chunk in payload.chunks(CHUNK_SIZE) {
+            batches.extend(splitter.push(chunk).unwrap());
+            // Only an incomplete field and the current batch are held back.
+            assert!(splitter.pending.len() <= MAX_BATCH_SIZE + CHUNK_SIZE);
+            assert!(splitter.batch.len() <= MAX_BATCH_SIZE);
+        }
+        batches.extend(splitter.finish().unwrap());
+
+        assert!(batches.len() > 1);
+        assert!(batches.iter().all(|batch| batch.len() <= MAX_BATCH_SIZE));
+        assert_eq!(
+            batches.iter().map(|batch| span_count(batch)).sum::<usize>(),
+            5000
+        );
+    }
+
+    #[test]
+    fn test_split_skips_unknown_fields() {
+        let mut payload = traces_data(1, 1).encode_to_vec();
+        // Field 2 as varint, followed by field 3 as 32-bit value.
+        payload.extend([0x10, 0x96, 0x01, 0x1d, 0, 0, 0, 0]);
+        payload.extend(traces_data(1, 2).encode_to_vec());
+
+        let mut splitter = TracesDataSplitter::new(1024, 1024);
+        let mut batches = splitter.push(&payload).unwrap();
+        batches.extend(splitter.finish().unwrap());
+
+        assert_eq!(batches.len(), 1);
+        assert_eq!(span_count(&batches[0]), 3);
+    }
+
+    #[test]
+    fn test_split_field_too_large() {
+        let payload = traces_data(1, 100).encode_to_vec();
+
+        // The field is rejected from its header, before it has been received.
+        let mut splitter = TracesDataSplitter::new(1024, 1024);
+        assert_eq!(splitter.push(&payload[..16]), Err(TracesError::TooLarge));
+    }
+
+    #[test]
+    fn test_split_oversized_resource_spans() {
+        let payload = traces_data(1, 100).encode_to_vec();
+        assert!(payload.len() > 1024);
+
+        let mut splitter = TracesDataSplitter::new(1024, payload.len());
+        // Oversized fields are only split when finishing.
+        assert!(splitter.push(&payload).unwrap().is_empty());
+        let batches = splitter.finish().unwrap();
+
+        assert!(batches.len() > 1);
+        assert!(batches.iter().all(|batch| batch.len() <= 1024));
+        assert_eq!(
+            batches.iter().map(|batch| span_count(batch)).sum::<usize>(),
+            100
+        );
+    }
+
+    #[test]
+    fn test_split_truncated() {
+        let payload = traces_data(2, 2).encode_to_vec();
+
+        let mut splitter = TracesDataSplitter::new(1024, 1024);
+        splitter.push(&payload[..payload.len() - 1]).unwrap();
+        assert_eq!(splitter.finish(), Err(TracesError::InvalidProtobuf));
+    }
+
+    #[test]
+    fn test_split_invalid_wire_type() {
+        // Field 1 encoded as varint instead of an embedded message.
+        let mut splitter = TracesDataSplitter::new(1024, 1024);
+        assert_eq!(
+            splitter.push(&[0x08, 0x01]),
+            Err(TracesError::InvalidProtobuf)
+        );
+    }
+
+    #[test]
+    fn test_split_empty() {
+        let splitter = TracesDataSplitter::new(1024, 1024);
+        assert_eq!(splitter.finish(), Ok(vec![]));
+    }
+}
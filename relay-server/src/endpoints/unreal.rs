@@ -1,9 +1,15 @@
+use std::io::{Read, Write};
+
 use axum::extract::{DefaultBodyLimit, FromRequest, Query};
 use axum::response::IntoResponse;
 use axum::routing::{post, MethodRouter};
 use bytes::Bytes;
+use flate2::read::GzDecoder;
+use flate2::write::ZlibEncoder;
+use flate2::Compression;
 use relay_config::Config;
 use relay_event_schema::protocol::EventId;
+use relay_statsd::metric;
 use serde::Deserialize;
 
 use crate::constants::UNREAL_USER_HEADER;
@@ -11,6 +17,110 @@ use crate::endpoints::common::{self, BadStoreRequest, TextResponse};
 use crate::envelope::{ContentType, Envelope, Item, ItemType};
 use crate::extractors::RequestMeta;
 use crate::service::ServiceState;
+use crate::statsd::RelayCounters;
+
+/// Magic bytes for gzip compressed crash reports.
+const GZIP_MAGIC_HEADER: &[u8] = b"\x1F\x8B";
+
+/// Upper bound for the length of the directory name at the start of an uncompressed crash report.
+const MAX_DIRECTORY_NAME_LEN: usize = 1024;
+
+/// Payload format of an Unreal crash report, detected from its leading bytes.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum UnrealFormat {
+    /// The legacy format: a zlib compressed crash context container.
+    Zlib,
+    /// A gzip compressed crash context container.
+    Gzip,
+    /// An uncompressed crash context container.
+    Uncompressed,
+}
+
+impl UnrealFormat {
+    /// Sniffs the payload format from the header of the crash report.
+    fn detect(data: &[u8]) -> Option<Self> {
+        if data.starts_with(GZIP_MAGIC_HEADER) {
+            Some(Self::Gzip)
+        } else if is_zlib_header(data) {
+            Some(Self::Zlib)
+        } else if is_crash_container(data) {
+            Some(Self::Uncompressed)
+        } else {
+            None
+        }
+    }
+
+    fn as_str(&self) -> &'static str {
+        match self {
+            Self::Zlib => "zlib",
+            Self::Gzip => "gzip",
+            Self::Uncompressed => "uncompressed",
+        }
+    }
+}
+
+/// Checks for a zlib header with deflate compression and a valid header checksum.
+fn is_zlib_header(data: &[u8]) -> bool {
+    match data {
+        [cmf, flg, ..] => cmf & 0x0f == 8 && u16::from_be_bytes([*cmf, *flg]) % 31 == 0,
+        _ => false,
+    }
+}
+
+/// Checks whether the data starts with the directory name of an uncompressed crash container.
+///
+/// The container starts with a length-prefixed, NUL-padded string. The string may be empty, as some
+/// crash reporters do not fill in the directory name.
+fn is_crash_container(data: &[u8]) -> bool {
+    let Some(len) = data.get(..4) else {
+        return false;
+    };
+
+    let len = u32::from_le_bytes([len[0], len[1], len[2], len[3]]) as usize;
+    if len == 0 || len > MAX_DIRECTORY_NAME_LEN {
+        return false;
+    }
+
+    match data.get(4..4 + len) {
+        Some([name @ .., 0]) => name
+            .iter()
+            .take_while(|b| **b != 0)
+            .all(|b| b.is_ascii_graphic()),
+        _ => false,
+    }
+}
+
+/// Converts the crash report into the zlib compressed container expected by the processor.
+///
+/// Gzip compressed reports are decompressed up to `limit` bytes. Larger reports are rejected.
+fn normalize_report(
+    data: Bytes,
+    format: UnrealFormat,
+    limit: usize,
+) -> Result<Bytes, BadStoreRequest> {
+    let uncompressed = match format {
+        UnrealFormat::Zlib => return Ok(data),
+        UnrealFormat::Gzip => {
+            let mut buffer = Vec::new();
+            GzDecoder::new(data.as_ref())
+                .take((limit as u64).saturating_add(1))
+                .read_to_end(&mut buffer)
+                .map_err(BadStoreRequest::InvalidCompressionContainer)?;
+            if buffer.len() > limit {
+                return Err(BadStoreRequest::Overflow(ItemType::UnrealReport));
+            }
+            buffer
+        }
+        UnrealFormat::Uncompressed => data.to_vec(),
+    };
+
+    let mut encoder = ZlibEncoder::new(Vec::new(), Compression::fast());
+    encoder
+        .write_all(&uncompressed)
+        .and_then(|_| encoder.finish())
+        .map(Bytes::from)
+        .map_err(BadStoreRequest::InvalidBody)
+}
 
 #[derive(Debug, Deserialize)]
 struct UnrealQuery {
@@ -28,13 +138,25 @@ struct UnrealParams {
 }
 
 impl UnrealParams {
-    fn extract_envelope(self) -> Result<Box<Envelope>, BadStoreRequest> {
+    fn extract_envelope(self, config: &Config) -> Result<Box<Envelope>, BadStoreRequest> {
         let Self { meta, query, data } = self;
 
         if data.is_empty() {
             return Err(BadStoreRequest::EmptyBody);
         }
 
+        let format = UnrealFormat::detect(&data);
+        metric!(
+            counter(RelayCounters::UnrealReportFormat) += 1,
+            format = format.map_or("unknown", |f| f.as_str())
+        );
+
+        let Some(format) = format else {
+            relay_log::trace!("unrecognized unreal crash report format");
+            return Err(BadStoreRequest::InvalidUnrealReport);
+        };
+        let data = normalize_report(data, format, config.max_envelope_size())?;
+
         let mut envelope = Envelope::from_request(Some(EventId::new()), meta);
 
         let mut item = Item::new(ItemType::UnrealReport);
@@ -53,7 +175,7 @@ async fn handle(
     state: ServiceState,
     params: UnrealParams,
 ) -> Result<impl IntoResponse, BadStoreRequest> {
-    let envelope = params.extract_envelope()?;
+    let envelope = params.extract_envelope(state.config())?;
     let id = envelope.event_id();
 
     // Never respond with a 429 since clients often retry these
@@ -70,3 +192,95 @@ async fn handle(
 pub fn route(config: &Config) -> MethodRouter<ServiceState> {
     post(handle).route_layer(DefaultBodyLimit::max(config.max_attachments_size()))
 }
+
+#[cfg(test)]
+mod tests {
+    use flate2::read::ZlibDecoder;
+    use flate2::write::GzEncoder;
+
+    use super::*;
+
+    const ZLIB_FIXTURE: &[u8] =
+        include_bytes!("../../../tests/integration/fixtures/native/unreal_crash");
+    const ZLIB_FIXTURE_APPLE: &[u8] =
+        include_bytes!("../../../tests/integration/fixtures/native/unreal_crash_apple");
+
+    fn decompress(data: &[u8]) -> Vec<u8> {
+        let mut buffer = Vec::new();
+        ZlibDecoder::new(data).read_to_end(&mut buffer).unwrap();
+        buffer
+    }
+
+    fn uncompressed_fixture() -> Vec<u8> {
+        decompress(ZLIB_FIXTURE)
+    }
+
+    fn gzip_fixture() -> Vec<u8> {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(&uncompressed_fixture()).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    #[test]
+    fn test_detect_format() {
+        assert_eq!(UnrealFormat::detect(ZLIB_FIXTURE), Some(UnrealFormat::Zlib));
+        assert_eq!(
+            UnrealFormat::detect(&gzip_fixture()),
+            Some(UnrealFormat::Gzip)
+        );
+        assert_eq!(
+            UnrealFormat::detect(&uncompressed_fixture()),
+            Some(UnrealFormat::Uncompressed)
+        );
+        // The Apple crash reporter leaves the directory name empty.
+        assert_eq!(
+            UnrealFormat::detect(&decompress(ZLIB_FIXTURE_APPLE)),
+            Some(UnrealFormat::Uncompressed)
+        );
+    }
+
+    #[test]
+    fn test_detect_unknown_format() {
+        assert_eq!(UnrealFormat::detect(b"MDMP\x93\xa7\x00\x00"), None);
+        assert_eq!(UnrealFormat::detect(b"{\"message\":\"hi\"}"), None);
+        assert_eq!(UnrealFormat::detect(b"\x78"), None);
+        // Directory name without a NUL terminator.
+        assert_eq!(UnrealFormat::detect(b"\x04\x00\x00\x00UE4C"), None);
+    }
+
+    #[test]
+    fn test_normalize_report() {
+        let expected = uncompressed_fixture();
+
+        for (data, format) in [
+            (ZLIB_FIXTURE.to_vec(), UnrealFormat::Zlib),
+            (gzip_fixture(), UnrealFormat::Gzip),
+            (uncompressed_fixture(), UnrealFormat::Uncompressed),
+        ] {
+            let normalized = normalize_report(Bytes::from(data), format, usize::MAX).unwrap();
+            assert!(is_zlib_header(&normalized), "{format:?}");
+            assert_eq!(decompress(&normalized), expected, "{format:?}");
+        }
+    }
+
+    #[test]
+    fn test_normalize_invalid_gzip() {
+        let data = Bytes::from_static(b"\x1F\x8Bnot gzip");
+        assert!(matches!(
+            normalize_report(data, UnrealFormat::Gzip, usize::MAX),
+            Err(BadStoreRequest::InvalidCompressionContainer(_))
+        ));
+    }
+
+    #[test]
+    fn test_normalize_gzip_over_limit() {
+        let data = Bytes::from(gzip_fixture());
+        let limit = uncompressed_fixture().len();
+
+        assert!(normalize_report(data.clone(), UnrealFormat::Gzip, limit).is_ok());
+        assert!(matches!(
+            normalize_report(data, UnrealFormat::Gzip, limit - 1),
+            Err(BadStoreRequest::Overflow(ItemType::UnrealReport))
+        ));
+    }
+}
@@ -0,0 +1,78 @@
+//! Validates the authentication of a request without ingesting data.
+
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use relay_base_schema::project::{ProjectId, ProjectKey};
+use serde::Serialize;
+use tokio::sync::broadcast::error::RecvError;
+
+use crate::extractors::RequestMeta;
+use crate::service::ServiceState;
+use crate::services::projects::cache::ProjectChange;
+use crate::services::projects::project::ProjectState;
+use crate::utils::ApiErrorResponse;
+
+/// Response of the validate endpoint for an accepted project key.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ValidateResponse {
+    /// The public key of the request.
+    public_key: ProjectKey,
+    /// The project id stated in the request path.
+    project_id: Option<ProjectId>,
+    /// The state of the project, either `enabled` or `pending` if the project config could not be
+    /// fetched in time.
+    state: &'static str,
+}
+
+/// Checks the project key and project id of the request against the project config.
+///
+/// Returns `200` with the resolved project key if the request would be accepted, `400` or `401`
+/// for malformed authentication, and `403` if the project key is disabled or does not belong to
+/// the project. The request is not ingested.
+pub async fn handle(state: ServiceState, meta: RequestMeta) -> Response {
+    let project_key = meta.public_key();
+    let project_cache = state.project_cache_handle();
+
+    // Subscribe before retrieving the project, to not miss the completion of the fetch.
+    let mut changes = project_cache.changes();
+    if project_cache.get(project_key).state().is_pending() {
+        let fetched = async {
+            loop {
+                match changes.recv().await {
+                    Ok(ProjectChange::Ready(key)) if key == project_key => break,
+                    Err(RecvError::Closed) => break,
+                    _ => continue,
+                }
+            }
+        };
+
+        // The timeout is reported as `pending` state in the response.
+        let _ = tokio::time::timeout(state.config().query_timeout(), fetched).await;
+    }
+
+    let project = project_cache.get(project_key);
+    let project_state = match project.state() {
+        ProjectState::Enabled(info) => match info.check_request(&meta, state.config()) {
+            Ok(()) => "enabled",
+            Err(reason) => {
+                let detail = format!("project key rejected: {}", reason.name());
+                return (StatusCode::FORBIDDEN, ApiErrorResponse::with_detail(detail))
+                    .into_response();
+            }
+        },
+        ProjectState::Disabled => {
+            let detail = "project key rejected: project is disabled";
+            return (StatusCode::FORBIDDEN, ApiErrorResponse::with_detail(detail)).into_response();
+        }
+        ProjectState::Pending => "pending",
+    };
+
+    let response = ValidateResponse {
+        public_key: project_key,
+        project_id: meta.project_id(),
+        state: project_state,
+    };
+
+    axum::Json(response).into_response()
+}
@@ -6,6 +6,7 @@ use std::ops::AddAssign;
 use uuid::Uuid;
 
 use bytes::Bytes;
+use chrono::{DateTime, Utc};
 use relay_event_schema::protocol::EventType;
 use relay_protocol::Value;
 use relay_quotas::DataCategory;
@@ -40,6 +41,7 @@ impl Item {
                 content_type: None,
                 filename: None,
                 routing_hint: None,
+                retention: None,
                 rate_limited: false,
                 replay_combined_payload: false,
                 source_quantities: None,
@@ -272,6 +274,24 @@ impl Item {
         self.headers.routing_hint = Some(routing_hint);
     }
 
+    /// Returns the data retention in days of this item, if specified.
+    pub fn retention(&self) -> Option<u16> {
+        self.headers.retention
+    }
+
+    /// Sets the data retention in days of this item.
+    pub fn set_retention(&mut self, retention: u16) {
+        self.headers.retention = Some(retention);
+    }
+
+    /// Returns `true` if the item outlived its retention, given the time its envelope was received.
+    ///
+    /// Items without a retention never expire.
+    pub fn is_retention_expired(&self, received_at: DateTime<Utc>) -> bool {
+        self.retention()
+            .is_some_and(|days| Utc::now() - received_at > chrono::Duration::days(days.into()))
+    }
+
     /// Returns whether this item should be rate limited.
     pub fn rate_limited(&self) -> bool {
         self.headers.rate_limited
@@ -772,6 +792,13 @@ pub struct ItemHeaders {
     #[serde(default, skip_serializing_if = "Option::is_none")]
     routing_hint: Option<Uuid>,
 
+    /// Data retention in days for this item.
+    ///
+    /// Items that are held back longer than their retention, for example in the envelope
+    /// buffer, are expired and removed from the envelope individually.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    retention: Option<u16>,
+
     /// Indicates that this item is being rate limited.
     ///
     /// By default, rate limited items are immediately removed from Envelopes. For processing,
@@ -38,6 +38,7 @@ use std::time::Duration;
 
 use bytes::Bytes;
 use chrono::{DateTime, Utc};
+use relay_auth::RelayId;
 use relay_dynamic_config::{ErrorBoundary, Feature};
 use relay_event_normalization::{normalize_transaction_name, TransactionNameRule};
 use relay_event_schema::protocol::{Event, EventId};
@@ -119,6 +120,13 @@ pub struct EnvelopeHeaders<M = RequestMeta> {
     #[serde(default, skip_serializing_if = "SmallVec::is_empty")]
     required_features: SmallVec<[Feature; 1]>,
 
+    /// The Relays that buffered this envelope, in the order in which they received it.
+    ///
+    /// This is an internal field that should only be set by Relay. It is used to detect envelopes
+    /// that are forwarded in a loop across a chain of Relays.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    relay_path: Vec<RelayId>,
+
     /// Other attributes for forward compatibility.
     #[serde(flatten)]
     other: BTreeMap<String, Value>,
@@ -160,6 +168,7 @@ impl EnvelopeHeaders<PartialMeta> {
             sent_at: self.sent_at,
             trace: self.trace,
             required_features: self.required_features,
+            relay_path: self.relay_path,
             other: self.other,
         })
     }
@@ -204,6 +213,7 @@ impl Envelope {
                 other: BTreeMap::new(),
                 trace: None,
                 required_features: smallvec::smallvec![],
+                relay_path: Vec::new(),
             },
             items: Items::new(),
         })
@@ -410,6 +420,24 @@ impl Envelope {
         self.headers.required_features.push(feature)
     }
 
+    /// The Relays that buffered this envelope, in the order in which they received it.
+    pub fn relay_path(&self) -> &[RelayId] {
+        &self.headers.relay_path
+    }
+
+    /// Appends a Relay to the path of this envelope.
+    ///
+    /// Returns `false` without changing the path if the Relay is already part of it, which means
+    /// that the envelope is forwarded in a loop.
+    pub fn append_relay_path(&mut self, relay_id: RelayId) -> bool {
+        if self.headers.relay_path.contains(&relay_id) {
+            return false;
+        }
+
+        self.headers.relay_path.push(relay_id);
+        true
+    }
+
     /// Returns the specified header value, if present.
     #[cfg_attr(not(feature = "processing"), allow(dead_code))]
     pub fn get_header<K>(&self, name: &K) -> Option<&Value>
@@ -5,7 +5,7 @@ use axum::extract::{ConnectInfo, FromRequestParts};
 use axum::http::request::Parts;
 use axum::http::HeaderMap;
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub struct ForwardedFor(String);
 
 impl ForwardedFor {
@@ -16,7 +16,7 @@ use relay_auth::RelayId;
 use relay_base_schema::organization::OrganizationId;
 use relay_base_schema::project::{ParseProjectKeyError, ProjectId, ProjectKey};
 use relay_common::{Auth, Dsn, ParseAuthError, ParseDsnError, Scheme};
-use relay_config::UpstreamDescriptor;
+use relay_config::{Config, UpstreamDescriptor};
 use relay_event_normalization::{ClientHints, RawUserAgentInfo};
 use relay_quotas::Scoping;
 use serde::{Deserialize, Serialize};
@@ -236,6 +236,12 @@ pub struct RequestMeta<D = PartialDsn> {
     /// NOTE: This is internal-only and not exposed to Envelope headers.
     #[serde(skip)]
     from_internal_relay: bool,
+
+    /// The envelope buffer partition requested in the `X-Relay-Partition` header.
+    ///
+    /// NOTE: This is internal-only and not exposed to Envelope headers.
+    #[serde(skip)]
+    partition: Option<u8>,
 }
 
 impl<D> RequestMeta<D> {
@@ -324,6 +330,11 @@ impl<D> RequestMeta<D> {
         self.from_internal_relay = value;
     }
 
+    /// Returns the envelope buffer partition requested by the client, if valid.
+    pub fn partition(&self) -> Option<u8> {
+        self.partition
+    }
+
     /// Sets the client for this [`RequestMeta`] on the current envelope.
     pub fn set_client(&mut self, client: String) {
         self.client = Some(client);
@@ -345,6 +356,7 @@ impl RequestMeta {
             received_at: Utc::now(),
             client_hints: ClientHints::default(),
             from_internal_relay: false,
+            partition: None,
         }
     }
 
@@ -449,6 +461,9 @@ impl PartialMeta {
         if self.from_internal_relay {
             complete.from_internal_relay = self.from_internal_relay;
         }
+        if self.partition.is_some() {
+            complete.partition = self.partition;
+        }
 
         complete.client_hints.copy_from(self.client_hints);
 
@@ -508,10 +523,37 @@ impl FromRequestParts<ServiceState> for PartialMeta {
             received_at,
             client_hints: ua.client_hints,
             from_internal_relay,
+            partition: parse_partition_header(parts, state.config()),
         })
     }
 }
 
+/// Parses the envelope buffer partition requested in the `X-Relay-Partition` header.
+///
+/// Returns `None` if routing by header is disabled, the header is missing, or the header does not
+/// name one of the configured partitions. Invalid values are logged and fall back to hashing.
+fn parse_partition_header(parts: &Parts, config: &Config) -> Option<u8> {
+    if !config.spool_envelopes_partition_routing_header() {
+        return None;
+    }
+
+    let value = parts.headers.get("x-relay-partition")?;
+    let partition = value
+        .to_str()
+        .ok()
+        .and_then(|value| value.trim().parse::<u8>().ok())
+        .filter(|&partition| partition < config.spool_partitions().get());
+
+    if partition.is_none() {
+        relay_log::warn!(
+            header = ?value,
+            "invalid buffer partition in X-Relay-Partition header"
+        );
+    }
+
+    partition
+}
+
 fn get_auth_header(req: &Parts, header_name: impl AsHeaderName) -> Option<&str> {
     req.headers
         .get(header_name)
@@ -670,6 +712,7 @@ impl FromRequestParts<ServiceState> for RequestMeta {
             received_at: partial_meta.received_at,
             client_hints: partial_meta.client_hints,
             from_internal_relay: partial_meta.from_internal_relay,
+            partition: partial_meta.partition,
         })
     }
 }
@@ -693,8 +736,66 @@ mod tests {
                 received_at: Utc::now(),
                 client_hints: ClientHints::default(),
                 from_internal_relay: false,
+                partition: None,
+            }
+        }
+
+        pub fn set_partition(&mut self, partition: Option<u8>) {
+            self.partition = partition;
+        }
+    }
+
+    fn partition_header(value: Option<&str>) -> Option<u8> {
+        let config = Config::from_json_value(serde_json::json!({
+            "spool": {
+                "envelopes": {
+                    "partitions": 4,
+                    "partition_routing_header": true
+                }
             }
+        }))
+        .unwrap();
+
+        let mut request = axum::http::Request::builder();
+        if let Some(value) = value {
+            request = request.header("X-Relay-Partition", value);
         }
+        let (parts, _) = request.body(()).unwrap().into_parts();
+
+        parse_partition_header(&parts, &config)
+    }
+
+    #[test]
+    fn test_partition_header_valid() {
+        assert_eq!(partition_header(Some("0")), Some(0));
+        assert_eq!(partition_header(Some(" 3 ")), Some(3));
+    }
+
+    #[test]
+    fn test_partition_header_invalid() {
+        assert_eq!(partition_header(Some("4")), None);
+        assert_eq!(partition_header(Some("-1")), None);
+        assert_eq!(partition_header(Some("first")), None);
+    }
+
+    #[test]
+    fn test_partition_header_absent() {
+        assert_eq!(partition_header(None), None);
+    }
+
+    #[test]
+    fn test_partition_header_disabled() {
+        let config = Config::from_json_value(serde_json::json!({
+            "spool": {"envelopes": {"partitions": 4}}
+        }))
+        .unwrap();
+        let (parts, _) = axum::http::Request::builder()
+            .header("X-Relay-Partition", "1")
+            .body(())
+            .unwrap()
+            .into_parts();
+
+        assert_eq!(parse_partition_header(&parts, &config), None);
     }
 
     #[test]
@@ -738,6 +839,7 @@ mod tests {
                 sec_ch_ua_model: None,
             },
             from_internal_relay: false,
+            partition: None,
         };
         deserialized.received_at = reqmeta.received_at;
         assert_eq!(deserialized, reqmeta);
@@ -268,7 +268,8 @@ mod utils;
 
 pub use self::envelope::Envelope; // pub for benchmarks
 pub use self::services::buffer::{
-    EnvelopeStack, PolymorphicEnvelopeBuffer, SqliteEnvelopeStack, SqliteEnvelopeStore,
+    DefaultCodec, EnvelopeStack, PolymorphicEnvelopeBuffer, SqliteEnvelopeStack,
+    SqliteEnvelopeStore,
 }; // pub for benchmarks
 pub use self::utils::{MemoryChecker, MemoryStat}; // pub for benchmarks
 
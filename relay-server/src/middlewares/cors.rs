@@ -1,14 +1,56 @@
 use std::time::Duration;
 
-use axum::http::{HeaderName, Method};
-use tower_http::cors::{Any, CorsLayer};
+use axum::http::{HeaderName, HeaderValue, Method};
+use relay_config::{CorsOrigin, CorsPolicy};
+use tower_http::cors::{AllowMethods, AllowOrigin, Any, CorsLayer};
 
 /// Creates a preconfigured CORS middleware builder for store requests.
 ///
 /// To configure CORS, register endpoints using `resource()` and finalize by calling `register()`,
 /// which returns an App. This configures POST as allowed method, allows default sentry headers, and
 /// exposes the return headers.
-pub fn cors() -> CorsLayer {
+///
+/// If a [`CorsPolicy`] is given, its allowed origins and methods replace the defaults.
+pub fn cors(policy: Option<&CorsPolicy>) -> CorsLayer {
+    let layer = default_cors();
+
+    match policy {
+        Some(policy) => layer
+            .allow_origin(allow_origin(policy))
+            .allow_methods(allow_methods(policy)),
+        None => layer,
+    }
+}
+
+fn allow_origin(policy: &CorsPolicy) -> AllowOrigin {
+    if policy.allowed_origins.contains(&CorsOrigin::Any) {
+        return AllowOrigin::any();
+    }
+
+    // Origins are validated when loading the config, so they are always valid header values.
+    let origins = policy
+        .allowed_origins
+        .iter()
+        .filter_map(|origin| HeaderValue::from_str(&origin.to_string()).ok());
+
+    AllowOrigin::list(origins)
+}
+
+fn allow_methods(policy: &CorsPolicy) -> AllowMethods {
+    let methods = policy.allowed_methods.iter().filter_map(|method| {
+        match Method::from_bytes(method.as_bytes()) {
+            Ok(method) => Some(method),
+            Err(_) => {
+                relay_log::error!("ignoring invalid CORS method {method}");
+                None
+            }
+        }
+    });
+
+    AllowMethods::list(methods)
+}
+
+fn default_cors() -> CorsLayer {
     CorsLayer::new()
         // This should also contain GET for the /store/ endpoint. Axum emits a correct "allow"
         // header for this. In practice, this is not an issue, so we can be more restrictive.
@@ -12,6 +12,7 @@ mod decompression;
 mod handle_panic;
 mod metrics;
 mod normalize_path;
+mod retry_after;
 mod trace;
 
 mod body_timing;
@@ -22,4 +23,5 @@ pub use self::decompression::*;
 pub use self::handle_panic::*;
 pub use self::metrics::*;
 pub use self::normalize_path::*;
+pub use self::retry_after::*;
 pub use self::trace::*;
@@ -0,0 +1,94 @@
+use std::time::Duration;
+
+use axum::http::{header, HeaderValue, Response, StatusCode};
+use rand::Rng;
+
+/// Adds a random delay of up to `jitter` to the `Retry-After` header of rejected requests.
+///
+/// Only responses with `429 Too Many Requests` or `503 Service Unavailable` are changed, and only
+/// if they carry a `Retry-After` header in the delay-seconds format. This spreads out the retries
+/// of clients that were rejected at the same time.
+///
+/// Use this with [`tower::ServiceBuilder::map_response`].
+pub fn jitter_retry_after<B>(mut response: Response<B>, jitter: Duration) -> Response<B> {
+    let jitter = jitter.as_secs();
+    if jitter == 0 {
+        return response;
+    }
+
+    let status = response.status();
+    if status != StatusCode::TOO_MANY_REQUESTS && status != StatusCode::SERVICE_UNAVAILABLE {
+        return response;
+    }
+
+    let headers = response.headers_mut();
+    let Some(retry_after) = headers
+        .get(header::RETRY_AFTER)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<u64>().ok())
+    else {
+        return response;
+    };
+
+    let delay = retry_after.saturating_add(rand::thread_rng().gen_range(0..=jitter));
+    headers.insert(header::RETRY_AFTER, HeaderValue::from(delay));
+
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeSet;
+
+    use super::*;
+
+    fn retry_after(status: StatusCode, value: &'static str, jitter: u64) -> String {
+        let response = Response::builder()
+            .status(status)
+            .header(header::RETRY_AFTER, value)
+            .body(())
+            .unwrap();
+
+        let response = jitter_retry_after(response, Duration::from_secs(jitter));
+        response.headers()[header::RETRY_AFTER]
+            .to_str()
+            .unwrap()
+            .to_owned()
+    }
+
+    #[test]
+    fn test_jitter_within_band() {
+        let values: BTreeSet<u64> = (0..200)
+            .map(|_| {
+                retry_after(StatusCode::SERVICE_UNAVAILABLE, "10", 5)
+                    .parse()
+                    .unwrap()
+            })
+            .collect();
+
+        assert!(values.iter().all(|value| (10..=15).contains(value)));
+        assert!(values.len() > 1);
+    }
+
+    #[test]
+    fn test_jitter_rate_limited() {
+        let value: u64 = retry_after(StatusCode::TOO_MANY_REQUESTS, "60", 3)
+            .parse()
+            .unwrap();
+        assert!((60..=63).contains(&value));
+    }
+
+    #[test]
+    fn test_no_jitter() {
+        assert_eq!(retry_after(StatusCode::SERVICE_UNAVAILABLE, "10", 0), "10");
+        assert_eq!(retry_after(StatusCode::OK, "10", 5), "10");
+        assert_eq!(
+            retry_after(
+                StatusCode::SERVICE_UNAVAILABLE,
+                "Wed, 21 Oct 2015 07:28:00 GMT",
+                5
+            ),
+            "Wed, 21 Oct 2015 07:28:00 GMT"
+        );
+    }
+}
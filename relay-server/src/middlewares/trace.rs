@@ -1,7 +1,31 @@
+use axum::body::Body;
+use axum::http::Request;
 use relay_log::Level;
 use tower_http::classify::{ServerErrorsAsFailures, SharedClassifier};
 use tower_http::trace::{DefaultOnFailure, TraceLayer};
+use tracing::Span;
 
-pub fn trace_http_layer() -> TraceLayer<SharedClassifier<ServerErrorsAsFailures>> {
-    TraceLayer::new_for_http().on_failure(DefaultOnFailure::new().level(Level::DEBUG))
+/// Function creating the span of a request.
+pub type MakeRequestSpan = fn(&Request<Body>) -> Span;
+
+pub fn trace_http_layer() -> TraceLayer<SharedClassifier<ServerErrorsAsFailures>, MakeRequestSpan> {
+    TraceLayer::new_for_http()
+        .make_span_with(make_request_span as MakeRequestSpan)
+        .on_failure(DefaultOnFailure::new().level(Level::DEBUG))
+}
+
+/// Creates the span of a request, which is the access log entry of the request.
+///
+/// In addition to the fields of the default span, this declares fields that handlers record once
+/// they are known:
+///
+///  - `partition`: The envelope buffer partition an ingested envelope was queued in.
+fn make_request_span(request: &Request<Body>) -> Span {
+    tracing::debug_span!(
+        "request",
+        method = %request.method(),
+        uri = %request.uri(),
+        version = ?request.version(),
+        partition = tracing::field::Empty,
+    )
 }
@@ -4,9 +4,7 @@ use std::time::Duration;
 
 use crate::metrics::{MetricOutcomes, MetricStats};
 use crate::services::autoscaling::{AutoscalingMetricService, AutoscalingMetrics};
-use crate::services::buffer::{
-    ObservableEnvelopeBuffer, PartitionedEnvelopeBuffer, ProjectKeyPair,
-};
+use crate::services::buffer::PartitionedEnvelopeBuffer;
 use crate::services::cogs::{CogsService, CogsServiceRecorder};
 use crate::services::global_config::{GlobalConfigManager, GlobalConfigService};
 #[cfg(feature = "processing")]
@@ -26,7 +24,7 @@ use crate::services::stats::RelayStats;
 use crate::services::store::{StoreService, StoreServicePool};
 use crate::services::test_store::{TestStore, TestStoreService};
 use crate::services::upstream::{UpstreamRelay, UpstreamRelayService};
-use crate::utils::{MemoryChecker, MemoryStat, ThreadKind};
+use crate::utils::{IngestQuotaLimiter, MemoryChecker, MemoryStat, ThreadKind};
 #[cfg(feature = "processing")]
 use anyhow::Context;
 use anyhow::Result;
@@ -140,6 +138,7 @@ fn create_store_pool(config: &Config) -> Result<StoreServicePool> {
 struct StateInner {
     config: Arc<Config>,
     memory_checker: MemoryChecker,
+    ingest_quota: IngestQuotaLimiter,
     registry: Registry,
 }
 
@@ -156,7 +155,9 @@ impl ServiceState {
         services: &dyn ServiceSpawn,
         config: Arc<Config>,
     ) -> Result<Self> {
-        let upstream_relay = services.start(UpstreamRelayService::new(config.clone()));
+        let upstream_relay = UpstreamRelayService::new(config.clone());
+        let upstream_encodings = upstream_relay.encodings();
+        let upstream_relay = services.start(upstream_relay);
         let test_store = services.start(TestStoreService::new(config.clone()));
 
         #[cfg(feature = "processing")]
@@ -268,6 +269,7 @@ impl ServiceState {
                 processor::Addrs {
                     outcome_aggregator: outcome_aggregator.clone(),
                     upstream_relay: upstream_relay.clone(),
+                    upstream_encodings,
                     test_store: test_store.clone(),
                     #[cfg(feature = "processing")]
                     store_forwarder: store.clone(),
@@ -340,6 +342,7 @@ impl ServiceState {
         let state = StateInner {
             config: config.clone(),
             memory_checker: MemoryChecker::new(memory_stat, config.clone()),
+            ingest_quota: IngestQuotaLimiter::new(config.ingest_quota().cloned()),
             registry,
         };
 
@@ -360,13 +363,18 @@ impl ServiceState {
         &self.inner.memory_checker
     }
 
+    /// Returns the limiter of the per-key quota enforced at ingest.
+    pub fn ingest_quota(&self) -> &IngestQuotaLimiter {
+        &self.inner.ingest_quota
+    }
+
     pub fn autoscaling(&self) -> &Addr<AutoscalingMetrics> {
         &self.inner.registry.autoscaling
     }
 
-    /// Returns the V2 envelope buffer, if present.
-    pub fn envelope_buffer(&self, project_key_pair: ProjectKeyPair) -> &ObservableEnvelopeBuffer {
-        self.inner.registry.envelope_buffer.buffer(project_key_pair)
+    /// Returns all partitions of the V2 envelope buffer.
+    pub fn envelope_buffers(&self) -> &PartitionedEnvelopeBuffer {
+        &self.inner.registry.envelope_buffer
     }
 
     /// Returns a [`ProjectCacheHandle`].
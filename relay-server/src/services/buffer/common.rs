@@ -1,5 +1,12 @@
+use std::error::Error;
+
+use chrono::{DateTime, Utc};
 use relay_base_schema::project::ProjectKey;
+use relay_config::Config;
+use relay_event_schema::protocol::EventId;
+use serde::Serialize;
 
+use crate::services::processor::ProcessingGroup;
 use crate::Envelope;
 
 /// Struct that represents two project keys.
@@ -7,6 +14,10 @@ use crate::Envelope;
 pub struct ProjectKeyPair {
     pub own_key: ProjectKey,
     pub sampling_key: ProjectKey,
+    /// The processing group of the envelopes if they are split into a stack per group.
+    ///
+    /// See `spool.envelopes.split_processing_groups`.
+    pub group: Option<ProcessingGroup>,
 }
 
 impl ProjectKeyPair {
@@ -14,6 +25,15 @@ impl ProjectKeyPair {
         Self {
             own_key,
             sampling_key,
+            group: None,
+        }
+    }
+
+    /// Returns the key of the stack that holds the envelopes of the given processing group.
+    pub fn with_group(self, group: ProcessingGroup) -> Self {
+        Self {
+            group: Some(group),
+            ..self
         }
     }
 
@@ -21,6 +41,12 @@ impl ProjectKeyPair {
         self.own_key != self.sampling_key
     }
 
+    /// Returns the project key pair of an envelope.
+    ///
+    /// This cannot fail: the own key comes from the envelope's DSN and the sampling key from its
+    /// dynamic sampling context, both of which are validated when the envelope is parsed. An
+    /// envelope with a missing or malformed key is rejected by the endpoint and never reaches the
+    /// buffer.
     pub fn from_envelope(envelope: &Envelope) -> Self {
         let own_key = envelope.meta().public_key();
         let sampling_key = envelope.sampling_key().unwrap_or(own_key);
@@ -32,12 +58,93 @@ impl ProjectKeyPair {
         let Self {
             own_key,
             sampling_key,
+            ..
         } = self;
 
         std::iter::once(*own_key).chain((own_key != sampling_key).then_some(*sampling_key))
     }
 }
 
+/// Summary of a buffered envelope that does not contain any payload data.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct EnvelopePreview {
+    /// The event id of the envelope, if it has one.
+    pub event_id: Option<EventId>,
+    /// The project key of the project to which the envelope belongs.
+    pub own_key: ProjectKey,
+    /// The time at which the envelope was received.
+    pub received_at: DateTime<Utc>,
+    /// The time at which the envelope was sent by the client, if known.
+    pub sent_at: Option<DateTime<Utc>>,
+    /// The items of the envelope.
+    pub items: Vec<ItemPreview>,
+}
+
+impl EnvelopePreview {
+    /// Creates a preview of the envelope.
+    pub fn new(envelope: &Envelope) -> Self {
+        Self {
+            event_id: envelope.event_id(),
+            own_key: envelope.meta().public_key(),
+            received_at: envelope.received_at(),
+            sent_at: envelope.sent_at(),
+            items: envelope
+                .items()
+                .map(|item| ItemPreview {
+                    ty: item.ty().as_str().to_owned(),
+                    size: item.len(),
+                })
+                .collect(),
+        }
+    }
+}
+
+/// Type and size of an item in an [`EnvelopePreview`].
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct ItemPreview {
+    /// The item type.
+    #[serde(rename = "type")]
+    pub ty: String,
+    /// The size of the item payload in bytes.
+    pub size: usize,
+}
+
+/// Returns the value of the `partition_id` tag reported on metrics of the given partition.
+///
+/// The partition id is prefixed with `metrics.buffer_prefix`, if configured.
+pub fn partition_tag(partition_id: u8, config: &Config) -> String {
+    match config.metrics_buffer_prefix() {
+        Some(prefix) => format!("{prefix}{partition_id}"),
+        None => partition_id.to_string(),
+    }
+}
+
+/// Parses project keys from the configuration, skipping and logging invalid keys.
+///
+/// `purpose` describes the configuration option in the log message, e.g. `"hot project"`.
+pub fn parse_project_keys<'a, C>(
+    project_keys: impl IntoIterator<Item = &'a String>,
+    purpose: &str,
+) -> C
+where
+    C: FromIterator<ProjectKey>,
+{
+    project_keys
+        .into_iter()
+        .filter_map(|project_key| match ProjectKey::parse(project_key) {
+            Ok(project_key) => Some(project_key),
+            Err(error) => {
+                relay_log::warn!(
+                    error = &error as &dyn Error,
+                    "ignoring invalid {purpose} key {project_key}"
+                );
+                None
+            }
+        })
+        .collect()
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -0,0 +1,273 @@
+//! A portable archive format for buffered envelopes.
+//!
+//! The archive decouples the transfer of buffered envelopes from the on-disk format of the spool,
+//! for example to migrate envelopes between Relay versions or machines. It consists of a header
+//! followed by a sequence of records:
+//!
+//! ```text
+//! header: magic (8 bytes) | version (u8) | codec id (u8)
+//! record: length (u32, little-endian) | envelope encoded with the codec (length bytes)
+//! ```
+//!
+//! The archive ends after the last complete record. Records are read up to a maximum size, so
+//! that a corrupt length cannot cause an arbitrarily large allocation.
+
+use std::io::{self, Read, Write};
+
+use crate::envelope::Envelope;
+use crate::services::buffer::envelope_store::codec::{codec_by_id, CodecId, EnvelopeCodec};
+use crate::services::buffer::envelope_store::sqlite::InsertEnvelopeError;
+
+/// Magic bytes at the start of every envelope archive.
+const ARCHIVE_MAGIC: &[u8; 8] = b"RELAYBUF";
+
+/// The version of the archive format written by this Relay.
+///
+/// Bump this when the layout of the header or records changes. Older versions that are still
+/// readable must remain supported in [`ArchiveReader::new`].
+const ARCHIVE_VERSION: u8 = 1;
+
+/// The oldest archive version that can still be read.
+const MIN_ARCHIVE_VERSION: u8 = 1;
+
+/// An error that occurs while reading or writing an envelope archive.
+#[derive(Debug, thiserror::Error)]
+pub enum ArchiveError {
+    #[error("failed to read or write the archive")]
+    Io(#[from] io::Error),
+
+    #[error("not an envelope archive")]
+    InvalidHeader,
+
+    #[error(
+        "unsupported archive version {0}, expected {MIN_ARCHIVE_VERSION} to {ARCHIVE_VERSION}"
+    )]
+    UnsupportedVersion(u8),
+
+    #[error("unknown envelope codec {0} in archive")]
+    UnknownCodec(CodecId),
+
+    #[error("record of {0} bytes exceeds the maximum record size")]
+    RecordTooLarge(usize),
+
+    #[error("failed to encode or decode an envelope")]
+    Envelope(#[from] InsertEnvelopeError),
+}
+
+/// Writes envelopes into an archive.
+#[derive(Debug)]
+pub struct ArchiveWriter<W> {
+    writer: W,
+    codec: &'static dyn EnvelopeCodec,
+}
+
+impl<W: Write> ArchiveWriter<W> {
+    /// Creates a new archive and writes its header.
+    pub fn new(mut writer: W, codec: &'static dyn EnvelopeCodec) -> Result<Self, ArchiveError> {
+        writer.write_all(ARCHIVE_MAGIC)?;
+        writer.write_all(&[ARCHIVE_VERSION, codec.id()])?;
+        Ok(Self { writer, codec })
+    }
+
+    /// Appends an envelope to the archive.
+    pub fn write(&mut self, envelope: &Envelope) -> Result<(), ArchiveError> {
+        let encoded = self.codec.encode(envelope)?;
+        let len = u32::try_from(encoded.len())
+            .map_err(|_| ArchiveError::RecordTooLarge(encoded.len()))?;
+
+        self.writer.write_all(&len.to_le_bytes())?;
+        self.writer.write_all(&encoded)?;
+        Ok(())
+    }
+
+    /// Flushes the archive and returns the underlying writer.
+    pub fn finish(mut self) -> Result<W, ArchiveError> {
+        self.writer.flush()?;
+        Ok(self.writer)
+    }
+}
+
+/// Reads envelopes from an archive.
+#[derive(Debug)]
+pub struct ArchiveReader<R> {
+    reader: R,
+    codec: &'static dyn EnvelopeCodec,
+    max_record_size: usize,
+}
+
+impl<R: Read> ArchiveReader<R> {
+    /// Opens an archive and validates its header.
+    ///
+    /// Returns an error if the archive was written with an unsupported version or codec, before
+    /// any envelopes are read. Records larger than `max_record_size` are rejected when they are
+    /// read.
+    pub fn new(mut reader: R, max_record_size: usize) -> Result<Self, ArchiveError> {
+        let mut magic = [0; ARCHIVE_MAGIC.len()];
+        read_exact_or_invalid(&mut reader, &mut magic)?;
+        if &magic != ARCHIVE_MAGIC {
+            return Err(ArchiveError::InvalidHeader);
+        }
+
+        let mut header = [0; 2];
+        read_exact_or_invalid(&mut reader, &mut header)?;
+        let [version, codec_id] = header;
+
+        if !(MIN_ARCHIVE_VERSION..=ARCHIVE_VERSION).contains(&version) {
+            return Err(ArchiveError::UnsupportedVersion(version));
+        }
+        let codec = codec_by_id(codec_id).ok_or(ArchiveError::UnknownCodec(codec_id))?;
+
+        Ok(Self {
+            reader,
+            codec,
+            max_record_size,
+        })
+    }
+
+    /// Reads the next envelope, or `None` at the end of the archive.
+    pub fn read(&mut self) -> Result<Option<Box<Envelope>>, ArchiveError> {
+        let mut len = [0; 4];
+        if !read_record_start(&mut self.reader, &mut len)? {
+            return Ok(None);
+        }
+
+        let len = u32::from_le_bytes(len) as usize;
+        if len > self.max_record_size {
+            return Err(ArchiveError::RecordTooLarge(len));
+        }
+
+        let mut encoded = vec![0; len];
+        self.reader.read_exact(&mut encoded)?;
+
+        Ok(Some(self.codec.decode(encoded.into_boxed_slice())?))
+    }
+}
+
+/// Reads a header field, treating a premature end of the input as an invalid header.
+fn read_exact_or_invalid(reader: &mut impl Read, buf: &mut [u8]) -> Result<(), ArchiveError> {
+    reader.read_exact(buf).map_err(|error| match error.kind() {
+        io::ErrorKind::UnexpectedEof => ArchiveError::InvalidHeader,
+        _ => ArchiveError::Io(error),
+    })
+}
+
+/// Fills `buf` with the start of the next record.
+///
+/// Returns `false` if the input ends cleanly before the record. A record that is cut off is an
+/// error.
+fn read_record_start(reader: &mut impl Read, buf: &mut [u8]) -> io::Result<bool> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match reader.read(&mut buf[filled..]) {
+            Ok(0) if filled == 0 => return Ok(false),
+            Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+            Ok(n) => filled += n,
+            Err(error) if error.kind() == io::ErrorKind::Interrupted => continue,
+            Err(error) => return Err(error),
+        }
+    }
+
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::services::buffer::envelope_store::codec::{DefaultCodec, MsgpackCodec};
+    use crate::services::buffer::testutils::utils::mock_envelopes;
+
+    fn read_all(data: &[u8]) -> Result<Vec<Box<Envelope>>, ArchiveError> {
+        let mut reader = ArchiveReader::new(data, usize::MAX)?;
+        let mut envelopes = Vec::new();
+        while let Some(envelope) = reader.read()? {
+            envelopes.push(envelope);
+        }
+        Ok(envelopes)
+    }
+
+    #[test]
+    fn test_round_trip() {
+        for codec in [&DefaultCodec as &'static dyn EnvelopeCodec, &MsgpackCodec] {
+            let envelopes = mock_envelopes(3);
+
+            let mut writer = ArchiveWriter::new(Vec::new(), codec).unwrap();
+            for envelope in &envelopes {
+                writer.write(envelope).unwrap();
+            }
+            let data = writer.finish().unwrap();
+
+            let read = read_all(&data).unwrap();
+            assert_eq!(read.len(), envelopes.len());
+            for (read, envelope) in read.iter().zip(&envelopes) {
+                assert_eq!(read.to_vec().unwrap(), envelope.to_vec().unwrap());
+            }
+        }
+    }
+
+    #[test]
+    fn test_empty_archive() {
+        let data = ArchiveWriter::new(Vec::new(), &DefaultCodec)
+            .unwrap()
+            .finish()
+            .unwrap();
+        assert!(read_all(&data).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_invalid_header() {
+        assert!(matches!(
+            read_all(b"SQLite format 3\0"),
+            Err(ArchiveError::InvalidHeader)
+        ));
+        assert!(matches!(
+            read_all(b"RELAY"),
+            Err(ArchiveError::InvalidHeader)
+        ));
+    }
+
+    #[test]
+    fn test_unsupported_version() {
+        let mut data = ARCHIVE_MAGIC.to_vec();
+        data.extend([ARCHIVE_VERSION + 1, DefaultCodec.id()]);
+
+        assert!(matches!(
+            read_all(&data),
+            Err(ArchiveError::UnsupportedVersion(v)) if v == ARCHIVE_VERSION + 1
+        ));
+    }
+
+    #[test]
+    fn test_unknown_codec() {
+        let mut data = ARCHIVE_MAGIC.to_vec();
+        data.extend([ARCHIVE_VERSION, u8::MAX]);
+
+        assert!(matches!(
+            read_all(&data),
+            Err(ArchiveError::UnknownCodec(u8::MAX))
+        ));
+    }
+
+    #[test]
+    fn test_record_too_large() {
+        let mut data = ARCHIVE_MAGIC.to_vec();
+        data.extend([ARCHIVE_VERSION, DefaultCodec.id()]);
+        // The record claims to be 4 GiB long, but the reader must not allocate that.
+        data.extend(u32::MAX.to_le_bytes());
+
+        let mut reader = ArchiveReader::new(data.as_slice(), 1024).unwrap();
+        assert!(matches!(
+            reader.read(),
+            Err(ArchiveError::RecordTooLarge(len)) if len == u32::MAX as usize
+        ));
+    }
+
+    #[test]
+    fn test_truncated_record() {
+        let mut writer = ArchiveWriter::new(Vec::new(), &DefaultCodec).unwrap();
+        writer.write(&mock_envelopes(1)[0]).unwrap();
+        let mut data = writer.finish().unwrap();
+        data.truncate(data.len() - 1);
+
+        assert!(matches!(read_all(&data), Err(ArchiveError::Io(_))));
+    }
+}
@@ -1,27 +1,68 @@
-use std::cmp::Ordering;
-use std::collections::BTreeSet;
+use std::cmp::{Ordering, Reverse};
+use std::collections::{BTreeSet, BinaryHeap, VecDeque};
 use std::convert::Infallible;
 use std::error::Error;
+use std::hash::{Hash, Hasher};
+use std::io::{Read, Write};
 use std::mem;
+use std::num::{NonZeroU32, NonZeroU64, NonZeroUsize};
+use std::path::Path;
+use std::sync::Arc;
 use std::time::Duration;
 
 use chrono::{DateTime, Utc};
+use futures::future::LocalBoxFuture;
+use futures::{FutureExt, Stream, StreamExt};
 use hashbrown::HashSet;
 use relay_base_schema::project::ProjectKey;
-use relay_config::Config;
+use relay_config::{Config, EnvelopeSpoolInitErrorPolicy};
+use relay_event_schema::protocol::EventId;
+use serde::{Deserialize, Serialize};
 use tokio::time::{timeout, Instant};
+use uuid::Uuid;
 
 use crate::envelope::Envelope;
-use crate::envelope::Item;
-use crate::services::buffer::common::ProjectKeyPair;
+use crate::envelope::{Item, ItemType};
+use crate::extractors::RequestMeta;
+use crate::services::buffer::common::{
+    parse_project_keys, partition_tag, EnvelopePreview, ProjectKeyPair,
+};
+use crate::services::buffer::envelope_buffer::archive::{
+    ArchiveError, ArchiveReader, ArchiveWriter,
+};
+use crate::services::buffer::envelope_buffer::policy::{
+    DefaultPolicy, PriorityClass, SchedulingPolicy, StackMeta,
+};
 use crate::services::buffer::envelope_stack::sqlite::SqliteEnvelopeStackError;
-use crate::services::buffer::envelope_stack::EnvelopeStack;
+use crate::services::buffer::envelope_stack::{self, EnvelopeStack};
+use crate::services::buffer::envelope_store::codec::DefaultCodec;
 use crate::services::buffer::envelope_store::sqlite::SqliteEnvelopeStoreError;
+use crate::services::buffer::hot_projects::HotProjects;
 use crate::services::buffer::stack_provider::memory::MemoryStackProvider;
 use crate::services::buffer::stack_provider::sqlite::SqliteStackProvider;
 use crate::services::buffer::stack_provider::{StackCreationType, StackProvider};
-use crate::statsd::{RelayGauges, RelayHistograms, RelayTimers};
-use crate::utils::MemoryChecker;
+use crate::services::outcome::{DiscardItemType, DiscardReason};
+use crate::services::processor::ProcessingGroup;
+use crate::statsd::{RelayCounters, RelayGauges, RelayHistograms, RelayTimers};
+use crate::utils::{self, MemoryChecker, MemoryStat};
+
+mod archive;
+mod policy;
+
+/// Maximum number of stacks sampled by a sweep of [`EnvelopeBuffer::sample_envelope_ages`].
+const MAX_AGE_SAMPLES: usize = 100;
+
+/// Maximum number of stacks inspected to compute the [`BalanceStats`] of a buffer.
+const MAX_BALANCE_SAMPLES: usize = 1000;
+
+/// Number of envelopes moved at once when [`PolymorphicEnvelopeBuffer::reload_config`] migrates
+/// a buffer to another backend.
+const MIGRATION_BATCH_SIZE: usize = 100;
+
+/// DSN of the synthetic envelope used by the buffer self-test.
+///
+/// The public key is reserved for the self-test and never belongs to a project.
+const SELF_TEST_DSN: &str = "https://00000000000000000000000000000000@localhost/0";
 
 /// Polymorphic envelope buffering interface.
 ///
@@ -50,6 +91,9 @@ impl PolymorphicEnvelopeBuffer {
 
     /// Creates either a memory-based or a disk-based envelope buffer,
     /// depending on the given configuration.
+    ///
+    /// If the disk-based buffer cannot be opened, `spool.envelopes.on_init_error` decides whether
+    /// this fails or falls back to a memory-based buffer.
     pub async fn from_config(
         partition_id: u8,
         config: &Config,
@@ -57,19 +101,163 @@ impl PolymorphicEnvelopeBuffer {
     ) -> Result<Self, EnvelopeBufferError> {
         let buffer = if config.spool_envelopes_path(partition_id).is_some() {
             relay_log::trace!("PolymorphicEnvelopeBuffer: initializing sqlite envelope buffer");
-            let buffer = EnvelopeBuffer::<SqliteStackProvider>::new(partition_id, config).await?;
-            Self::Sqlite(buffer)
+            if config.spool_envelopes_split_processing_groups() {
+                relay_log::warn!(
+                    "spool.envelopes.split_processing_groups is ignored by the disk-based buffer"
+                );
+            }
+            match EnvelopeBuffer::<SqliteStackProvider>::new(
+                partition_id,
+                config,
+                memory_checker.clone(),
+            )
+            .await
+            {
+                Ok(buffer) => Self::Sqlite(buffer),
+                Err(error)
+                    if config.spool_envelopes_on_init_error()
+                        == EnvelopeSpoolInitErrorPolicy::FallbackMemory =>
+                {
+                    relay_log::error!(
+                        error = &error as &dyn Error,
+                        partition_id,
+                        "failed to open the disk-based envelope buffer, buffering envelopes in memory"
+                    );
+                    let buffer = EnvelopeBuffer::<MemoryStackProvider>::new(
+                        partition_id,
+                        config,
+                        memory_checker,
+                    );
+                    Self::InMemory(buffer)
+                }
+                Err(error) => return Err(error),
+            }
         } else {
             relay_log::trace!("PolymorphicEnvelopeBuffer: initializing memory envelope buffer");
-            let buffer = EnvelopeBuffer::<MemoryStackProvider>::new(partition_id, memory_checker);
+            let buffer =
+                EnvelopeBuffer::<MemoryStackProvider>::new(partition_id, config, memory_checker);
             Self::InMemory(buffer)
         };
 
         Ok(buffer)
     }
 
+    /// Opens an existing spool file for replaying its envelopes.
+    ///
+    /// The returned buffer is initialized with all stacks found in the file and uses the default
+    /// configuration otherwise. Use [`Self::drain_all`] to read the envelopes, which removes them
+    /// from the file.
+    pub async fn from_path_for_replay(path: &Path) -> Result<Self, EnvelopeBufferError> {
+        let path = path
+            .to_str()
+            .ok_or(EnvelopeBufferError::InvalidReplayPath)?;
+        let config = Config::from_json_value(serde_json::json!({
+            "spool": {
+                "envelopes": {
+                    "path": path
+                }
+            }
+        }))
+        .map_err(|_| EnvelopeBufferError::InvalidReplayPath)?;
+        let config = Arc::new(config);
+        let memory_checker = MemoryChecker::new(MemoryStat::default(), config.clone());
+
+        let mut buffer =
+            EnvelopeBuffer::<SqliteStackProvider>::new(0, &config, memory_checker).await?;
+        buffer.initialize().await?;
+
+        Ok(Self::Sqlite(buffer))
+    }
+
+    /// Drains all envelopes from the buffer in priority order.
+    ///
+    /// In contrast to the envelope buffer service, this does not wait for projects to become
+    /// ready. Every stack is treated as ready and popped until the buffer is empty.
+    pub fn drain_all(
+        &mut self,
+    ) -> impl Stream<Item = Result<Box<Envelope>, EnvelopeBufferError>> + '_ {
+        futures::stream::try_unfold(self, |buffer| async move {
+            let envelope = buffer.pop().await?;
+            Ok::<_, EnvelopeBufferError>(envelope.map(|envelope| (envelope, buffer)))
+        })
+    }
+
+    /// Drains all envelopes from the buffer in the order they were received.
+    ///
+    /// See [`EnvelopeBuffer::iter_chronological`].
+    pub fn iter_chronological(
+        &mut self,
+    ) -> impl Stream<Item = Result<Box<Envelope>, EnvelopeBufferError>> + '_ {
+        match self {
+            Self::Sqlite(buffer) => buffer.iter_chronological().left_stream(),
+            Self::InMemory(buffer) => buffer.iter_chronological().right_stream(),
+        }
+    }
+
+    /// Pops ready envelopes as a stream, applying readiness changes as they arrive.
+    ///
+    /// See [`EnvelopeBuffer::pop_stream`].
+    pub fn pop_stream<'a, R>(
+        &'a mut self,
+        readiness: R,
+    ) -> impl Stream<Item = Result<Box<Envelope>, EnvelopeBufferError>> + 'a
+    where
+        R: Stream<Item = (ProjectKey, bool)> + Unpin + 'a,
+    {
+        match self {
+            Self::Sqlite(buffer) => buffer.pop_stream(readiness).left_stream(),
+            Self::InMemory(buffer) => buffer.pop_stream(readiness).right_stream(),
+        }
+    }
+
+    /// Exports all envelopes into a portable archive, removing them from the buffer.
+    ///
+    /// Envelopes are drained in the order of [`Self::pop`] and written with the [`DefaultCodec`],
+    /// independently of the codec configured for the spool. They are only removed from the buffer
+    /// once the archive has been written completely. Returns the number of exported envelopes.
+    ///
+    /// If writing fails, the disk-based buffer keeps the envelopes held, and redelivers them with
+    /// [`Self::redeliver_expired`]. The memory-based buffer cannot hold envelopes, so it returns
+    /// them with the error, so that they can be pushed back.
+    pub async fn export_archive<W: Write>(&mut self, writer: W) -> Result<u64, PartialFailure> {
+        let archive =
+            ArchiveWriter::new(writer, &DefaultCodec).map_err(|error| (error.into(), vec![]))?;
+
+        match self {
+            Self::Sqlite(buffer) => buffer
+                .export_archive(archive)
+                .await
+                .map_err(|error| (error, vec![])),
+            Self::InMemory(buffer) => buffer.export_archive(archive).await,
+        }
+    }
+
+    /// Imports all envelopes from an archive written by [`Self::export_archive`].
+    ///
+    /// The archive header is validated before any envelope is pushed, so an archive from an
+    /// unsupported version leaves the buffer untouched. Records larger than `max_envelope_size`
+    /// are rejected. Returns the envelopes that were evicted to stay within the maximum stack
+    /// depth.
+    pub async fn import_archive<R: Read>(
+        &mut self,
+        reader: R,
+        max_envelope_size: usize,
+    ) -> Result<Vec<Box<Envelope>>, EnvelopeBufferError> {
+        let mut archive = ArchiveReader::new(reader, max_envelope_size)?;
+
+        let mut evicted = Vec::new();
+        while let Some(envelope) = archive.read()? {
+            evicted.extend(self.push(envelope).await?);
+        }
+
+        Ok(evicted)
+    }
+
     /// Initializes the envelope buffer.
-    pub async fn initialize(&mut self) {
+    ///
+    /// Fails if the verification of the recovered stacks is enabled and finds more unrecoverable
+    /// envelopes than configured.
+    pub async fn initialize(&mut self) -> Result<(), EnvelopeBufferError> {
         match self {
             PolymorphicEnvelopeBuffer::InMemory(buffer) => buffer.initialize().await,
             PolymorphicEnvelopeBuffer::Sqlite(buffer) => buffer.initialize().await,
@@ -77,24 +265,138 @@ impl PolymorphicEnvelopeBuffer {
     }
 
     /// Adds an envelope to the buffer.
-    pub async fn push(&mut self, envelope: Box<Envelope>) -> Result<(), EnvelopeBufferError> {
-        relay_statsd::metric!(
-            histogram(RelayHistograms::BufferEnvelopeBodySize) =
-                envelope.items().map(Item::len).sum::<usize>() as u64,
-            partition_id = self.partition_tag()
-        );
+    ///
+    /// Returns the envelope that was evicted from the bottom of its stack to stay within the
+    /// configured maximum stack depth, if any.
+    pub async fn push(
+        &mut self,
+        envelope: Box<Envelope>,
+    ) -> Result<Option<Box<Envelope>>, EnvelopeBufferError> {
+        let project_key_pair = ProjectKeyPair::from_envelope(&envelope);
+        self.push_keyed(envelope, project_key_pair).await
+    }
+
+    /// Splits an envelope into an envelope per processing group, keyed by the stack it belongs to.
+    ///
+    /// Only the memory-based buffer splits envelopes, see
+    /// `spool.envelopes.split_processing_groups`. Otherwise, the envelope is returned unchanged.
+    /// Every returned envelope is admitted and pushed on its own, see [`Self::push_grouped`].
+    pub fn split_grouped(&self, envelope: Box<Envelope>) -> Vec<(ProjectKeyPair, Box<Envelope>)> {
+        let project_key_pair = ProjectKeyPair::from_envelope(&envelope);
+        if !matches!(self, Self::InMemory(buffer) if buffer.split_processing_groups) {
+            return vec![(project_key_pair, envelope)];
+        }
+
+        ProcessingGroup::split_envelope(*envelope)
+            .into_iter()
+            .map(|(group, envelope)| (project_key_pair.with_group(group), envelope))
+            .collect()
+    }
+
+    /// Adds envelopes returned by [`Self::split_grouped`] to their stacks.
+    ///
+    /// Returns the envelopes that were evicted from the bottom of their stacks to stay within the
+    /// configured maximum stack depth.
+    ///
+    /// If a push fails, the remaining envelopes are not pushed and are returned with the error.
+    pub async fn push_grouped(
+        &mut self,
+        envelopes: Vec<(ProjectKeyPair, Box<Envelope>)>,
+    ) -> Result<Vec<Box<Envelope>>, PushFailure> {
+        let mut evicted = Vec::new();
+        let mut envelopes = envelopes.into_iter();
+        while let Some((project_key_pair, envelope)) = envelopes.next() {
+            match self.push_keyed(envelope, project_key_pair).await {
+                Ok(pushed_evicted) => evicted.extend(pushed_evicted),
+                Err(error) => {
+                    return Err(PushFailure {
+                        error,
+                        evicted,
+                        unpushed: envelopes.map(|(_, envelope)| envelope).collect(),
+                    })
+                }
+            }
+        }
+
+        Ok(evicted)
+    }
+
+    /// Adds multiple envelopes to the buffer.
+    ///
+    /// Consecutive envelopes of the same stack are pushed at once, see
+    /// [`EnvelopeBuffer::push_all`]. If envelopes are split by processing group, they are pushed
+    /// individually instead. Returns the envelopes that were evicted from the bottom of their
+    /// stacks to stay within the configured maximum stack depth.
+    ///
+    /// If a push fails, the remaining envelopes are not pushed and are returned with the error.
+    pub async fn push_all(
+        &mut self,
+        envelopes: Vec<Box<Envelope>>,
+    ) -> Result<Vec<Box<Envelope>>, PushFailure> {
+        if matches!(self, Self::InMemory(buffer) if buffer.split_processing_groups) {
+            let mut evicted = Vec::new();
+            let mut envelopes = envelopes.into_iter();
+            while let Some(envelope) = envelopes.next() {
+                let split = self.split_grouped(envelope);
+                match self.push_grouped(split).await {
+                    Ok(split_evicted) => evicted.extend(split_evicted),
+                    Err(mut failure) => {
+                        evicted.append(&mut failure.evicted);
+                        failure.evicted = evicted;
+                        failure.unpushed.extend(envelopes);
+                        return Err(failure);
+                    }
+                }
+            }
+            return Ok(evicted);
+        }
+
+        for envelope in &envelopes {
+            self.sample_body_size(envelope);
+        }
 
         relay_statsd::metric!(
             timer(RelayTimers::BufferPush),
             partition_id = self.partition_tag(),
             {
                 match self {
-                    Self::Sqlite(buffer) => buffer.push(envelope).await,
-                    Self::InMemory(buffer) => buffer.push(envelope).await,
-                }?;
+                    Self::Sqlite(buffer) => buffer.push_all(envelopes).await,
+                    Self::InMemory(buffer) => buffer.push_all(envelopes).await,
+                }
+            }
+        )
+    }
+
+    /// Adds an envelope to the stack with the given key.
+    async fn push_keyed(
+        &mut self,
+        envelope: Box<Envelope>,
+        project_key_pair: ProjectKeyPair,
+    ) -> Result<Option<Box<Envelope>>, EnvelopeBufferError> {
+        self.sample_body_size(&envelope);
+
+        let evicted = relay_statsd::metric!(
+            timer(RelayTimers::BufferPush),
+            partition_id = self.partition_tag(),
+            {
+                match self {
+                    Self::Sqlite(buffer) => buffer.push_keyed(envelope, project_key_pair).await,
+                    Self::InMemory(buffer) => buffer.push_keyed(envelope, project_key_pair).await,
+                }?
             }
         );
-        Ok(())
+        Ok(evicted)
+    }
+
+    /// Reports the body size of a pushed envelope, subject to the configured sample rate.
+    fn sample_body_size(&self, envelope: &Envelope) {
+        if utils::sample(self.body_size_sample_rate()) {
+            relay_statsd::metric!(
+                histogram(RelayHistograms::BufferEnvelopeBodySize) =
+                    envelope.items().map(Item::len).sum::<usize>() as u64,
+                partition_id = self.partition_tag()
+            );
+        }
     }
 
     /// Returns a reference to the next-in-line envelope.
@@ -126,6 +428,127 @@ impl PolymorphicEnvelopeBuffer {
         Ok(envelope)
     }
 
+    /// Pops the next-in-line envelope together with the partition and stack it was popped from.
+    pub async fn pop_with_meta(&mut self) -> Result<Option<PoppedEnvelope>, EnvelopeBufferError> {
+        let popped = relay_statsd::metric!(
+            timer(RelayTimers::BufferPop),
+            partition_id = self.partition_tag(),
+            {
+                match self {
+                    Self::Sqlite(buffer) => buffer.pop_with_meta().await,
+                    Self::InMemory(buffer) => buffer.pop_with_meta().await,
+                }?
+            }
+        );
+        Ok(popped)
+    }
+
+    /// Pops the next-in-line envelope and returns the result of applying `f` to it.
+    pub async fn pop_map<T>(
+        &mut self,
+        f: impl FnOnce(Box<Envelope>) -> T,
+    ) -> Result<Option<T>, EnvelopeBufferError> {
+        let mapped = relay_statsd::metric!(
+            timer(RelayTimers::BufferPop),
+            partition_id = self.partition_tag(),
+            {
+                match self {
+                    Self::Sqlite(buffer) => buffer.pop_map(f).await,
+                    Self::InMemory(buffer) => buffer.pop_map(f).await,
+                }?
+            }
+        );
+        Ok(mapped)
+    }
+
+    /// Pops up to `n` envelopes from the next-in-line stack.
+    ///
+    /// See [`EnvelopeBuffer::pop_batch`].
+    pub async fn pop_batch(&mut self, n: usize) -> Result<Vec<Box<Envelope>>, EnvelopeBufferError> {
+        let envelopes = relay_statsd::metric!(
+            timer(RelayTimers::BufferPop),
+            partition_id = self.partition_tag(),
+            {
+                match self {
+                    Self::Sqlite(buffer) => buffer.pop_batch(n).await,
+                    Self::InMemory(buffer) => buffer.pop_batch(n).await,
+                }?
+            }
+        );
+        Ok(envelopes)
+    }
+
+    /// Pops the next envelope and holds it in the store until it is acknowledged.
+    ///
+    /// Only the disk-based buffer supports acknowledgements. See
+    /// [`EnvelopeBuffer::pop_with_ack`].
+    pub async fn pop_with_ack(
+        &mut self,
+        visibility_timeout: Duration,
+    ) -> Result<Option<(Box<Envelope>, AckToken)>, EnvelopeBufferError> {
+        match self {
+            Self::Sqlite(buffer) => buffer.pop_with_ack(visibility_timeout).await,
+            Self::InMemory(_) => Err(EnvelopeBufferError::AckUnsupported),
+        }
+    }
+
+    /// Acknowledges an envelope returned by [`Self::pop_with_ack`].
+    ///
+    /// Returns `false` if the envelope was already redelivered.
+    pub async fn ack(&mut self, token: AckToken) -> Result<bool, EnvelopeBufferError> {
+        match self {
+            Self::Sqlite(buffer) => buffer.ack(token).await,
+            Self::InMemory(_) => Err(EnvelopeBufferError::AckUnsupported),
+        }
+    }
+
+    /// Pushes held envelopes whose visibility timeout passed back into the buffer.
+    ///
+    /// Returns the envelopes evicted to stay within the maximum stack depth. The in-memory buffer
+    /// does not hold envelopes, so nothing is redelivered. See
+    /// [`EnvelopeBuffer::redeliver_expired`].
+    pub async fn redeliver_expired(&mut self) -> Result<Vec<Box<Envelope>>, PushFailure> {
+        match self {
+            Self::Sqlite(buffer) => buffer.redeliver_expired().await,
+            Self::InMemory(_) => Ok(Vec::new()),
+        }
+    }
+
+    /// Pops the oldest envelope of the next-in-line stack.
+    pub async fn pop_oldest(&mut self) -> Result<Option<Box<Envelope>>, EnvelopeBufferError> {
+        match self {
+            Self::Sqlite(buffer) => buffer.pop_oldest().await,
+            Self::InMemory(buffer) => buffer.pop_oldest().await,
+        }
+    }
+
+    /// Removes the stack of the project key pair and returns all of its envelopes.
+    ///
+    /// See [`EnvelopeBuffer::take_stack`].
+    pub async fn take_stack(
+        &mut self,
+        project_key_pair: &ProjectKeyPair,
+    ) -> Result<Vec<Box<Envelope>>, EnvelopeBufferError> {
+        match self {
+            Self::Sqlite(buffer) => buffer.take_stack(project_key_pair).await,
+            Self::InMemory(buffer) => buffer.take_stack(project_key_pair).await,
+        }
+    }
+
+    /// Updates how the buffer treats the stacks of a project.
+    ///
+    /// Returns the envelopes that were evicted because the project is disabled.
+    pub async fn set_project_state(
+        &mut self,
+        project: &ProjectKey,
+        state: BufferedProjectState,
+    ) -> Result<Vec<Box<Envelope>>, PartialFailure> {
+        match self {
+            Self::Sqlite(buffer) => buffer.set_project_state(project, state).await,
+            Self::InMemory(buffer) => buffer.set_project_state(project, state).await,
+        }
+    }
+
     /// Marks a project as ready or not ready.
     ///
     /// The buffer re-prioritizes its envelopes based on this information.
@@ -154,6 +577,32 @@ impl PolymorphicEnvelopeBuffer {
         }
     }
 
+    /// Records a failure to pop or forward an envelope of the given stack.
+    ///
+    /// Returns `true` if the stack was quarantined by this failure.
+    pub fn report_pop_failure(&mut self, project_key_pair: ProjectKeyPair) -> bool {
+        match self {
+            Self::Sqlite(buffer) => buffer.report_pop_failure(project_key_pair),
+            Self::InMemory(buffer) => buffer.report_pop_failure(project_key_pair),
+        }
+    }
+
+    /// Removes stacks loaded at startup that remained empty for longer than configured.
+    pub async fn remove_empty_init_stacks(&mut self) {
+        match self {
+            Self::Sqlite(buffer) => buffer.remove_empty_init_stacks().await,
+            Self::InMemory(buffer) => buffer.remove_empty_init_stacks().await,
+        }
+    }
+
+    /// Reports the age distribution of a sample of buffered envelopes.
+    pub fn sample_envelope_ages(&mut self) {
+        match self {
+            Self::Sqlite(buffer) => buffer.sample_envelope_ages(),
+            Self::InMemory(buffer) => buffer.sample_envelope_ages(),
+        }
+    }
+
     /// Returns `true` whether the buffer has capacity to accept new [`Envelope`]s.
     pub fn has_capacity(&self) -> bool {
         match self {
@@ -162,85 +611,406 @@ impl PolymorphicEnvelopeBuffer {
         }
     }
 
-    /// Returns the total number of envelopes that have been spooled since the startup. It does
-    /// not include the count that existed in a persistent spooler before.
-    pub fn item_count(&self) -> u64 {
+    /// Decides whether the envelope can be pushed, taking all configured limits into account.
+    ///
+    /// See [`EnvelopeBuffer::admission`].
+    pub fn admission(&self, envelope: &Envelope) -> Admission {
         match self {
-            Self::Sqlite(buffer) => buffer.tracked_count,
-            Self::InMemory(buffer) => buffer.tracked_count,
+            Self::Sqlite(buffer) => buffer.admission(envelope),
+            Self::InMemory(buffer) => buffer.admission(envelope),
         }
     }
 
-    /// Returns the total number of bytes that the spooler storage uses or `None` if the number
-    /// cannot be reliably determined.
-    pub fn total_size(&self) -> Option<u64> {
+    /// Evicts the next envelope of an unsampled trace to make room for the given envelope.
+    ///
+    /// See [`EnvelopeBuffer::evict_unsampled`].
+    pub async fn evict_unsampled(
+        &mut self,
+        envelope: &Envelope,
+    ) -> Result<Option<Box<Envelope>>, EnvelopeBufferError> {
         match self {
-            Self::Sqlite(buffer) => buffer.stack_provider.total_size(),
-            Self::InMemory(buffer) => buffer.stack_provider.total_size(),
+            Self::Sqlite(buffer) => buffer.evict_unsampled(envelope).await,
+            Self::InMemory(buffer) => buffer.evict_unsampled(envelope).await,
         }
     }
 
-    /// Shuts down the [`PolymorphicEnvelopeBuffer`].
-    pub async fn shutdown(&mut self) -> bool {
-        // Currently, we want to flush the buffer only for disk, since the in memory implementation
-        // tries to not do anything and pop as many elements as possible within the shutdown
-        // timeout.
-        let Self::Sqlite(buffer) = self else {
-            relay_log::trace!("PolymorphicEnvelopeBuffer: shutdown procedure not needed");
-            return false;
-        };
-        buffer.flush().await;
-
-        true
+    /// Decides whether the envelope can be pushed regardless of the capacity of the buffer, and
+    /// counts it towards the rate of new stacks.
+    ///
+    /// See [`EnvelopeBuffer::check_admission`].
+    pub fn check_admission(&mut self, envelope: &Envelope) -> Admission {
+        match self {
+            Self::Sqlite(buffer) => buffer.check_admission(envelope),
+            Self::InMemory(buffer) => buffer.check_admission(envelope),
+        }
     }
 
-    /// Returns the partition tag for this [`PolymorphicEnvelopeBuffer`].
-    fn partition_tag(&self) -> &str {
+    /// Returns `true` if the envelope is a retry of a recently checked envelope.
+    ///
+    /// See [`EnvelopeBuffer::is_retry`].
+    pub fn is_retry(&mut self, envelope: &Envelope) -> bool {
         match self {
-            PolymorphicEnvelopeBuffer::InMemory(buffer) => &buffer.partition_tag,
-            PolymorphicEnvelopeBuffer::Sqlite(buffer) => &buffer.partition_tag,
+            Self::Sqlite(buffer) => buffer.is_retry(envelope),
+            Self::InMemory(buffer) => buffer.is_retry(envelope),
         }
     }
-}
 
-/// Error that occurs while interacting with the envelope buffer.
-#[derive(Debug, thiserror::Error)]
-pub enum EnvelopeBufferError {
-    #[error("sqlite")]
-    SqliteStore(#[from] SqliteEnvelopeStoreError),
+    /// Returns `true` if the stack with the highest priority is ready.
+    ///
+    /// See [`EnvelopeBuffer::has_ready`].
+    pub fn has_ready(&self) -> bool {
+        match self {
+            Self::Sqlite(buffer) => buffer.has_ready(),
+            Self::InMemory(buffer) => buffer.has_ready(),
+        }
+    }
 
-    #[error("sqlite")]
-    SqliteStack(#[from] SqliteEnvelopeStackError),
+    /// Returns the total number of envelopes that have been spooled since the startup. It does
+    /// not include the count that existed in a persistent spooler before.
+    pub fn item_count(&self) -> u64 {
+        match self {
+            Self::Sqlite(buffer) => buffer.tracked_count,
+            Self::InMemory(buffer) => buffer.tracked_count,
+        }
+    }
 
-    #[error("failed to push envelope to the buffer")]
-    PushFailed,
-}
+    /// Returns the total size of attachments that have been spooled since the startup. Like
+    /// [`Self::item_count`], it does not include attachments that existed in a persistent spooler
+    /// before, and removing such envelopes does not reduce it.
+    pub fn attachment_bytes(&self) -> u64 {
+        match self {
+            Self::Sqlite(buffer) => buffer.attachment_bytes,
+            Self::InMemory(buffer) => buffer.attachment_bytes,
+        }
+    }
 
-impl From<Infallible> for EnvelopeBufferError {
-    fn from(value: Infallible) -> Self {
-        match value {}
+    /// Applies the given settings to the running buffer.
+    pub fn apply_settings(&mut self, settings: &LiveSettings) {
+        match self {
+            Self::Sqlite(buffer) => buffer.apply_settings(settings),
+            Self::InMemory(buffer) => buffer.apply_settings(settings),
+        }
     }
-}
 
-/// An envelope buffer that holds an individual stack for each project/sampling project combination.
-///
-/// Envelope stacks are organized in a priority queue, and are re-prioritized every time an envelope
-/// is pushed, popped, or when a project becomes ready.
-#[derive(Debug)]
-struct EnvelopeBuffer<P: StackProvider> {
-    /// The central priority queue.
-    priority_queue: priority_queue::PriorityQueue<QueueItem<ProjectKeyPair, P::Stack>, Priority>,
-    /// A lookup table to find all stacks involving a project.
-    stacks_by_project: hashbrown::HashMap<ProjectKey, BTreeSet<ProjectKeyPair>>,
-    /// A provider of stacks that provides utilities to create stacks, check their capacity...
+    /// Applies a reloaded configuration without dropping buffered envelopes.
     ///
-    /// This indirection is needed because different stack implementations might need different
-    /// initialization (e.g. a database connection).
-    stack_provider: P,
-    /// The total count of envelopes that the buffer is working with.
+    /// If the configuration selects the same kind of buffer, the settings that can change at
+    /// runtime are applied in place, see [`EnvelopeBuffer::reload_config`]. If it switches between
+    /// the memory-based and the disk-based buffer, a new buffer is created and initialized, and
+    /// the envelopes are moved in batches from the current buffer into it. Returns the envelopes
+    /// that were evicted from the new buffer to stay within the maximum stack depth.
     ///
-    /// Note that this count is not meant to be perfectly accurate since the initialization of the
-    /// count might not succeed if it takes more than a set timeout. For example, if we load the
+    /// If the new buffer cannot be created, the current buffer is kept unchanged. If moving the
+    /// envelopes fails, or the new buffer runs out of capacity, the current buffer is kept as
+    /// well. The envelopes that were already moved are taken out of the new buffer and returned
+    /// with the error, so that they can be pushed back. Only an envelope that failed to be pushed
+    /// into the new buffer may be lost.
+    pub async fn reload_config(
+        &mut self,
+        config: &Config,
+        memory_checker: MemoryChecker,
+    ) -> Result<Vec<Box<Envelope>>, PartialFailure> {
+        let partition_id = match self {
+            Self::Sqlite(buffer) => buffer.partition_id,
+            Self::InMemory(buffer) => buffer.partition_id,
+        };
+
+        let is_memory = config.spool_envelopes_path(partition_id).is_none();
+        if is_memory == self.is_memory() {
+            match self {
+                Self::Sqlite(buffer) => buffer.reload_config(config),
+                Self::InMemory(buffer) => buffer.reload_config(config),
+            }
+            return Ok(Vec::new());
+        }
+
+        relay_log::info!(
+            partition_id,
+            "migrating envelope buffer to the {} backend",
+            if is_memory { "memory" } else { "disk" }
+        );
+
+        let mut buffer = Self::from_config(partition_id, config, memory_checker)
+            .await
+            .map_err(|error| (error, Vec::new()))?;
+        buffer
+            .initialize()
+            .await
+            .map_err(|error| (error, Vec::new()))?;
+
+        let mut evicted = Vec::new();
+        if let Err(error) = self.move_into(&mut buffer, &mut evicted).await {
+            let mut envelopes = evicted;
+            loop {
+                match buffer.pop().await {
+                    Ok(Some(envelope)) => envelopes.push(envelope),
+                    Ok(None) => break,
+                    Err(error) => {
+                        relay_log::error!(
+                            error = &error as &dyn std::error::Error,
+                            "failed to take envelopes out of the new buffer"
+                        );
+                        break;
+                    }
+                }
+            }
+            return Err((error, envelopes));
+        }
+
+        *self = buffer;
+        Ok(evicted)
+    }
+
+    /// Moves all envelopes into the given buffer in batches of [`MIGRATION_BATCH_SIZE`].
+    ///
+    /// Before every batch, the other buffer must have capacity, which bounds a memory-based buffer
+    /// by its memory checker. Envelopes evicted from the other buffer are added to `evicted`.
+    async fn move_into(
+        &mut self,
+        buffer: &mut Self,
+        evicted: &mut Vec<Box<Envelope>>,
+    ) -> Result<(), EnvelopeBufferError> {
+        loop {
+            if !buffer.has_capacity() {
+                return Err(EnvelopeBufferError::MigrationCapacity);
+            }
+
+            let mut batch = Vec::with_capacity(MIGRATION_BATCH_SIZE);
+            let mut result = Ok(());
+            while batch.len() < MIGRATION_BATCH_SIZE {
+                match self.pop().await {
+                    Ok(Some(envelope)) => batch.push(envelope),
+                    Ok(None) => break,
+                    Err(error) => {
+                        result = Err(error);
+                        break;
+                    }
+                }
+            }
+            let exhausted = batch.len() < MIGRATION_BATCH_SIZE;
+
+            // Envelopes popped before a failed pop are still moved, so that they are returned
+            // along with the other moved envelopes.
+            match buffer.push_all(batch).await {
+                Ok(pushed_evicted) => evicted.extend(pushed_evicted),
+                Err(failure) => {
+                    evicted.extend(failure.evicted);
+                    evicted.extend(failure.unpushed);
+                    return Err(failure.error);
+                }
+            }
+            result?;
+
+            if exhausted {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Returns the maximum age of envelopes in the buffer.
+    pub fn max_age(&self) -> Duration {
+        match self {
+            Self::Sqlite(buffer) => buffer.max_age,
+            Self::InMemory(buffer) => buffer.max_age,
+        }
+    }
+
+    /// Returns up to `limit` stacks in the order in which they are popped.
+    pub fn queue_snapshot(&self, limit: usize) -> Vec<StackSnapshot> {
+        match self {
+            Self::Sqlite(buffer) => buffer.queue_snapshot(limit),
+            Self::InMemory(buffer) => buffer.queue_snapshot(limit),
+        }
+    }
+
+    /// Returns a preview of the buffered envelope with the given event id.
+    pub async fn event_preview(
+        &self,
+        event_id: EventId,
+    ) -> Result<Option<EnvelopePreview>, EnvelopeBufferError> {
+        match self {
+            Self::Sqlite(buffer) => buffer.event_preview(event_id).await,
+            Self::InMemory(buffer) => buffer.event_preview(event_id).await,
+        }
+    }
+
+    /// Returns the number of stacks and envelopes in the buffer, see [`BufferStats`].
+    pub fn stats(&self) -> BufferStats {
+        match self {
+            Self::Sqlite(buffer) => buffer.stats(),
+            Self::InMemory(buffer) => buffer.stats(),
+        }
+    }
+
+    /// Returns how evenly the buffered envelopes are distributed across projects.
+    pub fn balance_stats(&self) -> BalanceStats {
+        match self {
+            Self::Sqlite(buffer) => buffer.balance_stats(),
+            Self::InMemory(buffer) => buffer.balance_stats(),
+        }
+    }
+
+    /// Returns diagnostics on the envelope counts of the buffer.
+    pub fn count_diagnostics(&self) -> CountDiagnostics {
+        match self {
+            Self::Sqlite(buffer) => buffer.count_diagnostics(),
+            Self::InMemory(buffer) => buffer.count_diagnostics(),
+        }
+    }
+
+    /// Reloads the total count of envelopes from the store.
+    ///
+    /// This resets the drift of the total count that accumulates if the count loaded during
+    /// initialization was inaccurate. Envelopes that are held in memory are not part of the count
+    /// in the store.
+    pub async fn reset_total_count(&mut self) {
+        match self {
+            Self::Sqlite(buffer) => buffer.load_store_total_count().await,
+            Self::InMemory(buffer) => buffer.load_store_total_count().await,
+        }
+    }
+
+    /// Returns the total number of bytes that the spooler storage uses or `None` if the number
+    /// cannot be reliably determined.
+    pub fn total_size(&self) -> Option<u64> {
+        match self {
+            Self::Sqlite(buffer) => buffer.stack_provider.total_size(),
+            Self::InMemory(buffer) => buffer.stack_provider.total_size(),
+        }
+    }
+
+    /// Estimates how many more envelopes of average size fit into the buffer.
+    ///
+    /// See [`EnvelopeBuffer::estimated_remaining_capacity`]. Returns `None` for the memory-based
+    /// buffer, whose size cannot be determined.
+    pub fn estimated_remaining_capacity(&self) -> Option<u64> {
+        match self {
+            Self::Sqlite(buffer) => buffer.estimated_remaining_capacity(),
+            Self::InMemory(_) => None,
+        }
+    }
+
+    /// Shuts down the [`PolymorphicEnvelopeBuffer`].
+    pub async fn shutdown(&mut self) -> bool {
+        // Currently, we want to flush the buffer only for disk, since the in memory implementation
+        // tries to not do anything and pop as many elements as possible within the shutdown
+        // timeout.
+        let Self::Sqlite(buffer) = self else {
+            relay_log::trace!("PolymorphicEnvelopeBuffer: shutdown procedure not needed");
+            return false;
+        };
+        buffer.flush().await;
+
+        true
+    }
+
+    /// Returns the partition tag for this [`PolymorphicEnvelopeBuffer`].
+    fn partition_tag(&self) -> &str {
+        match self {
+            PolymorphicEnvelopeBuffer::InMemory(buffer) => &buffer.partition_tag,
+            PolymorphicEnvelopeBuffer::Sqlite(buffer) => &buffer.partition_tag,
+        }
+    }
+
+    fn body_size_sample_rate(&self) -> f32 {
+        match self {
+            PolymorphicEnvelopeBuffer::InMemory(buffer) => buffer.body_size_sample_rate,
+            PolymorphicEnvelopeBuffer::Sqlite(buffer) => buffer.body_size_sample_rate,
+        }
+    }
+}
+
+/// Error that occurs while interacting with the envelope buffer.
+#[derive(Debug, thiserror::Error)]
+pub enum EnvelopeBufferError {
+    #[error("sqlite")]
+    SqliteStore(#[from] SqliteEnvelopeStoreError),
+
+    #[error("sqlite")]
+    SqliteStack(#[from] SqliteEnvelopeStackError),
+
+    #[error("failed to push envelope to the buffer")]
+    PushFailed,
+
+    #[error("invalid path of the spool file to replay")]
+    InvalidReplayPath,
+
+    #[error("envelope archive")]
+    Archive(#[from] ArchiveError),
+
+    #[error("recovered {loaded} of {stored} envelopes in the store")]
+    RecoveryMismatch { stored: u64, loaded: u64 },
+
+    #[error("the envelope buffer is not initialized")]
+    NotInitialized,
+
+    #[error("acknowledgements require a disk-based envelope buffer")]
+    AckUnsupported,
+
+    #[error("envelope buffer self-test failed: {0}")]
+    SelfTest(&'static str),
+
+    #[error("the new envelope buffer ran out of capacity during the migration")]
+    MigrationCapacity,
+}
+
+/// Error of an operation that already removed envelopes from the buffer when it failed.
+///
+/// The removed envelopes are returned with the error, so that their outcomes can be emitted.
+pub type PartialFailure = (EnvelopeBufferError, Vec<Box<Envelope>>);
+
+/// Error of a push that failed part-way through.
+///
+/// The envelopes that were evicted before the push failed and the envelopes that were not pushed
+/// are returned with the error, so that their outcomes can be emitted. The envelope whose push
+/// failed is consumed by its stack.
+#[derive(Debug)]
+pub struct PushFailure {
+    pub error: EnvelopeBufferError,
+    /// Envelopes evicted from the bottom of their stacks before the push failed.
+    pub evicted: Vec<Box<Envelope>>,
+    /// Envelopes that were not pushed into the buffer.
+    pub unpushed: Vec<Box<Envelope>>,
+}
+
+impl From<EnvelopeBufferError> for PushFailure {
+    fn from(error: EnvelopeBufferError) -> Self {
+        Self {
+            error,
+            evicted: Vec::new(),
+            unpushed: Vec::new(),
+        }
+    }
+}
+
+impl From<Infallible> for EnvelopeBufferError {
+    fn from(value: Infallible) -> Self {
+        match value {}
+    }
+}
+
+/// An envelope buffer that holds an individual stack for each project/sampling project combination.
+///
+/// Envelope stacks are organized in a priority queue, and are re-prioritized every time an envelope
+/// is pushed, popped, or when a project becomes ready. The order of the queue is decided by the
+/// [`SchedulingPolicy`].
+#[derive(Debug)]
+struct EnvelopeBuffer<P: StackProvider, S: SchedulingPolicy = DefaultPolicy> {
+    /// The central priority queue.
+    priority_queue: priority_queue::PriorityQueue<QueueItem<ProjectKeyPair, P::Stack>, Priority<S>>,
+    /// The policy that orders the stacks in the priority queue.
+    policy: S,
+    /// A lookup table to find all stacks involving a project.
+    stacks_by_project: hashbrown::HashMap<ProjectKey, BTreeSet<ProjectKeyPair>>,
+    /// A provider of stacks that provides utilities to create stacks, check their capacity...
+    ///
+    /// This indirection is needed because different stack implementations might need different
+    /// initialization (e.g. a database connection).
+    stack_provider: P,
+    /// The total count of envelopes that the buffer is working with.
+    ///
+    /// Note that this count is not meant to be perfectly accurate since the initialization of the
+    /// count might not succeed if it takes more than a set timeout. For example, if we load the
     /// count of all envelopes from disk, and it takes more than the time we set, we will mark the
     /// initial count as 0 and just count incoming and outgoing envelopes from the buffer.
     total_count: i64,
@@ -250,26 +1020,216 @@ struct EnvelopeBuffer<P: StackProvider> {
     /// On startup this will always be 0 and will only count incoming envelopes. If a reliable
     /// count of currently buffered envelopes is required, prefer this over `total_count`
     tracked_count: u64,
+    /// The total size of attachment items in the buffer, ignoring envelopes that were previously
+    /// stored on disk.
+    ///
+    /// Like `tracked_count`, this starts at 0 and only accounts for incoming envelopes.
+    attachment_bytes: u64,
+    /// The number of envelopes at the top of each stack that are included in `attachment_bytes`.
+    ///
+    /// Envelopes that were stored on disk before startup are always at the bottom of their stacks,
+    /// so only the attachments of removed envelopes within this count are subtracted.
+    counted_envelopes: hashbrown::HashMap<ProjectKeyPair, usize>,
+    /// Maximum size of attachments in a single envelope, see
+    /// `spool.envelopes.max_envelope_attachment_bytes`.
+    max_envelope_attachment_bytes: Option<u64>,
+    /// The number of envelopes pushed since startup, including envelopes that were popped since.
+    pushed_count: u64,
+    /// The total size of item payloads of all envelopes counted in `pushed_count`.
+    pushed_bytes: u64,
     /// Whether the count initialization succeeded or not.
     ///
     /// This boolean is just used for tagging the metric that tracks the total count of envelopes
     /// in the buffer.
     total_count_initialized: bool,
+    /// The maximum number of envelopes in a single stack, if limited.
+    max_stack_depth: Option<NonZeroUsize>,
+    /// The maximum number of envelopes in the buffer, if limited.
+    max_total_count: Option<u64>,
+    /// The maximum number of envelopes returned by [`Self::pop_batch`].
+    max_pop_batch: usize,
+    /// The maximum age of envelopes before they are dropped.
+    max_age: Duration,
+    /// Projects whose stacks are prioritized after initialization.
+    hot_projects: HotProjects,
+    /// Time after the initialization during which hot projects are prioritized.
+    hot_projects_ttl: Duration,
+    /// The time at which hot stacks are no longer prioritized, if any are.
+    hot_until: Option<Instant>,
+    /// Projects whose stacks are never chosen for eviction.
+    protected_projects: HashSet<ProjectKey>,
+    /// Whether ready stacks with their next envelope in memory are popped before equally
+    /// prioritized stacks that need to read from disk.
+    prefer_memory_resident: bool,
+    /// Whether ready stacks whose next envelope belongs to an unsampled trace are popped last.
+    deprioritize_unsampled: bool,
+    /// Stacks whose next envelope belongs to an unsampled trace, if they are deprioritized.
+    ///
+    /// These envelopes are evicted first once the buffer reaches its maximum total count.
+    unsampled_stacks: HashSet<ProjectKeyPair>,
+    /// Time after the creation of a stack during which its envelopes are not evicted.
+    eviction_grace_period: Duration,
+    /// Whether envelopes of a trace are popped from the stack that received the trace first.
+    preserve_trace_order: bool,
+    /// Keys of the stacks holding envelopes of each trace, in the order the envelopes were pushed.
+    ///
+    /// This is only tracked if `preserve_trace_order` is enabled.
+    traces: hashbrown::HashMap<Uuid, VecDeque<ProjectKeyPair>>,
+    /// Keys of the stacks holding envelopes with each event id, used to look up event previews.
+    ///
+    /// Only envelopes pushed since startup are indexed, envelopes restored from the spool of a
+    /// previous run are not.
+    events: hashbrown::HashMap<EventId, VecDeque<ProjectKeyPair>>,
+    /// Maximum number of stacks loaded concurrently during initialization.
+    load_concurrency: usize,
+    /// Number of times a failed read from a stack is retried when popping.
+    pop_retries: u32,
+    /// Initial backoff between retries of a failed read, doubled with every retry.
+    pop_retry_backoff: Duration,
+    /// The id of this partition.
+    partition_id: u8,
+    /// Fraction of pushes that report the envelope body size.
+    body_size_sample_rate: f32,
+    /// Number of reported pop failures after which a stack is quarantined, if enabled.
+    quarantine_threshold: Option<NonZeroU32>,
+    /// Number of reported pop failures of stacks that are not quarantined yet.
+    pop_failures: hashbrown::HashMap<ProjectKeyPair, u32>,
+    /// Minimum time before the projects of a non-ready stack are fetched again.
+    min_fetch_debounce: Duration,
+    /// Maximum time that a stack loaded at startup may remain empty, if limited.
+    empty_init_stack_lifetime: Option<Duration>,
+    /// Stacks loaded at startup that did not receive an envelope yet, with their creation time.
+    ///
+    /// This is only tracked if `empty_init_stack_lifetime` is set.
+    init_stacks: hashbrown::HashMap<ProjectKeyPair, Instant>,
+    /// Number of envelope age sweeps, used to sample different stacks in every sweep.
+    age_sweeps: usize,
+    /// Age in seconds of the oldest envelope popped since the last age sweep.
+    max_served_age: Option<i64>,
+    /// Age of served envelopes above which a warning is logged, if enabled.
+    max_served_age_alert: Option<Duration>,
+    /// Whether new stacks start out ready.
+    default_ready: bool,
+    /// The sequence assigned to the most recent push.
+    sequence: Sequence,
+    /// The latest wall clock time observed when assigning a sequence.
+    last_clock: DateTime<Utc>,
+    /// Whether the loaded stacks are verified against the store after initialization.
+    verify_on_start: bool,
+    /// Whether a metric is reported once the initialization is complete.
+    report_initialized: bool,
+    /// Maximum number of unrecoverable envelopes before the initialization fails, if limited.
+    verify_max_mismatch: Option<u64>,
+    /// Duration after which an operation is logged as slow, if enabled.
+    slow_op_threshold: Option<Duration>,
+    /// Number of stacks flushed before yielding to other tasks.
+    flush_yield_interval: usize,
+    /// Whether envelopes are split into a stack per processing group.
+    ///
+    /// This is only supported by the memory-based buffer.
+    split_processing_groups: bool,
+    /// Whether [`Self::initialize`] has completed.
+    initialized: bool,
+    /// Whether peeking and popping fail until the buffer is initialized.
+    require_initialization: bool,
+    /// Whether the stacks and the store are tested before initializing the buffer.
+    self_test: bool,
+    /// Limits the creation of new stacks, if enabled.
+    stack_creation_limiter: Option<StackCreationLimiter>,
+    /// Fingerprints of recently pushed envelopes, if retries are detected.
+    fingerprints: Option<FingerprintIndex>,
+    /// Whether the result of [`Self::peek`] is cached until the next change to the buffer.
+    peek_cache: bool,
+    /// The cached result of the last [`Self::peek`], if the peek cache is enabled.
+    ///
+    /// Every operation that changes the stacks or their priorities must reset this.
+    cached_peek: Option<Peek>,
     /// The tag value of this partition which is used for reporting purposes.
     partition_tag: String,
 }
 
 impl EnvelopeBuffer<MemoryStackProvider> {
     /// Creates an empty memory-based buffer.
-    pub fn new(partition_id: u8, memory_checker: MemoryChecker) -> Self {
-        Self {
-            stacks_by_project: Default::default(),
-            priority_queue: Default::default(),
-            stack_provider: MemoryStackProvider::new(memory_checker),
-            total_count: 0,
-            tracked_count: 0,
-            total_count_initialized: false,
-            partition_tag: partition_id.to_string(),
+    pub fn new(partition_id: u8, config: &Config, memory_checker: MemoryChecker) -> Self {
+        Self::with_stack_provider(
+            partition_id,
+            config,
+            MemoryStackProvider::new(memory_checker),
+            DefaultPolicy,
+        )
+    }
+
+    /// Adds an envelope to the buffer without awaiting.
+    ///
+    /// Memory stacks are modified synchronously, so this behaves like [`Self::push`] but can be
+    /// called outside of an async context. Returns the envelope evicted from the bottom of its
+    /// stack, if any.
+    #[allow(dead_code)]
+    pub fn try_push_sync(
+        &mut self,
+        envelope: Box<Envelope>,
+    ) -> Result<Option<Box<Envelope>>, EnvelopeBufferError> {
+        self.cached_peek = None;
+        let started = Instant::now();
+        let project_key_pair = ProjectKeyPair::from_envelope(&envelope);
+        let received_at = envelope.received_at();
+        let sequence = self.next_sequence();
+        self.init_stacks.remove(&project_key_pair);
+
+        let pushed_envelope = PushedEnvelope::new(&envelope, self.preserve_trace_order);
+        let max_stack_depth = self
+            .max_stack_depth
+            .filter(|_| !self.is_protected(&project_key_pair));
+        let mut evicted = None;
+        match self.priority_queue.get_mut(&project_key_pair) {
+            Some((QueueItem { value: stack, .. }, _)) => {
+                stack.push_back(envelope);
+                if max_stack_depth.is_some_and(|max_depth| stack.depth() > max_depth.get()) {
+                    evicted = stack.pop_front();
+                }
+            }
+            None => {
+                let mut stack = self
+                    .stack_provider
+                    .create_stack(StackCreationType::New, project_key_pair);
+                stack.push_back(envelope);
+                self.insert_stack(project_key_pair, stack, received_at);
+            }
+        }
+
+        let evicted: Vec<_> = evicted.into_iter().collect();
+        self.track_pushed(project_key_pair, pushed_envelope, &evicted);
+        self.reprioritize_pushed(project_key_pair, received_at, sequence, &evicted, started);
+        Ok(evicted.into_iter().next())
+    }
+
+    /// Exports all envelopes into an archive.
+    ///
+    /// Memory stacks cannot hold envelopes until they are acknowledged, so the popped envelopes
+    /// are kept until the archive has been written. If writing fails, they are returned with the
+    /// error, so that they can be pushed back.
+    pub async fn export_archive<W: Write>(
+        &mut self,
+        mut archive: ArchiveWriter<W>,
+    ) -> Result<u64, PartialFailure> {
+        let mut exported = Vec::new();
+        loop {
+            let envelope = match self.pop().await {
+                Ok(Some(envelope)) => envelope,
+                Ok(None) => break,
+                Err(error) => return Err((error, exported)),
+            };
+
+            let written = archive.write(&envelope);
+            exported.push(envelope);
+            if let Err(error) = written {
+                return Err((error.into(), exported));
+            }
+        }
+
+        match archive.finish() {
+            Ok(_) => Ok(exported.len() as u64),
+            Err(error) => Err((error.into(), exported)),
         }
     }
 }
@@ -277,791 +1237,6429 @@ impl EnvelopeBuffer<MemoryStackProvider> {
 #[allow(dead_code)]
 impl EnvelopeBuffer<SqliteStackProvider> {
     /// Creates an empty sqlite-based buffer.
-    pub async fn new(partition_id: u8, config: &Config) -> Result<Self, EnvelopeBufferError> {
-        Ok(Self {
+    pub async fn new(
+        partition_id: u8,
+        config: &Config,
+        memory_checker: MemoryChecker,
+    ) -> Result<Self, EnvelopeBufferError> {
+        let stack_provider = SqliteStackProvider::new(partition_id, config, memory_checker).await?;
+        Ok(Self::with_stack_provider(
+            partition_id,
+            config,
+            stack_provider,
+            DefaultPolicy,
+        ))
+    }
+
+    /// Estimates how many more envelopes of average size fit into the store.
+    ///
+    /// The remaining space below `spool.envelopes.max_disk_size` is divided by the average payload
+    /// size of the envelopes pushed since startup. Since envelopes are compressed on disk, the
+    /// estimate is conservative. Returns `None` if no envelope was pushed yet.
+    pub fn estimated_remaining_capacity(&self) -> Option<u64> {
+        let average_size = self.pushed_bytes.checked_div(self.pushed_count)?.max(1);
+        let used = self.stack_provider.total_size()?;
+        let max_size = self.stack_provider.max_disk_size() as u64;
+
+        Some(max_size.saturating_sub(used) / average_size)
+    }
+
+    /// Pops the next envelope and holds it in the store until it is acknowledged.
+    ///
+    /// The envelope is removed from its stack like in [`Self::pop`], but it is not lost if the
+    /// consumer fails to process it: unless [`Self::ack`] is called with the returned token
+    /// within `visibility_timeout`, the envelope is pushed back into the buffer by
+    /// [`Self::redeliver_expired`]. Removing the envelope from its stack and holding it happens in
+    /// a single transaction, and held envelopes survive restarts, so envelopes are delivered at
+    /// least once.
+    pub async fn pop_with_ack(
+        &mut self,
+        visibility_timeout: Duration,
+    ) -> Result<Option<(Box<Envelope>, AckToken)>, EnvelopeBufferError> {
+        self.cached_peek = None;
+        self.ensure_initialized()?;
+        self.expire_hot_stacks();
+        let started = Instant::now();
+        let Some(project_key_pair) = self.next_stack() else {
+            return Ok(None);
+        };
+        let Some((QueueItem { value: stack, .. }, _)) =
+            self.priority_queue.get_mut(&project_key_pair)
+        else {
+            return Ok(None);
+        };
+
+        let deadline = chrono::Duration::from_std(visibility_timeout)
+            .ok()
+            .and_then(|timeout| Utc::now().checked_add_signed(timeout))
+            .unwrap_or(DateTime::<Utc>::MAX_UTC);
+        let (retries, backoff) = (self.pop_retries, self.pop_retry_backoff);
+        let (envelope, token) = retry_read(stack, retries, backoff, |stack| {
+            Box::pin(stack.pop_held(deadline))
+        })
+        .await?
+        .expect("found an empty stack");
+
+        let last_received_at = self.peek_popped_stack(project_key_pair).await;
+        self.untrack_envelope(&envelope, project_key_pair, false);
+        let counted = self.uncount_top(&project_key_pair);
+        self.update_popped_stack(project_key_pair, &envelope, last_received_at, counted);
+        self.report_slow_operation("pop_with_ack", started, Some(project_key_pair));
+
+        Ok(Some((envelope, AckToken(token))))
+    }
+
+    /// Acknowledges an envelope returned by [`Self::pop_with_ack`] and removes it from the store.
+    ///
+    /// Returns `false` if the envelope was not held anymore because its visibility timeout passed.
+    pub async fn ack(&mut self, token: AckToken) -> Result<bool, EnvelopeBufferError> {
+        Ok(self.stack_provider.ack(token.0).await?)
+    }
+
+    /// Pushes all held envelopes whose visibility timeout passed back into the buffer.
+    ///
+    /// Returns the redelivered envelopes that were evicted to stay within the configured maximum
+    /// stack depth, which the caller has to reject. If pushing fails, the envelopes that were not
+    /// redelivered are returned with the error.
+    pub async fn redeliver_expired(&mut self) -> Result<Vec<Box<Envelope>>, PushFailure> {
+        let envelopes = self
+            .stack_provider
+            .take_expired(Utc::now())
+            .await
+            .map_err(EnvelopeBufferError::from)?;
+        if envelopes.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        relay_statsd::metric!(
+            counter(RelayCounters::BufferRedelivered) += envelopes.len() as i64,
+            partition_id = &self.partition_tag
+        );
+        self.push_all(envelopes).await
+    }
+
+    /// Exports all envelopes into an archive and removes them once it has been written.
+    ///
+    /// Envelopes are held like in [`Self::pop_with_ack`] and acknowledged after the archive has
+    /// been flushed. If writing fails, the envelopes stay held and are pushed back into the buffer
+    /// by the next [`Self::redeliver_expired`], also after a restart.
+    pub async fn export_archive<W: Write>(
+        &mut self,
+        mut archive: ArchiveWriter<W>,
+    ) -> Result<u64, EnvelopeBufferError> {
+        let mut tokens = Vec::new();
+        // Held envelopes expire right away, so they are redelivered as soon as possible if the
+        // export fails.
+        while let Some((envelope, token)) = self.pop_with_ack(Duration::ZERO).await? {
+            tokens.push(token);
+            archive.write(&envelope)?;
+        }
+
+        archive.finish()?;
+        for &token in &tokens {
+            self.ack(token).await?;
+        }
+
+        Ok(tokens.len() as u64)
+    }
+}
+
+impl<P: StackProvider, S: SchedulingPolicy> EnvelopeBuffer<P, S> {
+    /// Creates an empty buffer with the given stack provider and scheduling policy.
+    fn with_stack_provider(
+        partition_id: u8,
+        config: &Config,
+        stack_provider: P,
+        policy: S,
+    ) -> Self {
+        Self {
             stacks_by_project: Default::default(),
             priority_queue: Default::default(),
-            stack_provider: SqliteStackProvider::new(partition_id, config).await?,
+            policy,
+            stack_provider,
             total_count: 0,
             tracked_count: 0,
+            attachment_bytes: 0,
+            counted_envelopes: Default::default(),
+            max_envelope_attachment_bytes: config
+                .spool_envelopes_max_envelope_attachment_bytes()
+                .map(|bytes| bytes as u64),
+            pushed_count: 0,
+            pushed_bytes: 0,
             total_count_initialized: false,
-            partition_tag: partition_id.to_string(),
-        })
+            max_stack_depth: config.spool_envelopes_max_stack_depth(),
+            max_total_count: config.spool_envelopes_max_total_count(),
+            max_pop_batch: config.spool_envelopes_max_pop_batch(),
+            max_age: config.spool_envelopes_max_age(),
+            hot_projects: HotProjects::new(partition_id, config),
+            hot_projects_ttl: config.spool_envelopes_hot_projects_ttl(),
+            hot_until: None,
+            protected_projects: parse_project_keys(
+                config.spool_envelopes_protected_projects(),
+                "protected project",
+            ),
+            prefer_memory_resident: config.spool_envelopes_prefer_memory_resident(),
+            deprioritize_unsampled: config.spool_envelopes_deprioritize_unsampled(),
+            unsampled_stacks: Default::default(),
+            eviction_grace_period: config.spool_envelopes_eviction_grace_period(),
+            preserve_trace_order: config.spool_envelopes_preserve_trace_order(),
+            traces: Default::default(),
+            events: Default::default(),
+            load_concurrency: config.spool_envelopes_load_concurrency(),
+            pop_retries: config.spool_envelopes_pop_retries(),
+            pop_retry_backoff: config.spool_envelopes_pop_retry_backoff(),
+            body_size_sample_rate: config.spool_envelopes_body_size_sample_rate(),
+            quarantine_threshold: config.spool_envelopes_quarantine_threshold(),
+            pop_failures: Default::default(),
+            min_fetch_debounce: config.spool_envelopes_min_fetch_debounce(),
+            empty_init_stack_lifetime: config.spool_envelopes_empty_init_stack_lifetime(),
+            init_stacks: Default::default(),
+            age_sweeps: 0,
+            max_served_age: None,
+            max_served_age_alert: config.spool_envelopes_max_served_age_alert(),
+            default_ready: config.spool_envelopes_default_ready(),
+            sequence: Sequence::default(),
+            last_clock: Utc::now(),
+            verify_on_start: config.spool_envelopes_verify_on_start(),
+            report_initialized: config.spool_envelopes_report_initialized(),
+            verify_max_mismatch: config.spool_envelopes_verify_max_mismatch(),
+            slow_op_threshold: config.spool_envelopes_slow_op_threshold(),
+            flush_yield_interval: config.spool_envelopes_flush_yield_interval(),
+            split_processing_groups: config.spool_envelopes_split_processing_groups(),
+            initialized: false,
+            require_initialization: config.spool_envelopes_require_initialization(),
+            self_test: config.spool_envelopes_self_test(),
+            stack_creation_limiter: config
+                .spool_envelopes_max_new_stacks_per_sec()
+                .map(StackCreationLimiter::new),
+            fingerprints: config
+                .spool_envelopes_fingerprint_window()
+                .map(FingerprintIndex::new),
+            peek_cache: config.spool_envelopes_peek_cache(),
+            cached_peek: None,
+            partition_id,
+            partition_tag: partition_tag(partition_id, config),
+        }
     }
 }
 
-impl<P: StackProvider> EnvelopeBuffer<P>
+impl<P: StackProvider, S: SchedulingPolicy> EnvelopeBuffer<P, S>
 where
     EnvelopeBufferError: From<<P::Stack as EnvelopeStack>::Error>,
 {
     /// Initializes the [`EnvelopeBuffer`] given the initialization state from the
     /// [`StackProvider`].
-    pub async fn initialize(&mut self) {
-        relay_statsd::metric!(
+    ///
+    /// If enabled, the loaded stacks are verified against the store afterwards, see
+    /// [`Self::verify_recovery`]. Once complete, the `buffer.initialized` metric is reported.
+    pub async fn initialize(&mut self) -> Result<(), EnvelopeBufferError> {
+        if self.self_test {
+            self.run_self_test().await?;
+        }
+
+        let started = Instant::now();
+        let loaded_pairs = relay_statsd::metric!(
             timer(RelayTimers::BufferInitialization),
             partition_id = &self.partition_tag,
             {
                 let initialization_state = self.stack_provider.initialize().await;
-                self.load_stacks(initialization_state.project_key_pairs)
-                    .await;
+                let project_key_pairs = initialization_state.project_key_pairs;
+                let loaded_pairs = self.verify_on_start.then(|| project_key_pairs.clone());
+                self.load_stacks(project_key_pairs).await;
                 self.load_store_total_count().await;
+                let hot_projects = self.hot_projects.load().await;
+                self.prioritize_hot_projects(&hot_projects);
+                loaded_pairs
             }
         );
+
+        if let Some(loaded_pairs) = loaded_pairs {
+            self.verify_recovery(loaded_pairs).await?;
+        }
+
+        self.initialized = true;
+        if self.report_initialized {
+            self.emit_initialized(started.elapsed());
+        }
+
+        Ok(())
     }
 
-    /// Pushes an envelope to the appropriate envelope stack and re-prioritizes the stack.
+    /// Passes a synthetic envelope through a stack and the store.
     ///
-    /// If the envelope stack does not exist, a new stack is pushed to the priority queue.
-    /// The priority of the stack is updated with the envelope's received_at time.
-    pub async fn push(&mut self, envelope: Box<Envelope>) -> Result<(), EnvelopeBufferError> {
-        let received_at = envelope.received_at();
-
+    /// The envelope is pushed, peeked and popped from a stack that is not part of the priority
+    /// queue, so it is never handed out by the buffer or counted in its metrics. Afterwards, it is
+    /// written to the store and read back in a transaction that is rolled back, so it never remains
+    /// in the store, even if Relay terminates during the self-test.
+    async fn run_self_test(&mut self) -> Result<(), EnvelopeBufferError> {
+        let dsn = SELF_TEST_DSN.parse().expect("self-test DSN is valid");
+        let envelope = Envelope::from_request(Some(EventId::new()), RequestMeta::outbound(dsn));
+        let event_id = envelope.event_id();
         let project_key_pair = ProjectKeyPair::from_envelope(&envelope);
-        if let Some((
-            QueueItem {
-                key: _,
-                value: stack,
-            },
-            _,
-        )) = self.priority_queue.get_mut(&project_key_pair)
-        {
-            stack.push(envelope).await?;
-        } else {
-            // Since we have initialization code that creates all the necessary stacks, we assume
-            // that any new stack that is added during the envelope buffer's lifecycle, is recreated.
-            self.push_stack(
-                StackCreationType::New,
-                ProjectKeyPair::from_envelope(&envelope),
-                Some(envelope),
-            )
-            .await?;
+
+        let mut stack = self
+            .stack_provider
+            .create_stack(StackCreationType::New, project_key_pair);
+        stack.push(envelope).await?;
+        if stack.peek().await?.is_none() {
+            return Err(EnvelopeBufferError::SelfTest(
+                "pushed envelope cannot be peeked",
+            ));
         }
-        self.priority_queue
-            .change_priority_by(&project_key_pair, |prio| {
-                prio.received_at = received_at;
-            });
+        let Some(envelope) = stack.pop().await? else {
+            return Err(EnvelopeBufferError::SelfTest(
+                "pushed envelope cannot be popped",
+            ));
+        };
+
+        let envelope = self.stack_provider.round_trip(envelope).await?;
+        if envelope.and_then(|envelope| envelope.event_id()) != event_id {
+            return Err(EnvelopeBufferError::SelfTest(
+                "envelope cannot be read back from the store",
+            ));
+        }
+
+        relay_log::info!(
+            tags.partition_id = self.partition_tag.as_str(),
+            "envelope buffer self-test passed"
+        );
+        Ok(())
+    }
+
+    /// Fails with [`EnvelopeBufferError::NotInitialized`] if envelopes must not be handed out yet.
+    ///
+    /// Before the initialization has completed, stacks from the store may be missing from the
+    /// priority queue, so envelopes would be served out of order.
+    fn ensure_initialized(&self) -> Result<(), EnvelopeBufferError> {
+        match self.require_initialization && !self.initialized {
+            true => Err(EnvelopeBufferError::NotInitialized),
+            false => Ok(()),
+        }
+    }
+
+    /// Reports the number of loaded stacks and envelopes and the duration of the initialization.
+    fn emit_initialized(&self, duration: Duration) {
+        let stacks = self.priority_queue.len() as u64;
+        let envelopes = self.total_count.max(0) as u64;
+        let duration_ms = duration.as_millis() as u64;
+
+        for (measure, value) in [
+            ("stacks", stacks),
+            ("envelopes", envelopes),
+            ("duration_ms", duration_ms),
+        ] {
+            relay_statsd::metric!(
+                gauge(RelayGauges::BufferInitialized) = value,
+                partition_id = &self.partition_tag,
+                measure = measure
+            );
+        }
+
+        relay_log::info!(
+            tags.partition_id = self.partition_tag.as_str(),
+            "envelope buffer initialized with {stacks} stacks and {envelopes} envelopes in {duration_ms}ms"
+        );
+    }
+
+    /// Pushes an envelope to the appropriate envelope stack and re-prioritizes the stack.
+    ///
+    /// If the envelope stack does not exist, a new stack is pushed to the priority queue.
+    /// The priority of the stack is updated with the envelope's received_at time.
+    ///
+    /// If the stack exceeds the maximum stack depth, the oldest envelope at the bottom of the
+    /// stack is removed and returned, unless the stack belongs to a protected project.
+    pub async fn push(
+        &mut self,
+        envelope: Box<Envelope>,
+    ) -> Result<Option<Box<Envelope>>, EnvelopeBufferError> {
+        let project_key_pair = ProjectKeyPair::from_envelope(&envelope);
+        self.push_keyed(envelope, project_key_pair).await
+    }
+
+    /// Pushes an envelope to the stack with the given key, see [`Self::push`].
+    async fn push_keyed(
+        &mut self,
+        envelope: Box<Envelope>,
+        project_key_pair: ProjectKeyPair,
+    ) -> Result<Option<Box<Envelope>>, EnvelopeBufferError> {
+        // A single envelope is either pushed or consumed by the failing stack, so the failure
+        // carries no other envelopes.
+        let mut evicted = self
+            .push_group(vec![envelope], project_key_pair)
+            .await
+            .map_err(|failure| failure.error)?;
+        Ok(evicted.pop())
+    }
+
+    /// Adds multiple envelopes to the buffer.
+    ///
+    /// Consecutive envelopes with the same [`ProjectKeyPair`] are pushed into their stack at once,
+    /// so the stack is looked up and reprioritized only once per group. Returns the envelopes that
+    /// were evicted to stay within the configured maximum stack depth.
+    ///
+    /// If a push fails, the envelopes of this and all later groups that were not pushed are
+    /// returned with the error, along with the envelopes evicted so far.
+    pub async fn push_all(
+        &mut self,
+        envelopes: Vec<Box<Envelope>>,
+    ) -> Result<Vec<Box<Envelope>>, PushFailure> {
+        let mut evicted = Vec::new();
+        let mut envelopes = envelopes.into_iter().peekable();
+        while let Some(envelope) = envelopes.next() {
+            let project_key_pair = ProjectKeyPair::from_envelope(&envelope);
+            let mut group = vec![envelope];
+            while let Some(envelope) = envelopes
+                .next_if(|envelope| ProjectKeyPair::from_envelope(envelope) == project_key_pair)
+            {
+                group.push(envelope);
+            }
+            match self.push_group(group, project_key_pair).await {
+                Ok(group_evicted) => evicted.extend(group_evicted),
+                Err(mut failure) => {
+                    evicted.append(&mut failure.evicted);
+                    failure.evicted = evicted;
+                    failure.unpushed.extend(envelopes);
+                    return Err(failure);
+                }
+            }
+        }
+
+        Ok(evicted)
+    }
+
+    /// Pushes envelopes in order to the stack with the given key and reprioritizes it once.
+    ///
+    /// If pushing fails, the envelopes pushed so far remain in the stack and are counted. The
+    /// envelopes after the failed one are returned with the error.
+    async fn push_group(
+        &mut self,
+        envelopes: Vec<Box<Envelope>>,
+        project_key_pair: ProjectKeyPair,
+    ) -> Result<Vec<Box<Envelope>>, PushFailure> {
+        self.cached_peek = None;
+        let started = Instant::now();
+        let Some(received_at) = envelopes.last().map(|envelope| envelope.received_at()) else {
+            return Ok(Vec::new());
+        };
+        let sequence = self.next_sequence();
+        self.init_stacks.remove(&project_key_pair);
+
+        let mut evicted = Vec::new();
+        let mut result = Ok(());
+        let protected = self.is_protected(&project_key_pair);
+        let max_stack_depth = self.max_stack_depth.filter(|_| !protected);
+        let mut envelopes = envelopes.into_iter();
+        while let Some(envelope) = envelopes.next() {
+            let pushed_envelope = PushedEnvelope::new(&envelope, self.preserve_trace_order);
+            let evicted_before = evicted.len();
+            let pushed = match self.priority_queue.get_mut(&project_key_pair) {
+                Some((
+                    QueueItem {
+                        key: _,
+                        value: stack,
+                    },
+                    _,
+                )) => push_bounded(stack, envelope, max_stack_depth, &mut evicted)
+                    .await
+                    .map_err(Into::into),
+                // Since we have initialization code that creates all the necessary stacks, we
+                // assume that any new stack that is added during the envelope buffer's lifecycle,
+                // is recreated.
+                None => {
+                    self.push_stack(StackCreationType::New, project_key_pair, Some(envelope))
+                        .await
+                }
+            };
+            if let Err(error) = pushed {
+                result = Err(error);
+                break;
+            }
+            self.track_pushed(
+                project_key_pair,
+                pushed_envelope,
+                &evicted[evicted_before..],
+            );
+        }
+
+        self.reprioritize_pushed(project_key_pair, received_at, sequence, &evicted, started);
+        match result {
+            Ok(()) => Ok(evicted),
+            Err(error) => Err(PushFailure {
+                error,
+                evicted,
+                unpushed: envelopes.collect(),
+            }),
+        }
+    }
 
+    /// Updates the counts of the buffer after an envelope was pushed into the stack.
+    ///
+    /// `evicted` are the envelopes that were evicted from the bottom of the stack by this push.
+    fn track_pushed(
+        &mut self,
+        project_key_pair: ProjectKeyPair,
+        pushed_envelope: PushedEnvelope,
+        evicted: &[Box<Envelope>],
+    ) {
         self.total_count += 1;
         self.tracked_count += 1;
-        self.track_total_count();
+        self.attachment_bytes += pushed_envelope.attachment_bytes;
+        *self.counted_envelopes.entry(project_key_pair).or_default() += 1;
+        let depth = self.stack_depth(&project_key_pair);
+        for evicted in evicted {
+            let counted = self.uncount_bottom(&project_key_pair, depth);
+            self.untrack_attachments(evicted, counted);
+        }
+        self.pushed_count += 1;
+        self.pushed_bytes += pushed_envelope.body_bytes;
+        if let Some(trace_id) = pushed_envelope.trace_id {
+            self.traces
+                .entry(trace_id)
+                .or_default()
+                .push_back(project_key_pair);
+        }
+        if let Some(event_id) = pushed_envelope.event_id {
+            self.events
+                .entry(event_id)
+                .or_default()
+                .push_back(project_key_pair);
+        }
+    }
 
-        Ok(())
+    /// Reprioritizes a stack after envelopes were pushed into it and accounts for the envelopes
+    /// evicted from it.
+    fn reprioritize_pushed(
+        &mut self,
+        project_key_pair: ProjectKeyPair,
+        received_at: DateTime<Utc>,
+        sequence: Sequence,
+        evicted: &[Box<Envelope>],
+        started: Instant,
+    ) {
+        let memory_resident = self.is_memory_resident(&project_key_pair);
+        let unsampled = self.update_unsampled(&project_key_pair);
+        let depth = self.stack_depth(&project_key_pair);
+        relay_statsd::metric!(
+            timer(RelayTimers::BufferReprioritize),
+            operation = "push",
+            partition_id = &self.partition_tag,
+            {
+                self.priority_queue
+                    .change_priority_by(&project_key_pair, |prio| {
+                        prio.received_at = received_at;
+                        prio.sequence = sequence;
+                        prio.memory_resident = memory_resident;
+                        prio.unsampled = unsampled;
+                        prio.depth = depth;
+                    });
+            }
+        );
+
+        for evicted in evicted {
+            self.untrack_envelope(evicted, project_key_pair, true);
+            self.total_count -= 1;
+            self.tracked_count = self.tracked_count.saturating_sub(1);
+            relay_statsd::metric!(
+                counter(RelayCounters::BufferStackDepthExceeded) += 1,
+                partition_id = &self.partition_tag
+            );
+        }
+        self.track_total_count();
+        self.report_slow_operation("push", started, Some(project_key_pair));
     }
 
     /// Returns a reference to the next-in-line envelope, if one exists.
+    ///
+    /// Stacks that are not ready are ordered by their next project fetch, so a stack that was
+    /// backed off with [`Self::mark_seen`] never hides a stack whose fetch is due. Callers do not
+    /// need to skip over backed off stacks: [`Peek::NotReady`] with a fetch time in the future
+    /// means that no stack is actionable before that time.
+    ///
+    /// If `spool.envelopes.peek_cache` is enabled, the result is reused until the buffer changes.
     pub async fn peek(&mut self) -> Result<Peek, EnvelopeBufferError> {
+        self.ensure_initialized()?;
+        self.expire_hot_stacks();
+        if let Some(peek) = self.cached_peek {
+            return Ok(peek);
+        }
+
+        let started = Instant::now();
+        let Some(project_key_pair) = self.next_stack() else {
+            return Ok(Peek::Empty);
+        };
         let Some((
-            QueueItem {
-                key: project_key_pair,
-                value: stack,
-            },
+            QueueItem { value: stack, .. },
             Priority {
                 readiness,
                 next_project_fetch,
                 ..
             },
-        )) = self.priority_queue.peek_mut()
+        )) = self.priority_queue.get_mut(&project_key_pair)
         else {
             return Ok(Peek::Empty);
         };
 
         let ready = readiness.ready();
+        // Envelopes are only keyed by a different sampling project if they carry a DSC and
+        // contain items that require a sampling decision, see `Envelope::sampling_key`.
+        let self_contained = project_key_pair.own_key == project_key_pair.sampling_key;
 
-        Ok(match (stack.peek().await?, ready) {
+        let peek = match (stack.peek().await?, ready) {
             (None, _) => Peek::Empty,
             (Some(last_received_at), true) => Peek::Ready {
-                project_key_pair: *project_key_pair,
+                project_key_pair,
                 last_received_at,
+                self_contained,
             },
             (Some(last_received_at), false) => Peek::NotReady {
-                project_key_pair: *project_key_pair,
+                project_key_pair,
                 next_project_fetch: *next_project_fetch,
                 last_received_at,
+                self_contained,
             },
-        })
+        };
+        self.report_slow_operation("peek", started, Some(project_key_pair));
+
+        if self.peek_cache {
+            self.cached_peek = Some(peek);
+        }
+
+        Ok(peek)
     }
 
     /// Returns the next-in-line envelope, if one exists.
     ///
     /// The priority of the envelope's stack is updated with the next envelope's received_at
     /// time. If the stack is empty after popping, it is removed from the priority queue.
+    ///
+    /// The envelope is taken from the stack that [`Self::peek`] reports, regardless of whether it
+    /// is ready. Callers peek first and only pop once the stack is ready.
+    ///
+    /// Failed reads from the stack are retried with backoff before the error is returned. Stacks
+    /// leave their envelopes in place if a read fails, so no envelope is lost by retrying.
     pub async fn pop(&mut self) -> Result<Option<Box<Envelope>>, EnvelopeBufferError> {
-        let Some((QueueItem { key, value: stack }, _)) = self.priority_queue.peek_mut() else {
+        let popped = self.pop_with_meta().await?;
+        Ok(popped.map(|popped| popped.envelope))
+    }
+
+    /// Returns the next-in-line envelope along with the partition and stack it was popped from.
+    ///
+    /// Behaves like [`Self::pop`]. The project key pair is the key of the stack, which is not
+    /// re-derived from the envelope.
+    pub async fn pop_with_meta(&mut self) -> Result<Option<PoppedEnvelope>, EnvelopeBufferError> {
+        self.cached_peek = None;
+        self.ensure_initialized()?;
+        self.expire_hot_stacks();
+        let started = Instant::now();
+        let Some(project_key_pair) = self.next_stack() else {
             return Ok(None);
         };
-        let project_key_pair = *key;
-        let envelope = stack.pop().await?.expect("found an empty stack");
+        let Some((QueueItem { value: stack, .. }, _)) =
+            self.priority_queue.get_mut(&project_key_pair)
+        else {
+            return Ok(None);
+        };
+        let (retries, backoff) = (self.pop_retries, self.pop_retry_backoff);
 
-        let last_received_at = stack.peek().await?;
+        let envelope = retry_read(stack, retries, backoff, |stack| Box::pin(stack.pop()))
+            .await?
+            .expect("found an empty stack");
 
-        match last_received_at {
-            None => {
-                self.pop_stack(project_key_pair);
-            }
-            Some(last_received_at) => {
-                self.priority_queue
-                    .change_priority_by(&project_key_pair, |prio| {
-                        prio.received_at = last_received_at;
-                    });
-            }
-        }
+        let last_received_at = self.peek_popped_stack(project_key_pair).await;
+        self.untrack_envelope(&envelope, project_key_pair, false);
+        let counted = self.uncount_top(&project_key_pair);
+        self.update_popped_stack(project_key_pair, &envelope, last_received_at, counted);
+        self.report_slow_operation("pop", started, Some(project_key_pair));
 
-        // We are fine with the count going negative, since it represents that more data was popped,
-        // than it was initially counted, meaning that we had a wrong total count from
-        // initialization.
-        self.total_count -= 1;
-        self.tracked_count = self.tracked_count.saturating_sub(1);
-        self.track_total_count();
+        Ok(Some(PoppedEnvelope {
+            envelope,
+            partition_id: self.partition_id,
+            project_key_pair,
+        }))
+    }
 
-        Ok(Some(envelope))
+    /// Pops the next-in-line envelope and returns the result of applying `f` to it.
+    ///
+    /// Behaves like [`Self::pop`], but lets the caller take apart or modify the envelope without
+    /// cloning it. The counts of the buffer are updated for the envelope as it was stored before
+    /// `f` runs, so they are correct regardless of what `f` does with the envelope.
+    pub async fn pop_map<T>(
+        &mut self,
+        f: impl FnOnce(Box<Envelope>) -> T,
+    ) -> Result<Option<T>, EnvelopeBufferError> {
+        let popped = self.pop_with_meta().await?;
+        Ok(popped.map(|popped| f(popped.envelope)))
     }
 
-    /// Re-prioritizes all stacks that involve the given project key by setting it to "ready".
+    /// Pops up to `n` envelopes from the next-in-line stack.
     ///
-    /// Returns `true` if at least one priority was changed.
-    pub fn mark_ready(&mut self, project: &ProjectKey, is_ready: bool) -> bool {
-        let mut changed = false;
-        if let Some(project_key_pairs) = self.stacks_by_project.get(project) {
-            for project_key_pair in project_key_pairs {
-                self.priority_queue
-                    .change_priority_by(project_key_pair, |stack| {
-                        let mut found = false;
-                        for (subkey, readiness) in [
-                            (
-                                project_key_pair.own_key,
-                                &mut stack.readiness.own_project_ready,
-                            ),
-                            (
-                                project_key_pair.sampling_key,
-                                &mut stack.readiness.sampling_project_ready,
-                            ),
-                        ] {
-                            if subkey == *project {
-                                found = true;
-                                if *readiness != is_ready {
-                                    changed = true;
-                                    *readiness = is_ready;
-                                }
-                            }
-                        }
-                        debug_assert!(found);
-                    });
+    /// The batch is taken from a single stack and ends early once that stack is empty or another
+    /// stack becomes next in line. `n` is clamped to `spool.envelopes.max_pop_batch`, so a single
+    /// batch never drains more than that many envelopes.
+    ///
+    /// Like [`Self::pop`], this does not check the readiness of the stack, which callers check
+    /// with [`Self::peek`]. The stack stays ready for the whole batch, since readiness only
+    /// changes through project updates between calls.
+    pub async fn pop_batch(&mut self, n: usize) -> Result<Vec<Box<Envelope>>, EnvelopeBufferError> {
+        let limit = n.min(self.max_pop_batch);
+        let mut envelopes = Vec::with_capacity(limit);
+        let mut batch_key = None;
+
+        while envelopes.len() < limit {
+            let Some(key) = self.next_stack() else {
+                break;
+            };
+            if batch_key.is_some_and(|batch_key| batch_key != key) {
+                break;
+            }
+            batch_key = Some(key);
+
+            match self.pop_with_meta().await? {
+                Some(popped) => envelopes.push(popped.envelope),
+                None => break,
             }
         }
 
-        changed
+        relay_statsd::metric!(
+            histogram(RelayHistograms::BufferPopBatchSize) = envelopes.len() as u64,
+            partition_id = &self.partition_tag
+        );
+
+        Ok(envelopes)
     }
 
-    /// Marks a stack as seen.
+    /// Pops the oldest envelope of the next-in-line stack.
     ///
-    /// Non-ready stacks are deprioritized when they are marked as seen, such that
-    /// the next call to `.peek()` will look at a different stack. This prevents
-    /// head-of-line blocking.
-    pub fn mark_seen(&mut self, project_key_pair: &ProjectKeyPair, next_fetch: Duration) {
-        self.priority_queue
-            .change_priority_by(project_key_pair, |stack| {
-                // We use the next project fetch to debounce project fetching and avoid head of
-                // line blocking of non-ready stacks.
-                stack.next_project_fetch = Instant::now() + next_fetch;
-            });
+    /// In contrast to [`Self::pop`], the envelope is taken from the bottom of the stack. This is
+    /// used to force progress when the buffer is stalled. The stack is selected like in
+    /// [`Self::pop`] and its readiness is not checked, since stalled stacks are not ready.
+    pub async fn pop_oldest(&mut self) -> Result<Option<Box<Envelope>>, EnvelopeBufferError> {
+        self.ensure_initialized()?;
+        self.expire_hot_stacks();
+        let Some(project_key_pair) = self.next_stack() else {
+            return Ok(None);
+        };
+        let envelope = self.pop_oldest_from(project_key_pair).await?;
+        Ok(Some(envelope.expect("found an empty stack")))
     }
 
-    /// Returns `true` if the underlying storage has the capacity to store more envelopes.
-    pub fn has_capacity(&self) -> bool {
-        self.stack_provider.has_store_capacity()
-    }
+    /// Pops the oldest envelope of the given stack, if the stack exists.
+    async fn pop_oldest_from(
+        &mut self,
+        project_key_pair: ProjectKeyPair,
+    ) -> Result<Option<Box<Envelope>>, EnvelopeBufferError> {
+        self.cached_peek = None;
+        let Some((QueueItem { value: stack, .. }, _)) =
+            self.priority_queue.get_mut(&project_key_pair)
+        else {
+            return Ok(None);
+        };
+        let Some(envelope) = stack.pop_oldest().await? else {
+            return Ok(None);
+        };
 
-    /// Flushes the envelope buffer.
-    pub async fn flush(&mut self) {
-        let priority_queue = mem::take(&mut self.priority_queue);
-        self.stack_provider
-            .flush(priority_queue.into_iter().map(|(q, _)| q.value))
-            .await;
+        let last_received_at = self.peek_popped_stack(project_key_pair).await;
+        self.untrack_envelope(&envelope, project_key_pair, true);
+        let counted = self.uncount_bottom(&project_key_pair, self.stack_depth(&project_key_pair));
+        self.update_popped_stack(project_key_pair, &envelope, last_received_at, counted);
+
+        Ok(Some(envelope))
     }
 
-    /// Pushes a new [`EnvelopeStack`] with the given [`Envelope`] inserted.
-    async fn push_stack(
+    /// Peeks the time at which the oldest envelope of the given stack was received.
+    async fn peek_oldest_from(
         &mut self,
-        stack_creation_type: StackCreationType,
         project_key_pair: ProjectKeyPair,
-        envelope: Option<Box<Envelope>>,
-    ) -> Result<(), EnvelopeBufferError> {
-        let received_at = envelope.as_ref().map_or(Utc::now(), |e| e.received_at());
-
-        let mut stack = self
-            .stack_provider
-            .create_stack(stack_creation_type, project_key_pair);
-        if let Some(envelope) = envelope {
-            stack.push(envelope).await?;
-        }
-
-        let previous_entry = self.priority_queue.push(
-            QueueItem {
-                key: project_key_pair,
-                value: stack,
-            },
-            Priority::new(received_at),
-        );
-        debug_assert!(previous_entry.is_none());
-        for project_key in project_key_pair.iter() {
-            self.stacks_by_project
-                .entry(project_key)
-                .or_default()
-                .insert(project_key_pair);
-        }
-        relay_statsd::metric!(
-            gauge(RelayGauges::BufferStackCount) = self.priority_queue.len() as u64,
-            partition_id = &self.partition_tag
-        );
+    ) -> Result<Option<DateTime<Utc>>, EnvelopeBufferError> {
+        let Some((QueueItem { value: stack, .. }, _)) =
+            self.priority_queue.get_mut(&project_key_pair)
+        else {
+            return Ok(None);
+        };
 
-        Ok(())
+        Ok(stack.peek_oldest().await?)
     }
 
-    /// Pops an [`EnvelopeStack`] with the supplied [`EnvelopeBufferError`].
-    fn pop_stack(&mut self, project_key_pair: ProjectKeyPair) {
-        for project_key in project_key_pair.iter() {
-            self.stacks_by_project
-                .get_mut(&project_key)
-                .expect("project_key is missing from lookup")
-                .remove(&project_key_pair);
-        }
-        self.priority_queue.remove(&project_key_pair);
+    /// Drains all envelopes from the buffer in the order they were received.
+    ///
+    /// Stacks are popped from the bottom and merged by the time at which envelopes were
+    /// received, so envelopes of all stacks are yielded with ascending `received_at`. Envelopes
+    /// received at the same time are yielded in an unspecified order. Like
+    /// [`PolymorphicEnvelopeBuffer::drain_all`], this ignores the readiness of stacks and is meant
+    /// for replaying a buffer.
+    ///
+    /// Envelopes are only removed from the buffer once they are yielded, so dropping the stream
+    /// early leaves all remaining envelopes in the buffer.
+    pub fn iter_chronological(
+        &mut self,
+    ) -> impl Stream<Item = Result<Box<Envelope>, EnvelopeBufferError>> + '_ {
+        // The time of the oldest remaining envelope of every stack, ordered oldest first.
+        let heads: BinaryHeap<Reverse<ChronologicalHead>> = BinaryHeap::new();
+
+        futures::stream::try_unfold(
+            (self, heads, false),
+            |(buffer, mut heads, started)| async move {
+                if !started {
+                    buffer.ensure_initialized()?;
+                    let stacks: Vec<_> = buffer
+                        .priority_queue
+                        .iter()
+                        .map(|(item, _)| item.key)
+                        .collect();
+                    for project_key_pair in stacks {
+                        if let Some(received_at) = buffer.peek_oldest_from(project_key_pair).await?
+                        {
+                            heads.push(Reverse(ChronologicalHead {
+                                received_at,
+                                project_key_pair,
+                            }));
+                        }
+                    }
+                }
 
-        relay_statsd::metric!(
-            gauge(RelayGauges::BufferStackCount) = self.priority_queue.len() as u64,
-            partition_id = &self.partition_tag
-        );
+                loop {
+                    let Some(Reverse(head)) = heads.pop() else {
+                        return Ok(None);
+                    };
+                    let project_key_pair = head.project_key_pair;
+                    let envelope = buffer.pop_oldest_from(project_key_pair).await?;
+                    if let Some(received_at) = buffer.peek_oldest_from(project_key_pair).await? {
+                        heads.push(Reverse(ChronologicalHead {
+                            received_at,
+                            project_key_pair,
+                        }));
+                    }
+
+                    // The stack might have been emptied since it was peeked.
+                    if let Some(envelope) = envelope {
+                        return Ok(Some((envelope, (buffer, heads, true))));
+                    }
+                }
+            },
+        )
     }
 
-    /// Creates all the [`EnvelopeStack`]s with no data given a set of [`ProjectKeyPair`].
-    async fn load_stacks(&mut self, project_key_pairs: HashSet<ProjectKeyPair>) {
-        for project_key_pair in project_key_pairs {
-            self.push_stack(StackCreationType::Initialization, project_key_pair, None)
-                .await
-                .expect("Pushing an empty stack raised an error");
-        }
+    /// Pops ready envelopes as a stream, applying readiness changes as they arrive.
+    ///
+    /// `readiness` yields projects that became ready or not ready, which are applied with
+    /// [`Self::mark_ready`] before every pop. If no stack is ready, the stream waits for the next
+    /// readiness change. Envelopes are only popped when the stream is polled, so the consumer
+    /// controls the rate. The stream ends once the buffer is empty, or if no stack is ready and
+    /// `readiness` has ended.
+    pub fn pop_stream<'a, R>(
+        &'a mut self,
+        readiness: R,
+    ) -> impl Stream<Item = Result<Box<Envelope>, EnvelopeBufferError>> + 'a
+    where
+        R: Stream<Item = (ProjectKey, bool)> + Unpin + 'a,
+    {
+        futures::stream::try_unfold(
+            (self, readiness.fuse()),
+            |(buffer, mut readiness)| async move {
+                loop {
+                    // Apply the changes that arrived since the last pop without waiting.
+                    while let Some(Some((project_key, is_ready))) = readiness.next().now_or_never()
+                    {
+                        buffer.mark_ready(&project_key, is_ready);
+                    }
+
+                    match buffer.peek().await? {
+                        Peek::Empty => return Ok(None),
+                        Peek::Ready { .. } => {
+                            let Some(envelope) = buffer.pop().await? else {
+                                return Ok(None);
+                            };
+                            return Ok(Some((envelope, (buffer, readiness))));
+                        }
+                        Peek::NotReady { .. } => match readiness.next().await {
+                            Some((project_key, is_ready)) => {
+                                buffer.mark_ready(&project_key, is_ready);
+                            }
+                            None => return Ok(None),
+                        },
+                    }
+                }
+            },
+        )
     }
 
-    /// Loads the total count from the store if it takes less than a specified duration.
+    /// Returns the time of the next envelope of a stack after an envelope was popped from it.
     ///
-    /// The total count returned by the store is related to the count of elements that the buffer
-    /// will process, besides the count of elements that will be added and removed during its
-    /// lifecycle
-    async fn load_store_total_count(&mut self) {
-        let total_count = timeout(Duration::from_secs(1), async {
-            self.stack_provider.store_total_count().await
-        })
-        .await;
-        match total_count {
-            Ok(total_count) => {
-                self.total_count = total_count as i64;
-                self.total_count_initialized = true;
-            }
+    /// The popped envelope must not be lost if this read fails, so the error is logged and the
+    /// stack keeps its previous priority instead. The stack is read again by the next peek.
+    async fn peek_popped_stack(
+        &mut self,
+        project_key_pair: ProjectKeyPair,
+    ) -> Option<DateTime<Utc>> {
+        let (retries, backoff) = (self.pop_retries, self.pop_retry_backoff);
+        let (QueueItem { value: stack, .. }, priority) =
+            self.priority_queue.get_mut(&project_key_pair)?;
+
+        match retry_read(stack, retries, backoff, |stack| Box::pin(stack.peek())).await {
+            Ok(last_received_at) => last_received_at,
             Err(error) => {
-                self.total_count_initialized = false;
                 relay_log::error!(
                     error = &error as &dyn Error,
-                    "failed to load the total envelope count of the store",
+                    "failed to peek envelope stack after pop"
                 );
+                Some(priority.received_at)
             }
-        };
-        self.track_total_count();
+        }
     }
 
-    /// Emits a metric to track the total count of envelopes that are in the envelope buffer.
-    fn track_total_count(&self) {
-        let total_count = self.total_count as f64;
-        let initialized = match self.total_count_initialized {
-            true => "true",
-            false => "false",
+    /// Updates the priority and counts after an envelope was popped from a stack.
+    ///
+    /// `counted` is whether the envelope is included in `attachment_bytes`, see
+    /// [`Self::uncount_top`] and [`Self::uncount_bottom`].
+    fn update_popped_stack(
+        &mut self,
+        project_key_pair: ProjectKeyPair,
+        envelope: &Envelope,
+        last_received_at: Option<DateTime<Utc>>,
+        counted: bool,
+    ) {
+        self.cached_peek = None;
+        match last_received_at {
+            None => {
+                self.pop_stack(project_key_pair);
+            }
+            Some(last_received_at) => {
+                let memory_resident = self.is_memory_resident(&project_key_pair);
+                let unsampled = self.update_unsampled(&project_key_pair);
+                let depth = self.stack_depth(&project_key_pair);
+                relay_statsd::metric!(
+                    timer(RelayTimers::BufferReprioritize),
+                    operation = "pop",
+                    partition_id = &self.partition_tag,
+                    {
+                        self.priority_queue
+                            .change_priority_by(&project_key_pair, |prio| {
+                                prio.received_at = last_received_at;
+                                prio.memory_resident = memory_resident;
+                                prio.unsampled = unsampled;
+                                prio.depth = depth;
+                            });
+                    }
+                );
+            }
+        }
+
+        let served_age = (Utc::now() - envelope.received_at()).num_seconds().max(0);
+        self.max_served_age = self.max_served_age.max(Some(served_age));
+
+        // We are fine with the count going negative, since it represents that more data was popped,
+        // than it was initially counted, meaning that we had a wrong total count from
+        // initialization.
+        self.total_count -= 1;
+        self.tracked_count = self.tracked_count.saturating_sub(1);
+        self.untrack_attachments(envelope, counted);
+        self.track_total_count();
+    }
+
+    /// Updates how the buffer treats the stacks of a project.
+    ///
+    /// [`Ready`](BufferedProjectState::Ready) and
+    /// [`RateLimited`](BufferedProjectState::RateLimited) behave like [`Self::mark_ready`]. For a
+    /// [`Disabled`](BufferedProjectState::Disabled) project, all stacks owned by the project are
+    /// removed and their envelopes are returned. Stacks that only use the project for dynamic
+    /// sampling are kept and marked ready, since their envelopes can be processed without it.
+    ///
+    /// If a stack cannot be read during eviction, the envelopes evicted up to that point are
+    /// returned with the error, see [`Self::evict_project`].
+    pub async fn set_project_state(
+        &mut self,
+        project: &ProjectKey,
+        state: BufferedProjectState,
+    ) -> Result<Vec<Box<Envelope>>, PartialFailure> {
+        match state {
+            BufferedProjectState::Ready => {
+                self.mark_ready(project, true);
+                Ok(Vec::new())
+            }
+            BufferedProjectState::RateLimited => {
+                self.mark_ready(project, false);
+                Ok(Vec::new())
+            }
+            BufferedProjectState::Disabled => {
+                self.mark_ready(project, true);
+                self.evict_project(project).await
+            }
+        }
+    }
+
+    /// Removes all stacks owned by the project and returns their envelopes.
+    ///
+    /// If a stack cannot be read, it remains in the buffer with the envelopes that were not read
+    /// yet. All envelopes that were removed until then are returned with the error.
+    async fn evict_project(
+        &mut self,
+        project: &ProjectKey,
+    ) -> Result<Vec<Box<Envelope>>, PartialFailure> {
+        self.cached_peek = None;
+        let owned_stacks: Vec<_> = self
+            .stacks_by_project
+            .get(project)
+            .into_iter()
+            .flatten()
+            .filter(|project_key_pair| project_key_pair.own_key == *project)
+            .copied()
+            .collect();
+
+        let mut evicted = Vec::new();
+        for project_key_pair in owned_stacks {
+            let start = evicted.len();
+            let mut result = Ok(());
+            if let Some((QueueItem { value: stack, .. }, _)) =
+                self.priority_queue.get_mut(&project_key_pair)
+            {
+                loop {
+                    match stack.pop().await {
+                        Ok(Some(envelope)) => evicted.push(envelope),
+                        Ok(None) => break,
+                        Err(error) => {
+                            result = Err(EnvelopeBufferError::from(error));
+                            break;
+                        }
+                    }
+                }
+            }
+
+            self.untrack_removed(project_key_pair, &evicted[start..]);
+            if let Err(error) = result {
+                return Err((error, evicted));
+            }
+            self.pop_stack(project_key_pair);
+        }
+
+        Ok(evicted)
+    }
+
+    /// Removes the stack of the project key pair and returns all of its envelopes.
+    ///
+    /// The envelopes are returned from the bottom to the top of the stack, that is from oldest to
+    /// newest. If the stack cannot be read, it remains in the buffer.
+    pub async fn take_stack(
+        &mut self,
+        project_key_pair: &ProjectKeyPair,
+    ) -> Result<Vec<Box<Envelope>>, EnvelopeBufferError> {
+        self.cached_peek = None;
+        let Some((QueueItem { value: stack, .. }, _)) =
+            self.priority_queue.get_mut(project_key_pair)
+        else {
+            return Ok(Vec::new());
+        };
+
+        let envelopes = stack.take_all().await?;
+        self.untrack_removed(*project_key_pair, envelopes.iter().rev());
+        self.pop_stack(*project_key_pair);
+
+        Ok(envelopes)
+    }
+
+    /// Updates the counts of the buffer for envelopes that were removed from a stack outside of a
+    /// pop.
+    ///
+    /// The envelopes must be given in the order they were removed from the top of the stack, that
+    /// is from newest to oldest.
+    fn untrack_removed<'a>(
+        &mut self,
+        project_key_pair: ProjectKeyPair,
+        envelopes: impl IntoIterator<Item = &'a Box<Envelope>>,
+    ) {
+        for envelope in envelopes {
+            self.untrack_envelope(envelope, project_key_pair, false);
+            self.total_count -= 1;
+            self.tracked_count = self.tracked_count.saturating_sub(1);
+            let counted = self.uncount_top(&project_key_pair);
+            self.untrack_attachments(envelope, counted);
+        }
+        self.track_total_count();
+    }
+
+    /// Stops counting the envelope that was removed from the top of a stack.
+    ///
+    /// Returns `true` if the envelope was included in `attachment_bytes`.
+    fn uncount_top(&mut self, project_key_pair: &ProjectKeyPair) -> bool {
+        match self.counted_envelopes.get_mut(project_key_pair) {
+            Some(counted) if *counted > 0 => {
+                *counted -= 1;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Stops counting the envelope that was removed from the bottom of a stack, given the number
+    /// of envelopes that remain in the stack.
+    ///
+    /// Returns `true` if the envelope was included in `attachment_bytes`, which is only the case
+    /// if all envelopes of the stack were counted.
+    fn uncount_bottom(&mut self, project_key_pair: &ProjectKeyPair, depth: usize) -> bool {
+        match self.counted_envelopes.get_mut(project_key_pair) {
+            Some(counted) if *counted > depth => {
+                *counted -= 1;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Subtracts the attachments of a removed envelope from `attachment_bytes` if it was counted.
+    fn untrack_attachments(&mut self, envelope: &Envelope, counted: bool) {
+        if counted {
+            self.attachment_bytes = self
+                .attachment_bytes
+                .saturating_sub(attachment_size(envelope));
+        }
+    }
+
+    /// Re-prioritizes all stacks that involve the given project key by setting it to "ready".
+    ///
+    /// Returns `true` if at least one priority was changed.
+    pub fn mark_ready(&mut self, project: &ProjectKey, is_ready: bool) -> bool {
+        self.cached_peek = None;
+        let mut changed = false;
+        if let Some(project_key_pairs) = self.stacks_by_project.get(project) {
+            relay_statsd::metric!(
+                timer(RelayTimers::BufferReprioritize),
+                operation = "mark_ready",
+                partition_id = &self.partition_tag,
+                {
+                    for project_key_pair in project_key_pairs {
+                        self.priority_queue
+                            .change_priority_by(project_key_pair, |stack| {
+                                changed |=
+                                    stack.readiness.mark(project_key_pair, project, is_ready);
+                            });
+                    }
+                }
+            );
+        }
+
+        changed
+    }
+
+    /// Marks a stack as seen.
+    ///
+    /// Non-ready stacks are deprioritized when they are marked as seen, such that
+    /// the next call to `.peek()` will look at a different stack. This prevents
+    /// head-of-line blocking. The next fetch is at least `spool.envelopes.min_fetch_debounce_ms`
+    /// away.
+    pub fn mark_seen(&mut self, project_key_pair: &ProjectKeyPair, next_fetch: Duration) {
+        self.cached_peek = None;
+        let next_fetch = next_fetch.max(self.min_fetch_debounce);
+        relay_statsd::metric!(
+            timer(RelayTimers::BufferReprioritize),
+            operation = "mark_seen",
+            partition_id = &self.partition_tag,
+            {
+                self.priority_queue
+                    .change_priority_by(project_key_pair, |stack| {
+                        // We use the next project fetch to debounce project fetching and avoid
+                        // head of line blocking of non-ready stacks.
+                        stack.next_project_fetch = Instant::now() + next_fetch;
+                    });
+            }
+        );
+    }
+
+    /// Records that an envelope of the given stack could not be popped or forwarded.
+    ///
+    /// Once the number of failures reaches the configured quarantine threshold, the stack is
+    /// quarantined and moved behind all other stacks. Quarantined stacks stay in the buffer, but
+    /// are only popped when no other stack is left.
+    ///
+    /// Returns `true` if the stack was quarantined by this call.
+    pub fn report_pop_failure(&mut self, project_key_pair: ProjectKeyPair) -> bool {
+        self.cached_peek = None;
+        let Some(threshold) = self.quarantine_threshold else {
+            return false;
+        };
+        let Some((_, priority)) = self.priority_queue.get(&project_key_pair) else {
+            return false;
         };
+        if priority.quarantined {
+            return false;
+        }
+
+        let failures = self.pop_failures.entry(project_key_pair).or_default();
+        *failures += 1;
+        if *failures < threshold.get() {
+            return false;
+        }
+
+        self.pop_failures.remove(&project_key_pair);
+        self.priority_queue
+            .change_priority_by(&project_key_pair, |priority| priority.quarantined = true);
+
+        relay_log::warn!(
+            tags.project_key = project_key_pair.own_key.as_str(),
+            tags.sampling_key = project_key_pair.sampling_key.as_str(),
+            "quarantined envelope buffer stack after {threshold} failures"
+        );
         relay_statsd::metric!(
-            histogram(RelayHistograms::BufferEnvelopesCount) = total_count,
-            initialized = initialized,
-            stack_type = self.stack_provider.stack_type(),
+            counter(RelayCounters::BufferStackQuarantined) += 1,
             partition_id = &self.partition_tag
         );
+
+        true
     }
-}
 
-/// Contains the state of the first element in the buffer.
-pub enum Peek {
-    Empty,
-    Ready {
-        project_key_pair: ProjectKeyPair,
-        last_received_at: DateTime<Utc>,
-    },
-    NotReady {
-        project_key_pair: ProjectKeyPair,
-        next_project_fetch: Instant,
-        last_received_at: DateTime<Utc>,
-    },
-}
+    /// Reports the age distribution of buffered envelopes.
+    ///
+    /// Every sampled stack reports the age of its most recent envelope in seconds. To bound the
+    /// cost for large buffers, at most [`MAX_AGE_SAMPLES`] stacks are sampled at an even stride,
+    /// starting at a different offset in every sweep.
+    ///
+    /// Additionally, the age of the oldest envelope popped since the last sweep is reported, see
+    /// [`Self::report_max_served_age`].
+    pub fn sample_envelope_ages(&mut self) {
+        self.report_max_served_age();
+
+        let stacks = self.priority_queue.len();
+        if stacks == 0 {
+            return;
+        }
 
-impl Peek {
-    pub fn last_received_at(&self) -> Option<DateTime<Utc>> {
-        match self {
-            Self::Empty => None,
-            Self::Ready {
-                last_received_at, ..
-            }
-            | Self::NotReady {
-                last_received_at, ..
-            } => Some(*last_received_at),
+        let stride = stacks.div_ceil(MAX_AGE_SAMPLES);
+        let offset = self.age_sweeps % stride;
+        self.age_sweeps = self.age_sweeps.wrapping_add(1);
+
+        let now = Utc::now();
+        for (_, priority) in self.priority_queue.iter().skip(offset).step_by(stride) {
+            let age = (now - priority.received_at).num_seconds().max(0);
+            relay_statsd::metric!(
+                histogram(RelayHistograms::BufferEnvelopeAgeDistribution) = age as f64,
+                partition_id = &self.partition_tag
+            );
         }
     }
-}
 
-#[derive(Debug)]
-struct QueueItem<K, V> {
-    key: K,
-    value: V,
-}
+    /// Reports the age of the oldest envelope popped since the last report.
+    ///
+    /// Logs a warning if the age exceeds `spool.envelopes.max_served_age_alert`, which indicates
+    /// that stacks are starved.
+    fn report_max_served_age(&mut self) {
+        let Some(age) = self.max_served_age.take() else {
+            return;
+        };
 
-impl<K, V> std::borrow::Borrow<K> for QueueItem<K, V> {
-    fn borrow(&self) -> &K {
-        &self.key
+        relay_statsd::metric!(
+            histogram(RelayHistograms::BufferMaxServedAge) = age as f64,
+            partition_id = &self.partition_tag
+        );
+
+        let threshold = self.max_served_age_alert;
+        if threshold.is_some_and(|threshold| age as u64 > threshold.as_secs()) {
+            relay_log::warn!(
+                partition_id = self.partition_id,
+                age_secs = age,
+                "envelope buffer served envelopes older than the alert threshold"
+            );
+        }
     }
-}
 
-impl<K: std::hash::Hash, V> std::hash::Hash for QueueItem<K, V> {
-    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
-        self.key.hash(state);
+    /// Returns `true` if the underlying storage has the capacity to store more envelopes.
+    ///
+    /// A buffer that reached `spool.envelopes.max_total_count` still has capacity if it can make
+    /// room by evicting an envelope of an unsampled trace, see [`Self::evict_unsampled`].
+    pub fn has_capacity(&self) -> bool {
+        let can_evict = || {
+            self.unsampled_stacks
+                .iter()
+                .any(|project_key_pair| self.is_evictable(project_key_pair))
+        };
+
+        (self.below_max_count() || can_evict()) && self.stack_provider.has_store_capacity()
     }
-}
 
-impl<K: PartialEq, V> PartialEq for QueueItem<K, V> {
-    fn eq(&self, other: &Self) -> bool {
-        self.key == other.key
+    /// Returns `true` if the buffer holds fewer envelopes than `spool.envelopes.max_total_count`.
+    fn below_max_count(&self) -> bool {
+        self.max_total_count
+            .is_none_or(|max_total_count| self.total_count < max_total_count as i64)
     }
-}
 
-impl<K: PartialEq, V> Eq for QueueItem<K, V> {}
+    /// Evicts the next envelope of an unsampled trace to make room for the given envelope.
+    ///
+    /// If `spool.envelopes.deprioritize_unsampled` is enabled and the envelope is only rejected
+    /// because the buffer reached `spool.envelopes.max_total_count`, the next envelope of an
+    /// unsampled stack is removed and returned, so that the given envelope is admitted instead.
+    /// Unsampled envelopes never evict other envelopes, and stacks of protected projects are not
+    /// evicted. Among the remaining stacks, the one that received an envelope least recently is
+    /// chosen.
+    ///
+    /// Only the next envelope of a stack is inspected. The disk-based buffer therefore only
+    /// recognizes unsampled envelopes that are held in its cache.
+    pub async fn evict_unsampled(
+        &mut self,
+        envelope: &Envelope,
+    ) -> Result<Option<Box<Envelope>>, EnvelopeBufferError> {
+        let only_full = self.admission(envelope) == Admission::RejectFull
+            && self.stack_provider.has_store_capacity()
+            && self.admits_stack(envelope);
+        if !only_full || envelope_stack::is_unsampled(envelope) {
+            return Ok(None);
+        }
+        let Some(project_key_pair) = self.evictable_unsampled() else {
+            return Ok(None);
+        };
+        let Some((QueueItem { value: stack, .. }, _)) =
+            self.priority_queue.get_mut(&project_key_pair)
+        else {
+            return Ok(None);
+        };
+        let Some(evicted) = stack.pop().await? else {
+            return Ok(None);
+        };
 
-#[derive(Debug, Clone)]
-struct Priority {
-    readiness: Readiness,
-    received_at: DateTime<Utc>,
-    next_project_fetch: Instant,
-}
+        let last_received_at = self.peek_popped_stack(project_key_pair).await;
+        self.untrack_envelope(&evicted, project_key_pair, false);
+        let counted = self.uncount_top(&project_key_pair);
+        self.update_popped_stack(project_key_pair, &evicted, last_received_at, counted);
+        relay_statsd::metric!(
+            counter(RelayCounters::BufferUnsampledEvicted) += 1,
+            partition_id = &self.partition_tag
+        );
 
-impl Priority {
-    fn new(received_at: DateTime<Utc>) -> Self {
-        Self {
-            readiness: Readiness::new(),
-            received_at,
-            next_project_fetch: Instant::now(),
+        Ok(Some(evicted))
+    }
+
+    /// Returns `true` if the envelope may be pushed without exceeding the rate of new stacks.
+    ///
+    /// Envelopes of existing stacks are always admitted. An envelope that creates a stack is only
+    /// admitted while fewer than `spool.envelopes.max_new_stacks_per_sec` distinct project pairs
+    /// have been admitted in the current second. Further envelopes of an admitted pair are
+    /// admitted as well, even if they are checked before the stack is created.
+    ///
+    /// With split processing groups, the rate applies to project pairs rather than to the
+    /// individual stacks of their groups.
+    pub fn admit(&mut self, envelope: &Envelope) -> bool {
+        let project_key_pair = ProjectKeyPair::from_envelope(envelope);
+        let exists = self.has_stack_for(&project_key_pair);
+        let Some(limiter) = self.stack_creation_limiter.as_mut() else {
+            return true;
+        };
+
+        if exists || limiter.try_admit(&project_key_pair) {
+            return true;
         }
+
+        relay_statsd::metric!(
+            counter(RelayCounters::BufferStackCreationLimited) += 1,
+            partition_id = &self.partition_tag
+        );
+        false
     }
-}
 
-impl Ord for Priority {
-    fn cmp(&self, other: &Self) -> Ordering {
-        match (self.readiness.ready(), other.readiness.ready()) {
-            // Assuming that two priorities differ only w.r.t. the `last_peek`, we want to prioritize
-            // stacks that were the least recently peeked. The rationale behind this is that we want
-            // to keep cycling through different stacks while peeking.
-            (true, true) => self.received_at.cmp(&other.received_at),
-            (true, false) => Ordering::Greater,
-            (false, true) => Ordering::Less,
-            // For non-ready stacks, we invert the priority, such that projects that are not
-            // ready and did not receive envelopes recently can be evicted.
-            (false, false) => self
-                .next_project_fetch
-                .cmp(&other.next_project_fetch)
-                .reverse()
-                .then(self.received_at.cmp(&other.received_at).reverse()),
+    /// Decides whether the envelope can be pushed, taking all configured limits into account.
+    ///
+    /// The checks are applied in order: an envelope whose attachments exceed
+    /// `spool.envelopes.max_envelope_attachment_bytes` is [`Admission::RejectOversized`], an
+    /// envelope that does not fit into the buffer is [`Admission::RejectFull`], and an envelope
+    /// that would create a stack beyond `spool.envelopes.max_new_stacks_per_sec` is
+    /// [`Admission::RejectProjectCapacity`].
+    ///
+    /// Unlike [`Self::check_admission`], this does not count the envelope towards the rate of new
+    /// stacks.
+    pub fn admission(&self, envelope: &Envelope) -> Admission {
+        if self
+            .max_envelope_attachment_bytes
+            .is_some_and(|max| attachment_size(envelope) > max)
+        {
+            return Admission::RejectOversized;
+        }
+
+        if !self.below_max_count() || !self.stack_provider.has_store_capacity() {
+            return Admission::RejectFull;
+        }
+
+        if !self.admits_stack(envelope) {
+            return Admission::RejectProjectCapacity;
+        }
+
+        Admission::Accept
+    }
+
+    /// Returns `true` if the envelope would be admitted by the rate of new stacks, without
+    /// counting it.
+    fn admits_stack(&self, envelope: &Envelope) -> bool {
+        let project_key_pair = ProjectKeyPair::from_envelope(envelope);
+        self.has_stack_for(&project_key_pair)
+            || self
+                .stack_creation_limiter
+                .as_ref()
+                .is_none_or(|limiter| limiter.would_admit(&project_key_pair))
+    }
+
+    /// Decides whether the envelope can be pushed like [`Self::admission`], and counts an accepted
+    /// envelope towards the rate of new stacks.
+    ///
+    /// Unlike [`Self::admission`], this ignores the capacity of the buffer and never returns
+    /// [`Admission::RejectFull`]. The capacity is enforced before envelopes are sent to a
+    /// partition, where the full policy of the endpoint applies.
+    pub fn check_admission(&mut self, envelope: &Envelope) -> Admission {
+        let admission = match self.admission(envelope) {
+            Admission::RejectFull if !self.admits_stack(envelope) => {
+                Admission::RejectProjectCapacity
+            }
+            Admission::RejectFull => Admission::Accept,
+            admission => admission,
+        };
+        match admission {
+            Admission::Accept => {
+                // Records the project pair, which cannot fail after `admission` accepted it.
+                self.admit(envelope);
+            }
+            Admission::RejectProjectCapacity => relay_statsd::metric!(
+                counter(RelayCounters::BufferStackCreationLimited) += 1,
+                partition_id = &self.partition_tag
+            ),
+            Admission::RejectFull | Admission::RejectOversized => (),
         }
+        admission
     }
-}
 
-impl PartialOrd for Priority {
-    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
-        Some(self.cmp(other))
+    /// Returns `true` if the buffer holds a stack for the project pair, in any processing group.
+    fn has_stack_for(&self, project_key_pair: &ProjectKeyPair) -> bool {
+        self.stacks_by_project
+            .get(&project_key_pair.own_key)
+            .is_some_and(|pairs| {
+                pairs
+                    .iter()
+                    .any(|pair| pair.sampling_key == project_key_pair.sampling_key)
+            })
     }
-}
 
-impl PartialEq for Priority {
-    fn eq(&self, other: &Self) -> bool {
-        self.cmp(other).is_eq()
+    /// Returns `true` if the envelope is a retry of a recently checked envelope.
+    ///
+    /// Envelopes are identified by a fingerprint of their project, event id and item bodies. The
+    /// buffer remembers the fingerprints of the last `spool.envelopes.fingerprint_window` checked
+    /// envelopes, including envelopes that have been popped since. Without a window, no envelope
+    /// is considered a retry. Envelopes without an event id, such as sessions or client reports,
+    /// are never considered retries, since identical payloads of them are legitimate.
+    ///
+    /// Pushing does not check for retries, so that envelopes restored into the buffer are never
+    /// dropped. Callers check fresh envelopes before they push them.
+    pub fn is_retry(&mut self, envelope: &Envelope) -> bool {
+        let Some(fingerprints) = self.fingerprints.as_mut() else {
+            return false;
+        };
+        if envelope.event_id().is_none() {
+            return false;
+        }
+
+        if fingerprints.insert(envelope) {
+            return false;
+        }
+
+        relay_statsd::metric!(
+            counter(RelayCounters::BufferRetryDropped) += 1,
+            partition_id = &self.partition_tag
+        );
+        true
+    }
+
+    /// Returns `true` if the stack with the highest priority is ready.
+    ///
+    /// Ready stacks are sorted before stacks that are not ready, so this tells whether any stack
+    /// is ready without peeking into a stack. Quarantined stacks are sorted last and are only
+    /// reported once no other stack is left, just like in [`Self::peek`].
+    ///
+    /// This reflects the readiness of the stack's projects, not whether the stack holds an
+    /// envelope. A ready stack can be empty until it is removed on the next pop.
+    pub fn has_ready(&self) -> bool {
+        match self.cached_peek {
+            Some(Peek::Ready { .. }) => return true,
+            Some(Peek::NotReady { .. }) => return false,
+            Some(Peek::Empty) | None => {}
+        }
+
+        self.priority_queue
+            .peek()
+            .is_some_and(|(_, priority)| priority.readiness.ready())
+    }
+
+    /// Applies the given settings, keeping the current value of all settings that are not set.
+    ///
+    /// Limits only apply to envelopes pushed or popped afterwards. Stacks that exceed a lowered
+    /// maximum stack depth are trimmed on their next push.
+    pub fn apply_settings(&mut self, settings: &LiveSettings) {
+        if let Some(max_total_count) = settings.max_total_count {
+            self.max_total_count = Some(max_total_count.get());
+        }
+        if let Some(max_stack_depth) = settings.max_stack_depth {
+            self.max_stack_depth = Some(max_stack_depth);
+        }
+        if let Some(max_envelope_delay_secs) = settings.max_envelope_delay_secs {
+            self.max_age = Duration::from_secs(max_envelope_delay_secs.get());
+        }
+    }
+
+    /// Applies the settings of a reloaded configuration that can change at runtime.
+    ///
+    /// This covers limits, timeouts and thresholds. Settings that affect the order or the keys of
+    /// existing stacks, such as hot projects or splitting by processing group, as well as the
+    /// location of the buffer keep their current value until Relay restarts. The windows of the
+    /// stack creation limit and of the fingerprint index are only reset if their size changes.
+    pub fn reload_config(&mut self, config: &Config) {
+        self.max_stack_depth = config.spool_envelopes_max_stack_depth();
+        self.max_total_count = config.spool_envelopes_max_total_count();
+        self.max_pop_batch = config.spool_envelopes_max_pop_batch();
+        self.max_age = config.spool_envelopes_max_age();
+        self.protected_projects = parse_project_keys(
+            config.spool_envelopes_protected_projects(),
+            "protected project",
+        );
+        self.pop_retries = config.spool_envelopes_pop_retries();
+        self.pop_retry_backoff = config.spool_envelopes_pop_retry_backoff();
+        self.body_size_sample_rate = config.spool_envelopes_body_size_sample_rate();
+        self.quarantine_threshold = config.spool_envelopes_quarantine_threshold();
+        self.min_fetch_debounce = config.spool_envelopes_min_fetch_debounce();
+        self.eviction_grace_period = config.spool_envelopes_eviction_grace_period();
+        self.empty_init_stack_lifetime = config.spool_envelopes_empty_init_stack_lifetime();
+        self.max_served_age_alert = config.spool_envelopes_max_served_age_alert();
+        self.slow_op_threshold = config.spool_envelopes_slow_op_threshold();
+        self.flush_yield_interval = config.spool_envelopes_flush_yield_interval();
+        self.max_envelope_attachment_bytes = config
+            .spool_envelopes_max_envelope_attachment_bytes()
+            .map(|bytes| bytes as u64);
+        self.peek_cache = config.spool_envelopes_peek_cache();
+        self.cached_peek = None;
+
+        let max_new_stacks_per_sec = config.spool_envelopes_max_new_stacks_per_sec();
+        if self.stack_creation_limiter.as_ref().map(|l| l.limit)
+            != max_new_stacks_per_sec.map(|limit| limit.get() as usize)
+        {
+            self.stack_creation_limiter = max_new_stacks_per_sec.map(StackCreationLimiter::new);
+        }
+
+        let fingerprint_window = config.spool_envelopes_fingerprint_window();
+        if self.fingerprints.as_ref().map(|f| f.capacity)
+            != fingerprint_window.map(NonZeroUsize::get)
+        {
+            self.fingerprints = fingerprint_window.map(FingerprintIndex::new);
+        }
+    }
+
+    /// Returns up to `limit` stacks in the order in which they are popped.
+    ///
+    /// The first stack is the one returned by [`Self::peek`]. This sorts a copy of all priorities,
+    /// so it should only be used for debugging.
+    pub fn queue_snapshot(&self, limit: usize) -> Vec<StackSnapshot> {
+        let mut stacks: Vec<_> = self
+            .priority_queue
+            .iter()
+            .map(|(item, priority)| (item.key, priority))
+            .collect();
+        stacks.sort_unstable_by(|(_, a), (_, b)| b.cmp(a));
+
+        let now = Instant::now();
+        stacks
+            .into_iter()
+            .take(limit)
+            .map(|(project_key_pair, priority)| StackSnapshot {
+                own_key: project_key_pair.own_key,
+                sampling_key: project_key_pair.sampling_key,
+                group: project_key_pair.group.map(|group| group.variant()),
+                received_at: priority.received_at,
+                own_project_ready: priority.readiness.own_project_ready(),
+                sampling_project_ready: priority.readiness.sampling_project_ready(),
+                next_project_fetch_ms: priority
+                    .next_project_fetch
+                    .saturating_duration_since(now)
+                    .as_millis() as u64,
+                hot: priority.hot,
+                quarantined: priority.quarantined,
+            })
+            .collect()
+    }
+
+    /// Returns a preview of the buffered envelope with the given event id.
+    ///
+    /// Only the stacks that hold envelopes with this event id are searched, including the
+    /// envelopes they stored on disk. Envelopes restored from the spool of a previous run are not
+    /// indexed and therefore not found.
+    pub async fn event_preview(
+        &self,
+        event_id: EventId,
+    ) -> Result<Option<EnvelopePreview>, EnvelopeBufferError> {
+        let Some(stacks) = self.events.get(&event_id) else {
+            return Ok(None);
+        };
+
+        for project_key_pair in stacks {
+            let Some((QueueItem { value: stack, .. }, _)) =
+                self.priority_queue.get(project_key_pair)
+            else {
+                continue;
+            };
+            if let Some(preview) = stack.preview(event_id).await? {
+                return Ok(Some(preview));
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// Returns the number of stacks and envelopes in the buffer.
+    pub fn stats(&self) -> BufferStats {
+        BufferStats {
+            stack_count: self.priority_queue.len(),
+            envelope_count: self.total_count.max(0) as u64,
+            total_size: self.stack_provider.total_size(),
+            oldest_received_at: self
+                .priority_queue
+                .iter()
+                .map(|(_, priority)| priority.received_at)
+                .min(),
+        }
+    }
+
+    /// Returns how evenly the buffered envelopes are distributed across projects.
+    ///
+    /// To bound the cost for large buffers, at most [`MAX_BALANCE_SAMPLES`] stacks are inspected at
+    /// an even stride. Depths of stacks backed by external storage only include envelopes pushed
+    /// since startup, see [`EnvelopeStack::depth`].
+    pub fn balance_stats(&self) -> BalanceStats {
+        let stride = self
+            .priority_queue
+            .len()
+            .div_ceil(MAX_BALANCE_SAMPLES)
+            .max(1);
+
+        let mut max_stack_depth = 0;
+        let mut project_depths = hashbrown::HashMap::<ProjectKey, usize>::new();
+        for (QueueItem { key, value: stack }, _) in self.priority_queue.iter().step_by(stride) {
+            let depth = stack.depth();
+            max_stack_depth = max_stack_depth.max(depth);
+            *project_depths.entry(key.own_key).or_default() += depth;
+        }
+
+        let mut depths: Vec<_> = project_depths.into_values().collect();
+        BalanceStats {
+            project_count: depths.len(),
+            max_stack_depth,
+            gini: gini_coefficient(&mut depths),
+        }
+    }
+
+    /// Returns diagnostics on the envelope counts of the buffer.
+    pub fn count_diagnostics(&self) -> CountDiagnostics {
+        CountDiagnostics {
+            total_count: self.total_count,
+            tracked_count: self.tracked_count,
+            initialized: self.total_count_initialized,
+        }
+    }
+
+    /// Flushes the envelope buffer.
+    ///
+    /// The most recently active projects are persisted, so that their stacks can be prioritized
+    /// after the next start. The stacks are flushed in chunks, and the buffer yields to other tasks
+    /// after every chunk, see `spool.envelopes.flush_yield_interval`.
+    pub async fn flush(&mut self) {
+        self.cached_peek = None;
+        let started = Instant::now();
+        let mut recent_stacks: Vec<_> = self
+            .priority_queue
+            .iter()
+            .map(|(item, priority)| (priority.received_at, item.key.own_key))
+            .collect();
+        recent_stacks.sort_unstable_by(|a, b| b.0.cmp(&a.0));
+        self.hot_projects
+            .store(
+                recent_stacks
+                    .into_iter()
+                    .map(|(_, project_key)| project_key),
+            )
+            .await;
+
+        let priority_queue = mem::take(&mut self.priority_queue);
+        let total = priority_queue.len();
+        relay_statsd::metric!(
+            gauge(RelayGauges::BufferFlushTotal) = total as u64,
+            partition_id = &self.partition_tag
+        );
+
+        // The stacks are flushed lazily as the provider consumes the iterator, so the gauge is
+        // updated right before each stack is flushed.
+        let partition_tag = &self.partition_tag;
+        let mut stacks = priority_queue
+            .into_iter()
+            .enumerate()
+            .map(|(flushed, (q, _))| {
+                relay_statsd::metric!(
+                    gauge(RelayGauges::BufferFlushRemaining) = (total - flushed) as u64,
+                    partition_id = partition_tag
+                );
+                q.value
+            })
+            .peekable();
+        while stacks.peek().is_some() {
+            let chunk = stacks.by_ref().take(self.flush_yield_interval);
+            self.stack_provider.flush(chunk).await;
+            tokio::task::yield_now().await;
+        }
+
+        relay_statsd::metric!(
+            gauge(RelayGauges::BufferFlushRemaining) = 0,
+            partition_id = &self.partition_tag
+        );
+        self.report_slow_operation("flush", started, None);
+    }
+
+    /// Pushes a new [`EnvelopeStack`] with the given [`Envelope`] inserted.
+    async fn push_stack(
+        &mut self,
+        stack_creation_type: StackCreationType,
+        project_key_pair: ProjectKeyPair,
+        envelope: Option<Box<Envelope>>,
+    ) -> Result<(), EnvelopeBufferError> {
+        let received_at = envelope.as_ref().map_or(Utc::now(), |e| e.received_at());
+
+        let mut stack = self
+            .stack_provider
+            .create_stack(stack_creation_type, project_key_pair);
+        if let Some(envelope) = envelope {
+            stack.push(envelope).await?;
+        }
+
+        self.insert_stack(project_key_pair, stack, received_at);
+
+        Ok(())
+    }
+
+    /// Assigns the next push sequence and detects if the system clock went backwards.
+    ///
+    /// If the wall clock is behind the latest observed time, `received_at` timestamps taken before
+    /// and after the jump cannot be compared. The sequence then starts a new clock epoch, which
+    /// orders all subsequent pushes before the ones that happened before the jump.
+    fn next_sequence(&mut self) -> Sequence {
+        let now = Utc::now();
+        if now < self.last_clock {
+            relay_log::warn!(
+                tags.partition_id = self.partition_tag.as_str(),
+                "system clock went backwards by {}ms",
+                (self.last_clock - now).num_milliseconds()
+            );
+            relay_statsd::metric!(
+                counter(RelayCounters::BufferClockBackwards) += 1,
+                partition_id = &self.partition_tag
+            );
+            self.sequence.epoch += 1;
+        }
+        self.last_clock = now;
+        self.sequence.counter += 1;
+        self.sequence
+    }
+
+    /// Inserts a created [`EnvelopeStack`] into the priority queue and the project lookup.
+    fn insert_stack(
+        &mut self,
+        project_key_pair: ProjectKeyPair,
+        stack: P::Stack,
+        received_at: DateTime<Utc>,
+    ) {
+        self.cached_peek = None;
+        let mut priority = Priority::new(
+            &project_key_pair,
+            received_at,
+            self.next_sequence(),
+            self.default_ready,
+            self.policy.clone(),
+        );
+        priority.memory_resident = self.prefer_memory_resident && stack.head_in_memory();
+        priority.unsampled = self.deprioritize_unsampled && stack.head_unsampled();
+        if priority.unsampled {
+            self.unsampled_stacks.insert(project_key_pair);
+        }
+        priority.depth = stack.depth();
+
+        let previous_entry = relay_statsd::metric!(
+            timer(RelayTimers::BufferReprioritize),
+            operation = "push_stack",
+            partition_id = &self.partition_tag,
+            {
+                self.priority_queue.push(
+                    QueueItem {
+                        key: project_key_pair,
+                        value: stack,
+                    },
+                    priority,
+                )
+            }
+        );
+        debug_assert!(previous_entry.is_none());
+        for project_key in project_key_pair.iter() {
+            self.stacks_by_project
+                .entry(project_key)
+                .or_default()
+                .insert(project_key_pair);
+        }
+        relay_statsd::metric!(
+            gauge(RelayGauges::BufferStackCount) = self.priority_queue.len() as u64,
+            partition_id = &self.partition_tag
+        );
+    }
+
+    /// Returns `true` if the stack belongs to a protected project and must never be evicted.
+    fn is_protected(&self, project_key_pair: &ProjectKeyPair) -> bool {
+        self.protected_projects.contains(&project_key_pair.own_key)
+    }
+
+    /// Returns `true` if memory resident stacks are preferred and the next envelope of the given
+    /// stack is held in memory.
+    fn is_memory_resident(&self, project_key_pair: &ProjectKeyPair) -> bool {
+        self.prefer_memory_resident
+            && self
+                .priority_queue
+                .get(project_key_pair)
+                .is_some_and(|(item, _)| item.value.head_in_memory())
+    }
+
+    /// Returns the key of the stack that is peeked and popped next.
+    ///
+    /// This is the stack with the highest priority, unless trace order is preserved and the
+    /// envelope on top of it belongs to a trace that was pushed to another ready stack first. In
+    /// that case, the other stack is popped first as long as an envelope of the trace is on top of
+    /// it.
+    fn next_stack(&self) -> Option<ProjectKeyPair> {
+        let (QueueItem { key, value: stack }, priority) = self.priority_queue.peek()?;
+        if !self.preserve_trace_order || !priority.readiness.ready() {
+            return Some(*key);
+        }
+
+        let linked = stack.head_trace_id().and_then(|trace_id| {
+            let first = *self.traces.get(&trace_id)?.front()?;
+            let (item, priority) = self.priority_queue.get(&first)?;
+            let poppable = priority.readiness.ready()
+                && !priority.quarantined
+                && item.value.head_trace_id() == Some(trace_id);
+            poppable.then_some(first)
+        });
+
+        Some(linked.unwrap_or(*key))
+    }
+
+    /// Removes an envelope that left the given stack from the trace and event indexes.
+    ///
+    /// `oldest` indicates that the envelope was removed from the bottom of the stack rather than
+    /// from the top.
+    fn untrack_envelope(
+        &mut self,
+        envelope: &Envelope,
+        project_key_pair: ProjectKeyPair,
+        oldest: bool,
+    ) {
+        if let Some(event_id) = envelope.event_id() {
+            untrack_stack(&mut self.events, event_id, project_key_pair, oldest);
+        }
+        if !self.preserve_trace_order {
+            return;
+        }
+        if let Some(trace_id) = envelope_stack::trace_id(envelope) {
+            untrack_stack(&mut self.traces, trace_id, project_key_pair, oldest);
+        }
+    }
+
+    /// Returns `true` if unsampled stacks are deprioritized and the next envelope of the given
+    /// stack belongs to an unsampled trace.
+    fn is_unsampled(&self, project_key_pair: &ProjectKeyPair) -> bool {
+        self.deprioritize_unsampled
+            && self
+                .priority_queue
+                .get(project_key_pair)
+                .is_some_and(|(item, _)| item.value.head_unsampled())
+    }
+
+    /// Like [`Self::is_unsampled`], and records the result in the set of unsampled stacks.
+    fn update_unsampled(&mut self, project_key_pair: &ProjectKeyPair) -> bool {
+        let unsampled = self.is_unsampled(project_key_pair);
+        if unsampled {
+            self.unsampled_stacks.insert(*project_key_pair);
+        } else {
+            self.unsampled_stacks.remove(project_key_pair);
+        }
+        unsampled
+    }
+
+    /// Returns `true` if envelopes of the stack may be evicted to make room for other stacks.
+    ///
+    /// Stacks of protected projects and stacks created within
+    /// `spool.envelopes.eviction_grace_period_ms` are never evicted.
+    fn is_evictable(&self, project_key_pair: &ProjectKeyPair) -> bool {
+        !self.is_protected(project_key_pair)
+            && self
+                .priority_queue
+                .get(project_key_pair)
+                .is_some_and(|(_, priority)| {
+                    priority.created_at.elapsed() >= self.eviction_grace_period
+                })
+    }
+
+    /// Returns the unsampled stack whose next envelope is evicted first.
+    ///
+    /// This is the evictable stack that received an envelope least recently.
+    fn evictable_unsampled(&self) -> Option<ProjectKeyPair> {
+        self.unsampled_stacks
+            .iter()
+            .filter(|project_key_pair| self.is_evictable(project_key_pair))
+            .filter_map(|project_key_pair| {
+                let (_, priority) = self.priority_queue.get(project_key_pair)?;
+                Some((priority.received_at, priority.sequence, *project_key_pair))
+            })
+            .min()
+            .map(|(_, _, project_key_pair)| project_key_pair)
+    }
+
+    /// Logs a warning if the operation that started at `started` exceeded the slow operation
+    /// threshold.
+    ///
+    /// The depth is the depth of the stack after the operation, or `0` if the operation does not
+    /// act on a single stack or removed it.
+    fn report_slow_operation(
+        &self,
+        operation: &'static str,
+        started: Instant,
+        project_key_pair: Option<ProjectKeyPair>,
+    ) {
+        let Some(threshold) = self.slow_op_threshold else {
+            return;
+        };
+        let duration = started.elapsed();
+        if duration <= threshold {
+            return;
+        }
+
+        let depth =
+            project_key_pair.map_or(0, |project_key_pair| self.stack_depth(&project_key_pair));
+        relay_log::warn!(
+            operation,
+            partition_id = self.partition_id,
+            duration_ms = duration.as_millis() as u64,
+            own_key = ?project_key_pair.map(|pair| pair.own_key),
+            sampling_key = ?project_key_pair.map(|pair| pair.sampling_key),
+            depth,
+            "slow envelope buffer operation"
+        );
+        relay_statsd::metric!(
+            counter(RelayCounters::BufferSlowOperation) += 1,
+            operation = operation,
+            partition_id = &self.partition_tag
+        );
+    }
+
+    /// Returns the number of envelopes in the stack of the given project key pair.
+    fn stack_depth(&self, project_key_pair: &ProjectKeyPair) -> usize {
+        self.priority_queue
+            .get(project_key_pair)
+            .map_or(0, |(item, _)| item.value.depth())
+    }
+
+    /// Pops an [`EnvelopeStack`] with the supplied [`EnvelopeBufferError`].
+    fn pop_stack(&mut self, project_key_pair: ProjectKeyPair) {
+        self.cached_peek = None;
+        self.pop_failures.remove(&project_key_pair);
+        self.init_stacks.remove(&project_key_pair);
+        self.counted_envelopes.remove(&project_key_pair);
+        self.unsampled_stacks.remove(&project_key_pair);
+        for project_key in project_key_pair.iter() {
+            self.stacks_by_project
+                .get_mut(&project_key)
+                .expect("project_key is missing from lookup")
+                .remove(&project_key_pair);
+        }
+        relay_statsd::metric!(
+            timer(RelayTimers::BufferReprioritize),
+            operation = "pop_stack",
+            partition_id = &self.partition_tag,
+            {
+                self.priority_queue.remove(&project_key_pair);
+            }
+        );
+
+        relay_statsd::metric!(
+            gauge(RelayGauges::BufferStackCount) = self.priority_queue.len() as u64,
+            partition_id = &self.partition_tag
+        );
+    }
+
+    /// Marks all stacks that involve one of the given projects as ready and moves them ahead of
+    /// all other ready stacks.
+    ///
+    /// Hot stacks stay ahead until they are drained or `hot_projects_ttl` has passed, see
+    /// [`Self::expire_hot_stacks`].
+    fn prioritize_hot_projects(&mut self, hot_projects: &HashSet<ProjectKey>) {
+        self.cached_peek = None;
+        if !hot_projects.is_empty() {
+            self.hot_until = Some(Instant::now() + self.hot_projects_ttl);
+        }
+        for project_key in hot_projects {
+            let Some(project_key_pairs) = self.stacks_by_project.get(project_key) else {
+                continue;
+            };
+
+            for project_key_pair in project_key_pairs {
+                self.priority_queue
+                    .change_priority_by(project_key_pair, |priority| {
+                        priority.readiness = Readiness::new(project_key_pair, true);
+                        priority.hot = true;
+                    });
+            }
+        }
+    }
+
+    /// Orders hot stacks like all other stacks once `hot_projects_ttl` has passed.
+    ///
+    /// This keeps a hot project that keeps receiving envelopes from starving all other stacks.
+    fn expire_hot_stacks(&mut self) {
+        match self.hot_until {
+            Some(hot_until) if hot_until <= Instant::now() => self.hot_until = None,
+            _ => return,
+        }
+
+        self.cached_peek = None;
+        let hot_stacks: Vec<_> = self
+            .priority_queue
+            .iter()
+            .filter(|(_, priority)| priority.hot)
+            .map(|(item, _)| item.key)
+            .collect();
+        for project_key_pair in hot_stacks {
+            self.priority_queue
+                .change_priority_by(&project_key_pair, |priority| priority.hot = false);
+        }
+    }
+
+    /// Creates all the [`EnvelopeStack`]s with no data given a set of [`ProjectKeyPair`].
+    ///
+    /// Up to `load_concurrency` stacks are created concurrently, they are inserted into the
+    /// priority queue in the order of the supplied pairs.
+    async fn load_stacks(&mut self, project_key_pairs: HashSet<ProjectKeyPair>) {
+        let stack_provider = &self.stack_provider;
+        let stacks: Vec<_> = futures::stream::iter(project_key_pairs)
+            .map(|project_key_pair| async move {
+                let stack = stack_provider
+                    .create_stack(StackCreationType::Initialization, project_key_pair);
+                (project_key_pair, stack)
+            })
+            .buffered(self.load_concurrency)
+            .collect()
+            .await;
+
+        let now = Instant::now();
+        for (project_key_pair, stack) in stacks {
+            self.insert_stack(project_key_pair, stack, Utc::now());
+            if self.empty_init_stack_lifetime.is_some() {
+                self.init_stacks.insert(project_key_pair, now);
+            }
+        }
+    }
+
+    /// Removes stacks loaded at startup that did not receive an envelope within the configured
+    /// lifetime and hold no envelopes.
+    ///
+    /// Stacks that still hold envelopes from startup are no longer tracked afterwards, they are
+    /// removed once popped empty.
+    pub async fn remove_empty_init_stacks(&mut self) {
+        self.cached_peek = None;
+        let Some(lifetime) = self.empty_init_stack_lifetime else {
+            return;
+        };
+
+        let expired: Vec<_> = self
+            .init_stacks
+            .extract_if(|_, created_at| created_at.elapsed() >= lifetime)
+            .map(|(project_key_pair, _)| project_key_pair)
+            .collect();
+
+        for project_key_pair in expired {
+            let Some((QueueItem { value: stack, .. }, _)) =
+                self.priority_queue.get_mut(&project_key_pair)
+            else {
+                continue;
+            };
+
+            if matches!(stack.peek().await, Ok(None)) {
+                relay_log::debug!(
+                    tags.project_key = project_key_pair.own_key.as_str(),
+                    tags.sampling_key = project_key_pair.sampling_key.as_str(),
+                    "removing empty envelope buffer stack loaded at startup"
+                );
+                self.pop_stack(project_key_pair);
+            }
+        }
+    }
+
+    /// Compares the envelopes of the loaded stacks with all envelopes in the store.
+    ///
+    /// Envelopes in the store that do not belong to any loaded stack cannot be recovered, for
+    /// example because their project keys are corrupted. A mismatch is logged and reported, and
+    /// fails the verification if it exceeds the configured maximum.
+    async fn verify_recovery(
+        &self,
+        loaded_pairs: HashSet<ProjectKeyPair>,
+    ) -> Result<(), EnvelopeBufferError> {
+        let stack_provider = &self.stack_provider;
+        let loaded = futures::stream::iter(loaded_pairs)
+            .map(|project_key_pair| stack_provider.store_stack_count(project_key_pair))
+            .buffer_unordered(self.load_concurrency)
+            .fold(0, |loaded, count| async move { loaded + count })
+            .await;
+        let stored = stack_provider.store_total_count().await;
+
+        let mismatch = stored.abs_diff(loaded);
+        relay_statsd::metric!(
+            gauge(RelayGauges::BufferRecoveryMismatch) = mismatch,
+            partition_id = &self.partition_tag
+        );
+
+        if mismatch == 0 {
+            return Ok(());
+        }
+
+        relay_log::error!(
+            tags.partition_id = self.partition_tag.as_str(),
+            "envelope buffer recovered {loaded} of {stored} envelopes in the store",
+        );
+
+        match self.verify_max_mismatch {
+            Some(max_mismatch) if mismatch > max_mismatch => {
+                Err(EnvelopeBufferError::RecoveryMismatch { stored, loaded })
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Loads the total count from the store if it takes less than a specified duration.
+    ///
+    /// The total count returned by the store is related to the count of elements that the buffer
+    /// will process, besides the count of elements that will be added and removed during its
+    /// lifecycle
+    async fn load_store_total_count(&mut self) {
+        let total_count = timeout(Duration::from_secs(1), async {
+            self.stack_provider.store_total_count().await
+        })
+        .await;
+        match total_count {
+            Ok(total_count) => {
+                self.total_count = total_count as i64;
+                self.total_count_initialized = true;
+            }
+            Err(error) => {
+                self.total_count_initialized = false;
+                relay_log::error!(
+                    error = &error as &dyn Error,
+                    "failed to load the total envelope count of the store",
+                );
+            }
+        };
+        self.track_total_count();
+    }
+
+    /// Emits a metric to track the total count of envelopes that are in the envelope buffer.
+    fn track_total_count(&self) {
+        let total_count = self.total_count as f64;
+        let initialized = match self.total_count_initialized {
+            true => "true",
+            false => "false",
+        };
+        relay_statsd::metric!(
+            histogram(RelayHistograms::BufferEnvelopesCount) = total_count,
+            initialized = initialized,
+            stack_type = self.stack_provider.stack_type(),
+            partition_id = &self.partition_tag
+        );
+    }
+}
+
+/// Returns the total payload size of attachment items in the envelope.
+fn attachment_size(envelope: &Envelope) -> u64 {
+    envelope
+        .items()
+        .filter(|item| item.ty() == &ItemType::Attachment)
+        .map(Item::len)
+        .sum::<usize>() as u64
+}
+
+/// Pushes an envelope to a stack and evicts the oldest envelope if the stack exceeds `max_depth`.
+async fn push_bounded<S: EnvelopeStack>(
+    stack: &mut S,
+    envelope: Box<Envelope>,
+    max_depth: Option<NonZeroUsize>,
+    evicted: &mut Vec<Box<Envelope>>,
+) -> Result<(), S::Error> {
+    stack.push(envelope).await?;
+    if max_depth.is_some_and(|max_depth| stack.depth() > max_depth.get()) {
+        evicted.extend(stack.pop_oldest().await?);
+    }
+    Ok(())
+}
+
+/// Properties of an envelope that are accounted once it was pushed into its stack.
+struct PushedEnvelope {
+    attachment_bytes: u64,
+    body_bytes: u64,
+    /// The trace of the envelope, if the order of traces is preserved.
+    trace_id: Option<Uuid>,
+    event_id: Option<EventId>,
+}
+
+impl PushedEnvelope {
+    fn new(envelope: &Envelope, preserve_trace_order: bool) -> Self {
+        Self {
+            attachment_bytes: attachment_size(envelope),
+            body_bytes: envelope.items().map(Item::len).sum::<usize>() as u64,
+            trace_id: envelope_stack::trace_id(envelope).filter(|_| preserve_trace_order),
+            event_id: envelope.event_id(),
+        }
+    }
+}
+
+/// Removes one entry of the given stack from the stacks indexed under `key`.
+///
+/// `oldest` removes the entry that was pushed first rather than the one pushed last.
+fn untrack_stack<K: std::hash::Hash + Eq>(
+    index: &mut hashbrown::HashMap<K, VecDeque<ProjectKeyPair>>,
+    key: K,
+    project_key_pair: ProjectKeyPair,
+    oldest: bool,
+) {
+    let hashbrown::hash_map::Entry::Occupied(mut entry) = index.entry(key) else {
+        return;
+    };
+
+    let stacks = entry.get_mut();
+    let position = if oldest {
+        stacks.iter().position(|key| *key == project_key_pair)
+    } else {
+        stacks.iter().rposition(|key| *key == project_key_pair)
+    };
+    if let Some(position) = position {
+        stacks.remove(position);
+    }
+    if stacks.is_empty() {
+        entry.remove();
+    }
+}
+
+/// Runs a read operation on an envelope stack, retrying it with exponential backoff on failure.
+///
+/// The operation must leave the stack unchanged when it fails.
+async fn retry_read<S, T>(
+    stack: &mut S,
+    retries: u32,
+    backoff: Duration,
+    mut read: impl for<'a> FnMut(&'a mut S) -> LocalBoxFuture<'a, Result<T, S::Error>>,
+) -> Result<T, S::Error>
+where
+    S: EnvelopeStack,
+{
+    let mut attempt = 0;
+    loop {
+        match read(stack).await {
+            Ok(value) => return Ok(value),
+            Err(error) if attempt < retries => {
+                relay_log::warn!(
+                    error = &error as &dyn Error,
+                    attempt,
+                    "failed to read from envelope stack, retrying"
+                );
+                tokio::time::sleep(backoff.saturating_mul(2u32.saturating_pow(attempt))).await;
+                attempt += 1;
+            }
+            Err(error) => return Err(error),
+        }
+    }
+}
+
+/// Envelope counts of a buffer, used to audit the accounting of the spool.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CountDiagnostics {
+    /// The count of envelopes including the ones that were on disk at startup.
+    ///
+    /// This count can diverge from the actual count, see `initialized`.
+    pub total_count: i64,
+    /// The count of envelopes that were pushed to the buffer since startup minus the ones popped.
+    pub tracked_count: u64,
+    /// Whether `total_count` was loaded from the store in time during initialization.
+    ///
+    /// If `false`, `total_count` ignores envelopes that were on disk at startup.
+    pub initialized: bool,
+}
+
+/// Settings of the buffer that can be changed while Relay is running.
+///
+/// Settings that are not set keep their current value. Settings that are not part of this struct,
+/// such as the path or the partitions of the buffer, require a restart and are rejected.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LiveSettings {
+    /// See `spool.envelopes.max_total_count`.
+    pub max_total_count: Option<NonZeroU64>,
+    /// See `spool.envelopes.max_stack_depth`.
+    pub max_stack_depth: Option<NonZeroUsize>,
+    /// See `spool.envelopes.max_envelope_delay_secs`.
+    pub max_envelope_delay_secs: Option<NonZeroU64>,
+}
+
+/// The priority of a stack in the buffer, used to debug the order in which stacks are popped.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct StackSnapshot {
+    /// The own project key of the stack.
+    pub own_key: ProjectKey,
+    /// The sampling project key of the stack.
+    pub sampling_key: ProjectKey,
+    /// The processing group of the stack if envelopes are split by processing group.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub group: Option<&'static str>,
+    /// The time at which the most recent envelope of the stack was received.
+    pub received_at: DateTime<Utc>,
+    /// Whether the own project is ready.
+    pub own_project_ready: bool,
+    /// Whether the sampling project is ready.
+    pub sampling_project_ready: bool,
+    /// Milliseconds until the projects of a non-ready stack are fetched again, `0` if due.
+    pub next_project_fetch_ms: u64,
+    /// Whether the stack belongs to a hot project.
+    pub hot: bool,
+    /// Whether the stack is quarantined after repeated pop failures.
+    pub quarantined: bool,
+}
+
+/// Size of a buffer, see [`EnvelopeBuffer::stats`].
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct BufferStats {
+    /// The number of stacks in the buffer.
+    pub stack_count: usize,
+    /// The number of envelopes in the buffer, including the ones that were on disk at startup.
+    pub envelope_count: u64,
+    /// The size of the buffer's storage in bytes, if it uses external storage.
+    pub total_size: Option<u64>,
+    /// The time at which the most recent envelope of the least recently received stack was
+    /// received.
+    pub oldest_received_at: Option<DateTime<Utc>>,
+}
+
+/// Distribution of buffered envelopes across projects, see [`EnvelopeBuffer::balance_stats`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BalanceStats {
+    /// The number of projects that own at least one of the inspected stacks.
+    pub project_count: usize,
+    /// The depth of the deepest inspected stack.
+    pub max_stack_depth: usize,
+    /// The Gini coefficient of the number of envelopes per project.
+    ///
+    /// `0` means that all projects buffer the same number of envelopes. Values close to `1`
+    /// indicate that a single project holds most of the buffered envelopes.
+    pub gini: f64,
+}
+
+/// Computes the Gini coefficient of the given values, sorting them in place.
+///
+/// Returns `0` if there are no values or all values are zero.
+fn gini_coefficient(values: &mut [usize]) -> f64 {
+    values.sort_unstable();
+
+    let n = values.len() as f64;
+    let total: f64 = values.iter().map(|&v| v as f64).sum();
+    if total == 0.0 {
+        return 0.0;
+    }
+
+    let weighted: f64 = values
+        .iter()
+        .enumerate()
+        .map(|(i, &v)| (i + 1) as f64 * v as f64)
+        .sum();
+
+    (2.0 * weighted) / (n * total) - (n + 1.0) / n
+}
+
+/// How the buffer treats the stacks of a project, see [`EnvelopeBuffer::set_project_state`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BufferedProjectState {
+    /// The project is available and its stacks can be popped.
+    Ready,
+    /// The project is temporarily unavailable, for example while its config is fetched.
+    ///
+    /// Envelopes of the project are held in the buffer, and its stacks are deprioritized until the
+    /// project becomes ready again.
+    RateLimited,
+    /// The project is disabled and its envelopes can never be processed.
+    Disabled,
+}
+
+/// Contains the state of the first element in the buffer.
+///
+/// `self_contained` is `true` if the envelopes of the stack need no dynamic sampling decision
+/// from another project. Such stacks only depend on the readiness of their own project.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Peek {
+    Empty,
+    Ready {
+        project_key_pair: ProjectKeyPair,
+        last_received_at: DateTime<Utc>,
+        self_contained: bool,
+    },
+    NotReady {
+        project_key_pair: ProjectKeyPair,
+        next_project_fetch: Instant,
+        last_received_at: DateTime<Utc>,
+        self_contained: bool,
+    },
+}
+
+/// The decision of [`PolymorphicEnvelopeBuffer::admission`] for an envelope.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Admission {
+    /// The envelope can be pushed.
+    Accept,
+    /// The buffer has reached its maximum size or count.
+    RejectFull,
+    /// The envelope would create a stack beyond the configured rate of new stacks.
+    RejectProjectCapacity,
+    /// The attachments of the envelope exceed the attachment limit for a single envelope.
+    RejectOversized,
+}
+
+impl Admission {
+    /// Returns the reason to report in the outcome of a rejected envelope.
+    ///
+    /// Returns `None` for [`Admission::Accept`].
+    pub fn discard_reason(self) -> Option<DiscardReason> {
+        match self {
+            Self::Accept => None,
+            Self::RejectFull => Some(DiscardReason::Internal),
+            Self::RejectProjectCapacity => Some(DiscardReason::StackCreationRate),
+            Self::RejectOversized => Some(DiscardReason::TooLarge(DiscardItemType::Attachment)),
+        }
+    }
+}
+
+/// Acknowledges an envelope popped with [`PolymorphicEnvelopeBuffer::pop_with_ack`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AckToken(i64);
+
+impl AckToken {
+    /// Returns the numeric value of the token, which is also its serialized form.
+    pub fn value(self) -> i64 {
+        self.0
+    }
+}
+
+/// An envelope popped from the buffer, along with where it was stored.
+#[derive(Debug)]
+pub struct PoppedEnvelope {
+    /// The popped envelope.
+    pub envelope: Box<Envelope>,
+    /// The id of the partition the envelope was popped from.
+    pub partition_id: u8,
+    /// The key of the stack the envelope was popped from.
+    pub project_key_pair: ProjectKeyPair,
+}
+
+impl Peek {
+    pub fn last_received_at(&self) -> Option<DateTime<Utc>> {
+        match self {
+            Self::Empty => None,
+            Self::Ready {
+                last_received_at, ..
+            }
+            | Self::NotReady {
+                last_received_at, ..
+            } => Some(*last_received_at),
+        }
+    }
+}
+
+/// The oldest remaining envelope of a stack in [`EnvelopeBuffer::iter_chronological`].
+///
+/// Heads are ordered by the time at which their envelope was received. The envelope itself stays
+/// in the stack until it is yielded.
+#[derive(Debug)]
+struct ChronologicalHead {
+    received_at: DateTime<Utc>,
+    project_key_pair: ProjectKeyPair,
+}
+
+impl PartialEq for ChronologicalHead {
+    fn eq(&self, other: &Self) -> bool {
+        self.received_at == other.received_at
+    }
+}
+
+impl Eq for ChronologicalHead {}
+
+impl PartialOrd for ChronologicalHead {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for ChronologicalHead {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.received_at.cmp(&other.received_at)
+    }
+}
+
+/// A bounded index of the fingerprints of recently pushed envelopes.
+///
+/// Once the index is full, the oldest fingerprint is evicted for every new one.
+#[derive(Debug)]
+struct FingerprintIndex {
+    capacity: usize,
+    /// The fingerprints in the order they were inserted.
+    order: VecDeque<u64>,
+    fingerprints: HashSet<u64>,
+}
+
+impl FingerprintIndex {
+    fn new(capacity: NonZeroUsize) -> Self {
+        Self {
+            capacity: capacity.get(),
+            order: VecDeque::with_capacity(capacity.get()),
+            fingerprints: HashSet::with_capacity(capacity.get()),
+        }
+    }
+
+    /// Computes the fingerprint of an envelope from its project, event id and item bodies.
+    fn fingerprint(envelope: &Envelope) -> u64 {
+        let mut hasher = std::hash::DefaultHasher::new();
+        envelope.meta().public_key().hash(&mut hasher);
+        envelope.event_id().hash(&mut hasher);
+        for item in envelope.items() {
+            item.ty().hash(&mut hasher);
+            item.payload().hash(&mut hasher);
+        }
+        hasher.finish()
+    }
+
+    /// Inserts the fingerprint of the envelope, returns `false` if it was already present.
+    fn insert(&mut self, envelope: &Envelope) -> bool {
+        let fingerprint = Self::fingerprint(envelope);
+        if !self.fingerprints.insert(fingerprint) {
+            return false;
+        }
+
+        if self.order.len() >= self.capacity {
+            if let Some(oldest) = self.order.pop_front() {
+                self.fingerprints.remove(&oldest);
+            }
+        }
+        self.order.push_back(fingerprint);
+        true
+    }
+}
+
+/// Limits the number of project pairs that create new stacks per second.
+#[derive(Debug)]
+struct StackCreationLimiter {
+    limit: usize,
+    /// The start of the current one second window.
+    window_start: Instant,
+    /// The project pairs admitted in the current window.
+    admitted: HashSet<(ProjectKey, ProjectKey)>,
+}
+
+impl StackCreationLimiter {
+    fn new(limit: NonZeroU32) -> Self {
+        Self {
+            limit: limit.get() as usize,
+            window_start: Instant::now(),
+            admitted: HashSet::new(),
+        }
+    }
+
+    /// Returns `true` if the pair was already admitted or the rate is not exhausted yet.
+    fn try_admit(&mut self, project_key_pair: &ProjectKeyPair) -> bool {
+        let now = Instant::now();
+        if now.duration_since(self.window_start) >= Duration::from_secs(1) {
+            self.window_start = now;
+            self.admitted.clear();
+        }
+
+        let key = (project_key_pair.own_key, project_key_pair.sampling_key);
+        if self.admitted.contains(&key) {
+            return true;
+        }
+        if self.admitted.len() >= self.limit {
+            return false;
+        }
+
+        self.admitted.insert(key);
+        true
+    }
+
+    /// Returns `true` if [`Self::try_admit`] would admit the pair, without admitting it.
+    fn would_admit(&self, project_key_pair: &ProjectKeyPair) -> bool {
+        if self.window_start.elapsed() >= Duration::from_secs(1) {
+            return true;
+        }
+
+        let key = (project_key_pair.own_key, project_key_pair.sampling_key);
+        self.admitted.contains(&key) || self.admitted.len() < self.limit
+    }
+}
+
+#[derive(Debug)]
+struct QueueItem<K, V> {
+    key: K,
+    value: V,
+}
+
+impl<K, V> std::borrow::Borrow<K> for QueueItem<K, V> {
+    fn borrow(&self) -> &K {
+        &self.key
+    }
+}
+
+impl<K: std::hash::Hash, V> std::hash::Hash for QueueItem<K, V> {
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        self.key.hash(state);
+    }
+}
+
+impl<K: PartialEq, V> PartialEq for QueueItem<K, V> {
+    fn eq(&self, other: &Self) -> bool {
+        self.key == other.key
+    }
+}
+
+impl<K: PartialEq, V> Eq for QueueItem<K, V> {}
+
+#[derive(Debug, Clone)]
+struct Priority<S> {
+    readiness: Readiness,
+    received_at: DateTime<Utc>,
+    next_project_fetch: Instant,
+    /// The time at which the stack was created.
+    created_at: Instant,
+    /// Whether the stack belongs to a hot project and is served before other ready stacks.
+    ///
+    /// This is cleared once `hot_projects_ttl` has passed, see
+    /// [`EnvelopeBuffer::expire_hot_stacks`].
+    hot: bool,
+    /// Whether the next envelope of the stack is held in memory.
+    ///
+    /// This is only tracked if memory resident stacks are preferred, otherwise it is always
+    /// `false`.
+    memory_resident: bool,
+    /// Whether the next envelope of the stack belongs to an unsampled trace.
+    ///
+    /// This is only tracked if unsampled stacks are deprioritized, otherwise it is always `false`.
+    unsampled: bool,
+    /// Whether the stack is quarantined and sorted behind all other stacks.
+    quarantined: bool,
+    /// The push sequence of the stack's most recent envelope.
+    ///
+    /// This breaks ties between equal `received_at` timestamps and protects the order of stacks
+    /// against the system clock going backwards.
+    sequence: Sequence,
+    /// Number of envelopes in the stack as of the last push or pop.
+    depth: usize,
+    /// The policy that compares this priority to others.
+    policy: S,
+}
+
+impl<S: SchedulingPolicy> Priority<S> {
+    fn new(
+        project_key_pair: &ProjectKeyPair,
+        received_at: DateTime<Utc>,
+        sequence: Sequence,
+        ready: bool,
+        policy: S,
+    ) -> Self {
+        Self {
+            readiness: Readiness::new(project_key_pair, ready),
+            received_at,
+            sequence,
+            next_project_fetch: Instant::now(),
+            created_at: Instant::now(),
+            hot: false,
+            memory_resident: false,
+            unsampled: false,
+            quarantined: false,
+            depth: 0,
+            policy,
+        }
+    }
+
+    /// Returns the properties of the stack that are passed to the [`SchedulingPolicy`].
+    fn meta(&self) -> StackMeta {
+        let class = if self.quarantined {
+            PriorityClass::Quarantined
+        } else if self.hot {
+            PriorityClass::Hot
+        } else {
+            PriorityClass::Normal
+        };
+
+        StackMeta {
+            ready: self.readiness.ready(),
+            received_at: self.received_at,
+            depth: self.depth,
+            next_fetch: self.next_project_fetch,
+            class,
+            memory_resident: self.memory_resident,
+            unsampled: self.unsampled,
+            sequence: self.sequence,
+        }
+    }
+}
+
+impl<S: SchedulingPolicy> Ord for Priority<S> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.policy.compare(&self.meta(), &other.meta())
+    }
+}
+
+impl<S: SchedulingPolicy> PartialOrd for Priority<S> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<S: SchedulingPolicy> PartialEq for Priority<S> {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other).is_eq()
+    }
+}
+
+impl<S: SchedulingPolicy> Eq for Priority<S> {}
+
+/// Monotonic order of pushes into a buffer, independent of the system clock.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Sequence {
+    /// Incremented every time the system clock is observed to go backwards.
+    epoch: u64,
+    /// Incremented on every push.
+    counter: u64,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Readiness {
+    /// The readiness of a stack whose own project is also its sampling project.
+    Single(bool),
+    /// The readiness of a stack with a distinct sampling project.
+    Pair {
+        own_project_ready: bool,
+        sampling_project_ready: bool,
+    },
+}
+
+impl Readiness {
+    /// Creates the readiness of a new stack.
+    ///
+    /// By default, new stacks are optimistically ready, since the large majority of stack creations
+    /// are re-creations after a stack was emptied. See `spool.envelopes.default_ready`.
+    fn new(project_key_pair: &ProjectKeyPair, ready: bool) -> Self {
+        if project_key_pair.has_distinct_sampling_key() {
+            Self::Pair {
+                own_project_ready: ready,
+                sampling_project_ready: ready,
+            }
+        } else {
+            Self::Single(ready)
+        }
+    }
+
+    fn ready(&self) -> bool {
+        self.own_project_ready() && self.sampling_project_ready()
+    }
+
+    fn own_project_ready(&self) -> bool {
+        match *self {
+            Self::Single(ready) => ready,
+            Self::Pair {
+                own_project_ready, ..
+            } => own_project_ready,
+        }
+    }
+
+    fn sampling_project_ready(&self) -> bool {
+        match *self {
+            Self::Single(ready) => ready,
+            Self::Pair {
+                sampling_project_ready,
+                ..
+            } => sampling_project_ready,
+        }
+    }
+
+    /// Marks the given project of the stack's pair as ready or not ready.
+    ///
+    /// Returns `true` if the readiness changed.
+    fn mark(
+        &mut self,
+        project_key_pair: &ProjectKeyPair,
+        project: &ProjectKey,
+        is_ready: bool,
+    ) -> bool {
+        let readiness = match self {
+            Self::Single(ready) => ready,
+            Self::Pair {
+                own_project_ready, ..
+            } if project_key_pair.own_key == *project => own_project_ready,
+            Self::Pair {
+                sampling_project_ready,
+                ..
+            } => sampling_project_ready,
+        };
+        debug_assert!(project_key_pair.iter().any(|key| key == *project));
+
+        mem::replace(readiness, is_ready) != is_ready
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::TryStreamExt;
+    use relay_common::Dsn;
+    use relay_event_schema::protocol::EventId;
+    use relay_sampling::DynamicSamplingContext;
+    use std::str::FromStr;
+    use std::sync::atomic::AtomicUsize;
+    use std::sync::Arc;
+    use uuid::Uuid;
+
+    use crate::envelope::{ContentType, Item, ItemType};
+    use crate::extractors::RequestMeta;
+    use crate::services::buffer::common::ProjectKeyPair;
+    use crate::services::buffer::envelope_stack::memory::MemoryEnvelopeStack;
+    use crate::services::buffer::envelope_store::sqlite::DatabaseEnvelope;
+    use crate::services::buffer::stack_provider::InitializationState;
+    use crate::services::buffer::testutils::utils::mock_envelopes;
+    use crate::utils::MemoryStat;
+    use crate::SqliteEnvelopeStore;
+
+    use super::*;
+
+    impl Peek {
+        fn is_empty(&self) -> bool {
+            matches!(self, Peek::Empty)
+        }
+    }
+
+    fn new_envelope(
+        own_key: ProjectKey,
+        sampling_key: Option<ProjectKey>,
+        event_id: Option<EventId>,
+    ) -> Box<Envelope> {
+        let mut envelope = Envelope::from_request(
+            None,
+            RequestMeta::new(Dsn::from_str(&format!("http://{own_key}@localhost/1")).unwrap()),
+        );
+        if let Some(sampling_key) = sampling_key {
+            envelope.set_dsc(DynamicSamplingContext {
+                public_key: sampling_key,
+                trace_id: "67e5504410b1426f9247bb680e5fe0c8".parse().unwrap(),
+                release: None,
+                user: Default::default(),
+                replay_id: None,
+                environment: None,
+                transaction: None,
+                sample_rate: None,
+                sampled: None,
+                other: Default::default(),
+            });
+            envelope.add_item(Item::new(ItemType::Transaction));
+        }
+        if let Some(event_id) = event_id {
+            envelope.set_event_id(event_id);
+        }
+        envelope
+    }
+
+    /// Creates an envelope whose dynamic sampling context marks the trace as sampled or unsampled.
+    fn new_traced_envelope(project_key: ProjectKey, sampled: bool) -> Box<Envelope> {
+        let mut envelope = new_envelope(project_key, None, None);
+        envelope.set_dsc(DynamicSamplingContext {
+            public_key: project_key,
+            trace_id: "67e5504410b1426f9247bb680e5fe0c8".parse().unwrap(),
+            release: None,
+            user: Default::default(),
+            replay_id: None,
+            environment: None,
+            transaction: None,
+            sample_rate: None,
+            sampled: Some(sampled),
+            other: Default::default(),
+        });
+        envelope
+    }
+
+    /// A memory stack that fails a shared number of reads without modifying the stack.
+    ///
+    /// Every push is delayed by `push_delay` to simulate a slow stack. Pushes only fail with
+    /// `push_failures`, which drops the pushed envelope.
+    #[derive(Debug)]
+    struct FlakyEnvelopeStack {
+        inner: MemoryEnvelopeStack,
+        failures: Arc<AtomicUsize>,
+        peek_failures: Arc<AtomicUsize>,
+        push_failures: Arc<AtomicUsize>,
+        push_delay: Duration,
+    }
+
+    impl FlakyEnvelopeStack {
+        fn fail(&self) -> Result<(), SqliteEnvelopeStackError> {
+            Self::consume_failure(&self.failures)
+        }
+
+        fn consume_failure(failures: &AtomicUsize) -> Result<(), SqliteEnvelopeStackError> {
+            match failures.fetch_update(
+                std::sync::atomic::Ordering::Relaxed,
+                std::sync::atomic::Ordering::Relaxed,
+                |n| n.checked_sub(1),
+            ) {
+                Ok(_) => Err(SqliteEnvelopeStoreError::EnvelopeExtractionError.into()),
+                Err(_) => Ok(()),
+            }
+        }
+    }
+
+    impl EnvelopeStack for FlakyEnvelopeStack {
+        type Error = SqliteEnvelopeStackError;
+
+        async fn push(&mut self, envelope: Box<Envelope>) -> Result<(), Self::Error> {
+            tokio::time::sleep(self.push_delay).await;
+            Self::consume_failure(&self.push_failures)?;
+            self.inner.push(envelope).await.unwrap();
+            Ok(())
+        }
+
+        async fn peek(&mut self) -> Result<Option<DateTime<Utc>>, Self::Error> {
+            Self::consume_failure(&self.peek_failures)?;
+            self.fail()?;
+            Ok(self.inner.peek().await.unwrap())
+        }
+
+        async fn pop(&mut self) -> Result<Option<Box<Envelope>>, Self::Error> {
+            self.fail()?;
+            Ok(self.inner.pop().await.unwrap())
+        }
+
+        async fn peek_oldest(&mut self) -> Result<Option<DateTime<Utc>>, Self::Error> {
+            self.fail()?;
+            Ok(self.inner.peek_oldest().await.unwrap())
+        }
+
+        async fn pop_oldest(&mut self) -> Result<Option<Box<Envelope>>, Self::Error> {
+            self.fail()?;
+            Ok(self.inner.pop_oldest().await.unwrap())
+        }
+
+        async fn take_all(&mut self) -> Result<Vec<Box<Envelope>>, Self::Error> {
+            self.fail()?;
+            Ok(self.inner.take_all().await.unwrap())
+        }
+
+        fn depth(&self) -> usize {
+            self.inner.depth()
+        }
+
+        async fn preview(&self, event_id: EventId) -> Result<Option<EnvelopePreview>, Self::Error> {
+            Ok(self.inner.preview(event_id).await.unwrap())
+        }
+
+        fn head_in_memory(&self) -> bool {
+            self.inner.head_in_memory()
+        }
+
+        fn head_unsampled(&self) -> bool {
+            self.inner.head_unsampled()
+        }
+
+        fn head_trace_id(&self) -> Option<Uuid> {
+            self.inner.head_trace_id()
+        }
+
+        async fn flush(self) {
+            self.inner.flush().await
+        }
+    }
+
+    #[derive(Debug)]
+    struct FlakyStackProvider {
+        failures: Arc<AtomicUsize>,
+        /// Failures that only affect peeks, which are consumed before `failures`.
+        peek_failures: Arc<AtomicUsize>,
+        /// Failures that only affect pushes.
+        push_failures: Arc<AtomicUsize>,
+        push_delay: Duration,
+    }
+
+    impl StackProvider for FlakyStackProvider {
+        type Stack = FlakyEnvelopeStack;
+
+        async fn initialize(&self) -> InitializationState {
+            InitializationState::empty()
+        }
+
+        fn create_stack(&self, _: StackCreationType, _: ProjectKeyPair) -> Self::Stack {
+            FlakyEnvelopeStack {
+                inner: MemoryEnvelopeStack::new(),
+                failures: Arc::clone(&self.failures),
+                peek_failures: Arc::clone(&self.peek_failures),
+                push_failures: Arc::clone(&self.push_failures),
+                push_delay: self.push_delay,
+            }
+        }
+
+        fn has_store_capacity(&self) -> bool {
+            true
+        }
+
+        async fn store_total_count(&self) -> u64 {
+            0
+        }
+
+        async fn round_trip(
+            &self,
+            envelope: Box<Envelope>,
+        ) -> Result<Option<Box<Envelope>>, SqliteEnvelopeStackError> {
+            Ok(Some(envelope))
+        }
+
+        async fn store_stack_count(&self, _: ProjectKeyPair) -> u64 {
+            0
+        }
+
+        fn total_size(&self) -> Option<u64> {
+            None
+        }
+
+        fn stack_type<'a>(&self) -> &'a str {
+            "flaky"
+        }
+
+        async fn flush(&mut self, envelope_stacks: impl IntoIterator<Item = Self::Stack>) {
+            for envelope_stack in envelope_stacks {
+                envelope_stack.flush().await;
+            }
+        }
+    }
+
+    fn mock_config(path: &str) -> Arc<Config> {
+        Config::from_json_value(serde_json::json!({
+            "spool": {
+                "envelopes": {
+                    "path": path
+                }
+            }
+        }))
+        .unwrap()
+        .into()
+    }
+
+    fn mock_memory_checker() -> MemoryChecker {
+        MemoryChecker::new(MemoryStat::default(), mock_config("my/db/path").clone())
+    }
+
+    async fn peek_received_at(buffer: &mut EnvelopeBuffer<MemoryStackProvider>) -> DateTime<Utc> {
+        buffer.peek().await.unwrap().last_received_at().unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_insert_pop() {
+        let mut buffer = EnvelopeBuffer::<MemoryStackProvider>::new(
+            0,
+            &Config::default(),
+            mock_memory_checker(),
+        );
+
+        let project_key1 = ProjectKey::parse("a94ae32be2584e0bbd7a4cbb95971fed").unwrap();
+        let project_key2 = ProjectKey::parse("a94ae32be2584e0bbd7a4cbb95971fee").unwrap();
+        let project_key3 = ProjectKey::parse("a94ae32be2584e0bbd7a4cbb95971fef").unwrap();
+
+        assert!(buffer.pop().await.unwrap().is_none());
+        assert!(buffer.peek().await.unwrap().is_empty());
+
+        let envelope1 = new_envelope(project_key1, None, None);
+        let time1 = envelope1.meta().received_at();
+        buffer.push(envelope1).await.unwrap();
+
+        let envelope2 = new_envelope(project_key2, None, None);
+        let time2 = envelope2.meta().received_at();
+        buffer.push(envelope2).await.unwrap();
+
+        // Both projects are ready, so project 2 is on top (has the newest envelopes):
+        assert_eq!(peek_received_at(&mut buffer).await, time2);
+
+        buffer.mark_ready(&project_key1, false);
+        buffer.mark_ready(&project_key2, false);
+
+        // Both projects are not ready, so project 1 is on top (has the oldest envelopes):
+        assert_eq!(peek_received_at(&mut buffer).await, time1);
+
+        let envelope3 = new_envelope(project_key3, None, None);
+        let time3 = envelope3.meta().received_at();
+        buffer.push(envelope3).await.unwrap();
+        buffer.mark_ready(&project_key3, false);
+
+        // All projects are not ready, so project 1 is on top (has the oldest envelopes):
+        assert_eq!(peek_received_at(&mut buffer).await, time1);
+
+        // After marking a project ready, it goes to the top:
+        buffer.mark_ready(&project_key3, true);
+        assert_eq!(peek_received_at(&mut buffer).await, time3);
+        assert_eq!(
+            buffer.pop().await.unwrap().unwrap().meta().public_key(),
+            project_key3
+        );
+
+        // After popping, project 1 is on top again:
+        assert_eq!(peek_received_at(&mut buffer).await, time1);
+
+        // Mark project 1 as ready (still on top):
+        buffer.mark_ready(&project_key1, true);
+        assert_eq!(peek_received_at(&mut buffer).await, time1);
+
+        // Mark project 2 as ready as well (now on top because most recent):
+        buffer.mark_ready(&project_key2, true);
+        assert_eq!(peek_received_at(&mut buffer).await, time2);
+        assert_eq!(
+            buffer.pop().await.unwrap().unwrap().meta().public_key(),
+            project_key2
+        );
+
+        // Pop last element:
+        assert_eq!(
+            buffer.pop().await.unwrap().unwrap().meta().public_key(),
+            project_key1
+        );
+        assert!(buffer.pop().await.unwrap().is_none());
+        assert!(buffer.peek().await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_reprioritize_metric() {
+        let mut buffer = EnvelopeBuffer::<MemoryStackProvider>::new(
+            0,
+            &Config::default(),
+            mock_memory_checker(),
+        );
+
+        let project_key = ProjectKey::parse("a94ae32be2584e0bbd7a4cbb95971fed").unwrap();
+        buffer
+            .push(new_envelope(project_key, None, None))
+            .await
+            .unwrap();
+
+        let captures = relay_statsd::with_capturing_test_client(|| {
+            buffer.mark_ready(&project_key, true);
+        });
+
+        assert_eq!(captures.len(), 1);
+        assert!(captures[0].starts_with("buffer.reprioritize.duration:"));
+        assert!(captures[0].ends_with("|h|#operation:mark_ready,partition_id:0"));
+    }
+
+    #[tokio::test]
+    async fn test_metrics_buffer_prefix() {
+        let config = Config::from_json_value(serde_json::json!({
+            "metrics": {
+                "buffer_prefix": "edge-"
+            }
+        }))
+        .unwrap();
+        let mut buffer =
+            EnvelopeBuffer::<MemoryStackProvider>::new(1, &config, mock_memory_checker());
+
+        let project_key = ProjectKey::parse("a94ae32be2584e0bbd7a4cbb95971fed").unwrap();
+        buffer
+            .push(new_envelope(project_key, None, None))
+            .await
+            .unwrap();
+
+        let captures = relay_statsd::with_capturing_test_client(|| {
+            buffer.mark_ready(&project_key, true);
+        });
+
+        assert_eq!(captures.len(), 1);
+        assert!(captures[0].ends_with("|h|#operation:mark_ready,partition_id:edge-1"));
+    }
+
+    #[tokio::test]
+    async fn test_queue_snapshot_matches_peek() {
+        let mut buffer = EnvelopeBuffer::<MemoryStackProvider>::new(
+            0,
+            &Config::default(),
+            mock_memory_checker(),
+        );
+
+        let project_keys = [
+            "a94ae32be2584e0bbd7a4cbb95971fed",
+            "a94ae32be2584e0bbd7a4cbb95971fee",
+            "a94ae32be2584e0bbd7a4cbb95971fef",
+            "a94ae32be2584e0bbd7a4cbb95971fe0",
+        ]
+        .map(|key| ProjectKey::parse(key).unwrap());
+
+        for project_key in project_keys {
+            buffer
+                .push(new_envelope(project_key, None, None))
+                .await
+                .unwrap();
+        }
+        buffer.mark_ready(&project_keys[1], false);
+        buffer.mark_ready(&project_keys[3], false);
+        buffer.mark_seen(
+            &ProjectKeyPair::new(project_keys[3], project_keys[3]),
+            Duration::from_secs(60),
+        );
+
+        let snapshot = buffer.queue_snapshot(10);
+        assert_eq!(snapshot.len(), 4);
+        assert!(snapshot[3].next_project_fetch_ms > 0);
+        assert_eq!(buffer.queue_snapshot(2), snapshot[..2]);
+
+        // Popping drains the stacks in the order of the snapshot.
+        for stack in snapshot {
+            let project_key_pair = match buffer.peek().await.unwrap() {
+                Peek::Ready {
+                    project_key_pair, ..
+                } => {
+                    assert!(stack.own_project_ready && stack.sampling_project_ready);
+                    project_key_pair
+                }
+                Peek::NotReady {
+                    project_key_pair, ..
+                } => {
+                    assert!(!stack.own_project_ready || !stack.sampling_project_ready);
+                    project_key_pair
+                }
+                Peek::Empty => panic!("buffer should not be empty"),
+            };
+            assert_eq!(
+                project_key_pair,
+                ProjectKeyPair::new(stack.own_key, stack.sampling_key)
+            );
+            buffer.pop().await.unwrap();
+        }
+        assert!(buffer.peek().await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_set_project_state_disabled_evicts() {
+        let mut buffer = EnvelopeBuffer::<MemoryStackProvider>::new(
+            0,
+            &Config::default(),
+            mock_memory_checker(),
+        );
+
+        let project_key1 = ProjectKey::parse("a94ae32be2584e0bbd7a4cbb95971fed").unwrap();
+        let project_key2 = ProjectKey::parse("a94ae32be2584e0bbd7a4cbb95971fee").unwrap();
+
+        buffer
+            .push(new_envelope(project_key1, None, None))
+            .await
+            .unwrap();
+        buffer
+            .push(new_envelope(project_key1, None, None))
+            .await
+            .unwrap();
+        let event_id = EventId::new();
+        buffer
+            .push(new_envelope(
+                project_key2,
+                Some(project_key1),
+                Some(event_id),
+            ))
+            .await
+            .unwrap();
+
+        let evicted = buffer
+            .set_project_state(&project_key1, BufferedProjectState::Disabled)
+            .await
+            .unwrap();
+        assert_eq!(evicted.len(), 2);
+        assert!(evicted
+            .iter()
+            .all(|envelope| envelope.meta().public_key() == project_key1));
+        assert_eq!(buffer.tracked_count, 1);
+
+        // The stack that only samples with the disabled project is kept and can proceed.
+        assert_eq!(
+            buffer.stacks_by_project[&project_key1],
+            BTreeSet::from([ProjectKeyPair::new(project_key2, project_key1)])
+        );
+        buffer.mark_ready(&project_key2, true);
+        assert!(matches!(buffer.peek().await.unwrap(), Peek::Ready { .. }));
+        assert_eq!(
+            buffer.pop().await.unwrap().unwrap().event_id(),
+            Some(event_id)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_set_project_state_disabled_keeps_unreadable_stack() {
+        let failures = Arc::new(AtomicUsize::new(0));
+        let mut buffer = EnvelopeBuffer::with_stack_provider(
+            0,
+            &Config::default(),
+            FlakyStackProvider {
+                failures: Arc::clone(&failures),
+                peek_failures: Arc::new(AtomicUsize::new(0)),
+                push_failures: Arc::new(AtomicUsize::new(0)),
+                push_delay: Duration::ZERO,
+            },
+            DefaultPolicy,
+        );
+        let project_key = ProjectKey::parse("a94ae32be2584e0bbd7a4cbb95971fed").unwrap();
+        for _ in 0..2 {
+            buffer
+                .push(new_envelope(project_key, None, None))
+                .await
+                .unwrap();
+        }
+
+        // The stack cannot be read, so it is kept along with its envelopes and counts.
+        failures.store(1, std::sync::atomic::Ordering::Relaxed);
+        let (_, evicted) = buffer
+            .set_project_state(&project_key, BufferedProjectState::Disabled)
+            .await
+            .unwrap_err();
+        assert!(evicted.is_empty());
+        assert_eq!(buffer.tracked_count, 2);
+        assert!(buffer.stacks_by_project[&project_key]
+            .contains(&ProjectKeyPair::new(project_key, project_key)));
+
+        let evicted = buffer
+            .set_project_state(&project_key, BufferedProjectState::Disabled)
+            .await
+            .unwrap();
+        assert_eq!(evicted.len(), 2);
+        assert_eq!(buffer.tracked_count, 0);
+        assert!(buffer.priority_queue.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_peek_self_contained() {
+        let mut buffer = EnvelopeBuffer::<MemoryStackProvider>::new(
+            0,
+            &Config::default(),
+            mock_memory_checker(),
+        );
+
+        let project_key = ProjectKey::parse("a94ae32be2584e0bbd7a4cbb95971fed").unwrap();
+        let sampling_key = ProjectKey::parse("b56ae32be2584e0bbd7a4cbb95971fed").unwrap();
+
+        let sampled_event_id = EventId::new();
+        buffer
+            .push(new_envelope(
+                project_key,
+                Some(sampling_key),
+                Some(sampled_event_id),
+            ))
+            .await
+            .unwrap();
+        let event_id = EventId::new();
+        buffer
+            .push(new_envelope(project_key, None, Some(event_id)))
+            .await
+            .unwrap();
+
+        // The envelope without a DSC does not depend on the sampling project.
+        buffer.mark_ready(&sampling_key, false);
+        let Peek::Ready {
+            project_key_pair,
+            self_contained,
+            ..
+        } = buffer.peek().await.unwrap()
+        else {
+            panic!("envelope without DSC should be ready");
+        };
+        assert_eq!(
+            project_key_pair,
+            ProjectKeyPair::new(project_key, project_key)
+        );
+        assert!(self_contained);
+        assert_eq!(
+            buffer.pop().await.unwrap().unwrap().event_id(),
+            Some(event_id)
+        );
+
+        let Peek::NotReady { self_contained, .. } = buffer.peek().await.unwrap() else {
+            panic!("envelope with DSC should wait for the sampling project");
+        };
+        assert!(!self_contained);
+        assert_eq!(
+            buffer.pop().await.unwrap().unwrap().event_id(),
+            Some(sampled_event_id)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_has_ready() {
+        let mut buffer = EnvelopeBuffer::<MemoryStackProvider>::new(
+            0,
+            &Config::default(),
+            mock_memory_checker(),
+        );
+        assert!(!buffer.has_ready());
+
+        let project_key1 = ProjectKey::parse("a94ae32be2584e0bbd7a4cbb95971fed").unwrap();
+        let project_key2 = ProjectKey::parse("b56ae32be2584e0bbd7a4cbb95971fed").unwrap();
+        buffer
+            .push(new_envelope(project_key1, None, None))
+            .await
+            .unwrap();
+        buffer
+            .push(new_envelope(project_key2, None, None))
+            .await
+            .unwrap();
+        assert!(!buffer.has_ready());
+
+        // A single ready stack is sorted above the other one.
+        buffer.mark_ready(&project_key1, true);
+        assert!(buffer.has_ready());
+
+        buffer.mark_ready(&project_key1, false);
+        assert!(!buffer.has_ready());
+
+        // Once the ready stack is drained and removed, only the other stack is left.
+        buffer.mark_ready(&project_key2, true);
+        assert!(buffer.has_ready());
+        assert!(buffer.pop().await.unwrap().is_some());
+        assert!(!buffer.has_ready());
+    }
+
+    #[tokio::test]
+    async fn test_set_project_state_rate_limited_holds() {
+        let mut buffer = EnvelopeBuffer::<MemoryStackProvider>::new(
+            0,
+            &Config::default(),
+            mock_memory_checker(),
+        );
+
+        let project_key = ProjectKey::parse("a94ae32be2584e0bbd7a4cbb95971fed").unwrap();
+        buffer
+            .push(new_envelope(project_key, None, None))
+            .await
+            .unwrap();
+
+        let evicted = buffer
+            .set_project_state(&project_key, BufferedProjectState::Ready)
+            .await
+            .unwrap();
+        assert!(evicted.is_empty());
+        assert!(matches!(buffer.peek().await.unwrap(), Peek::Ready { .. }));
+
+        let evicted = buffer
+            .set_project_state(&project_key, BufferedProjectState::RateLimited)
+            .await
+            .unwrap();
+        assert!(evicted.is_empty());
+        assert!(matches!(
+            buffer.peek().await.unwrap(),
+            Peek::NotReady { .. }
+        ));
+        assert_eq!(buffer.tracked_count, 1);
+    }
+
+    #[tokio::test]
+    async fn test_pop_with_meta() {
+        let mut buffer =
+            PolymorphicEnvelopeBuffer::InMemory(EnvelopeBuffer::<MemoryStackProvider>::new(
+                3,
+                &Config::default(),
+                mock_memory_checker(),
+            ));
+
+        let project_key1 = ProjectKey::parse("a94ae32be2584e0bbd7a4cbb95971fed").unwrap();
+        let project_key2 = ProjectKey::parse("a94ae32be2584e0bbd7a4cbb95971fee").unwrap();
+
+        for sampling_key in [None, Some(project_key2)] {
+            let event_id = EventId::new();
+            buffer
+                .push(new_envelope(project_key1, sampling_key, Some(event_id)))
+                .await
+                .unwrap();
+
+            let popped = buffer.pop_with_meta().await.unwrap().unwrap();
+            assert_eq!(popped.envelope.event_id(), Some(event_id));
+            assert_eq!(popped.partition_id, 3);
+            assert_eq!(
+                popped.project_key_pair,
+                ProjectKeyPair::new(project_key1, sampling_key.unwrap_or(project_key1))
+            );
+        }
+
+        assert!(buffer.pop_with_meta().await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_attachment_bytes() {
+        let mut buffer =
+            PolymorphicEnvelopeBuffer::InMemory(EnvelopeBuffer::<MemoryStackProvider>::new(
+                0,
+                &Config::default(),
+                mock_memory_checker(),
+            ));
+        let project_key = ProjectKey::parse("a94ae32be2584e0bbd7a4cbb95971fed").unwrap();
+
+        let with_attachment = |payload: &'static str| {
+            let mut envelope = new_envelope(project_key, None, None);
+            let mut item = Item::new(ItemType::Attachment);
+            item.set_payload(ContentType::OctetStream, payload);
+            envelope.add_item(item);
+            let mut item = Item::new(ItemType::Event);
+            item.set_payload(ContentType::Json, "{}");
+            envelope.add_item(item);
+            envelope
+        };
+
+        buffer.push(with_attachment("0123456789")).await.unwrap();
+        buffer
+            .push(new_envelope(project_key, None, None))
+            .await
+            .unwrap();
+        buffer.push(with_attachment("01234")).await.unwrap();
+        assert_eq!(buffer.attachment_bytes(), 15);
+
+        // Only the size of attachments is accounted, other items are ignored.
+        buffer.pop().await.unwrap().unwrap();
+        assert_eq!(buffer.attachment_bytes(), 10);
+        buffer.pop().await.unwrap().unwrap();
+        assert_eq!(buffer.attachment_bytes(), 10);
+        buffer.pop().await.unwrap().unwrap();
+        assert_eq!(buffer.attachment_bytes(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_pop_map() {
+        let mut buffer = EnvelopeBuffer::<MemoryStackProvider>::new(
+            0,
+            &Config::default(),
+            mock_memory_checker(),
+        );
+        let project_key = ProjectKey::parse("a94ae32be2584e0bbd7a4cbb95971fed").unwrap();
+
+        for _ in 0..2 {
+            let mut envelope = new_envelope(project_key, None, None);
+            let mut item = Item::new(ItemType::Attachment);
+            item.set_payload(ContentType::OctetStream, "0123456789");
+            envelope.add_item(item);
+            buffer.push(envelope).await.unwrap();
+        }
+        assert_eq!(buffer.tracked_count, 2);
+        assert_eq!(buffer.attachment_bytes, 20);
+
+        // The transform strips the attachment, which must not affect the accounting.
+        let stripped = buffer
+            .pop_map(|mut envelope| {
+                envelope.take_items_by(|item| item.ty() == &ItemType::Attachment);
+                envelope
+            })
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(stripped
+            .items()
+            .all(|item| item.ty() != &ItemType::Attachment));
+        assert_eq!(buffer.tracked_count, 1);
+        assert_eq!(buffer.attachment_bytes, 10);
+
+        // The transform can also discard the envelope.
+        let public_key = buffer
+            .pop_map(|envelope| envelope.meta().public_key())
+            .await
+            .unwrap();
+        assert_eq!(public_key, Some(project_key));
+        assert_eq!(buffer.tracked_count, 0);
+        assert_eq!(buffer.attachment_bytes, 0);
+
+        assert!(buffer.pop_map(|_| unreachable!()).await.unwrap().is_none());
+        assert_eq!(buffer.total_count, 0);
+    }
+
+    #[test]
+    fn test_body_size_sample_rate() {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+        let project_key = ProjectKey::parse("a94ae32be2584e0bbd7a4cbb95971fed").unwrap();
+
+        for (sample_rate, expected) in [(0.0, 0), (1.0, 3)] {
+            let config = Config::from_json_value(serde_json::json!({
+                "spool": {
+                    "envelopes": {
+                        "body_size_sample_rate": sample_rate
+                    }
+                }
+            }))
+            .unwrap();
+            let mut buffer =
+                PolymorphicEnvelopeBuffer::InMemory(EnvelopeBuffer::<MemoryStackProvider>::new(
+                    0,
+                    &config,
+                    mock_memory_checker(),
+                ));
+
+            let captures = relay_statsd::with_capturing_test_client(|| {
+                runtime.block_on(async {
+                    for _ in 0..3 {
+                        buffer
+                            .push(new_envelope(project_key, None, None))
+                            .await
+                            .unwrap();
+                    }
+                });
+            });
+
+            let body_size_metrics = captures
+                .iter()
+                .filter(|metric| metric.starts_with("buffer.envelope_body_size:"))
+                .count();
+            assert_eq!(body_size_metrics, expected, "sample rate {sample_rate}");
+        }
+    }
+
+    #[test]
+    fn test_envelope_age_distribution() {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+        let mut buffer = EnvelopeBuffer::<MemoryStackProvider>::new(
+            0,
+            &Config::default(),
+            mock_memory_checker(),
+        );
+
+        let now = Utc::now();
+        let ages = [60, 3600, 86400];
+        runtime.block_on(async {
+            for (index, age) in ages.into_iter().enumerate() {
+                let project_key = ProjectKey::parse(&format!("{index:032x}")).unwrap();
+                let mut envelope = new_envelope(project_key, None, None);
+                envelope.set_received_at(now - chrono::Duration::seconds(age));
+                buffer.push(envelope).await.unwrap();
+            }
+        });
+
+        let captures = relay_statsd::with_capturing_test_client(|| {
+            buffer.sample_envelope_ages();
+        });
+
+        let mut captures: Vec<_> = captures
+            .into_iter()
+            .filter(|metric| metric.starts_with("buffer.envelope_age:"))
+            .collect();
+        captures.sort();
+        assert_eq!(
+            captures,
+            [
+                "buffer.envelope_age:3600|h|#partition_id:0",
+                "buffer.envelope_age:60|h|#partition_id:0",
+                "buffer.envelope_age:86400|h|#partition_id:0",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_max_served_age() {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+        let mut buffer = EnvelopeBuffer::<MemoryStackProvider>::new(
+            0,
+            &Config::default(),
+            mock_memory_checker(),
+        );
+
+        let now = Utc::now();
+        let project_key = ProjectKey::parse("a94ae32be2584e0bbd7a4cbb95971fed").unwrap();
+        runtime.block_on(async {
+            for age in [86400, 60] {
+                let mut envelope = new_envelope(project_key, None, None);
+                envelope.set_received_at(now - chrono::Duration::seconds(age));
+                buffer.push(envelope).await.unwrap();
+            }
+            buffer.mark_ready(&project_key, true);
+
+            // Pops the most recent envelope first, then the aged one.
+            for _ in 0..2 {
+                buffer.pop().await.unwrap().unwrap();
+            }
+        });
+
+        let max_served_age = |buffer: &mut EnvelopeBuffer<MemoryStackProvider>| {
+            let captures = relay_statsd::with_capturing_test_client(|| {
+                buffer.sample_envelope_ages();
+            });
+            captures
+                .into_iter()
+                .filter(|metric| metric.starts_with("buffer.max_served_age:"))
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            max_served_age(&mut buffer),
+            ["buffer.max_served_age:86400|h|#partition_id:0"]
+        );
+        // The maximum is reset after every report.
+        assert!(max_served_age(&mut buffer).is_empty());
+    }
+
+    #[test]
+    fn test_pop_batch_clamped() {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+        let config = Config::from_json_value(serde_json::json!({
+            "spool": {
+                "envelopes": {
+                    "max_pop_batch": 2
+                }
+            }
+        }))
+        .unwrap();
+        let mut buffer =
+            EnvelopeBuffer::<MemoryStackProvider>::new(0, &config, mock_memory_checker());
+
+        let project_key1 = ProjectKey::parse("a94ae32be2584e0bbd7a4cbb95971fee").unwrap();
+        let project_key2 = ProjectKey::parse("a94ae32be2584e0bbd7a4cbb95971fef").unwrap();
+
+        let captures = relay_statsd::with_capturing_test_client(|| {
+            runtime.block_on(async {
+                buffer
+                    .push(new_envelope(project_key2, None, None))
+                    .await
+                    .unwrap();
+                for _ in 0..5 {
+                    buffer
+                        .push(new_envelope(project_key1, None, None))
+                        .await
+                        .unwrap();
+                }
+
+                // The request is clamped to the configured maximum.
+                let batch = buffer.pop_batch(10).await.unwrap();
+                assert_eq!(batch.len(), 2);
+                assert!(batch.iter().all(|e| e.meta().public_key() == project_key1));
+
+                // The batch ends once the stack is drained.
+                let batch = buffer.pop_batch(2).await.unwrap();
+                assert_eq!(batch.len(), 2);
+                let batch = buffer.pop_batch(2).await.unwrap();
+                assert_eq!(batch.len(), 1);
+                assert_eq!(batch[0].meta().public_key(), project_key1);
+            });
+        });
+
+        let captures: Vec<_> = captures
+            .into_iter()
+            .filter(|metric| metric.starts_with("buffer.pop_batch_size:"))
+            .collect();
+        assert_eq!(
+            captures,
+            [
+                "buffer.pop_batch_size:2|h|#partition_id:0",
+                "buffer.pop_batch_size:2|h|#partition_id:0",
+                "buffer.pop_batch_size:1|h|#partition_id:0",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_envelope_age_distribution_is_sampled() {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+        let mut buffer = EnvelopeBuffer::<MemoryStackProvider>::new(
+            0,
+            &Config::default(),
+            mock_memory_checker(),
+        );
+
+        let stacks = 2 * MAX_AGE_SAMPLES + 1;
+        runtime.block_on(async {
+            for index in 0..stacks {
+                let project_key = ProjectKey::parse(&format!("{index:032x}")).unwrap();
+                buffer
+                    .push(new_envelope(project_key, None, None))
+                    .await
+                    .unwrap();
+            }
+        });
+
+        // Every sweep samples a bounded subset, and consecutive sweeps sample different stacks.
+        let mut total = 0;
+        for _ in 0..3 {
+            let captures = relay_statsd::with_capturing_test_client(|| {
+                buffer.sample_envelope_ages();
+            });
+            let samples = captures
+                .iter()
+                .filter(|metric| metric.starts_with("buffer.envelope_age:"))
+                .count();
+            assert!(samples <= MAX_AGE_SAMPLES);
+            total += samples;
+        }
+        assert_eq!(total, stacks);
+    }
+
+    #[test]
+    fn test_flush_remaining_metric() {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+
+        let mut buffer = EnvelopeBuffer::<MemoryStackProvider>::new(
+            0,
+            &Config::default(),
+            mock_memory_checker(),
+        );
+        runtime.block_on(async {
+            for project_key in [
+                "a94ae32be2584e0bbd7a4cbb95971fed",
+                "a94ae32be2584e0bbd7a4cbb95971fee",
+            ] {
+                let project_key = ProjectKey::parse(project_key).unwrap();
+                buffer
+                    .push(new_envelope(project_key, None, None))
+                    .await
+                    .unwrap();
+            }
+        });
+
+        let captures = relay_statsd::with_capturing_test_client(|| {
+            runtime.block_on(buffer.flush());
+        });
+
+        let flush_metrics: Vec<_> = captures
+            .iter()
+            .filter(|metric| metric.starts_with("buffer.flush."))
+            .collect();
+        assert_eq!(
+            flush_metrics,
+            [
+                "buffer.flush.total:2|g|#partition_id:0",
+                "buffer.flush.remaining:2|g|#partition_id:0",
+                "buffer.flush.remaining:1|g|#partition_id:0",
+                "buffer.flush.remaining:0|g|#partition_id:0",
+            ]
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_pop_retries_failed_reads() {
+        let failures = Arc::new(AtomicUsize::new(0));
+        let mut buffer = EnvelopeBuffer::with_stack_provider(
+            0,
+            &Config::default(),
+            FlakyStackProvider {
+                failures: Arc::clone(&failures),
+                peek_failures: Arc::new(AtomicUsize::new(0)),
+                push_failures: Arc::new(AtomicUsize::new(0)),
+                push_delay: Duration::ZERO,
+            },
+            DefaultPolicy,
+        );
+        let project_key = ProjectKey::parse("a94ae32be2584e0bbd7a4cbb95971fed").unwrap();
+
+        let envelope = new_envelope(project_key, None, Some(EventId::new()));
+        let event_id = envelope.event_id();
+        buffer.push(envelope).await.unwrap();
+
+        // Transient errors are retried and the envelope is recovered.
+        failures.store(2, std::sync::atomic::Ordering::Relaxed);
+        let popped = buffer.pop().await.unwrap().unwrap();
+        assert_eq!(popped.event_id(), event_id);
+        assert!(buffer.peek().await.unwrap().is_empty());
+
+        let envelope = new_envelope(project_key, None, Some(EventId::new()));
+        let event_id = envelope.event_id();
+        buffer.push(envelope).await.unwrap();
+
+        // Once all retries fail, the error is returned and the envelope stays in the buffer.
+        failures.store(4, std::sync::atomic::Ordering::Relaxed);
+        assert!(buffer.pop().await.is_err());
+        let popped = buffer.pop().await.unwrap().unwrap();
+        assert_eq!(popped.event_id(), event_id);
+    }
+
+    #[tokio::test]
+    async fn test_pop_keeps_envelope_if_peek_fails() {
+        let config = Config::from_json_value(serde_json::json!({
+            "spool": {
+                "envelopes": {
+                    "pop_retries": 0
+                }
+            }
+        }))
+        .unwrap();
+        let peek_failures = Arc::new(AtomicUsize::new(0));
+        let mut buffer = EnvelopeBuffer::with_stack_provider(
+            0,
+            &config,
+            FlakyStackProvider {
+                failures: Arc::new(AtomicUsize::new(0)),
+                peek_failures: Arc::clone(&peek_failures),
+                push_failures: Arc::new(AtomicUsize::new(0)),
+                push_delay: Duration::ZERO,
+            },
+            DefaultPolicy,
+        );
+        let project_key = ProjectKey::parse("a94ae32be2584e0bbd7a4cbb95971fed").unwrap();
+
+        let envelope1 = new_envelope(project_key, None, Some(EventId::new()));
+        let event_id1 = envelope1.event_id();
+        let envelope2 = new_envelope(project_key, None, Some(EventId::new()));
+        let event_id2 = envelope2.event_id();
+        buffer.push(envelope1).await.unwrap();
+        buffer.push(envelope2).await.unwrap();
+
+        // The pop succeeds, but reading the next envelope of the stack afterwards fails.
+        peek_failures.store(1, std::sync::atomic::Ordering::Relaxed);
+        let popped = buffer.pop().await.unwrap().unwrap();
+        assert_eq!(popped.event_id(), event_id2);
+
+        // The stack is kept and read again.
+        assert!(matches!(buffer.peek().await.unwrap(), Peek::Ready { .. }));
+        let popped = buffer.pop().await.unwrap().unwrap();
+        assert_eq!(popped.event_id(), event_id1);
+        assert!(buffer.peek().await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_flush_yields_to_other_tasks() {
+        let config = Config::from_json_value(serde_json::json!({
+            "spool": {
+                "envelopes": {
+                    "flush_yield_interval": 2
+                }
+            }
+        }))
+        .unwrap();
+        let mut buffer =
+            EnvelopeBuffer::<MemoryStackProvider>::new(0, &config, mock_memory_checker());
+        for index in 0..10u32 {
+            let project_key = ProjectKey::parse(&format!("{index:032x}")).unwrap();
+            buffer
+                .push(new_envelope(project_key, None, None))
+                .await
+                .unwrap();
+        }
+
+        let progress = Arc::new(AtomicUsize::new(0));
+        let task = tokio::spawn({
+            let progress = Arc::clone(&progress);
+            async move {
+                loop {
+                    progress.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+                    tokio::task::yield_now().await;
+                }
+            }
+        });
+
+        // The test runtime is single threaded, so the task only runs if the flush yields.
+        buffer.flush().await;
+        assert!(progress.load(std::sync::atomic::Ordering::Relaxed) > 0);
+        assert!(buffer.priority_queue.is_empty());
+
+        task.abort();
+    }
+
+    #[test]
+    fn test_slow_operation_reported() {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_time()
+            .start_paused(true)
+            .build()
+            .unwrap();
+        let config = Config::from_json_value(serde_json::json!({
+            "spool": {
+                "envelopes": {
+                    "slow_op_threshold_ms": 100
+                }
+            }
+        }))
+        .unwrap();
+        let mut buffer = EnvelopeBuffer::with_stack_provider(
+            0,
+            &config,
+            FlakyStackProvider {
+                failures: Arc::new(AtomicUsize::new(0)),
+                peek_failures: Arc::new(AtomicUsize::new(0)),
+                push_failures: Arc::new(AtomicUsize::new(0)),
+                push_delay: Duration::from_millis(200),
+            },
+            DefaultPolicy,
+        );
+        let project_key = ProjectKey::parse("a94ae32be2584e0bbd7a4cbb95971fed").unwrap();
+
+        let captures = relay_statsd::with_capturing_test_client(|| {
+            runtime.block_on(async {
+                buffer
+                    .push(new_envelope(project_key, None, None))
+                    .await
+                    .unwrap();
+                buffer.pop().await.unwrap().unwrap();
+            });
+        });
+
+        // Only the push exceeds the threshold, the pop is not delayed.
+        let slow_operations: Vec<_> = captures
+            .iter()
+            .filter(|metric| metric.starts_with("buffer.slow_operation"))
+            .collect();
+        assert_eq!(
+            slow_operations,
+            ["buffer.slow_operation:1|c|#operation:push,partition_id:0"]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_project_internal_order() {
+        let mut buffer = EnvelopeBuffer::<MemoryStackProvider>::new(
+            0,
+            &Config::default(),
+            mock_memory_checker(),
+        );
+
+        let project_key = ProjectKey::parse("a94ae32be2584e0bbd7a4cbb95971fed").unwrap();
+
+        let envelope1 = new_envelope(project_key, None, None);
+        let time1 = envelope1.meta().received_at();
+        let envelope2 = new_envelope(project_key, None, None);
+        let time2 = envelope2.meta().received_at();
+
+        assert!(time2 > time1);
+
+        buffer.push(envelope1).await.unwrap();
+        buffer.push(envelope2).await.unwrap();
+
+        assert_eq!(
+            buffer.pop().await.unwrap().unwrap().meta().received_at(),
+            time2
+        );
+        assert_eq!(
+            buffer.pop().await.unwrap().unwrap().meta().received_at(),
+            time1
+        );
+        assert!(buffer.pop().await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_sampling_projects() {
+        let mut buffer = EnvelopeBuffer::<MemoryStackProvider>::new(
+            0,
+            &Config::default(),
+            mock_memory_checker(),
+        );
+
+        let project_key1 = ProjectKey::parse("a94ae32be2584e0bbd7a4cbb95971fed").unwrap();
+        let project_key2 = ProjectKey::parse("a94ae32be2584e0bbd7a4cbb95971fef").unwrap();
+
+        let envelope1 = new_envelope(project_key1, None, None);
+        let time1 = envelope1.received_at();
+        buffer.push(envelope1).await.unwrap();
+
+        let envelope2 = new_envelope(project_key2, None, None);
+        let time2 = envelope2.received_at();
+        buffer.push(envelope2).await.unwrap();
+
+        let envelope3 = new_envelope(project_key1, Some(project_key2), None);
+        let time3 = envelope3.meta().received_at();
+        buffer.push(envelope3).await.unwrap();
+
+        buffer.mark_ready(&project_key1, false);
+        buffer.mark_ready(&project_key2, false);
+
+        // Nothing is ready, instant1 is on top:
+        assert_eq!(
+            buffer.peek().await.unwrap().last_received_at().unwrap(),
+            time1
+        );
+
+        // Mark project 2 ready, gets on top:
+        buffer.mark_ready(&project_key2, true);
+        assert_eq!(
+            buffer.peek().await.unwrap().last_received_at().unwrap(),
+            time2
+        );
+
+        // Revert
+        buffer.mark_ready(&project_key2, false);
+        assert_eq!(
+            buffer.peek().await.unwrap().last_received_at().unwrap(),
+            time1
+        );
+
+        // Project 1 ready:
+        buffer.mark_ready(&project_key1, true);
+        assert_eq!(
+            buffer.peek().await.unwrap().last_received_at().unwrap(),
+            time1
+        );
+
+        // when both projects are ready, event no 3 ends up on top:
+        buffer.mark_ready(&project_key2, true);
+        assert_eq!(
+            buffer.pop().await.unwrap().unwrap().meta().received_at(),
+            time3
+        );
+        assert_eq!(
+            buffer.peek().await.unwrap().last_received_at().unwrap(),
+            time2
+        );
+
+        buffer.mark_ready(&project_key2, false);
+        assert_eq!(buffer.pop().await.unwrap().unwrap().received_at(), time1);
+        assert_eq!(buffer.pop().await.unwrap().unwrap().received_at(), time2);
+
+        assert!(buffer.pop().await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_project_keys_distinct() {
+        let project_key1 = ProjectKey::parse("a94ae32be2584e0bbd7a4cbb95971fed").unwrap();
+        let project_key2 = ProjectKey::parse("a94ae32be2584e0bbd7a4cbb95971fef").unwrap();
+
+        let project_key_pair1 = ProjectKeyPair::new(project_key1, project_key2);
+        let project_key_pair2 = ProjectKeyPair::new(project_key2, project_key1);
+
+        assert_ne!(project_key_pair1, project_key_pair2);
+
+        let mut buffer = EnvelopeBuffer::<MemoryStackProvider>::new(
+            0,
+            &Config::default(),
+            mock_memory_checker(),
+        );
+        buffer
+            .push(new_envelope(project_key1, Some(project_key2), None))
+            .await
+            .unwrap();
+        buffer
+            .push(new_envelope(project_key2, Some(project_key1), None))
+            .await
+            .unwrap();
+        assert_eq!(buffer.priority_queue.len(), 2);
+    }
+
+    /// Returns a config with a spool path whose parent is a file, so the database cannot be opened.
+    fn unopenable_spool_config(on_init_error: &str) -> Config {
+        let file = std::env::temp_dir().join(Uuid::new_v4().to_string());
+        std::fs::write(&file, b"").unwrap();
+
+        Config::from_json_value(serde_json::json!({
+            "spool": {
+                "envelopes": {
+                    "path": file.join("spool.db"),
+                    "on_init_error": on_init_error
+                }
+            }
+        }))
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_on_init_error_fail() {
+        let config = unopenable_spool_config("fail");
+        let result =
+            PolymorphicEnvelopeBuffer::from_config(0, &config, mock_memory_checker()).await;
+        assert!(result.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_on_init_error_fallback_memory() {
+        let config = unopenable_spool_config("fallback_memory");
+        let mut buffer = PolymorphicEnvelopeBuffer::from_config(0, &config, mock_memory_checker())
+            .await
+            .unwrap();
+        assert!(buffer.is_memory());
+
+        buffer.initialize().await.unwrap();
+        let project_key = ProjectKey::parse("a94ae32be2584e0bbd7a4cbb95971fee").unwrap();
+        buffer
+            .push(new_envelope(project_key, None, None))
+            .await
+            .unwrap();
+        assert_eq!(buffer.item_count(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_split_processing_groups() {
+        let project_key = ProjectKey::parse("a94ae32be2584e0bbd7a4cbb95971fed").unwrap();
+        let new_split_envelope = || {
+            let mut envelope = new_envelope(project_key, None, None);
+            envelope.add_item(Item::new(ItemType::Event));
+            envelope.add_item(Item::new(ItemType::Attachment));
+            envelope.add_item(Item::new(ItemType::Session));
+            envelope
+        };
+        let stack_depth = |buffer: &PolymorphicEnvelopeBuffer, group| {
+            let PolymorphicEnvelopeBuffer::InMemory(buffer) = buffer else {
+                panic!("expected a memory buffer");
+            };
+            let pair = ProjectKeyPair::new(project_key, project_key);
+            buffer.stack_depth(&group.map_or(pair, |group| pair.with_group(group)))
+        };
+
+        let config = Config::from_json_value(serde_json::json!({
+            "spool": {
+                "envelopes": {
+                    "split_processing_groups": true
+                }
+            }
+        }))
+        .unwrap();
+        let mut buffer = PolymorphicEnvelopeBuffer::from_config(0, &config, mock_memory_checker())
+            .await
+            .unwrap();
+        for _ in 0..2 {
+            let split = buffer.split_grouped(new_split_envelope());
+            assert_eq!(split.len(), 2);
+            buffer.push_grouped(split).await.unwrap();
+        }
+
+        // The attachment stays with its event, the session gets a separate stack.
+        assert_eq!(buffer.item_count(), 4);
+        assert_eq!(stack_depth(&buffer, Some(ProcessingGroup::Error)), 2);
+        assert_eq!(stack_depth(&buffer, Some(ProcessingGroup::Session)), 2);
+        assert_eq!(stack_depth(&buffer, None), 0);
+
+        let mut buffer =
+            PolymorphicEnvelopeBuffer::from_config(0, &Config::default(), mock_memory_checker())
+                .await
+                .unwrap();
+        for _ in 0..2 {
+            let split = buffer.split_grouped(new_split_envelope());
+            assert_eq!(split.len(), 1);
+            buffer.push_grouped(split).await.unwrap();
+        }
+
+        assert_eq!(buffer.item_count(), 2);
+        assert_eq!(stack_depth(&buffer, None), 2);
+    }
+
+    #[tokio::test]
+    async fn test_keyless_envelopes_share_stack() {
+        let project_key1 = ProjectKey::parse("a94ae32be2584e0bbd7a4cbb95971fed").unwrap();
+        let project_key2 = ProjectKey::parse("a94ae32be2584e0bbd7a4cbb95971fef").unwrap();
+
+        let mut buffer = EnvelopeBuffer::<MemoryStackProvider>::new(
+            0,
+            &Config::default(),
+            mock_memory_checker(),
+        );
+        buffer
+            .push(new_envelope(project_key1, None, None))
+            .await
+            .unwrap();
+
+        // A DSC without items that are sampled does not create a separate stack.
+        let mut envelope = new_envelope(project_key1, Some(project_key2), None);
+        envelope.retain_items(|item| item.ty() != &ItemType::Transaction);
+        envelope.add_item(Item::new(ItemType::Attachment));
+        assert_eq!(
+            ProjectKeyPair::from_envelope(&envelope),
+            ProjectKeyPair::new(project_key1, project_key1)
+        );
+        buffer.push(envelope).await.unwrap();
+        assert_eq!(buffer.priority_queue.len(), 1);
+
+        // Sampled items are keyed by their sampling project.
+        buffer
+            .push(new_envelope(project_key1, Some(project_key2), None))
+            .await
+            .unwrap();
+        assert_eq!(buffer.priority_queue.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_mark_ready_single_project() {
+        let config = Config::from_json_value(serde_json::json!({
+            "spool": {
+                "envelopes": {
+                    "default_ready": false
+                }
+            }
+        }))
+        .unwrap();
+        let mut buffer =
+            EnvelopeBuffer::<MemoryStackProvider>::new(0, &config, mock_memory_checker());
+
+        let project_key = ProjectKey::parse("a94ae32be2584e0bbd7a4cbb95971fed").unwrap();
+        buffer
+            .push(new_envelope(project_key, Some(project_key), None))
+            .await
+            .unwrap();
+
+        let project_key_pair = ProjectKeyPair::new(project_key, project_key);
+        let readiness = |buffer: &EnvelopeBuffer<MemoryStackProvider>| {
+            buffer
+                .priority_queue
+                .get(&project_key_pair)
+                .unwrap()
+                .1
+                .readiness
+        };
+        assert!(matches!(readiness(&buffer), Readiness::Single(false)));
+
+        // Marking the project changes the readiness of the stack once.
+        assert!(buffer.mark_ready(&project_key, true));
+        assert!(matches!(readiness(&buffer), Readiness::Single(true)));
+        assert!(!buffer.mark_ready(&project_key, true));
+        assert!(matches!(buffer.peek().await.unwrap(), Peek::Ready { .. }));
+
+        assert!(buffer.mark_ready(&project_key, false));
+        assert!(matches!(
+            buffer.peek().await.unwrap(),
+            Peek::NotReady { .. }
+        ));
+    }
+
+    #[test]
+    fn test_total_order() {
+        let p1 = Priority {
+            readiness: Readiness::Pair {
+                own_project_ready: true,
+                sampling_project_ready: true,
+            },
+            received_at: Utc::now(),
+            next_project_fetch: Instant::now(),
+            created_at: Instant::now(),
+            hot: false,
+            memory_resident: false,
+            unsampled: false,
+            quarantined: false,
+            sequence: Sequence::default(),
+            depth: 1,
+            policy: DefaultPolicy,
+        };
+        let mut p2 = p1.clone();
+        p2.next_project_fetch += Duration::from_millis(1);
+
+        // Last peek does not matter because project is ready:
+        assert_eq!(p1.cmp(&p2), Ordering::Equal);
+        assert_eq!(p1, p2);
+    }
+
+    #[tokio::test]
+    async fn test_event_preview() {
+        let mut buffer = EnvelopeBuffer::<MemoryStackProvider>::new(
+            0,
+            &Config::default(),
+            mock_memory_checker(),
+        );
+
+        let project_key = ProjectKey::parse("a94ae32be2584e0bbd7a4cbb95971fed").unwrap();
+        let event_id = EventId::new();
+        let mut envelope = new_envelope(project_key, None, Some(event_id));
+        let mut item = Item::new(ItemType::Attachment);
+        item.set_payload(ContentType::OctetStream, "data");
+        envelope.add_item(item);
+        let received_at = envelope.received_at();
+        buffer.push(envelope).await.unwrap();
+        buffer
+            .push(new_envelope(project_key, None, None))
+            .await
+            .unwrap();
+
+        let preview = buffer.event_preview(event_id).await.unwrap().unwrap();
+        assert_eq!(preview.event_id, Some(event_id));
+        assert_eq!(preview.own_key, project_key);
+        assert_eq!(preview.received_at, received_at);
+        assert_eq!(preview.items.len(), 1);
+        assert_eq!(preview.items[0].ty, "attachment");
+        assert_eq!(preview.items[0].size, 4);
+
+        assert!(buffer
+            .event_preview(EventId::new())
+            .await
+            .unwrap()
+            .is_none());
+
+        // Once the envelope is popped, it is no longer indexed.
+        buffer.pop().await.unwrap();
+        buffer.pop().await.unwrap();
+        assert!(buffer.events.is_empty());
+        assert!(buffer.event_preview(event_id).await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_event_preview_on_disk() {
+        let path = std::env::temp_dir().join(Uuid::new_v4().to_string());
+        let config = Config::from_json_value(serde_json::json!({
+            "spool": {
+                "envelopes": {
+                    "path": path,
+                    // Write every envelope to disk.
+                    "batch_size_bytes": 1
+                }
+            }
+        }))
+        .unwrap();
+        let mut buffer =
+            EnvelopeBuffer::<SqliteStackProvider>::new(0, &config, mock_memory_checker())
+                .await
+                .unwrap();
+        buffer.initialize().await.unwrap();
+
+        let project_key = ProjectKey::parse("a94ae32be2584e0bbd7a4cbb95971fed").unwrap();
+        let event_id = EventId::new();
+        let envelope = new_envelope(project_key, None, Some(event_id));
+        let received_at = envelope.received_at();
+        buffer.push(envelope).await.unwrap();
+        for _ in 0..2 {
+            buffer
+                .push(new_envelope(project_key, None, None))
+                .await
+                .unwrap();
+        }
+
+        let preview = buffer.event_preview(event_id).await.unwrap().unwrap();
+        assert_eq!(preview.event_id, Some(event_id));
+        assert_eq!(preview.own_key, project_key);
+        assert_eq!(
+            preview.received_at.timestamp_millis(),
+            received_at.timestamp_millis()
+        );
+
+        // Reading the preview leaves the envelope on disk.
+        assert_eq!(buffer.stats().envelope_count, 3);
+        assert!(buffer
+            .event_preview(EventId::new())
+            .await
+            .unwrap()
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn test_balance_stats() {
+        async fn balance_stats(depths: &[usize]) -> BalanceStats {
+            let mut buffer = EnvelopeBuffer::<MemoryStackProvider>::new(
+                0,
+                &Config::default(),
+                mock_memory_checker(),
+            );
+            for (index, &depth) in depths.iter().enumerate() {
+                let project_key = ProjectKey::parse(&format!("{index:032x}")).unwrap();
+                for _ in 0..depth {
+                    buffer
+                        .push(new_envelope(project_key, None, None))
+                        .await
+                        .unwrap();
+                }
+            }
+            buffer.balance_stats()
+        }
+
+        let even = balance_stats(&[3, 3, 3, 3]).await;
+        assert_eq!(even.project_count, 4);
+        assert_eq!(even.max_stack_depth, 3);
+        assert!(even.gini.abs() < 1e-9);
+
+        let skewed = balance_stats(&[1, 1, 1, 9]).await;
+        assert_eq!(skewed.project_count, 4);
+        assert_eq!(skewed.max_stack_depth, 9);
+        assert!((skewed.gini - 0.5).abs() < 1e-9);
+
+        assert_eq!(balance_stats(&[]).await, BalanceStats::default());
+    }
+
+    #[tokio::test]
+    async fn test_take_stack() {
+        let mut buffer = EnvelopeBuffer::<MemoryStackProvider>::new(
+            0,
+            &Config::default(),
+            mock_memory_checker(),
+        );
+
+        let project_key1 = ProjectKey::parse("a94ae32be2584e0bbd7a4cbb95971fed").unwrap();
+        let project_key2 = ProjectKey::parse("b56ae32be2584e0bbd7a4cbb95971fed").unwrap();
+
+        let mut event_ids = Vec::new();
+        for _ in 0..3 {
+            let event_id = EventId::new();
+            event_ids.push(event_id);
+            buffer
+                .push(new_envelope(project_key1, None, Some(event_id)))
+                .await
+                .unwrap();
+        }
+        buffer
+            .push(new_envelope(project_key2, None, None))
+            .await
+            .unwrap();
+
+        let project_key_pair = ProjectKeyPair::new(project_key1, project_key1);
+        let taken = buffer.take_stack(&project_key_pair).await.unwrap();
+        let taken_ids: Vec<_> = taken.iter().map(|e| e.event_id().unwrap()).collect();
+        assert_eq!(taken_ids, event_ids);
+
+        assert_eq!(buffer.priority_queue.len(), 1);
+        assert!(buffer.priority_queue.get(&project_key_pair).is_none());
+        assert!(!buffer.stacks_by_project[&project_key1].contains(&project_key_pair));
+        assert_eq!(buffer.total_count, 1);
+
+        // Taking a stack that does not exist returns nothing.
+        assert!(buffer
+            .take_stack(&project_key_pair)
+            .await
+            .unwrap()
+            .is_empty());
+
+        let popped = buffer.pop().await.unwrap().unwrap();
+        assert_eq!(popped.meta().public_key(), project_key2);
+    }
+
+    #[tokio::test]
+    async fn test_equal_received_at_uses_push_order() {
+        let mut buffer = EnvelopeBuffer::<MemoryStackProvider>::new(
+            0,
+            &Config::default(),
+            mock_memory_checker(),
+        );
+
+        let project_key1 = ProjectKey::parse("a94ae32be2584e0bbd7a4cbb95971fed").unwrap();
+        let project_key2 = ProjectKey::parse("b56ae32be2584e0bbd7a4cbb95971fed").unwrap();
+
+        let received_at = Utc::now();
+        for project_key in [project_key1, project_key2] {
+            let mut envelope = new_envelope(project_key, None, None);
+            envelope.set_received_at(received_at);
+            buffer.push(envelope).await.unwrap();
+        }
+
+        let popped = buffer.pop().await.unwrap().unwrap();
+        assert_eq!(popped.meta().public_key(), project_key2);
+        let popped = buffer.pop().await.unwrap().unwrap();
+        assert_eq!(popped.meta().public_key(), project_key1);
+    }
+
+    #[tokio::test]
+    async fn test_custom_scheduling_policy() {
+        /// Pops the deepest ready stack first.
+        #[derive(Debug, Clone)]
+        struct DeepestFirst;
+
+        impl SchedulingPolicy for DeepestFirst {
+            fn compare(&self, a: &StackMeta, b: &StackMeta) -> Ordering {
+                a.ready
+                    .cmp(&b.ready)
+                    .then(a.depth.cmp(&b.depth))
+                    .then(a.sequence.cmp(&b.sequence))
+            }
+        }
+
+        let mut buffer = EnvelopeBuffer::with_stack_provider(
+            0,
+            &Config::default(),
+            MemoryStackProvider::new(mock_memory_checker()),
+            DeepestFirst,
+        );
+
+        let project_key1 = ProjectKey::parse("a94ae32be2584e0bbd7a4cbb95971fed").unwrap();
+        let project_key2 = ProjectKey::parse("b56ae32be2584e0bbd7a4cbb95971fed").unwrap();
+
+        buffer
+            .push(new_envelope(project_key1, None, None))
+            .await
+            .unwrap();
+        buffer
+            .push(new_envelope(project_key1, None, None))
+            .await
+            .unwrap();
+        buffer
+            .push(new_envelope(project_key2, None, None))
+            .await
+            .unwrap();
+
+        // The default policy would pop the most recent envelope of `project_key2` first.
+        let mut popped = vec![];
+        while let Some(envelope) = buffer.pop().await.unwrap() {
+            popped.push(envelope.meta().public_key());
+        }
+        assert_eq!(popped, [project_key1, project_key2, project_key1]);
+    }
+
+    #[test]
+    fn test_clock_backwards_keeps_push_order() {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+        let mut buffer = EnvelopeBuffer::<MemoryStackProvider>::new(
+            0,
+            &Config::default(),
+            mock_memory_checker(),
+        );
+
+        let project_key1 = ProjectKey::parse("a94ae32be2584e0bbd7a4cbb95971fed").unwrap();
+        let project_key2 = ProjectKey::parse("b56ae32be2584e0bbd7a4cbb95971fed").unwrap();
+
+        let captures = relay_statsd::with_capturing_test_client(|| {
+            runtime.block_on(async {
+                buffer
+                    .push(new_envelope(project_key1, None, None))
+                    .await
+                    .unwrap();
+
+                // Simulate the system clock jumping back by an hour after the first push.
+                buffer.last_clock = Utc::now() + chrono::Duration::hours(1);
+                let mut envelope = new_envelope(project_key2, None, None);
+                envelope.set_received_at(Utc::now() - chrono::Duration::hours(1));
+                buffer.push(envelope).await.unwrap();
+
+                let popped = buffer.pop().await.unwrap().unwrap();
+                assert_eq!(popped.meta().public_key(), project_key2);
+                let popped = buffer.pop().await.unwrap().unwrap();
+                assert_eq!(popped.meta().public_key(), project_key1);
+            });
+        });
+
+        assert!(captures.contains(&"buffer.clock_backwards:1|c|#partition_id:0".to_owned()));
+    }
+
+    #[tokio::test]
+    async fn test_last_peek_internal_order() {
+        let mut buffer = EnvelopeBuffer::<MemoryStackProvider>::new(
+            0,
+            &Config::default(),
+            mock_memory_checker(),
+        );
+
+        let project_key_1 = ProjectKey::parse("a94ae32be2584e0bbd7a4cbb95971fed").unwrap();
+        let event_id_1 = EventId::new();
+        let envelope1 = new_envelope(project_key_1, None, Some(event_id_1));
+        let time1 = envelope1.received_at();
+
+        let project_key_2 = ProjectKey::parse("b56ae32be2584e0bbd7a4cbb95971fed").unwrap();
+        let event_id_2 = EventId::new();
+        let envelope2 = new_envelope(project_key_2, None, Some(event_id_2));
+        let time2 = envelope2.received_at();
+
+        buffer.push(envelope1).await.unwrap();
+        buffer.push(envelope2).await.unwrap();
+
+        buffer.mark_ready(&project_key_1, false);
+        buffer.mark_ready(&project_key_2, false);
+
+        // event_id_1 is first element:
+        let Peek::NotReady {
+            last_received_at, ..
+        } = buffer.peek().await.unwrap()
+        else {
+            panic!();
+        };
+        assert_eq!(last_received_at, time1);
+
+        // Second peek returns same element:
+        let Peek::NotReady {
+            last_received_at,
+            project_key_pair,
+            ..
+        } = buffer.peek().await.unwrap()
+        else {
+            panic!();
+        };
+        assert_eq!(last_received_at, time1);
+        assert_ne!(last_received_at, time2);
+
+        buffer.mark_seen(&project_key_pair, Duration::ZERO);
+
+        // After mark_seen, event 2 is on top:
+        let Peek::NotReady {
+            last_received_at, ..
+        } = buffer.peek().await.unwrap()
+        else {
+            panic!();
+        };
+        assert_eq!(last_received_at, time2);
+        assert_ne!(last_received_at, time1);
+
+        let Peek::NotReady {
+            last_received_at,
+            project_key_pair,
+            ..
+        } = buffer.peek().await.unwrap()
+        else {
+            panic!();
+        };
+        assert_eq!(last_received_at, time2);
+        assert_ne!(last_received_at, time1);
+
+        buffer.mark_seen(&project_key_pair, Duration::ZERO);
+
+        // After another mark_seen, cycle back to event 1:
+        let Peek::NotReady {
+            last_received_at, ..
+        } = buffer.peek().await.unwrap()
+        else {
+            panic!();
+        };
+        assert_eq!(last_received_at, time1);
+        assert_ne!(last_received_at, time2);
+    }
+
+    #[tokio::test]
+    async fn test_peek_skips_backed_off_stack() {
+        let mut buffer = EnvelopeBuffer::<MemoryStackProvider>::new(
+            0,
+            &mock_config("my/db/path"),
+            mock_memory_checker(),
+        );
+
+        let project_key_1 = ProjectKey::parse("a94ae32be2584e0bbd7a4cbb95971fed").unwrap();
+        let project_key_2 = ProjectKey::parse("b56ae32be2584e0bbd7a4cbb95971fed").unwrap();
+        buffer
+            .push(new_envelope(project_key_1, None, None))
+            .await
+            .unwrap();
+        buffer
+            .push(new_envelope(project_key_2, None, None))
+            .await
+            .unwrap();
+        buffer.mark_ready(&project_key_1, false);
+        buffer.mark_ready(&project_key_2, false);
+
+        let Peek::NotReady {
+            project_key_pair: top,
+            ..
+        } = buffer.peek().await.unwrap()
+        else {
+            panic!();
+        };
+
+        // Backing off the top stack makes the other stack, whose fetch is due, the next in line.
+        buffer.mark_seen(&top, Duration::from_secs(10));
+        let Peek::NotReady {
+            project_key_pair,
+            next_project_fetch,
+            ..
+        } = buffer.peek().await.unwrap()
+        else {
+            panic!();
+        };
+        assert_ne!(project_key_pair, top);
+        assert!(next_project_fetch <= Instant::now());
+
+        // Once all stacks are backed off, the stack with the soonest fetch is returned.
+        buffer.mark_seen(&project_key_pair, Duration::from_secs(5));
+        let Peek::NotReady {
+            project_key_pair: soonest,
+            next_project_fetch,
+            ..
+        } = buffer.peek().await.unwrap()
+        else {
+            panic!();
+        };
+        assert_eq!(soonest, project_key_pair);
+        assert!(next_project_fetch > Instant::now());
+        assert!(next_project_fetch <= Instant::now() + Duration::from_secs(5));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_min_fetch_debounce() {
+        let config = Config::from_json_value(serde_json::json!({
+            "spool": {
+                "envelopes": {
+                    "min_fetch_debounce_ms": 500
+                }
+            }
+        }))
+        .unwrap();
+        let mut buffer =
+            EnvelopeBuffer::<MemoryStackProvider>::new(0, &config, mock_memory_checker());
+
+        let project_key = ProjectKey::parse("a94ae32be2584e0bbd7a4cbb95971fed").unwrap();
+        buffer
+            .push(new_envelope(project_key, None, None))
+            .await
+            .unwrap();
+
+        let project_key_pair = ProjectKeyPair::new(project_key, project_key);
+        let next_project_fetch = |buffer: &EnvelopeBuffer<MemoryStackProvider>| {
+            buffer
+                .priority_queue
+                .get(&project_key_pair)
+                .unwrap()
+                .1
+                .next_project_fetch
+        };
+
+        // A zero duration is raised to the minimum.
+        buffer.mark_seen(&project_key_pair, Duration::ZERO);
+        assert_eq!(
+            next_project_fetch(&buffer),
+            Instant::now() + Duration::from_millis(500)
+        );
+
+        // Longer durations are not affected.
+        buffer.mark_seen(&project_key_pair, Duration::from_secs(2));
+        assert_eq!(
+            next_project_fetch(&buffer),
+            Instant::now() + Duration::from_secs(2)
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_remove_empty_init_stacks() {
+        let config = Config::from_json_value(serde_json::json!({
+            "spool": {
+                "envelopes": {
+                    "empty_init_stack_lifetime_secs": 60
+                }
+            }
+        }))
+        .unwrap();
+        let mut buffer =
+            EnvelopeBuffer::<MemoryStackProvider>::new(0, &config, mock_memory_checker());
+
+        let empty_key = ProjectKey::parse("a94ae32be2584e0bbd7a4cbb95971fed").unwrap();
+        let refilled_key = ProjectKey::parse("b56ae32be2584e0bbd7a4cbb95971fed").unwrap();
+        let empty_pair = ProjectKeyPair::new(empty_key, empty_key);
+        let refilled_pair = ProjectKeyPair::new(refilled_key, refilled_key);
+
+        buffer
+            .load_stacks(HashSet::from([empty_pair, refilled_pair]))
+            .await;
+        buffer
+            .push(new_envelope(refilled_key, None, None))
+            .await
+            .unwrap();
+
+        // Stacks are kept within their lifetime.
+        tokio::time::advance(Duration::from_secs(30)).await;
+        buffer.remove_empty_init_stacks().await;
+        assert_eq!(buffer.priority_queue.len(), 2);
+
+        tokio::time::advance(Duration::from_secs(30)).await;
+        buffer.remove_empty_init_stacks().await;
+        assert_eq!(buffer.priority_queue.len(), 1);
+        assert!(buffer.priority_queue.get(&empty_pair).is_none());
+        assert!(!buffer.stacks_by_project[&empty_key].contains(&empty_pair));
+        assert_eq!(buffer.stack_depth(&refilled_pair), 1);
+    }
+
+    #[tokio::test]
+    async fn test_push_all() {
+        let mut buffer = EnvelopeBuffer::<MemoryStackProvider>::new(
+            0,
+            &Config::default(),
+            mock_memory_checker(),
+        );
+
+        let project_key1 = ProjectKey::parse("a94ae32be2584e0bbd7a4cbb95971fed").unwrap();
+        let project_key2 = ProjectKey::parse("b56ae32be2584e0bbd7a4cbb95971fed").unwrap();
+
+        // Pushed in the groups [a1, a2], [b1], [a3], [b2, b3].
+        let keys = [
+            project_key1,
+            project_key1,
+            project_key2,
+            project_key1,
+            project_key2,
+            project_key2,
+        ];
+        let event_ids: Vec<_> = keys.iter().map(|_| EventId::new()).collect();
+        let envelopes = keys
+            .iter()
+            .zip(&event_ids)
+            .map(|(key, event_id)| new_envelope(*key, None, Some(*event_id)))
+            .collect();
+
+        let evicted = buffer.push_all(envelopes).await.unwrap();
+        assert!(evicted.is_empty());
+        assert_eq!(buffer.tracked_count, 6);
+        assert_eq!(buffer.total_count, 6);
+        assert_eq!(buffer.priority_queue.len(), 2);
+
+        buffer.mark_ready(&project_key1, true);
+        buffer.mark_ready(&project_key2, true);
+
+        // The second stack received the last envelope and is popped first.
+        let mut popped = vec![];
+        while let Some(envelope) = buffer.pop().await.unwrap() {
+            popped.push(envelope.event_id().unwrap());
+        }
+        let expected = [5, 4, 2, 3, 1, 0].map(|index| event_ids[index]);
+        assert_eq!(popped, expected);
+        assert_eq!(buffer.tracked_count, 0);
+    }
+
+    #[tokio::test]
+    async fn test_push_all_returns_unpushed() {
+        let push_failures = Arc::new(AtomicUsize::new(0));
+        let mut buffer = EnvelopeBuffer::with_stack_provider(
+            0,
+            &Config::default(),
+            FlakyStackProvider {
+                failures: Arc::new(AtomicUsize::new(0)),
+                peek_failures: Arc::new(AtomicUsize::new(0)),
+                push_failures: Arc::clone(&push_failures),
+                push_delay: Duration::ZERO,
+            },
+            DefaultPolicy,
+        );
+
+        let project_key1 = ProjectKey::parse("a94ae32be2584e0bbd7a4cbb95971fed").unwrap();
+        let project_key2 = ProjectKey::parse("b56ae32be2584e0bbd7a4cbb95971fed").unwrap();
+
+        // Pushed in the groups [a1, a2], [b1], [a3].
+        let keys = [project_key1, project_key1, project_key2, project_key1];
+        let event_ids: Vec<_> = keys.iter().map(|_| EventId::new()).collect();
+        let envelopes = keys
+            .iter()
+            .zip(&event_ids)
+            .map(|(key, event_id)| new_envelope(*key, None, Some(*event_id)))
+            .collect();
+
+        // The first push fails, the rest of its group and all later groups are returned.
+        push_failures.store(1, std::sync::atomic::Ordering::Relaxed);
+        let failure = buffer.push_all(envelopes).await.unwrap_err();
+        assert!(failure.evicted.is_empty());
+        let unpushed: Vec<_> = failure
+            .unpushed
+            .iter()
+            .map(|envelope| envelope.event_id())
+            .collect();
+        let expected: Vec<_> = event_ids[1..].iter().copied().map(Some).collect();
+        assert_eq!(unpushed, expected);
+        assert_eq!(buffer.tracked_count, 0);
+
+        let evicted = buffer.push_all(failure.unpushed).await.unwrap();
+        assert!(evicted.is_empty());
+        assert_eq!(buffer.tracked_count, 3);
+    }
+
+    #[tokio::test]
+    async fn test_try_push_sync() {
+        let config = Config::from_json_value(serde_json::json!({
+            "spool": {
+                "envelopes": {
+                    "max_stack_depth": 100
+                }
+            }
+        }))
+        .unwrap();
+        let mut sync_buffer =
+            EnvelopeBuffer::<MemoryStackProvider>::new(0, &config, mock_memory_checker());
+        let mut async_buffer =
+            EnvelopeBuffer::<MemoryStackProvider>::new(0, &config, mock_memory_checker());
+
+        let project_keys = [
+            "a94ae32be2584e0bbd7a4cbb95971fed",
+            "b56ae32be2584e0bbd7a4cbb95971fed",
+        ]
+        .map(|key| ProjectKey::parse(key).unwrap());
+
+        // Timings are not asserted, since they are unreliable in tests. Both paths must leave the
+        // buffer in the same state, including evictions.
+        let (mut sync_evicted, mut async_evicted) = (0, 0);
+        let sync_started = Instant::now();
+        for i in 0..1000 {
+            let envelope = new_envelope(project_keys[i % 2], None, None);
+            sync_evicted += sync_buffer.try_push_sync(envelope).unwrap().iter().count();
+        }
+        let sync_elapsed = sync_started.elapsed();
+
+        let async_started = Instant::now();
+        for i in 0..1000 {
+            let envelope = new_envelope(project_keys[i % 2], None, None);
+            async_evicted += async_buffer.push(envelope).await.unwrap().iter().count();
+        }
+        let async_elapsed = async_started.elapsed();
+        relay_log::debug!(?sync_elapsed, ?async_elapsed, "pushed 1000 envelopes");
+
+        assert_eq!(sync_evicted, 800);
+        assert_eq!(sync_evicted, async_evicted);
+        assert_eq!(sync_buffer.tracked_count, async_buffer.tracked_count);
+        assert_eq!(sync_buffer.priority_queue.len(), 2);
+        for project_key in project_keys {
+            let project_key_pair = ProjectKeyPair::new(project_key, project_key);
+            assert_eq!(sync_buffer.stack_depth(&project_key_pair), 100);
+            assert_eq!(async_buffer.stack_depth(&project_key_pair), 100);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_max_stack_depth() {
+        let config = Config::from_json_value(serde_json::json!({
+            "spool": {
+                "envelopes": {
+                    "max_stack_depth": 2
+                }
+            }
+        }))
+        .unwrap();
+        let mut buffer =
+            EnvelopeBuffer::<MemoryStackProvider>::new(0, &config, mock_memory_checker());
+
+        let project_key1 = ProjectKey::parse("a94ae32be2584e0bbd7a4cbb95971fed").unwrap();
+        let project_key2 = ProjectKey::parse("b56ae32be2584e0bbd7a4cbb95971fed").unwrap();
+
+        let event_ids = [EventId::new(), EventId::new(), EventId::new()];
+        for event_id in &event_ids[..2] {
+            let envelope = new_envelope(project_key1, None, Some(*event_id));
+            assert!(buffer.push(envelope).await.unwrap().is_none());
+        }
+
+        // Another stack is not affected by the depth of the first one.
+        let other_event_id = EventId::new();
+        let envelope = new_envelope(project_key2, None, Some(other_event_id));
+        assert!(buffer.push(envelope).await.unwrap().is_none());
+
+        // Exceeding the depth evicts the oldest envelope of the stack.
+        let envelope = new_envelope(project_key1, None, Some(event_ids[2]));
+        let evicted = buffer.push(envelope).await.unwrap().unwrap();
+        assert_eq!(evicted.event_id(), Some(event_ids[0]));
+        assert_eq!(buffer.tracked_count, 3);
+
+        let mut popped = vec![];
+        while let Some(envelope) = buffer.pop().await.unwrap() {
+            popped.push(envelope.event_id().unwrap());
+        }
+        assert_eq!(popped.len(), 3);
+        assert!(popped.contains(&other_event_id));
+        assert!(popped.contains(&event_ids[1]));
+        assert!(popped.contains(&event_ids[2]));
+        assert!(!popped.contains(&event_ids[0]));
+    }
+
+    #[tokio::test]
+    async fn test_protected_projects_are_not_evicted() {
+        let protected_key = ProjectKey::parse("a94ae32be2584e0bbd7a4cbb95971fed").unwrap();
+        let unprotected_key = ProjectKey::parse("b56ae32be2584e0bbd7a4cbb95971fed").unwrap();
+        let config = Config::from_json_value(serde_json::json!({
+            "spool": {
+                "envelopes": {
+                    "max_stack_depth": 1,
+                    "protected_projects": [protected_key.as_str()]
+                }
+            }
+        }))
+        .unwrap();
+        let mut buffer =
+            EnvelopeBuffer::<MemoryStackProvider>::new(0, &config, mock_memory_checker());
+
+        for project_key in [protected_key, unprotected_key] {
+            let envelope = new_envelope(project_key, None, None);
+            assert!(buffer.push(envelope).await.unwrap().is_none());
+        }
+
+        // The protected stack grows beyond the maximum depth.
+        let envelope = new_envelope(protected_key, None, None);
+        assert!(buffer.push(envelope).await.unwrap().is_none());
+
+        // The unprotected stack still evicts its oldest envelope.
+        let envelope = new_envelope(unprotected_key, None, None);
+        let evicted = buffer.push(envelope).await.unwrap().unwrap();
+        assert_eq!(evicted.meta().public_key(), unprotected_key);
+
+        assert_eq!(buffer.tracked_count, 3);
+    }
+
+    #[tokio::test]
+    async fn test_report_pop_failure_quarantines_stack() {
+        let healthy_key = ProjectKey::parse("a94ae32be2584e0bbd7a4cbb95971fed").unwrap();
+        let poison_key = ProjectKey::parse("b56ae32be2584e0bbd7a4cbb95971fed").unwrap();
+        let poison_stack = ProjectKeyPair::new(poison_key, poison_key);
+
+        let config = Config::from_json_value(serde_json::json!({
+            "spool": {
+                "envelopes": {
+                    "quarantine_threshold": 3
+                }
+            }
+        }))
+        .unwrap();
+        let mut buffer =
+            EnvelopeBuffer::<MemoryStackProvider>::new(0, &config, mock_memory_checker());
+
+        // The poisoned stack received the most recent envelope and is popped first.
+        for project_key in [healthy_key, poison_key] {
+            buffer
+                .push(new_envelope(project_key, None, None))
+                .await
+                .unwrap();
+        }
+        let Peek::Ready {
+            project_key_pair, ..
+        } = buffer.peek().await.unwrap()
+        else {
+            panic!("expected a ready stack");
+        };
+        assert_eq!(project_key_pair, poison_stack);
+
+        assert!(!buffer.report_pop_failure(poison_stack));
+        assert!(!buffer.report_pop_failure(poison_stack));
+        assert!(buffer.report_pop_failure(poison_stack));
+        // Further failures of a quarantined stack are ignored.
+        assert!(!buffer.report_pop_failure(poison_stack));
+
+        let snapshot = buffer.queue_snapshot(10);
+        assert_eq!(snapshot.len(), 2);
+        assert_eq!(snapshot[0].own_key, healthy_key);
+        assert!(!snapshot[0].quarantined);
+        assert_eq!(snapshot[1].own_key, poison_key);
+        assert!(snapshot[1].quarantined);
+
+        // The quarantined stack is only popped after all other stacks.
+        let envelope = buffer.pop().await.unwrap().unwrap();
+        assert_eq!(envelope.meta().public_key(), healthy_key);
+        let envelope = buffer.pop().await.unwrap().unwrap();
+        assert_eq!(envelope.meta().public_key(), poison_key);
+        assert!(buffer.pop().await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_report_pop_failure_without_threshold() {
+        let project_key = ProjectKey::parse("a94ae32be2584e0bbd7a4cbb95971fed").unwrap();
+        let mut buffer = EnvelopeBuffer::<MemoryStackProvider>::new(
+            0,
+            &Config::default(),
+            mock_memory_checker(),
+        );
+        buffer
+            .push(new_envelope(project_key, None, None))
+            .await
+            .unwrap();
+
+        let project_key_pair = ProjectKeyPair::new(project_key, project_key);
+        for _ in 0..10 {
+            assert!(!buffer.report_pop_failure(project_key_pair));
+        }
+        assert!(!buffer.queue_snapshot(1)[0].quarantined);
+    }
+
+    #[tokio::test]
+    async fn test_default_ready() {
+        let project_key = ProjectKey::parse("a94ae32be2584e0bbd7a4cbb95971fed").unwrap();
+
+        for default_ready in [true, false] {
+            let config = Config::from_json_value(serde_json::json!({
+                "spool": {
+                    "envelopes": {
+                        "default_ready": default_ready
+                    }
+                }
+            }))
+            .unwrap();
+            let mut buffer =
+                EnvelopeBuffer::<MemoryStackProvider>::new(0, &config, mock_memory_checker());
+
+            buffer
+                .push(new_envelope(project_key, None, None))
+                .await
+                .unwrap();
+            assert_eq!(
+                matches!(buffer.peek().await.unwrap(), Peek::Ready { .. }),
+                default_ready
+            );
+
+            // An explicit `mark_ready` makes the stack ready in both cases.
+            assert_eq!(buffer.mark_ready(&project_key, true), !default_ready);
+            assert!(matches!(buffer.peek().await.unwrap(), Peek::Ready { .. }));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_hot_projects_drain_first() {
+        let project_key1 = ProjectKey::parse("a94ae32be2584e0bbd7a4cbb95971fed").unwrap();
+        let project_key2 = ProjectKey::parse("b56ae32be2584e0bbd7a4cbb95971fed").unwrap();
+
+        let config = Config::from_json_value(serde_json::json!({
+            "spool": {
+                "envelopes": {
+                    "hot_projects": [project_key1.as_str()]
+                }
+            }
+        }))
+        .unwrap();
+        let mut buffer =
+            EnvelopeBuffer::<MemoryStackProvider>::new(0, &config, mock_memory_checker());
+
+        buffer
+            .push(new_envelope(project_key1, None, None))
+            .await
+            .unwrap();
+        buffer
+            .push(new_envelope(project_key1, None, None))
+            .await
+            .unwrap();
+        buffer
+            .push(new_envelope(project_key2, None, None))
+            .await
+            .unwrap();
+
+        // The more recent stack is first before the hot projects are prioritized.
+        let Peek::Ready {
+            project_key_pair, ..
+        } = buffer.peek().await.unwrap()
+        else {
+            panic!();
+        };
+        assert_eq!(project_key_pair.own_key, project_key2);
+
+        buffer.initialize().await.unwrap();
+
+        let Peek::Ready {
+            project_key_pair, ..
+        } = buffer.peek().await.unwrap()
+        else {
+            panic!();
+        };
+        assert_eq!(project_key_pair.own_key, project_key1);
+
+        // The hot stack stays first until it is drained.
+        for _ in 0..2 {
+            let envelope = buffer.pop().await.unwrap().unwrap();
+            assert_eq!(envelope.meta().public_key(), project_key1);
+        }
+        let Peek::Ready {
+            project_key_pair, ..
+        } = buffer.peek().await.unwrap()
+        else {
+            panic!();
+        };
+        assert_eq!(project_key_pair.own_key, project_key2);
+    }
+
+    #[tokio::test]
+    async fn test_hot_projects_expire() {
+        let project_key1 = ProjectKey::parse("a94ae32be2584e0bbd7a4cbb95971fed").unwrap();
+        let project_key2 = ProjectKey::parse("b56ae32be2584e0bbd7a4cbb95971fed").unwrap();
+
+        let config = Config::from_json_value(serde_json::json!({
+            "spool": {
+                "envelopes": {
+                    "hot_projects": [project_key1.as_str()],
+                    "hot_projects_ttl": 0
+                }
+            }
+        }))
+        .unwrap();
+        let mut buffer =
+            EnvelopeBuffer::<MemoryStackProvider>::new(0, &config, mock_memory_checker());
+
+        buffer
+            .push(new_envelope(project_key1, None, None))
+            .await
+            .unwrap();
+        buffer
+            .push(new_envelope(project_key2, None, None))
+            .await
+            .unwrap();
+        buffer.initialize().await.unwrap();
+
+        // The hot stack is ordered like any other stack once the ttl has passed.
+        let Peek::Ready {
+            project_key_pair, ..
+        } = buffer.peek().await.unwrap()
+        else {
+            panic!();
+        };
+        assert_eq!(project_key_pair.own_key, project_key2);
+        assert!(!buffer.queue_snapshot(2).iter().any(|stack| stack.hot));
+    }
+
+    #[tokio::test]
+    async fn test_deprioritize_unsampled() {
+        let sampled_key = ProjectKey::parse("a94ae32be2584e0bbd7a4cbb95971fed").unwrap();
+        let unsampled_key = ProjectKey::parse("b56ae32be2584e0bbd7a4cbb95971fed").unwrap();
+        for (deprioritize, expected) in [(false, unsampled_key), (true, sampled_key)] {
+            let config = Config::from_json_value(serde_json::json!({
+                "spool": {
+                    "envelopes": {
+                        "deprioritize_unsampled": deprioritize
+                    }
+                }
+            }))
+            .unwrap();
+            let mut buffer =
+                EnvelopeBuffer::<MemoryStackProvider>::new(0, &config, mock_memory_checker());
+
+            // The unsampled stack received the most recent envelope.
+            buffer
+                .push(new_traced_envelope(sampled_key, true))
+                .await
+                .unwrap();
+            buffer
+                .push(new_traced_envelope(unsampled_key, false))
+                .await
+                .unwrap();
+            buffer.mark_ready(&sampled_key, true);
+            buffer.mark_ready(&unsampled_key, true);
+
+            let Peek::Ready {
+                project_key_pair, ..
+            } = buffer.peek().await.unwrap()
+            else {
+                panic!("expected a ready stack");
+            };
+            assert_eq!(project_key_pair.own_key, expected, "{deprioritize}");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_evict_unsampled_when_full() {
+        let sampled_key = ProjectKey::parse("a94ae32be2584e0bbd7a4cbb95971fed").unwrap();
+        let unsampled_key = ProjectKey::parse("b56ae32be2584e0bbd7a4cbb95971fed").unwrap();
+        for deprioritize in [false, true] {
+            let config = Config::from_json_value(serde_json::json!({
+                "spool": {
+                    "envelopes": {
+                        "max_total_count": 2,
+                        "deprioritize_unsampled": deprioritize
+                    }
+                }
+            }))
+            .unwrap();
+            let mut buffer =
+                EnvelopeBuffer::<MemoryStackProvider>::new(0, &config, mock_memory_checker());
+
+            buffer
+                .push(new_traced_envelope(sampled_key, true))
+                .await
+                .unwrap();
+            buffer
+                .push(new_traced_envelope(unsampled_key, false))
+                .await
+                .unwrap();
+            assert_eq!(buffer.has_capacity(), deprioritize, "{deprioritize}");
+
+            // Unsampled envelopes do not make room for each other.
+            let unsampled = new_traced_envelope(unsampled_key, false);
+            assert!(buffer.evict_unsampled(&unsampled).await.unwrap().is_none());
+            assert_eq!(buffer.admission(&unsampled), Admission::RejectFull);
+
+            let sampled = new_traced_envelope(sampled_key, true);
+            let evicted = buffer.evict_unsampled(&sampled).await.unwrap();
+            if !deprioritize {
+                assert!(evicted.is_none());
+                assert_eq!(buffer.admission(&sampled), Admission::RejectFull);
+                continue;
+            }
+
+            // The unsampled envelope is evicted before the sampled one is rejected.
+            let evicted = evicted.unwrap();
+            assert_eq!(evicted.meta().public_key(), unsampled_key);
+            assert_eq!(buffer.admission(&sampled), Admission::Accept);
+            buffer.push(sampled).await.unwrap();
+            assert_eq!(buffer.total_count, 2);
+
+            // Only sampled envelopes remain, so the buffer is full.
+            assert!(!buffer.has_capacity());
+            let sampled = new_traced_envelope(sampled_key, true);
+            assert!(buffer.evict_unsampled(&sampled).await.unwrap().is_none());
+            assert_eq!(buffer.admission(&sampled), Admission::RejectFull);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_evict_unsampled_least_recent_stack() {
+        let sampled_key = ProjectKey::parse("a94ae32be2584e0bbd7a4cbb95971fed").unwrap();
+        let older_key = ProjectKey::parse("b56ae32be2584e0bbd7a4cbb95971fed").unwrap();
+        let newer_key = ProjectKey::parse("c67ae32be2584e0bbd7a4cbb95971fed").unwrap();
+        let config = Config::from_json_value(serde_json::json!({
+            "spool": {
+                "envelopes": {
+                    "max_total_count": 3,
+                    "max_new_stacks_per_sec": 1,
+                    "deprioritize_unsampled": true
+                }
+            }
+        }))
+        .unwrap();
+        let mut buffer =
+            EnvelopeBuffer::<MemoryStackProvider>::new(0, &config, mock_memory_checker());
+
+        for (project_key, sampled) in [(older_key, false), (newer_key, false), (sampled_key, true)]
+        {
+            buffer
+                .push(new_traced_envelope(project_key, sampled))
+                .await
+                .unwrap();
+        }
+
+        // An envelope that would also exceed the rate of new stacks does not evict anything.
+        let admitted_key = ProjectKey::parse("d78ae32be2584e0bbd7a4cbb95971fed").unwrap();
+        assert!(buffer.admit(&new_traced_envelope(admitted_key, true)));
+        let limited_key = ProjectKey::parse("e89ae32be2584e0bbd7a4cbb95971fed").unwrap();
+        let limited = new_traced_envelope(limited_key, true);
+        assert!(buffer.evict_unsampled(&limited).await.unwrap().is_none());
+        assert_eq!(buffer.total_count, 3);
+
+        // The unsampled stack that received an envelope least recently is evicted first.
+        let sampled = new_traced_envelope(sampled_key, true);
+        let evicted = buffer.evict_unsampled(&sampled).await.unwrap().unwrap();
+        assert_eq!(evicted.meta().public_key(), older_key);
+        assert_eq!(buffer.admission(&sampled), Admission::Accept);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_eviction_grace_period() {
+        let sampled_key = ProjectKey::parse("a94ae32be2584e0bbd7a4cbb95971fed").unwrap();
+        let unsampled_key = ProjectKey::parse("b56ae32be2584e0bbd7a4cbb95971fed").unwrap();
+        let config = Config::from_json_value(serde_json::json!({
+            "spool": {
+                "envelopes": {
+                    "max_total_count": 2,
+                    "deprioritize_unsampled": true,
+                    "eviction_grace_period_ms": 1000
+                }
+            }
+        }))
+        .unwrap();
+        let mut buffer =
+            EnvelopeBuffer::<MemoryStackProvider>::new(0, &config, mock_memory_checker());
+
+        buffer
+            .push(new_traced_envelope(sampled_key, true))
+            .await
+            .unwrap();
+        buffer
+            .push(new_traced_envelope(unsampled_key, false))
+            .await
+            .unwrap();
+
+        // The unsampled stack was just created, so the full buffer cannot make room.
+        assert!(!buffer.has_capacity());
+        let sampled = new_traced_envelope(sampled_key, true);
+        assert!(buffer.evict_unsampled(&sampled).await.unwrap().is_none());
+        assert_eq!(buffer.admission(&sampled), Admission::RejectFull);
+        assert_eq!(buffer.total_count, 2);
+
+        // After the grace period, the unsampled stack is evicted.
+        tokio::time::advance(Duration::from_secs(1)).await;
+        assert!(buffer.has_capacity());
+        let evicted = buffer.evict_unsampled(&sampled).await.unwrap().unwrap();
+        assert_eq!(evicted.meta().public_key(), unsampled_key);
+        assert_eq!(buffer.admission(&sampled), Admission::Accept);
+    }
+
+    #[tokio::test]
+    async fn test_preserve_trace_order() {
+        let sampling_key = ProjectKey::parse("a94ae32be2584e0bbd7a4cbb95971fed").unwrap();
+        let own_key = ProjectKey::parse("b56ae32be2584e0bbd7a4cbb95971fed").unwrap();
+        let event_ids: Vec<_> = (0..4).map(|_| EventId::new()).collect();
+
+        for (preserve, expected) in [(false, [3, 2, 1, 0]), (true, [2, 0, 3, 1])] {
+            let config = Config::from_json_value(serde_json::json!({
+                "spool": {
+                    "envelopes": {
+                        "preserve_trace_order": preserve
+                    }
+                }
+            }))
+            .unwrap();
+            let mut buffer =
+                EnvelopeBuffer::<MemoryStackProvider>::new(0, &config, mock_memory_checker());
+
+            // Envelopes of the same trace alternate between the stack of the sampling project and
+            // the stack of another project that is sampled by it.
+            for (i, &event_id) in event_ids.iter().enumerate() {
+                let key = if i % 2 == 0 { sampling_key } else { own_key };
+                buffer
+                    .push(new_envelope(key, Some(sampling_key), Some(event_id)))
+                    .await
+                    .unwrap();
+            }
+            buffer.mark_ready(&sampling_key, true);
+            buffer.mark_ready(&own_key, true);
+
+            let mut popped = Vec::new();
+            while let Some(envelope) = buffer.pop().await.unwrap() {
+                popped.push(envelope.event_id().unwrap());
+            }
+            let expected: Vec<_> = expected.iter().map(|&i| event_ids[i]).collect();
+            assert_eq!(popped, expected, "{preserve}");
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_admit_limits_new_stacks() {
+        let config = Config::from_json_value(serde_json::json!({
+            "spool": {
+                "envelopes": {
+                    "max_new_stacks_per_sec": 2
+                }
+            }
+        }))
+        .unwrap();
+        let mut buffer =
+            EnvelopeBuffer::<MemoryStackProvider>::new(0, &config, mock_memory_checker());
+
+        let project_keys: Vec<_> = (0..5)
+            .map(|i| ProjectKey::parse(&format!("a94ae32be2584e0bbd7a4cbb95971fe{i}")).unwrap())
+            .collect();
+
+        // The first two pairs create stacks, all further pairs are rejected.
+        for (i, &project_key) in project_keys.iter().enumerate() {
+            let envelope = new_envelope(project_key, None, None);
+            let admitted = buffer.admit(&envelope);
+            assert_eq!(admitted, i < 2, "project {i}");
+            if admitted {
+                buffer.push(envelope).await.unwrap();
+            }
+        }
+        assert_eq!(buffer.priority_queue.len(), 2);
+
+        // Existing stacks keep accepting envelopes.
+        for &project_key in &project_keys[..2] {
+            let envelope = new_envelope(project_key, None, None);
+            assert!(buffer.admit(&envelope));
+            buffer.push(envelope).await.unwrap();
+        }
+        assert!(!buffer.admit(&new_envelope(project_keys[2], None, None)));
+
+        // New stacks are admitted again in the next second.
+        tokio::time::advance(Duration::from_secs(1)).await;
+        assert!(buffer.admit(&new_envelope(project_keys[2], None, None)));
+        // A pair that was admitted but not pushed yet remains admitted.
+        assert!(buffer.admit(&new_envelope(project_keys[2], None, None)));
+        assert!(buffer.admit(&new_envelope(project_keys[3], None, None)));
+        assert!(!buffer.admit(&new_envelope(project_keys[4], None, None)));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_admission() {
+        let config = Config::from_json_value(serde_json::json!({
+            "spool": {
+                "envelopes": {
+                    "max_new_stacks_per_sec": 1,
+                    "max_total_count": 2,
+                    "max_envelope_attachment_bytes": 10
+                }
+            }
+        }))
+        .unwrap();
+        let mut buffer = PolymorphicEnvelopeBuffer::from_config(0, &config, mock_memory_checker())
+            .await
+            .unwrap();
+
+        let project_key1 = ProjectKey::parse("a94ae32be2584e0bbd7a4cbb95971fee").unwrap();
+        let project_key2 = ProjectKey::parse("b94ae32be2584e0bbd7a4cbb95971fee").unwrap();
+
+        let envelope = new_envelope(project_key1, None, None);
+        assert_eq!(buffer.admission(&envelope), Admission::Accept);
+        assert_eq!(buffer.admission(&envelope).discard_reason(), None);
+        // Checking admission does not count towards the rate of new stacks.
+        assert_eq!(
+            buffer.admission(&new_envelope(project_key2, None, None)),
+            Admission::Accept
+        );
+
+        assert_eq!(buffer.check_admission(&envelope), Admission::Accept);
+        buffer.push(envelope).await.unwrap();
+
+        let envelope = new_envelope(project_key2, None, None);
+        assert_eq!(
+            buffer.admission(&envelope),
+            Admission::RejectProjectCapacity
+        );
+        assert_eq!(
+            buffer.admission(&envelope).discard_reason(),
+            Some(DiscardReason::StackCreationRate)
+        );
+
+        let mut envelope = new_envelope(project_key1, None, None);
+        let mut item = Item::new(ItemType::Attachment);
+        item.set_payload(ContentType::OctetStream, "0123456789abcdef");
+        envelope.add_item(item);
+        assert_eq!(buffer.admission(&envelope), Admission::RejectOversized);
+
+        buffer
+            .push(new_envelope(project_key1, None, None))
+            .await
+            .unwrap();
+        assert_eq!(
+            buffer.admission(&new_envelope(project_key1, None, None)),
+            Admission::RejectFull
+        );
+        // Oversized envelopes are reported as such even if the buffer is full.
+        assert_eq!(buffer.admission(&envelope), Admission::RejectOversized);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_check_admission_counts_new_stacks() {
+        let config = Config::from_json_value(serde_json::json!({
+            "spool": {
+                "envelopes": {
+                    "max_new_stacks_per_sec": 1
+                }
+            }
+        }))
+        .unwrap();
+        let mut buffer = PolymorphicEnvelopeBuffer::from_config(0, &config, mock_memory_checker())
+            .await
+            .unwrap();
+
+        let project_key1 = ProjectKey::parse("a94ae32be2584e0bbd7a4cbb95971fee").unwrap();
+        let project_key2 = ProjectKey::parse("b94ae32be2584e0bbd7a4cbb95971fee").unwrap();
+
+        let envelope = new_envelope(project_key1, None, None);
+        assert_eq!(buffer.check_admission(&envelope), Admission::Accept);
+        // The accepted pair counts towards the rate before its stack is created.
+        assert_eq!(
+            buffer.check_admission(&new_envelope(project_key2, None, None)),
+            Admission::RejectProjectCapacity
+        );
+        assert_eq!(buffer.check_admission(&envelope), Admission::Accept);
+    }
+
+    #[tokio::test]
+    async fn test_check_admission_ignores_capacity() {
+        let config = Config::from_json_value(serde_json::json!({
+            "spool": {
+                "envelopes": {
+                    "max_new_stacks_per_sec": 1,
+                    "max_total_count": 1
+                }
+            }
+        }))
+        .unwrap();
+        let mut buffer = PolymorphicEnvelopeBuffer::from_config(0, &config, mock_memory_checker())
+            .await
+            .unwrap();
+
+        let project_key1 = ProjectKey::parse("a94ae32be2584e0bbd7a4cbb95971fee").unwrap();
+        let project_key2 = ProjectKey::parse("b94ae32be2584e0bbd7a4cbb95971fee").unwrap();
+
+        let envelope = new_envelope(project_key1, None, None);
+        assert_eq!(buffer.check_admission(&envelope), Admission::Accept);
+        buffer.push(envelope).await.unwrap();
+
+        // The full buffer still accepts envelopes, but enforces the rate of new stacks.
+        let envelope = new_envelope(project_key1, None, None);
+        assert_eq!(buffer.admission(&envelope), Admission::RejectFull);
+        assert_eq!(buffer.check_admission(&envelope), Admission::Accept);
+        assert_eq!(
+            buffer.check_admission(&new_envelope(project_key2, None, None)),
+            Admission::RejectProjectCapacity
+        );
+    }
+
+    #[tokio::test]
+    async fn test_reload_config_keeps_envelopes() {
+        let config = Config::from_json_value(serde_json::json!({
+            "spool": {
+                "envelopes": {
+                    "max_stack_depth": 10
+                }
+            }
+        }))
+        .unwrap();
+        let mut buffer = PolymorphicEnvelopeBuffer::from_config(0, &config, mock_memory_checker())
+            .await
+            .unwrap();
+
+        let project_key = ProjectKey::parse("a94ae32be2584e0bbd7a4cbb95971fee").unwrap();
+        for _ in 0..3 {
+            buffer
+                .push(new_envelope(project_key, None, None))
+                .await
+                .unwrap();
+        }
+
+        let config = Config::from_json_value(serde_json::json!({
+            "spool": {
+                "envelopes": {
+                    "max_stack_depth": 5,
+                    "max_total_count": 4
+                }
+            }
+        }))
+        .unwrap();
+        let evicted = buffer
+            .reload_config(&config, mock_memory_checker())
+            .await
+            .unwrap();
+        assert!(evicted.is_empty());
+
+        let PolymorphicEnvelopeBuffer::InMemory(inner) = &buffer else {
+            panic!("buffer was recreated");
+        };
+        assert_eq!(inner.max_stack_depth, NonZeroUsize::new(5));
+        assert_eq!(inner.max_total_count, Some(4));
+        assert_eq!(buffer.item_count(), 3);
+
+        let drained: Vec<_> = buffer.drain_all().try_collect().await.unwrap();
+        assert_eq!(drained.len(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_reload_config_migrates_backend() {
+        let config = Config::from_json_value(serde_json::json!({})).unwrap();
+        let mut buffer = PolymorphicEnvelopeBuffer::from_config(0, &config, mock_memory_checker())
+            .await
+            .unwrap();
+
+        let project_key = ProjectKey::parse("a94ae32be2584e0bbd7a4cbb95971fee").unwrap();
+        let event_ids: Vec<_> = (0..3).map(|_| EventId::new()).collect();
+        for &event_id in &event_ids {
+            buffer
+                .push(new_envelope(project_key, None, Some(event_id)))
+                .await
+                .unwrap();
+        }
+
+        let path = std::env::temp_dir().join(Uuid::new_v4().to_string());
+        let config = Config::from_json_value(serde_json::json!({
+            "spool": {
+                "envelopes": {
+                    "path": path
+                }
+            }
+        }))
+        .unwrap();
+        let evicted = buffer
+            .reload_config(&config, mock_memory_checker())
+            .await
+            .unwrap();
+        assert!(evicted.is_empty());
+        assert!(!buffer.is_memory());
+
+        let mut drained: Vec<_> = buffer
+            .drain_all()
+            .map_ok(|envelope| envelope.event_id().unwrap())
+            .try_collect()
+            .await
+            .unwrap();
+        drained.sort();
+        let mut expected = event_ids;
+        expected.sort();
+        assert_eq!(drained, expected);
+    }
+
+    #[tokio::test]
+    async fn test_is_retry() {
+        let config = Config::from_json_value(serde_json::json!({
+            "spool": {
+                "envelopes": {
+                    "fingerprint_window": 2
+                }
+            }
+        }))
+        .unwrap();
+        let mut buffer =
+            EnvelopeBuffer::<MemoryStackProvider>::new(0, &config, mock_memory_checker());
+
+        let project_key = ProjectKey::parse("a94ae32be2584e0bbd7a4cbb95971fee").unwrap();
+        let envelope = |event_id: EventId, body: &'static str| {
+            let mut envelope = new_envelope(project_key, None, Some(event_id));
+            let mut item = Item::new(ItemType::Event);
+            item.set_payload(ContentType::Json, body);
+            envelope.add_item(item);
+            envelope
+        };
+
+        let event_id = EventId::new();
+        assert!(!buffer.is_retry(&envelope(event_id, "{}")));
+        assert!(buffer.is_retry(&envelope(event_id, "{}")));
+        assert!(buffer.is_retry(&envelope(event_id, "{}")));
+
+        // A different body or event id is not a retry.
+        assert!(!buffer.is_retry(&envelope(event_id, "{\"a\":1}")));
+        assert!(!buffer.is_retry(&envelope(EventId::new(), "{}")));
+
+        // Fingerprints outside of the window are forgotten.
+        assert!(!buffer.is_retry(&envelope(event_id, "{}")));
+
+        // Envelopes without an event id, such as sessions, are never retries.
+        let mut session = new_envelope(project_key, None, None);
+        let mut item = Item::new(ItemType::Session);
+        item.set_payload(ContentType::Json, "{}");
+        session.add_item(item);
+        assert!(!buffer.is_retry(&session));
+        assert!(!buffer.is_retry(&session));
+    }
+
+    #[tokio::test]
+    async fn test_peek_cache_is_coherent() {
+        let config = Config::from_json_value(serde_json::json!({
+            "spool": {
+                "envelopes": {
+                    "peek_cache": true,
+                    "default_ready": false
+                }
+            }
+        }))
+        .unwrap();
+        let mut buffer =
+            EnvelopeBuffer::<MemoryStackProvider>::new(0, &config, mock_memory_checker());
+
+        let project_keys = [
+            ProjectKey::parse("a94ae32be2584e0bbd7a4cbb95971fee").unwrap(),
+            ProjectKey::parse("b94ae32be2584e0bbd7a4cbb95971fee").unwrap(),
+            ProjectKey::parse("c94ae32be2584e0bbd7a4cbb95971fee").unwrap(),
+        ];
+
+        // A fixed linear congruential generator keeps the sequence of operations reproducible.
+        let mut state: u64 = 42;
+        let mut next = |bound: u64| {
+            state = state
+                .wrapping_mul(6364136223846793005)
+                .wrapping_add(1442695040888963407);
+            (state >> 33) % bound
+        };
+
+        for _ in 0..500 {
+            let project_key = project_keys[next(3) as usize];
+            match next(5) {
+                0 | 1 => {
+                    let sampling_key = (next(2) == 0).then(|| project_keys[next(3) as usize]);
+                    let envelope = new_envelope(project_key, sampling_key, None);
+                    buffer.push(envelope).await.unwrap();
+                }
+                2 => {
+                    buffer.pop().await.unwrap();
+                }
+                3 => {
+                    buffer.mark_ready(&project_key, next(2) == 0);
+                }
+                _ => {
+                    let project_key_pair = ProjectKeyPair::new(project_key, project_key);
+                    buffer.mark_seen(&project_key_pair, Duration::from_secs(next(10)));
+                }
+            }
+
+            let cached = buffer.peek().await.unwrap();
+            assert_eq!(buffer.peek().await.unwrap(), cached);
+            assert_eq!(buffer.has_ready(), matches!(cached, Peek::Ready { .. }));
+
+            buffer.cached_peek = None;
+            assert_eq!(buffer.peek().await.unwrap(), cached);
+        }
     }
-}
 
-impl Eq for Priority {}
+    async fn sqlite_buffer() -> EnvelopeBuffer<SqliteStackProvider> {
+        let path = std::env::temp_dir().join(Uuid::new_v4().to_string());
+        let config = Config::from_json_value(serde_json::json!({
+            "spool": {
+                "envelopes": {
+                    "path": path
+                }
+            }
+        }))
+        .unwrap();
+        let mut buffer =
+            EnvelopeBuffer::<SqliteStackProvider>::new(0, &config, mock_memory_checker())
+                .await
+                .unwrap();
+        buffer.initialize().await.unwrap();
+        buffer
+    }
 
-#[derive(Debug, Clone, Copy)]
-struct Readiness {
-    own_project_ready: bool,
-    sampling_project_ready: bool,
-}
+    #[tokio::test]
+    async fn test_pop_stream() {
+        let config = Config::from_json_value(serde_json::json!({
+            "spool": {
+                "envelopes": {
+                    "default_ready": false
+                }
+            }
+        }))
+        .unwrap();
+        let mut buffer = PolymorphicEnvelopeBuffer::from_config(0, &config, mock_memory_checker())
+            .await
+            .unwrap();
+        buffer.initialize().await.unwrap();
 
-impl Readiness {
-    fn new() -> Self {
-        // Optimistically set ready state to true.
-        // The large majority of stack creations are re-creations after a stack was emptied.
-        Self {
-            own_project_ready: true,
-            sampling_project_ready: true,
+        let project_key_1 = ProjectKey::parse("a94ae32be2584e0bbd7a4cbb95971fe1").unwrap();
+        let project_key_2 = ProjectKey::parse("a94ae32be2584e0bbd7a4cbb95971fe2").unwrap();
+        for project_key in [project_key_1, project_key_1, project_key_2] {
+            buffer
+                .push(new_envelope(project_key, None, None))
+                .await
+                .unwrap();
         }
-    }
 
-    fn ready(&self) -> bool {
-        self.own_project_ready && self.sampling_project_ready
-    }
-}
+        let (readiness_tx, readiness_rx) = futures::channel::mpsc::unbounded();
+        let stream = buffer.pop_stream(readiness_rx);
+        futures::pin_mut!(stream);
+        let mut next_key = || {
+            stream
+                .next()
+                .now_or_never()
+                .map(|envelope| envelope.map(|e| e.unwrap().meta().public_key()))
+        };
 
-#[cfg(test)]
-mod tests {
-    use relay_common::Dsn;
-    use relay_event_schema::protocol::EventId;
-    use relay_sampling::DynamicSamplingContext;
-    use std::str::FromStr;
-    use std::sync::Arc;
-    use uuid::Uuid;
+        // Nothing is ready, the stream waits.
+        assert_eq!(next_key(), None);
 
-    use crate::envelope::{Item, ItemType};
-    use crate::extractors::RequestMeta;
-    use crate::services::buffer::common::ProjectKeyPair;
-    use crate::services::buffer::envelope_store::sqlite::DatabaseEnvelope;
-    use crate::services::buffer::testutils::utils::mock_envelopes;
-    use crate::utils::MemoryStat;
-    use crate::SqliteEnvelopeStore;
+        readiness_tx.unbounded_send((project_key_1, true)).unwrap();
+        assert_eq!(next_key(), Some(Some(project_key_1)));
 
-    use super::*;
+        // The project becomes unavailable again before its second envelope is popped.
+        readiness_tx.unbounded_send((project_key_1, false)).unwrap();
+        assert_eq!(next_key(), None);
 
-    impl Peek {
-        fn is_empty(&self) -> bool {
-            matches!(self, Peek::Empty)
-        }
-    }
+        readiness_tx.unbounded_send((project_key_2, true)).unwrap();
+        assert_eq!(next_key(), Some(Some(project_key_2)));
 
-    fn new_envelope(
-        own_key: ProjectKey,
-        sampling_key: Option<ProjectKey>,
-        event_id: Option<EventId>,
-    ) -> Box<Envelope> {
-        let mut envelope = Envelope::from_request(
-            None,
-            RequestMeta::new(Dsn::from_str(&format!("http://{own_key}@localhost/1")).unwrap()),
-        );
-        if let Some(sampling_key) = sampling_key {
-            envelope.set_dsc(DynamicSamplingContext {
-                public_key: sampling_key,
-                trace_id: "67e5504410b1426f9247bb680e5fe0c8".parse().unwrap(),
-                release: None,
-                user: Default::default(),
-                replay_id: None,
-                environment: None,
-                transaction: None,
-                sample_rate: None,
-                sampled: None,
-                other: Default::default(),
-            });
-            envelope.add_item(Item::new(ItemType::Transaction));
-        }
-        if let Some(event_id) = event_id {
-            envelope.set_event_id(event_id);
-        }
-        envelope
+        readiness_tx.unbounded_send((project_key_1, true)).unwrap();
+        assert_eq!(next_key(), Some(Some(project_key_1)));
+
+        // The buffer is empty.
+        assert_eq!(next_key(), Some(None));
     }
 
-    fn mock_config(path: &str) -> Arc<Config> {
-        Config::from_json_value(serde_json::json!({
+    #[tokio::test]
+    async fn test_estimated_remaining_capacity() {
+        let path = std::env::temp_dir().join(Uuid::new_v4().to_string());
+        let config = Config::from_json_value(serde_json::json!({
             "spool": {
                 "envelopes": {
-                    "path": path
+                    "path": path,
+                    "max_disk_size": 10_000_000,
+                    // Keeps the disk usage stable while the test runs.
+                    "disk_usage_refresh_frequency_ms": 3_600_000
                 }
             }
         }))
-        .unwrap()
-        .into()
-    }
+        .unwrap();
+        let mut buffer = PolymorphicEnvelopeBuffer::from_config(0, &config, mock_memory_checker())
+            .await
+            .unwrap();
+        buffer.initialize().await.unwrap();
+        assert_eq!(buffer.estimated_remaining_capacity(), None);
+
+        let project_key = ProjectKey::parse("a94ae32be2584e0bbd7a4cbb95971fee").unwrap();
+        for size in [500, 1000, 1500] {
+            let mut envelope = new_envelope(project_key, None, None);
+            let mut item = Item::new(ItemType::Attachment);
+            item.set_payload(ContentType::OctetStream, vec![0; size]);
+            envelope.add_item(item);
+            buffer.push(envelope).await.unwrap();
+        }
 
-    fn mock_memory_checker() -> MemoryChecker {
-        MemoryChecker::new(MemoryStat::default(), mock_config("my/db/path").clone())
-    }
+        let used = buffer.total_size().unwrap();
+        assert_eq!(
+            buffer.estimated_remaining_capacity(),
+            Some((10_000_000 - used) / 1000)
+        );
 
-    async fn peek_received_at(buffer: &mut EnvelopeBuffer<MemoryStackProvider>) -> DateTime<Utc> {
-        buffer.peek().await.unwrap().last_received_at().unwrap()
+        let buffer =
+            PolymorphicEnvelopeBuffer::from_config(0, &Config::default(), mock_memory_checker())
+                .await
+                .unwrap();
+        assert_eq!(buffer.estimated_remaining_capacity(), None);
     }
 
     #[tokio::test]
-    async fn test_insert_pop() {
-        let mut buffer = EnvelopeBuffer::<MemoryStackProvider>::new(0, mock_memory_checker());
-
-        let project_key1 = ProjectKey::parse("a94ae32be2584e0bbd7a4cbb95971fed").unwrap();
-        let project_key2 = ProjectKey::parse("a94ae32be2584e0bbd7a4cbb95971fee").unwrap();
-        let project_key3 = ProjectKey::parse("a94ae32be2584e0bbd7a4cbb95971fef").unwrap();
+    async fn test_pop_with_ack_acknowledged() {
+        let mut buffer = sqlite_buffer().await;
 
-        assert!(buffer.pop().await.unwrap().is_none());
-        assert!(buffer.peek().await.unwrap().is_empty());
+        let project_key = ProjectKey::parse("a94ae32be2584e0bbd7a4cbb95971fed").unwrap();
+        let event_id = EventId::new();
+        buffer
+            .push(new_envelope(project_key, None, Some(event_id)))
+            .await
+            .unwrap();
 
-        let envelope1 = new_envelope(project_key1, None, None);
-        let time1 = envelope1.meta().received_at();
-        buffer.push(envelope1).await.unwrap();
+        let (envelope, token) = buffer.pop_with_ack(Duration::ZERO).await.unwrap().unwrap();
+        assert_eq!(envelope.event_id(), Some(event_id));
+        assert!(buffer.ack(token).await.unwrap());
 
-        let envelope2 = new_envelope(project_key2, None, None);
-        let time2 = envelope2.meta().received_at();
-        buffer.push(envelope2).await.unwrap();
+        // The acknowledged envelope is gone, even though its visibility timeout passed.
+        assert!(buffer.redeliver_expired().await.unwrap().is_empty());
+        assert!(buffer.pop_with_ack(Duration::ZERO).await.unwrap().is_none());
+        assert!(!buffer.ack(token).await.unwrap());
+    }
 
-        // Both projects are ready, so project 2 is on top (has the newest envelopes):
-        assert_eq!(peek_received_at(&mut buffer).await, time2);
+    #[tokio::test]
+    async fn test_pop_with_ack_redelivered() {
+        let mut buffer = sqlite_buffer().await;
 
-        buffer.mark_ready(&project_key1, false);
-        buffer.mark_ready(&project_key2, false);
+        let project_key = ProjectKey::parse("a94ae32be2584e0bbd7a4cbb95971fed").unwrap();
+        let event_id = EventId::new();
+        buffer
+            .push(new_envelope(project_key, None, Some(event_id)))
+            .await
+            .unwrap();
 
-        // Both projects are not ready, so project 1 is on top (has the oldest envelopes):
-        assert_eq!(peek_received_at(&mut buffer).await, time1);
+        // Within the visibility timeout, the envelope is not delivered again.
+        let (_, token) = buffer
+            .pop_with_ack(Duration::from_secs(3600))
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(buffer.redeliver_expired().await.unwrap().is_empty());
+        assert!(buffer.pop_with_ack(Duration::ZERO).await.unwrap().is_none());
+        assert!(buffer.ack(token).await.unwrap());
 
-        let envelope3 = new_envelope(project_key3, None, None);
-        let time3 = envelope3.meta().received_at();
-        buffer.push(envelope3).await.unwrap();
-        buffer.mark_ready(&project_key3, false);
+        buffer
+            .push(new_envelope(project_key, None, Some(event_id)))
+            .await
+            .unwrap();
 
-        // All projects are not ready, so project 1 is on top (has the oldest envelopes):
-        assert_eq!(peek_received_at(&mut buffer).await, time1);
+        // Without an acknowledgement, the envelope reappears once its timeout passed.
+        let (_, expired_token) = buffer.pop_with_ack(Duration::ZERO).await.unwrap().unwrap();
+        assert_eq!(buffer.total_count, 0);
+        assert!(buffer.redeliver_expired().await.unwrap().is_empty());
+        assert_eq!(buffer.total_count, 1);
 
-        // After marking a project ready, it goes to the top:
-        buffer.mark_ready(&project_key3, true);
-        assert_eq!(peek_received_at(&mut buffer).await, time3);
-        assert_eq!(
-            buffer.pop().await.unwrap().unwrap().meta().public_key(),
-            project_key3
-        );
+        let (envelope, token) = buffer
+            .pop_with_ack(Duration::from_secs(3600))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(envelope.event_id(), Some(event_id));
+        assert_ne!(token, expired_token);
+        assert!(!buffer.ack(expired_token).await.unwrap());
+        assert!(buffer.ack(token).await.unwrap());
+    }
 
-        // After popping, project 1 is on top again:
-        assert_eq!(peek_received_at(&mut buffer).await, time1);
+    #[tokio::test]
+    async fn test_pop_with_ack_from_disk() {
+        let path = std::env::temp_dir().join(Uuid::new_v4().to_string());
+        let config = Config::from_json_value(serde_json::json!({
+            "spool": {
+                "envelopes": {
+                    "path": path
+                }
+            }
+        }))
+        .unwrap();
 
-        // Mark project 1 as ready (still on top):
-        buffer.mark_ready(&project_key1, true);
-        assert_eq!(peek_received_at(&mut buffer).await, time1);
+        // Seed a stack on disk, so envelopes are held in the transaction that reads them.
+        let project_key = ProjectKey::parse("a94ae32be2584e0bbd7a4cbb95971fed").unwrap();
+        let event_ids = [EventId::new(), EventId::new()];
+        let envelopes = event_ids
+            .iter()
+            .map(|event_id| {
+                let envelope = new_envelope(project_key, None, Some(*event_id));
+                DatabaseEnvelope::try_from(envelope.as_ref()).unwrap()
+            })
+            .collect::<Vec<_>>();
+        let mut store = SqliteEnvelopeStore::prepare(0, &config, mock_memory_checker())
+            .await
+            .unwrap();
+        store
+            .insert_batch(envelopes.try_into().unwrap())
+            .await
+            .unwrap();
 
-        // Mark project 2 as ready as well (now on top because most recent):
-        buffer.mark_ready(&project_key2, true);
-        assert_eq!(peek_received_at(&mut buffer).await, time2);
-        assert_eq!(
-            buffer.pop().await.unwrap().unwrap().meta().public_key(),
-            project_key2
-        );
+        let mut buffer =
+            EnvelopeBuffer::<SqliteStackProvider>::new(0, &config, mock_memory_checker())
+                .await
+                .unwrap();
+        buffer.initialize().await.unwrap();
+
+        let mut tokens = vec![];
+        for event_id in event_ids.into_iter().rev() {
+            let (envelope, token) = buffer.pop_with_ack(Duration::ZERO).await.unwrap().unwrap();
+            assert_eq!(envelope.event_id(), Some(event_id));
+            tokens.push(token);
+        }
+        assert!(buffer.pop_with_ack(Duration::ZERO).await.unwrap().is_none());
 
-        // Pop last element:
-        assert_eq!(
-            buffer.pop().await.unwrap().unwrap().meta().public_key(),
-            project_key1
-        );
-        assert!(buffer.pop().await.unwrap().is_none());
-        assert!(buffer.peek().await.unwrap().is_empty());
+        // Both envelopes were held, so they are delivered again.
+        assert!(buffer.redeliver_expired().await.unwrap().is_empty());
+        for token in tokens {
+            assert!(!buffer.ack(token).await.unwrap());
+        }
+        for _ in event_ids {
+            assert!(buffer
+                .pop_with_ack(Duration::from_secs(3600))
+                .await
+                .unwrap()
+                .is_some());
+        }
     }
 
     #[tokio::test]
-    async fn test_project_internal_order() {
-        let mut buffer = EnvelopeBuffer::<MemoryStackProvider>::new(0, mock_memory_checker());
+    async fn test_redeliver_expired_evicted() {
+        let mut buffer = sqlite_buffer().await;
+        buffer.max_stack_depth = NonZeroUsize::new(1);
 
         let project_key = ProjectKey::parse("a94ae32be2584e0bbd7a4cbb95971fed").unwrap();
+        let held_id = EventId::new();
+        buffer
+            .push(new_envelope(project_key, None, Some(held_id)))
+            .await
+            .unwrap();
+        buffer.pop_with_ack(Duration::ZERO).await.unwrap().unwrap();
 
-        let envelope1 = new_envelope(project_key, None, None);
-        let time1 = envelope1.meta().received_at();
-        let envelope2 = new_envelope(project_key, None, None);
-        let time2 = envelope2.meta().received_at();
+        // The stack is full again, so the redelivered envelope is evicted for the caller to reject.
+        buffer
+            .push(new_envelope(project_key, None, Some(EventId::new())))
+            .await
+            .unwrap();
+        let evicted = buffer.redeliver_expired().await.unwrap();
+        assert_eq!(evicted.len(), 1);
+        assert_eq!(buffer.total_count, 1);
+    }
 
-        assert!(time2 > time1);
+    /// Pushes envelopes with interleaved receive times to three stacks and checks that they are
+    /// drained in the order they were received, also if the stream is dropped early.
+    async fn assert_iter_chronological(buffer: &mut PolymorphicEnvelopeBuffer) {
+        let project_keys = [
+            ProjectKey::parse("a94ae32be2584e0bbd7a4cbb95971fed").unwrap(),
+            ProjectKey::parse("b56ae32be2584e0bbd7a4cbb95971fed").unwrap(),
+            ProjectKey::parse("c67ae32be2584e0bbd7a4cbb95971fed").unwrap(),
+        ];
+        let stacks = [0, 1, 1, 2, 0, 0, 2, 1, 2, 0, 1, 2];
+
+        let start = Utc::now() - chrono::Duration::minutes(1);
+        let mut event_ids = vec![];
+        for (index, stack) in stacks.into_iter().enumerate() {
+            let event_id = EventId::new();
+            let mut envelope = new_envelope(project_keys[stack], None, Some(event_id));
+            envelope.set_received_at(start + chrono::Duration::seconds(index as i64));
+            buffer.push(envelope).await.unwrap();
+            event_ids.push(Some(event_id));
+        }
 
-        buffer.push(envelope1).await.unwrap();
-        buffer.push(envelope2).await.unwrap();
+        // Dropping the stream early must not lose the envelopes that were not yielded.
+        let mut envelopes: Vec<_> = buffer
+            .iter_chronological()
+            .take(2)
+            .try_collect()
+            .await
+            .unwrap();
+        assert_eq!(envelopes.len(), 2);
 
-        assert_eq!(
-            buffer.pop().await.unwrap().unwrap().meta().received_at(),
-            time2
-        );
-        assert_eq!(
-            buffer.pop().await.unwrap().unwrap().meta().received_at(),
-            time1
+        envelopes.extend(
+            buffer
+                .iter_chronological()
+                .try_collect::<Vec<_>>()
+                .await
+                .unwrap(),
         );
+        assert!(envelopes
+            .windows(2)
+            .all(|pair| pair[0].received_at() < pair[1].received_at()));
+        let popped: Vec<_> = envelopes.iter().map(|e| e.event_id()).collect();
+        assert_eq!(popped, event_ids);
+
         assert!(buffer.pop().await.unwrap().is_none());
     }
 
     #[tokio::test]
-    async fn test_sampling_projects() {
-        let mut buffer = EnvelopeBuffer::<MemoryStackProvider>::new(0, mock_memory_checker());
-
-        let project_key1 = ProjectKey::parse("a94ae32be2584e0bbd7a4cbb95971fed").unwrap();
-        let project_key2 = ProjectKey::parse("a94ae32be2584e0bbd7a4cbb95971fef").unwrap();
+    async fn test_iter_chronological_memory() {
+        let mut buffer = PolymorphicEnvelopeBuffer::InMemory(EnvelopeBuffer::new(
+            0,
+            &Config::default(),
+            mock_memory_checker(),
+        ));
+        assert_iter_chronological(&mut buffer).await;
+    }
 
-        let envelope1 = new_envelope(project_key1, None, None);
-        let time1 = envelope1.received_at();
-        buffer.push(envelope1).await.unwrap();
+    #[tokio::test]
+    async fn test_iter_chronological_sqlite() {
+        let path = std::env::temp_dir().join(Uuid::new_v4().to_string());
+        let config = Config::from_json_value(serde_json::json!({
+            "spool": {
+                "envelopes": {
+                    "path": path,
+                    // Write every envelope to disk.
+                    "batch_size_bytes": 1
+                }
+            }
+        }))
+        .unwrap();
+        let mut buffer =
+            EnvelopeBuffer::<SqliteStackProvider>::new(0, &config, mock_memory_checker())
+                .await
+                .unwrap();
+        buffer.initialize().await.unwrap();
 
-        let envelope2 = new_envelope(project_key2, None, None);
-        let time2 = envelope2.received_at();
-        buffer.push(envelope2).await.unwrap();
+        let mut buffer = PolymorphicEnvelopeBuffer::Sqlite(buffer);
+        assert_iter_chronological(&mut buffer).await;
+    }
 
-        let envelope3 = new_envelope(project_key1, Some(project_key2), None);
-        let time3 = envelope3.meta().received_at();
-        buffer.push(envelope3).await.unwrap();
+    #[tokio::test]
+    async fn test_prefer_memory_resident_stacks() {
+        let path = std::env::temp_dir().join(Uuid::new_v4().to_string());
+        let config = Config::from_json_value(serde_json::json!({
+            "spool": {
+                "envelopes": {
+                    "path": path,
+                    "prefer_memory_resident_stacks": true
+                }
+            }
+        }))
+        .unwrap();
+        let received_at = Utc::now();
+
+        // Seed a stack on disk, which is loaded without reading its envelopes.
+        let disk_key = ProjectKey::parse("a94ae32be2584e0bbd7a4cbb95971fed").unwrap();
+        let mut envelope = new_envelope(disk_key, None, None);
+        envelope.set_received_at(received_at);
+        let mut store = SqliteEnvelopeStore::prepare(0, &config, mock_memory_checker())
+            .await
+            .unwrap();
+        store
+            .insert_batch(
+                vec![DatabaseEnvelope::try_from(envelope.as_ref()).unwrap()]
+                    .try_into()
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
 
-        buffer.mark_ready(&project_key1, false);
-        buffer.mark_ready(&project_key2, false);
+        let mut buffer =
+            EnvelopeBuffer::<SqliteStackProvider>::new(0, &config, mock_memory_checker())
+                .await
+                .unwrap();
+        buffer.initialize().await.unwrap();
 
-        // Nothing is ready, instant1 is on top:
-        assert_eq!(
-            buffer.peek().await.unwrap().last_received_at().unwrap(),
-            time1
+        // Stacks are loaded with the current time, so align it with the in-memory stack below.
+        buffer.priority_queue.change_priority_by(
+            &ProjectKeyPair::new(disk_key, disk_key),
+            |prio| {
+                prio.received_at = received_at;
+            },
         );
 
-        // Mark project 2 ready, gets on top:
-        buffer.mark_ready(&project_key2, true);
-        assert_eq!(
-            buffer.peek().await.unwrap().last_received_at().unwrap(),
-            time2
-        );
+        let memory_key = ProjectKey::parse("b56ae32be2584e0bbd7a4cbb95971fed").unwrap();
+        let mut envelope = new_envelope(memory_key, None, None);
+        envelope.set_received_at(received_at);
+        buffer.push(envelope).await.unwrap();
 
-        // Revert
-        buffer.mark_ready(&project_key2, false);
-        assert_eq!(
-            buffer.peek().await.unwrap().last_received_at().unwrap(),
-            time1
-        );
+        let Peek::Ready {
+            project_key_pair, ..
+        } = buffer.peek().await.unwrap()
+        else {
+            panic!();
+        };
+        assert_eq!(project_key_pair.own_key, memory_key);
 
-        // Project 1 ready:
-        buffer.mark_ready(&project_key1, true);
-        assert_eq!(
-            buffer.peek().await.unwrap().last_received_at().unwrap(),
-            time1
-        );
+        // Once the in-memory stack is drained, the stack on disk is popped.
+        buffer.pop().await.unwrap();
+        let envelope = buffer.pop().await.unwrap().unwrap();
+        assert_eq!(envelope.meta().public_key(), disk_key);
+    }
 
-        // when both projects are ready, event no 3 ends up on top:
-        buffer.mark_ready(&project_key2, true);
-        assert_eq!(
-            buffer.pop().await.unwrap().unwrap().meta().received_at(),
-            time3
-        );
-        assert_eq!(
-            buffer.peek().await.unwrap().last_received_at().unwrap(),
-            time2
-        );
+    #[tokio::test]
+    async fn test_drain_all_for_replay() {
+        let path = std::env::temp_dir().join(Uuid::new_v4().to_string());
+        let config = mock_config(path.to_str().unwrap());
+        let mut store = SqliteEnvelopeStore::prepare(0, &config, mock_memory_checker())
+            .await
+            .unwrap();
 
-        buffer.mark_ready(&project_key2, false);
-        assert_eq!(buffer.pop().await.unwrap().unwrap().received_at(), time1);
-        assert_eq!(buffer.pop().await.unwrap().unwrap().received_at(), time2);
+        let envelopes = mock_envelopes(10);
+        let mut event_ids: Vec<_> = envelopes.iter().map(|e| e.event_id().unwrap()).collect();
+        store
+            .insert_batch(
+                envelopes
+                    .iter()
+                    .map(|e| DatabaseEnvelope::try_from(e.as_ref()).unwrap())
+                    .collect::<Vec<_>>()
+                    .try_into()
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
 
-        assert!(buffer.pop().await.unwrap().is_none());
+        let mut buffer = PolymorphicEnvelopeBuffer::from_path_for_replay(&path)
+            .await
+            .unwrap();
+        let drained: Vec<_> = buffer.drain_all().try_collect().await.unwrap();
+
+        // Envelopes of a stack are drained from the most recent to the oldest.
+        event_ids.reverse();
+        assert_eq!(
+            drained
+                .iter()
+                .map(|e| e.event_id().unwrap())
+                .collect::<Vec<_>>(),
+            event_ids
+        );
+        assert!(buffer.peek().await.unwrap().is_empty());
     }
 
     #[tokio::test]
-    async fn test_project_keys_distinct() {
+    async fn test_export_import_archive() {
         let project_key1 = ProjectKey::parse("a94ae32be2584e0bbd7a4cbb95971fed").unwrap();
-        let project_key2 = ProjectKey::parse("a94ae32be2584e0bbd7a4cbb95971fef").unwrap();
-
-        let project_key_pair1 = ProjectKeyPair::new(project_key1, project_key2);
-        let project_key_pair2 = ProjectKeyPair::new(project_key2, project_key1);
+        let project_key2 = ProjectKey::parse("a94ae32be2584e0bbd7a4cbb95971fee").unwrap();
 
-        assert_ne!(project_key_pair1, project_key_pair2);
+        let mut source =
+            PolymorphicEnvelopeBuffer::InMemory(EnvelopeBuffer::<MemoryStackProvider>::new(
+                0,
+                &Config::default(),
+                mock_memory_checker(),
+            ));
+
+        let envelopes = [
+            new_envelope(project_key1, None, Some(EventId::new())),
+            new_envelope(project_key1, Some(project_key2), Some(EventId::new())),
+            new_envelope(project_key2, None, Some(EventId::new())),
+        ];
+        let mut expected: Vec<_> = envelopes.iter().map(|e| e.to_vec().unwrap()).collect();
+        for envelope in envelopes {
+            source.push(envelope).await.unwrap();
+        }
 
-        let mut buffer = EnvelopeBuffer::<MemoryStackProvider>::new(0, mock_memory_checker());
-        buffer
-            .push(new_envelope(project_key1, Some(project_key2), None))
+        let mut archive = Vec::new();
+        assert_eq!(source.export_archive(&mut archive).await.unwrap(), 3);
+        assert!(source.peek().await.unwrap().is_empty());
+
+        let mut target =
+            PolymorphicEnvelopeBuffer::InMemory(EnvelopeBuffer::<MemoryStackProvider>::new(
+                1,
+                &Config::default(),
+                mock_memory_checker(),
+            ));
+        let evicted = target
+            .import_archive(archive.as_slice(), usize::MAX)
             .await
             .unwrap();
-        buffer
-            .push(new_envelope(project_key2, Some(project_key1), None))
+        assert!(evicted.is_empty());
+
+        let mut imported: Vec<_> = target
+            .drain_all()
+            .map_ok(|e| e.to_vec().unwrap())
+            .try_collect()
             .await
             .unwrap();
-        assert_eq!(buffer.priority_queue.len(), 2);
-    }
-
-    #[test]
-    fn test_total_order() {
-        let p1 = Priority {
-            readiness: Readiness {
-                own_project_ready: true,
-                sampling_project_ready: true,
-            },
-            received_at: Utc::now(),
-            next_project_fetch: Instant::now(),
-        };
-        let mut p2 = p1.clone();
-        p2.next_project_fetch += Duration::from_millis(1);
 
-        // Last peek does not matter because project is ready:
-        assert_eq!(p1.cmp(&p2), Ordering::Equal);
-        assert_eq!(p1, p2);
+        expected.sort();
+        imported.sort();
+        assert_eq!(imported, expected);
     }
 
     #[tokio::test]
-    async fn test_last_peek_internal_order() {
-        let mut buffer = EnvelopeBuffer::<MemoryStackProvider>::new(0, mock_memory_checker());
+    async fn test_export_archive_keeps_envelopes_on_failure() {
+        /// Accepts the archive header and fails to write any records.
+        struct HeaderOnly;
+
+        impl Write for HeaderOnly {
+            fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+                match buf.len() {
+                    // The magic bytes and the version and codec ids.
+                    8 | 2 => Ok(buf.len()),
+                    _ => Err(std::io::ErrorKind::WriteZero.into()),
+                }
+            }
 
-        let project_key_1 = ProjectKey::parse("a94ae32be2584e0bbd7a4cbb95971fed").unwrap();
-        let event_id_1 = EventId::new();
-        let envelope1 = new_envelope(project_key_1, None, Some(event_id_1));
-        let time1 = envelope1.received_at();
+            fn flush(&mut self) -> std::io::Result<()> {
+                Ok(())
+            }
+        }
 
-        let project_key_2 = ProjectKey::parse("b56ae32be2584e0bbd7a4cbb95971fed").unwrap();
-        let event_id_2 = EventId::new();
-        let envelope2 = new_envelope(project_key_2, None, Some(event_id_2));
-        let time2 = envelope2.received_at();
+        let project_key = ProjectKey::parse("a94ae32be2584e0bbd7a4cbb95971fed").unwrap();
+        let mut buffer =
+            PolymorphicEnvelopeBuffer::InMemory(EnvelopeBuffer::<MemoryStackProvider>::new(
+                0,
+                &Config::default(),
+                mock_memory_checker(),
+            ));
+        let envelope = new_envelope(project_key, None, Some(EventId::new()));
+        let expected = envelope.to_vec().unwrap();
+        buffer.push(envelope).await.unwrap();
+
+        let (error, envelopes) = buffer.export_archive(HeaderOnly).await.unwrap_err();
+        assert!(matches!(
+            error,
+            EnvelopeBufferError::Archive(ArchiveError::Io(_))
+        ));
+        assert_eq!(envelopes.len(), 1);
+        assert_eq!(envelopes[0].to_vec().unwrap(), expected);
+    }
 
-        buffer.push(envelope1).await.unwrap();
-        buffer.push(envelope2).await.unwrap();
+    #[tokio::test]
+    async fn test_import_archive_unsupported_version() {
+        let mut buffer =
+            PolymorphicEnvelopeBuffer::InMemory(EnvelopeBuffer::<MemoryStackProvider>::new(
+                0,
+                &Config::default(),
+                mock_memory_checker(),
+            ));
+
+        let mut archive = Vec::new();
+        buffer.export_archive(&mut archive).await.unwrap();
+        // Bump the version in the header, which follows the 8 magic bytes.
+        archive[8] += 1;
+
+        let result = buffer.import_archive(archive.as_slice(), usize::MAX).await;
+        assert!(matches!(
+            result,
+            Err(EnvelopeBufferError::Archive(
+                ArchiveError::UnsupportedVersion(_)
+            ))
+        ));
+    }
 
-        buffer.mark_ready(&project_key_1, false);
-        buffer.mark_ready(&project_key_2, false);
+    #[tokio::test]
+    async fn test_load_stacks_concurrently() {
+        let project_key1 = ProjectKey::parse("a94ae32be2584e0bbd7a4cbb95971fed").unwrap();
+        let project_key2 = ProjectKey::parse("a94ae32be2584e0bbd7a4cbb95971fee").unwrap();
+        let project_key3 = ProjectKey::parse("a94ae32be2584e0bbd7a4cbb95971fef").unwrap();
+        let project_key_pairs = HashSet::from([
+            ProjectKeyPair::new(project_key1, project_key1),
+            ProjectKeyPair::new(project_key1, project_key2),
+            ProjectKeyPair::new(project_key2, project_key3),
+            ProjectKeyPair::new(project_key3, project_key3),
+        ]);
+
+        let mut buffers = Vec::new();
+        for load_concurrency in [1, 3] {
+            let config = Config::from_json_value(serde_json::json!({
+                "spool": {
+                    "envelopes": {
+                        "load_concurrency": load_concurrency
+                    }
+                }
+            }))
+            .unwrap();
+            let mut buffer =
+                EnvelopeBuffer::<MemoryStackProvider>::new(0, &config, mock_memory_checker());
+            buffer.load_stacks(project_key_pairs.clone()).await;
+            buffers.push(buffer);
+        }
 
-        // event_id_1 is first element:
-        let Peek::NotReady {
-            last_received_at, ..
-        } = buffer.peek().await.unwrap()
-        else {
-            panic!();
+        let [sequential, concurrent] = buffers.as_slice() else {
+            unreachable!();
         };
-        assert_eq!(last_received_at, time1);
-
-        // Second peek returns same element:
-        let Peek::NotReady {
-            last_received_at,
-            project_key_pair,
-            ..
-        } = buffer.peek().await.unwrap()
-        else {
-            panic!();
+        let stacks = |buffer: &EnvelopeBuffer<MemoryStackProvider>| {
+            buffer
+                .priority_queue
+                .iter()
+                .map(|(item, _)| item.key)
+                .collect::<HashSet<_>>()
         };
-        assert_eq!(last_received_at, time1);
-        assert_ne!(last_received_at, time2);
 
-        buffer.mark_seen(&project_key_pair, Duration::ZERO);
+        assert_eq!(stacks(sequential), project_key_pairs);
+        assert_eq!(stacks(concurrent), project_key_pairs);
+        assert_eq!(sequential.stacks_by_project, concurrent.stacks_by_project);
+    }
 
-        // After mark_seen, event 2 is on top:
-        let Peek::NotReady {
-            last_received_at, ..
-        } = buffer.peek().await.unwrap()
-        else {
-            panic!();
-        };
-        assert_eq!(last_received_at, time2);
-        assert_ne!(last_received_at, time1);
+    #[tokio::test]
+    async fn test_pop_before_initialize() {
+        let config = Config::from_json_value(serde_json::json!({
+            "spool": {
+                "envelopes": {
+                    "require_initialization": true
+                }
+            }
+        }))
+        .unwrap();
+        let mut buffer =
+            EnvelopeBuffer::<MemoryStackProvider>::new(0, &config, mock_memory_checker());
 
-        let Peek::NotReady {
-            last_received_at,
-            project_key_pair,
-            ..
-        } = buffer.peek().await.unwrap()
-        else {
-            panic!();
-        };
-        assert_eq!(last_received_at, time2);
-        assert_ne!(last_received_at, time1);
+        // Pushing is allowed before the buffer is initialized.
+        let project_key = ProjectKey::parse("a94ae32be2584e0bbd7a4cbb95971fed").unwrap();
+        buffer
+            .push(new_envelope(project_key, None, None))
+            .await
+            .unwrap();
 
-        buffer.mark_seen(&project_key_pair, Duration::ZERO);
+        assert!(matches!(
+            buffer.peek().await,
+            Err(EnvelopeBufferError::NotInitialized)
+        ));
+        assert!(matches!(
+            buffer.pop().await,
+            Err(EnvelopeBufferError::NotInitialized)
+        ));
+        assert_eq!(buffer.tracked_count, 1);
 
-        // After another mark_seen, cycle back to event 1:
-        let Peek::NotReady {
-            last_received_at, ..
-        } = buffer.peek().await.unwrap()
-        else {
-            panic!();
-        };
-        assert_eq!(last_received_at, time1);
-        assert_ne!(last_received_at, time2);
+        buffer.initialize().await.unwrap();
+
+        assert!(!buffer.peek().await.unwrap().is_empty());
+        assert!(buffer.pop().await.unwrap().is_some());
     }
 
     #[tokio::test]
@@ -1072,10 +7670,13 @@ mod tests {
             .into_string()
             .unwrap();
         let config = mock_config(&path);
-        let mut store = SqliteEnvelopeStore::prepare(0, &config).await.unwrap();
-        let mut buffer = EnvelopeBuffer::<SqliteStackProvider>::new(0, &config)
+        let mut store = SqliteEnvelopeStore::prepare(0, &config, mock_memory_checker())
             .await
             .unwrap();
+        let mut buffer =
+            EnvelopeBuffer::<SqliteStackProvider>::new(0, &config, mock_memory_checker())
+                .await
+                .unwrap();
 
         // We write 5 envelopes to disk so that we can check if they are loaded. These envelopes
         // belong to the same project keys, so they belong to the same envelope stack.
@@ -1096,7 +7697,7 @@ mod tests {
         assert!(buffer.priority_queue.is_empty());
         assert!(buffer.stacks_by_project.is_empty());
 
-        buffer.initialize().await;
+        buffer.initialize().await.unwrap();
 
         // We assume that we loaded only 1 envelope stack, because of the project keys combinations
         // of the envelopes we inserted above.
@@ -1105,4 +7706,244 @@ mod tests {
         // should be 2.
         assert_eq!(buffer.stacks_by_project.len(), 2);
     }
+
+    #[tokio::test]
+    async fn test_attachment_bytes_ignores_stored_envelopes() {
+        let path = std::env::temp_dir()
+            .join(Uuid::new_v4().to_string())
+            .into_os_string()
+            .into_string()
+            .unwrap();
+        let config = mock_config(&path);
+        let mut store = SqliteEnvelopeStore::prepare(0, &config, mock_memory_checker())
+            .await
+            .unwrap();
+        let project_key = ProjectKey::parse("a94ae32be2584e0bbd7a4cbb95971fed").unwrap();
+
+        let with_attachment = |payload: &'static str| {
+            let mut envelope = new_envelope(project_key, None, None);
+            let mut item = Item::new(ItemType::Attachment);
+            item.set_payload(ContentType::OctetStream, payload);
+            envelope.add_item(item);
+            envelope
+        };
+
+        // Two envelopes with attachments are stored before the buffer starts.
+        store
+            .insert_batch(
+                [with_attachment("0123456789"), with_attachment("0123456789")]
+                    .iter()
+                    .map(|e| DatabaseEnvelope::try_from(e.as_ref()).unwrap())
+                    .collect::<Vec<_>>()
+                    .try_into()
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        let mut buffer =
+            EnvelopeBuffer::<SqliteStackProvider>::new(0, &config, mock_memory_checker())
+                .await
+                .unwrap();
+        buffer.initialize().await.unwrap();
+        assert_eq!(buffer.attachment_bytes, 0);
+
+        buffer.push(with_attachment("01234")).await.unwrap();
+        buffer.push(with_attachment("012")).await.unwrap();
+        assert_eq!(buffer.attachment_bytes, 8);
+
+        // The bottom of the stack was stored before, so its attachments were never counted.
+        buffer.pop_oldest().await.unwrap().unwrap();
+        assert_eq!(buffer.attachment_bytes, 8);
+
+        // Only the envelopes pushed since the startup reduce the counter.
+        buffer.pop().await.unwrap().unwrap();
+        assert_eq!(buffer.attachment_bytes, 5);
+        buffer.pop().await.unwrap().unwrap();
+        assert_eq!(buffer.attachment_bytes, 0);
+
+        // A pushed envelope stays counted while the remaining stored envelope is popped below it.
+        buffer.push(with_attachment("01234")).await.unwrap();
+        buffer.pop_oldest().await.unwrap().unwrap();
+        assert_eq!(buffer.attachment_bytes, 5);
+        buffer.pop().await.unwrap().unwrap();
+        assert_eq!(buffer.attachment_bytes, 0);
+        assert!(buffer.pop().await.unwrap().is_none());
+    }
+
+    #[test]
+    fn test_initialized_metric() {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        let path = std::env::temp_dir().join(Uuid::new_v4().to_string());
+        let config = Config::from_json_value(serde_json::json!({
+            "spool": {
+                "envelopes": {
+                    "path": path
+                }
+            }
+        }))
+        .unwrap();
+
+        let project_key1 = ProjectKey::parse("a94ae32be2584e0bbd7a4cbb95971fed").unwrap();
+        let project_key2 = ProjectKey::parse("b56ae32be2584e0bbd7a4cbb95971fed").unwrap();
+        runtime.block_on(async {
+            let mut store = SqliteEnvelopeStore::prepare(0, &config, mock_memory_checker())
+                .await
+                .unwrap();
+            for (project_key, count) in [(project_key1, 2), (project_key2, 1)] {
+                store
+                    .insert_batch(
+                        (0..count)
+                            .map(|_| {
+                                let envelope = new_envelope(project_key, None, None);
+                                DatabaseEnvelope::try_from(envelope.as_ref()).unwrap()
+                            })
+                            .collect::<Vec<_>>()
+                            .try_into()
+                            .unwrap(),
+                    )
+                    .await
+                    .unwrap();
+            }
+        });
+
+        let captures = relay_statsd::with_capturing_test_client(|| {
+            runtime.block_on(async {
+                let mut buffer =
+                    EnvelopeBuffer::<SqliteStackProvider>::new(0, &config, mock_memory_checker())
+                        .await
+                        .unwrap();
+                buffer.initialize().await.unwrap();
+            });
+        });
+
+        let captures: Vec<_> = captures
+            .into_iter()
+            .filter(|metric| metric.starts_with("buffer.initialized:"))
+            .collect();
+        assert_eq!(captures.len(), 3);
+        assert_eq!(
+            captures[0],
+            "buffer.initialized:2|g|#partition_id:0,measure:stacks"
+        );
+        assert_eq!(
+            captures[1],
+            "buffer.initialized:3|g|#partition_id:0,measure:envelopes"
+        );
+        assert!(captures[2].ends_with("|g|#partition_id:0,measure:duration_ms"));
+    }
+
+    #[tokio::test]
+    async fn test_verify_recovery() {
+        let path = std::env::temp_dir().join(Uuid::new_v4().to_string());
+        let config = |max_mismatch: Option<u64>| {
+            Config::from_json_value(serde_json::json!({
+                "spool": {
+                    "envelopes": {
+                        "path": path,
+                        "verify_on_start": true,
+                        "verify_max_mismatch": max_mismatch
+                    }
+                }
+            }))
+            .unwrap()
+        };
+
+        let project_key1 = ProjectKey::parse("a94ae32be2584e0bbd7a4cbb95971fed").unwrap();
+        let project_key2 = ProjectKey::parse("b56ae32be2584e0bbd7a4cbb95971fed").unwrap();
+        let mut store = SqliteEnvelopeStore::prepare(0, &config(None), mock_memory_checker())
+            .await
+            .unwrap();
+        // Batches are stored in a single row, so every project is inserted separately.
+        for project_key in [project_key1, project_key2] {
+            let envelope = new_envelope(project_key, None, None);
+            store
+                .insert_batch(
+                    vec![DatabaseEnvelope::try_from(envelope.as_ref()).unwrap()]
+                        .try_into()
+                        .unwrap(),
+                )
+                .await
+                .unwrap();
+        }
+
+        // Corrupt the project key of one envelope, so that its stack cannot be recovered.
+        let db = sqlx::SqlitePool::connect_with(
+            sqlx::sqlite::SqliteConnectOptions::new().filename(&path),
+        )
+        .await
+        .unwrap();
+        sqlx::query("UPDATE envelopes SET own_key = 'invalid' WHERE own_key = ?")
+            .bind(project_key2.to_string())
+            .execute(&db)
+            .await
+            .unwrap();
+
+        // The mismatch is within the tolerated maximum.
+        let mut buffer =
+            EnvelopeBuffer::<SqliteStackProvider>::new(0, &config(Some(1)), mock_memory_checker())
+                .await
+                .unwrap();
+        buffer.initialize().await.unwrap();
+        assert_eq!(buffer.priority_queue.len(), 1);
+
+        let mut buffer =
+            EnvelopeBuffer::<SqliteStackProvider>::new(0, &config(Some(0)), mock_memory_checker())
+                .await
+                .unwrap();
+        assert!(matches!(
+            buffer.initialize().await,
+            Err(EnvelopeBufferError::RecoveryMismatch {
+                stored: 2,
+                loaded: 1
+            })
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_self_test() {
+        let path = std::env::temp_dir().join(Uuid::new_v4().to_string());
+        let config = Config::from_json_value(serde_json::json!({
+            "spool": {
+                "envelopes": {
+                    "path": path,
+                    "self_test": true
+                }
+            }
+        }))
+        .unwrap();
+
+        // The synthetic envelope leaves no trace in a healthy buffer.
+        let mut buffer =
+            EnvelopeBuffer::<SqliteStackProvider>::new(0, &config, mock_memory_checker())
+                .await
+                .unwrap();
+        buffer.initialize().await.unwrap();
+        assert!(buffer.priority_queue.is_empty());
+        assert_eq!(buffer.total_count, 0);
+        assert_eq!(buffer.stack_provider.store_total_count().await, 0);
+
+        // Break the store by removing the table that holds the envelopes.
+        let db = sqlx::SqlitePool::connect_with(
+            sqlx::sqlite::SqliteConnectOptions::new().filename(&path),
+        )
+        .await
+        .unwrap();
+        sqlx::query("DROP TABLE envelopes")
+            .execute(&db)
+            .await
+            .unwrap();
+
+        let mut buffer =
+            EnvelopeBuffer::<SqliteStackProvider>::new(0, &config, mock_memory_checker())
+                .await
+                .unwrap();
+        assert!(matches!(
+            buffer.initialize().await,
+            Err(EnvelopeBufferError::SqliteStack(_))
+        ));
+    }
 }
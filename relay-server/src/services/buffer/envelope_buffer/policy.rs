@@ -0,0 +1,104 @@
+use std::cmp::Ordering;
+use std::fmt::Debug;
+
+use chrono::{DateTime, Utc};
+use tokio::time::Instant;
+
+use crate::services::buffer::envelope_buffer::Sequence;
+
+/// Class of a stack that takes precedence over all other properties in the [`DefaultPolicy`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PriorityClass {
+    /// The stack belongs to a hot project and is drained before other ready stacks.
+    Hot,
+    /// A stack without special treatment.
+    Normal,
+    /// The stack failed repeatedly and is sorted behind all other stacks.
+    Quarantined,
+}
+
+/// The properties of a stack that determine its position in the buffer.
+#[derive(Debug, Clone, Copy)]
+pub struct StackMeta {
+    /// Whether both the own and the sampling project of the stack are ready.
+    pub ready: bool,
+    /// The time at which the most recent envelope of the stack was received.
+    pub received_at: DateTime<Utc>,
+    /// Number of envelopes in the stack as of the last push or pop.
+    pub depth: usize,
+    /// The earliest time at which the projects of a stack that is not ready are fetched again.
+    pub next_fetch: Instant,
+    /// The class of the stack.
+    pub class: PriorityClass,
+    /// Whether the next envelope of the stack is held in memory.
+    ///
+    /// This is only tracked if memory resident stacks are preferred, otherwise it is always
+    /// `false`.
+    pub memory_resident: bool,
+    /// Whether the next envelope of the stack belongs to an unsampled trace.
+    ///
+    /// This is only tracked if unsampled stacks are deprioritized, otherwise it is always `false`.
+    pub unsampled: bool,
+    /// The push sequence of the stack's most recent envelope.
+    ///
+    /// Unlike `received_at`, the sequence is not affected by the system clock going backwards.
+    pub sequence: Sequence,
+}
+
+/// Decides the order in which the envelope buffer pops its stacks.
+///
+/// Stacks are kept in a max-heap, so the stack that compares greatest is popped first. Since the
+/// buffer evicts from the non-ready end of the queue, policies should order non-ready stacks after
+/// ready ones.
+pub trait SchedulingPolicy: Clone + Debug {
+    /// Compares the properties of two stacks.
+    ///
+    /// The comparison must be a total order, otherwise the order of the buffer is undefined.
+    fn compare(&self, a: &StackMeta, b: &StackMeta) -> Ordering;
+}
+
+/// The scheduling policy used by Relay.
+///
+/// Quarantined stacks are sorted after all other stacks. Ready stacks are sorted before stacks
+/// that are not ready, with stacks of hot projects first, stacks of unsampled traces last, and
+/// otherwise the most recently received envelopes first. Stacks that are not ready are sorted by
+/// their next project fetch, such that stacks that did not receive envelopes recently can be
+/// evicted.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct DefaultPolicy;
+
+impl SchedulingPolicy for DefaultPolicy {
+    fn compare(&self, a: &StackMeta, b: &StackMeta) -> Ordering {
+        let quarantined = |meta: &StackMeta| meta.class == PriorityClass::Quarantined;
+        let hot = |meta: &StackMeta| meta.class == PriorityClass::Hot;
+
+        // Quarantined stacks are only popped once all other stacks have been drained.
+        if quarantined(a) != quarantined(b) {
+            return quarantined(a).cmp(&quarantined(b)).reverse();
+        }
+
+        match (a.ready, b.ready) {
+            // Assuming that two priorities differ only w.r.t. the `last_peek`, we want to prioritize
+            // stacks that were the least recently peeked. The rationale behind this is that we want
+            // to keep cycling through different stacks while peeking.
+            (true, true) => hot(a)
+                .cmp(&hot(b))
+                .then(a.unsampled.cmp(&b.unsampled).reverse())
+                .then(a.sequence.epoch.cmp(&b.sequence.epoch))
+                .then(a.received_at.cmp(&b.received_at))
+                .then(a.memory_resident.cmp(&b.memory_resident))
+                .then(a.sequence.counter.cmp(&b.sequence.counter)),
+            (true, false) => Ordering::Greater,
+            (false, true) => Ordering::Less,
+            // For non-ready stacks, we invert the priority, such that projects that are not
+            // ready and did not receive envelopes recently can be evicted.
+            (false, false) => a
+                .next_fetch
+                .cmp(&b.next_fetch)
+                .reverse()
+                .then(a.sequence.epoch.cmp(&b.sequence.epoch).reverse())
+                .then(a.received_at.cmp(&b.received_at).reverse())
+                .then(a.sequence.counter.cmp(&b.sequence.counter).reverse()),
+        }
+    }
+}
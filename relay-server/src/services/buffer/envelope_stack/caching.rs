@@ -1,7 +1,13 @@
 use chrono::{DateTime, Utc};
+use relay_event_schema::protocol::EventId;
+use uuid::Uuid;
 
-use super::EnvelopeStack;
+use super::{is_unsampled, trace_id, EnvelopeStack};
 use crate::envelope::Envelope;
+use crate::services::buffer::common::EnvelopePreview;
+use crate::services::buffer::envelope_stack::sqlite::{
+    SqliteEnvelopeStack, SqliteEnvelopeStackError,
+};
 
 /// An envelope stack implementation that caches one element in memory and delegates
 /// to another envelope stack for additional storage.
@@ -26,6 +32,29 @@ where
     }
 }
 
+impl CachingEnvelopeStack<SqliteEnvelopeStack> {
+    /// Pops the next envelope and holds it in the store until `deadline`.
+    ///
+    /// See [`SqliteEnvelopeStack::pop_held`]. If holding the cached envelope fails, it remains
+    /// cached.
+    pub async fn pop_held(
+        &mut self,
+        deadline: DateTime<Utc>,
+    ) -> Result<Option<(Box<Envelope>, i64)>, SqliteEnvelopeStackError> {
+        let Some(envelope) = self.cached.take() else {
+            return self.inner.pop_held(deadline).await;
+        };
+
+        match self.inner.hold(&envelope, deadline).await {
+            Ok(token) => Ok(Some((envelope, token))),
+            Err(error) => {
+                self.cached = Some(envelope);
+                Err(error)
+            }
+        }
+    }
+}
+
 impl<S> EnvelopeStack for CachingEnvelopeStack<S>
 where
     S: EnvelopeStack,
@@ -57,6 +86,57 @@ where
         }
     }
 
+    async fn peek_oldest(&mut self) -> Result<Option<DateTime<Utc>>, Self::Error> {
+        match self.inner.peek_oldest().await? {
+            Some(received_at) => Ok(Some(received_at)),
+            None => Ok(self.cached.as_ref().map(|envelope| envelope.received_at())),
+        }
+    }
+
+    async fn pop_oldest(&mut self) -> Result<Option<Box<Envelope>>, Self::Error> {
+        match self.inner.pop_oldest().await? {
+            Some(envelope) => Ok(Some(envelope)),
+            None => Ok(self.cached.take()),
+        }
+    }
+
+    async fn take_all(&mut self) -> Result<Vec<Box<Envelope>>, Self::Error> {
+        let mut envelopes = self.inner.take_all().await?;
+        envelopes.extend(self.cached.take());
+        Ok(envelopes)
+    }
+
+    fn depth(&self) -> usize {
+        self.inner.depth() + usize::from(self.cached.is_some())
+    }
+
+    async fn preview(&self, event_id: EventId) -> Result<Option<EnvelopePreview>, Self::Error> {
+        match &self.cached {
+            Some(envelope) if envelope.event_id() == Some(event_id) => {
+                Ok(Some(EnvelopePreview::new(envelope)))
+            }
+            _ => self.inner.preview(event_id).await,
+        }
+    }
+
+    fn head_in_memory(&self) -> bool {
+        self.cached.is_some() || self.inner.head_in_memory()
+    }
+
+    fn head_unsampled(&self) -> bool {
+        match &self.cached {
+            Some(envelope) => is_unsampled(envelope),
+            None => self.inner.head_unsampled(),
+        }
+    }
+
+    fn head_trace_id(&self) -> Option<Uuid> {
+        match &self.cached {
+            Some(envelope) => trace_id(envelope),
+            None => self.inner.head_trace_id(),
+        }
+    }
+
     async fn flush(mut self) {
         if let Some(envelope) = self.cached {
             if self.inner.push(envelope).await.is_err() {
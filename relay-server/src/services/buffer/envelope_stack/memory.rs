@@ -1,17 +1,31 @@
+use std::collections::VecDeque;
 use std::convert::Infallible;
 
 use chrono::{DateTime, Utc};
+use relay_event_schema::protocol::EventId;
+use uuid::Uuid;
 
+use crate::services::buffer::common::EnvelopePreview;
 use crate::Envelope;
 
-use super::EnvelopeStack;
+use super::{is_unsampled, trace_id, EnvelopeStack};
 
 #[derive(Debug)]
-pub struct MemoryEnvelopeStack(#[allow(clippy::vec_box)] Vec<Box<Envelope>>);
+pub struct MemoryEnvelopeStack(VecDeque<Box<Envelope>>);
 
 impl MemoryEnvelopeStack {
     pub fn new() -> Self {
-        Self(vec![])
+        Self(VecDeque::new())
+    }
+
+    /// Pushes an envelope on top of the stack without awaiting.
+    pub fn push_back(&mut self, envelope: Box<Envelope>) {
+        self.0.push_back(envelope);
+    }
+
+    /// Removes the envelope at the bottom of the stack without awaiting.
+    pub fn pop_front(&mut self) -> Option<Box<Envelope>> {
+        self.0.pop_front()
     }
 }
 
@@ -19,16 +33,52 @@ impl EnvelopeStack for MemoryEnvelopeStack {
     type Error = Infallible;
 
     async fn push(&mut self, envelope: Box<Envelope>) -> Result<(), Self::Error> {
-        self.0.push(envelope);
+        self.push_back(envelope);
         Ok(())
     }
 
     async fn peek(&mut self) -> Result<Option<DateTime<Utc>>, Self::Error> {
-        Ok(self.0.last().map(|e| e.received_at()))
+        Ok(self.0.back().map(|e| e.received_at()))
     }
 
     async fn pop(&mut self) -> Result<Option<Box<Envelope>>, Self::Error> {
-        Ok(self.0.pop())
+        Ok(self.0.pop_back())
+    }
+
+    async fn peek_oldest(&mut self) -> Result<Option<DateTime<Utc>>, Self::Error> {
+        Ok(self.0.front().map(|e| e.received_at()))
+    }
+
+    async fn pop_oldest(&mut self) -> Result<Option<Box<Envelope>>, Self::Error> {
+        Ok(self.pop_front())
+    }
+
+    async fn take_all(&mut self) -> Result<Vec<Box<Envelope>>, Self::Error> {
+        Ok(std::mem::take(&mut self.0).into())
+    }
+
+    fn depth(&self) -> usize {
+        self.0.len()
+    }
+
+    async fn preview(&self, event_id: EventId) -> Result<Option<EnvelopePreview>, Self::Error> {
+        Ok(self
+            .0
+            .iter()
+            .find(|envelope| envelope.event_id() == Some(event_id))
+            .map(|envelope| EnvelopePreview::new(envelope)))
+    }
+
+    fn head_in_memory(&self) -> bool {
+        true
+    }
+
+    fn head_unsampled(&self) -> bool {
+        self.0.back().is_some_and(|envelope| is_unsampled(envelope))
+    }
+
+    fn head_trace_id(&self) -> Option<Uuid> {
+        self.0.back().and_then(|envelope| trace_id(envelope))
     }
 
     async fn flush(self) {}
@@ -1,8 +1,11 @@
 use std::future::Future;
 
 use chrono::{DateTime, Utc};
+use relay_event_schema::protocol::EventId;
+use uuid::Uuid;
 
 use crate::envelope::Envelope;
+use crate::services::buffer::common::EnvelopePreview;
 
 pub mod caching;
 pub mod memory;
@@ -23,7 +26,57 @@ pub trait EnvelopeStack: Send + std::fmt::Debug {
     /// Pops the [`Envelope`] on top of the stack.
     fn pop(&mut self) -> impl Future<Output = Result<Option<Box<Envelope>>, Self::Error>>;
 
+    /// Peeks the oldest [`Envelope`] at the bottom of the stack.
+    fn peek_oldest(&mut self) -> impl Future<Output = Result<Option<DateTime<Utc>>, Self::Error>>;
+
+    /// Pops the oldest [`Envelope`] at the bottom of the stack.
+    fn pop_oldest(&mut self) -> impl Future<Output = Result<Option<Box<Envelope>>, Self::Error>>;
+
+    /// Removes and returns all [`Envelope`]s of the stack, from the bottom to the top.
+    fn take_all(&mut self) -> impl Future<Output = Result<Vec<Box<Envelope>>, Self::Error>>;
+
+    /// Returns the number of [`Envelope`]s in the stack.
+    ///
+    /// Stacks backed by external storage also count envelopes that were stored before the stack
+    /// was created, but only once the stack has been pushed to or popped from. Until then, the
+    /// returned value might be lower than the actual depth.
+    fn depth(&self) -> usize;
+
+    /// Returns a preview of the [`Envelope`] with the given event id, if it is in the stack.
+    ///
+    /// Stacks backed by external storage also read the envelopes stored there.
+    fn preview(
+        &self,
+        event_id: EventId,
+    ) -> impl Future<Output = Result<Option<EnvelopePreview>, Self::Error>>;
+
+    /// Returns `true` if the [`Envelope`] on top of the stack is held in memory.
+    ///
+    /// Popping from a stack whose top lives in external storage requires reading from it first.
+    fn head_in_memory(&self) -> bool;
+
+    /// Returns `true` if the [`Envelope`] on top of the stack belongs to an unsampled trace.
+    ///
+    /// Stacks backed by external storage return `false` if they would have to decode the envelope.
+    fn head_unsampled(&self) -> bool;
+
+    /// Returns the trace id of the [`Envelope`] on top of the stack, if it has a dynamic sampling
+    /// context.
+    ///
+    /// Stacks backed by external storage return `None` if they would have to decode the envelope.
+    fn head_trace_id(&self) -> Option<Uuid>;
+
     /// Persists all envelopes in the [`EnvelopeStack`]s to external storage, if possible,
     /// and consumes the stack provider.
     fn flush(self) -> impl Future<Output = ()>;
 }
+
+/// Returns `true` if the dynamic sampling context of the envelope marks its trace as unsampled.
+pub fn is_unsampled(envelope: &Envelope) -> bool {
+    envelope.dsc().and_then(|dsc| dsc.sampled) == Some(false)
+}
+
+/// Returns the trace id of the dynamic sampling context of the envelope.
+pub fn trace_id(envelope: &Envelope) -> Option<Uuid> {
+    envelope.dsc().map(|dsc| *dsc.trace_id)
+}
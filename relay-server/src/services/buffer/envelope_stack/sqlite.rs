@@ -1,16 +1,23 @@
+use std::collections::VecDeque;
 use std::fmt::Debug;
 use std::num::NonZeroUsize;
 
 use chrono::{DateTime, Utc};
 use relay_base_schema::project::ProjectKey;
+use relay_config::EnvelopeSpoolBusyFallback;
+use relay_event_schema::protocol::EventId;
+use uuid::Uuid;
 
 use crate::envelope::Envelope;
+use crate::services::buffer::common::EnvelopePreview;
 use crate::services::buffer::envelope_stack::EnvelopeStack;
+use crate::services::buffer::envelope_store::codec::EnvelopeCodec;
 use crate::services::buffer::envelope_store::sqlite::{
     DatabaseBatch, DatabaseEnvelope, InsertEnvelopeError, SqliteEnvelopeStore,
     SqliteEnvelopeStoreError,
 };
 use crate::statsd::{RelayCounters, RelayTimers};
+use crate::utils::OverflowReservation;
 
 /// An error returned when doing an operation on [`SqliteEnvelopeStack`].
 #[derive(Debug, thiserror::Error)]
@@ -35,11 +42,20 @@ pub struct SqliteEnvelopeStack {
     own_key: ProjectKey,
     /// The project key of the root project of the trace to which all the envelopes belong.
     sampling_key: ProjectKey,
+    /// The codec used to encode envelopes that are pushed onto this stack.
+    codec: &'static dyn EnvelopeCodec,
     /// In-memory stack containing a batch of envelopes that either have not been written to disk yet, or have been read from disk recently.
-    batch: Vec<DatabaseEnvelope>,
+    batch: VecDeque<DatabaseEnvelope>,
     /// Boolean representing whether calls to `push()` and `peek()` check disk in case not enough
     /// elements are available in the `batches_buffer`.
     check_disk: bool,
+    /// Number of envelopes in this stack, both on disk and in the `batch`.
+    depth: usize,
+    /// Whether `depth` still has to be initialized with the envelopes on disk, see
+    /// [`Self::seed_depth`].
+    seed_depth: bool,
+    /// Memory reserved for the `batch` while it is kept in memory because the database is locked.
+    overflow: Option<OverflowReservation>,
     /// The tag value of this partition which is used for reporting purposes.
     partition_tag: String,
 }
@@ -52,6 +68,7 @@ impl SqliteEnvelopeStack {
         batch_size_bytes: usize,
         own_key: ProjectKey,
         sampling_key: ProjectKey,
+        codec: &'static dyn EnvelopeCodec,
         check_disk: bool,
     ) -> Self {
         Self {
@@ -60,8 +77,12 @@ impl SqliteEnvelopeStack {
                 .expect("batch bytes should be > 0"),
             own_key,
             sampling_key,
-            batch: vec![],
+            codec,
+            batch: VecDeque::new(),
             check_disk,
+            depth: 0,
+            seed_depth: check_disk,
+            overflow: None,
             partition_tag: partition_id.to_string(),
         }
     }
@@ -75,9 +96,14 @@ impl SqliteEnvelopeStack {
     ///
     /// In case there is a failure while writing envelopes, all the envelopes that were enqueued
     /// to be written to disk are lost. The explanation for this behavior can be found in the body
-    /// of the method.
+    /// of the method. The exception is a database that stays locked with the `memory` busy
+    /// fallback, in which case the envelopes remain in the `batch` and are spooled with a later
+    /// push. This requires memory to be reserved for the batch, otherwise the envelopes are lost
+    /// like with the `reject` fallback.
     async fn spool_to_disk(&mut self) -> Result<(), SqliteEnvelopeStackError> {
-        let batch = std::mem::take(&mut self.batch);
+        let batch = Vec::from(std::mem::take(&mut self.batch));
+        // The batch is no longer held in memory once it is written or dropped.
+        self.overflow = None;
         let Ok(batch) = DatabaseBatch::try_from(batch) else {
             return Ok(());
         };
@@ -87,21 +113,44 @@ impl SqliteEnvelopeStack {
             partition_id = &self.partition_tag
         );
 
+        // A locked database does not indicate corruption, so the batch can be kept in memory
+        // without risking an infinite cycle.
+        let overflow = match self.envelope_store.busy_fallback() {
+            EnvelopeSpoolBusyFallback::Reject => None,
+            EnvelopeSpoolBusyFallback::Memory => Some(batch.clone()),
+        };
+
         // When early return here, we are acknowledging that the elements that we popped from
         // the buffer are lost in case of failure. We are doing this on purposes, since if we were
         // to have a database corruption during runtime, and we were to put the values back into
         // the buffer we will end up with an infinite cycle.
-        relay_statsd::metric!(
+        let result = relay_statsd::metric!(
             timer(RelayTimers::BufferSpool),
             partition_id = &self.partition_tag,
-            {
-                self.envelope_store
-                    .insert_batch(batch)
-                    .await
-                    .map_err(SqliteEnvelopeStackError::EnvelopeStoreError)?;
-            }
+            { self.envelope_store.insert_batch(batch).await }
         );
 
+        match (result, overflow) {
+            (Ok(()), _) => {}
+            (Err(error), Some(batch)) if error.is_busy() => {
+                let batch: Vec<DatabaseEnvelope> = batch.into();
+                let bytes = batch.iter().map(|e| e.len()).sum();
+                let Some(reservation) = self.envelope_store.reserve_overflow(bytes) else {
+                    relay_log::warn!("memory overflow limit reached, dropping envelopes");
+                    return Err(SqliteEnvelopeStackError::EnvelopeStoreError(error));
+                };
+
+                relay_statsd::metric!(
+                    counter(RelayCounters::BufferSqliteBusyOverflow) += batch.len() as u64,
+                    partition_id = &self.partition_tag
+                );
+                self.batch = batch.into();
+                self.overflow = Some(reservation);
+                return Ok(());
+            }
+            (Err(error), _) => return Err(SqliteEnvelopeStackError::EnvelopeStoreError(error)),
+        }
+
         // If we successfully spooled to disk, we know that data should be there.
         self.check_disk = true;
 
@@ -110,7 +159,8 @@ impl SqliteEnvelopeStack {
 
     /// Unspools from disk a batch of envelopes and appends them to the `batch`.
     ///
-    /// In case there is a failure while deleting envelopes, the envelopes will be lost.
+    /// In case there is a failure while reading envelopes, they remain on disk and are read again
+    /// by the next call.
     async fn unspool_from_disk(&mut self) -> Result<(), SqliteEnvelopeStackError> {
         debug_assert!(self.batch.is_empty());
         let batch = relay_statsd::metric!(
@@ -126,7 +176,7 @@ impl SqliteEnvelopeStack {
 
         match batch {
             Some(batch) => {
-                self.batch = batch.into();
+                self.batch = Vec::from(batch).into();
             }
             None => self.check_disk = false,
         }
@@ -138,6 +188,107 @@ impl SqliteEnvelopeStack {
         Ok(())
     }
 
+    /// Initializes the depth of a stack with the envelopes that are already on disk.
+    ///
+    /// Stacks that are loaded on startup start out with envelopes on disk that were not pushed
+    /// through this instance. If counting them fails, the depth is seeded by a later call.
+    async fn seed_depth(&mut self) {
+        if !self.seed_depth {
+            return;
+        }
+
+        match self
+            .envelope_store
+            .count(self.own_key, self.sampling_key)
+            .await
+        {
+            Ok(count) => {
+                // Envelopes that were pushed before the depth could be seeded are either on disk
+                // and included in the count, or still in the batch.
+                self.depth = count as usize + self.batch.len();
+                self.seed_depth = false;
+            }
+            Err(error) => relay_log::error!(
+                error = &error as &dyn std::error::Error,
+                "failed to count the envelopes of a stack on disk"
+            ),
+        }
+    }
+
+    /// Pops the newest envelope and holds it in the store until `deadline`.
+    ///
+    /// Works like [`EnvelopeStack::pop`], but the envelope is moved to the in-flight table of the
+    /// store and returned along with its token. An envelope read from disk is moved in the same
+    /// transaction that unspools its batch, see
+    /// [`SqliteEnvelopeStore::delete_batch_and_hold_newest`]. If holding an envelope of the
+    /// in-memory batch fails, it remains on the stack.
+    pub async fn pop_held(
+        &mut self,
+        deadline: DateTime<Utc>,
+    ) -> Result<Option<(Box<Envelope>, i64)>, SqliteEnvelopeStackError> {
+        self.seed_depth().await;
+
+        let (envelope, token) = match self.batch.pop_back() {
+            Some(envelope) => match self
+                .envelope_store
+                .insert_inflight(&envelope, deadline)
+                .await
+            {
+                Ok(token) => (envelope, token),
+                Err(error) => {
+                    self.batch.push_back(envelope);
+                    return Err(error.into());
+                }
+            },
+            None if self.check_disk => {
+                let held = relay_statsd::metric!(
+                    timer(RelayTimers::BufferUnspool),
+                    partition_id = &self.partition_tag,
+                    {
+                        self.envelope_store
+                            .delete_batch_and_hold_newest(self.own_key, self.sampling_key, deadline)
+                            .await?
+                    }
+                );
+                let Some(held) = held else {
+                    self.check_disk = false;
+                    return Ok(None);
+                };
+
+                relay_statsd::metric!(
+                    counter(RelayCounters::BufferUnspooledEnvelopes) +=
+                        held.envelopes.len() as u64 + 1,
+                    partition_id = &self.partition_tag
+                );
+                self.batch = held.envelopes.into();
+                (held.held, held.token)
+            }
+            None => return Ok(None),
+        };
+
+        self.depth = self.depth.saturating_sub(1);
+        if self.batch.is_empty() {
+            self.overflow = None;
+        }
+
+        Ok(Some((envelope.try_into()?, token)))
+    }
+
+    /// Holds an envelope that is not part of this stack in the store until `deadline`.
+    ///
+    /// Returns the token of the held envelope, see [`Self::pop_held`].
+    pub async fn hold(
+        &mut self,
+        envelope: &Envelope,
+        deadline: DateTime<Utc>,
+    ) -> Result<i64, SqliteEnvelopeStackError> {
+        let encoded = DatabaseEnvelope::encode(envelope, self.codec)?;
+        Ok(self
+            .envelope_store
+            .insert_inflight(&encoded, deadline)
+            .await?)
+    }
+
     /// Validates that the incoming [`Envelope`] has the same project keys at the
     /// [`SqliteEnvelopeStack`].
     fn validate_envelope(&self, envelope: &Envelope) -> bool {
@@ -153,6 +304,7 @@ impl EnvelopeStack for SqliteEnvelopeStack {
 
     async fn push(&mut self, envelope: Box<Envelope>) -> Result<(), Self::Error> {
         debug_assert!(self.validate_envelope(&envelope));
+        self.seed_depth().await;
 
         if self.above_spool_threshold() {
             self.spool_to_disk().await?;
@@ -161,9 +313,10 @@ impl EnvelopeStack for SqliteEnvelopeStack {
         let encoded_envelope = relay_statsd::metric!(
             timer(RelayTimers::BufferEnvelopesSerialization),
             partition_id = &self.partition_tag,
-            { DatabaseEnvelope::try_from(envelope.as_ref())? }
+            { DatabaseEnvelope::encode(envelope.as_ref(), self.codec)? }
         );
-        self.batch.push(encoded_envelope);
+        self.batch.push_back(encoded_envelope);
+        self.depth += 1;
 
         Ok(())
     }
@@ -173,7 +326,7 @@ impl EnvelopeStack for SqliteEnvelopeStack {
             self.unspool_from_disk().await?
         }
 
-        let Some(envelope) = self.batch.last() else {
+        let Some(envelope) = self.batch.back() else {
             return Ok(None);
         };
 
@@ -181,18 +334,121 @@ impl EnvelopeStack for SqliteEnvelopeStack {
     }
 
     async fn pop(&mut self) -> Result<Option<Box<Envelope>>, Self::Error> {
+        self.seed_depth().await;
         if self.batch.is_empty() && self.check_disk {
             self.unspool_from_disk().await?
         }
 
-        let Some(envelope) = self.batch.pop() else {
+        let Some(envelope) = self.batch.pop_back() else {
             return Ok(None);
         };
+        self.depth = self.depth.saturating_sub(1);
+        if self.batch.is_empty() {
+            self.overflow = None;
+        }
         let envelope = envelope.try_into()?;
 
         Ok(Some(envelope))
     }
 
+    async fn peek_oldest(&mut self) -> Result<Option<DateTime<Utc>>, Self::Error> {
+        // Envelopes on disk are always older than the ones in the in-memory batch.
+        if self.check_disk {
+            let received_at = self
+                .envelope_store
+                .oldest_received_at(self.own_key, self.sampling_key)
+                .await?;
+            if received_at.is_some() {
+                return Ok(received_at);
+            }
+        }
+
+        Ok(self.batch.front().map(|envelope| envelope.received_at()))
+    }
+
+    async fn pop_oldest(&mut self) -> Result<Option<Box<Envelope>>, Self::Error> {
+        self.seed_depth().await;
+        // Envelopes on disk are always older than the ones in the in-memory batch.
+        let mut envelope = None;
+        if self.check_disk {
+            envelope = self
+                .envelope_store
+                .delete_oldest(self.own_key, self.sampling_key)
+                .await?;
+            self.check_disk = envelope.is_some();
+        }
+
+        let Some(envelope) = envelope.or_else(|| self.batch.pop_front()) else {
+            return Ok(None);
+        };
+        self.depth = self.depth.saturating_sub(1);
+        if self.batch.is_empty() {
+            self.overflow = None;
+        }
+
+        Ok(Some(envelope.try_into()?))
+    }
+
+    async fn take_all(&mut self) -> Result<Vec<Box<Envelope>>, Self::Error> {
+        // Envelopes on disk are always older than the ones in the in-memory batch.
+        let mut envelopes = Vec::new();
+        if self.check_disk {
+            envelopes = self
+                .envelope_store
+                .delete_all(self.own_key, self.sampling_key)
+                .await?;
+            self.check_disk = false;
+        }
+        envelopes.extend(self.batch.drain(..));
+        self.depth = 0;
+        self.seed_depth = false;
+        self.overflow = None;
+
+        envelopes
+            .into_iter()
+            .map(|envelope| Ok(envelope.try_into()?))
+            .collect()
+    }
+
+    fn depth(&self) -> usize {
+        self.depth
+    }
+
+    async fn preview(&self, event_id: EventId) -> Result<Option<EnvelopePreview>, Self::Error> {
+        let find = |envelope: &DatabaseEnvelope| {
+            Box::<Envelope>::try_from(envelope.clone())
+                .ok()
+                .filter(|envelope| envelope.event_id() == Some(event_id))
+                .map(|envelope| EnvelopePreview::new(&envelope))
+        };
+
+        if let Some(preview) = self.batch.iter().find_map(find) {
+            return Ok(Some(preview));
+        }
+        if !self.check_disk {
+            return Ok(None);
+        }
+
+        Ok(self
+            .envelope_store
+            .find(self.own_key, self.sampling_key, find)
+            .await?)
+    }
+
+    fn head_in_memory(&self) -> bool {
+        !self.batch.is_empty()
+    }
+
+    fn head_unsampled(&self) -> bool {
+        // Envelopes in the batch are encoded, decoding them here would be too expensive.
+        false
+    }
+
+    fn head_trace_id(&self) -> Option<Uuid> {
+        // See `head_unsampled`.
+        None
+    }
+
     async fn flush(mut self) {
         if let Err(e) = self.spool_to_disk().await {
             relay_log::error!(error = &e as &dyn std::error::Error, "flush error");
@@ -207,6 +463,7 @@ mod tests {
     use std::time::Duration;
 
     use super::*;
+    use crate::services::buffer::envelope_store::codec::DefaultCodec;
     use crate::services::buffer::testutils::utils::{mock_envelope, mock_envelopes, setup_db};
 
     /// Helper function to calculate the total size of a slice of envelopes after compression
@@ -228,6 +485,7 @@ mod tests {
             10,
             ProjectKey::parse("a94ae32be2584e0bbd7a4cbb95971fee").unwrap(),
             ProjectKey::parse("c25ae32be2584e0bbd7a4cbb95971fe1").unwrap(),
+            &DefaultCodec,
             true,
         );
 
@@ -252,6 +510,7 @@ mod tests {
             threshold_size,
             ProjectKey::parse("a94ae32be2584e0bbd7a4cbb95971fee").unwrap(),
             ProjectKey::parse("b81ae32be2584e0bbd7a4cbb95971fe1").unwrap(),
+            &DefaultCodec,
             true,
         );
 
@@ -293,6 +552,7 @@ mod tests {
             2,
             ProjectKey::parse("a94ae32be2584e0bbd7a4cbb95971fee").unwrap(),
             ProjectKey::parse("b81ae32be2584e0bbd7a4cbb95971fe1").unwrap(),
+            &DefaultCodec,
             true,
         );
 
@@ -313,6 +573,7 @@ mod tests {
             2,
             ProjectKey::parse("a94ae32be2584e0bbd7a4cbb95971fee").unwrap(),
             ProjectKey::parse("b81ae32be2584e0bbd7a4cbb95971fe1").unwrap(),
+            &DefaultCodec,
             true,
         );
 
@@ -331,6 +592,7 @@ mod tests {
             9999,
             ProjectKey::parse("a94ae32be2584e0bbd7a4cbb95971fee").unwrap(),
             ProjectKey::parse("b81ae32be2584e0bbd7a4cbb95971fe1").unwrap(),
+            &DefaultCodec,
             true,
         );
 
@@ -377,6 +639,7 @@ mod tests {
             threshold_size,
             ProjectKey::parse("a94ae32be2584e0bbd7a4cbb95971fee").unwrap(),
             ProjectKey::parse("b81ae32be2584e0bbd7a4cbb95971fe1").unwrap(),
+            &DefaultCodec,
             true,
         );
 
@@ -435,6 +698,124 @@ mod tests {
         assert_eq!(stack.batch.len(), 0);
     }
 
+    #[tokio::test]
+    async fn test_pop_oldest() {
+        let db = setup_db(true).await;
+        let envelope_store = SqliteEnvelopeStore::new(0, db, Duration::from_millis(100));
+
+        let envelopes = mock_envelopes(7);
+        let threshold_size = calculate_compressed_size(&envelopes[..5]) - 1;
+
+        let mut stack = SqliteEnvelopeStack::new(
+            0,
+            envelope_store.clone(),
+            threshold_size,
+            ProjectKey::parse("a94ae32be2584e0bbd7a4cbb95971fee").unwrap(),
+            ProjectKey::parse("b81ae32be2584e0bbd7a4cbb95971fe1").unwrap(),
+            &DefaultCodec,
+            true,
+        );
+
+        // We push 7 envelopes, the first 5 are spooled to disk in a single row.
+        for envelope in envelopes.clone() {
+            assert!(stack.push(envelope).await.is_ok());
+        }
+        assert_eq!(stack.batch.len(), 2);
+        assert_eq!(stack.depth(), 7);
+
+        // We pop the oldest envelopes, first from disk and then from memory.
+        for (i, envelope) in envelopes.iter().enumerate() {
+            let popped_envelope = stack.pop_oldest().await.unwrap().unwrap();
+            assert_eq!(
+                popped_envelope.event_id().unwrap(),
+                envelope.event_id().unwrap()
+            );
+            assert_eq!(stack.depth(), 6 - i);
+        }
+
+        assert!(stack.pop_oldest().await.unwrap().is_none());
+        assert_eq!(envelope_store.total_count().await.unwrap(), 0);
+        assert_eq!(stack.batch.len(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_depth_includes_envelopes_on_disk() {
+        let db = setup_db(true).await;
+        let envelope_store = SqliteEnvelopeStore::new(0, db, Duration::from_millis(100));
+        let own_key = ProjectKey::parse("a94ae32be2584e0bbd7a4cbb95971fee").unwrap();
+        let sampling_key = ProjectKey::parse("b81ae32be2584e0bbd7a4cbb95971fe1").unwrap();
+
+        let envelopes = mock_envelopes(7);
+        let threshold_size = calculate_compressed_size(&envelopes[..5]) - 1;
+
+        let mut stack = SqliteEnvelopeStack::new(
+            0,
+            envelope_store.clone(),
+            threshold_size,
+            own_key,
+            sampling_key,
+            &DefaultCodec,
+            true,
+        );
+        for envelope in envelopes {
+            assert!(stack.push(envelope).await.is_ok());
+        }
+        stack.flush().await;
+        assert_eq!(envelope_store.total_count().await.unwrap(), 7);
+
+        // A stack loaded on startup counts the envelopes that are already on disk.
+        let mut stack = SqliteEnvelopeStack::new(
+            0,
+            envelope_store.clone(),
+            threshold_size,
+            own_key,
+            sampling_key,
+            &DefaultCodec,
+            true,
+        );
+        assert!(stack.push(mock_envelope(Utc::now())).await.is_ok());
+        assert_eq!(stack.depth(), 8);
+
+        assert!(stack.pop_oldest().await.unwrap().is_some());
+        assert_eq!(stack.depth(), 7);
+        assert_eq!(envelope_store.total_count().await.unwrap(), 6);
+    }
+
+    #[tokio::test]
+    async fn test_take_all() {
+        let db = setup_db(true).await;
+        let envelope_store = SqliteEnvelopeStore::new(0, db, Duration::from_millis(100));
+
+        let envelopes = mock_envelopes(7);
+        let threshold_size = calculate_compressed_size(&envelopes[..5]) - 1;
+
+        let mut stack = SqliteEnvelopeStack::new(
+            0,
+            envelope_store.clone(),
+            threshold_size,
+            ProjectKey::parse("a94ae32be2584e0bbd7a4cbb95971fee").unwrap(),
+            ProjectKey::parse("b81ae32be2584e0bbd7a4cbb95971fe1").unwrap(),
+            &DefaultCodec,
+            true,
+        );
+
+        // We push 7 envelopes, the first 5 are spooled to disk in a single row.
+        for envelope in envelopes.clone() {
+            assert!(stack.push(envelope).await.is_ok());
+        }
+        assert_eq!(stack.batch.len(), 2);
+
+        // All envelopes are returned from the bottom to the top of the stack.
+        let taken = stack.take_all().await.unwrap();
+        let taken_ids: Vec<_> = taken.iter().map(|e| e.event_id().unwrap()).collect();
+        let expected_ids: Vec<_> = envelopes.iter().map(|e| e.event_id().unwrap()).collect();
+        assert_eq!(taken_ids, expected_ids);
+
+        assert_eq!(stack.depth(), 0);
+        assert!(stack.pop().await.unwrap().is_none());
+        assert_eq!(envelope_store.total_count().await.unwrap(), 0);
+    }
+
     #[tokio::test]
     async fn test_drain() {
         let db = setup_db(true).await;
@@ -445,6 +826,7 @@ mod tests {
             10 * COMPRESSED_ENVELOPE_SIZE,
             ProjectKey::parse("a94ae32be2584e0bbd7a4cbb95971fee").unwrap(),
             ProjectKey::parse("b81ae32be2584e0bbd7a4cbb95971fe1").unwrap(),
+            &DefaultCodec,
             true,
         );
 
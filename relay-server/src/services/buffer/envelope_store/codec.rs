@@ -0,0 +1,219 @@
+use std::fmt::Debug;
+
+use bytes::Bytes;
+use relay_config::EnvelopeSpoolCodec;
+use serde::{Deserialize, Serialize};
+
+use crate::envelope::{Envelope, EnvelopeHeaders};
+use crate::services::buffer::envelope_store::sqlite::InsertEnvelopeError;
+use crate::statsd::{RelayHistograms, RelayTimers};
+
+/// Fixed first 4 bytes for zstd compressed envelopes.
+///
+/// Used for backward compatibility to check whether an envelope on disk is zstd-encoded.
+const ZSTD_MAGIC_WORD: &[u8] = &[40, 181, 47, 253];
+
+// Use the lowest level of compression.
+//
+// Experiments showed that level 3 is significantly slower than level 1 while offering
+// no significant size reduction for our use case.
+const COMPRESSION_LEVEL: i32 = 1;
+
+/// Identifier of an [`EnvelopeCodec`] which is persisted alongside every row on disk.
+///
+/// The identifiers must never be reused, since they are used to decode envelopes that were
+/// written by previous versions of Relay.
+pub type CodecId = u8;
+
+/// An encoding for [`Envelope`]s that are written to the on-disk buffer.
+pub trait EnvelopeCodec: Debug + Send + Sync {
+    /// Returns the stable identifier of this codec.
+    fn id(&self) -> CodecId;
+
+    /// Encodes an [`Envelope`] into bytes.
+    fn encode(&self, envelope: &Envelope) -> Result<Box<[u8]>, InsertEnvelopeError>;
+
+    /// Decodes an [`Envelope`] from bytes that were produced by [`Self::encode`].
+    fn decode(&self, data: Box<[u8]>) -> Result<Box<Envelope>, InsertEnvelopeError>;
+}
+
+/// Codec that stores the envelope wire format compressed with zstd.
+#[derive(Debug)]
+pub struct DefaultCodec;
+
+impl DefaultCodec {
+    const ID: CodecId = 0;
+}
+
+impl EnvelopeCodec for DefaultCodec {
+    fn id(&self) -> CodecId {
+        Self::ID
+    }
+
+    fn encode(&self, envelope: &Envelope) -> Result<Box<[u8]>, InsertEnvelopeError> {
+        let serialized_envelope = envelope.to_vec()?;
+        relay_statsd::metric!(
+            histogram(RelayHistograms::BufferEnvelopeSize) = serialized_envelope.len() as u64
+        );
+
+        let encoded_envelope =
+            relay_statsd::metric!(timer(RelayTimers::BufferEnvelopeCompression), {
+                zstd::encode_all(serialized_envelope.as_slice(), COMPRESSION_LEVEL)?
+            });
+        relay_statsd::metric!(
+            histogram(RelayHistograms::BufferEnvelopeSizeCompressed) =
+                encoded_envelope.len() as u64
+        );
+
+        Ok(encoded_envelope.into_boxed_slice())
+    }
+
+    fn decode(&self, mut data: Box<[u8]>) -> Result<Box<Envelope>, InsertEnvelopeError> {
+        if data.starts_with(ZSTD_MAGIC_WORD) {
+            relay_statsd::metric!(timer(RelayTimers::BufferEnvelopeDecompression), {
+                data = zstd::decode_all(&*data)?.into_boxed_slice();
+            });
+        }
+
+        Ok(Envelope::parse_bytes(Bytes::from(data))?)
+    }
+}
+
+/// Envelope representation written by the [`MsgpackCodec`].
+#[derive(Serialize)]
+struct MsgpackEnvelopeRef<'a> {
+    headers: &'a EnvelopeHeaders,
+    #[serde(with = "serde_bytes")]
+    items: &'a [u8],
+}
+
+/// Envelope representation read by the [`MsgpackCodec`].
+#[derive(Deserialize)]
+struct MsgpackEnvelope {
+    headers: EnvelopeHeaders,
+    #[serde(with = "serde_bytes")]
+    items: Vec<u8>,
+}
+
+/// Codec that stores the envelope headers as MessagePack followed by the raw items.
+///
+/// The result is compressed with zstd, just like in the [`DefaultCodec`].
+#[derive(Debug)]
+pub struct MsgpackCodec;
+
+impl MsgpackCodec {
+    const ID: CodecId = 1;
+}
+
+impl EnvelopeCodec for MsgpackCodec {
+    fn id(&self) -> CodecId {
+        Self::ID
+    }
+
+    fn encode(&self, envelope: &Envelope) -> Result<Box<[u8]>, InsertEnvelopeError> {
+        let serialized_envelope = envelope.to_vec()?;
+        relay_statsd::metric!(
+            histogram(RelayHistograms::BufferEnvelopeSize) = serialized_envelope.len() as u64
+        );
+
+        // The envelope headers are always serialized as a single line of JSON, so everything
+        // after the first newline are the items.
+        let items_offset = serialized_envelope
+            .iter()
+            .position(|b| *b == b'\n')
+            .map_or(serialized_envelope.len(), |position| position + 1);
+
+        let packed = rmp_serde::to_vec_named(&MsgpackEnvelopeRef {
+            headers: envelope.headers(),
+            items: &serialized_envelope[items_offset..],
+        })?;
+
+        let encoded_envelope =
+            relay_statsd::metric!(timer(RelayTimers::BufferEnvelopeCompression), {
+                zstd::encode_all(packed.as_slice(), COMPRESSION_LEVEL)?
+            });
+        relay_statsd::metric!(
+            histogram(RelayHistograms::BufferEnvelopeSizeCompressed) =
+                encoded_envelope.len() as u64
+        );
+
+        Ok(encoded_envelope.into_boxed_slice())
+    }
+
+    fn decode(&self, data: Box<[u8]>) -> Result<Box<Envelope>, InsertEnvelopeError> {
+        let packed = relay_statsd::metric!(timer(RelayTimers::BufferEnvelopeDecompression), {
+            zstd::decode_all(&*data)?
+        });
+
+        let MsgpackEnvelope { headers, items } = rmp_serde::from_slice(&packed)?;
+        let items = Envelope::parse_items_bytes(Bytes::from(items))?;
+
+        Ok(Envelope::from_parts(headers, items))
+    }
+}
+
+/// Returns the [`EnvelopeCodec`] selected by the configuration.
+pub fn codec_from_config(codec: EnvelopeSpoolCodec) -> &'static dyn EnvelopeCodec {
+    match codec {
+        EnvelopeSpoolCodec::Default => &DefaultCodec,
+        EnvelopeSpoolCodec::Msgpack => &MsgpackCodec,
+    }
+}
+
+/// Returns the [`EnvelopeCodec`] with the given identifier, if it is known.
+pub fn codec_by_id(id: CodecId) -> Option<&'static dyn EnvelopeCodec> {
+    match id {
+        DefaultCodec::ID => Some(&DefaultCodec),
+        MsgpackCodec::ID => Some(&MsgpackCodec),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Utc;
+
+    use super::*;
+    use crate::services::buffer::testutils::utils::mock_envelope;
+
+    fn assert_round_trip(codec: &dyn EnvelopeCodec) {
+        let envelope = mock_envelope(Utc::now());
+
+        let encoded = codec.encode(&envelope).unwrap();
+        let decoded = codec.decode(encoded).unwrap();
+
+        assert_eq!(decoded.event_id(), envelope.event_id());
+        assert_eq!(decoded.meta().public_key(), envelope.meta().public_key());
+        assert_eq!(decoded.sampling_key(), envelope.sampling_key());
+        assert_eq!(decoded.len(), envelope.len());
+        assert_eq!(decoded.to_vec().unwrap(), envelope.to_vec().unwrap());
+    }
+
+    #[test]
+    fn test_default_codec_round_trip() {
+        assert_round_trip(&DefaultCodec);
+    }
+
+    #[test]
+    fn test_msgpack_codec_round_trip() {
+        assert_round_trip(&MsgpackCodec);
+    }
+
+    #[test]
+    fn test_default_codec_decodes_uncompressed() {
+        let envelope = mock_envelope(Utc::now());
+        let serialized = envelope.to_vec().unwrap().into_boxed_slice();
+
+        let decoded = DefaultCodec.decode(serialized).unwrap();
+        assert_eq!(decoded.event_id(), envelope.event_id());
+    }
+
+    #[test]
+    fn test_codec_by_id() {
+        for codec in [EnvelopeSpoolCodec::Default, EnvelopeSpoolCodec::Msgpack] {
+            let codec = codec_from_config(codec);
+            assert_eq!(codec_by_id(codec.id()).unwrap().id(), codec.id());
+        }
+        assert!(codec_by_id(u8::MAX).is_none());
+    }
+}
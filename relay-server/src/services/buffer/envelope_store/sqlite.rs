@@ -1,20 +1,26 @@
+use std::collections::VecDeque;
+use std::future::Future;
 use std::io::{ErrorKind, Read};
 use std::path::Path;
 use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 use crate::envelope::EnvelopeError;
 
 use crate::services::buffer::common::ProjectKeyPair;
-use crate::statsd::{RelayGauges, RelayHistograms, RelayTimers};
+use crate::services::buffer::envelope_store::codec::{
+    codec_by_id, CodecId, DefaultCodec, EnvelopeCodec,
+};
+use crate::statsd::{RelayCounters, RelayGauges, RelayTimers};
+use crate::utils::{MemoryChecker, OverflowReservation};
 use crate::Envelope;
-use bytes::{Buf, Bytes};
+use bytes::Buf;
 use chrono::{DateTime, Utc};
-use futures::stream::StreamExt;
+use futures::TryStreamExt;
 use hashbrown::HashSet;
 use relay_base_schema::project::{ParseProjectKeyError, ProjectKey};
-use relay_config::Config;
+use relay_config::{Config, EnvelopeSpoolBusyFallback};
 use serde::{Deserialize, Serialize};
 use sqlx::migrate::MigrateError;
 use sqlx::query::Query;
@@ -26,10 +32,13 @@ use sqlx::{Pool, Row, Sqlite};
 use tokio::fs::DirBuilder;
 use tokio::time::sleep;
 
-/// Fixed first 4 bytes for zstd compressed envelopes.
+/// Time without operations on the database after which maintenance may run.
+const MAINTENANCE_IDLE_PERIOD: Duration = Duration::from_secs(1);
+
+/// Maximum number of free pages that a single maintenance step returns to the file system.
 ///
-/// Used for backward compatibility to check whether an envelope on disk is zstd-encoded.
-const ZSTD_MAGIC_WORD: &[u8] = &[40, 181, 47, 253];
+/// Every step is a separate statement, so operations of the buffer only wait for a single step.
+const MAINTENANCE_STEP_PAGES: u32 = 128;
 
 /// Struct that contains all the fields of an [`Envelope`] that are mapped to the database columns.
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -37,6 +46,7 @@ pub struct DatabaseEnvelope {
     received_at: i64,
     own_key: ProjectKey,
     sampling_key: ProjectKey,
+    codec: CodecId,
     encoded_envelope: Box<[u8]>,
 }
 
@@ -76,20 +86,50 @@ impl From<DatabaseBatch> for Vec<DatabaseEnvelope> {
     }
 }
 
+/// A batch read from disk whose newest envelope was moved to the in-flight table.
+///
+/// Returned by [`SqliteEnvelopeStore::delete_batch_and_hold_newest`].
+#[derive(Debug)]
+pub struct HeldBatch {
+    /// The remaining envelopes of the batch, from oldest to newest.
+    pub envelopes: Vec<DatabaseEnvelope>,
+    /// The newest envelope of the batch, which is held in the in-flight table.
+    pub held: DatabaseEnvelope,
+    /// The token of the held envelope, see [`SqliteEnvelopeStore::insert_inflight`].
+    pub token: i64,
+}
+
 #[derive(Debug, thiserror::Error)]
 pub enum InsertEnvelopeError {
     #[error("envelope conversion error: {0}")]
     Envelope(#[from] EnvelopeError),
     #[error("compression error: {0}")]
     Zstd(#[from] std::io::Error),
+    #[error("msgpack encoding error: {0}")]
+    MsgpackEncode(#[from] rmp_serde::encode::Error),
+    #[error("msgpack decoding error: {0}")]
+    MsgpackDecode(#[from] rmp_serde::decode::Error),
+    #[error("unknown envelope codec: {0}")]
+    UnknownCodec(CodecId),
 }
 
 impl DatabaseEnvelope {
-    // Use the lowest level of compression.
-    //
-    // Experiments showed that level 3 is significantly slower than level 1 while offering
-    // no significant size reduction for our use case.
-    const COMPRESSION_LEVEL: i32 = 1;
+    /// Encodes an [`Envelope`] with the given [`EnvelopeCodec`].
+    pub fn encode(
+        envelope: &Envelope,
+        codec: &dyn EnvelopeCodec,
+    ) -> Result<Self, InsertEnvelopeError> {
+        let own_key = envelope.meta().public_key();
+        let sampling_key = envelope.sampling_key().unwrap_or(own_key);
+
+        Ok(DatabaseEnvelope {
+            received_at: envelope.received_at().timestamp_millis(),
+            own_key,
+            sampling_key,
+            codec: codec.id(),
+            encoded_envelope: codec.encode(envelope)?,
+        })
+    }
 
     pub fn len(&self) -> usize {
         self.encoded_envelope.len()
@@ -109,16 +149,12 @@ impl TryFrom<DatabaseEnvelope> for Box<Envelope> {
             received_at: _,
             own_key,
             sampling_key,
-            mut encoded_envelope,
+            codec,
+            encoded_envelope,
         } = value;
 
-        if encoded_envelope.starts_with(ZSTD_MAGIC_WORD) {
-            relay_statsd::metric!(timer(RelayTimers::BufferEnvelopeDecompression), {
-                encoded_envelope = zstd::decode_all(&*encoded_envelope)?.into_boxed_slice();
-            });
-        }
-
-        let mut envelope = Envelope::parse_bytes(Bytes::from(encoded_envelope))?;
+        let codec = codec_by_id(codec).ok_or(InsertEnvelopeError::UnknownCodec(codec))?;
+        let mut envelope = codec.decode(encoded_envelope)?;
         debug_assert_eq!(envelope.meta().public_key(), own_key);
         debug_assert!(envelope
             .sampling_key()
@@ -134,29 +170,7 @@ impl<'a> TryFrom<&'a Envelope> for DatabaseEnvelope {
     type Error = InsertEnvelopeError;
 
     fn try_from(value: &'a Envelope) -> Result<Self, Self::Error> {
-        let own_key = value.meta().public_key();
-        let sampling_key = value.sampling_key().unwrap_or(own_key);
-
-        let serialized_envelope = value.to_vec()?;
-        relay_statsd::metric!(
-            histogram(RelayHistograms::BufferEnvelopeSize) = serialized_envelope.len() as u64
-        );
-
-        let encoded_envelope =
-            relay_statsd::metric!(timer(RelayTimers::BufferEnvelopeCompression), {
-                zstd::encode_all(serialized_envelope.as_slice(), Self::COMPRESSION_LEVEL)?
-            });
-        relay_statsd::metric!(
-            histogram(RelayHistograms::BufferEnvelopeSizeCompressed) =
-                encoded_envelope.len() as u64
-        );
-
-        Ok(DatabaseEnvelope {
-            received_at: value.received_at().timestamp_millis(),
-            own_key,
-            sampling_key,
-            encoded_envelope: encoded_envelope.into_boxed_slice(),
-        })
+        Self::encode(value, &DefaultCodec)
     }
 }
 
@@ -194,6 +208,66 @@ pub enum SqliteEnvelopeStoreError {
     FileSizeReadFailed(sqlx::Error),
 }
 
+impl SqliteEnvelopeStoreError {
+    /// Returns `true` if the operation failed because the database was locked by another
+    /// connection.
+    pub fn is_busy(&self) -> bool {
+        match self {
+            Self::WriteError(error) | Self::FetchError(error) => is_busy_error(error),
+            _ => false,
+        }
+    }
+}
+
+/// Returns `true` for `SQLITE_BUSY` and `SQLITE_LOCKED`, including their extended result codes.
+fn is_busy_error(error: &sqlx::Error) -> bool {
+    let sqlx::Error::Database(error) = error else {
+        return false;
+    };
+
+    error
+        .code()
+        .and_then(|code| code.parse::<i32>().ok())
+        .is_some_and(|code| matches!(code & 0xff, 5 | 6))
+}
+
+/// Handling of operations that fail because the database is locked.
+#[derive(Debug, Clone, Copy)]
+struct BusyHandling {
+    /// Number of retries of a locked operation.
+    retries: u32,
+    /// Backoff before the first retry, which doubles with every retry.
+    backoff: Duration,
+    /// How callers should handle envelopes once all retries failed.
+    fallback: EnvelopeSpoolBusyFallback,
+}
+
+impl BusyHandling {
+    fn new(config: &Config) -> Self {
+        Self {
+            retries: config.spool_envelopes_sqlite_busy_retries(),
+            backoff: config.spool_envelopes_sqlite_busy_retry_backoff(),
+            fallback: config.spool_envelopes_sqlite_busy_fallback(),
+        }
+    }
+
+    /// Returns the randomized backoff before the given retry.
+    fn backoff(&self, retry: u32) -> Duration {
+        let backoff = self.backoff.saturating_mul(2u32.saturating_pow(retry));
+        backoff.mul_f64(0.5 + rand::random::<f64>())
+    }
+}
+
+impl Default for BusyHandling {
+    fn default() -> Self {
+        Self {
+            retries: 3,
+            backoff: Duration::from_millis(10),
+            fallback: EnvelopeSpoolBusyFallback::Reject,
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 struct DiskUsage {
     db: Pool<Sqlite>,
@@ -298,7 +372,11 @@ impl DiskUsage {
 pub struct SqliteEnvelopeStore {
     db: Pool<Sqlite>,
     disk_usage: DiskUsage,
+    busy: BusyHandling,
+    memory_checker: Option<MemoryChecker>,
     partition_tag: String,
+    /// Number of operations run on the database, used to detect idle periods for maintenance.
+    operations: Arc<AtomicU64>,
 }
 
 impl SqliteEnvelopeStore {
@@ -307,7 +385,10 @@ impl SqliteEnvelopeStore {
         Self {
             db: db.clone(),
             disk_usage: DiskUsage::new(partition_id, db, refresh_frequency),
+            busy: BusyHandling::default(),
+            memory_checker: None,
             partition_tag: partition_id.to_string(),
+            operations: Arc::new(AtomicU64::new(0)),
         }
     }
 
@@ -316,6 +397,7 @@ impl SqliteEnvelopeStore {
     pub async fn prepare(
         partition_id: u8,
         config: &Config,
+        memory_checker: MemoryChecker,
     ) -> Result<SqliteEnvelopeStore, SqliteEnvelopeStoreError> {
         // If no path is provided, we can't do disk spooling.
         let Some(path) = config.spool_envelopes_path(partition_id) else {
@@ -324,7 +406,15 @@ impl SqliteEnvelopeStore {
 
         relay_log::info!("buffer file {}", path.to_string_lossy());
 
-        Self::setup(&path).await?;
+        // Maintenance returns free pages to the file system in small steps, which requires
+        // incremental auto-vacuum. Without maintenance, free pages are returned on every commit.
+        let maintenance_interval = config.spool_envelopes_sqlite_maintenance_interval();
+        let auto_vacuum = match maintenance_interval {
+            Some(_) => SqliteAutoVacuum::Incremental,
+            None => SqliteAutoVacuum::Full,
+        };
+
+        Self::setup(&path, auto_vacuum).await?;
 
         let options = SqliteConnectOptions::new()
             .filename(&path)
@@ -342,15 +432,18 @@ impl SqliteEnvelopeStore {
             // Which guarantees good balance between safety and speed.
             .synchronous(SqliteSynchronous::Normal)
             // The freelist pages are moved to the end of the database file and the database file is truncated to remove the freelist pages at every
-            // transaction commit. Note, however, that auto-vacuum only truncates the freelist pages from the file.
+            // transaction commit, or by the maintenance task in incremental mode. Note, however, that auto-vacuum only truncates the freelist pages from the file.
             // Auto-vacuum does not de-fragment the database nor repack individual database pages the way that the VACUUM command does.
             //
             // This will help us to keep the file size under some control.
-            .auto_vacuum(SqliteAutoVacuum::Full)
+            .auto_vacuum(auto_vacuum)
             // If shared-cache mode is enabled and a thread establishes multiple
             // connections to the same database, the connections share a single data and schema cache.
             // This can significantly reduce the quantity of memory and IO required by the system.
-            .shared_cache(true);
+            .shared_cache(true)
+            // Waits for locks held by other connections before failing with `SQLITE_BUSY`.
+            // Operations that still fail are retried by the store, see `BusyHandling`.
+            .busy_timeout(config.spool_envelopes_sqlite_busy_timeout());
 
         let db = SqlitePoolOptions::new()
             .max_connections(1)
@@ -359,7 +452,7 @@ impl SqliteEnvelopeStore {
             .await
             .map_err(SqliteEnvelopeStoreError::SqlxSetupFailed)?;
 
-        Ok(SqliteEnvelopeStore {
+        let store = SqliteEnvelopeStore {
             db: db.clone(),
             disk_usage: DiskUsage::prepare(
                 partition_id,
@@ -367,20 +460,153 @@ impl SqliteEnvelopeStore {
                 config.spool_disk_usage_refresh_frequency_ms(),
             )
             .await?,
+            busy: BusyHandling::new(config),
+            memory_checker: Some(memory_checker),
             partition_tag: partition_id.to_string(),
-        })
+            operations: Arc::new(AtomicU64::new(0)),
+        };
+
+        if let Some(interval) = maintenance_interval {
+            store.start_maintenance(interval);
+        }
+
+        Ok(store)
+    }
+
+    /// Starts a background task that compacts the database in the given interval.
+    ///
+    /// After every interval, the task waits for [`MAINTENANCE_IDLE_PERIOD`] without operations
+    /// on the database before it runs the maintenance, see [`Self::run_maintenance`]. The task
+    /// exits when the store is dropped.
+    fn start_maintenance(&self, interval: Duration) {
+        let db = self.db.clone();
+        let partition_tag = self.partition_tag.clone();
+        // We get a weak reference, so the task exits once the store is dropped.
+        let operations_weak = Arc::downgrade(&self.operations);
+
+        relay_system::spawn!(async move {
+            loop {
+                sleep(interval).await;
+
+                loop {
+                    let Some(operations) = operations_weak.upgrade() else {
+                        return;
+                    };
+                    let before = operations.load(Ordering::Relaxed);
+                    drop(operations);
+
+                    sleep(MAINTENANCE_IDLE_PERIOD).await;
+
+                    let Some(operations) = operations_weak.upgrade() else {
+                        return;
+                    };
+                    if operations.load(Ordering::Relaxed) == before {
+                        break;
+                    }
+                }
+
+                let Some(operations) = operations_weak.upgrade() else {
+                    return;
+                };
+                if let Err(error) = Self::maintain(&db, &partition_tag, &operations).await {
+                    relay_log::error!(
+                        error = &error as &dyn std::error::Error,
+                        "failed to compact the buffer database"
+                    );
+                }
+            }
+        });
+    }
+
+    /// Compacts the database.
+    ///
+    /// Free pages are returned to the file system with incremental vacuum steps of
+    /// [`MAINTENANCE_STEP_PAGES`], so other operations only wait for a single step. Compaction
+    /// stops early once other operations run on the database and continues with the next call.
+    /// It has no effect unless the database uses incremental auto-vacuum.
+    pub async fn run_maintenance(&self) -> Result<(), SqliteEnvelopeStoreError> {
+        Self::maintain(&self.db, &self.partition_tag, &self.operations).await
+    }
+
+    async fn maintain(
+        db: &Pool<Sqlite>,
+        partition_tag: &str,
+        operations: &AtomicU64,
+    ) -> Result<(), SqliteEnvelopeStoreError> {
+        let started = Instant::now();
+        let free_before = Self::free_size(db).await?;
+
+        let mut free_after = free_before;
+        while free_after > 0 {
+            let before = operations.load(Ordering::Relaxed);
+            sqlx::query(&format!(
+                "PRAGMA incremental_vacuum({MAINTENANCE_STEP_PAGES});"
+            ))
+            .execute(db)
+            .await
+            .map_err(SqliteEnvelopeStoreError::WriteError)?;
+            let free_size = Self::free_size(db).await?;
+
+            // Without incremental auto-vacuum, the step does not free any pages.
+            let progress = free_size < free_after;
+            free_after = free_size;
+            if !progress || operations.load(Ordering::Relaxed) != before {
+                break;
+            }
+        }
+
+        // Truncating the file requires a checkpoint of the write-ahead log. A passive checkpoint
+        // does not wait for other connections and is completed by later checkpoints otherwise.
+        sqlx::query("PRAGMA wal_checkpoint(PASSIVE);")
+            .execute(db)
+            .await
+            .map_err(SqliteEnvelopeStoreError::WriteError)?;
+
+        relay_statsd::metric!(
+            timer(RelayTimers::BufferSqliteMaintenance) = started.elapsed(),
+            partition_id = partition_tag
+        );
+        relay_statsd::metric!(
+            counter(RelayCounters::BufferSqliteMaintenanceReclaimed) +=
+                free_before.saturating_sub(free_after),
+            partition_id = partition_tag
+        );
+        relay_log::debug!(
+            free_before,
+            free_after,
+            "compacted buffer database for partition {partition_tag}"
+        );
+
+        Ok(())
+    }
+
+    /// Returns the size of the free pages in the database.
+    async fn free_size(db: &Pool<Sqlite>) -> Result<u64, SqliteEnvelopeStoreError> {
+        let free_size: i64 = build_free_size()
+            .fetch_one(db)
+            .await
+            .and_then(|r| r.try_get(0))
+            .map_err(SqliteEnvelopeStoreError::FileSizeReadFailed)?;
+
+        Ok(free_size as u64)
     }
 
     /// Set up the database and return the current number of envelopes.
     ///
     /// The directories and spool file will be created if they don't already
     /// exist.
-    async fn setup(path: &Path) -> Result<(), SqliteEnvelopeStoreError> {
+    /// The auto-vacuum mode only takes effect for new databases, since it has to be set before
+    /// the migrations create any tables.
+    async fn setup(
+        path: &Path,
+        auto_vacuum: SqliteAutoVacuum,
+    ) -> Result<(), SqliteEnvelopeStoreError> {
         Self::create_spool_directory(path).await?;
 
         let options = SqliteConnectOptions::new()
             .filename(path)
             .journal_mode(SqliteJournalMode::Wal)
+            .auto_vacuum(auto_vacuum)
             .create_if_missing(true);
 
         let db = SqlitePoolOptions::new()
@@ -415,6 +641,9 @@ impl SqliteEnvelopeStore {
     }
 
     /// Inserts one or more envelopes into the database.
+    ///
+    /// Envelopes that were encoded with different codecs are written to separate rows, since the
+    /// codec is stored per row.
     pub async fn insert_batch(
         &mut self,
         envelopes: DatabaseBatch,
@@ -426,51 +655,388 @@ impl SqliteEnvelopeStore {
             envelopes,
         } = envelopes;
 
-        let count = envelopes.len();
-        let encoded = match count {
-            0 => {
-                debug_assert!(false, "should not be called with empty batch");
-                return Ok(());
-            }
-            // special-casing single envelopes shaves off a little bit of time for large envelopes,
-            // but it's mainly for backward compatibility.
-            1 => envelopes.into_iter().next().unwrap().encoded_envelope,
-            _more => pack_envelopes(envelopes),
-        };
+        if envelopes.is_empty() {
+            debug_assert!(false, "should not be called with empty batch");
+            return Ok(());
+        }
 
-        let query = sqlx::query("INSERT INTO envelopes (received_at, own_key, sampling_key, count, envelope) VALUES (?, ?, ?, ?, ?);")
-            .bind(received_at)
-            .bind(own_key.as_str())
-            .bind(sampling_key.as_str())
-            .bind(count as u16)
-            .bind(encoded);
+        for envelopes in split_by_codec(envelopes) {
+            let codec = envelopes[0].codec;
+            // Every row is sorted by the most recent envelope it contains.
+            let received_at = envelopes.last().map_or(received_at, |e| e.received_at);
+
+            let count = envelopes.len();
+            let encoded = match count {
+                // special-casing single envelopes shaves off a little bit of time for large
+                // envelopes, but it's mainly for backward compatibility.
+                1 => envelopes.into_iter().next().unwrap().encoded_envelope,
+                _more => pack_envelopes(envelopes),
+            };
+
+            let db = &self.db;
+            let encoded = &encoded;
+            relay_statsd::metric!(
+                timer(RelayTimers::BufferSqlWrite),
+                partition_id = &self.partition_tag,
+                {
+                    self.retry_busy(move || async move {
+                        build_insert_envelopes(
+                            received_at,
+                            own_key,
+                            sampling_key,
+                            count,
+                            codec,
+                            encoded,
+                        )
+                        .execute(db)
+                        .await
+                        .map_err(SqliteEnvelopeStoreError::WriteError)
+                    })
+                    .await?;
+                }
+            );
+        }
 
-        relay_statsd::metric!(
-            timer(RelayTimers::BufferSqlWrite),
-            partition_id = &self.partition_tag,
-            {
-                query
-                    .execute(&self.db)
-                    .await
-                    .map_err(SqliteEnvelopeStoreError::WriteError)?;
-            }
-        );
         Ok(())
     }
 
     /// Deletes and returns at most `limit` [`Envelope`]s from the database.
+    ///
+    /// The row is deleted in a transaction that is only committed once the row has been read. If
+    /// reading fails, the row remains in the database and can be read again. Rows that are read
+    /// but contain corrupt data are still deleted, since reading them again cannot succeed.
     pub async fn delete_batch(
         &mut self,
         own_key: ProjectKey,
         sampling_key: ProjectKey,
     ) -> Result<Option<DatabaseBatch>, SqliteEnvelopeStoreError> {
-        let mut rows = build_delete_and_fetch_many_envelopes(own_key, sampling_key).fetch(&self.db);
-        let Some(row) = rows.as_mut().next().await else {
+        self.retry_busy(|| self.try_delete_batch(own_key, sampling_key))
+            .await
+    }
+
+    async fn try_delete_batch(
+        &self,
+        own_key: ProjectKey,
+        sampling_key: ProjectKey,
+    ) -> Result<Option<DatabaseBatch>, SqliteEnvelopeStoreError> {
+        let mut transaction = self
+            .db
+            .begin()
+            .await
+            .map_err(SqliteEnvelopeStoreError::FetchError)?;
+
+        let row = build_delete_and_fetch_many_envelopes(own_key, sampling_key)
+            .fetch_optional(&mut *transaction)
+            .await
+            .map_err(SqliteEnvelopeStoreError::FetchError)?;
+        let Some(row) = row else {
+            return Ok(None);
+        };
+        let batch = extract_batch(own_key, sampling_key, row);
+
+        transaction
+            .commit()
+            .await
+            .map_err(SqliteEnvelopeStoreError::WriteError)?;
+
+        Ok(Some(batch?))
+    }
+
+    /// Deletes and returns the most recent batch like [`Self::delete_batch`], holding its newest
+    /// envelope until `deadline`.
+    ///
+    /// The newest envelope is moved to the in-flight table in the same transaction that deletes
+    /// the row, so it is never lost in between. See [`Self::insert_inflight`] for held envelopes.
+    /// Like in [`Self::delete_batch`], a row with corrupt data is still deleted.
+    pub async fn delete_batch_and_hold_newest(
+        &mut self,
+        own_key: ProjectKey,
+        sampling_key: ProjectKey,
+        deadline: DateTime<Utc>,
+    ) -> Result<Option<HeldBatch>, SqliteEnvelopeStoreError> {
+        let deadline = deadline.timestamp_millis();
+        self.retry_busy(|| self.try_delete_batch_and_hold_newest(own_key, sampling_key, deadline))
+            .await
+    }
+
+    async fn try_delete_batch_and_hold_newest(
+        &self,
+        own_key: ProjectKey,
+        sampling_key: ProjectKey,
+        deadline: i64,
+    ) -> Result<Option<HeldBatch>, SqliteEnvelopeStoreError> {
+        let mut transaction = self
+            .db
+            .begin()
+            .await
+            .map_err(SqliteEnvelopeStoreError::FetchError)?;
+
+        let row = build_delete_and_fetch_many_envelopes(own_key, sampling_key)
+            .fetch_optional(&mut *transaction)
+            .await
+            .map_err(SqliteEnvelopeStoreError::FetchError)?;
+        let Some(row) = row else {
+            return Ok(None);
+        };
+
+        let batch = match extract_batch(own_key, sampling_key, row) {
+            Ok(batch) => batch,
+            Err(error) => {
+                transaction
+                    .commit()
+                    .await
+                    .map_err(SqliteEnvelopeStoreError::WriteError)?;
+                return Err(error);
+            }
+        };
+        let mut envelopes = Vec::from(batch);
+        let Some(held) = envelopes.pop() else {
+            return Ok(None);
+        };
+
+        let token = build_insert_inflight(&held, deadline)
+            .execute(&mut *transaction)
+            .await
+            .map_err(SqliteEnvelopeStoreError::WriteError)?
+            .last_insert_rowid();
+
+        transaction
+            .commit()
+            .await
+            .map_err(SqliteEnvelopeStoreError::WriteError)?;
+
+        Ok(Some(HeldBatch {
+            envelopes,
+            held,
+            token,
+        }))
+    }
+
+    /// Deletes and returns all [`DatabaseEnvelope`]s of the given project key pair.
+    ///
+    /// The envelopes are returned from oldest to newest. Rows are read and deleted in a single
+    /// transaction, so reading failures leave all rows in the database. Like in
+    /// [`Self::delete_batch`], rows with corrupt data are still deleted. They are logged and
+    /// skipped, so that the envelopes of the remaining rows are still returned.
+    pub async fn delete_all(
+        &mut self,
+        own_key: ProjectKey,
+        sampling_key: ProjectKey,
+    ) -> Result<Vec<DatabaseEnvelope>, SqliteEnvelopeStoreError> {
+        self.retry_busy(|| self.try_delete_all(own_key, sampling_key))
+            .await
+    }
+
+    async fn try_delete_all(
+        &self,
+        own_key: ProjectKey,
+        sampling_key: ProjectKey,
+    ) -> Result<Vec<DatabaseEnvelope>, SqliteEnvelopeStoreError> {
+        let mut transaction = self
+            .db
+            .begin()
+            .await
+            .map_err(SqliteEnvelopeStoreError::FetchError)?;
+
+        let rows = build_fetch_all_envelopes(own_key, sampling_key)
+            .fetch_all(&mut *transaction)
+            .await
+            .map_err(SqliteEnvelopeStoreError::FetchError)?;
+        build_delete_all_envelopes(own_key, sampling_key)
+            .execute(&mut *transaction)
+            .await
+            .map_err(SqliteEnvelopeStoreError::WriteError)?;
+
+        let mut envelopes = Vec::new();
+        for row in rows {
+            match extract_batch(own_key, sampling_key, row) {
+                Ok(batch) => envelopes.extend(Vec::from(batch)),
+                Err(error) => {
+                    relay_log::error!(
+                        error = &error as &dyn std::error::Error,
+                        "failed to read envelopes from spool, skipping corrupt row"
+                    );
+                }
+            }
+        }
+
+        transaction
+            .commit()
+            .await
+            .map_err(SqliteEnvelopeStoreError::WriteError)?;
+
+        Ok(envelopes)
+    }
+
+    /// Deletes and returns the oldest [`DatabaseEnvelope`] of the given project key pair.
+    ///
+    /// If the oldest row in the database contains multiple envelopes, the remaining envelopes are
+    /// written back to the database. The row is deleted and the remaining envelopes are written
+    /// back in a single transaction, so a failure in between leaves the row in the database. Like
+    /// in [`Self::delete_batch`], a row with corrupt data is still deleted.
+    pub async fn delete_oldest(
+        &mut self,
+        own_key: ProjectKey,
+        sampling_key: ProjectKey,
+    ) -> Result<Option<DatabaseEnvelope>, SqliteEnvelopeStoreError> {
+        self.retry_busy(|| self.try_delete_oldest(own_key, sampling_key))
+            .await
+    }
+
+    async fn try_delete_oldest(
+        &self,
+        own_key: ProjectKey,
+        sampling_key: ProjectKey,
+    ) -> Result<Option<DatabaseEnvelope>, SqliteEnvelopeStoreError> {
+        let mut transaction = self
+            .db
+            .begin()
+            .await
+            .map_err(SqliteEnvelopeStoreError::FetchError)?;
+
+        let row = build_delete_and_fetch_oldest_envelopes(own_key, sampling_key)
+            .fetch_optional(&mut *transaction)
+            .await
+            .map_err(SqliteEnvelopeStoreError::FetchError)?;
+        let Some(row) = row else {
+            return Ok(None);
+        };
+
+        let batch = match extract_batch(own_key, sampling_key, row) {
+            Ok(batch) => batch,
+            Err(error) => {
+                transaction
+                    .commit()
+                    .await
+                    .map_err(SqliteEnvelopeStoreError::WriteError)?;
+                return Err(error);
+            }
+        };
+        let received_at = batch.received_at;
+        let mut envelopes = VecDeque::from(Vec::from(batch));
+        let Some(oldest) = envelopes.pop_front() else {
             return Ok(None);
         };
-        let row = row.map_err(SqliteEnvelopeStoreError::FetchError)?;
 
-        Ok(Some(extract_batch(own_key, sampling_key, row)?))
+        // All envelopes of a row share the codec and the row is sorted by its most recent
+        // envelope, which does not change when the oldest envelope is removed.
+        if !envelopes.is_empty() {
+            let count = envelopes.len();
+            let encoded = match count {
+                1 => envelopes.pop_front().unwrap().encoded_envelope,
+                _more => pack_envelopes(envelopes.into()),
+            };
+            build_insert_envelopes(
+                received_at,
+                own_key,
+                sampling_key,
+                count,
+                oldest.codec,
+                &encoded,
+            )
+            .execute(&mut *transaction)
+            .await
+            .map_err(SqliteEnvelopeStoreError::WriteError)?;
+        }
+
+        transaction
+            .commit()
+            .await
+            .map_err(SqliteEnvelopeStoreError::WriteError)?;
+
+        Ok(Some(oldest))
+    }
+
+    /// Stores an envelope that was handed to a consumer until it is acknowledged.
+    ///
+    /// Returns the token of the row, which is passed to [`Self::delete_inflight`] once the
+    /// consumer acknowledges the envelope. If it is not acknowledged before `deadline`, the
+    /// envelope is returned by [`Self::delete_expired_inflight`].
+    pub async fn insert_inflight(
+        &mut self,
+        envelope: &DatabaseEnvelope,
+        deadline: DateTime<Utc>,
+    ) -> Result<i64, SqliteEnvelopeStoreError> {
+        let db = &self.db;
+        let deadline = deadline.timestamp_millis();
+        let result = self
+            .retry_busy(move || async move {
+                build_insert_inflight(envelope, deadline)
+                    .execute(db)
+                    .await
+                    .map_err(SqliteEnvelopeStoreError::WriteError)
+            })
+            .await?;
+
+        Ok(result.last_insert_rowid())
+    }
+
+    /// Deletes the in-flight envelope with the given token.
+    ///
+    /// Returns `false` if there is no such envelope, for example because its deadline passed and
+    /// it was returned by [`Self::delete_expired_inflight`].
+    pub async fn delete_inflight(&mut self, token: i64) -> Result<bool, SqliteEnvelopeStoreError> {
+        let db = &self.db;
+        let result = self
+            .retry_busy(move || async move {
+                sqlx::query("DELETE FROM inflight WHERE token = ?")
+                    .bind(token)
+                    .execute(db)
+                    .await
+                    .map_err(SqliteEnvelopeStoreError::WriteError)
+            })
+            .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    /// Deletes and returns all in-flight envelopes whose deadline is not after `now`.
+    ///
+    /// Rows with corrupt project keys are deleted and skipped.
+    pub async fn delete_expired_inflight(
+        &mut self,
+        now: DateTime<Utc>,
+    ) -> Result<Vec<DatabaseEnvelope>, SqliteEnvelopeStoreError> {
+        let db = &self.db;
+        let now = now.timestamp_millis();
+        let rows = self
+            .retry_busy(move || async move {
+                build_delete_and_fetch_expired_inflight(now)
+                    .fetch_all(db)
+                    .await
+                    .map_err(SqliteEnvelopeStoreError::FetchError)
+            })
+            .await?;
+
+        let mut envelopes = Vec::with_capacity(rows.len());
+        for row in rows {
+            let Ok(ProjectKeyPair {
+                own_key,
+                sampling_key,
+                ..
+            }) = extract_project_key_pair(&row)
+            else {
+                continue;
+            };
+
+            envelopes.push(DatabaseEnvelope {
+                received_at: row
+                    .try_get("received_at")
+                    .map_err(SqliteEnvelopeStoreError::FetchError)?,
+                own_key,
+                sampling_key,
+                codec: row
+                    .try_get("codec")
+                    .map_err(SqliteEnvelopeStoreError::FetchError)?,
+                encoded_envelope: row
+                    .try_get("envelope")
+                    .map_err(SqliteEnvelopeStoreError::FetchError)?,
+            });
+        }
+
+        // Redeliver envelopes in the order they were received.
+        envelopes.sort_by_key(|envelope| envelope.received_at);
+        Ok(envelopes)
     }
 
     /// Returns a set of project key pairs, representing all the unique combinations of
@@ -478,6 +1044,7 @@ impl SqliteEnvelopeStore {
     pub async fn project_key_pairs(
         &self,
     ) -> Result<HashSet<ProjectKeyPair>, SqliteEnvelopeStoreError> {
+        self.record_operation();
         let project_key_pairs = build_get_project_key_pairs()
             .fetch_all(&self.db)
             .await
@@ -486,7 +1053,7 @@ impl SqliteEnvelopeStore {
         let project_key_pairs = project_key_pairs
             .into_iter()
             // Collect only keys we can extract.
-            .filter_map(|project_key_pair| extract_project_key_pair(project_key_pair).ok())
+            .filter_map(|project_key_pair| extract_project_key_pair(&project_key_pair).ok())
             .collect();
 
         Ok(project_key_pairs)
@@ -497,31 +1064,201 @@ impl SqliteEnvelopeStore {
         self.disk_usage.usage()
     }
 
-    /// Returns the total count of envelopes stored in the database.
-    pub async fn total_count(&self) -> Result<u64, SqliteEnvelopeStoreError> {
-        let row = build_count_all()
-            .fetch_one(&self.db)
-            .await
-            .map_err(SqliteEnvelopeStoreError::FetchError)?;
+    /// Returns how envelopes should be handled when a write fails because the database is
+    /// locked.
+    pub fn busy_fallback(&self) -> EnvelopeSpoolBusyFallback {
+        self.busy.fallback
+    }
 
-        let total_count: i64 = row.get(0);
-        Ok(total_count as u64)
+    /// Reserves memory for envelopes that are kept in memory because the database is locked.
+    ///
+    /// Returns `None` if no memory can be reserved, see [`MemoryChecker::reserve_overflow`]. Stores
+    /// created without a [`MemoryChecker`] never reserve memory.
+    pub fn reserve_overflow(&self, bytes: usize) -> Option<OverflowReservation> {
+        self.memory_checker.as_ref()?.reserve_overflow(bytes)
     }
-}
 
-fn pack_envelopes(envelopes: Vec<DatabaseEnvelope>) -> Box<[u8]> {
-    let mut packed = vec![];
-    for envelope in envelopes {
-        packed.extend_from_slice(&envelope.received_at.to_le_bytes());
-        packed.extend_from_slice(&(envelope.encoded_envelope.len() as u32).to_le_bytes());
-        packed.extend_from_slice(&envelope.encoded_envelope);
+    /// Runs the operation and retries it with a jittered exponential backoff while it fails
+    /// because the database is locked.
+    ///
+    /// Other errors and the error of the last retry are returned as-is.
+    async fn retry_busy<T, F, Fut>(&self, mut operation: F) -> Result<T, SqliteEnvelopeStoreError>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T, SqliteEnvelopeStoreError>>,
+    {
+        self.record_operation();
+
+        let mut retry = 0;
+        loop {
+            match operation().await {
+                Err(error) if error.is_busy() && retry < self.busy.retries => {
+                    relay_statsd::metric!(
+                        counter(RelayCounters::BufferSqliteBusyRetry) += 1,
+                        partition_id = &self.partition_tag
+                    );
+                    sleep(self.busy.backoff(retry)).await;
+                    retry += 1;
+                }
+                result => return result,
+            }
+        }
     }
-    packed.into_boxed_slice()
-}
 
-fn unpack_envelopes(
+    /// Counts an operation of the buffer on the database, which delays maintenance.
+    fn record_operation(&self) {
+        self.operations.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Returns the time at which the oldest [`DatabaseEnvelope`] of the given project key pair
+    /// was received, without deleting it.
+    pub async fn oldest_received_at(
+        &self,
+        own_key: ProjectKey,
+        sampling_key: ProjectKey,
+    ) -> Result<Option<DateTime<Utc>>, SqliteEnvelopeStoreError> {
+        self.record_operation();
+        let row = build_fetch_oldest_envelopes(own_key, sampling_key)
+            .fetch_optional(&self.db)
+            .await
+            .map_err(SqliteEnvelopeStoreError::FetchError)?;
+        let Some(row) = row else {
+            return Ok(None);
+        };
+
+        let batch = extract_batch(own_key, sampling_key, row)?;
+        Ok(Vec::from(batch).first().map(DatabaseEnvelope::received_at))
+    }
+
+    /// Returns the first value produced by `find` for the [`DatabaseEnvelope`]s of the given
+    /// project key pair, without deleting them.
+    ///
+    /// Rows are read one at a time from oldest to newest, so that the envelopes of a stack are
+    /// never all held in memory. Rows with corrupt data are skipped.
+    pub async fn find<T>(
+        &self,
+        own_key: ProjectKey,
+        sampling_key: ProjectKey,
+        mut find: impl FnMut(&DatabaseEnvelope) -> Option<T>,
+    ) -> Result<Option<T>, SqliteEnvelopeStoreError> {
+        self.record_operation();
+        let mut rows = build_fetch_all_envelopes(own_key, sampling_key).fetch(&self.db);
+        while let Some(row) = rows
+            .try_next()
+            .await
+            .map_err(SqliteEnvelopeStoreError::FetchError)?
+        {
+            let Ok(batch) = extract_batch(own_key, sampling_key, row) else {
+                continue;
+            };
+            if let Some(found) = Vec::from(batch).iter().find_map(&mut find) {
+                return Ok(Some(found));
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// Writes an envelope to the database and reads it back without persisting it.
+    ///
+    /// Both happen in a transaction that is rolled back, so the envelope never remains in the
+    /// database, even if Relay terminates in between. Returns `None` if the envelope cannot be
+    /// read back.
+    pub async fn round_trip(
+        &self,
+        envelope: &DatabaseEnvelope,
+    ) -> Result<Option<DatabaseEnvelope>, SqliteEnvelopeStoreError> {
+        self.record_operation();
+        let mut transaction = self
+            .db
+            .begin()
+            .await
+            .map_err(SqliteEnvelopeStoreError::FetchError)?;
+
+        let (own_key, sampling_key) = (envelope.own_key, envelope.sampling_key);
+        build_insert_envelopes(
+            envelope.received_at,
+            own_key,
+            sampling_key,
+            1,
+            envelope.codec,
+            &envelope.encoded_envelope,
+        )
+        .execute(&mut *transaction)
+        .await
+        .map_err(SqliteEnvelopeStoreError::WriteError)?;
+        let row = build_delete_and_fetch_many_envelopes(own_key, sampling_key)
+            .fetch_optional(&mut *transaction)
+            .await
+            .map_err(SqliteEnvelopeStoreError::FetchError)?;
+
+        transaction
+            .rollback()
+            .await
+            .map_err(SqliteEnvelopeStoreError::WriteError)?;
+
+        let Some(row) = row else {
+            return Ok(None);
+        };
+        let batch = extract_batch(own_key, sampling_key, row)?;
+        Ok(Vec::from(batch).pop())
+    }
+
+    /// Returns the total count of envelopes stored in the database.
+    pub async fn total_count(&self) -> Result<u64, SqliteEnvelopeStoreError> {
+        self.record_operation();
+        let row = build_count_all()
+            .fetch_one(&self.db)
+            .await
+            .map_err(SqliteEnvelopeStoreError::FetchError)?;
+
+        let total_count: i64 = row.get(0);
+        Ok(total_count as u64)
+    }
+
+    /// Returns the count of envelopes stored in the database for the given project keys.
+    pub async fn count(
+        &self,
+        own_key: ProjectKey,
+        sampling_key: ProjectKey,
+    ) -> Result<u64, SqliteEnvelopeStoreError> {
+        self.record_operation();
+        let row = build_count_for_project_keys(own_key, sampling_key)
+            .fetch_one(&self.db)
+            .await
+            .map_err(SqliteEnvelopeStoreError::FetchError)?;
+
+        let count: i64 = row.get(0);
+        Ok(count as u64)
+    }
+}
+
+/// Splits a list of envelopes into consecutive runs that share the same codec.
+fn split_by_codec(envelopes: Vec<DatabaseEnvelope>) -> Vec<Vec<DatabaseEnvelope>> {
+    let mut runs: Vec<Vec<DatabaseEnvelope>> = vec![];
+    for envelope in envelopes {
+        match runs.last_mut() {
+            Some(run) if run[0].codec == envelope.codec => run.push(envelope),
+            _ => runs.push(vec![envelope]),
+        }
+    }
+    runs
+}
+
+fn pack_envelopes(envelopes: Vec<DatabaseEnvelope>) -> Box<[u8]> {
+    let mut packed = vec![];
+    for envelope in envelopes {
+        packed.extend_from_slice(&envelope.received_at.to_le_bytes());
+        packed.extend_from_slice(&(envelope.encoded_envelope.len() as u32).to_le_bytes());
+        packed.extend_from_slice(&envelope.encoded_envelope);
+    }
+    packed.into_boxed_slice()
+}
+
+fn unpack_envelopes(
     own_key: ProjectKey,
     sampling_key: ProjectKey,
+    codec: CodecId,
     data: &[u8],
 ) -> Result<Vec<DatabaseEnvelope>, std::io::Error> {
     let mut envelopes = vec![];
@@ -548,6 +1285,7 @@ fn unpack_envelopes(
             received_at,
             own_key,
             sampling_key,
+            codec,
             encoded_envelope: b.into_boxed_slice(),
         });
     }
@@ -569,6 +1307,9 @@ fn extract_batch(
     let count: u64 = row
         .try_get("count")
         .map_err(SqliteEnvelopeStoreError::FetchError)?;
+    let codec: CodecId = row
+        .try_get("codec")
+        .map_err(SqliteEnvelopeStoreError::FetchError)?;
 
     let envelopes = match count {
         0 => {
@@ -579,9 +1320,10 @@ fn extract_batch(
             received_at,
             own_key,
             sampling_key,
+            codec,
             encoded_envelope: data,
         }],
-        _more => unpack_envelopes(own_key, sampling_key, &data)?,
+        _more => unpack_envelopes(own_key, sampling_key, codec, &data)?,
     };
 
     Ok(DatabaseBatch {
@@ -593,7 +1335,7 @@ fn extract_batch(
 }
 
 /// Deserializes a pair of [`ProjectKey`] from the database.
-fn extract_project_key_pair(row: SqliteRow) -> Result<ProjectKeyPair, SqliteEnvelopeStoreError> {
+fn extract_project_key_pair(row: &SqliteRow) -> Result<ProjectKeyPair, SqliteEnvelopeStoreError> {
     let own_key = row
         .try_get("own_key")
         .map_err(SqliteEnvelopeStoreError::FetchError)
@@ -618,6 +1360,27 @@ fn extract_project_key_pair(row: SqliteRow) -> Result<ProjectKeyPair, SqliteEnve
     }
 }
 
+/// Builds a query that inserts a row of one or more encoded envelopes.
+pub fn build_insert_envelopes<'a>(
+    received_at: i64,
+    own_key: ProjectKey,
+    sampling_key: ProjectKey,
+    count: usize,
+    codec: CodecId,
+    encoded: &'a [u8],
+) -> Query<'a, Sqlite, SqliteArguments<'a>> {
+    sqlx::query(
+        "INSERT INTO envelopes (received_at, own_key, sampling_key, count, codec, envelope)
+         VALUES (?, ?, ?, ?, ?, ?);",
+    )
+    .bind(received_at)
+    .bind(own_key.to_string())
+    .bind(sampling_key.to_string())
+    .bind(count as u16)
+    .bind(codec)
+    .bind(encoded)
+}
+
 /// Builds a query that deletes many [`Envelope`] from the database.
 pub fn build_delete_and_fetch_many_envelopes<'a>(
     own_key: ProjectKey,
@@ -629,12 +1392,102 @@ pub fn build_delete_and_fetch_many_envelopes<'a>(
          WHERE id IN (SELECT id FROM envelopes WHERE own_key = ? AND sampling_key = ?
             ORDER BY received_at DESC LIMIT 1)
          RETURNING
-            received_at, own_key, sampling_key, envelope, count",
+            received_at, own_key, sampling_key, envelope, count, codec",
+    )
+    .bind(own_key.to_string())
+    .bind(project_key.to_string())
+}
+
+/// Builds a query that fetches all rows of envelopes with the given project keys, oldest first.
+pub fn build_fetch_all_envelopes<'a>(
+    own_key: ProjectKey,
+    project_key: ProjectKey,
+) -> Query<'a, Sqlite, SqliteArguments<'a>> {
+    sqlx::query(
+        "SELECT
+            received_at, own_key, sampling_key, envelope, count, codec
+         FROM envelopes WHERE own_key = ? AND sampling_key = ?
+         ORDER BY received_at ASC",
     )
     .bind(own_key.to_string())
     .bind(project_key.to_string())
 }
 
+/// Builds a query that deletes all rows of envelopes with the given project keys.
+pub fn build_delete_all_envelopes<'a>(
+    own_key: ProjectKey,
+    project_key: ProjectKey,
+) -> Query<'a, Sqlite, SqliteArguments<'a>> {
+    sqlx::query("DELETE FROM envelopes WHERE own_key = ? AND sampling_key = ?")
+        .bind(own_key.to_string())
+        .bind(project_key.to_string())
+}
+
+/// Builds a query that deletes the oldest row of envelopes with the given project keys.
+pub fn build_delete_and_fetch_oldest_envelopes<'a>(
+    own_key: ProjectKey,
+    project_key: ProjectKey,
+) -> Query<'a, Sqlite, SqliteArguments<'a>> {
+    sqlx::query(
+        "DELETE FROM
+            envelopes
+         WHERE id IN (SELECT id FROM envelopes WHERE own_key = ? AND sampling_key = ?
+            ORDER BY received_at ASC LIMIT 1)
+         RETURNING
+            received_at, own_key, sampling_key, envelope, count, codec",
+    )
+    .bind(own_key.to_string())
+    .bind(project_key.to_string())
+}
+
+/// Builds a query that fetches the oldest row of envelopes with the given project keys.
+pub fn build_fetch_oldest_envelopes<'a>(
+    own_key: ProjectKey,
+    project_key: ProjectKey,
+) -> Query<'a, Sqlite, SqliteArguments<'a>> {
+    sqlx::query(
+        "SELECT
+            received_at, own_key, sampling_key, envelope, count, codec
+         FROM
+            envelopes
+         WHERE own_key = ? AND sampling_key = ?
+         ORDER BY received_at ASC LIMIT 1",
+    )
+    .bind(own_key.to_string())
+    .bind(project_key.to_string())
+}
+
+/// Builds a query that inserts an in-flight envelope with the given deadline in milliseconds.
+pub fn build_insert_inflight(
+    envelope: &DatabaseEnvelope,
+    deadline: i64,
+) -> Query<'_, Sqlite, SqliteArguments<'_>> {
+    sqlx::query(
+        "INSERT INTO inflight (deadline, received_at, own_key, sampling_key, codec, envelope)
+         VALUES (?, ?, ?, ?, ?, ?);",
+    )
+    .bind(deadline)
+    .bind(envelope.received_at)
+    .bind(envelope.own_key.to_string())
+    .bind(envelope.sampling_key.to_string())
+    .bind(envelope.codec)
+    .bind(&envelope.encoded_envelope[..])
+}
+
+/// Builds a query that deletes all in-flight envelopes whose deadline has passed.
+pub fn build_delete_and_fetch_expired_inflight<'a>(
+    now: i64,
+) -> Query<'a, Sqlite, SqliteArguments<'a>> {
+    sqlx::query(
+        "DELETE FROM
+            inflight
+         WHERE deadline <= ?
+         RETURNING
+            received_at, own_key, sampling_key, codec, envelope",
+    )
+    .bind(now)
+}
+
 /// Creates a query which fetches the number of used database pages multiplied by the page size.
 ///
 /// This info used to estimate the current allocated database size.
@@ -644,6 +1497,13 @@ pub fn build_estimate_size<'a>() -> Query<'a, Sqlite, SqliteArguments<'a>> {
     )
 }
 
+/// Creates a query which fetches the number of free database pages multiplied by the page size.
+pub fn build_free_size<'a>() -> Query<'a, Sqlite, SqliteArguments<'a>> {
+    sqlx::query(
+        "SELECT freelist_count * page_size FROM pragma_freelist_count(), pragma_page_size();",
+    )
+}
+
 /// Returns the query to select all the unique combinations of own and sampling keys.
 pub fn build_get_project_key_pairs<'a>() -> Query<'a, Sqlite, SqliteArguments<'a>> {
     sqlx::query("SELECT DISTINCT own_key, sampling_key FROM envelopes;")
@@ -657,14 +1517,28 @@ pub fn build_count_all<'a>() -> Query<'a, Sqlite, SqliteArguments<'a>> {
     sqlx::query("SELECT SUM(count) FROM envelopes;")
 }
 
+/// Returns the query to count the number of envelopes on disk for the given project keys.
+pub fn build_count_for_project_keys<'a>(
+    own_key: ProjectKey,
+    sampling_key: ProjectKey,
+) -> Query<'a, Sqlite, SqliteArguments<'a>> {
+    sqlx::query(
+        "SELECT COALESCE(SUM(count), 0) FROM envelopes WHERE own_key = ? AND sampling_key = ?;",
+    )
+    .bind(own_key.to_string())
+    .bind(sampling_key.to_string())
+}
+
 #[cfg(test)]
 mod tests {
+    use sqlx::Connection;
     use std::time::Duration;
     use tokio::time::sleep;
 
     use relay_base_schema::project::ProjectKey;
 
     use super::*;
+    use crate::services::buffer::envelope_store::codec::MsgpackCodec;
     use crate::services::buffer::testutils::utils::{mock_envelopes, setup_db};
 
     #[tokio::test]
@@ -771,6 +1645,121 @@ mod tests {
             .is_none());
     }
 
+    #[tokio::test]
+    async fn test_round_trip_leaves_no_envelope() {
+        let db = setup_db(true).await;
+        let mut envelope_store = SqliteEnvelopeStore::new(0, db, Duration::from_millis(100));
+
+        let own_key = ProjectKey::parse("a94ae32be2584e0bbd7a4cbb95971fee").unwrap();
+        let sampling_key = ProjectKey::parse("b81ae32be2584e0bbd7a4cbb95971fe1").unwrap();
+
+        let envelope = mock_envelopes(1).pop().unwrap();
+        let encoded = DatabaseEnvelope::try_from(envelope.as_ref()).unwrap();
+        let read = envelope_store.round_trip(&encoded).await.unwrap().unwrap();
+        assert_eq!(
+            read.received_at().timestamp_millis(),
+            envelope.received_at().timestamp_millis()
+        );
+
+        // The envelope was only written in a transaction that was rolled back.
+        assert_eq!(envelope_store.total_count().await.unwrap(), 0);
+        assert!(envelope_store
+            .delete_batch(own_key, sampling_key)
+            .await
+            .unwrap()
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn test_insert_and_delete_mixed_codecs() {
+        let db = setup_db(true).await;
+        let mut envelope_store = SqliteEnvelopeStore::new(0, db, Duration::from_millis(100));
+
+        let own_key = ProjectKey::parse("a94ae32be2584e0bbd7a4cbb95971fee").unwrap();
+        let sampling_key = ProjectKey::parse("b81ae32be2584e0bbd7a4cbb95971fe1").unwrap();
+
+        // We encode the envelopes alternating between codecs, simulating a codec switch while
+        // envelopes of the previous codec are still buffered.
+        let envelopes = mock_envelopes(4);
+        let codecs: [&dyn EnvelopeCodec; 2] = [&DefaultCodec, &MsgpackCodec];
+        let batch: Vec<_> = envelopes
+            .iter()
+            .enumerate()
+            .map(|(i, e)| DatabaseEnvelope::encode(e, codecs[i / 2]).unwrap())
+            .collect();
+
+        envelope_store
+            .insert_batch(batch.try_into().unwrap())
+            .await
+            .unwrap();
+        assert_eq!(envelope_store.total_count().await.unwrap(), 4);
+
+        // Every codec run is stored in its own row, the newest one is returned first.
+        let mut extracted = vec![];
+        while let Some(batch) = envelope_store
+            .delete_batch(own_key, sampling_key)
+            .await
+            .unwrap()
+        {
+            assert_eq!(batch.len(), 2);
+            extracted.extend(Vec::<DatabaseEnvelope>::from(batch));
+        }
+        assert_eq!(extracted.len(), 4);
+
+        let decoded: Vec<Box<Envelope>> = extracted
+            .into_iter()
+            .map(|e| e.try_into().unwrap())
+            .collect();
+        for (decoded, envelope) in decoded
+            .iter()
+            .zip(envelopes[2..].iter().chain(&envelopes[..2]))
+        {
+            assert_eq!(decoded.event_id(), envelope.event_id());
+            assert_eq!(
+                decoded.received_at().timestamp_millis(),
+                envelope.received_at().timestamp_millis()
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_delete_all_skips_corrupt_rows() {
+        let db = setup_db(true).await;
+        let mut envelope_store = SqliteEnvelopeStore::new(0, db, Duration::from_millis(100));
+
+        let own_key = ProjectKey::parse("a94ae32be2584e0bbd7a4cbb95971fee").unwrap();
+        let sampling_key = ProjectKey::parse("b81ae32be2584e0bbd7a4cbb95971fe1").unwrap();
+
+        let inserted = mock_envelopes(2);
+        let envelopes: Vec<_> = inserted
+            .iter()
+            .map(|e| DatabaseEnvelope::try_from(e.as_ref()).unwrap())
+            .collect();
+        let codec = envelopes[0].codec;
+        envelope_store
+            .insert_batch(envelopes.try_into().unwrap())
+            .await
+            .unwrap();
+
+        // A row that claims to contain two envelopes but cannot be unpacked.
+        build_insert_envelopes(0, own_key, sampling_key, 2, codec, b"corrupt")
+            .execute(&envelope_store.db)
+            .await
+            .unwrap();
+
+        let deleted = envelope_store
+            .delete_all(own_key, sampling_key)
+            .await
+            .unwrap();
+        assert_eq!(deleted.len(), 2);
+
+        // The corrupt row is deleted as well.
+        assert_eq!(
+            envelope_store.count(own_key, sampling_key).await.unwrap(),
+            0
+        );
+    }
+
     #[tokio::test]
     async fn test_insert_and_get_project_keys_pairs() {
         let db = setup_db(true).await;
@@ -857,4 +1846,184 @@ mod tests {
 
         assert_eq!(store.total_count().await.unwrap(), envelopes.len() as u64);
     }
+
+    #[tokio::test]
+    async fn test_maintenance_keeps_envelopes() {
+        let db = setup_db(true).await;
+        let mut store = SqliteEnvelopeStore::new(0, db, Duration::from_millis(1));
+
+        let own_key = ProjectKey::parse("a94ae32be2584e0bbd7a4cbb95971fee").unwrap();
+        let sampling_key = ProjectKey::parse("b81ae32be2584e0bbd7a4cbb95971fe1").unwrap();
+
+        let envelopes = mock_envelopes(20);
+        for envelope in &envelopes {
+            store
+                .insert_batch(
+                    vec![DatabaseEnvelope::try_from(envelope.as_ref()).unwrap()]
+                        .try_into()
+                        .unwrap(),
+                )
+                .await
+                .unwrap();
+        }
+
+        // Free some pages before compacting.
+        for _ in 0..10 {
+            store
+                .delete_batch(own_key, sampling_key)
+                .await
+                .unwrap()
+                .unwrap();
+        }
+
+        store.run_maintenance().await.unwrap();
+        assert_eq!(store.total_count().await.unwrap(), 10);
+
+        let mut remaining = vec![];
+        while let Some(batch) = store.delete_batch(own_key, sampling_key).await.unwrap() {
+            remaining.extend(Vec::from(batch));
+        }
+        let remaining: Vec<_> = remaining
+            .iter()
+            .map(|e| e.received_at().timestamp_millis())
+            .collect();
+        let expected: Vec<_> = envelopes[..10]
+            .iter()
+            .rev()
+            .map(|e| e.received_at().timestamp_millis())
+            .collect();
+        assert_eq!(remaining, expected);
+    }
+
+    #[tokio::test]
+    async fn test_maintenance_returns_free_pages() {
+        let path = std::env::temp_dir().join(uuid::Uuid::new_v4().to_string());
+        SqliteEnvelopeStore::setup(&path, SqliteAutoVacuum::Incremental)
+            .await
+            .unwrap();
+        let options = SqliteConnectOptions::new()
+            .filename(&path)
+            .auto_vacuum(SqliteAutoVacuum::Incremental);
+        let db = SqlitePoolOptions::new()
+            .max_connections(1)
+            .connect_with(options)
+            .await
+            .unwrap();
+        let mut store = SqliteEnvelopeStore::new(0, db.clone(), Duration::from_millis(1));
+
+        let own_key = ProjectKey::parse("a94ae32be2584e0bbd7a4cbb95971fee").unwrap();
+        let sampling_key = ProjectKey::parse("b81ae32be2584e0bbd7a4cbb95971fe1").unwrap();
+        for envelope in mock_envelopes(200) {
+            store
+                .insert_batch(
+                    vec![DatabaseEnvelope::try_from(envelope.as_ref()).unwrap()]
+                        .try_into()
+                        .unwrap(),
+                )
+                .await
+                .unwrap();
+        }
+        while store
+            .delete_batch(own_key, sampling_key)
+            .await
+            .unwrap()
+            .is_some()
+        {}
+
+        // Pages are only returned to the file system by maintenance.
+        assert!(SqliteEnvelopeStore::free_size(&db).await.unwrap() > 0);
+        store.run_maintenance().await.unwrap();
+        assert_eq!(SqliteEnvelopeStore::free_size(&db).await.unwrap(), 0);
+    }
+
+    /// Creates a store that fails immediately on a locked database and a second connection to
+    /// the same database, which can hold the lock.
+    async fn setup_contended_store(
+        busy: BusyHandling,
+    ) -> (SqliteEnvelopeStore, sqlx::SqliteConnection) {
+        let path = std::env::temp_dir().join(uuid::Uuid::new_v4().to_string());
+        SqliteEnvelopeStore::setup(&path, SqliteAutoVacuum::Full)
+            .await
+            .unwrap();
+
+        let options = SqliteConnectOptions::new()
+            .filename(&path)
+            .busy_timeout(Duration::ZERO);
+        let db = SqlitePoolOptions::new()
+            .max_connections(1)
+            .connect_with(options.clone())
+            .await
+            .unwrap();
+        let lock = sqlx::SqliteConnection::connect_with(&options)
+            .await
+            .unwrap();
+
+        let mut store = SqliteEnvelopeStore::new(0, db, Duration::from_millis(100));
+        store.busy = busy;
+        (store, lock)
+    }
+
+    #[tokio::test]
+    async fn test_insert_retries_while_locked() {
+        let (mut store, mut lock) = setup_contended_store(BusyHandling {
+            retries: 10,
+            backoff: Duration::from_millis(5),
+            fallback: EnvelopeSpoolBusyFallback::Reject,
+        })
+        .await;
+
+        // The other connection holds the write lock for a while.
+        sqlx::query("BEGIN IMMEDIATE")
+            .execute(&mut lock)
+            .await
+            .unwrap();
+        let release = tokio::spawn(async move {
+            sleep(Duration::from_millis(50)).await;
+            sqlx::query("COMMIT").execute(&mut lock).await.unwrap();
+        });
+
+        let envelopes = mock_envelopes(3);
+        store
+            .insert_batch(
+                envelopes
+                    .iter()
+                    .map(|e| DatabaseEnvelope::try_from(e.as_ref()).unwrap())
+                    .collect::<Vec<_>>()
+                    .try_into()
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        release.await.unwrap();
+
+        assert_eq!(store.total_count().await.unwrap(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_insert_fails_while_locked_without_retries() {
+        let (mut store, mut lock) = setup_contended_store(BusyHandling {
+            retries: 0,
+            backoff: Duration::from_millis(5),
+            fallback: EnvelopeSpoolBusyFallback::Reject,
+        })
+        .await;
+
+        sqlx::query("BEGIN IMMEDIATE")
+            .execute(&mut lock)
+            .await
+            .unwrap();
+
+        let envelopes = mock_envelopes(3);
+        let result = store
+            .insert_batch(
+                envelopes
+                    .iter()
+                    .map(|e| DatabaseEnvelope::try_from(e.as_ref()).unwrap())
+                    .collect::<Vec<_>>()
+                    .try_into()
+                    .unwrap(),
+            )
+            .await;
+        assert!(result.unwrap_err().is_busy());
+    }
 }
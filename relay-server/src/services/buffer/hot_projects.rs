@@ -0,0 +1,156 @@
+use std::error::Error;
+use std::path::{Path, PathBuf};
+
+use hashbrown::HashSet;
+use relay_base_schema::project::ProjectKey;
+use relay_config::Config;
+
+use crate::services::buffer::common::parse_project_keys;
+
+/// Maximum number of project keys that are persisted on shutdown.
+const MAX_PERSISTED_HOT_PROJECTS: usize = 1000;
+
+/// An error returned when reading or writing the hot projects file.
+#[derive(Debug, thiserror::Error)]
+pub enum HotProjectsError {
+    #[error("failed to access the hot projects file: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("failed to parse the hot projects file: {0}")]
+    Json(#[from] serde_json::Error),
+}
+
+/// Projects whose stacks are drained first after a restart of the buffer.
+///
+/// Hot projects come from the configuration and, if enabled, from a file that is written on
+/// shutdown with the most recently active projects.
+#[derive(Debug)]
+pub struct HotProjects {
+    /// Project keys listed in the configuration.
+    configured: Vec<ProjectKey>,
+    /// Location of the file which persists recently active projects across restarts.
+    path: Option<PathBuf>,
+}
+
+impl HotProjects {
+    /// Creates the [`HotProjects`] of a partition from the provided [`Config`].
+    pub fn new(partition_id: u8, config: &Config) -> Self {
+        let configured = parse_project_keys(config.spool_envelopes_hot_projects(), "hot project");
+
+        Self {
+            configured,
+            path: config.spool_envelopes_hot_projects_path(partition_id),
+        }
+    }
+
+    /// Returns all hot project keys, including the ones persisted by the previous shutdown.
+    pub async fn load(&self) -> HashSet<ProjectKey> {
+        let mut project_keys: HashSet<_> = self.configured.iter().copied().collect();
+
+        if let Some(path) = &self.path {
+            match read(path).await {
+                Ok(persisted) => project_keys.extend(persisted),
+                Err(HotProjectsError::Io(error))
+                    if error.kind() == std::io::ErrorKind::NotFound => {}
+                Err(error) => {
+                    relay_log::error!(
+                        error = &error as &dyn Error,
+                        "failed to load the hot projects of the buffer"
+                    );
+                }
+            }
+        }
+
+        project_keys
+    }
+
+    /// Persists the given project keys, ordered from most to least recently active.
+    ///
+    /// Does nothing if persisting hot projects is disabled.
+    pub async fn store(&self, project_keys: impl IntoIterator<Item = ProjectKey>) {
+        let Some(path) = &self.path else {
+            return;
+        };
+
+        let mut seen = HashSet::new();
+        let project_keys: Vec<_> = project_keys
+            .into_iter()
+            .filter(|project_key| seen.insert(*project_key))
+            .take(MAX_PERSISTED_HOT_PROJECTS)
+            .collect();
+
+        if let Err(error) = write(path, &project_keys).await {
+            relay_log::error!(
+                error = &error as &dyn Error,
+                "failed to store the hot projects of the buffer"
+            );
+        }
+    }
+}
+
+/// Reads a list of project keys from the file at `path`.
+async fn read(path: &Path) -> Result<Vec<ProjectKey>, HotProjectsError> {
+    let data = tokio::fs::read(path).await?;
+    Ok(serde_json::from_slice(&data)?)
+}
+
+/// Writes a list of project keys to the file at `path`.
+async fn write(path: &Path, project_keys: &[ProjectKey]) -> Result<(), HotProjectsError> {
+    let data = serde_json::to_vec(project_keys)?;
+    tokio::fs::write(path, data).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use uuid::Uuid;
+
+    use super::*;
+
+    fn mock_config(path: &str, hot_projects: &[&str]) -> Config {
+        Config::from_json_value(serde_json::json!({
+            "spool": {
+                "envelopes": {
+                    "path": path,
+                    "hot_projects": hot_projects,
+                    "persist_hot_projects": true
+                }
+            }
+        }))
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_round_trip() {
+        let path = std::env::temp_dir()
+            .join(Uuid::new_v4().to_string())
+            .into_os_string()
+            .into_string()
+            .unwrap();
+        let configured = "a94ae32be2584e0bbd7a4cbb95971fee";
+        let config = mock_config(&path, &[configured, "invalid"]);
+        let hot_projects = HotProjects::new(0, &config);
+
+        // Without a persisted file, only the valid configured keys are hot.
+        let project_keys = hot_projects.load().await;
+        assert_eq!(
+            project_keys,
+            HashSet::from([ProjectKey::parse(configured).unwrap()])
+        );
+
+        let persisted = [
+            ProjectKey::parse("b81ae32be2584e0bbd7a4cbb95971fe1").unwrap(),
+            ProjectKey::parse("c25ae32be2584e0bbd7a4cbb95971fe1").unwrap(),
+        ];
+        hot_projects.store(persisted).await;
+
+        // A new instance picks up the keys persisted by the previous one.
+        let project_keys = HotProjects::new(0, &config).load().await;
+        assert_eq!(project_keys.len(), 3);
+        assert!(project_keys.contains(&ProjectKey::parse(configured).unwrap()));
+        assert!(persisted.iter().all(|key| project_keys.contains(key)));
+
+        tokio::fs::remove_file(config.spool_envelopes_hot_projects_path(0).unwrap())
+            .await
+            .unwrap();
+    }
+}
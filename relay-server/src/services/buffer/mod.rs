@@ -1,57 +1,77 @@
 //! Types for buffering envelopes.
 
 use std::error::Error;
-use std::num::NonZeroU8;
+use std::num::{NonZeroU32, NonZeroU8};
 use std::sync::atomic::Ordering;
 use std::sync::atomic::{AtomicBool, AtomicU64};
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 use std::time::Duration;
 
 use ahash::RandomState;
 use chrono::DateTime;
 use chrono::Utc;
-use relay_config::Config;
+use relay_auth::RelayId;
+use relay_base_schema::project::ProjectKey;
+use relay_config::{Config, EnvelopeBufferFullPolicy, InvalidDscPolicy};
+use relay_event_schema::protocol::EventId;
 use relay_system::Receiver;
 use relay_system::ServiceSpawn;
 use relay_system::ServiceSpawnExt as _;
-use relay_system::{Addr, FromMessage, Interface, NoResponse, Service};
+use relay_system::{
+    Addr, AsyncResponse, FromMessage, Interface, NoResponse, SendError, Sender, Service,
+};
 use relay_system::{Controller, Shutdown};
+use serde::{Deserialize, Serialize};
 use tokio::sync::watch;
-use tokio::time::{timeout, Instant};
+use tokio::time::{timeout, Instant, MissedTickBehavior};
 
-use crate::envelope::Envelope;
-use crate::services::buffer::envelope_buffer::Peek;
+use crate::envelope::{Envelope, Item};
+use crate::services::buffer::envelope_buffer::{BufferedProjectState, Peek};
 use crate::services::global_config;
 use crate::services::outcome::DiscardReason;
 use crate::services::outcome::Outcome;
 use crate::services::outcome::TrackOutcome;
 use crate::services::processor::{EnvelopeProcessor, ProcessEnvelope, ProcessingGroup};
-use crate::services::projects::cache::{CheckedEnvelope, ProjectCacheHandle, ProjectChange};
+use crate::services::projects::cache::{
+    CheckedEnvelope, Project, ProjectCacheHandle, ProjectChange,
+};
 use crate::services::test_store::TestStore;
 use crate::statsd::RelayCounters;
 
-use crate::utils::ManagedEnvelope;
+use crate::utils::{ItemAction, ManagedEnvelope};
 use crate::MemoryChecker;
 use crate::MemoryStat;
 
+pub use envelope_buffer::AckToken;
+pub use envelope_buffer::Admission;
 // pub for benchmarks
+pub use envelope_buffer::BalanceStats;
+pub use envelope_buffer::BufferStats;
+pub use envelope_buffer::CountDiagnostics;
 pub use envelope_buffer::EnvelopeBufferError;
+pub use envelope_buffer::LiveSettings;
 // pub for benchmarks
 pub use envelope_buffer::PolymorphicEnvelopeBuffer;
+pub use envelope_buffer::PoppedEnvelope;
+pub use envelope_buffer::PushFailure;
+pub use envelope_buffer::StackSnapshot;
 // pub for benchmarks
 pub use envelope_stack::sqlite::SqliteEnvelopeStack;
 // pub for benchmarks
 pub use envelope_stack::EnvelopeStack;
 // pub for benchmarks
+pub use envelope_store::codec::DefaultCodec;
+// pub for benchmarks
 pub use envelope_store::sqlite::SqliteEnvelopeStore;
 
-use crate::services::projects::project::ProjectState;
-pub use common::ProjectKeyPair;
+use crate::services::projects::project::{ProjectInfo, ProjectState};
+pub use common::{EnvelopePreview, ItemPreview, ProjectKeyPair};
 
 mod common;
 mod envelope_buffer;
 mod envelope_stack;
 mod envelope_store;
+mod hot_projects;
 mod stack_provider;
 mod testutils;
 
@@ -60,6 +80,43 @@ mod testutils;
 pub enum EnvelopeBuffer {
     /// A fresh envelope that gets pushed into the buffer by the request handler.
     Push(Box<Envelope>),
+    /// Requests diagnostics on the envelope counts of the buffer.
+    CountDiagnostics(Sender<CountDiagnostics>),
+    /// Reloads the total envelope count from the store and responds with the updated counts.
+    ResetTotalCount(Sender<CountDiagnostics>),
+    /// Marks the stacks of a project as ready or not ready.
+    ///
+    /// Responds with `true` if the priority of at least one stack changed.
+    MarkReady(ProjectKey, bool, Sender<bool>),
+    /// Responds with up to the given number of stacks in the order in which they are popped.
+    QueueSnapshot(usize, Sender<Vec<StackSnapshot>>),
+    /// Responds with the number of stacks and envelopes in the buffer.
+    Stats(Sender<BufferStats>),
+    /// Responds with the distribution of envelopes across projects.
+    BalanceStats(Sender<BalanceStats>),
+    /// Responds with a preview of the buffered envelope with the given event id.
+    EventPreview(EventId, Sender<Option<EnvelopePreview>>),
+    /// Applies new settings to the running buffer.
+    UpdateSettings(LiveSettings, Sender<()>),
+    /// Pops up to the given number of envelopes and responds with them.
+    Drain(
+        usize,
+        Sender<Result<Vec<Box<Envelope>>, EnvelopeBufferError>>,
+    ),
+    /// Drained envelopes that get pushed back into the buffer.
+    ///
+    /// Responds once the envelopes have been pushed.
+    Restore(Vec<Box<Envelope>>, Sender<()>),
+    /// Pops up to the given number of envelopes and holds them until they are acknowledged.
+    DrainWithAck(
+        usize,
+        Duration,
+        Sender<Result<Vec<(Box<Envelope>, AckToken)>, EnvelopeBufferError>>,
+    ),
+    /// Acknowledges envelopes popped with [`Self::DrainWithAck`].
+    Ack(Vec<AckToken>, Sender<Result<usize, EnvelopeBufferError>>),
+    /// Applies the spool settings of a reloaded configuration.
+    ReloadConfig(Arc<Config>),
 }
 
 impl Interface for EnvelopeBuffer {}
@@ -72,12 +129,252 @@ impl FromMessage<Self> for EnvelopeBuffer {
     }
 }
 
+/// Returns the [`CountDiagnostics`] of a buffer partition.
+#[derive(Debug)]
+pub struct GetCountDiagnostics;
+
+impl FromMessage<GetCountDiagnostics> for EnvelopeBuffer {
+    type Response = AsyncResponse<CountDiagnostics>;
+
+    fn from_message(_: GetCountDiagnostics, sender: Sender<CountDiagnostics>) -> Self {
+        Self::CountDiagnostics(sender)
+    }
+}
+
+/// Reloads the total count of a buffer partition from its store.
+///
+/// Responds with the [`CountDiagnostics`] after the reload.
+#[derive(Debug)]
+pub struct ResetTotalCount;
+
+impl FromMessage<ResetTotalCount> for EnvelopeBuffer {
+    type Response = AsyncResponse<CountDiagnostics>;
+
+    fn from_message(_: ResetTotalCount, sender: Sender<CountDiagnostics>) -> Self {
+        Self::ResetTotalCount(sender)
+    }
+}
+
+/// Returns the first stacks of a buffer partition in the order in which they are popped.
+#[derive(Debug)]
+pub struct GetQueueSnapshot {
+    /// The maximum number of stacks to return.
+    pub limit: usize,
+}
+
+impl FromMessage<GetQueueSnapshot> for EnvelopeBuffer {
+    type Response = AsyncResponse<Vec<StackSnapshot>>;
+
+    fn from_message(message: GetQueueSnapshot, sender: Sender<Vec<StackSnapshot>>) -> Self {
+        Self::QueueSnapshot(message.limit, sender)
+    }
+}
+
+/// Returns the [`BufferStats`] of a buffer partition.
+#[derive(Debug)]
+pub struct GetBufferStats;
+
+impl FromMessage<GetBufferStats> for EnvelopeBuffer {
+    type Response = AsyncResponse<BufferStats>;
+
+    fn from_message(_: GetBufferStats, sender: Sender<BufferStats>) -> Self {
+        Self::Stats(sender)
+    }
+}
+
+/// Returns the [`BalanceStats`] of a buffer partition.
+#[derive(Debug)]
+pub struct GetBalanceStats;
+
+impl FromMessage<GetBalanceStats> for EnvelopeBuffer {
+    type Response = AsyncResponse<BalanceStats>;
+
+    fn from_message(_: GetBalanceStats, sender: Sender<BalanceStats>) -> Self {
+        Self::BalanceStats(sender)
+    }
+}
+
+/// Returns a preview of a buffered envelope of a buffer partition.
+#[derive(Debug)]
+pub struct GetEventPreview {
+    /// The event id of the envelope.
+    pub event_id: EventId,
+}
+
+impl FromMessage<GetEventPreview> for EnvelopeBuffer {
+    type Response = AsyncResponse<Option<EnvelopePreview>>;
+
+    fn from_message(message: GetEventPreview, sender: Sender<Option<EnvelopePreview>>) -> Self {
+        Self::EventPreview(message.event_id, sender)
+    }
+}
+
+/// Pops envelopes from a buffer partition and returns them to the sender.
+///
+/// The envelopes are removed from the buffer without emitting outcomes, so the sender takes over
+/// responsibility for them. If the buffer fails to pop, the envelopes popped so far are pushed
+/// back and an error is returned.
+#[derive(Debug)]
+pub struct DrainEnvelopes {
+    /// The maximum number of envelopes to pop.
+    pub count: usize,
+}
+
+impl FromMessage<DrainEnvelopes> for EnvelopeBuffer {
+    type Response = AsyncResponse<Result<Vec<Box<Envelope>>, EnvelopeBufferError>>;
+
+    fn from_message(
+        message: DrainEnvelopes,
+        sender: Sender<Result<Vec<Box<Envelope>>, EnvelopeBufferError>>,
+    ) -> Self {
+        Self::Drain(message.count, sender)
+    }
+}
+
+/// Pops envelopes from a buffer partition and holds them until they are acknowledged.
+///
+/// Unlike [`DrainEnvelopes`], the envelopes are pushed back into the buffer unless they are
+/// acknowledged with [`AckEnvelopes`] within the visibility timeout, see
+/// [`PolymorphicEnvelopeBuffer::pop_with_ack`]. Only the disk-based buffer supports this.
+#[derive(Debug)]
+pub struct DrainEnvelopesWithAck {
+    /// The maximum number of envelopes to pop.
+    pub count: usize,
+    /// The time after which envelopes that were not acknowledged are delivered again.
+    pub visibility_timeout: Duration,
+}
+
+impl FromMessage<DrainEnvelopesWithAck> for EnvelopeBuffer {
+    type Response = AsyncResponse<Result<Vec<(Box<Envelope>, AckToken)>, EnvelopeBufferError>>;
+
+    fn from_message(
+        message: DrainEnvelopesWithAck,
+        sender: Sender<Result<Vec<(Box<Envelope>, AckToken)>, EnvelopeBufferError>>,
+    ) -> Self {
+        Self::DrainWithAck(message.count, message.visibility_timeout, sender)
+    }
+}
+
+/// Acknowledges envelopes drained with [`DrainEnvelopesWithAck`] and removes them from the buffer.
+///
+/// Responds with the number of envelopes that were acknowledged before their visibility timeout
+/// passed.
+#[derive(Debug)]
+pub struct AckEnvelopes(pub Vec<AckToken>);
+
+impl FromMessage<AckEnvelopes> for EnvelopeBuffer {
+    type Response = AsyncResponse<Result<usize, EnvelopeBufferError>>;
+
+    fn from_message(
+        message: AckEnvelopes,
+        sender: Sender<Result<usize, EnvelopeBufferError>>,
+    ) -> Self {
+        Self::Ack(message.0, sender)
+    }
+}
+
+/// Pushes drained envelopes back into a buffer partition.
+///
+/// Responds once the envelopes have been pushed. Unlike a regular push, this ignores the capacity
+/// of the partition.
+#[derive(Debug)]
+pub struct RestoreEnvelopes(pub Vec<Box<Envelope>>);
+
+impl FromMessage<RestoreEnvelopes> for EnvelopeBuffer {
+    type Response = AsyncResponse<()>;
+
+    fn from_message(message: RestoreEnvelopes, sender: Sender<()>) -> Self {
+        Self::Restore(message.0, sender)
+    }
+}
+
+/// Applies new settings to a running buffer partition.
+#[derive(Debug)]
+pub struct UpdateSettings(pub LiveSettings);
+
+impl FromMessage<UpdateSettings> for EnvelopeBuffer {
+    type Response = AsyncResponse<()>;
+
+    fn from_message(message: UpdateSettings, sender: Sender<()>) -> Self {
+        Self::UpdateSettings(message.0, sender)
+    }
+}
+
+/// Marks the stacks of a project in a buffer partition as ready or not ready.
+#[derive(Debug)]
+pub struct MarkReady {
+    /// The project whose stacks are updated.
+    pub project_key: ProjectKey,
+    /// Whether the project is ready.
+    pub is_ready: bool,
+}
+
+impl FromMessage<MarkReady> for EnvelopeBuffer {
+    type Response = AsyncResponse<bool>;
+
+    fn from_message(message: MarkReady, sender: Sender<bool>) -> Self {
+        Self::MarkReady(message.project_key, message.is_ready, sender)
+    }
+}
+
+/// Number of envelopes moved at once by [`PartitionedEnvelopeBuffer::decommission`].
+const DECOMMISSION_BATCH_SIZE: usize = 100;
+
+/// Interval in which [`PartitionedEnvelopeBuffer::push`] checks for capacity while blocking.
+const CAPACITY_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Error returned by [`PartitionedEnvelopeBuffer::push`] if no partition has capacity.
+#[derive(Debug, thiserror::Error)]
+pub enum PushError {
+    /// The envelope was not pushed and is handed back to the caller.
+    #[error("all buffer partitions are full")]
+    Full(ManagedEnvelope),
+    /// The envelope was dropped and its outcome has been emitted.
+    #[error("envelope dropped because all buffer partitions are full")]
+    Dropped,
+}
+
+/// Error returned by [`PartitionedEnvelopeBuffer::decommission`].
+#[derive(Debug, thiserror::Error)]
+pub enum DecommissionError {
+    /// There is no partition with the given id.
+    #[error("unknown buffer partition {0}")]
+    UnknownPartition(u8),
+    /// The partition is the last one that accepts envelopes.
+    #[error("cannot decommission the last active buffer partition")]
+    LastPartition,
+    /// The partition could not be drained.
+    #[error("failed to drain the buffer partition")]
+    Drain(#[from] DrainError),
+}
+
+/// Error returned when draining envelopes from a [`PartitionedEnvelopeBuffer`].
+#[derive(Debug, thiserror::Error)]
+pub enum DrainError {
+    /// The buffer partition could not be reached.
+    #[error("failed to reach the buffer partition")]
+    Send(#[from] SendError),
+    /// The buffer partition failed to pop envelopes.
+    #[error("failed to pop envelopes from the buffer partition")]
+    Buffer(#[from] EnvelopeBufferError),
+}
+
+/// Identifies an envelope drained with [`PartitionedEnvelopeBuffer::drain_with_ack`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DrainAck {
+    /// The partition that holds the envelope.
+    pub partition_id: u8,
+    /// The acknowledgement token within the partition.
+    pub token: AckToken,
+}
+
 /// Abstraction that wraps a list of [`ObservableEnvelopeBuffer`]s to which [`Envelope`] are routed
 /// based on their [`ProjectKeyPair`].
 #[derive(Debug, Clone)]
 pub struct PartitionedEnvelopeBuffer {
     buffers: Arc<Vec<ObservableEnvelopeBuffer>>,
     hasher: RandomState,
+    scheduler: Arc<Mutex<PartitionScheduler>>,
 }
 
 impl PartitionedEnvelopeBuffer {
@@ -96,6 +393,9 @@ impl PartitionedEnvelopeBuffer {
         services: &dyn ServiceSpawn,
     ) -> Self {
         let mut envelope_buffers = Vec::with_capacity(partitions.get() as usize);
+        let weights = (0..partitions.get())
+            .map(|partition_id| config.spool_partition_weight(partition_id))
+            .collect();
         for partition_id in 0..partitions.get() {
             let envelope_buffer = EnvelopeBufferService::new(
                 partition_id,
@@ -114,35 +414,187 @@ impl PartitionedEnvelopeBuffer {
             envelope_buffers.push(envelope_buffer);
         }
 
-        Self {
+        let partitioned = Self {
             buffers: Arc::new(envelope_buffers),
             hasher: Self::build_hasher(),
-        }
+            scheduler: Arc::new(Mutex::new(PartitionScheduler::new(weights))),
+        };
+
+        #[cfg(unix)]
+        partitioned.reload_on_hangup(config);
+
+        partitioned
+    }
+
+    /// Reloads the config on SIGHUP and applies its spool settings to all partitions.
+    ///
+    /// The config is loaded once per signal with the same overrides as at startup, see
+    /// [`Config::reload`], and sent to every partition.
+    #[cfg(unix)]
+    fn reload_on_hangup(&self, config: Arc<Config>) {
+        let buffers = Arc::clone(&self.buffers);
+        relay_system::spawn!(async move {
+            use tokio::signal::unix::{signal, SignalKind};
+            let Ok(mut signal) = signal(SignalKind::hangup()) else {
+                return;
+            };
+            while let Some(()) = signal.recv().await {
+                relay_log::info!("SIGHUP received, reloading envelope buffer config");
+                match config.reload() {
+                    Ok(reloaded) => {
+                        let reloaded = Arc::new(reloaded);
+                        for buffer in buffers.iter() {
+                            buffer
+                                .addr
+                                .send(EnvelopeBuffer::ReloadConfig(Arc::clone(&reloaded)));
+                        }
+                    }
+                    Err(error) => {
+                        relay_log::error!("failed to reload envelope buffer config: {error:#}");
+                    }
+                }
+            }
+        });
     }
 
-    /// Returns the [`ObservableEnvelopeBuffer`] to which [`Envelope`]s having the supplied
+    /// Returns the id of the partition to which [`Envelope`]s having the supplied
     /// [`ProjectKeyPair`] will be sent.
     ///
     /// The rationale of using this partitioning strategy is to reduce memory usage across buffers
-    /// since each individual buffer will only take care of a subset of projects.
-    pub fn buffer(&self, project_key_pair: ProjectKeyPair) -> &ObservableEnvelopeBuffer {
-        let buffer_index =
-            (self.hasher.hash_one(project_key_pair) % self.buffers.len() as u64) as usize;
-        self.buffers
-            .get(buffer_index)
-            .expect("buffers should not be empty")
+    /// since each individual buffer will only take care of a subset of projects. The processing
+    /// group is not part of the hash, so all groups of a project share a partition.
+    ///
+    /// Pairs of a [decommissioned](Self::decommission) partition are spread across the remaining
+    /// partitions, while all other pairs keep their partition.
+    pub fn partition_id(&self, project_key_pair: ProjectKeyPair) -> u8 {
+        let ProjectKeyPair {
+            own_key,
+            sampling_key,
+            ..
+        } = project_key_pair;
+        let hash = self.hasher.hash_one((own_key, sampling_key));
+        let partition_id = (hash % self.buffers.len() as u64) as usize;
+        if !self.buffers[partition_id].is_decommissioned() {
+            return partition_id as u8;
+        }
+
+        let active: Vec<_> = (0..self.buffers.len())
+            .filter(|&partition_id| !self.buffers[partition_id].is_decommissioned())
+            .collect();
+        if active.is_empty() {
+            return partition_id as u8;
+        }
+        active[(hash % active.len() as u64) as usize] as u8
+    }
+
+    /// Returns the id of the partition to which the given [`Envelope`] will be sent.
+    ///
+    /// This is the partition requested by the client in the `X-Relay-Partition` header if routing
+    /// by header is enabled, and otherwise the partition of the envelope's [`ProjectKeyPair`].
+    pub fn envelope_partition_id(&self, envelope: &Envelope) -> u8 {
+        envelope
+            .meta()
+            .partition()
+            .filter(|&partition_id| {
+                self.buffers
+                    .get(partition_id as usize)
+                    .is_some_and(|buffer| !buffer.is_decommissioned())
+            })
+            .unwrap_or_else(|| self.partition_id(ProjectKeyPair::from_envelope(envelope)))
     }
 
     /// Returns `true` if all [`ObservableEnvelopeBuffer`]s have capacity to get new [`Envelope`]s.
     ///
     /// If no buffers are specified, the function returns `true`, assuming that there is capacity
-    /// if the buffer is not setup.
+    /// if the buffer is not setup. Decommissioned partitions are ignored.
     pub fn has_capacity(&self) -> bool {
         if self.buffers.is_empty() {
             return true;
         }
 
-        self.buffers.iter().all(|buffer| buffer.has_capacity())
+        self.buffers
+            .iter()
+            .filter(|buffer| !buffer.is_decommissioned())
+            .all(|buffer| buffer.has_capacity())
+    }
+
+    /// Pushes an envelope into the first partition that has capacity.
+    ///
+    /// The partition of the envelope's [`ProjectKeyPair`] is tried first, followed by all other
+    /// partitions in ascending order of their id. If all partitions are full, the given `policy`
+    /// applies. `block_timeout` is the maximum time to wait with
+    /// [`EnvelopeBufferFullPolicy::BlockUntilCapacity`].
+    ///
+    /// The observed capacity decides whether the envelope is accepted and selects the partition.
+    /// The partition does not check its capacity again, it only rejects envelopes with an outcome
+    /// that exceed the other limits of [`PolymorphicEnvelopeBuffer::check_admission`].
+    ///
+    /// Returns the id of the partition that received the envelope.
+    pub async fn push(
+        &self,
+        envelope: ManagedEnvelope,
+        policy: EnvelopeBufferFullPolicy,
+        block_timeout: Duration,
+    ) -> Result<u8, PushError> {
+        let mut envelope = match self.try_push(envelope) {
+            Ok(partition_id) => return Ok(partition_id),
+            Err(envelope) => envelope,
+        };
+
+        match policy {
+            EnvelopeBufferFullPolicy::Reject | EnvelopeBufferFullPolicy::Reject429 => {
+                Err(PushError::Full(envelope))
+            }
+            EnvelopeBufferFullPolicy::DropWithOutcome => {
+                envelope.reject(Outcome::Invalid(DiscardReason::Internal));
+                Err(PushError::Dropped)
+            }
+            EnvelopeBufferFullPolicy::BlockUntilCapacity => {
+                let deadline = Instant::now() + block_timeout;
+                while Instant::now() < deadline {
+                    tokio::time::sleep_until(deadline.min(Instant::now() + CAPACITY_POLL_INTERVAL))
+                        .await;
+
+                    envelope = match self.try_push(envelope) {
+                        Ok(partition_id) => return Ok(partition_id),
+                        Err(envelope) => envelope,
+                    };
+                }
+
+                Err(PushError::Full(envelope))
+            }
+        }
+    }
+
+    /// Pushes an envelope into the first partition that has capacity without waiting.
+    ///
+    /// See [`Self::push`] for the order in which partitions are tried.
+    fn try_push(&self, envelope: ManagedEnvelope) -> Result<u8, ManagedEnvelope> {
+        let home = self.envelope_partition_id(envelope.envelope());
+        let partitions = self.buffers.len();
+
+        for offset in 0..partitions {
+            let partition_id = (home as usize + offset) % partitions;
+            let buffer = &self.buffers[partition_id];
+            if buffer.is_decommissioned() || !buffer.has_capacity() {
+                continue;
+            }
+
+            if offset > 0 {
+                relay_log::debug!(
+                    partition_id,
+                    home_partition_id = home,
+                    "envelope pushed into fallback buffer partition"
+                );
+            }
+
+            buffer
+                .addr
+                .send(EnvelopeBuffer::Push(envelope.into_envelope()));
+            return Ok(partition_id as u8);
+        }
+
+        Err(envelope)
     }
 
     pub fn item_count(&self) -> u64 {
@@ -156,6 +608,320 @@ impl PartitionedEnvelopeBuffer {
             .sum()
     }
 
+    /// Returns the total size of attachments buffered across all partitions.
+    pub fn attachment_bytes(&self) -> u64 {
+        self.buffers
+            .iter()
+            .map(|buffer| buffer.attachment_bytes())
+            .sum()
+    }
+
+    /// Returns the [`CountDiagnostics`] of all partitions, ordered by partition id.
+    pub async fn count_diagnostics(&self) -> Result<Vec<CountDiagnostics>, SendError> {
+        futures::future::try_join_all(
+            self.buffers
+                .iter()
+                .map(|buffer| buffer.addr.send(GetCountDiagnostics)),
+        )
+        .await
+    }
+
+    /// Reloads the total counts of all partitions from their stores.
+    ///
+    /// Returns the updated [`CountDiagnostics`], ordered by partition id.
+    pub async fn reset_total_counts(&self) -> Result<Vec<CountDiagnostics>, SendError> {
+        futures::future::try_join_all(
+            self.buffers
+                .iter()
+                .map(|buffer| buffer.addr.send(ResetTotalCount)),
+        )
+        .await
+    }
+
+    /// Returns up to `limit` stacks of every partition in the order in which they are popped.
+    ///
+    /// The snapshots are ordered by partition id.
+    pub async fn queue_snapshots(
+        &self,
+        limit: usize,
+    ) -> Result<Vec<Vec<StackSnapshot>>, SendError> {
+        futures::future::try_join_all(
+            self.buffers
+                .iter()
+                .map(|buffer| buffer.addr.send(GetQueueSnapshot { limit })),
+        )
+        .await
+    }
+
+    /// Returns the [`BufferStats`] of every partition, ordered by partition id.
+    pub async fn stats(&self) -> Result<Vec<BufferStats>, SendError> {
+        futures::future::try_join_all(
+            self.buffers
+                .iter()
+                .map(|buffer| buffer.addr.send(GetBufferStats)),
+        )
+        .await
+    }
+
+    /// Returns the [`BalanceStats`] of every partition, ordered by partition id.
+    pub async fn balance_stats(&self) -> Result<Vec<BalanceStats>, SendError> {
+        futures::future::try_join_all(
+            self.buffers
+                .iter()
+                .map(|buffer| buffer.addr.send(GetBalanceStats)),
+        )
+        .await
+    }
+
+    /// Returns a preview of the buffered envelope with the given event id from any partition.
+    pub async fn event_preview(
+        &self,
+        event_id: EventId,
+    ) -> Result<Option<EnvelopePreview>, SendError> {
+        let previews = futures::future::try_join_all(
+            self.buffers
+                .iter()
+                .map(|buffer| buffer.addr.send(GetEventPreview { event_id })),
+        )
+        .await?;
+
+        Ok(previews.into_iter().flatten().next())
+    }
+
+    /// Returns the id of the partition that a worker pulling envelopes should serve next.
+    ///
+    /// Partitions with a backlog are served in proportion to their configured weights, see
+    /// `spool.envelopes.partition_weights`. Every partition with a backlog is served eventually,
+    /// so partitions with low weights are not starved. Among partitions that are equally due, the
+    /// one with the larger backlog is served first.
+    ///
+    /// Returns `None` if no partition has a backlog.
+    pub fn next_partition_to_serve(&self) -> Option<u8> {
+        let backlogs: Vec<_> = self
+            .buffers
+            .iter()
+            .map(|buffer| match buffer.is_decommissioned() {
+                true => 0,
+                false => buffer.item_count(),
+            })
+            .collect();
+
+        let mut scheduler = self
+            .scheduler
+            .lock()
+            .unwrap_or_else(|scheduler| scheduler.into_inner());
+        scheduler
+            .next(&backlogs)
+            .map(|partition_id| partition_id as u8)
+    }
+
+    /// Pops up to `count` envelopes from a single partition.
+    async fn drain_partition(
+        buffer: &ObservableEnvelopeBuffer,
+        count: usize,
+    ) -> Result<Vec<Box<Envelope>>, DrainError> {
+        Ok(buffer.addr.send(DrainEnvelopes { count }).await??)
+    }
+
+    /// Pops up to `count` envelopes from the partitions and holds them until they are acknowledged.
+    ///
+    /// Envelopes that are not acknowledged with [`Self::ack`] within `visibility_timeout` are
+    /// pushed back into their partition, see [`DrainEnvelopesWithAck`]. If a partition cannot be
+    /// drained, the error is returned and the envelopes drained so far are delivered again once
+    /// their visibility timeout passed.
+    ///
+    /// Draining starts at the partition returned by [`Self::next_partition_to_serve`] and continues
+    /// with the following partitions until `count` envelopes were drained. Workers that drain
+    /// small batches thereby serve partitions in proportion to their weights.
+    pub async fn drain_with_ack(
+        &self,
+        count: usize,
+        visibility_timeout: Duration,
+    ) -> Result<Vec<(Box<Envelope>, DrainAck)>, DrainError> {
+        // The observed backlogs lag behind, so partitions without an observed backlog are drained
+        // as well once the scheduled partition is exhausted.
+        let first = self.next_partition_to_serve().map_or(0, usize::from);
+        let partitions = self.buffers.len();
+
+        let mut envelopes = Vec::new();
+        for offset in 0..partitions {
+            let partition_id = (first + offset) % partitions;
+            let buffer = &self.buffers[partition_id];
+            let remaining = count.saturating_sub(envelopes.len());
+            if remaining == 0 {
+                break;
+            }
+
+            let message = DrainEnvelopesWithAck {
+                count: remaining,
+                visibility_timeout,
+            };
+            let drained = buffer.addr.send(message).await??;
+            envelopes.extend(drained.into_iter().map(|(envelope, token)| {
+                let partition_id = partition_id as u8;
+                (
+                    envelope,
+                    DrainAck {
+                        partition_id,
+                        token,
+                    },
+                )
+            }));
+        }
+
+        Ok(envelopes)
+    }
+
+    /// Acknowledges envelopes drained with [`Self::drain_with_ack`].
+    ///
+    /// Returns the number of envelopes that were acknowledged before their visibility timeout
+    /// passed. Acknowledgements of unknown partitions are ignored.
+    pub async fn ack(&self, acks: Vec<DrainAck>) -> Result<usize, DrainError> {
+        let mut partitions = vec![Vec::new(); self.buffers.len()];
+        for ack in acks {
+            if let Some(tokens) = partitions.get_mut(ack.partition_id as usize) {
+                tokens.push(ack.token);
+            }
+        }
+
+        let mut acked = 0;
+        for (buffer, tokens) in self.buffers.iter().zip(partitions) {
+            if !tokens.is_empty() {
+                acked += buffer.addr.send(AckEnvelopes(tokens)).await??;
+            }
+        }
+
+        Ok(acked)
+    }
+
+    /// Pushes drained envelopes back into the partitions of their [`ProjectKeyPair`].
+    ///
+    /// Unlike [`Self::push`], this ignores the capacity of the partitions, since the envelopes were
+    /// part of the buffer before they were drained. Waits until all partitions have pushed the
+    /// envelopes.
+    async fn restore_and_wait(&self, envelopes: Vec<Box<Envelope>>) -> Result<(), SendError> {
+        let mut partitions = vec![Vec::new(); self.buffers.len()];
+        for envelope in envelopes {
+            let partition_id = self.envelope_partition_id(&envelope);
+            partitions[partition_id as usize].push(envelope);
+        }
+
+        futures::future::try_join_all(
+            self.buffers
+                .iter()
+                .zip(partitions)
+                .filter(|(_, envelopes)| !envelopes.is_empty())
+                .map(|(buffer, envelopes)| buffer.addr.send(RestoreEnvelopes(envelopes))),
+        )
+        .await?;
+
+        Ok(())
+    }
+
+    /// Moves all envelopes of a partition to the remaining partitions.
+    ///
+    /// The partition stops receiving new envelopes, and the pairs routed to it are spread across
+    /// the remaining partitions, see [`Self::partition_id`]. Its envelopes are then drained
+    /// regardless of the readiness of their projects and restored into the partitions of their
+    /// pairs, which frees the storage of the partition. The envelope counts of all partitions are
+    /// updated as part of the drain and restore.
+    ///
+    /// Envelopes are moved in batches of [`DECOMMISSION_BATCH_SIZE`], and every batch is pushed
+    /// into the remaining partitions before the next one is drained. This bounds memory usage and
+    /// the number of envelopes in flight if Relay stops during the move.
+    ///
+    /// The service of the partition keeps running, so envelopes that were pushed concurrently are
+    /// still processed. Returns the number of moved envelopes once the partition is empty. If the
+    /// move fails, the partition receives envelopes again and keeps the envelopes that have not
+    /// been moved yet.
+    pub async fn decommission(&self, partition_id: u8) -> Result<usize, DecommissionError> {
+        let Some(buffer) = self.buffers.get(partition_id as usize) else {
+            return Err(DecommissionError::UnknownPartition(partition_id));
+        };
+        if buffer.is_decommissioned() {
+            return Ok(0);
+        }
+
+        let active = self
+            .buffers
+            .iter()
+            .filter(|buffer| !buffer.is_decommissioned())
+            .count();
+        if active <= 1 {
+            return Err(DecommissionError::LastPartition);
+        }
+
+        buffer.metrics.decommissioned.store(true, Ordering::Relaxed);
+        let count = match self.move_envelopes(buffer).await {
+            Ok(count) => count,
+            Err(error) => {
+                buffer
+                    .metrics
+                    .decommissioned
+                    .store(false, Ordering::Relaxed);
+                return Err(error.into());
+            }
+        };
+
+        relay_log::info!(
+            partition_id,
+            count,
+            "decommissioned envelope buffer partition"
+        );
+
+        Ok(count)
+    }
+
+    /// Moves envelopes in batches from the given partition to the partitions of their pairs until
+    /// the partition is empty.
+    async fn move_envelopes(&self, buffer: &ObservableEnvelopeBuffer) -> Result<usize, DrainError> {
+        let mut count = 0;
+        loop {
+            let envelopes = Self::drain_partition(buffer, DECOMMISSION_BATCH_SIZE).await?;
+            if envelopes.is_empty() {
+                return Ok(count);
+            }
+
+            count += envelopes.len();
+            self.restore_and_wait(envelopes).await?;
+        }
+    }
+
+    /// Applies new settings to all partitions.
+    ///
+    /// Every partition applies all settings at once, so a partition never runs with a partial
+    /// update of the settings.
+    pub async fn update_settings(&self, settings: LiveSettings) -> Result<(), SendError> {
+        futures::future::try_join_all(
+            self.buffers
+                .iter()
+                .map(|buffer| buffer.addr.send(UpdateSettings(settings.clone()))),
+        )
+        .await?;
+
+        Ok(())
+    }
+
+    /// Marks the stacks of a project as ready or not ready in all partitions.
+    ///
+    /// A project can have stacks in multiple partitions, since envelopes are partitioned by their
+    /// [`ProjectKeyPair`]. Returns `true` if the priority of a stack changed in any partition.
+    pub async fn mark_ready(
+        &self,
+        project_key: ProjectKey,
+        is_ready: bool,
+    ) -> Result<bool, SendError> {
+        let changed = futures::future::try_join_all(self.buffers.iter().map(|buffer| {
+            buffer.addr.send(MarkReady {
+                project_key,
+                is_ready,
+            })
+        }))
+        .await?;
+
+        Ok(changed.into_iter().any(|changed| changed))
+    }
+
     /// Builds a hasher with fixed seeds for consistent partitioning across Relay instances.
     fn build_hasher() -> RandomState {
         const K0: u64 = 0xd34db33f11223344;
@@ -167,11 +933,66 @@ impl PartitionedEnvelopeBuffer {
     }
 }
 
+/// Smooth weighted round-robin over the partitions of a [`PartitionedEnvelopeBuffer`].
+#[derive(Debug, Default)]
+struct PartitionScheduler {
+    /// The configured weight of every partition, defaulting to `1` for missing partitions.
+    weights: Vec<NonZeroU32>,
+    /// The credit every partition accumulated since it was last served.
+    credits: Vec<i64>,
+}
+
+impl PartitionScheduler {
+    fn new(weights: Vec<NonZeroU32>) -> Self {
+        let credits = vec![0; weights.len()];
+        Self { weights, credits }
+    }
+
+    fn weight(&self, partition_id: usize) -> i64 {
+        self.weights
+            .get(partition_id)
+            .map_or(1, |weight| i64::from(weight.get()))
+    }
+
+    /// Picks the next partition among the partitions with a non-zero backlog.
+    ///
+    /// Every eligible partition earns its weight in credits, and the partition with the most
+    /// credits is served and pays the total weight of all eligible partitions.
+    fn next(&mut self, backlogs: &[u64]) -> Option<usize> {
+        if self.credits.len() < backlogs.len() {
+            self.credits.resize(backlogs.len(), 0);
+        }
+        let eligible = || (0..backlogs.len()).filter(|&id| backlogs[id] > 0);
+
+        let mut total = 0;
+        for partition_id in eligible() {
+            let weight = self.weight(partition_id);
+            self.credits[partition_id] += weight;
+            total += weight;
+        }
+
+        let next = eligible().max_by(|&a, &b| {
+            self.credits[a]
+                .cmp(&self.credits[b])
+                .then(backlogs[a].cmp(&backlogs[b]))
+                // Prefer lower ids on ties, since `max_by` returns the last maximum.
+                .then(b.cmp(&a))
+        })?;
+
+        self.credits[next] -= total;
+        Some(next)
+    }
+}
+
 #[derive(Debug)]
 pub struct EnvelopeBufferMetrics {
     has_capacity: AtomicBool,
     item_count: AtomicU64,
     storage_size: AtomicU64,
+    attachment_bytes: AtomicU64,
+    /// Whether the partition no longer receives envelopes, see
+    /// [`PartitionedEnvelopeBuffer::decommission`].
+    decommissioned: AtomicBool,
 }
 
 /// Contains the services [`Addr`] and a watch channel to observe its state.
@@ -204,6 +1025,16 @@ impl ObservableEnvelopeBuffer {
     pub fn storage_size(&self) -> u64 {
         self.metrics.storage_size.load(Ordering::Relaxed)
     }
+
+    /// Returns the total size of attachments in the buffer.
+    pub fn attachment_bytes(&self) -> u64 {
+        self.metrics.attachment_bytes.load(Ordering::Relaxed)
+    }
+
+    /// Returns `true` if the buffer no longer receives envelopes.
+    pub fn is_decommissioned(&self) -> bool {
+        self.metrics.decommissioned.load(Ordering::Relaxed)
+    }
 }
 
 /// Services that the buffer service communicates with.
@@ -235,6 +1066,9 @@ pub struct EnvelopeBufferService {
 /// whenever a new message or a global config update comes in.
 const DEFAULT_SLEEP: Duration = Duration::from_secs(1);
 
+/// Interval in which the buffer reports the age distribution of its envelopes.
+const AGE_SAMPLE_INTERVAL: Duration = Duration::from_secs(10);
+
 impl EnvelopeBufferService {
     /// Creates a memory or disk based [`EnvelopeBufferService`], depending on the given config.
     pub fn new(
@@ -254,6 +1088,8 @@ impl EnvelopeBufferService {
                 has_capacity: AtomicBool::new(true),
                 item_count: AtomicU64::new(0),
                 storage_size: AtomicU64::new(0),
+                attachment_bytes: AtomicU64::new(0),
+                decommissioned: AtomicBool::new(false),
             }),
             sleep: Duration::ZERO,
         }
@@ -316,12 +1152,18 @@ impl EnvelopeBufferService {
     }
 
     /// Tries to pop an envelope for a ready project.
+    ///
+    /// `last_progress` is updated whenever an envelope leaves the buffer or the buffer is empty.
+    /// It is used to force progress once the buffer stalled for longer than the configured
+    /// maximum.
     async fn try_pop(
         partition_tag: &str,
         config: &Config,
         buffer: &mut PolymorphicEnvelopeBuffer,
         services: &Services,
+        last_progress: &mut Instant,
     ) -> Result<Duration, EnvelopeBufferError> {
+        let max_age = buffer.max_age();
         let sleep = match buffer.peek().await? {
             Peek::Empty => {
                 relay_statsd::metric!(
@@ -329,6 +1171,7 @@ impl EnvelopeBufferService {
                     peek_result = "empty",
                     partition_id = partition_tag
                 );
+                *last_progress = Instant::now();
 
                 DEFAULT_SLEEP // wait for reset by `handle_message`.
             }
@@ -337,7 +1180,7 @@ impl EnvelopeBufferService {
             }
             | Peek::NotReady {
                 last_received_at, ..
-            } if is_expired(last_received_at, config) => {
+            } if is_expired(last_received_at, max_age) => {
                 relay_statsd::metric!(
                     counter(RelayCounters::BufferTryPop) += 1,
                     peek_result = "expired",
@@ -348,7 +1191,12 @@ impl EnvelopeBufferService {
                     .await?
                     .expect("Element disappeared despite exclusive excess");
 
-                Self::drop_expired(envelope, services);
+                Self::reject(
+                    envelope,
+                    Outcome::Invalid(DiscardReason::Timestamp),
+                    services,
+                );
+                *last_progress = Instant::now();
 
                 Duration::ZERO // try next pop immediately
             }
@@ -362,14 +1210,30 @@ impl EnvelopeBufferService {
                     partition_id = partition_tag
                 );
 
-                Self::pop_and_forward(partition_tag, services, buffer, project_key_pair).await?;
+                match Self::pop_and_forward(
+                    partition_tag,
+                    config,
+                    services,
+                    buffer,
+                    project_key_pair,
+                )
+                .await
+                {
+                    Ok(true) => *last_progress = Instant::now(),
+                    Ok(false) => (),
+                    Err(error) => {
+                        buffer.report_pop_failure(project_key_pair);
+                        return Err(error);
+                    }
+                }
 
                 Duration::ZERO // try next pop immediately
             }
             Peek::NotReady {
                 project_key_pair,
                 next_project_fetch,
-                last_received_at: _,
+                self_contained,
+                ..
             } => {
                 relay_log::trace!("EnvelopeBufferService: project(s) of envelope not ready");
                 relay_statsd::metric!(
@@ -378,18 +1242,59 @@ impl EnvelopeBufferService {
                     partition_id = partition_tag
                 );
 
-                // We want to fetch the configs again, only if some time passed between the last
-                // peek of this not ready project key pair and the current peek. This is done to
+                if let Some(max_stall) = config.spool_envelopes_max_stall() {
+                    if last_progress.elapsed() >= max_stall
+                        && Self::force_forward(config, services, buffer, project_key_pair).await?
+                    {
+                        relay_log::warn!(
+                            tags.project_key = project_key_pair.own_key.as_str(),
+                            "envelope buffer stalled for {max_stall:?}, forced progress"
+                        );
+                        relay_statsd::metric!(
+                            counter(RelayCounters::BufferForcedProgress) += 1,
+                            partition_id = partition_tag
+                        );
+                        *last_progress = Instant::now();
+
+                        return Ok(Duration::ZERO);
+                    }
+                }
+
+                // We want to fetch the configs again, only if some time passed between the last
+                // peek of this not ready project key pair and the current peek. This is done to
                 // avoid flooding the project cache with `UpdateProject` messages.
                 if Instant::now() >= next_project_fetch {
-                    relay_log::trace!("EnvelopeBufferService: requesting project(s) update");
+                    // New stacks start out not ready if `default_ready` is disabled, even if their
+                    // projects are cached already. No project change is sent for cached projects,
+                    // so their stacks are marked ready here.
+                    if !config.spool_envelopes_default_ready() {
+                        let cached = project_key_pair.iter().all(|project_key| {
+                            !services
+                                .project_cache_handle
+                                .get(project_key)
+                                .state()
+                                .is_pending()
+                        });
+
+                        if cached {
+                            for project_key in project_key_pair.iter() {
+                                buffer.mark_ready(&project_key, true);
+                            }
+                            return Ok(Duration::ZERO);
+                        }
+                    }
 
-                    let own_key = project_key_pair.own_key;
-                    let sampling_key = project_key_pair.sampling_key;
+                    relay_log::trace!("EnvelopeBufferService: requesting project(s) update");
 
-                    services.project_cache_handle.fetch(own_key);
-                    if sampling_key != own_key {
-                        services.project_cache_handle.fetch(sampling_key);
+                    // Self-contained stacks need no sampling decision, so they only wait for
+                    // their own project.
+                    services
+                        .project_cache_handle
+                        .fetch(project_key_pair.own_key);
+                    if !self_contained {
+                        services
+                            .project_cache_handle
+                            .fetch(project_key_pair.sampling_key);
                     }
 
                     // Deprioritize the stack to prevent head-of-line blocking and update the next fetch
@@ -404,17 +1309,41 @@ impl EnvelopeBufferService {
         Ok(sleep)
     }
 
-    fn drop_expired(envelope: Box<Envelope>, services: &Services) {
+    /// Rejects an envelope that is dropped from the buffer with the given outcome.
+    fn reject(envelope: Box<Envelope>, outcome: Outcome, services: &Services) {
+        Self::managed_envelope(envelope, services).reject(outcome);
+    }
+
+    /// Wraps an envelope of the buffer to emit outcomes for it.
+    ///
+    /// Outcomes are attributed to the project key of the envelope itself, not the key of the stack
+    /// it was buffered in. If the project is available, the outcomes carry its full scoping,
+    /// otherwise the scoping is derived from the request meta.
+    fn managed_envelope(envelope: Box<Envelope>, services: &Services) -> ManagedEnvelope {
+        let project_key = envelope.meta().public_key();
         let mut managed_envelope = ManagedEnvelope::new(
             envelope,
             services.outcome_aggregator.clone(),
             services.test_store.clone(),
             ProcessingGroup::Ungrouped,
         );
-        managed_envelope.reject(Outcome::Invalid(DiscardReason::Timestamp));
+
+        let project = services.project_cache_handle.get(project_key);
+        if let ProjectState::Enabled(info) = project.state() {
+            let scoping = info.scope_request(managed_envelope.meta());
+            managed_envelope.scope(scoping);
+        }
+
+        managed_envelope
     }
 
-    async fn handle_message(buffer: &mut PolymorphicEnvelopeBuffer, message: EnvelopeBuffer) {
+    async fn handle_message(
+        config: &Config,
+        buffer: &mut PolymorphicEnvelopeBuffer,
+        services: &Services,
+        memory_checker: &MemoryChecker,
+        message: EnvelopeBuffer,
+    ) {
         match message {
             EnvelopeBuffer::Push(envelope) => {
                 // NOTE: This function assumes that a project state update for the relevant
@@ -422,9 +1351,243 @@ impl EnvelopeBufferService {
                 // For better separation of concerns, this prefetch should be triggered from here
                 // once buffer V1 has been removed.
                 relay_log::trace!("EnvelopeBufferService: received push message");
-                Self::push(buffer, envelope).await;
+                Self::push(config, buffer, services, envelope).await;
+            }
+            EnvelopeBuffer::Restore(envelopes, sender) => {
+                Self::push_all(buffer, services, envelopes).await;
+                sender.send(());
+            }
+            EnvelopeBuffer::CountDiagnostics(sender) => {
+                sender.send(buffer.count_diagnostics());
+            }
+            EnvelopeBuffer::ResetTotalCount(sender) => {
+                buffer.reset_total_count().await;
+                sender.send(buffer.count_diagnostics());
+            }
+            EnvelopeBuffer::MarkReady(project_key, is_ready, sender) => {
+                sender.send(buffer.mark_ready(&project_key, is_ready));
+            }
+            EnvelopeBuffer::QueueSnapshot(limit, sender) => {
+                sender.send(buffer.queue_snapshot(limit));
+            }
+            EnvelopeBuffer::Stats(sender) => {
+                sender.send(buffer.stats());
+            }
+            EnvelopeBuffer::BalanceStats(sender) => {
+                sender.send(buffer.balance_stats());
+            }
+            EnvelopeBuffer::EventPreview(event_id, sender) => {
+                let preview = buffer
+                    .event_preview(event_id)
+                    .await
+                    .unwrap_or_else(|error| {
+                        relay_log::error!(
+                            error = &error as &dyn std::error::Error,
+                            "failed to read event preview from the envelope buffer"
+                        );
+                        None
+                    });
+                sender.send(preview);
+            }
+            EnvelopeBuffer::UpdateSettings(settings, sender) => {
+                buffer.apply_settings(&settings);
+                sender.send(());
+            }
+            EnvelopeBuffer::ReloadConfig(reloaded) => {
+                Self::reload_config(buffer, services, &reloaded, memory_checker.clone()).await;
+            }
+            EnvelopeBuffer::Drain(count, sender) => {
+                sender.send(Self::drain(buffer, services, count).await);
+            }
+            EnvelopeBuffer::DrainWithAck(count, visibility_timeout, sender) => {
+                Self::redeliver_expired(buffer, services).await;
+                sender.send(Self::drain_with_ack(buffer, count, visibility_timeout).await);
+            }
+            EnvelopeBuffer::Ack(tokens, sender) => {
+                sender.send(Self::ack(buffer, tokens).await);
+            }
+        };
+    }
+
+    /// Pops up to `count` envelopes regardless of the readiness of their projects and holds them
+    /// until they are acknowledged.
+    ///
+    /// If the buffer fails to pop, the error is returned. The envelopes popped so far remain held
+    /// and are delivered again once their visibility timeout passed.
+    async fn drain_with_ack(
+        buffer: &mut PolymorphicEnvelopeBuffer,
+        count: usize,
+        visibility_timeout: Duration,
+    ) -> Result<Vec<(Box<Envelope>, AckToken)>, EnvelopeBufferError> {
+        let mut envelopes = Vec::new();
+        while envelopes.len() < count {
+            match buffer.pop_with_ack(visibility_timeout).await {
+                Ok(Some(popped)) => envelopes.push(popped),
+                Ok(None) => break,
+                Err(error) => {
+                    relay_log::error!(
+                        error = &error as &dyn std::error::Error,
+                        "failed to drain envelope from buffer"
+                    );
+                    return Err(error);
+                }
+            }
+        }
+        Ok(envelopes)
+    }
+
+    /// Pushes drained envelopes whose visibility timeout passed back into the buffer.
+    ///
+    /// Redelivered envelopes that exceed the maximum stack depth are rejected. If the held
+    /// envelopes cannot be read, they remain held and are redelivered by a later call. Envelopes
+    /// that were read but could not be pushed are rejected.
+    async fn redeliver_expired(buffer: &mut PolymorphicEnvelopeBuffer, services: &Services) {
+        match buffer.redeliver_expired().await {
+            Ok(evicted) => {
+                for envelope in evicted {
+                    Self::reject(
+                        envelope,
+                        Outcome::Invalid(DiscardReason::StackDepth),
+                        services,
+                    );
+                }
+            }
+            Err(failure) => {
+                relay_log::error!(
+                    error = &failure.error as &dyn Error,
+                    "failed to redeliver drained envelopes"
+                );
+                Self::reject_push_failure(failure, services);
+            }
+        }
+    }
+
+    /// Rejects the envelopes returned with a failed push.
+    ///
+    /// Evicted envelopes are rejected for exceeding the stack depth, envelopes that were not
+    /// pushed are rejected as internal errors.
+    fn reject_push_failure(failure: PushFailure, services: &Services) {
+        for envelope in failure.evicted {
+            Self::reject(
+                envelope,
+                Outcome::Invalid(DiscardReason::StackDepth),
+                services,
+            );
+        }
+        for envelope in failure.unpushed {
+            Self::reject(
+                envelope,
+                Outcome::Invalid(DiscardReason::Internal),
+                services,
+            );
+        }
+    }
+
+    /// Acknowledges envelopes drained with [`Self::drain_with_ack`].
+    ///
+    /// Returns the number of envelopes that were still held.
+    async fn ack(
+        buffer: &mut PolymorphicEnvelopeBuffer,
+        tokens: Vec<AckToken>,
+    ) -> Result<usize, EnvelopeBufferError> {
+        let mut acked = 0;
+        for token in tokens {
+            if buffer.ack(token).await? {
+                acked += 1;
+            }
+        }
+        Ok(acked)
+    }
+
+    /// Pops up to `count` envelopes regardless of the readiness of their projects.
+    ///
+    /// If the buffer fails to pop, the envelopes popped so far are pushed back and the error is
+    /// returned, so that the caller does not mistake a partial drain for an empty buffer.
+    async fn drain(
+        buffer: &mut PolymorphicEnvelopeBuffer,
+        services: &Services,
+        count: usize,
+    ) -> Result<Vec<Box<Envelope>>, EnvelopeBufferError> {
+        let mut envelopes = Vec::new();
+        while envelopes.len() < count {
+            match buffer.pop().await {
+                Ok(Some(envelope)) => envelopes.push(envelope),
+                Ok(None) => break,
+                Err(error) => {
+                    relay_log::error!(
+                        error = &error as &dyn std::error::Error,
+                        "failed to drain envelope from buffer"
+                    );
+                    Self::push_all(buffer, services, envelopes).await;
+                    return Err(error);
+                }
+            }
+        }
+        Ok(envelopes)
+    }
+
+    /// Applies the spool settings of a reloaded configuration without dropping envelopes.
+    ///
+    /// See [`PolymorphicEnvelopeBuffer::reload_config`]. If the buffer cannot be migrated to a new
+    /// backend, it keeps running with the current one.
+    async fn reload_config(
+        buffer: &mut PolymorphicEnvelopeBuffer,
+        services: &Services,
+        config: &Config,
+        memory_checker: MemoryChecker,
+    ) {
+        match buffer.reload_config(config, memory_checker).await {
+            Ok(evicted) => {
+                for envelope in evicted {
+                    Self::reject(
+                        envelope,
+                        Outcome::Invalid(DiscardReason::StackDepth),
+                        services,
+                    );
+                }
+            }
+            Err((error, envelopes)) => {
+                relay_log::error!(
+                    error = &error as &dyn Error,
+                    "failed to reload envelope buffer config"
+                );
+                Self::push_all(buffer, services, envelopes).await;
+            }
+        }
+    }
+
+    /// Updates the stacks of a project that became available.
+    ///
+    /// Envelopes of disabled projects are evicted and rejected right away instead of waiting for
+    /// their stacks to be popped.
+    async fn handle_project_ready(
+        buffer: &mut PolymorphicEnvelopeBuffer,
+        services: &Services,
+        project_key: ProjectKey,
+    ) {
+        let state = match services.project_cache_handle.get(project_key).state() {
+            ProjectState::Disabled => BufferedProjectState::Disabled,
+            _ => BufferedProjectState::Ready,
+        };
+
+        let evicted = match buffer.set_project_state(&project_key, state).await {
+            Ok(evicted) => evicted,
+            Err((error, evicted)) => {
+                relay_log::error!(
+                    error = &error as &dyn std::error::Error,
+                    "failed to evict envelopes of disabled project"
+                );
+                evicted
             }
         };
+
+        for envelope in evicted {
+            Self::reject(
+                envelope,
+                Outcome::Invalid(DiscardReason::ProjectId),
+                services,
+            );
+        }
     }
 
     async fn handle_shutdown(buffer: &mut PolymorphicEnvelopeBuffer, message: Shutdown) -> bool {
@@ -449,21 +1612,192 @@ impl EnvelopeBufferService {
         false
     }
 
-    async fn push(buffer: &mut PolymorphicEnvelopeBuffer, envelope: Box<Envelope>) {
-        if let Err(e) = buffer.push(envelope).await {
-            relay_log::error!(
-                error = &e as &dyn std::error::Error,
-                "failed to push envelope"
+    async fn push(
+        config: &Config,
+        buffer: &mut PolymorphicEnvelopeBuffer,
+        services: &Services,
+        envelope: Box<Envelope>,
+    ) {
+        let Some(envelope) = Self::check_relay_path(config.relay_id(), services, envelope) else {
+            return;
+        };
+        let Some(envelope) = Self::check_sampling_key(config, services, envelope) else {
+            return;
+        };
+
+        if buffer.is_retry(&envelope) {
+            Self::reject(
+                envelope,
+                Outcome::Invalid(DiscardReason::Duplicate),
+                services,
             );
+            return;
+        }
+
+        if buffer.admission(&envelope) == Admission::RejectFull {
+            // A full buffer makes room by dropping envelopes of unsampled traces first. The
+            // envelope itself was accepted by the full policy of the endpoint and is pushed in any
+            // case.
+            match buffer.evict_unsampled(&envelope).await {
+                Ok(Some(evicted)) => {
+                    Self::reject(
+                        evicted,
+                        Outcome::Invalid(DiscardReason::BufferEvictedUnsampled),
+                        services,
+                    );
+                }
+                Ok(None) => (),
+                Err(e) => {
+                    relay_log::error!(
+                        error = &e as &dyn std::error::Error,
+                        "failed to evict unsampled envelope"
+                    );
+                }
+            }
+        }
+
+        // Envelopes split by processing group are admitted individually, like they are counted.
+        let mut admitted = Vec::new();
+        for (project_key_pair, envelope) in buffer.split_grouped(envelope) {
+            match buffer.check_admission(&envelope).discard_reason() {
+                Some(reason) => Self::reject(envelope, Outcome::Invalid(reason), services),
+                None => admitted.push((project_key_pair, envelope)),
+            }
+        }
+
+        match buffer.push_grouped(admitted).await {
+            Ok(evicted) => {
+                for evicted in evicted {
+                    Self::reject(
+                        evicted,
+                        Outcome::Invalid(DiscardReason::StackDepth),
+                        services,
+                    );
+                }
+            }
+            Err(failure) => {
+                relay_log::error!(
+                    error = &failure.error as &dyn std::error::Error,
+                    "failed to push envelope"
+                );
+                Self::reject_push_failure(failure, services);
+            }
+        }
+    }
+
+    /// Appends this Relay to the path of the envelope and rejects envelopes that passed it before.
+    ///
+    /// The path is part of the envelope headers, so it is forwarded to the upstream along with the
+    /// envelope. Relays without credentials do not have an id and skip the check. Returns `None`
+    /// if the envelope was rejected.
+    fn check_relay_path(
+        relay_id: Option<&RelayId>,
+        services: &Services,
+        mut envelope: Box<Envelope>,
+    ) -> Option<Box<Envelope>> {
+        let Some(&relay_id) = relay_id else {
+            return Some(envelope);
+        };
+
+        if envelope.append_relay_path(relay_id) {
+            return Some(envelope);
+        }
+
+        relay_log::warn!(
+            tags.project_key = envelope.meta().public_key().as_str(),
+            "dropping envelope forwarded in a loop"
+        );
+        Self::reject(
+            envelope,
+            Outcome::Invalid(DiscardReason::RelayLoop),
+            services,
+        );
+        None
+    }
+
+    /// Applies `sampling.on_invalid_dsc` to an envelope whose sampling project is disabled.
+    ///
+    /// A disabled sampling project never provides a sampling decision, so the envelope would wait
+    /// for it in its own stack. Returns `None` if the envelope was rejected.
+    fn check_sampling_key(
+        config: &Config,
+        services: &Services,
+        mut envelope: Box<Envelope>,
+    ) -> Option<Box<Envelope>> {
+        let policy = config.sampling_on_invalid_dsc();
+        if policy == InvalidDscPolicy::Keep {
+            return Some(envelope);
+        }
+
+        let sampling_key = envelope
+            .sampling_key()
+            .filter(|&sampling_key| sampling_key != envelope.meta().public_key());
+        let Some(sampling_key) = sampling_key else {
+            return Some(envelope);
+        };
+
+        let project = services.project_cache_handle.get(sampling_key);
+        if !matches!(project.state(), ProjectState::Disabled) {
+            return Some(envelope);
+        }
+
+        relay_log::debug!(
+            tags.sampling_key = sampling_key.as_str(),
+            "sampling project of envelope is disabled"
+        );
+        match policy {
+            InvalidDscPolicy::Keep => Some(envelope),
+            InvalidDscPolicy::Strip => {
+                envelope.remove_dsc();
+                Some(envelope)
+            }
+            InvalidDscPolicy::Reject => {
+                Self::reject(
+                    envelope,
+                    Outcome::Invalid(DiscardReason::InvalidDsc),
+                    services,
+                );
+                None
+            }
+        }
+    }
+
+    /// Pushes envelopes back into the buffer that were part of it before.
+    ///
+    /// The envelopes were admitted when they were first pushed, so the admission checks and the
+    /// stack creation limit for fresh ingest do not apply.
+    async fn push_all(
+        buffer: &mut PolymorphicEnvelopeBuffer,
+        services: &Services,
+        envelopes: Vec<Box<Envelope>>,
+    ) {
+        match buffer.push_all(envelopes).await {
+            Ok(evicted) => {
+                for evicted in evicted {
+                    Self::reject(
+                        evicted,
+                        Outcome::Invalid(DiscardReason::StackDepth),
+                        services,
+                    );
+                }
+            }
+            Err(failure) => {
+                relay_log::error!(
+                    error = &failure.error as &dyn std::error::Error,
+                    "failed to push envelopes"
+                );
+                Self::reject_push_failure(failure, services);
+            }
         }
     }
 
     async fn pop_and_forward(
         partition_tag: &str,
+        config: &Config,
         services: &Services,
         buffer: &mut PolymorphicEnvelopeBuffer,
         project_key_pair: ProjectKeyPair,
-    ) -> Result<(), EnvelopeBufferError> {
+    ) -> Result<bool, EnvelopeBufferError> {
         let own_key = project_key_pair.own_key;
         let own_project = services.project_cache_handle.get(own_key);
         // We try to load the own project state and bail in case it's pending.
@@ -477,7 +1811,7 @@ impl EnvelopeBufferService {
                     partition_id = &partition_tag
                 );
 
-                return Ok(());
+                return Ok(false);
             }
         };
 
@@ -496,7 +1830,7 @@ impl EnvelopeBufferService {
                         partition_id = &partition_tag
                     );
 
-                    return Ok(());
+                    return Ok(false);
                 }
             }
         } else {
@@ -514,15 +1848,98 @@ impl EnvelopeBufferService {
         // If the own project state is disabled, we want to drop the envelope and early return since
         // we can't do much about it.
         let Some(own_project_info) = own_project_info else {
-            let mut managed_envelope = ManagedEnvelope::new(
+            Self::reject(
                 envelope,
-                services.outcome_aggregator.clone(),
-                services.test_store.clone(),
-                ProcessingGroup::Ungrouped,
+                Outcome::Invalid(DiscardReason::ProjectId),
+                services,
+            );
+
+            return Ok(true);
+        };
+
+        Self::forward(
+            config,
+            services,
+            &own_project,
+            own_project_info,
+            sampling_project_info,
+            envelope,
+        )
+        .await;
+
+        Ok(true)
+    }
+
+    /// Forwards the oldest envelope of the next-in-line stack regardless of its readiness.
+    ///
+    /// The envelope is processed without a dynamic sampling decision unless the sampling project
+    /// is already available. An envelope cannot be processed without the config of its own
+    /// project, so if the own project is still pending, the envelope stays in the buffer and this
+    /// returns `false`. The stack is then handled like any other stack that is not ready.
+    async fn force_forward(
+        config: &Config,
+        services: &Services,
+        buffer: &mut PolymorphicEnvelopeBuffer,
+        project_key_pair: ProjectKeyPair,
+    ) -> Result<bool, EnvelopeBufferError> {
+        let own_project = services.project_cache_handle.get(project_key_pair.own_key);
+        let own_project_info = match own_project.state() {
+            ProjectState::Enabled(info) => Some(info.clone()),
+            ProjectState::Disabled => None,
+            ProjectState::Pending => return Ok(false),
+        };
+
+        let envelope = buffer
+            .pop_oldest()
+            .await?
+            .expect("Element disappeared despite exclusive excess");
+
+        let Some(own_project_info) = own_project_info else {
+            Self::reject(
+                envelope,
+                Outcome::Invalid(DiscardReason::ProjectId),
+                services,
             );
-            managed_envelope.reject(Outcome::Invalid(DiscardReason::ProjectId));
+            return Ok(true);
+        };
+
+        let sampling_project_info = if project_key_pair.has_distinct_sampling_key() {
+            match services
+                .project_cache_handle
+                .get(project_key_pair.sampling_key)
+                .state()
+            {
+                ProjectState::Enabled(info) => Some(info.clone()),
+                ProjectState::Disabled | ProjectState::Pending => None,
+            }
+        } else {
+            Some(own_project_info.clone())
+        };
+
+        Self::forward(
+            config,
+            services,
+            &own_project,
+            own_project_info,
+            sampling_project_info,
+            envelope,
+        )
+        .await;
+
+        Ok(true)
+    }
 
-            return Ok(());
+    /// Splits the envelope by processing group and sends the parts to the processor.
+    async fn forward(
+        config: &Config,
+        services: &Services,
+        own_project: &Project<'_>,
+        own_project_info: Arc<ProjectInfo>,
+        sampling_project_info: Option<Arc<ProjectInfo>>,
+        envelope: Box<Envelope>,
+    ) {
+        let Some(envelope) = Self::strip_expired_items(envelope, config, services) else {
+            return;
         };
 
         // We only extract the sampling project info if both projects belong to the same org.
@@ -554,8 +1971,39 @@ impl EnvelopeBufferService {
                 reservoir_counters,
             });
         }
+    }
 
-        Ok(())
+    /// Removes all items from the envelope that outlived their retention or the maximum age
+    /// configured for their item type, see `spool.envelopes.item_max_age`.
+    ///
+    /// An outcome is emitted for every removed item. Returns `None` if no items are left.
+    fn strip_expired_items(
+        envelope: Box<Envelope>,
+        config: &Config,
+        services: &Services,
+    ) -> Option<Box<Envelope>> {
+        let received_at = envelope.received_at();
+        let is_item_expired = |item: &Item| {
+            item.is_retention_expired(received_at)
+                || config
+                    .spool_envelopes_item_max_age(item.ty().as_str())
+                    .is_some_and(|max_age| is_expired(received_at, max_age))
+        };
+
+        if !envelope.items().any(is_item_expired) {
+            return Some(envelope);
+        }
+
+        let mut managed_envelope = Self::managed_envelope(envelope, services);
+        managed_envelope.retain_items(|item| match is_item_expired(item) {
+            true => ItemAction::Drop(Outcome::Invalid(DiscardReason::Timestamp)),
+            false => ItemAction::Keep,
+        });
+
+        // Outcomes for the removed items have been emitted, the remaining items are still owned by
+        // the returned envelope.
+        let envelope = managed_envelope.into_envelope();
+        (!envelope.is_empty()).then_some(envelope)
     }
 
     fn update_observable_state(&self, buffer: &mut PolymorphicEnvelopeBuffer) {
@@ -568,13 +2016,16 @@ impl EnvelopeBufferService {
         self.metrics
             .item_count
             .store(buffer.item_count(), Ordering::Relaxed);
+        self.metrics
+            .attachment_bytes
+            .store(buffer.attachment_bytes(), Ordering::Relaxed);
     }
 }
 
-fn is_expired(last_received_at: DateTime<Utc>, config: &Config) -> bool {
+fn is_expired(last_received_at: DateTime<Utc>, max_age: Duration) -> bool {
     (Utc::now() - last_received_at)
         .to_std()
-        .is_ok_and(|age| age > config.spool_envelopes_max_age())
+        .is_ok_and(|age| age > max_age)
 }
 
 impl Service for EnvelopeBufferService {
@@ -587,19 +2038,32 @@ impl Service for EnvelopeBufferService {
         let services = self.services.clone();
 
         let dequeue = Arc::<AtomicBool>::new(true.into());
+        let mut last_progress = Instant::now();
 
-        let mut buffer =
-            PolymorphicEnvelopeBuffer::from_config(self.partition_id, &config, memory_checker)
-                .await
-                .expect("failed to start the envelope buffer service");
+        let mut buffer = PolymorphicEnvelopeBuffer::from_config(
+            self.partition_id,
+            &config,
+            memory_checker.clone(),
+        )
+        .await
+        .expect("failed to start the envelope buffer service");
 
-        buffer.initialize().await;
+        buffer
+            .initialize()
+            .await
+            .expect("failed to initialize the envelope buffer");
+
+        // Envelopes that were drained but not acknowledged before a restart are delivered again.
+        // Envelopes held past the restart are redelivered periodically below.
+        Self::redeliver_expired(&mut buffer, &services).await;
 
         // We convert the partition id to string to use it as a tag for all the metrics.
-        let partition_tag = self.partition_id.to_string();
+        let partition_tag = common::partition_tag(self.partition_id, &config);
 
         let mut shutdown = Controller::shutdown_handle();
         let mut project_changes = self.services.project_cache_handle.changes();
+        let mut age_sample_interval = tokio::time::interval(AGE_SAMPLE_INTERVAL);
+        age_sample_interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
 
         #[cfg(unix)]
         {
@@ -627,7 +2091,7 @@ impl Service for EnvelopeBufferService {
                 // so we do not exceed the buffer capacity by starving the dequeue.
                 // on the other hand, prioritizing old messages violates the LIFO design.
                 _ = self.ready_to_pop(&buffer, dequeue.load(Ordering::Relaxed)) => {
-                    match Self::try_pop(&partition_tag, &config, &mut buffer, &services).await {
+                    match Self::try_pop(&partition_tag, &config, &mut buffer, &services, &mut last_progress).await {
                             Ok(new_sleep) => {
                                 sleep = new_sleep;
                             }
@@ -642,7 +2106,7 @@ impl Service for EnvelopeBufferService {
                 change = project_changes.recv() => {
                     match change {
                             Ok(ProjectChange::Ready(project_key)) => {
-                                buffer.mark_ready(&project_key, true);
+                                Self::handle_project_ready(&mut buffer, &services, project_key).await;
                             },
                             Ok(ProjectChange::Evicted(project_key)) => {
                                 buffer.mark_ready(&project_key, false);
@@ -653,7 +2117,14 @@ impl Service for EnvelopeBufferService {
                         sleep = Duration::ZERO;
                 }
                 Some(message) = rx.recv() => {
-                    Self::handle_message(&mut buffer, message).await;
+                    Self::handle_message(
+                        &config,
+                        &mut buffer,
+                        &services,
+                        &memory_checker,
+                        message,
+                    )
+                    .await;
                         sleep = Duration::ZERO;
                 }
                 shutdown = shutdown.notified() => {
@@ -666,6 +2137,12 @@ impl Service for EnvelopeBufferService {
                 Ok(()) = global_config_rx.changed() => {
                     sleep = Duration::ZERO;
                 }
+                _ = age_sample_interval.tick() => {
+                    buffer.sample_envelope_ages();
+                    buffer.remove_empty_init_stacks().await;
+                    Self::redeliver_expired(&mut buffer, &services).await;
+                    sleep = Duration::ZERO;
+                }
                 else => break,
             }
 
@@ -693,14 +2170,18 @@ impl Service for EnvelopeBufferService {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::envelope::{ContentType, ItemType};
+    use crate::services::outcome::DiscardItemType;
     use crate::services::projects::project::{ProjectInfo, ProjectState};
     use crate::testutils::new_envelope;
     use crate::MemoryStat;
     use chrono::Utc;
-    use relay_base_schema::project::ProjectKey;
+    use relay_base_schema::organization::OrganizationId;
+    use relay_base_schema::project::{ProjectId, ProjectKey};
     use relay_dynamic_config::GlobalConfig;
-    use relay_quotas::DataCategory;
+    use relay_quotas::{DataCategory, Scoping};
     use relay_system::TokioServiceSpawn;
+    use std::num::NonZeroU64;
     use std::time::Duration;
     use tokio::sync::mpsc;
     use uuid::Uuid;
@@ -777,31 +2258,177 @@ mod tests {
     }
 
     #[tokio::test(start_paused = true)]
-    async fn pop_with_global_config_changes() {
+    async fn update_settings_changes_capacity() {
         let EnvelopeBufferServiceResult {
             service,
-            global_tx,
-            envelope_processor_rx,
-            project_cache_handle,
+            global_tx: _global_tx,
+            envelope_processor_rx: _envelope_processor_rx,
+            project_cache_handle: _project_cache_handle,
             outcome_aggregator_rx: _outcome_aggregator_rx,
-            ..
         } = envelope_buffer_service(None, global_config::Status::Pending);
 
-        let addr = service.start_detached();
+        let ObservableEnvelopeBuffer { addr, metrics } = service.start_in(&TokioServiceSpawn);
 
-        let envelope = new_envelope(false, "foo");
-        let project_key = envelope.meta().public_key();
-        let project_info = Arc::new(ProjectInfo::default());
-        project_cache_handle
-            .test_set_project_state(project_key, ProjectState::Enabled(project_info));
-        addr.send(EnvelopeBuffer::Push(envelope.clone()));
+        addr.send(EnvelopeBuffer::Push(new_envelope(false, "foo")));
+        addr.send(EnvelopeBuffer::Push(new_envelope(false, "foo")));
+        tokio::time::advance(Duration::from_millis(100)).await;
+        assert!(metrics.has_capacity.load(Ordering::Relaxed));
 
-        tokio::time::sleep(Duration::from_millis(1000)).await;
+        addr.send(UpdateSettings(LiveSettings {
+            max_total_count: NonZeroU64::new(2),
+            ..Default::default()
+        }))
+        .await
+        .unwrap();
+        tokio::time::advance(Duration::from_millis(100)).await;
+        assert!(!metrics.has_capacity.load(Ordering::Relaxed));
 
-        assert_eq!(envelope_processor_rx.len(), 0);
+        addr.send(UpdateSettings(LiveSettings {
+            max_total_count: NonZeroU64::new(3),
+            ..Default::default()
+        }))
+        .await
+        .unwrap();
+        tokio::time::advance(Duration::from_millis(100)).await;
+        assert!(metrics.has_capacity.load(Ordering::Relaxed));
+    }
 
-        global_tx.send_replace(global_config::Status::Ready(Arc::new(
-            GlobalConfig::default(),
+    #[test]
+    fn live_settings_reject_unsafe_settings() {
+        let settings: LiveSettings =
+            serde_json::from_str(r#"{"max_total_count": 10, "max_envelope_delay_secs": 60}"#)
+                .unwrap();
+        assert_eq!(settings.max_total_count, NonZeroU64::new(10));
+        assert_eq!(settings.max_stack_depth, None);
+
+        // Settings that require a restart are rejected.
+        assert!(serde_json::from_str::<LiveSettings>(r#"{"path": "/tmp/spool"}"#).is_err());
+        assert!(serde_json::from_str::<LiveSettings>(r#"{"partitions": 2}"#).is_err());
+        // Limits cannot be set to zero.
+        assert!(serde_json::from_str::<LiveSettings>(r#"{"max_total_count": 0}"#).is_err());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn push_rejects_retries() {
+        let EnvelopeBufferServiceResult {
+            service,
+            mut outcome_aggregator_rx,
+            ..
+        } = envelope_buffer_service(
+            Some(serde_json::json!({
+                "spool": {
+                    "envelopes": {
+                        "fingerprint_window": 10
+                    }
+                }
+            })),
+            global_config::Status::Pending,
+        );
+
+        let addr = service.start_detached();
+
+        let envelope = new_envelope(false, "foo");
+        addr.send(EnvelopeBuffer::Push(envelope.clone()));
+        addr.send(EnvelopeBuffer::Push(envelope));
+
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        let outcome = outcome_aggregator_rx.try_recv().unwrap();
+        assert_eq!(outcome.outcome, Outcome::Invalid(DiscardReason::Duplicate));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn restore_bypasses_stack_creation_limit() {
+        let EnvelopeBufferServiceResult {
+            service,
+            mut outcome_aggregator_rx,
+            ..
+        } = envelope_buffer_service(
+            Some(serde_json::json!({
+                "spool": {
+                    "envelopes": {
+                        "max_new_stacks_per_sec": 1
+                    }
+                }
+            })),
+            global_config::Status::Pending,
+        );
+
+        let addr = service.start_detached();
+
+        // The envelopes belong to two different stacks.
+        let envelopes = vec![new_envelope(false, "foo"), new_envelope(true, "foo")];
+        addr.send(RestoreEnvelopes(envelopes)).await.unwrap();
+
+        let diagnostics = addr.send(GetCountDiagnostics).await.unwrap();
+        assert_eq!(diagnostics.total_count, 2);
+        assert!(outcome_aggregator_rx.try_recv().is_err());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn push_admits_split_envelopes_individually() {
+        let EnvelopeBufferServiceResult {
+            service,
+            mut outcome_aggregator_rx,
+            ..
+        } = envelope_buffer_service(
+            Some(serde_json::json!({
+                "spool": {
+                    "envelopes": {
+                        "split_processing_groups": true,
+                        "max_envelope_attachment_bytes": 10
+                    }
+                }
+            })),
+            global_config::Status::Pending,
+        );
+
+        let addr = service.start_detached();
+
+        // The attachment stays with the transaction, the session is split into its own envelope.
+        let mut envelope = new_envelope(false, "foo");
+        let mut item = Item::new(ItemType::Attachment);
+        item.set_payload(ContentType::OctetStream, "0123456789abcdef");
+        envelope.add_item(item);
+        envelope.add_item(Item::new(ItemType::Session));
+        addr.send(EnvelopeBuffer::Push(envelope));
+
+        // Only the envelope with the oversized attachment is rejected.
+        let diagnostics = addr.send(GetCountDiagnostics).await.unwrap();
+        assert_eq!(diagnostics.total_count, 1);
+        let outcome = outcome_aggregator_rx.try_recv().unwrap();
+        assert_eq!(
+            outcome.outcome,
+            Outcome::Invalid(DiscardReason::TooLarge(DiscardItemType::Attachment))
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn pop_with_global_config_changes() {
+        let EnvelopeBufferServiceResult {
+            service,
+            global_tx,
+            envelope_processor_rx,
+            project_cache_handle,
+            outcome_aggregator_rx: _outcome_aggregator_rx,
+            ..
+        } = envelope_buffer_service(None, global_config::Status::Pending);
+
+        let addr = service.start_detached();
+
+        let envelope = new_envelope(false, "foo");
+        let project_key = envelope.meta().public_key();
+        let project_info = Arc::new(ProjectInfo::default());
+        project_cache_handle
+            .test_set_project_state(project_key, ProjectState::Enabled(project_info));
+        addr.send(EnvelopeBuffer::Push(envelope.clone()));
+
+        tokio::time::sleep(Duration::from_millis(1000)).await;
+
+        assert_eq!(envelope_processor_rx.len(), 0);
+
+        global_tx.send_replace(global_config::Status::Ready(Arc::new(
+            GlobalConfig::default(),
         )));
 
         tokio::time::sleep(Duration::from_millis(1000)).await;
@@ -809,6 +2436,42 @@ mod tests {
         assert_eq!(envelope_processor_rx.len(), 1);
     }
 
+    #[tokio::test(start_paused = true)]
+    async fn pop_cached_project_without_default_ready() {
+        let EnvelopeBufferServiceResult {
+            service,
+            global_tx: _global_tx,
+            envelope_processor_rx,
+            project_cache_handle,
+            outcome_aggregator_rx: _outcome_aggregator_rx,
+            ..
+        } = envelope_buffer_service(
+            Some(serde_json::json!({
+                "spool": {
+                    "envelopes": {
+                        "default_ready": false
+                    }
+                }
+            })),
+            global_config::Status::Ready(Arc::new(GlobalConfig::default())),
+        );
+
+        let addr = service.start_detached();
+
+        // The project is cached before its stack is created, so there is no project change that
+        // marks the new stack ready.
+        let envelope = new_envelope(false, "foo");
+        let project_key = envelope.meta().public_key();
+        let project_info = Arc::new(ProjectInfo::default());
+        project_cache_handle
+            .test_set_project_state(project_key, ProjectState::Enabled(project_info));
+        addr.send(EnvelopeBuffer::Push(envelope));
+
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        assert_eq!(envelope_processor_rx.len(), 1);
+    }
+
     #[tokio::test(start_paused = true)]
     async fn pop_with_project_state_changes() {
         let EnvelopeBufferServiceResult {
@@ -853,6 +2516,194 @@ mod tests {
         assert_eq!(envelope_processor_rx.len(), 0);
     }
 
+    #[tokio::test(start_paused = true)]
+    async fn test_max_stall_forces_progress() {
+        let EnvelopeBufferServiceResult {
+            service,
+            mut envelope_processor_rx,
+            project_cache_handle,
+            global_tx: _global_tx,
+            outcome_aggregator_rx: _outcome_aggregator_rx,
+        } = envelope_buffer_service(
+            Some(serde_json::json!({
+                "spool": {
+                    "envelopes": {
+                        "max_stall": 5
+                    }
+                }
+            })),
+            global_config::Status::Ready(Arc::new(GlobalConfig::default())),
+        );
+
+        let addr = service.start_detached();
+
+        // The own project is available, but the sampling project never becomes ready.
+        let envelope = new_envelope(true, "foo");
+        let project_key = envelope.meta().public_key();
+        let sampling_key = envelope.sampling_key().unwrap();
+        assert_ne!(project_key, sampling_key);
+        project_cache_handle.test_set_project_state(
+            project_key,
+            ProjectState::Enabled(Arc::new(ProjectInfo::default())),
+        );
+        project_cache_handle.test_set_project_state(sampling_key, ProjectState::Pending);
+        addr.send(EnvelopeBuffer::Push(envelope));
+
+        tokio::time::sleep(Duration::from_secs(3)).await;
+        assert_eq!(envelope_processor_rx.len(), 0);
+
+        tokio::time::sleep(Duration::from_secs(5)).await;
+        assert_eq!(envelope_processor_rx.len(), 1);
+
+        // The envelope is processed without a dynamic sampling decision.
+        let Some(EnvelopeProcessor::ProcessEnvelope(message)) = envelope_processor_rx.recv().await
+        else {
+            panic!("expected a process envelope message");
+        };
+        assert!(message.sampling_project_info.is_none());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_max_stall_keeps_pending_project() {
+        let EnvelopeBufferServiceResult {
+            service,
+            mut envelope_processor_rx,
+            project_cache_handle,
+            global_tx: _global_tx,
+            mut outcome_aggregator_rx,
+        } = envelope_buffer_service(
+            Some(serde_json::json!({
+                "spool": {
+                    "envelopes": {
+                        "max_stall": 5
+                    }
+                }
+            })),
+            global_config::Status::Ready(Arc::new(GlobalConfig::default())),
+        );
+
+        let addr = service.start_detached();
+
+        // The own project config never becomes available.
+        let envelope = new_envelope(false, "foo");
+        let project_key = envelope.meta().public_key();
+        project_cache_handle.test_set_project_state(project_key, ProjectState::Pending);
+        addr.send(EnvelopeBuffer::Push(envelope));
+
+        tokio::time::sleep(Duration::from_secs(8)).await;
+
+        // Without a project config, the envelope must neither be processed nor dropped.
+        assert!(envelope_processor_rx.try_recv().is_err());
+        assert!(outcome_aggregator_rx.try_recv().is_err());
+        let diagnostics = addr.send(GetCountDiagnostics).await.unwrap();
+        assert_eq!(diagnostics.total_count, 1);
+
+        // Once the project config is available, the stalled envelope is forwarded.
+        project_cache_handle.test_set_project_state(
+            project_key,
+            ProjectState::Enabled(Arc::new(ProjectInfo::default())),
+        );
+        tokio::time::sleep(Duration::from_secs(8)).await;
+        assert_eq!(envelope_processor_rx.len(), 1);
+    }
+
+    /// Pushes an envelope whose sampling project is disabled with the given `on_invalid_dsc`.
+    async fn push_invalid_dsc(
+        on_invalid_dsc: &str,
+    ) -> (
+        mpsc::UnboundedReceiver<EnvelopeProcessor>,
+        mpsc::UnboundedReceiver<TrackOutcome>,
+    ) {
+        let EnvelopeBufferServiceResult {
+            service,
+            envelope_processor_rx,
+            project_cache_handle,
+            outcome_aggregator_rx,
+            global_tx: _global_tx,
+        } = envelope_buffer_service(
+            Some(serde_json::json!({
+                "sampling": {
+                    "on_invalid_dsc": on_invalid_dsc
+                }
+            })),
+            global_config::Status::Ready(Arc::new(GlobalConfig::default())),
+        );
+
+        let addr = service.start_detached();
+
+        let envelope = new_envelope(true, "foo");
+        let project_key = envelope.meta().public_key();
+        let sampling_key = envelope.sampling_key().unwrap();
+        project_cache_handle.test_set_project_state(
+            project_key,
+            ProjectState::Enabled(Arc::new(ProjectInfo::default())),
+        );
+        project_cache_handle.test_set_project_state(sampling_key, ProjectState::Disabled);
+        addr.send(EnvelopeBuffer::Push(envelope));
+
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        (envelope_processor_rx, outcome_aggregator_rx)
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn invalid_dsc_is_stripped() {
+        let (mut envelope_processor_rx, mut outcome_aggregator_rx) =
+            push_invalid_dsc("strip").await;
+
+        let Some(EnvelopeProcessor::ProcessEnvelope(message)) = envelope_processor_rx.recv().await
+        else {
+            panic!("expected a process envelope message");
+        };
+        assert!(message.envelope.envelope().dsc().is_none());
+        assert!(outcome_aggregator_rx.try_recv().is_err());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn invalid_dsc_is_rejected() {
+        let (mut envelope_processor_rx, mut outcome_aggregator_rx) =
+            push_invalid_dsc("reject").await;
+
+        assert!(envelope_processor_rx.try_recv().is_err());
+        let outcome = outcome_aggregator_rx.try_recv().unwrap();
+        assert_eq!(outcome.outcome, Outcome::Invalid(DiscardReason::InvalidDsc));
+    }
+
+    #[tokio::test]
+    async fn relay_loop_is_rejected() {
+        let EnvelopeBufferServiceResult {
+            service,
+            mut outcome_aggregator_rx,
+            ..
+        } = envelope_buffer_service(None, global_config::Status::Pending);
+
+        let relay_id = RelayId::new_v4();
+        let other_relay_id = RelayId::new_v4();
+
+        // The envelope passes this Relay and another one, which forwards it back to this Relay.
+        let envelope = new_envelope(false, "foo");
+        let envelope =
+            EnvelopeBufferService::check_relay_path(Some(&relay_id), &service.services, envelope)
+                .unwrap();
+        let envelope = Envelope::parse_bytes(envelope.to_vec().unwrap().into()).unwrap();
+        let envelope = EnvelopeBufferService::check_relay_path(
+            Some(&other_relay_id),
+            &service.services,
+            envelope,
+        )
+        .unwrap();
+        assert_eq!(envelope.relay_path(), [relay_id, other_relay_id]);
+        assert!(outcome_aggregator_rx.try_recv().is_err());
+
+        let envelope = Envelope::parse_bytes(envelope.to_vec().unwrap().into()).unwrap();
+        let rejected =
+            EnvelopeBufferService::check_relay_path(Some(&relay_id), &service.services, envelope);
+        assert!(rejected.is_none());
+
+        let outcome = outcome_aggregator_rx.try_recv().unwrap();
+        assert_eq!(outcome.outcome, Outcome::Invalid(DiscardReason::RelayLoop));
+    }
+
     #[tokio::test]
     async fn pop_requires_memory_capacity() {
         let EnvelopeBufferServiceResult {
@@ -965,15 +2816,214 @@ mod tests {
     }
 
     #[tokio::test(start_paused = true)]
-    async fn test_partitioned_buffer() {
-        let (_global_tx, global_rx) = watch::channel(global_config::Status::Ready(Arc::new(
-            GlobalConfig::default(),
-        )));
-        let (outcome_aggregator, _outcome_rx) = Addr::custom();
-        let project_cache_handle = ProjectCacheHandle::for_test();
-        let (envelope_processor, mut envelope_processor_rx) = Addr::custom();
+    async fn old_envelope_outcome_is_attributed_to_project() {
+        let EnvelopeBufferServiceResult {
+            service,
+            envelope_processor_rx,
+            project_cache_handle,
+            mut outcome_aggregator_rx,
+            global_tx: _global_tx,
+        } = envelope_buffer_service(
+            None,
+            global_config::Status::Ready(Arc::new(GlobalConfig::default())),
+        );
 
-        // Create common services for both buffers
+        let config = service.config.clone();
+        let addr = service.start_detached();
+
+        let mut envelope = new_envelope(false, "foo");
+        let project_key = envelope.meta().public_key();
+        envelope.meta_mut().set_received_at(
+            Utc::now()
+                - chrono::Duration::seconds(2 * config.spool_envelopes_max_age().as_secs() as i64),
+        );
+
+        project_cache_handle.test_set_project_state(
+            project_key,
+            ProjectState::Enabled(Arc::new(ProjectInfo {
+                project_id: Some(ProjectId::new(42)),
+                organization_id: Some(OrganizationId::new(7)),
+                ..Default::default()
+            })),
+        );
+        addr.send(EnvelopeBuffer::Push(envelope));
+
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        assert_eq!(envelope_processor_rx.len(), 0);
+
+        let outcomes: Vec<_> = std::iter::from_fn(|| outcome_aggregator_rx.try_recv().ok())
+            .filter(|outcome| outcome.category == DataCategory::Transaction)
+            .collect();
+        assert_eq!(outcomes.len(), 1);
+        assert_eq!(
+            outcomes[0].outcome,
+            Outcome::Invalid(DiscardReason::Timestamp)
+        );
+        assert_eq!(outcomes[0].scoping.project_key, project_key);
+        assert_eq!(outcomes[0].scoping.project_id, ProjectId::new(42));
+        assert_eq!(outcomes[0].scoping.organization_id, OrganizationId::new(7));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn expired_items_are_stripped() {
+        let EnvelopeBufferServiceResult {
+            service,
+            mut envelope_processor_rx,
+            project_cache_handle,
+            mut outcome_aggregator_rx,
+            global_tx: _global_tx,
+        } = envelope_buffer_service(
+            None,
+            global_config::Status::Ready(Arc::new(GlobalConfig::default())),
+        );
+
+        let addr = service.start_detached();
+
+        let mut envelope = new_envelope(false, "foo");
+        let project_key = envelope.meta().public_key();
+        envelope
+            .meta_mut()
+            .set_received_at(Utc::now() - chrono::Duration::hours(2));
+        // Only the first attachment has a retention which already passed.
+        let mut attachments = envelope
+            .items_mut()
+            .filter(|item| item.ty() == &ItemType::Attachment);
+        attachments.next().unwrap().set_retention(0);
+        attachments.next().unwrap().set_retention(1);
+
+        project_cache_handle.test_set_project_state(
+            project_key,
+            ProjectState::Enabled(Arc::new(ProjectInfo::default())),
+        );
+        addr.send(EnvelopeBuffer::Push(envelope));
+
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        let Some(EnvelopeProcessor::ProcessEnvelope(message)) = envelope_processor_rx.recv().await
+        else {
+            panic!("expected a process envelope message");
+        };
+        let envelope = message.envelope.envelope();
+        assert_eq!(envelope.len(), 2);
+        assert!(envelope.items().all(|item| item.retention() != Some(0)));
+
+        let outcome = outcome_aggregator_rx.try_recv().unwrap();
+        assert_eq!(outcome.outcome, Outcome::Invalid(DiscardReason::Timestamp));
+        assert_eq!(outcome.category, DataCategory::Attachment);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn items_expire_by_type() {
+        let EnvelopeBufferServiceResult {
+            service,
+            mut envelope_processor_rx,
+            project_cache_handle,
+            mut outcome_aggregator_rx,
+            global_tx: _global_tx,
+        } = envelope_buffer_service(
+            Some(serde_json::json!({
+                "spool": {
+                    "envelopes": {
+                        "item_max_age": {
+                            "transaction": 30
+                        }
+                    }
+                }
+            })),
+            global_config::Status::Ready(Arc::new(GlobalConfig::default())),
+        );
+
+        let addr = service.start_detached();
+
+        let mut envelope = new_envelope(false, "foo");
+        envelope.add_item(Item::new(ItemType::Event));
+        let project_key = envelope.meta().public_key();
+        envelope
+            .meta_mut()
+            .set_received_at(Utc::now() - chrono::Duration::seconds(60));
+
+        project_cache_handle.test_set_project_state(
+            project_key,
+            ProjectState::Enabled(Arc::new(ProjectInfo::default())),
+        );
+        addr.send(EnvelopeBuffer::Push(envelope));
+
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        // The transaction expired, the error and its attachments are processed.
+        let Some(EnvelopeProcessor::ProcessEnvelope(message)) = envelope_processor_rx.recv().await
+        else {
+            panic!("expected a process envelope message");
+        };
+        let envelope = message.envelope.envelope();
+        assert!(envelope.items().any(|item| item.ty() == &ItemType::Event));
+        assert!(envelope
+            .items()
+            .all(|item| item.ty() != &ItemType::Transaction));
+        assert!(envelope_processor_rx.try_recv().is_err());
+
+        let mut categories = Vec::new();
+        while let Ok(outcome) = outcome_aggregator_rx.try_recv() {
+            assert_eq!(outcome.outcome, Outcome::Invalid(DiscardReason::Timestamp));
+            categories.push(outcome.category);
+        }
+        assert!(categories.contains(&DataCategory::Transaction));
+        assert!(!categories.contains(&DataCategory::Error));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_count_diagnostics() {
+        let EnvelopeBufferServiceResult {
+            service,
+            project_cache_handle,
+            global_tx: _global_tx,
+            envelope_processor_rx: _envelope_processor_rx,
+            outcome_aggregator_rx: _outcome_aggregator_rx,
+        } = envelope_buffer_service(
+            None,
+            global_config::Status::Ready(Arc::new(GlobalConfig::default())),
+        );
+
+        let addr = service.start_detached();
+
+        let envelope = new_envelope(false, "foo");
+        project_cache_handle
+            .test_set_project_state(envelope.meta().public_key(), ProjectState::Pending);
+        addr.send(EnvelopeBuffer::Push(envelope));
+
+        let diagnostics = addr.send(GetCountDiagnostics).await.unwrap();
+        assert_eq!(
+            diagnostics,
+            CountDiagnostics {
+                total_count: 1,
+                tracked_count: 1,
+                initialized: true,
+            }
+        );
+
+        // The in-memory store is always empty, so only the tracked envelope remains.
+        let diagnostics = addr.send(ResetTotalCount).await.unwrap();
+        assert_eq!(
+            diagnostics,
+            CountDiagnostics {
+                total_count: 0,
+                tracked_count: 1,
+                initialized: true,
+            }
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_partitioned_buffer() {
+        let (_global_tx, global_rx) = watch::channel(global_config::Status::Ready(Arc::new(
+            GlobalConfig::default(),
+        )));
+        let (outcome_aggregator, _outcome_rx) = Addr::custom();
+        let project_cache_handle = ProjectCacheHandle::for_test();
+        let (envelope_processor, mut envelope_processor_rx) = Addr::custom();
+
+        // Create common services for both buffers
         let services = Services {
             envelope_processor,
             project_cache_handle: project_cache_handle.clone(),
@@ -1007,6 +3057,7 @@ mod tests {
         let partitioned = PartitionedEnvelopeBuffer {
             buffers: Arc::new(vec![observable1, observable2]),
             hasher: PartitionedEnvelopeBuffer::build_hasher(),
+            scheduler: Default::default(),
         };
 
         // Create two envelopes with different project keys
@@ -1033,5 +3084,639 @@ mod tests {
         assert!(envelope_processor_rx.recv().await.is_some());
         assert!(envelope_processor_rx.recv().await.is_some());
         assert!(envelope_processor_rx.is_empty());
+
+        // The partition decision is stable and always within the available partitions.
+        let project_key_pair = ProjectKeyPair::new(project_key, project_key);
+        let partition_id = partitioned.partition_id(project_key_pair);
+        assert!(partition_id < 2);
+        assert_eq!(partitioned.partition_id(project_key_pair), partition_id);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_partitioned_mark_ready() {
+        // Keep the global config pending, so that the buffers do not pop and the readiness of
+        // stacks only changes through `mark_ready`.
+        let (_global_tx, global_rx) = watch::channel(global_config::Status::Pending);
+        let (outcome_aggregator, _outcome_rx) = Addr::custom();
+        let project_cache_handle = ProjectCacheHandle::for_test();
+        let (envelope_processor, _envelope_processor_rx) = Addr::custom();
+
+        let services = Services {
+            envelope_processor,
+            project_cache_handle: project_cache_handle.clone(),
+            outcome_aggregator,
+            test_store: Addr::dummy(),
+        };
+        let config = Arc::new(Config::default());
+
+        let buffers = (0..2)
+            .map(|partition_id| {
+                EnvelopeBufferService::new(
+                    partition_id,
+                    config.clone(),
+                    MemoryStat::default(),
+                    global_rx.clone(),
+                    services.clone(),
+                )
+                .start_in(&TokioServiceSpawn)
+            })
+            .collect();
+
+        let partitioned = PartitionedEnvelopeBuffer {
+            buffers: Arc::new(buffers),
+            hasher: PartitionedEnvelopeBuffer::build_hasher(),
+            scheduler: Default::default(),
+        };
+
+        // The project has a stack in both partitions, which are not ready while it is pending.
+        let envelope = new_envelope(false, "foo");
+        let project_key = envelope.meta().public_key();
+        project_cache_handle.test_set_project_state(project_key, ProjectState::Pending);
+        for buffer in partitioned.buffers.iter() {
+            buffer.addr().send(EnvelopeBuffer::Push(envelope.clone()));
+        }
+
+        assert!(partitioned.mark_ready(project_key, true).await.unwrap());
+
+        // Both partitions were updated, so marking them again changes nothing.
+        for buffer in partitioned.buffers.iter() {
+            let changed = buffer
+                .addr()
+                .send(MarkReady {
+                    project_key,
+                    is_ready: true,
+                })
+                .await
+                .unwrap();
+            assert!(!changed);
+        }
+        assert!(!partitioned.mark_ready(project_key, true).await.unwrap());
+
+        // A project without stacks does not change any priority.
+        let other_key = ProjectKey::parse("a94ae32be2584e0bbd7a4cbb95971fee").unwrap();
+        assert!(!partitioned.mark_ready(other_key, true).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_partitioned_drain_with_ack() {
+        // Keep the global config pending, so that the buffers only pop when drained.
+        let (_global_tx, global_rx) = watch::channel(global_config::Status::Pending);
+        let (outcome_aggregator, _outcome_rx) = Addr::custom();
+        let (envelope_processor, _envelope_processor_rx) = Addr::custom();
+
+        let services = Services {
+            envelope_processor,
+            project_cache_handle: ProjectCacheHandle::for_test(),
+            outcome_aggregator,
+            test_store: Addr::dummy(),
+        };
+        let config = Arc::new(
+            Config::from_json_value(serde_json::json!({
+                "spool": {
+                    "envelopes": {
+                        "path": std::env::temp_dir().join(Uuid::new_v4().to_string()),
+                    }
+                }
+            }))
+            .unwrap(),
+        );
+
+        let buffers = (0..2)
+            .map(|partition_id| {
+                EnvelopeBufferService::new(
+                    partition_id,
+                    config.clone(),
+                    MemoryStat::default(),
+                    global_rx.clone(),
+                    services.clone(),
+                )
+                .start_in(&TokioServiceSpawn)
+            })
+            .collect();
+
+        let partitioned = PartitionedEnvelopeBuffer {
+            buffers: Arc::new(buffers),
+            hasher: PartitionedEnvelopeBuffer::build_hasher(),
+            scheduler: Default::default(),
+        };
+
+        // Every partition holds three envelopes.
+        for buffer in partitioned.buffers.iter() {
+            for _ in 0..3 {
+                buffer
+                    .addr()
+                    .send(EnvelopeBuffer::Push(new_envelope(false, "foo")));
+            }
+        }
+
+        // The first partition is drained completely before the second one.
+        let drained = partitioned.drain_with_ack(4, Duration::ZERO).await.unwrap();
+        assert_eq!(drained.len(), 4);
+        let acks: Vec<_> = drained.iter().map(|(_, ack)| *ack).collect();
+        assert_eq!(acks[0].partition_id, 0);
+        assert_eq!(acks[3].partition_id, 1);
+        assert_eq!(partitioned.ack(acks[..2].to_vec()).await.unwrap(), 2);
+
+        // Envelopes that were not acknowledged in time are delivered again.
+        let hour = Duration::from_secs(3600);
+        let drained = partitioned.drain_with_ack(10, hour).await.unwrap();
+        assert_eq!(drained.len(), 4);
+        assert!(partitioned
+            .drain_with_ack(10, hour)
+            .await
+            .unwrap()
+            .is_empty());
+
+        assert_eq!(partitioned.ack(acks[2..].to_vec()).await.unwrap(), 0);
+        let acks = drained.iter().map(|(_, ack)| *ack).collect();
+        assert_eq!(partitioned.ack(acks).await.unwrap(), 4);
+        assert!(partitioned
+            .drain_with_ack(10, hour)
+            .await
+            .unwrap()
+            .is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_partitioned_drain_with_ack_weighted() {
+        // Keep the global config pending, so that the buffers only pop when drained.
+        let (_global_tx, global_rx) = watch::channel(global_config::Status::Pending);
+        let (outcome_aggregator, _outcome_rx) = Addr::custom();
+        let (envelope_processor, _envelope_processor_rx) = Addr::custom();
+
+        let services = Services {
+            envelope_processor,
+            project_cache_handle: ProjectCacheHandle::for_test(),
+            outcome_aggregator,
+            test_store: Addr::dummy(),
+        };
+        let config = Arc::new(
+            Config::from_json_value(serde_json::json!({
+                "spool": {
+                    "envelopes": {
+                        "path": std::env::temp_dir().join(Uuid::new_v4().to_string()),
+                    }
+                }
+            }))
+            .unwrap(),
+        );
+
+        let buffers = (0..2)
+            .map(|partition_id| {
+                EnvelopeBufferService::new(
+                    partition_id,
+                    config.clone(),
+                    MemoryStat::default(),
+                    global_rx.clone(),
+                    services.clone(),
+                )
+                .start_in(&TokioServiceSpawn)
+            })
+            .collect();
+
+        let weights = [1, 2].map(|weight| NonZeroU32::new(weight).unwrap());
+        let partitioned = PartitionedEnvelopeBuffer {
+            buffers: Arc::new(buffers),
+            hasher: PartitionedEnvelopeBuffer::build_hasher(),
+            scheduler: Arc::new(Mutex::new(PartitionScheduler::new(weights.to_vec()))),
+        };
+
+        // Every partition holds three envelopes.
+        for buffer in partitioned.buffers.iter() {
+            for _ in 0..3 {
+                buffer
+                    .addr()
+                    .send(EnvelopeBuffer::Push(new_envelope(false, "foo")));
+            }
+            buffer.addr().send(GetCountDiagnostics).await.unwrap();
+        }
+
+        // The partition with the higher weight is drained twice as often.
+        let mut served = vec![];
+        for _ in 0..3 {
+            let drained = partitioned
+                .drain_with_ack(1, Duration::from_secs(3600))
+                .await
+                .unwrap();
+            served.push(drained[0].1.partition_id);
+        }
+        assert_eq!(served, [1, 0, 1]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_partitioned_decommission() {
+        // Keep the global config pending, so that the buffers only pop when drained.
+        let (_global_tx, global_rx) = watch::channel(global_config::Status::Pending);
+        let (outcome_aggregator, _outcome_rx) = Addr::custom();
+        let (envelope_processor, _envelope_processor_rx) = Addr::custom();
+
+        let services = Services {
+            envelope_processor,
+            project_cache_handle: ProjectCacheHandle::for_test(),
+            outcome_aggregator,
+            test_store: Addr::dummy(),
+        };
+        let config = Arc::new(Config::default());
+
+        let buffers = (0..3)
+            .map(|partition_id| {
+                EnvelopeBufferService::new(
+                    partition_id,
+                    config.clone(),
+                    MemoryStat::default(),
+                    global_rx.clone(),
+                    services.clone(),
+                )
+                .start_in(&TokioServiceSpawn)
+            })
+            .collect();
+
+        let partitioned = PartitionedEnvelopeBuffer {
+            buffers: Arc::new(buffers),
+            hasher: PartitionedEnvelopeBuffer::build_hasher(),
+            scheduler: Default::default(),
+        };
+
+        // Every partition holds three envelopes.
+        for buffer in partitioned.buffers.iter() {
+            for _ in 0..3 {
+                buffer
+                    .addr()
+                    .send(EnvelopeBuffer::Push(new_envelope(false, "foo")));
+            }
+        }
+
+        assert_eq!(partitioned.decommission(1).await.unwrap(), 3);
+        tokio::time::advance(Duration::from_millis(100)).await;
+
+        let counts: Vec<_> = partitioned
+            .stats()
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|stats| stats.envelope_count)
+            .collect();
+        assert_eq!(counts[1], 0);
+        assert_eq!(counts.iter().sum::<u64>(), 9);
+
+        // No pair is routed to the decommissioned partition anymore.
+        let envelope = new_envelope(false, "foo");
+        assert_ne!(partitioned.envelope_partition_id(&envelope), 1);
+
+        assert_eq!(partitioned.decommission(1).await.unwrap(), 0);
+        assert_eq!(partitioned.decommission(0).await.unwrap(), 0);
+        assert!(matches!(
+            partitioned.decommission(2).await,
+            Err(DecommissionError::LastPartition)
+        ));
+        assert!(matches!(
+            partitioned.decommission(3).await,
+            Err(DecommissionError::UnknownPartition(3))
+        ));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_partitioned_decommission_in_batches() {
+        // Keep the global config pending, so that the buffers only pop when drained.
+        let (_global_tx, global_rx) = watch::channel(global_config::Status::Pending);
+        let (outcome_aggregator, _outcome_rx) = Addr::custom();
+        let (envelope_processor, _envelope_processor_rx) = Addr::custom();
+
+        let services = Services {
+            envelope_processor,
+            project_cache_handle: ProjectCacheHandle::for_test(),
+            outcome_aggregator,
+            test_store: Addr::dummy(),
+        };
+        let config = Arc::new(Config::default());
+
+        let buffers = (0..2)
+            .map(|partition_id| {
+                EnvelopeBufferService::new(
+                    partition_id,
+                    config.clone(),
+                    MemoryStat::default(),
+                    global_rx.clone(),
+                    services.clone(),
+                )
+                .start_in(&TokioServiceSpawn)
+            })
+            .collect();
+
+        let partitioned = PartitionedEnvelopeBuffer {
+            buffers: Arc::new(buffers),
+            hasher: PartitionedEnvelopeBuffer::build_hasher(),
+            scheduler: Default::default(),
+        };
+
+        // The partition holds more envelopes than are moved in a single batch.
+        let count = DECOMMISSION_BATCH_SIZE * 2 + 1;
+        for _ in 0..count {
+            partitioned.buffers[1]
+                .addr()
+                .send(EnvelopeBuffer::Push(new_envelope(false, "foo")));
+        }
+
+        assert_eq!(partitioned.decommission(1).await.unwrap(), count);
+
+        let counts: Vec<_> = partitioned
+            .stats()
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|stats| stats.envelope_count)
+            .collect();
+        assert_eq!(counts, [count as u64, 0]);
+    }
+
+    /// Creates a partitioned buffer whose partitions forward their messages to the returned
+    /// receivers instead of running a buffer service.
+    fn partitioned_with_capacity(
+        has_capacity: &[bool],
+    ) -> (
+        PartitionedEnvelopeBuffer,
+        Vec<mpsc::UnboundedReceiver<EnvelopeBuffer>>,
+    ) {
+        let (buffers, receivers) = has_capacity
+            .iter()
+            .map(|&has_capacity| {
+                let (addr, rx) = Addr::custom();
+                let metrics = Arc::new(EnvelopeBufferMetrics {
+                    has_capacity: AtomicBool::new(has_capacity),
+                    item_count: AtomicU64::new(0),
+                    storage_size: AtomicU64::new(0),
+                    attachment_bytes: AtomicU64::new(0),
+                    decommissioned: AtomicBool::new(false),
+                });
+                (ObservableEnvelopeBuffer { addr, metrics }, rx)
+            })
+            .unzip();
+
+        let partitioned = PartitionedEnvelopeBuffer {
+            buffers: Arc::new(buffers),
+            hasher: PartitionedEnvelopeBuffer::build_hasher(),
+            scheduler: Default::default(),
+        };
+
+        (partitioned, receivers)
+    }
+
+    fn managed_envelope(outcome_aggregator: Addr<TrackOutcome>) -> ManagedEnvelope {
+        ManagedEnvelope::new(
+            new_envelope(false, "foo"),
+            outcome_aggregator,
+            Addr::dummy(),
+            ProcessingGroup::Transaction,
+        )
+    }
+
+    #[test]
+    fn test_next_partition_to_serve_weighted() {
+        let (mut partitioned, _receivers) = partitioned_with_capacity(&[true, true, true]);
+        let weights = [3, 1, 1].map(|weight| NonZeroU32::new(weight).unwrap());
+        partitioned.scheduler = Arc::new(Mutex::new(PartitionScheduler::new(weights.to_vec())));
+
+        let set_backlogs = |backlogs: [u64; 3]| {
+            for (buffer, backlog) in partitioned.buffers.iter().zip(backlogs) {
+                buffer.metrics.item_count.store(backlog, Ordering::Relaxed);
+            }
+        };
+        let serve = |rounds: usize| {
+            let mut served = [0; 3];
+            for _ in 0..rounds {
+                if let Some(partition_id) = partitioned.next_partition_to_serve() {
+                    served[partition_id as usize] += 1;
+                }
+            }
+            served
+        };
+
+        // All partitions have a backlog, so they are served by weight.
+        set_backlogs([100, 100, 5]);
+        assert_eq!(serve(50), [30, 10, 10]);
+
+        // The priority partition is empty, the others share the workers equally.
+        set_backlogs([0, 100, 5]);
+        assert_eq!(serve(10), [0, 5, 5]);
+
+        // A low priority partition is still served while the priority partition has a backlog.
+        set_backlogs([100, 0, 5]);
+        assert_eq!(serve(40), [30, 0, 10]);
+
+        set_backlogs([0, 0, 0]);
+        assert_eq!(partitioned.next_partition_to_serve(), None);
+    }
+
+    #[tokio::test]
+    async fn test_push_falls_back_to_other_partition() {
+        let (partitioned, mut receivers) = partitioned_with_capacity(&[true, true]);
+        let envelope = managed_envelope(Addr::dummy());
+
+        let home = partitioned.partition_id(ProjectKeyPair::from_envelope(envelope.envelope()));
+        let other = 1 - home;
+        partitioned.buffers[home as usize]
+            .metrics
+            .has_capacity
+            .store(false, Ordering::Relaxed);
+
+        let partition_id = partitioned
+            .push(envelope, EnvelopeBufferFullPolicy::Reject, Duration::ZERO)
+            .await
+            .unwrap();
+
+        assert_eq!(partition_id, other);
+        assert!(receivers[home as usize].try_recv().is_err());
+        assert!(matches!(
+            receivers[other as usize].try_recv(),
+            Ok(EnvelopeBuffer::Push(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_push_to_requested_partition() {
+        let (partitioned, mut receivers) = partitioned_with_capacity(&[true, true]);
+        let mut envelope = managed_envelope(Addr::dummy());
+
+        let home = partitioned.partition_id(ProjectKeyPair::from_envelope(envelope.envelope()));
+        let requested = 1 - home;
+        envelope
+            .envelope_mut()
+            .meta_mut()
+            .set_partition(Some(requested));
+
+        let partition_id = partitioned
+            .push(envelope, EnvelopeBufferFullPolicy::Reject, Duration::ZERO)
+            .await
+            .unwrap();
+
+        assert_eq!(partition_id, requested);
+        assert!(receivers[home as usize].try_recv().is_err());
+        assert!(matches!(
+            receivers[requested as usize].try_recv(),
+            Ok(EnvelopeBuffer::Push(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_push_without_requested_partition() {
+        let (partitioned, mut receivers) = partitioned_with_capacity(&[true, true]);
+        let mut envelope = managed_envelope(Addr::dummy());
+        let home = partitioned.partition_id(ProjectKeyPair::from_envelope(envelope.envelope()));
+
+        // A partition beyond the configured partitions falls back to hashing.
+        envelope.envelope_mut().meta_mut().set_partition(Some(2));
+        assert_eq!(partitioned.envelope_partition_id(envelope.envelope()), home);
+
+        envelope.envelope_mut().meta_mut().set_partition(None);
+        let partition_id = partitioned
+            .push(envelope, EnvelopeBufferFullPolicy::Reject, Duration::ZERO)
+            .await
+            .unwrap();
+
+        assert_eq!(partition_id, home);
+        assert!(matches!(
+            receivers[home as usize].try_recv(),
+            Ok(EnvelopeBuffer::Push(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_push_full_reject() {
+        let (partitioned, mut receivers) = partitioned_with_capacity(&[false, false]);
+
+        for policy in [
+            EnvelopeBufferFullPolicy::Reject,
+            EnvelopeBufferFullPolicy::Reject429,
+        ] {
+            let (outcome_aggregator, mut outcome_rx) = Addr::custom();
+            let envelope = managed_envelope(outcome_aggregator);
+
+            let result = partitioned.push(envelope, policy, Duration::ZERO).await;
+            let Err(PushError::Full(envelope)) = result else {
+                panic!("expected a full buffer with {policy:?}");
+            };
+
+            // The envelope is handed back without emitting an outcome.
+            envelope.into_envelope();
+            assert!(outcome_rx.try_recv().is_err());
+        }
+
+        for receiver in &mut receivers {
+            assert!(receiver.try_recv().is_err());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_push_full_drop_with_outcome() {
+        let (partitioned, mut receivers) = partitioned_with_capacity(&[false, false]);
+        let (outcome_aggregator, mut outcome_rx) = Addr::custom();
+
+        let result = partitioned
+            .push(
+                managed_envelope(outcome_aggregator),
+                EnvelopeBufferFullPolicy::DropWithOutcome,
+                Duration::ZERO,
+            )
+            .await;
+        assert!(matches!(result, Err(PushError::Dropped)));
+
+        let outcome = outcome_rx.try_recv().unwrap();
+        assert_eq!(outcome.outcome, Outcome::Invalid(DiscardReason::Internal));
+
+        for receiver in &mut receivers {
+            assert!(receiver.try_recv().is_err());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_push_full_drop_transaction_outcome() {
+        let (partitioned, mut receivers) = partitioned_with_capacity(&[false, false]);
+        let (outcome_aggregator, mut outcome_rx) = Addr::custom();
+
+        let mut envelope = managed_envelope(outcome_aggregator);
+        let project_key = envelope.envelope().meta().public_key();
+        envelope.scope(Scoping {
+            organization_id: OrganizationId::new(7),
+            project_id: ProjectId::new(42),
+            project_key,
+            key_id: Some(17),
+        });
+
+        let result = partitioned
+            .push(
+                envelope,
+                EnvelopeBufferFullPolicy::DropWithOutcome,
+                Duration::ZERO,
+            )
+            .await;
+        assert!(matches!(result, Err(PushError::Dropped)));
+
+        let outcomes: Vec<_> = std::iter::from_fn(|| outcome_rx.try_recv().ok()).collect();
+        let transaction = outcomes
+            .iter()
+            .find(|outcome| outcome.category == DataCategory::Transaction)
+            .expect("expected a transaction outcome");
+        assert_eq!(transaction.quantity, 1);
+        assert_eq!(transaction.scoping.project_key, project_key);
+        assert_eq!(transaction.scoping.project_id, ProjectId::new(42));
+        assert_eq!(transaction.scoping.organization_id, OrganizationId::new(7));
+        assert!(outcomes
+            .iter()
+            .all(|outcome| outcome.scoping.project_key == project_key));
+
+        for receiver in &mut receivers {
+            assert!(receiver.try_recv().is_err());
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_push_full_block_until_capacity() {
+        let (partitioned, mut receivers) = partitioned_with_capacity(&[false, false]);
+
+        // A partition regains capacity before the timeout.
+        let buffers = partitioned.buffers.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            buffers[1]
+                .metrics
+                .has_capacity
+                .store(true, Ordering::Relaxed);
+        });
+
+        let partition_id = partitioned
+            .push(
+                managed_envelope(Addr::dummy()),
+                EnvelopeBufferFullPolicy::BlockUntilCapacity,
+                Duration::from_secs(1),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(partition_id, 1);
+        assert!(matches!(
+            receivers[1].try_recv(),
+            Ok(EnvelopeBuffer::Push(_))
+        ));
+
+        // No partition regains capacity before the timeout.
+        partitioned.buffers[1]
+            .metrics
+            .has_capacity
+            .store(false, Ordering::Relaxed);
+
+        let start = Instant::now();
+        let result = partitioned
+            .push(
+                managed_envelope(Addr::dummy()),
+                EnvelopeBufferFullPolicy::BlockUntilCapacity,
+                Duration::from_secs(1),
+            )
+            .await;
+
+        let Err(PushError::Full(envelope)) = result else {
+            panic!("expected a full buffer after the timeout");
+        };
+        envelope.into_envelope();
+        assert_eq!(start.elapsed(), Duration::from_secs(1));
     }
 }
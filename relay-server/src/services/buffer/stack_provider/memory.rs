@@ -1,3 +1,6 @@
+use std::convert::Infallible;
+
+use crate::envelope::Envelope;
 use crate::services::buffer::common::ProjectKeyPair;
 use crate::services::buffer::envelope_stack::memory::MemoryEnvelopeStack;
 use crate::services::buffer::stack_provider::{
@@ -39,6 +42,17 @@ impl StackProvider for MemoryStackProvider {
         0
     }
 
+    async fn round_trip(
+        &self,
+        envelope: Box<Envelope>,
+    ) -> Result<Option<Box<Envelope>>, Infallible> {
+        Ok(Some(envelope))
+    }
+
+    async fn store_stack_count(&self, _: ProjectKeyPair) -> u64 {
+        0
+    }
+
     fn total_size(&self) -> Option<u64> {
         // We can't reliably tell how much memory is used so just return None.
         None
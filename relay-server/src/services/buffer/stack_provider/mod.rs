@@ -1,3 +1,4 @@
+use crate::envelope::Envelope;
 use crate::services::buffer::common::ProjectKeyPair;
 use crate::EnvelopeStack;
 use hashbrown::HashSet;
@@ -58,6 +59,19 @@ pub trait StackProvider: std::fmt::Debug {
     /// Returns the total count of the store used by this [`StackProvider`].
     fn store_total_count(&self) -> impl Future<Output = u64>;
 
+    /// Writes the envelope to the store used by this [`StackProvider`] and reads it back.
+    ///
+    /// The envelope is not persisted in the store. Returns `None` if the envelope cannot be found
+    /// in the store after writing it. Providers without a store return the envelope unchanged.
+    fn round_trip(
+        &self,
+        envelope: Box<Envelope>,
+    ) -> impl Future<Output = Result<Option<Box<Envelope>>, <Self::Stack as EnvelopeStack>::Error>>;
+
+    /// Returns the count of envelopes in the store used by this [`StackProvider`] that belong
+    /// to the given stack.
+    fn store_stack_count(&self, project_key_pair: ProjectKeyPair) -> impl Future<Output = u64>;
+
     /// Returns the number of bytes the storage occupies. Will return `None` if no
     /// reliable information can be provided.
     fn total_size(&self) -> Option<u64>;
@@ -1,16 +1,21 @@
 use std::error::Error;
 
+use chrono::{DateTime, Utc};
 use relay_config::Config;
 
+use crate::envelope::Envelope;
 use crate::services::buffer::common::ProjectKeyPair;
 use crate::services::buffer::envelope_stack::caching::CachingEnvelopeStack;
+use crate::services::buffer::envelope_stack::sqlite::SqliteEnvelopeStackError;
+use crate::services::buffer::envelope_store::codec::{codec_from_config, EnvelopeCodec};
 use crate::services::buffer::envelope_store::sqlite::{
-    SqliteEnvelopeStore, SqliteEnvelopeStoreError,
+    DatabaseEnvelope, SqliteEnvelopeStore, SqliteEnvelopeStoreError,
 };
 use crate::services::buffer::stack_provider::{
     InitializationState, StackCreationType, StackProvider,
 };
 use crate::statsd::RelayTimers;
+use crate::utils::MemoryChecker;
 use crate::{EnvelopeStack, SqliteEnvelopeStack};
 
 #[derive(Debug)]
@@ -18,22 +23,69 @@ pub struct SqliteStackProvider {
     envelope_store: SqliteEnvelopeStore,
     batch_size_bytes: usize,
     max_disk_size: usize,
+    codec: &'static dyn EnvelopeCodec,
     partition_id: u8,
 }
 
 #[warn(dead_code)]
 impl SqliteStackProvider {
     /// Creates a new [`SqliteStackProvider`] from the provided [`Config`].
-    pub async fn new(partition_id: u8, config: &Config) -> Result<Self, SqliteEnvelopeStoreError> {
-        let envelope_store = SqliteEnvelopeStore::prepare(partition_id, config).await?;
+    ///
+    /// The [`MemoryChecker`] bounds the envelopes that stacks keep in memory while the database
+    /// is locked.
+    pub async fn new(
+        partition_id: u8,
+        config: &Config,
+        memory_checker: MemoryChecker,
+    ) -> Result<Self, SqliteEnvelopeStoreError> {
+        let envelope_store =
+            SqliteEnvelopeStore::prepare(partition_id, config, memory_checker).await?;
         Ok(Self {
             envelope_store,
             batch_size_bytes: config.spool_envelopes_batch_size_bytes(),
             max_disk_size: config.spool_envelopes_max_disk_size(),
+            codec: codec_from_config(config.spool_envelopes_codec()),
             partition_id,
         })
     }
 
+    /// Removes the held envelope with the given token from the store.
+    ///
+    /// Returns `false` if the envelope is no longer held.
+    pub async fn ack(&self, token: i64) -> Result<bool, SqliteEnvelopeStackError> {
+        Ok(self.envelope_store.clone().delete_inflight(token).await?)
+    }
+
+    /// Removes and returns all held envelopes whose deadline passed.
+    ///
+    /// Envelopes that cannot be decoded are dropped.
+    pub async fn take_expired(
+        &self,
+        now: DateTime<Utc>,
+    ) -> Result<Vec<Box<Envelope>>, SqliteEnvelopeStackError> {
+        let expired = self
+            .envelope_store
+            .clone()
+            .delete_expired_inflight(now)
+            .await?;
+
+        let envelopes = expired
+            .into_iter()
+            .filter_map(|envelope| match Box::<Envelope>::try_from(envelope) {
+                Ok(envelope) => Some(envelope),
+                Err(error) => {
+                    relay_log::error!(
+                        error = &error as &dyn Error,
+                        "failed to decode in-flight envelope"
+                    );
+                    None
+                }
+            })
+            .collect();
+
+        Ok(envelopes)
+    }
+
     /// Returns `true` when there might be data residing on disk, `false` otherwise.
     fn assume_data_on_disk(stack_creation_type: StackCreationType) -> bool {
         matches!(stack_creation_type, StackCreationType::Initialization)
@@ -67,6 +119,7 @@ impl StackProvider for SqliteStackProvider {
             self.batch_size_bytes,
             project_key_pair.own_key,
             project_key_pair.sampling_key,
+            self.codec,
             // We want to check the disk by default if we are creating the stack for the first time,
             // since we might have some data on disk.
             // On the other hand, if we are recreating a stack, it means that we popped it because
@@ -78,6 +131,11 @@ impl StackProvider for SqliteStackProvider {
         CachingEnvelopeStack::new(inner)
     }
 
+    /// Returns the maximum size of the store in bytes, see `spool.envelopes.max_disk_size`.
+    pub fn max_disk_size(&self) -> usize {
+        self.max_disk_size
+    }
+
     fn has_store_capacity(&self) -> bool {
         (self.envelope_store.usage() as usize) < self.max_disk_size
     }
@@ -96,6 +154,30 @@ impl StackProvider for SqliteStackProvider {
             })
     }
 
+    async fn round_trip(
+        &self,
+        envelope: Box<Envelope>,
+    ) -> Result<Option<Box<Envelope>>, SqliteEnvelopeStackError> {
+        let encoded = DatabaseEnvelope::encode(&envelope, self.codec)?;
+        match self.envelope_store.round_trip(&encoded).await? {
+            Some(envelope) => Ok(Some(envelope.try_into()?)),
+            None => Ok(None),
+        }
+    }
+
+    async fn store_stack_count(&self, project_key_pair: ProjectKeyPair) -> u64 {
+        self.envelope_store
+            .count(project_key_pair.own_key, project_key_pair.sampling_key)
+            .await
+            .unwrap_or_else(|error| {
+                relay_log::error!(
+                    error = &error as &dyn Error,
+                    "failed to get the count of envelopes of a stack for the sqlite envelope store",
+                );
+                0
+            })
+    }
+
     fn total_size(&self) -> Option<u64> {
         Some(self.envelope_store.usage())
     }
@@ -132,6 +214,7 @@ mod tests {
     use crate::services::buffer::stack_provider::sqlite::SqliteStackProvider;
     use crate::services::buffer::stack_provider::{StackCreationType, StackProvider};
     use crate::services::buffer::testutils::utils::mock_envelopes;
+    use crate::utils::{MemoryChecker, MemoryStat};
     use crate::EnvelopeStack;
 
     fn mock_config() -> Arc<Config> {
@@ -157,7 +240,10 @@ mod tests {
     #[tokio::test]
     async fn test_flush() {
         let config = mock_config();
-        let mut stack_provider = SqliteStackProvider::new(0, &config).await.unwrap();
+        let memory_checker = MemoryChecker::new(MemoryStat::default(), config.clone());
+        let mut stack_provider = SqliteStackProvider::new(0, &config, memory_checker)
+            .await
+            .unwrap();
 
         let own_key = ProjectKey::parse("a94ae32be2584e0bbd7a4cbb95971fee").unwrap();
         let sampling_key = ProjectKey::parse("b81ae32be2584e0bbd7a4cbb95971fe1").unwrap();
@@ -36,6 +36,7 @@ pub mod health_check;
 pub mod metrics;
 pub mod outcome;
 pub mod outcome_aggregator;
+pub mod outcome_spool;
 pub mod processor;
 pub mod projects;
 pub mod relays;
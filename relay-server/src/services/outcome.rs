@@ -14,9 +14,13 @@ use std::{fmt, mem};
 
 use crate::envelope::ItemType;
 #[cfg(feature = "processing")]
+use crate::http::StatusCode;
 use crate::service::ServiceError;
+use crate::services::outcome_spool::OutcomeSpool;
 use crate::services::processor::{EnvelopeProcessor, SubmitClientReports};
-use crate::services::upstream::{Method, SendQuery, UpstreamQuery, UpstreamRelay};
+use crate::services::upstream::{
+    Method, SendQuery, UpstreamQuery, UpstreamRelay, UpstreamRequestError,
+};
 use crate::statsd::RelayCounters;
 use crate::utils::SleepHandle;
 use chrono::{DateTime, SecondsFormat, Utc};
@@ -33,8 +37,9 @@ use relay_quotas::{DataCategory, ReasonCode, Scoping};
 use relay_sampling::config::RuleId;
 use relay_sampling::evaluation::MatchedRuleIds;
 use relay_statsd::metric;
-use relay_system::{Addr, FromMessage, Interface, NoResponse, Service};
+use relay_system::{Addr, AsyncResponse, FromMessage, Interface, NoResponse, Sender, Service};
 use serde::{Deserialize, Serialize};
+use tokio::time::MissedTickBehavior;
 
 /// Defines the structure of the HTTP outcomes requests
 #[derive(Debug, Default, Deserialize, Serialize)]
@@ -151,6 +156,16 @@ impl FromMessage<Self> for TrackOutcome {
     }
 }
 
+/// Tracks an outcome and responds once it has been persisted by the outcome backend.
+///
+/// Unlike [`TrackOutcome`] sent to the outcome aggregator, this message is sent directly to the
+/// [`OutcomeProducer`] and is not aggregated. The response is sent once the outcome has been sent
+/// to the upstream or written to the outcome spool, or handed to the Kafka producer in processing
+/// mode. If that fails, no response is sent. Outcomes emitted as client reports are acknowledged
+/// once they have been handed to the client report producer.
+#[derive(Debug)]
+pub struct TrackOutcomeSync(pub TrackOutcome);
+
 /// Defines the possible outcomes from processing an event.
 #[derive(Clone, Debug, PartialEq, Eq, Hash)]
 pub enum Outcome {
@@ -469,6 +484,28 @@ pub enum DiscardReason {
 
     /// An attachment was submitted with a transaction.
     TransactionAttachment,
+
+    /// (Relay) The envelope was evicted from the buffer because its stack exceeded the maximum
+    /// stack depth.
+    StackDepth,
+
+    /// (Relay) The envelope was rejected by the buffer because it would have created a new stack
+    /// beyond the configured rate.
+    StackCreationRate,
+
+    /// (Relay) The envelope belongs to an unsampled trace and was evicted from a full buffer to
+    /// make room for another envelope.
+    BufferEvictedUnsampled,
+
+    /// (Relay) The dynamic sampling context of the envelope references a project that does not
+    /// exist.
+    InvalidDsc,
+
+    /// (Relay) The envelope contains more items than `limits.max_items_per_envelope`.
+    TooManyItems,
+
+    /// (Relay) The envelope was buffered by this Relay before and is forwarded in a loop.
+    RelayLoop,
 }
 
 impl DiscardReason {
@@ -517,6 +554,12 @@ impl DiscardReason {
             DiscardReason::InvalidSpan => "invalid_span",
             DiscardReason::FeatureDisabled(_) => "feature_disabled",
             DiscardReason::TransactionAttachment => "transaction_attachment",
+            DiscardReason::StackDepth => "stack_depth",
+            DiscardReason::StackCreationRate => "stack_creation_rate",
+            DiscardReason::BufferEvictedUnsampled => "buffer_evicted_unsampled",
+            DiscardReason::InvalidDsc => "invalid_dsc",
+            DiscardReason::TooManyItems => "too_many_items",
+            DiscardReason::RelayLoop => "relay_loop",
         }
     }
 }
@@ -776,13 +819,36 @@ pub enum OutcomeError {
     SerializationError(serde_json::Error),
 }
 
+/// Messages of the [`HttpOutcomeProducer`].
+#[derive(Debug)]
+enum HttpOutcome {
+    /// Sends the outcome in the next batch.
+    Track(TrackRawOutcome),
+    /// Sends the outcome in the next batch and responds once it was sent or spooled.
+    TrackSync(TrackRawOutcome, Sender<()>),
+}
+
+impl Interface for HttpOutcome {}
+
+impl FromMessage<Self> for HttpOutcome {
+    type Response = NoResponse;
+
+    fn from_message(message: Self, _: ()) -> Self {
+        message
+    }
+}
+
 /// Outcome producer backend via HTTP as [`TrackRawOutcome`].
 #[derive(Debug)]
 struct HttpOutcomeProducer {
     config: Arc<Config>,
     upstream_relay: Addr<UpstreamRelay>,
     unsent_outcomes: Vec<TrackRawOutcome>,
+    /// Senders of synchronous outcomes in `unsent_outcomes`, see [`TrackOutcomeSync`].
+    pending_acks: Vec<Sender<()>>,
     flush_handle: SleepHandle,
+    /// Buffers outcomes that failed to send, if `outcomes.spool` is enabled.
+    spool: Option<OutcomeSpool>,
 }
 
 impl HttpOutcomeProducer {
@@ -791,7 +857,38 @@ impl HttpOutcomeProducer {
             config,
             upstream_relay,
             unsent_outcomes: Vec::new(),
+            pending_acks: Vec::new(),
             flush_handle: SleepHandle::idle(),
+            spool: None,
+        }
+    }
+
+    /// Opens the outcome spool if it is configured.
+    async fn prepare_spool(&mut self) {
+        let Some(path) = self.config.outcome_spool_path() else {
+            return;
+        };
+
+        match OutcomeSpool::prepare(&path, self.config.outcome_spool_max_count()).await {
+            Ok(spool) => {
+                relay_log::info!("outcome spool file {}", path.to_string_lossy());
+                self.spool = Some(spool);
+            }
+            Err(error) => relay_log::error!(
+                error = &error as &dyn Error,
+                "failed to open the outcome spool, outcomes that fail to send are dropped"
+            ),
+        }
+    }
+
+    /// Sends spooled outcomes in the background, if the outcome spool is enabled.
+    fn schedule_replay(&self) {
+        if let Some(spool) = self.spool.clone() {
+            relay_system::spawn!(replay_spooled(
+                spool,
+                self.upstream_relay.clone(),
+                self.config.outcome_batch_size(),
+            ));
         }
     }
 
@@ -811,22 +908,51 @@ impl HttpOutcomeProducer {
         let request = SendOutcomes {
             outcomes: mem::take(&mut self.unsent_outcomes),
         };
+        let acks = mem::take(&mut self.pending_acks);
+        let count = request.outcomes.len();
 
         let upstream_relay = self.upstream_relay.clone();
+        let spool = self.spool.clone();
+        let batch_size = self.config.outcome_batch_size();
+        // Keep a copy of the outcomes to spool them if the request fails.
+        let backup = spool.is_some().then(|| request.outcomes.clone());
 
         relay_system::spawn!(async move {
             match upstream_relay.send(SendQuery(request)).await {
-                Ok(_) => relay_log::trace!("outcome batch sent"),
+                Ok(_) => {
+                    relay_log::trace!("outcome batch sent");
+                    acks.into_iter().for_each(|ack| ack.send(()));
+                    if let Some(spool) = spool {
+                        replay_spooled(spool, upstream_relay, batch_size).await;
+                    }
+                }
+                Err(error) if is_permanent_rejection(&error) => {
+                    drop_rejected(count, &error);
+                    acks.into_iter().for_each(|ack| ack.send(()));
+                }
                 Err(error) => {
-                    relay_log::error!(error = &error as &dyn Error, "outcome batch sending failed")
+                    relay_log::error!(error = &error as &dyn Error, "outcome batch sending failed");
+                    if let (Some(spool), Some(outcomes)) = (spool, backup) {
+                        if spool_outcomes(&spool, outcomes).await == 0 {
+                            acks.into_iter().for_each(|ack| ack.send(()));
+                        }
+                    }
                 }
             }
         });
     }
 
-    fn handle_message(&mut self, message: TrackRawOutcome) {
+    fn handle_message(&mut self, message: HttpOutcome) {
         relay_log::trace!("batching outcome");
-        self.unsent_outcomes.push(message);
+        match message {
+            HttpOutcome::Track(outcome) => self.unsent_outcomes.push(outcome),
+            // Synchronous outcomes are sent with the next batch, so that outcomes of many
+            // rejected envelopes do not result in as many requests to the upstream.
+            HttpOutcome::TrackSync(outcome, ack) => {
+                self.unsent_outcomes.push(outcome);
+                self.pending_acks.push(ack);
+            }
+        }
 
         if self.unsent_outcomes.len() >= self.config.outcome_batch_size() {
             self.send_batch();
@@ -837,22 +963,138 @@ impl HttpOutcomeProducer {
 }
 
 impl Service for HttpOutcomeProducer {
-    type Interface = TrackRawOutcome;
+    type Interface = HttpOutcome;
 
     async fn run(mut self, mut rx: relay_system::Receiver<Self::Interface>) {
+        self.prepare_spool().await;
+
+        // The first tick completes right away and sends outcomes left over from a previous run.
+        let mut replay_interval =
+            tokio::time::interval(self.config.outcome_spool_replay_interval());
+        replay_interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
         loop {
             tokio::select! {
                 // Prioritize flush over receiving messages to prevent starving.
                 biased;
 
                 () = &mut self.flush_handle => self.send_batch(),
-                Some(message) = rx.recv() => self.handle_message(message),
-                else => break,
+                _ = replay_interval.tick(), if self.spool.is_some() => self.schedule_replay(),
+                message = rx.recv() => match message {
+                    Some(message) => self.handle_message(message),
+                    None => break,
+                },
             }
         }
     }
 }
 
+/// Writes outcomes that failed to send to the spool.
+///
+/// Returns the number of outcomes that were dropped because they could not be spooled.
+async fn spool_outcomes(spool: &OutcomeSpool, outcomes: Vec<TrackRawOutcome>) -> usize {
+    let count = outcomes.len();
+    let dropped = match spool.push(outcomes).await {
+        Ok(dropped) => {
+            if dropped > 0 {
+                relay_log::error!(dropped, "outcome spool is full, dropping outcomes");
+            }
+            dropped
+        }
+        Err(error) => {
+            relay_log::error!(error = &error as &dyn Error, "failed to spool outcomes");
+            count
+        }
+    };
+
+    metric!(counter(RelayCounters::OutcomesSpooled) += (count - dropped) as u64);
+    metric!(counter(RelayCounters::OutcomesSpoolDropped) += dropped as u64);
+    dropped
+}
+
+/// Returns `true` if the upstream rejected outcomes in a way that sending them again cannot fix.
+///
+/// Rate limits and timeouts are temporary, all other client errors are permanent.
+fn is_permanent_rejection(error: &UpstreamRequestError) -> bool {
+    match error {
+        UpstreamRequestError::RateLimited(_) => false,
+        _ => error.status_code().is_some_and(|status| {
+            status.is_client_error()
+                && status != StatusCode::REQUEST_TIMEOUT
+                && status != StatusCode::TOO_MANY_REQUESTS
+        }),
+    }
+}
+
+/// Drops outcomes that the upstream rejected permanently, see [`is_permanent_rejection`].
+fn drop_rejected(count: usize, error: &UpstreamRequestError) {
+    relay_log::error!(
+        error = error as &dyn Error,
+        count,
+        "upstream rejected outcomes, dropping them"
+    );
+    metric!(counter(RelayCounters::OutcomesRejected) += count as u64);
+}
+
+/// Sends spooled outcomes to the upstream until the spool is empty or a request fails.
+///
+/// Outcomes are removed from the spool only once the upstream accepted or permanently rejected
+/// them, so that they are sent again after a failed request or a restart. Only one replay runs at
+/// a time.
+async fn replay_spooled(
+    spool: OutcomeSpool,
+    upstream_relay: Addr<UpstreamRelay>,
+    batch_size: usize,
+) {
+    if !spool.begin_replay() {
+        return;
+    }
+
+    loop {
+        let mut batch = match spool.peek(batch_size).await {
+            Ok(Some(batch)) => batch,
+            Ok(None) => break,
+            Err(error) => {
+                relay_log::error!(
+                    error = &error as &dyn Error,
+                    "failed to read spooled outcomes"
+                );
+                break;
+            }
+        };
+
+        // A batch of outcomes that all failed to decode is removed without sending it.
+        let count = batch.outcomes.len();
+        if count > 0 {
+            let request = SendOutcomes {
+                outcomes: mem::take(&mut batch.outcomes),
+            };
+
+            match upstream_relay.send(SendQuery(request)).await {
+                Ok(_) => relay_log::debug!(count, "sent spooled outcomes"),
+                Err(error) if is_permanent_rejection(&error) => drop_rejected(count, &error),
+                Err(error) => {
+                    relay_log::warn!(
+                        error = &error as &dyn Error,
+                        "failed to send spooled outcomes"
+                    );
+                    break;
+                }
+            }
+        }
+
+        if let Err(error) = spool.remove(batch).await {
+            relay_log::error!(
+                error = &error as &dyn Error,
+                "failed to remove sent outcomes from the spool"
+            );
+            break;
+        }
+    }
+
+    spool.end_replay();
+}
+
 /// Outcome producer backend via HTTP as [`ClientReport`].
 #[derive(Debug)]
 struct ClientReportOutcomeProducer {
@@ -1001,6 +1243,7 @@ impl KafkaOutcomesProducer {
 #[derive(Debug)]
 pub enum OutcomeProducer {
     TrackOutcome(TrackOutcome),
+    TrackOutcomeSync(TrackOutcomeSync, Sender<()>),
     TrackRawOutcome(TrackRawOutcome),
 }
 
@@ -1014,6 +1257,14 @@ impl FromMessage<TrackOutcome> for OutcomeProducer {
     }
 }
 
+impl FromMessage<TrackOutcomeSync> for OutcomeProducer {
+    type Response = AsyncResponse<()>;
+
+    fn from_message(message: TrackOutcomeSync, sender: Sender<()>) -> Self {
+        Self::TrackOutcomeSync(message, sender)
+    }
+}
+
 impl FromMessage<TrackRawOutcome> for OutcomeProducer {
     type Response = NoResponse;
 
@@ -1034,7 +1285,7 @@ fn send_outcome_metric(message: &impl TrackOutcomeLike, to: &'static str) {
 #[derive(Debug)]
 enum OutcomeBroker {
     ClientReport(Addr<TrackOutcome>),
-    Http(Addr<TrackRawOutcome>),
+    Http(Addr<HttpOutcome>),
     #[cfg(feature = "processing")]
     Kafka(KafkaOutcomesProducer),
     Disabled,
@@ -1044,6 +1295,9 @@ impl OutcomeBroker {
     fn handle_message(&self, message: OutcomeProducer, config: &Config) {
         match message {
             OutcomeProducer::TrackOutcome(msg) => self.handle_track_outcome(msg, config),
+            OutcomeProducer::TrackOutcomeSync(TrackOutcomeSync(msg), sender) => {
+                self.handle_track_outcome_sync(msg, sender, config)
+            }
             OutcomeProducer::TrackRawOutcome(msg) => self.handle_track_raw_outcome(msg),
         }
     }
@@ -1098,12 +1352,45 @@ impl OutcomeBroker {
             }
             Self::Http(producer) => {
                 send_outcome_metric(&message, "http");
-                producer.send(TrackRawOutcome::from_outcome(message, config));
+                producer.send(HttpOutcome::Track(TrackRawOutcome::from_outcome(
+                    message, config,
+                )));
             }
             Self::Disabled => (),
         }
     }
 
+    /// Tracks an outcome and responds once the backend has persisted it, see [`TrackOutcomeSync`].
+    fn handle_track_outcome_sync(
+        &self,
+        message: TrackOutcome,
+        sender: Sender<()>,
+        config: &Config,
+    ) {
+        match self {
+            #[cfg(feature = "processing")]
+            Self::Kafka(kafka_producer) => {
+                send_outcome_metric(&message, "kafka");
+                let raw_message = TrackRawOutcome::from_outcome(message, config);
+                match self.send_kafka_message(kafka_producer, raw_message) {
+                    Ok(()) => sender.send(()),
+                    Err(error) => {
+                        relay_log::error!(error = &error as &dyn Error, "failed to produce outcome")
+                    }
+                }
+            }
+            Self::Http(producer) => {
+                send_outcome_metric(&message, "http");
+                let raw_message = TrackRawOutcome::from_outcome(message, config);
+                producer.send(HttpOutcome::TrackSync(raw_message, sender));
+            }
+            Self::ClientReport(_) | Self::Disabled => {
+                self.handle_track_outcome(message, config);
+                sender.send(());
+            }
+        }
+    }
+
     fn handle_track_raw_outcome(&self, message: TrackRawOutcome) {
         match self {
             #[cfg(feature = "processing")]
@@ -1115,7 +1402,7 @@ impl OutcomeBroker {
             }
             Self::Http(producer) => {
                 send_outcome_metric(&message, "http");
-                producer.send(message);
+                producer.send(HttpOutcome::Track(message));
             }
             Self::ClientReport(_) => (),
             Self::Disabled => (),
@@ -1209,8 +1496,82 @@ impl Service for OutcomeProducerService {
 
 #[cfg(test)]
 mod tests {
+    use futures::future::poll_immediate;
+    use relay_base_schema::project::ProjectKey;
+    use relay_system::MessageResponse;
+
+    use crate::http::Response;
+    use crate::services::upstream::UpstreamRequestError;
+
     use super::*;
 
+    fn track_outcome_sync(config: &Config) -> TrackRawOutcome {
+        let outcome = TrackOutcome {
+            timestamp: Utc::now(),
+            scoping: Scoping {
+                organization_id: OrganizationId::new(1),
+                project_id: ProjectId::new(42),
+                project_key: ProjectKey::parse("a94ae32be2584e0bbd7a4cbb95971fee").unwrap(),
+                key_id: None,
+            },
+            outcome: Outcome::Invalid(DiscardReason::Internal),
+            event_id: None,
+            remote_addr: None,
+            category: DataCategory::Error,
+            quantity: 1,
+        };
+
+        TrackRawOutcome::from_outcome(outcome, config)
+    }
+
+    #[tokio::test]
+    async fn test_http_outcome_sync_acked_after_send() {
+        let config = Arc::new(Config::default());
+        let (upstream_relay, mut upstream_rx) = Addr::custom();
+        let producer =
+            HttpOutcomeProducer::new(Arc::clone(&config), upstream_relay).start_detached();
+
+        let (sender, mut ack) = AsyncResponse::channel();
+        producer.send(HttpOutcome::TrackSync(track_outcome_sync(&config), sender));
+
+        // The outcome is sent right away, but not acknowledged until the upstream responds.
+        let UpstreamRelay::SendRequest(request) = upstream_rx.recv().await.unwrap() else {
+            panic!("expected an upstream request");
+        };
+        assert!(poll_immediate(&mut ack).await.is_none());
+
+        let body = serde_json::to_vec(&SendOutcomesResponse {}).unwrap();
+        let response = http::response::Response::builder()
+            .status(http::StatusCode::OK)
+            .header(http::header::CONTENT_LENGTH, body.len())
+            .body(body)
+            .unwrap();
+        request.respond(Ok(Response(response.into()))).await;
+
+        assert!(ack.await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_http_outcome_sync_not_acked_on_failure() {
+        let config = Arc::new(Config::default());
+        let (upstream_relay, mut upstream_rx) = Addr::custom();
+        let producer =
+            HttpOutcomeProducer::new(Arc::clone(&config), upstream_relay).start_detached();
+
+        let (sender, ack) = AsyncResponse::channel();
+        producer.send(HttpOutcome::TrackSync(track_outcome_sync(&config), sender));
+
+        let UpstreamRelay::SendRequest(request) = upstream_rx.recv().await.unwrap() else {
+            panic!("expected an upstream request");
+        };
+        request
+            .respond(Err(UpstreamRequestError::ChannelClosed))
+            .await;
+
+        // Without an outcome spool, the outcome is lost and must not be acknowledged.
+        assert!(ack.await.is_err());
+    }
+
     #[test]
     fn rule_category_roundtrip() {
         let input = "123,1004,1500,1403,1403,1404,1000";
@@ -0,0 +1,292 @@
+//! Buffers outcomes on disk that could not be sent to the upstream.
+//!
+//! The spool uses the same SQLite setup as the envelope buffer, but a separate database file, see
+//! [`Config::outcome_spool_path`](relay_config::Config::outcome_spool_path), with its own
+//! migrations in `outcome-migrations/`.
+
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use sqlx::migrate::MigrateError;
+use sqlx::sqlite::{SqliteConnectOptions, SqliteJournalMode, SqlitePoolOptions, SqliteSynchronous};
+use sqlx::{Pool, Row, Sqlite};
+use tokio::fs::DirBuilder;
+
+use crate::services::outcome::TrackRawOutcome;
+
+/// An error returned by the [`OutcomeSpool`].
+#[derive(Debug, thiserror::Error)]
+pub enum OutcomeSpoolError {
+    #[error("failed to create the spool file: {0}")]
+    FileSetupError(std::io::Error),
+
+    #[error("failed to set up the database: {0}")]
+    SqlxSetupFailed(sqlx::Error),
+
+    #[error("failed to migrate the database: {0}")]
+    MigrationError(MigrateError),
+
+    #[error("failed to write to disk: {0}")]
+    WriteError(sqlx::Error),
+
+    #[error("failed to read from disk: {0}")]
+    FetchError(sqlx::Error),
+}
+
+/// A batch of the oldest outcomes in an [`OutcomeSpool`].
+///
+/// The outcomes remain in the spool until the batch is passed to [`OutcomeSpool::remove`].
+#[derive(Debug)]
+pub struct SpooledOutcomes {
+    /// The decoded outcomes of the batch.
+    pub outcomes: Vec<TrackRawOutcome>,
+    /// The id of the newest row in the batch, including rows that failed to decode.
+    last_id: i64,
+}
+
+/// A bounded queue of raw outcomes stored in a SQLite database.
+///
+/// Outcomes are read in the order they were pushed. The spool is cheap to clone, all clones
+/// share the same database.
+#[derive(Debug, Clone)]
+pub struct OutcomeSpool {
+    db: Pool<Sqlite>,
+    max_count: usize,
+    replaying: Arc<AtomicBool>,
+}
+
+impl OutcomeSpool {
+    /// Opens the spool at the given path, creating the database if it does not exist.
+    pub async fn prepare(path: &Path, max_count: usize) -> Result<Self, OutcomeSpoolError> {
+        if let Some(parent) = path.parent() {
+            if !parent.as_os_str().is_empty() && !parent.exists() {
+                DirBuilder::new()
+                    .recursive(true)
+                    .create(parent)
+                    .await
+                    .map_err(OutcomeSpoolError::FileSetupError)?;
+            }
+        }
+
+        let options = SqliteConnectOptions::new()
+            .filename(path)
+            .journal_mode(SqliteJournalMode::Wal)
+            .synchronous(SqliteSynchronous::Normal)
+            .create_if_missing(true);
+
+        let db = SqlitePoolOptions::new()
+            .max_connections(1)
+            .min_connections(1)
+            .connect_with(options)
+            .await
+            .map_err(OutcomeSpoolError::SqlxSetupFailed)?;
+
+        sqlx::migrate!("../outcome-migrations")
+            .run(&db)
+            .await
+            .map_err(OutcomeSpoolError::MigrationError)?;
+
+        Ok(Self {
+            db,
+            max_count,
+            replaying: Arc::new(AtomicBool::new(false)),
+        })
+    }
+
+    /// Appends outcomes to the spool.
+    ///
+    /// Outcomes that exceed the capacity of the spool are dropped. Returns the number of dropped
+    /// outcomes.
+    pub async fn push(&self, outcomes: Vec<TrackRawOutcome>) -> Result<usize, OutcomeSpoolError> {
+        let capacity = self.max_count.saturating_sub(self.count().await?);
+        let dropped = outcomes.len().saturating_sub(capacity);
+
+        let mut transaction = self
+            .db
+            .begin()
+            .await
+            .map_err(OutcomeSpoolError::WriteError)?;
+
+        for outcome in outcomes.into_iter().take(capacity) {
+            // Raw outcomes consist of plain values, so serialization cannot fail.
+            let Ok(encoded) = serde_json::to_vec(&outcome) else {
+                continue;
+            };
+
+            sqlx::query("INSERT INTO outcomes (outcome) VALUES (?)")
+                .bind(encoded)
+                .execute(&mut *transaction)
+                .await
+                .map_err(OutcomeSpoolError::WriteError)?;
+        }
+
+        transaction
+            .commit()
+            .await
+            .map_err(OutcomeSpoolError::WriteError)?;
+
+        Ok(dropped)
+    }
+
+    /// Returns up to `limit` of the oldest outcomes without removing them from the spool.
+    ///
+    /// Returns `None` if the spool is empty. Outcomes that cannot be decoded are skipped and
+    /// removed along with the rest of the batch.
+    pub async fn peek(&self, limit: usize) -> Result<Option<SpooledOutcomes>, OutcomeSpoolError> {
+        let rows = sqlx::query("SELECT id, outcome FROM outcomes ORDER BY id ASC LIMIT ?")
+            .bind(limit as i64)
+            .fetch_all(&self.db)
+            .await
+            .map_err(OutcomeSpoolError::FetchError)?;
+
+        let rows: Vec<(i64, Vec<u8>)> = rows
+            .into_iter()
+            .filter_map(|row| Some((row.try_get("id").ok()?, row.try_get("outcome").ok()?)))
+            .collect();
+
+        let Some(&(last_id, _)) = rows.last() else {
+            return Ok(None);
+        };
+
+        let outcomes = rows
+            .into_iter()
+            .filter_map(|(_, encoded)| match serde_json::from_slice(&encoded) {
+                Ok(outcome) => Some(outcome),
+                Err(error) => {
+                    relay_log::error!(
+                        error = &error as &dyn std::error::Error,
+                        "failed to decode spooled outcome"
+                    );
+                    None
+                }
+            })
+            .collect();
+
+        Ok(Some(SpooledOutcomes { outcomes, last_id }))
+    }
+
+    /// Removes a batch returned by [`Self::peek`] from the spool.
+    ///
+    /// Call this only once the outcomes have been handled, so that they survive failures and
+    /// restarts until then.
+    pub async fn remove(&self, batch: SpooledOutcomes) -> Result<(), OutcomeSpoolError> {
+        sqlx::query("DELETE FROM outcomes WHERE id <= ?")
+            .bind(batch.last_id)
+            .execute(&self.db)
+            .await
+            .map_err(OutcomeSpoolError::WriteError)?;
+
+        Ok(())
+    }
+
+    /// Returns the number of outcomes in the spool.
+    pub async fn count(&self) -> Result<usize, OutcomeSpoolError> {
+        let row = sqlx::query("SELECT COUNT(*) FROM outcomes")
+            .fetch_one(&self.db)
+            .await
+            .map_err(OutcomeSpoolError::FetchError)?;
+
+        let count: i64 = row.try_get(0).map_err(OutcomeSpoolError::FetchError)?;
+        Ok(count as usize)
+    }
+
+    /// Marks the spool as being replayed.
+    ///
+    /// Returns `false` if another replay is already running. Call [`Self::end_replay`] once the
+    /// replay has finished.
+    pub fn begin_replay(&self) -> bool {
+        !self.replaying.swap(true, Ordering::AcqRel)
+    }
+
+    /// Marks a replay started with [`Self::begin_replay`] as finished.
+    pub fn end_replay(&self) {
+        self.replaying.store(false, Ordering::Release);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn spool(max_count: usize) -> OutcomeSpool {
+        let path = std::env::temp_dir().join(uuid::Uuid::new_v4().to_string());
+        OutcomeSpool::prepare(&path, max_count).await.unwrap()
+    }
+
+    fn outcome(quantity: u32) -> TrackRawOutcome {
+        serde_json::from_value(serde_json::json!({
+            "timestamp": "2025-01-01T00:00:00.000000Z",
+            "project_id": 42,
+            "outcome": 3,
+            "reason": "project_id",
+            "category": 1,
+            "quantity": quantity,
+        }))
+        .unwrap()
+    }
+
+    fn quantities(batch: &SpooledOutcomes) -> Vec<Option<u32>> {
+        batch.outcomes.iter().map(|o| o.quantity).collect()
+    }
+
+    #[tokio::test]
+    async fn test_push_and_remove_in_order() {
+        let spool = spool(10).await;
+
+        let dropped = spool.push((1..=5).map(outcome).collect()).await.unwrap();
+        assert_eq!(dropped, 0);
+        assert_eq!(spool.count().await.unwrap(), 5);
+
+        let batch = spool.peek(3).await.unwrap().unwrap();
+        assert_eq!(quantities(&batch), [Some(1), Some(2), Some(3)]);
+        spool.remove(batch).await.unwrap();
+
+        let batch = spool.peek(3).await.unwrap().unwrap();
+        assert_eq!(quantities(&batch), [Some(4), Some(5)]);
+        spool.remove(batch).await.unwrap();
+
+        assert!(spool.peek(3).await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_peek_keeps_outcomes() {
+        let spool = spool(10).await;
+        spool.push((1..=2).map(outcome).collect()).await.unwrap();
+
+        let batch = spool.peek(10).await.unwrap().unwrap();
+        assert_eq!(quantities(&batch), [Some(1), Some(2)]);
+
+        // Outcomes pushed after the peek are not removed with the batch.
+        spool.push(vec![outcome(3)]).await.unwrap();
+        let batch = spool.peek(10).await.unwrap().unwrap();
+        assert_eq!(quantities(&batch), [Some(1), Some(2), Some(3)]);
+
+        let first = spool.peek(2).await.unwrap().unwrap();
+        spool.remove(first).await.unwrap();
+        let batch = spool.peek(10).await.unwrap().unwrap();
+        assert_eq!(quantities(&batch), [Some(3)]);
+    }
+
+    #[tokio::test]
+    async fn test_push_drops_beyond_capacity() {
+        let spool = spool(3).await;
+
+        assert_eq!(spool.push((1..=2).map(outcome).collect()).await.unwrap(), 0);
+        assert_eq!(spool.push((3..=5).map(outcome).collect()).await.unwrap(), 2);
+        assert_eq!(spool.count().await.unwrap(), 3);
+
+        let batch = spool.peek(10).await.unwrap().unwrap();
+        assert_eq!(quantities(&batch), [Some(1), Some(2), Some(3)]);
+    }
+
+    #[tokio::test]
+    async fn test_single_replay() {
+        let spool = spool(3).await;
+
+        assert!(spool.begin_replay());
+        assert!(!spool.clone().begin_replay());
+        spool.end_replay();
+        assert!(spool.begin_replay());
+    }
+}
@@ -58,7 +58,7 @@ use crate::services::projects::cache::ProjectCacheHandle;
 use crate::services::projects::project::{ProjectInfo, ProjectState};
 use crate::services::test_store::{Capture, TestStore};
 use crate::services::upstream::{
-    SendRequest, UpstreamRelay, UpstreamRequest, UpstreamRequestError,
+    SendRequest, UpstreamEncodings, UpstreamRelay, UpstreamRequest, UpstreamRequestError,
 };
 use crate::statsd::{RelayCounters, RelayHistograms, RelayTimers};
 use crate::utils::{
@@ -229,7 +229,7 @@ processing_group!(Ungrouped, Ungrouped);
 pub struct Processed;
 
 /// Describes the groups of the processable items.
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
 pub enum ProcessingGroup {
     /// All the transaction related items.
     ///
@@ -1110,6 +1110,8 @@ pub struct EnvelopeProcessorService {
 pub struct Addrs {
     pub outcome_aggregator: Addr<TrackOutcome>,
     pub upstream_relay: Addr<UpstreamRelay>,
+    /// Content encodings accepted by the upstream, used to encode forwarded envelopes.
+    pub upstream_encodings: UpstreamEncodings,
     pub test_store: Addr<TestStore>,
     #[cfg(feature = "processing")]
     pub store_forwarder: Option<Addr<Store>>,
@@ -1123,6 +1125,7 @@ impl Default for Addrs {
         Addrs {
             outcome_aggregator: Addr::dummy(),
             upstream_relay: Addr::dummy(),
+            upstream_encodings: UpstreamEncodings::default(),
             test_store: Addr::dummy(),
             #[cfg(feature = "processing")]
             store_forwarder: None,
@@ -2630,7 +2633,7 @@ impl EnvelopeProcessorService {
         envelope.envelope_mut().set_sent_at(Utc::now());
 
         relay_log::trace!("sending envelope to sentry endpoint");
-        let http_encoding = self.inner.config.http_encoding();
+        let http_encoding = self.http_encoding();
         let result = envelope.envelope().to_vec().and_then(|v| {
             encode_payload(&v.into(), http_encoding).map_err(EnvelopeError::PayloadIoFailed)
         });
@@ -3085,6 +3088,18 @@ impl EnvelopeProcessorService {
         }
     }
 
+    /// Returns the content encoding for request bodies sent to the upstream.
+    ///
+    /// This is the configured encoding, unless the upstream has advertised that it does not accept
+    /// it. Envelopes are buffered uncompressed, so they can be encoded for whichever upstream
+    /// they are forwarded to.
+    fn http_encoding(&self) -> HttpEncoding {
+        self.inner
+            .addrs
+            .upstream_encodings
+            .negotiate(self.inner.config.http_encoding())
+    }
+
     /// Creates a [`SendMetricsRequest`] and sends it to the upstream relay.
     fn send_global_partition(&self, partition_key: u32, partition: &mut Partition<'_>) {
         if partition.is_empty() {
@@ -3092,7 +3107,7 @@ impl EnvelopeProcessorService {
         }
 
         let (unencoded, project_info) = partition.take();
-        let http_encoding = self.inner.config.http_encoding();
+        let http_encoding = self.http_encoding();
         let encoded = match encode_payload(&unencoded, http_encoding) {
             Ok(payload) => payload,
             Err(error) => {
@@ -3726,6 +3741,7 @@ impl<'a> IntoIterator for CombinedQuotas<'a> {
 mod tests {
     use std::collections::BTreeMap;
     use std::env;
+    use std::io::Read;
 
     use insta::assert_debug_snapshot;
     use relay_base_schema::metrics::{DurationUnit, MetricUnit};
@@ -4259,6 +4275,59 @@ mod tests {
         }
     }
 
+    #[tokio::test]
+    async fn test_submit_envelope_negotiates_encoding() {
+        let mut token = Cogs::noop().timed(ResourceId::Relay, AppFeature::Unattributed);
+
+        // Zstd is configured by default, but the upstream only accepts gzip.
+        let upstream_encodings = UpstreamEncodings::default();
+        upstream_encodings.update("gzip, zstd;q=0");
+
+        let (upstream_relay, mut upstream_rx) = Addr::custom();
+        let processor = create_test_processor_with_addrs(
+            Config::default(),
+            Addrs {
+                upstream_relay,
+                upstream_encodings,
+                ..Default::default()
+            },
+        )
+        .await;
+
+        let envelope = testutils::new_envelope(false, "foo");
+        let event_id = envelope.event_id();
+        let envelope = ManagedEnvelope::new(
+            envelope,
+            Addr::dummy(),
+            Addr::dummy(),
+            ProcessingGroup::Transaction,
+        );
+        processor.handle_submit_envelope(
+            &mut token,
+            SubmitEnvelope {
+                envelope: envelope.into_processed(),
+            },
+        );
+
+        let UpstreamRelay::SendRequest(mut request) = upstream_rx.recv().await.unwrap() else {
+            panic!("expected an upstream request");
+        };
+        let mut builder = http::RequestBuilder::reqwest(
+            reqwest::Client::new().post("http://localhost/api/42/envelope/"),
+        );
+        request.build(&mut builder).unwrap();
+        let http::Request(request) = builder.finish().unwrap();
+
+        assert_eq!(request.headers()["content-encoding"], "gzip");
+
+        let mut decoded = Vec::new();
+        flate2::read::GzDecoder::new(request.body().unwrap().as_bytes().unwrap())
+            .read_to_end(&mut decoded)
+            .unwrap();
+        let forwarded = Envelope::parse_bytes(decoded.into()).unwrap();
+        assert_eq!(forwarded.event_id(), event_id);
+    }
+
     #[tokio::test]
     async fn test_process_batched_metrics() {
         let mut token = Cogs::noop().timed(ResourceId::Relay, AppFeature::Unattributed);
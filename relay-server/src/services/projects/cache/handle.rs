@@ -3,12 +3,12 @@ use std::sync::Arc;
 
 use relay_base_schema::project::ProjectKey;
 use relay_config::Config;
-use relay_system::Addr;
+use relay_system::{Addr, SendError};
 use tokio::sync::broadcast;
 
 use super::state::Shared;
 use crate::services::projects::cache::service::ProjectChange;
-use crate::services::projects::cache::{Project, ProjectCache};
+use crate::services::projects::cache::{Project, ProjectCache, Refetch};
 
 /// A synchronous handle to the [`ProjectCache`].
 ///
@@ -37,6 +37,14 @@ impl ProjectCacheHandle {
         self.service.send(ProjectCache::Fetch(project_key));
     }
 
+    /// Forces an immediate fetch of the supplied project, even if the cached state is up to date.
+    ///
+    /// Returns `false` if the refetch was debounced, because the project was refetched recently.
+    /// Completion of the fetch is reported through [`Self::changes`].
+    pub async fn refetch(&self, project_key: ProjectKey) -> Result<bool, SendError> {
+        self.service.send(Refetch(project_key)).await
+    }
+
     /// Returns a subscription to all [`ProjectChange`]'s.
     ///
     /// This stream notifies the subscriber about project state changes in the project cache.
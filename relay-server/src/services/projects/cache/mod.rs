@@ -5,4 +5,4 @@ mod state;
 
 pub use self::handle::ProjectCacheHandle;
 pub use self::project::{CheckedEnvelope, Project};
-pub use self::service::{ProjectCache, ProjectCacheService, ProjectChange};
+pub use self::service::{ProjectCache, ProjectCacheService, ProjectChange, Refetch};
@@ -1,12 +1,16 @@
 use std::sync::Arc;
+use std::time::Duration;
 
 use futures::future::BoxFuture;
 use futures::StreamExt as _;
 use relay_base_schema::project::ProjectKey;
 use relay_config::Config;
 use relay_statsd::metric;
-use relay_system::{Service, ServiceSpawn, ServiceSpawnExt as _};
+use relay_system::{
+    AsyncResponse, FromMessage, NoResponse, Sender, Service, ServiceSpawn, ServiceSpawnExt as _,
+};
 use tokio::sync::broadcast;
+use tokio::time::Instant;
 
 use crate::services::projects::cache::handle::ProjectCacheHandle;
 use crate::services::projects::cache::state::{CompletedFetch, Eviction, Fetch, ProjectStore};
@@ -25,6 +29,11 @@ use crate::utils::FuturesScheduled;
 /// do not deal with lags in the channel gracefully.
 const PROJECT_EVENTS_CHANNEL_SIZE: usize = 512_000;
 
+/// Minimum time between two forced refetches of the same project.
+///
+/// Refetches requested more frequently are ignored.
+const REFETCH_DEBOUNCE: Duration = Duration::from_secs(10);
+
 /// A cache for projects, which allows concurrent access to the cached projects.
 #[derive(Debug)]
 pub enum ProjectCache {
@@ -34,26 +43,47 @@ pub enum ProjectCache {
     /// from the cache. Fetches for an already cached project ensure the project
     /// is always up to date and not evicted.
     Fetch(ProjectKey),
+    /// Forces an immediate fetch of the specified project.
+    ///
+    /// See [`Refetch`].
+    Refetch(ProjectKey, Sender<bool>),
 }
 
 impl ProjectCache {
     fn variant(&self) -> &'static str {
         match self {
             Self::Fetch(_) => "fetch",
+            Self::Refetch(_, _) => "refetch",
         }
     }
 }
 
 impl relay_system::Interface for ProjectCache {}
 
-impl relay_system::FromMessage<Self> for ProjectCache {
-    type Response = relay_system::NoResponse;
+impl FromMessage<Self> for ProjectCache {
+    type Response = NoResponse;
 
     fn from_message(message: Self, _: ()) -> Self {
         message
     }
 }
 
+/// Forces an immediate fetch of a project from the upstream, bypassing all caches.
+///
+/// Unlike [`ProjectCache::Fetch`], the fetch is started even if the cached project is still up to
+/// date. Refetches of the same project are debounced. Responds with `true` if a fetch was started
+/// or is already in progress, and `false` if the refetch was debounced.
+#[derive(Debug)]
+pub struct Refetch(pub ProjectKey);
+
+impl FromMessage<Refetch> for ProjectCache {
+    type Response = AsyncResponse<bool>;
+
+    fn from_message(Refetch(project_key): Refetch, sender: Sender<bool>) -> Self {
+        Self::Refetch(project_key, sender)
+    }
+}
+
 /// Project life-cycle changes produced by the project cache.
 #[derive(Debug, Copy, Clone)]
 pub enum ProjectChange {
@@ -70,6 +100,8 @@ pub struct ProjectCacheService {
     config: Arc<Config>,
 
     scheduled_fetches: FuturesScheduled<BoxFuture<'static, CompletedFetch>>,
+    /// Projects which were recently refetched, used to debounce [`Refetch`] requests.
+    recent_refetches: hashbrown::HashMap<ProjectKey, Instant>,
 
     project_events_tx: broadcast::Sender<ProjectChange>,
 }
@@ -84,6 +116,7 @@ impl ProjectCacheService {
             source,
             config,
             scheduled_fetches: FuturesScheduled::default(),
+            recent_refetches: Default::default(),
             project_events_tx,
         }
     }
@@ -113,7 +146,7 @@ impl ProjectCacheService {
         let when = fetch.when();
         let task = async move {
             let state = match source
-                .fetch(fetch.project_key(), false, fetch.revision())
+                .fetch(fetch.project_key(), fetch.no_cache(), fetch.revision())
                 .await
             {
                 Ok(result) => result,
@@ -148,6 +181,30 @@ impl ProjectCacheService {
         }
     }
 
+    fn handle_refetch(&mut self, project_key: ProjectKey, sender: Sender<bool>) {
+        let now = Instant::now();
+        self.recent_refetches
+            .retain(|_, refetched_at| now.duration_since(*refetched_at) < REFETCH_DEBOUNCE);
+
+        if self.recent_refetches.contains_key(&project_key) {
+            relay_log::trace!(
+                tags.project_key = project_key.as_str(),
+                "project refetch debounced"
+            );
+            sender.send(false);
+            return;
+        }
+
+        self.recent_refetches.insert(project_key, now);
+
+        // If there is already a fetch in progress, its completion is reported the same way.
+        if let Some(fetch) = self.store.try_begin_refetch(project_key, &self.config) {
+            self.schedule_fetch(fetch);
+        }
+
+        sender.send(true);
+    }
+
     fn handle_completed_fetch(&mut self, fetch: CompletedFetch) {
         let project_key = fetch.project_key();
 
@@ -186,6 +243,7 @@ impl ProjectCacheService {
     fn handle_message(&mut self, message: ProjectCache) {
         match message {
             ProjectCache::Fetch(project_key) => self.handle_fetch(project_key),
+            ProjectCache::Refetch(project_key, sender) => self.handle_refetch(project_key, sender),
         }
     }
 }
@@ -60,6 +60,20 @@ impl ProjectStore {
         fetch
     }
 
+    /// Tries to begin a forced fetch for the passed `project_key`.
+    ///
+    /// Unlike [`Self::try_begin_fetch`], this starts a fetch even if the cached project is still
+    /// up to date or in a backoff. Returns `None` if there is already a fetch ongoing.
+    pub fn try_begin_refetch(&mut self, project_key: ProjectKey, config: &Config) -> Option<Fetch> {
+        let fetch = self.get_or_create(project_key, config).try_begin_refetch();
+
+        if fetch.is_some() {
+            self.evictions.remove(&project_key);
+        }
+
+        fetch
+    }
+
     /// Completes a [`CompletedFetch`] started with [`Self::try_begin_fetch`].
     ///
     /// Returns a new [`Fetch`] if another fetch must be scheduled. This happens when the fetched
@@ -268,6 +282,11 @@ impl ProjectRef<'_> {
             .map(|fetch| fetch.with_revision(self.shared.revision()))
     }
 
+    fn try_begin_refetch(&mut self) -> Option<Fetch> {
+        // Do not send the current revision, the upstream must always return the full state.
+        self.private.try_begin_refetch()
+    }
+
     fn complete_fetch(&mut self, fetch: CompletedFetch, config: &Config) -> Option<ExpiryTime> {
         let now = Instant::now();
         self.private.complete_fetch(&fetch, now);
@@ -307,6 +326,7 @@ pub struct Fetch {
     project_key: ProjectKey,
     when: Option<Instant>,
     revision: Revision,
+    no_cache: bool,
 }
 
 impl Fetch {
@@ -331,6 +351,13 @@ impl Fetch {
         self.revision.clone()
     }
 
+    /// Returns `true` if the fetch must bypass all caches of the project source.
+    ///
+    /// This is the case for fetches started with [`ProjectStore::try_begin_refetch`].
+    pub fn no_cache(&self) -> bool {
+        self.no_cache
+    }
+
     /// Completes the fetch with a result and returns a [`CompletedFetch`].
     pub fn complete(self, state: SourceProjectState) -> CompletedFetch {
         CompletedFetch {
@@ -516,6 +543,26 @@ impl PrivateProjectState {
             project_key: self.project_key,
             when,
             revision: Revision::default(),
+            no_cache: false,
+        })
+    }
+
+    fn try_begin_refetch(&mut self) -> Option<Fetch> {
+        if matches!(self.state, FetchState::InProgress) {
+            relay_log::trace!(
+                tags.project_key = self.project_key.as_str(),
+                "project refetch skipped, fetch in progress"
+            );
+            return None;
+        }
+
+        self.state = FetchState::InProgress;
+
+        Some(Fetch {
+            project_key: self.project_key,
+            when: None,
+            revision: Revision::default(),
+            no_cache: true,
         })
     }
 
@@ -698,6 +745,33 @@ mod tests {
         assert_state!(store, project_key, ProjectState::Enabled(_));
     }
 
+    #[tokio::test(start_paused = true)]
+    async fn test_store_refetch() {
+        let project_key = ProjectKey::parse("aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa").unwrap();
+        let mut store = ProjectStore::default();
+        let config = Default::default();
+
+        let fetch = store.try_begin_fetch(project_key, &config).unwrap();
+        assert!(!fetch.no_cache());
+
+        // A refetch is not possible while another fetch is in progress.
+        assert!(store.try_begin_refetch(project_key, &config).is_none());
+
+        let fetch = fetch.complete(ProjectState::Disabled.into());
+        assert!(store.complete_fetch(fetch, &config).is_none());
+
+        // The project is up to date, but a refetch is still started immediately.
+        assert!(store.try_begin_fetch(project_key, &config).is_none());
+        let fetch = store.try_begin_refetch(project_key, &config).unwrap();
+        assert_eq!(fetch.when(), None);
+        assert_eq!(fetch.revision().as_str(), None);
+        assert!(fetch.no_cache());
+
+        let fetch = fetch.complete(ProjectState::new_allowed().into());
+        assert!(store.complete_fetch(fetch, &config).is_none());
+        assert_state!(store, project_key, ProjectState::Enabled(_));
+    }
+
     #[tokio::test(start_paused = true)]
     async fn test_store_evict_projects() {
         let project_key1 = ProjectKey::parse("aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa").unwrap();
@@ -106,9 +106,30 @@ impl ProjectInfo {
         envelope: &Envelope,
         config: &Config,
     ) -> Result<(), DiscardReason> {
+        self.check_request(envelope.meta(), config)?;
+
+        // Check feature.
+        if let Some(disabled_feature) = envelope
+            .required_features()
+            .iter()
+            .find(|f| !self.has_feature(**f))
+        {
+            return Err(DiscardReason::FeatureDisabled(*disabled_feature));
+        }
+
+        Ok(())
+    }
+
+    /// Determines whether requests with the given meta data are accepted by this project.
+    ///
+    /// This runs the checks of [`Self::check_envelope`] that do not depend on the contents of the
+    /// envelope:
+    ///
+    ///  - Allowed origin headers
+    ///  - Matching project id and project key (DSN)
+    pub fn check_request(&self, meta: &RequestMeta, config: &Config) -> Result<(), DiscardReason> {
         // Verify that the stated project id in the DSN matches the public key used to retrieve this
         // project state.
-        let meta = envelope.meta();
         if !self.is_valid_project_id(meta.project_id(), config) {
             return Err(DiscardReason::ProjectId);
         }
@@ -124,15 +145,6 @@ impl ProjectInfo {
             return Err(DiscardReason::ProjectId);
         }
 
-        // Check feature.
-        if let Some(disabled_feature) = envelope
-            .required_features()
-            .iter()
-            .find(|f| !self.has_feature(**f))
-        {
-            return Err(DiscardReason::FeatureDisabled(*disabled_feature));
-        }
-
         Ok(())
     }
 
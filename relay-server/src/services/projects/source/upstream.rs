@@ -30,7 +30,8 @@ use crate::utils::{RetryBackoff, SleepHandle};
 /// A query to retrieve a batch of project states from upstream.
 ///
 /// This query does not implement `Deserialize`. To parse the query, use a wrapper that skips
-/// invalid project keys instead of failing the entire batch.
+/// invalid project keys instead of failing the entire batch. Relay requests version 3 of the
+/// endpoint, which skips invalid project keys and unknown fields. Only version 4 rejects them.
 #[derive(Debug, Serialize)]
 #[serde(rename_all = "camelCase")]
 pub struct GetProjectStates {
@@ -59,6 +59,8 @@ type App = NormalizePath<axum::Router>;
 
 /// Build the axum application with all routes and middleware.
 fn make_app(service: ServiceState) -> App {
+    let retry_after_jitter = service.config().retry_after_jitter();
+
     // Build the router middleware into a single service which runs _after_ routing. Service
     // builder order defines layers added first will be called first. This means:
     //  - Requests go from top to bottom
@@ -75,6 +77,7 @@ fn make_app(service: ServiceState) -> App {
             HeaderName::from_static("cross-origin-resource-policy"),
             HeaderValue::from_static("cross-origin"),
         ))
+        .map_response(move |response| middlewares::jitter_retry_after(response, retry_after_jitter))
         .layer(NewSentryLayer::new_from_top())
         .layer(SentryHttpLayer::with_transaction())
         .layer(middlewares::trace_http_layer())
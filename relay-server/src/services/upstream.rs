@@ -9,13 +9,14 @@ use std::collections::VecDeque;
 use std::fmt;
 use std::future::Future;
 use std::pin::Pin;
+use std::sync::atomic::{AtomicU8, Ordering};
 use std::sync::Arc;
 use std::time::Duration;
 
 use bytes::Bytes;
 use itertools::Itertools;
 use relay_auth::{RegisterChallenge, RegisterRequest, RegisterResponse, Registration};
-use relay_config::{Config, Credentials, RelayMode};
+use relay_config::{Config, Credentials, HttpEncoding, RelayMode, UpstreamDescriptor};
 use relay_quotas::{
     DataCategories, QuotaScope, RateLimit, RateLimitScope, RateLimits, ReasonCode, RetryAfter,
     Scoping,
@@ -130,7 +131,7 @@ impl UpstreamRequestError {
     /// If this error is the result of sending a request to the upstream, this method returns `Some`
     /// with the status code. If the request could not be made or the error originates elsewhere,
     /// this returns `None`.
-    fn status_code(&self) -> Option<StatusCode> {
+    pub fn status_code(&self) -> Option<StatusCode> {
         match self {
             UpstreamRequestError::ResponseError(code, _) => Some(*code),
             UpstreamRequestError::Http(HttpError::Reqwest(e)) => e.status(),
@@ -222,6 +223,99 @@ pub struct IsAuthenticated;
 #[derive(Debug)]
 pub struct IsNetworkOutage;
 
+/// Content encodings that the upstream accepts for request bodies.
+///
+/// The upstream advertises its accepted encodings with the `Accept-Encoding` header on its
+/// responses. The encodings of the last response carrying this header are cached and shared
+/// between the [`UpstreamRelayService`] and the services that encode request bodies. Responses
+/// to forwarded requests and to requests sent to other upstreams are ignored.
+///
+/// As long as the upstream has not advertised any encodings, it is assumed to accept all of them.
+#[derive(Clone, Debug, Default)]
+pub struct UpstreamEncodings(Arc<AtomicU8>);
+
+impl UpstreamEncodings {
+    /// Set if the upstream has advertised its accepted encodings.
+    const KNOWN: u8 = 1;
+
+    /// Encodings in the order in which they are preferred if the configured one is not accepted.
+    const PREFERENCE: [HttpEncoding; 4] = [
+        HttpEncoding::Zstd,
+        HttpEncoding::Br,
+        HttpEncoding::Gzip,
+        HttpEncoding::Deflate,
+    ];
+
+    /// Returns the bit that represents the given encoding.
+    fn bit(encoding: HttpEncoding) -> u8 {
+        match encoding {
+            HttpEncoding::Identity => 0,
+            HttpEncoding::Deflate => 1 << 1,
+            HttpEncoding::Gzip => 1 << 2,
+            HttpEncoding::Br => 1 << 3,
+            HttpEncoding::Zstd => 1 << 4,
+        }
+    }
+
+    /// Updates the accepted encodings from the value of an `Accept-Encoding` header.
+    ///
+    /// Encodings with a quality value of `0` are not accepted, the wildcard `*` accepts all
+    /// encodings.
+    pub fn update(&self, accept_encoding: &str) {
+        let mut accepted = Self::KNOWN;
+
+        for entry in accept_encoding.split(',') {
+            let mut parts = entry.split(';');
+            let name = parts.next().unwrap_or_default().trim();
+            let rejected = parts.any(|param| {
+                param
+                    .trim()
+                    .strip_prefix("q=")
+                    .and_then(|q| q.trim().parse::<f32>().ok())
+                    .is_some_and(|q| q <= 0.0)
+            });
+
+            if rejected {
+                continue;
+            }
+
+            if name == "*" {
+                accepted |= Self::PREFERENCE
+                    .into_iter()
+                    .map(Self::bit)
+                    .fold(0, |a, b| a | b);
+            } else {
+                accepted |= Self::bit(HttpEncoding::parse(name));
+            }
+        }
+
+        self.0.store(accepted, Ordering::Relaxed);
+    }
+
+    /// Returns `true` if the upstream accepts request bodies with the given encoding.
+    pub fn accepts(&self, encoding: HttpEncoding) -> bool {
+        let accepted = self.0.load(Ordering::Relaxed);
+        accepted & Self::KNOWN == 0
+            || encoding.name().is_none()
+            || accepted & Self::bit(encoding) != 0
+    }
+
+    /// Returns the encoding to use for a request body.
+    ///
+    /// This is the `preferred` encoding if the upstream accepts it. Otherwise, this falls back to
+    /// the best compression that the upstream accepts, or no compression at all.
+    pub fn negotiate(&self, preferred: HttpEncoding) -> HttpEncoding {
+        if self.accepts(preferred) {
+            return preferred;
+        }
+
+        Self::PREFERENCE
+            .into_iter()
+            .find(|&encoding| self.accepts(encoding))
+            .unwrap_or(HttpEncoding::Identity)
+    }
+}
+
 /// Priority of an upstream request.
 ///
 /// See [`UpstreamRequest::priority`] for more information.
@@ -257,6 +351,13 @@ pub trait UpstreamRequest: Send + Sync + fmt::Debug {
     /// The path relative to the upstream.
     fn path(&self) -> Cow<'_, str>;
 
+    /// The upstream to which this request is sent.
+    ///
+    /// Defaults to `None`, which sends the request to the configured upstream of this Relay.
+    fn upstream(&self) -> Option<&UpstreamDescriptor<'static>> {
+        None
+    }
+
     /// Whether this request should retry on network errors.
     ///
     /// Defaults to `true` and should be disabled if there is an external retry mechanism. Note that
@@ -298,6 +399,16 @@ pub trait UpstreamRequest: Send + Sync + fmt::Debug {
         true
     }
 
+    /// Whether this request is forwarded on behalf of a client.
+    ///
+    /// Forwarded requests are sent through a separate connection pool configured with
+    /// `forwarding.client`.
+    ///
+    /// Defaults to `false`.
+    fn forwarded(&self) -> bool {
+        false
+    }
+
     /// Add the `X-Sentry-Relay-Signature` header to the outgoing request.
     ///
     /// When no signature should be added, this method should return `None`. Otherwise, this method
@@ -731,12 +842,34 @@ impl UpstreamQuery for RegisterResponse {
 struct SharedClient {
     config: Arc<Config>,
     reqwest: reqwest::Client,
+    /// Client for [forwarded](UpstreamRequest::forwarded) requests with its own connection pool.
+    forward: reqwest::Client,
+    encodings: UpstreamEncodings,
 }
 
 impl SharedClient {
     /// Creates a new `SharedClient` instance.
-    pub fn build(config: Arc<Config>) -> Self {
-        let reqwest = reqwest::ClientBuilder::new()
+    pub fn build(config: Arc<Config>, encodings: UpstreamEncodings) -> Self {
+        let reqwest = Self::client_builder(&config).build().unwrap();
+
+        let forward = Self::client_builder(&config)
+            .pool_idle_timeout(config.forwarding_pool_idle_timeout())
+            .pool_max_idle_per_host(config.forwarding_pool_max_idle_per_host())
+            .tcp_keepalive(config.forwarding_tcp_keepalive())
+            .build()
+            .unwrap();
+
+        Self {
+            config,
+            reqwest,
+            forward,
+            encodings,
+        }
+    }
+
+    /// Returns a client builder with the settings shared by all requests.
+    fn client_builder(config: &Config) -> reqwest::ClientBuilder {
+        reqwest::ClientBuilder::new()
             .connect_timeout(config.http_connection_timeout())
             .timeout(config.http_timeout())
             // In the forward endpoint, this means that content negotiation is done twice, and the
@@ -746,10 +879,15 @@ impl SharedClient {
             // the resolved entries. This helps to limit the amount of requests made to upstream DNS
             // server (important for K8s infrastructure).
             .hickory_dns(true)
-            .build()
-            .unwrap();
+    }
 
-        Self { config, reqwest }
+    /// Returns the client that sends the given request.
+    fn client(&self, request: &dyn UpstreamRequest) -> &reqwest::Client {
+        if request.forwarded() {
+            &self.forward
+        } else {
+            &self.reqwest
+        }
     }
 
     /// Builds the request in a non-blocking fashion.
@@ -762,17 +900,20 @@ impl SharedClient {
         request: &mut dyn UpstreamRequest,
     ) -> Result<reqwest::Request, UpstreamRequestError> {
         tokio::task::block_in_place(|| {
-            let url = self
-                .config
-                .upstream_descriptor()
-                .get_url(request.path().as_ref());
-
-            let host_header = self
-                .config
-                .http_host_header()
-                .unwrap_or_else(|| self.config.upstream_descriptor().host());
+            let (url, host_header) = match request.upstream() {
+                Some(upstream) => (upstream.get_url(request.path().as_ref()), upstream.host()),
+                None => {
+                    let upstream = self.config.upstream_descriptor();
+                    let host_header = self
+                        .config
+                        .http_host_header()
+                        .unwrap_or_else(|| upstream.host());
+                    (upstream.get_url(request.path().as_ref()), host_header)
+                }
+            };
 
-            let mut builder = RequestBuilder::reqwest(self.reqwest.request(request.method(), url));
+            let client = self.client(request);
+            let mut builder = RequestBuilder::reqwest(client.request(request.method(), url));
             builder.header("Host", host_header.as_bytes());
 
             if request.set_relay_id() {
@@ -862,8 +1003,20 @@ impl SharedClient {
     ) -> Result<Response, UpstreamRequestError> {
         request.configure(&self.config);
         let client_request = self.build_request(request)?;
-        let response = self.reqwest.execute(client_request).await?;
-        self.transform_response(request, Response(response)).await
+        let response = Response(self.client(request).execute(client_request).await?);
+
+        // Only the configured upstream advertises the encodings that are cached. Responses of
+        // other upstreams and of forwarded requests do not describe it.
+        if request.upstream().is_none() && !request.forwarded() {
+            let accept_encoding = response
+                .get_header(header::ACCEPT_ENCODING)
+                .and_then(|v| std::str::from_utf8(v).ok());
+            if let Some(accept_encoding) = accept_encoding {
+                self.encodings.update(accept_encoding);
+            }
+        }
+
+        self.transform_response(request, response).await
     }
 
     /// Convenience method to send a query to the upstream and await the result.
@@ -1064,6 +1217,11 @@ enum RequestOutcome {
     /// This does not automatically mean that the request was successfully accepted. It could also
     /// have been rate limited or rejected as invalid.
     Received,
+    /// The request was sent to a different upstream than the configured one.
+    ///
+    /// The result of the request does not reflect the state of the connection to the configured
+    /// upstream.
+    OtherUpstream,
 }
 
 /// Internal message of the upstream's [`UpstreamBroker`].
@@ -1438,6 +1596,7 @@ impl UpstreamBroker {
             emit_response_metrics(send_start, &entry, &result);
 
             let status = match result {
+                _ if entry.request.upstream().is_some() => RequestOutcome::OtherUpstream,
                 Err(ref err) if err.is_network_error() => RequestOutcome::Dropped,
                 _ => RequestOutcome::Received,
             };
@@ -1467,6 +1626,7 @@ impl UpstreamBroker {
                 self.conn.reset_error();
                 self.queue.trigger_retries();
             }
+            RequestOutcome::OtherUpstream => {}
         }
     }
 
@@ -1485,13 +1645,22 @@ impl UpstreamBroker {
 #[derive(Debug)]
 pub struct UpstreamRelayService {
     config: Arc<Config>,
+    encodings: UpstreamEncodings,
 }
 
 impl UpstreamRelayService {
     /// Creates a new `UpstreamRelay` instance.
     pub fn new(config: Arc<Config>) -> Self {
         // Broker and other actual components are implemented in the Service's `spawn_handler`.
-        Self { config }
+        Self {
+            config,
+            encodings: UpstreamEncodings::default(),
+        }
+    }
+
+    /// Returns a handle to the content encodings accepted by the upstream.
+    pub fn encodings(&self) -> UpstreamEncodings {
+        self.encodings.clone()
     }
 }
 
@@ -1499,9 +1668,9 @@ impl Service for UpstreamRelayService {
     type Interface = UpstreamRelay;
 
     async fn run(self, mut rx: relay_system::Receiver<Self::Interface>) {
-        let Self { config } = self;
+        let Self { config, encodings } = self;
 
-        let client = SharedClient::build(config.clone());
+        let client = SharedClient::build(config.clone(), encodings);
 
         // Channel for serialized communication from the auth monitor, connection monitor, and
         // concurrent requests back to the broker.
@@ -1541,3 +1710,174 @@ impl Service for UpstreamRelayService {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::AtomicUsize;
+
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    use super::*;
+
+    #[derive(Debug)]
+    struct TestRequest {
+        forwarded: bool,
+    }
+
+    impl UpstreamRequest for TestRequest {
+        fn method(&self) -> Method {
+            Method::GET
+        }
+
+        fn path(&self) -> Cow<'_, str> {
+            Cow::Borrowed("/")
+        }
+
+        fn set_relay_id(&self) -> bool {
+            false
+        }
+
+        fn forwarded(&self) -> bool {
+            self.forwarded
+        }
+
+        fn route(&self) -> &'static str {
+            "test"
+        }
+
+        fn respond(
+            self: Box<Self>,
+            _: Result<Response, UpstreamRequestError>,
+        ) -> Pin<Box<dyn Future<Output = ()> + Send + Sync>> {
+            Box::pin(async {})
+        }
+    }
+
+    /// Sends `requests` forwarded requests and returns the number of connections opened.
+    async fn count_forward_connections(client: serde_json::Value, requests: usize) -> usize {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let connections = Arc::new(AtomicUsize::new(0));
+
+        let accepted = connections.clone();
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                accepted.fetch_add(1, Ordering::Relaxed);
+                tokio::spawn(async move {
+                    // Requests are small and without body, so every read is a full request.
+                    let mut buf = [0; 4096];
+                    while let Ok(1..) = stream.read(&mut buf).await {
+                        let response = b"HTTP/1.1 200 OK\r\ncontent-length: 2\r\n\r\nok";
+                        if stream.write_all(response).await.is_err() {
+                            break;
+                        }
+                    }
+                });
+            }
+        });
+
+        let config = Config::from_json_value(serde_json::json!({
+            "relay": {"upstream": format!("http://{addr}/")},
+            "forwarding": {"client": client},
+        }))
+        .unwrap();
+        let client = SharedClient::build(Arc::new(config), UpstreamEncodings::default());
+
+        for _ in 0..requests {
+            let mut request = TestRequest { forwarded: true };
+            let response = client.send(&mut request).await.unwrap();
+            assert_eq!(response.bytes(1024).await.unwrap(), b"ok");
+        }
+
+        connections.load(Ordering::Relaxed)
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_forward_reuses_connections() {
+        let connections = count_forward_connections(serde_json::json!({}), 3).await;
+        assert_eq!(connections, 1);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_forward_without_idle_connections() {
+        let client = serde_json::json!({"pool_max_idle_per_host": 0});
+        let connections = count_forward_connections(client, 3).await;
+        assert_eq!(connections, 3);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_encodings_only_from_configured_upstream() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                tokio::spawn(async move {
+                    let mut buf = [0; 4096];
+                    while let Ok(1..) = stream.read(&mut buf).await {
+                        let response = b"HTTP/1.1 200 OK\r\naccept-encoding: identity\r\n\
+                            content-length: 2\r\n\r\nok";
+                        if stream.write_all(response).await.is_err() {
+                            break;
+                        }
+                    }
+                });
+            }
+        });
+
+        let config = Config::from_json_value(serde_json::json!({
+            "relay": {"upstream": format!("http://{addr}/")},
+        }))
+        .unwrap();
+        let encodings = UpstreamEncodings::default();
+        let client = SharedClient::build(Arc::new(config), encodings.clone());
+
+        // Forwarded requests do not describe the configured upstream.
+        let mut request = TestRequest { forwarded: true };
+        client.send(&mut request).await.unwrap();
+        assert!(encodings.accepts(HttpEncoding::Zstd));
+
+        let mut request = TestRequest { forwarded: false };
+        client.send(&mut request).await.unwrap();
+        assert!(!encodings.accepts(HttpEncoding::Zstd));
+    }
+
+    #[test]
+    fn test_negotiate_encoding() {
+        let encodings = UpstreamEncodings::default();
+
+        // All encodings are assumed to be accepted until the upstream advertises its encodings.
+        assert!(matches!(
+            encodings.negotiate(HttpEncoding::Zstd),
+            HttpEncoding::Zstd
+        ));
+
+        encodings.update("gzip, deflate;q=0.5, zstd;q=0");
+        assert!(matches!(
+            encodings.negotiate(HttpEncoding::Zstd),
+            HttpEncoding::Gzip
+        ));
+        assert!(matches!(
+            encodings.negotiate(HttpEncoding::Deflate),
+            HttpEncoding::Deflate
+        ));
+        assert!(matches!(
+            encodings.negotiate(HttpEncoding::Identity),
+            HttpEncoding::Identity
+        ));
+
+        encodings.update("identity");
+        assert!(matches!(
+            encodings.negotiate(HttpEncoding::Zstd),
+            HttpEncoding::Identity
+        ));
+
+        encodings.update("*");
+        assert!(matches!(
+            encodings.negotiate(HttpEncoding::Br),
+            HttpEncoding::Br
+        ));
+    }
+}
@@ -36,6 +36,35 @@ pub enum RelayGauges {
     BufferStackCount,
     /// The used disk for the buffer.
     BufferDiskUsed,
+    /// The number of stacks to flush when the buffer starts flushing during shutdown.
+    ///
+    /// This metric is tagged with:
+    /// - `partition_id`: The partition of the buffer.
+    BufferFlushTotal,
+    /// The number of stacks that still have to be flushed during shutdown.
+    ///
+    /// Reaches `0` once the flush is complete. Together with [`Self::BufferFlushTotal`], this
+    /// shows the progress of the flush.
+    ///
+    /// This metric is tagged with:
+    /// - `partition_id`: The partition of the buffer.
+    BufferFlushRemaining,
+    /// The number of envelopes in the store that are not reachable through the recovered stacks.
+    ///
+    /// Only reported on startup if `spool.envelopes.verify_on_start` is enabled.
+    ///
+    /// This metric is tagged with:
+    /// - `partition_id`: The partition of the buffer.
+    BufferRecoveryMismatch,
+    /// Reported once by every buffer partition after it finished loading on startup.
+    ///
+    /// Only reported if `spool.envelopes.report_initialized` is enabled.
+    ///
+    /// This metric is tagged with:
+    /// - `partition_id`: The partition of the buffer.
+    /// - `measure`: `stacks` for the number of loaded stacks, `envelopes` for the number of
+    ///   envelopes in the store, and `duration_ms` for the duration of the initialization.
+    BufferInitialized,
     /// The currently used memory by the entire system.
     ///
     /// Relay uses the same value for its memory health check.
@@ -77,6 +106,8 @@ pub enum RelayGauges {
     /// - `service`: the service name.
     /// - `instance_id`: a for the service name unique identifier for the running service
     ServiceUtilization,
+    /// The number of requests that are currently proxied by the forward endpoint.
+    ForwardInFlight,
 }
 
 impl GaugeMetric for RelayGauges {
@@ -88,6 +119,10 @@ impl GaugeMetric for RelayGauges {
             RelayGauges::NetworkOutage => "upstream.network_outage",
             RelayGauges::BufferStackCount => "buffer.stack_count",
             RelayGauges::BufferDiskUsed => "buffer.disk_used",
+            RelayGauges::BufferFlushTotal => "buffer.flush.total",
+            RelayGauges::BufferFlushRemaining => "buffer.flush.remaining",
+            RelayGauges::BufferRecoveryMismatch => "buffer.recovery_mismatch",
+            RelayGauges::BufferInitialized => "buffer.initialized",
             RelayGauges::SystemMemoryUsed => "health.system_memory.used",
             RelayGauges::SystemMemoryTotal => "health.system_memory.total",
             #[cfg(feature = "processing")]
@@ -106,6 +141,7 @@ impl GaugeMetric for RelayGauges {
             #[cfg(feature = "processing")]
             RelayGauges::MetricDelayMax => "metrics.delay.max",
             RelayGauges::ServiceUtilization => "service.utilization",
+            RelayGauges::ForwardInFlight => "forward.in_flight",
         }
     }
 }
@@ -234,6 +270,30 @@ pub enum RelayHistograms {
     BufferEnvelopeSize,
     /// Size of a compressed envelope pushed to the envelope buffer.
     BufferEnvelopeSizeCompressed,
+    /// Age in seconds of envelopes in the envelope buffer.
+    ///
+    /// The buffer periodically samples a bounded number of stacks and reports the age of the most
+    /// recent envelope of each sampled stack.
+    ///
+    /// This metric is tagged with:
+    /// - `partition_id`: The id of the buffer partition.
+    BufferEnvelopeAgeDistribution,
+    /// Age in seconds of the oldest envelope popped from the envelope buffer.
+    ///
+    /// The age is measured from the time the envelope was received. The maximum since the last
+    /// report is reported together with [`Self::BufferEnvelopeAgeDistribution`], if envelopes were
+    /// popped in the meantime. A high value indicates that some stacks are starved.
+    ///
+    /// This metric is tagged with:
+    /// - `partition_id`: The id of the buffer partition.
+    BufferMaxServedAge,
+    /// Number of envelopes returned by a single batch pop from the envelope buffer.
+    ///
+    /// The size of a batch is limited by `spool.envelopes.max_pop_batch`.
+    ///
+    /// This metric is tagged with:
+    /// - `partition_id`: The id of the buffer partition.
+    BufferPopBatchSize,
     /// The number of batches emitted per partition.
     BatchesPerPartition,
     /// The number of buckets in a batch emitted.
@@ -340,6 +400,9 @@ impl HistogramMetric for RelayHistograms {
             RelayHistograms::BufferEnvelopeBodySize => "buffer.envelope_body_size",
             RelayHistograms::BufferEnvelopeSize => "buffer.envelope_size",
             RelayHistograms::BufferEnvelopeSizeCompressed => "buffer.envelope_size.compressed",
+            RelayHistograms::BufferEnvelopeAgeDistribution => "buffer.envelope_age",
+            RelayHistograms::BufferMaxServedAge => "buffer.max_served_age",
+            RelayHistograms::BufferPopBatchSize => "buffer.pop_batch_size",
             RelayHistograms::ProjectStatePending => "project_state.pending",
             RelayHistograms::ProjectStateAttempts => "project_state.attempts",
             RelayHistograms::ProjectStateRequestBatchSize => "project_state.request.batch_size",
@@ -557,6 +620,11 @@ pub enum RelayTimers {
     BufferSpool,
     /// Timing in milliseconds for the time it takes for the buffer to spool data to SQLite.
     BufferSqlWrite,
+    /// Timing in milliseconds for compacting the envelope buffer database.
+    ///
+    /// This metric is tagged with:
+    /// - `partition_id`: The id of the buffer partition.
+    BufferSqliteMaintenance,
     /// Timing in milliseconds for the time it takes for the buffer to unspool data from disk.
     BufferUnspool,
     /// Timing in milliseconds for the time it takes for the buffer to push.
@@ -567,6 +635,13 @@ pub enum RelayTimers {
     BufferPop,
     /// Timing in milliseconds for the time it takes for the buffer to drain its envelopes.
     BufferDrain,
+    /// Timing in milliseconds for the time it takes for the buffer to reorder its priority queue.
+    ///
+    /// This metric is tagged with:
+    ///  - `operation`: The buffer operation that changed the priority queue, for example `push`,
+    ///    `pop` or `mark_ready`.
+    ///  - `partition_id`: The partition of the buffer.
+    BufferReprioritize,
     /// Timing in milliseconds for the time it takes for an envelope to be serialized.
     BufferEnvelopesSerialization,
     /// Timing in milliseconds for the time it takes for an envelope to be compressed.
@@ -618,11 +693,13 @@ impl TimerMetric for RelayTimers {
             RelayTimers::BufferInitialization => "buffer.initialization.duration",
             RelayTimers::BufferSpool => "buffer.spool.duration",
             RelayTimers::BufferSqlWrite => "buffer.write.duration",
+            RelayTimers::BufferSqliteMaintenance => "buffer.sqlite_maintenance.duration",
             RelayTimers::BufferUnspool => "buffer.unspool.duration",
             RelayTimers::BufferPush => "buffer.push.duration",
             RelayTimers::BufferPeek => "buffer.peek.duration",
             RelayTimers::BufferPop => "buffer.pop.duration",
             RelayTimers::BufferDrain => "buffer.drain.duration",
+            RelayTimers::BufferReprioritize => "buffer.reprioritize.duration",
             RelayTimers::BufferEnvelopesSerialization => "buffer.envelopes_serialization",
             RelayTimers::BufferEnvelopeCompression => "buffer.envelopes_compression",
             RelayTimers::BufferEnvelopeDecompression => "buffer.envelopes_decompression",
@@ -679,6 +756,57 @@ pub enum RelayCounters {
     /// Number of times one or more projects of an envelope were pending when trying to pop
     /// their envelope.
     BufferProjectPending,
+    /// Number of envelopes evicted from the bottom of a buffer stack because the stack exceeded
+    /// the maximum stack depth.
+    BufferStackDepthExceeded,
+    /// Number of envelopes of unsampled traces evicted from a full buffer to make room for other
+    /// envelopes, see `spool.envelopes.deprioritize_unsampled`.
+    BufferUnsampledEvicted,
+    /// Number of envelopes rejected because they would have created a new buffer stack beyond
+    /// `spool.envelopes.max_new_stacks_per_sec`.
+    BufferStackCreationLimited,
+    /// Number of envelopes dropped by the buffer because they are identical to an envelope pushed
+    /// recently, see `spool.envelopes.fingerprint_window`.
+    BufferRetryDropped,
+    /// Number of envelopes pushed back into the buffer because they were not acknowledged
+    /// within their visibility timeout.
+    BufferRedelivered,
+    /// Number of envelopes forwarded without waiting for their projects because the buffer
+    /// exceeded the maximum stall duration.
+    BufferForcedProgress,
+    /// Number of buffer stacks that were quarantined after repeated pop failures.
+    BufferStackQuarantined,
+    /// Number of times the envelope buffer observed the system clock going backwards.
+    ///
+    /// Stacks pushed after such a jump are prioritized by push order over older stacks.
+    ///
+    /// This metric is tagged with:
+    /// - `partition_id`: The id of the buffer partition.
+    BufferClockBackwards,
+    /// Number of retries of envelope buffer database operations that failed because the database
+    /// was locked.
+    ///
+    /// This metric is tagged with:
+    /// - `partition_id`: The id of the buffer partition.
+    BufferSqliteBusyRetry,
+    /// Number of bytes reclaimed by compacting the envelope buffer database.
+    ///
+    /// This metric is tagged with:
+    /// - `partition_id`: The id of the buffer partition.
+    BufferSqliteMaintenanceReclaimed,
+    /// Number of envelopes kept in memory because the envelope buffer database stayed locked.
+    ///
+    /// This is only reported with the `memory` busy fallback.
+    ///
+    /// This metric is tagged with:
+    /// - `partition_id`: The id of the buffer partition.
+    BufferSqliteBusyOverflow,
+    /// Number of buffer operations that took longer than `spool.envelopes.slow_op_threshold_ms`.
+    ///
+    /// This metric is tagged with:
+    /// - `operation`: The slow operation, one of `push`, `pop`, `peek` or `flush`.
+    /// - `partition_id`: The id of the buffer partition.
+    BufferSlowOperation,
     /// Number of outcomes and reasons for rejected Envelopes.
     ///
     /// This metric is tagged with:
@@ -853,6 +981,34 @@ pub enum RelayCounters {
     ServerSocketAccept,
     /// Incremented every time the server aborts a connection because of an idle timeout.
     ServerConnectionIdleTimeout,
+    /// Number of Unreal crash reports received on the unreal endpoint.
+    ///
+    /// This metric is tagged with:
+    ///  - `format`: The detected payload format, one of `zlib`, `gzip`, `uncompressed` or
+    ///    `unknown`.
+    UnrealReportFormat,
+    /// Number of requests the forward endpoint sent to each of its configured upstreams.
+    ///
+    /// This metric is tagged with:
+    ///  - `upstream`: The upstream the request was sent to.
+    ///  - `result`: `"success"`, or `"failure"` if the upstream could not be reached or responded
+    ///    with a server error.
+    ForwardUpstreamRequest,
+    /// Number of requests to the store endpoint rejected because the body exceeds
+    /// `limits.max_event_size`.
+    StoreOversized,
+    /// Number of outcomes written to the outcome spool after they failed to send upstream.
+    ///
+    /// This metric is only emitted if `outcomes.spool` is enabled.
+    OutcomesSpooled,
+    /// Number of outcomes dropped because they could not be written to the outcome spool.
+    ///
+    /// This happens if the spool holds `outcomes.max_spooled` outcomes or fails to write to disk.
+    OutcomesSpoolDropped,
+    /// Number of outcomes dropped because the upstream rejected them with a client error.
+    ///
+    /// Such outcomes are not spooled, since sending them again would fail the same way.
+    OutcomesRejected,
     /// The total delay of metric buckets in seconds.
     ///
     /// The delay is measured from initial creation of the bucket in an internal Relay
@@ -889,6 +1045,20 @@ impl CounterMetric for RelayCounters {
             RelayCounters::BufferUnspooledEnvelopes => "buffer.unspooled_envelopes",
             RelayCounters::BufferProjectChangedEvent => "buffer.project_changed_event",
             RelayCounters::BufferProjectPending => "buffer.project_pending",
+            RelayCounters::BufferStackDepthExceeded => "buffer.stack_depth_exceeded",
+            RelayCounters::BufferUnsampledEvicted => "buffer.unsampled_evicted",
+            RelayCounters::BufferStackCreationLimited => "buffer.stack_creation_limited",
+            RelayCounters::BufferRetryDropped => "buffer.retry_dropped",
+            RelayCounters::BufferRedelivered => "buffer.redelivered",
+            RelayCounters::BufferForcedProgress => "buffer.forced_progress",
+            RelayCounters::BufferStackQuarantined => "buffer.stack_quarantined",
+            RelayCounters::BufferClockBackwards => "buffer.clock_backwards",
+            RelayCounters::BufferSqliteBusyRetry => "buffer.sqlite_busy_retry",
+            RelayCounters::BufferSqliteMaintenanceReclaimed => {
+                "buffer.sqlite_maintenance.reclaimed"
+            }
+            RelayCounters::BufferSqliteBusyOverflow => "buffer.sqlite_busy_overflow",
+            RelayCounters::BufferSlowOperation => "buffer.slow_operation",
             RelayCounters::Outcomes => "events.outcomes",
             RelayCounters::ProjectStateRequest => "project_state.request",
             #[cfg(feature = "processing")]
@@ -916,6 +1086,12 @@ impl CounterMetric for RelayCounters {
             RelayCounters::ReplayExceededSegmentLimit => "replay.segment_limit_exceeded",
             RelayCounters::ServerSocketAccept => "server.http.accepted",
             RelayCounters::ServerConnectionIdleTimeout => "server.http.idle_timeout",
+            RelayCounters::UnrealReportFormat => "unreal.report_format",
+            RelayCounters::ForwardUpstreamRequest => "forward.upstream.request",
+            RelayCounters::StoreOversized => "store.oversized",
+            RelayCounters::OutcomesSpooled => "outcomes.spool.spooled",
+            RelayCounters::OutcomesSpoolDropped => "outcomes.spool.dropped",
+            RelayCounters::OutcomesRejected => "outcomes.rejected",
             #[cfg(feature = "processing")]
             RelayCounters::MetricDelaySum => "metrics.delay.sum",
             #[cfg(feature = "processing")]
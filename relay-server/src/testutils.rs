@@ -139,6 +139,7 @@ pub async fn create_test_processor(config: Config) -> EnvelopeProcessorService {
         processor::Addrs {
             outcome_aggregator,
             upstream_relay,
+            upstream_encodings: Default::default(),
             test_store,
             #[cfg(feature = "processing")]
             store_forwarder: None,
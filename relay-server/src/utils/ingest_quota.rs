@@ -0,0 +1,407 @@
+use std::collections::HashMap;
+use std::hash::BuildHasher;
+use std::sync::{Mutex, MutexGuard, PoisonError};
+use std::time::{Duration, Instant};
+
+use relay_base_schema::project::ProjectKey;
+use relay_config::KeyIngestQuota;
+
+/// Envelope counts of a public key in the current and the previous window.
+#[derive(Debug)]
+struct WindowCounter {
+    /// Start of the current window.
+    window_start: Instant,
+    /// Number of envelopes accepted in the previous window.
+    previous: u64,
+    /// Number of envelopes accepted in the current window.
+    current: u64,
+    /// Time at which the last envelope was counted.
+    last_used: Instant,
+}
+
+impl WindowCounter {
+    fn new(now: Instant) -> Self {
+        Self {
+            window_start: now,
+            previous: 0,
+            current: 0,
+            last_used: now,
+        }
+    }
+
+    /// Moves the counter to the window that contains `now`.
+    fn advance(&mut self, now: Instant, window: Duration) {
+        let elapsed = now.saturating_duration_since(self.window_start);
+        if elapsed < window {
+            return;
+        }
+
+        if elapsed < 2 * window {
+            self.previous = self.current;
+            self.window_start += window;
+        } else {
+            self.previous = 0;
+            self.window_start = now;
+        }
+        self.current = 0;
+    }
+
+    /// Approximates the number of envelopes within the sliding window ending at `now`.
+    ///
+    /// The previous window is weighted by the fraction by which it overlaps the sliding window.
+    fn estimate(&self, now: Instant, window: Duration) -> f64 {
+        let elapsed = now.saturating_duration_since(self.window_start);
+        let overlap = 1.0 - (elapsed.as_secs_f64() / window.as_secs_f64()).min(1.0);
+        self.previous as f64 * overlap + self.current as f64
+    }
+
+    /// Returns `true` if the counter has no effect on the sliding window ending at `now`.
+    fn is_expired(&self, now: Instant, window: Duration) -> bool {
+        now.saturating_duration_since(self.window_start) >= 2 * window
+    }
+
+    /// Counts an envelope if fewer than `max_envelopes` are within the sliding window.
+    ///
+    /// Returns the start of the window the envelope was counted in, or the time until the current
+    /// window ends if the quota is exhausted.
+    fn reserve(
+        &mut self,
+        now: Instant,
+        window: Duration,
+        max_envelopes: u64,
+    ) -> Result<Instant, Duration> {
+        self.advance(now, window);
+
+        if self.estimate(now, window) >= max_envelopes as f64 {
+            let window_end = self.window_start + window;
+            return Err(window_end.saturating_duration_since(now));
+        }
+
+        self.current += 1;
+        self.last_used = now;
+        Ok(self.window_start)
+    }
+
+    /// Removes an envelope counted by [`Self::reserve`] in the window starting at `window_start`.
+    ///
+    /// Envelopes of windows that no longer overlap the sliding window are not removed.
+    fn release(&mut self, window_start: Instant, window: Duration) {
+        if window_start == self.window_start {
+            self.current = self.current.saturating_sub(1);
+        } else if window_start + window == self.window_start {
+            self.previous = self.previous.saturating_sub(1);
+        }
+    }
+}
+
+/// Maximum number of shards of the tracked keys, each guarded by its own lock.
+const MAX_SHARDS: usize = 16;
+
+/// A subset of the tracked keys.
+#[derive(Debug, Default)]
+struct Shard {
+    counters: HashMap<ProjectKey, WindowCounter>,
+    /// Time after which expired counters are removed again, see [`Self::sweep`].
+    next_sweep: Option<Instant>,
+}
+
+impl Shard {
+    /// Returns the counter of the given key, creating it if necessary.
+    ///
+    /// If the shard already tracks `max_keys` keys, expired counters are removed first. If the
+    /// shard is still full, the counter of the least recently used key is evicted.
+    fn counter(
+        &mut self,
+        public_key: ProjectKey,
+        now: Instant,
+        window: Duration,
+        max_keys: usize,
+    ) -> &mut WindowCounter {
+        if !self.counters.contains_key(&public_key) && self.counters.len() >= max_keys {
+            self.sweep(now, window);
+            if self.counters.len() >= max_keys {
+                self.evict_least_recently_used();
+            }
+        }
+
+        self.counters
+            .entry(public_key)
+            .or_insert_with(|| WindowCounter::new(now))
+    }
+
+    /// Removes the counter of the key that counted an envelope least recently.
+    fn evict_least_recently_used(&mut self) {
+        let oldest = self
+            .counters
+            .iter()
+            .min_by_key(|(_, counter)| counter.last_used)
+            .map(|(public_key, _)| *public_key);
+
+        if let Some(public_key) = oldest {
+            self.counters.remove(&public_key);
+        }
+    }
+
+    /// Removes expired counters, at most once per window.
+    ///
+    /// This keeps the cost of a full shard constant for most checks, since sweeping is linear in
+    /// the number of tracked keys.
+    fn sweep(&mut self, now: Instant, window: Duration) {
+        if self.next_sweep.is_some_and(|next_sweep| now < next_sweep) {
+            return;
+        }
+
+        self.counters
+            .retain(|_, counter| !counter.is_expired(now, window));
+        self.next_sweep = Some(now + window);
+    }
+}
+
+/// Limits the number of envelopes accepted per public key over a rolling window.
+///
+/// The window is approximated by weighting the count of the previous fixed window, so only two
+/// counters are stored per key. Keys are spread across shards with separate locks, so concurrent
+/// requests of different keys rarely contend.
+///
+/// Envelopes are checked and counted at once with [`Self::reserve`], so that concurrent requests
+/// cannot exceed the quota together. Reservations of envelopes that are not accepted are released
+/// again, so rejected envelopes do not use up the quota. At most [`KeyIngestQuota::max_keys`] keys
+/// are tracked. If more keys are counted, the least recently
+/// used key is evicted and starts with a fresh quota when it is counted again.
+#[derive(Debug)]
+pub struct IngestQuotaLimiter {
+    quota: Option<KeyIngestQuota>,
+    hasher: ahash::RandomState,
+    shards: Box<[Mutex<Shard>]>,
+    /// Maximum number of keys tracked per shard.
+    keys_per_shard: usize,
+}
+
+impl IngestQuotaLimiter {
+    /// Creates a limiter for the given quota, which accepts everything if `None`.
+    pub fn new(quota: Option<KeyIngestQuota>) -> Self {
+        let max_keys = quota.as_ref().map_or(0, |quota| quota.max_keys);
+        let shard_count = max_keys.clamp(1, MAX_SHARDS);
+
+        Self {
+            quota,
+            hasher: ahash::RandomState::new(),
+            shards: (0..shard_count).map(|_| Mutex::default()).collect(),
+            keys_per_shard: max_keys.div_ceil(shard_count).max(1),
+        }
+    }
+
+    /// Counts another envelope of the given public key against the quota, if it is not exceeded.
+    ///
+    /// Returns the time after which the key should retry if the quota is exceeded. Otherwise, the
+    /// envelope is counted until the returned reservation is dropped without being committed.
+    pub fn reserve(&self, public_key: ProjectKey) -> Result<IngestQuotaReservation<'_>, Duration> {
+        self.reserve_at(public_key, Instant::now())
+    }
+
+    fn reserve_at(
+        &self,
+        public_key: ProjectKey,
+        now: Instant,
+    ) -> Result<IngestQuotaReservation<'_>, Duration> {
+        let Some(quota) = &self.quota else {
+            return Ok(IngestQuotaReservation {
+                limiter: self,
+                reserved: None,
+            });
+        };
+        let window = Duration::from_secs(quota.window_secs.max(1));
+
+        let window_start = lock(self.shard(public_key))
+            .counter(public_key, now, window, self.keys_per_shard)
+            .reserve(now, window, quota.max_envelopes)?;
+
+        Ok(IngestQuotaReservation {
+            limiter: self,
+            reserved: Some((public_key, window_start)),
+        })
+    }
+
+    fn release(&self, public_key: ProjectKey, window_start: Instant) {
+        let Some(quota) = &self.quota else {
+            return;
+        };
+        let window = Duration::from_secs(quota.window_secs.max(1));
+
+        if let Some(counter) = lock(self.shard(public_key)).counters.get_mut(&public_key) {
+            counter.release(window_start, window);
+        }
+    }
+
+    fn shard(&self, public_key: ProjectKey) -> &Mutex<Shard> {
+        let shard_index = self.hasher.hash_one(public_key) as usize % self.shards.len();
+        &self.shards[shard_index]
+    }
+}
+
+/// An envelope counted against the ingest quota by [`IngestQuotaLimiter::reserve`].
+///
+/// The envelope is removed from the quota again when the reservation is dropped, unless it has
+/// been committed with [`Self::commit`].
+#[derive(Debug)]
+#[must_use = "the reservation is released when dropped"]
+pub struct IngestQuotaReservation<'a> {
+    limiter: &'a IngestQuotaLimiter,
+    /// The key and the start of the window the envelope was counted in.
+    ///
+    /// This is `None` if there is no quota or the reservation has been committed.
+    reserved: Option<(ProjectKey, Instant)>,
+}
+
+impl IngestQuotaReservation<'_> {
+    /// Keeps the envelope counted against the quota once it has been accepted.
+    pub fn commit(mut self) {
+        self.reserved = None;
+    }
+}
+
+impl Drop for IngestQuotaReservation<'_> {
+    fn drop(&mut self) {
+        if let Some((public_key, window_start)) = self.reserved.take() {
+            self.limiter.release(public_key, window_start);
+        }
+    }
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limiter(max_envelopes: u64, max_keys: usize) -> IngestQuotaLimiter {
+        IngestQuotaLimiter::new(Some(KeyIngestQuota {
+            max_envelopes,
+            window_secs: 60,
+            max_keys,
+        }))
+    }
+
+    fn project_key(index: u32) -> ProjectKey {
+        ProjectKey::parse(&format!("{index:032x}")).unwrap()
+    }
+
+    /// Reserves an envelope and commits the reservation.
+    fn accept(
+        limiter: &IngestQuotaLimiter,
+        public_key: ProjectKey,
+        now: Instant,
+    ) -> Result<(), Duration> {
+        limiter.reserve_at(public_key, now)?.commit();
+        Ok(())
+    }
+
+    #[test]
+    fn test_under_quota_accepted() {
+        let limiter = limiter(3, 10);
+        let now = Instant::now();
+
+        for _ in 0..3 {
+            assert!(accept(&limiter, project_key(1), now).is_ok());
+        }
+        // Other keys have their own quota.
+        assert!(accept(&limiter, project_key(2), now).is_ok());
+    }
+
+    #[test]
+    fn test_over_quota_rejected_until_window_resets() {
+        let limiter = limiter(2, 10);
+        let now = Instant::now();
+
+        assert!(accept(&limiter, project_key(1), now).is_ok());
+        assert!(accept(&limiter, project_key(1), now).is_ok());
+        assert_eq!(
+            accept(&limiter, project_key(1), now + Duration::from_secs(10)),
+            Err(Duration::from_secs(50))
+        );
+
+        // Halfway into the next window, half of the previous window still counts.
+        let next = now + Duration::from_secs(90);
+        assert!(accept(&limiter, project_key(1), next).is_ok());
+        assert!(accept(&limiter, project_key(1), next).is_err());
+
+        // Once the previous window no longer overlaps, the full quota is available again.
+        let later = now + Duration::from_secs(180);
+        assert!(accept(&limiter, project_key(1), later).is_ok());
+        assert!(accept(&limiter, project_key(1), later).is_ok());
+        assert!(accept(&limiter, project_key(1), later).is_err());
+    }
+
+    #[test]
+    fn test_dropped_reservation_released() {
+        let limiter = limiter(1, 10);
+        let now = Instant::now();
+
+        // Envelopes that are rejected after the reservation do not use up the quota.
+        for _ in 0..3 {
+            drop(limiter.reserve_at(project_key(1), now).unwrap());
+        }
+
+        assert!(accept(&limiter, project_key(1), now).is_ok());
+        assert!(accept(&limiter, project_key(1), now).is_err());
+    }
+
+    #[test]
+    fn test_concurrent_reservations_limited() {
+        let limiter = limiter(2, 10);
+        let now = Instant::now();
+
+        // Pending reservations count against the quota before they are committed.
+        let first = limiter.reserve_at(project_key(1), now).unwrap();
+        let second = limiter.reserve_at(project_key(1), now).unwrap();
+        assert!(limiter.reserve_at(project_key(1), now).is_err());
+
+        first.commit();
+        drop(second);
+        assert!(accept(&limiter, project_key(1), now).is_ok());
+        assert!(accept(&limiter, project_key(1), now).is_err());
+    }
+
+    #[test]
+    fn test_release_after_window_moved() {
+        let limiter = limiter(1, 10);
+        let now = Instant::now();
+
+        let reservation = limiter.reserve_at(project_key(1), now).unwrap();
+        // Another envelope moves the counter into the next window before the release.
+        let next = now + Duration::from_secs(60);
+        assert!(limiter.reserve_at(project_key(1), next).is_err());
+        drop(reservation);
+
+        // The released envelope no longer counts in the previous window.
+        assert!(accept(&limiter, project_key(1), next).is_ok());
+    }
+
+    #[test]
+    fn test_least_recently_used_key_evicted() {
+        let limiter = limiter(1, 1);
+        let now = Instant::now();
+
+        assert!(accept(&limiter, project_key(1), now).is_ok());
+        assert!(accept(&limiter, project_key(1), now).is_err());
+
+        // A new key replaces the least recently used key instead of sharing its quota.
+        let next = now + Duration::from_secs(1);
+        assert!(accept(&limiter, project_key(2), next).is_ok());
+        assert!(accept(&limiter, project_key(2), next).is_err());
+        assert!(accept(&limiter, project_key(3), next).is_ok());
+
+        // The evicted key starts with a fresh quota.
+        assert!(accept(&limiter, project_key(1), next).is_ok());
+    }
+
+    #[test]
+    fn test_no_quota() {
+        let limiter = IngestQuotaLimiter::new(None);
+        for _ in 0..10 {
+            limiter.reserve(project_key(1)).unwrap().commit();
+        }
+    }
+}
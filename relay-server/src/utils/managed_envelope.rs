@@ -336,6 +336,24 @@ impl ManagedEnvelope {
         self.context.summary.event_category
     }
 
+    /// Rejects the envelope like [`Self::reject`], but returns the outcomes instead of sending
+    /// them to the outcome aggregator.
+    ///
+    /// This allows the caller to emit the outcomes through a different path, for example
+    /// synchronously.
+    pub fn reject_and_collect(&mut self, outcome: Outcome) -> Vec<TrackOutcome> {
+        let (collector, mut rx) = Addr::custom();
+        let outcome_aggregator = std::mem::replace(&mut self.outcome_aggregator, collector);
+        self.reject(outcome);
+        self.outcome_aggregator = outcome_aggregator;
+
+        let mut outcomes = Vec::new();
+        while let Ok(outcome) = rx.try_recv() {
+            outcomes.push(outcome);
+        }
+        outcomes
+    }
+
     /// Records rejection outcomes for all items stored in this context.
     ///
     /// This does not send outcomes for empty envelopes or request-only contexts.
@@ -593,4 +611,32 @@ mod tests {
 
         assert!(rx.blocking_recv().is_none());
     }
+
+    #[test]
+    fn reject_and_collect_returns_outcomes() {
+        let bytes =
+            Bytes::from(r#"{"dsn":"https://e12d836b15bb49d7bbf99e64295d995b:@sentry.io/42"}"#);
+        let envelope = Envelope::parse_bytes(bytes).unwrap();
+
+        let (test_store, _) = Addr::custom();
+        let (outcome_aggregator, mut rx) = Addr::custom();
+        let mut env = ManagedEnvelope::new(
+            envelope,
+            outcome_aggregator,
+            test_store,
+            ProcessingGroup::Ungrouped,
+        );
+        env.context.summary.span_quantity = 123;
+
+        let outcomes = env.reject_and_collect(Outcome::Invalid(DiscardReason::Internal));
+        drop(env);
+
+        let categories: Vec<_> = outcomes.iter().map(|o| o.category).collect();
+        assert_eq!(categories, [DataCategory::Span, DataCategory::SpanIndexed]);
+        assert!(outcomes.iter().all(|o| o.quantity == 123));
+
+        // Nothing is sent to the outcome aggregator, not even when dropping the envelope.
+        rx.close();
+        assert!(rx.blocking_recv().is_none());
+    }
 }
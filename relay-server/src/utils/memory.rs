@@ -55,6 +55,8 @@ struct Inner {
     reference_time: Instant,
     system: Mutex<System>,
     refresh_frequency_ms: u64,
+    /// Bytes currently reserved through [`MemoryChecker::reserve_overflow`].
+    overflow_bytes: AtomicU64,
 }
 
 /// Wrapper which hides the [`Arc`] and exposes utils method to make working with
@@ -74,6 +76,7 @@ impl MemoryStat {
             reference_time: Instant::now(),
             system: Mutex::new(system),
             refresh_frequency_ms,
+            overflow_bytes: AtomicU64::new(0),
         }))
     }
 
@@ -235,6 +238,49 @@ impl MemoryChecker {
 
         MemoryCheck::Exceeded(memory)
     }
+
+    /// Reserves memory for envelopes that a fallback keeps in memory instead of on disk.
+    ///
+    /// All clones of the underlying [`MemoryStat`] share the limit configured in
+    /// `spool.envelopes.memory_overflow_limit`. Returns `None` if the reservation would exceed
+    /// this limit or if the memory limits of Relay are exceeded. The memory is released when the
+    /// returned reservation is dropped.
+    pub fn reserve_overflow(&self, bytes: usize) -> Option<OverflowReservation> {
+        if self.check_memory().is_exceeded() {
+            return None;
+        }
+
+        let limit = self.config.spool_envelopes_memory_overflow_limit() as u64;
+        let bytes = bytes as u64;
+        self.memory_stat
+            .0
+            .overflow_bytes
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |reserved| {
+                reserved.checked_add(bytes).filter(|&total| total <= limit)
+            })
+            .ok()?;
+
+        Some(OverflowReservation {
+            memory_stat: self.memory_stat.clone(),
+            bytes,
+        })
+    }
+}
+
+/// Memory reserved by [`MemoryChecker::reserve_overflow`], which is released on drop.
+#[derive(Debug)]
+pub struct OverflowReservation {
+    memory_stat: MemoryStat,
+    bytes: u64,
+}
+
+impl Drop for OverflowReservation {
+    fn drop(&mut self) {
+        self.memory_stat
+            .0
+            .overflow_bytes
+            .fetch_sub(self.bytes, Ordering::Relaxed);
+    }
 }
 
 #[cfg(test)]
@@ -301,6 +347,46 @@ mod tests {
         assert!(memory_checker.check_memory().is_exceeded());
     }
 
+    #[test]
+    fn test_reserve_overflow() {
+        let config = Config::from_json_value(serde_json::json!({
+            "health": {
+                "max_memory_percent": 1.0
+            },
+            "spool": {
+                "envelopes": {
+                    "memory_overflow_limit": 100
+                }
+            }
+        }))
+        .unwrap();
+        let memory_checker = MemoryChecker::new(MemoryStat::default(), Arc::new(config));
+
+        let first = memory_checker.reserve_overflow(60).unwrap();
+        // Clones share the limit.
+        assert!(memory_checker.clone().reserve_overflow(50).is_none());
+        let second = memory_checker.reserve_overflow(40).unwrap();
+        assert!(memory_checker.reserve_overflow(1).is_none());
+
+        // Dropped reservations release their memory.
+        drop(first);
+        assert!(memory_checker.reserve_overflow(50).is_some());
+        drop(second);
+        assert!(memory_checker.reserve_overflow(100).is_some());
+    }
+
+    #[test]
+    fn test_overflow_rejected_when_memory_exceeded() {
+        let config = Config::from_json_value(serde_json::json!({
+            "health": {
+                "max_memory_percent": 0.0
+            }
+        }))
+        .unwrap();
+        let memory_checker = MemoryChecker::new(MemoryStat::default(), Arc::new(config));
+        assert!(memory_checker.reserve_overflow(1).is_none());
+    }
+
     #[test]
     fn test_last_update_is_updated() {
         let memory = MemoryStat::new(0);
@@ -1,5 +1,6 @@
 mod api;
 mod dynamic_sampling;
+mod ingest_quota;
 mod managed_envelope;
 mod multipart;
 mod param_parser;
@@ -22,6 +23,7 @@ mod unreal;
 
 pub use self::api::*;
 pub use self::dynamic_sampling::*;
+pub use self::ingest_quota::*;
 pub use self::managed_envelope::*;
 pub use self::memory::*;
 pub use self::multipart::*;
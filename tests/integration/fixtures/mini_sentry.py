@@ -426,7 +426,7 @@ def mini_sentry(request):  # noqa
         keys = {}
         relays = {}
         for id in ids:
-            relay = authenticated_relays[id]
+            relay = authenticated_relays.get(id)
             if relay:
                 keys[id] = relay["publicKey"]
                 relays[id] = relay
@@ -2,6 +2,7 @@ from unittest import mock
 import pytest
 import uuid
 import json
+import time
 
 from requests.exceptions import HTTPError
 from sentry_sdk.envelope import Envelope, Item, PayloadRef
@@ -146,6 +147,43 @@ def test_attachments_ratelimit(
     outcomes_consumer.assert_rate_limited("static_disabled_quota")
 
 
+def test_attachments_buffer_backpressure(mini_sentry, relay_with_processing):
+    """
+    Checks that only attachments are throttled once the buffer holds too many of them.
+    """
+    original_endpoint = mini_sentry.app.view_functions["get_project_config"]
+
+    @mini_sentry.app.endpoint("get_project_config")
+    def get_project_config():
+        # Withhold the global config, so that envelopes remain in the buffer.
+        res = original_endpoint().get_json()
+        res.pop("global")
+        return res
+
+    project_id = 42
+    mini_sentry.add_full_project_config(project_id)
+    relay = relay_with_processing(
+        options={"spool": {"envelopes": {"max_attachment_bytes": 10}}}
+    )
+
+    event_id = "515539018c9b4260a6f999572f1661ee"
+    attachments = [("att_1", "foo.txt", b"this is an attachment")]
+
+    try:
+        # The first attachment fills the budget of the buffer.
+        relay.send_attachments(project_id, event_id, attachments)
+        time.sleep(1)
+
+        with pytest.raises(HTTPError) as excinfo:
+            relay.send_attachments(project_id, event_id, attachments)
+        assert excinfo.value.response.status_code == 429
+
+        # Other ingest continues.
+        relay.send_event(project_id)
+    finally:
+        mini_sentry.clear_test_failures()
+
+
 def test_attachments_pii(mini_sentry, relay):
     event_id = "515539018c9b4260a6f999572f1661ee"
 
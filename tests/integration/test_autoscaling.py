@@ -26,6 +26,21 @@ def test_basic_autoscaling_endpoint(mini_sentry, relay):
     assert int(parsed["relay_up"]) == 1
 
 
+def test_buffer_metrics_endpoint_disabled_by_default(mini_sentry, relay):
+    relay = relay(mini_sentry)
+    response = relay.get("/api/relay/metrics/")
+    assert response.status_code == 404
+    assert response.text == ""
+
+
+def test_buffer_metrics_endpoint(mini_sentry, relay):
+    relay = relay(mini_sentry, {"metrics": {"prometheus_buffer_endpoint": True}})
+    response = relay.get("/api/relay/metrics/")
+    assert response.status_code == 200
+    parsed = parse_prometheus(response.text)
+    assert int(parsed['relay_buffer_envelope_count{partition_id="0"}']) == 0
+
+
 def test_sqlite_spooling_metrics(mini_sentry, relay):
     # Create a temporary directory for the sqlite db
     db_file_path = os.path.join(tempfile.mkdtemp(), "database.db")
@@ -377,3 +377,43 @@ def test_root_project_same(mini_sentry, relay):
     same_dsn = mini_sentry.get_dsn_public_key(project_id)
     txn = send_transaction_with_dsc(mini_sentry, relay, project_id, same_dsn)
     assert txn["contexts"]["trace"]["client_sample_rate"] == 0.5
+
+
+def test_validate_valid_key(mini_sentry, relay):
+    project_id = 42
+    mini_sentry.add_basic_project_config(project_id)
+    relay = relay(mini_sentry)
+    public_key = mini_sentry.get_dsn_public_key(project_id)
+
+    response = relay.get(f"/api/{project_id}/validate/?sentry_key={public_key}")
+    assert response.status_code == 200
+    assert response.json() == {
+        "publicKey": public_key,
+        "projectId": project_id,
+        "state": "enabled",
+    }
+
+    # Validating does not ingest anything.
+    assert mini_sentry.captured_events.empty()
+
+
+def test_validate_malformed_key(mini_sentry, relay):
+    project_id = 42
+    mini_sentry.add_basic_project_config(project_id)
+    relay = relay(mini_sentry)
+
+    response = relay.get(f"/api/{project_id}/validate/?sentry_key=not-a-key")
+    assert response.status_code == 400
+
+    response = relay.get(f"/api/{project_id}/validate/")
+    assert response.status_code == 401
+
+
+def test_validate_mismatched_project_id(mini_sentry, relay):
+    project_id = 42
+    mini_sentry.add_basic_project_config(project_id)
+    relay = relay(mini_sentry)
+    public_key = mini_sentry.get_dsn_public_key(project_id)
+
+    response = relay.get(f"/api/{project_id + 1}/validate/?sentry_key={public_key}")
+    assert response.status_code == 403
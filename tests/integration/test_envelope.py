@@ -56,6 +56,34 @@ Hello World
     assert mini_sentry.captured_events.empty()
 
 
+@pytest.mark.parametrize("debug_partition_header", [False, True])
+def test_envelope_partition_header(mini_sentry, relay, debug_partition_header):
+    relay = relay(
+        mini_sentry,
+        options={
+            "spool": {
+                "envelopes": {
+                    "partitions": 4,
+                    "debug_partition_header": debug_partition_header,
+                }
+            }
+        },
+    )
+    project_id = 42
+    mini_sentry.add_basic_project_config(project_id)
+
+    envelope = Envelope()
+    envelope.add_event({"message": "Hello, World!"})
+    response = relay.send_envelope(project_id, envelope)
+
+    if debug_partition_header:
+        assert 0 <= int(response.headers["x-relay-partition"]) < 4
+    else:
+        assert "x-relay-partition" not in response.headers
+
+    assert mini_sentry.captured_events.get(timeout=1)
+
+
 def test_envelope_empty(mini_sentry, relay):
     relay = relay(mini_sentry)
     PROJECT_ID = 42
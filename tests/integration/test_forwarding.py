@@ -1,5 +1,6 @@
 import errno
 import gzip
+import socket
 import time
 
 import pytest
@@ -116,3 +117,26 @@ def test_timeouts(mini_sentry, relay):
 
     response = relay.get("/api/test/timeout")
     assert response.status_code == 504
+
+
+def test_forwarding_failover(mini_sentry, relay):
+    mini_sentry.fail_on_relay_error = False
+
+    @mini_sentry.app.route("/api/test/failover")
+    def hi():
+        return "ok"
+
+    # Reserve a port without listening on it, so that connections to the primary fail.
+    with socket.socket() as sock:
+        sock.bind(("127.0.0.1", 0))
+        primary = "http://127.0.0.1:{}/".format(sock.getsockname()[1])
+
+    relay = relay(
+        mini_sentry,
+        options={"forwarding": {"upstreams": [primary, mini_sentry.url]}},
+    )
+
+    for _ in range(2):
+        response = relay.get("/api/test/failover")
+        assert response.status_code == 200
+        assert response.text == "ok"
@@ -290,3 +290,70 @@ def test_ourlog_extraction_is_disabled_without_feature(
 
     assert len(ourlogs) == 0
     ourlogs_consumer.assert_empty()
+
+
+def test_ourlog_extraction_with_log_endpoint(
+    mini_sentry,
+    relay_with_processing,
+    ourlogs_consumer,
+):
+    ourlogs_consumer = ourlogs_consumer()
+    project_id = 42
+    project_config = mini_sentry.add_full_project_config(project_id)
+    project_config["config"]["features"] = [
+        "organizations:ourlogs-ingestion",
+    ]
+
+    relay = relay_with_processing(options=TEST_CONFIG)
+
+    start = datetime.now(timezone.utc)
+    dsn_key = relay.get_dsn_public_key(project_id)
+
+    response = relay.post(
+        f"/api/{project_id}/log/?sentry_key={dsn_key}",
+        json=[
+            {
+                "timestamp": start.timestamp(),
+                "trace_id": "5b8efff798038103d269b633813fc60c",
+                "span_id": "eee19b7ec3c1b174",
+                "level": "info",
+                "body": "Example log record",
+            },
+            {
+                "timestamp": start.timestamp(),
+                "body": "Log record without trace",
+            },
+        ],
+    )
+
+    assert response.status_code == 200
+    assert response.json() == {
+        "accepted": 1,
+        "rejected": [{"index": 1, "reason": "missing or invalid trace_id"}],
+    }
+
+    ourlogs = ourlogs_consumer.get_ourlogs()
+    assert len(ourlogs) == 1
+    assert ourlogs[0]["body"] == "Example log record"
+    assert ourlogs[0]["trace_id"] == "5b8efff798038103d269b633813fc60c"
+
+    ourlogs_consumer.assert_empty()
+
+
+def test_log_endpoint_rejects_invalid_batch(mini_sentry, relay):
+    project_id = 42
+    mini_sentry.add_full_project_config(project_id)
+    relay = relay(mini_sentry)
+    dsn_key = relay.get_dsn_public_key(project_id)
+
+    response = relay.post(
+        f"/api/{project_id}/log/?sentry_key={dsn_key}",
+        json=[{"body": "incomplete"}],
+    )
+
+    assert response.status_code == 400
+    assert response.json() == {
+        "accepted": 0,
+        "rejected": [{"index": 0, "reason": "missing or invalid timestamp"}],
+    }
+    assert mini_sentry.captured_events.empty()
@@ -325,6 +325,104 @@ def test_outcomes_not_sent_when_disabled(relay, mini_sentry):
         pass  # we do expect not to get anything since we have outcomes disabled
 
 
+def test_outcomes_synchronous_on_reject(relay, mini_sentry, tmp_path):
+    """
+    Test that outcomes are emitted for envelopes rejected due to a full buffer.
+
+    Fill up the disk buffer and verify that the rejected envelope produces an outcome
+    when outcomes are emitted synchronously on rejection.
+    """
+    mini_sentry.fail_on_relay_error = False
+
+    project_id = 42
+    mini_sentry.add_full_project_config(project_id)
+    # Set a broken config, so the envelopes stay in the buffer.
+    mini_sentry.project_configs[project_id]["config"]["quotas"] = None
+
+    config = {
+        "outcomes": {
+            "emit_outcomes": True,
+            "batch_size": 1,
+            "batch_interval": 1,
+            "synchronous_on_reject": True,
+        },
+        "spool": {
+            "envelopes": {
+                "path": str(tmp_path / "buffer.db"),
+                "max_disk_size": 24577,  # one more than the initial size
+                "batch_size_bytes": 1,
+            }
+        },
+    }
+
+    relay = relay(mini_sentry, config)
+
+    for _ in range(100):
+        try:
+            relay.send_event(project_id)
+        except HTTPError as e:
+            assert e.response.status_code == 503
+            break
+    else:
+        assert False, "the buffer never rejected an envelope"
+
+    outcomes = mini_sentry.captured_outcomes.get(timeout=3)["outcomes"]
+    assert len(outcomes) == 1
+    outcome = outcomes[0]
+    assert outcome["project_id"] == project_id
+    assert outcome["outcome"] == 3  # invalid
+    assert outcome["reason"] == "internal"
+    assert outcome["category"] == DataCategory.ERROR
+    assert outcome["quantity"] == 1
+
+
+def test_outcomes_spooled_during_upstream_outage(relay, mini_sentry, tmp_path):
+    """
+    Test that outcomes which fail to send are spooled and sent once the upstream recovers.
+    """
+    mini_sentry.fail_on_relay_error = False
+
+    original_endpoint = mini_sentry.app.view_functions["outcomes"]
+    outage = True
+
+    @mini_sentry.app.endpoint("outcomes")
+    def outcomes():
+        if outage:
+            return "", 500
+        return original_endpoint()
+
+    config = {
+        "outcomes": {
+            "emit_outcomes": True,
+            "batch_size": 1,
+            "batch_interval": 1,
+            "spool": True,
+        },
+        "spool": {"envelopes": {"path": str(tmp_path / "buffer.db")}},
+    }
+
+    relay = relay(mini_sentry, config)
+
+    _send_event(relay)
+    time.sleep(1)
+    assert mini_sentry.captured_outcomes.empty()
+
+    # The next successful request also sends the spooled outcome.
+    outage = False
+    _send_event(relay)
+
+    outcomes = []
+    while len(outcomes) < 2:
+        outcomes.extend(mini_sentry.captured_outcomes.get(timeout=3)["outcomes"])
+
+    assert len(outcomes) == 2
+    for outcome in outcomes:
+        assert outcome["outcome"] == 3  # invalid
+        assert outcome["reason"] == "project_id"
+
+    assert mini_sentry.captured_outcomes.empty()
+
+
 def test_outcomes_non_processing_max_batch_time(relay, mini_sentry):
     """
     Test that outcomes are not batched more than max specified time.
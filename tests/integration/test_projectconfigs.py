@@ -155,25 +155,49 @@ def test_invalid_signature(mini_sentry, relay):
     assert "signature" in response.text
 
 
-def test_broken_projectkey(mini_sentry, relay):
+@pytest.mark.parametrize(
+    "broken_key",
+    [
+        "deadbeef",  # wrong length
+        42,  # wrong type
+        "/?$äß000000000000000000000000000",  # invalid characters
+    ],
+)
+def test_broken_projectkey(mini_sentry, relay, broken_key):
     relay = relay(mini_sentry)
     mini_sentry.add_basic_project_config(42)
     public_key = mini_sentry.get_dsn_public_key(42)
 
-    body = {
-        "publicKeys": [
-            public_key,  # valid
-            "deadbeef",  # wrong length
-            42,  # wrong type
-            "/?$äß000000000000000000000000000",  # invalid characters
-        ]
-    }
+    body = {"publicKeys": [public_key, broken_key]}
     packed, signature = SecretKey.parse(relay.secret_key).pack(body)
 
+    # Before version 4, broken keys are skipped.
     data, _ = get_response(relay, packed, signature)
+    assert public_key in data["configs"]
+
+    response = request_config(relay, packed, signature, version="4")
+
+    assert response.status_code == 400  # Bad Request
+    assert "publicKeys[1]" in response.text
 
+
+def test_unknown_field(mini_sentry, relay):
+    relay = relay(mini_sentry)
+    mini_sentry.add_basic_project_config(42)
+    public_key = mini_sentry.get_dsn_public_key(42)
+
+    body = {"publicKeys": [public_key], "fullConfigs": True, "noCache": False}
+    packed, signature = SecretKey.parse(relay.secret_key).pack(body)
+
+    # Before version 4, unknown fields are ignored.
+    data, _ = get_response(relay, packed, signature)
     assert public_key in data["configs"]
 
+    response = request_config(relay, packed, signature, version="4")
+
+    assert response.status_code == 400  # Bad Request
+    assert "fullConfigs" in response.text
+
 
 def test_pending_projects(mini_sentry, relay):
     # V3 requests will never return a projectconfig on the first request, only some
@@ -385,3 +409,32 @@ def test_unchanged_projects(mini_sentry, relay):
     assert public_key in data["unchanged"]
     assert public_key not in data["configs"]
     assert data.get("pending") is None
+
+
+def test_project_refetch(mini_sentry, relay):
+    relay = relay(mini_sentry, {"cache": {"project_expiry": 3600}})
+    mini_sentry.add_basic_project_config(42)
+    public_key = mini_sentry.get_dsn_public_key(42)
+
+    # Once the event is sent the project state is requested and cached.
+    relay.send_event(42)
+    mini_sentry.captured_events.get(timeout=1)
+
+    # Disable the project upstream, the cached state is still fresh.
+    mini_sentry.project_configs[42]["disabled"] = True
+
+    packed, signature = SecretKey.parse(relay.secret_key).pack({})
+    headers = {
+        "X-Sentry-Relay-Id": relay.relay_id,
+        "X-Sentry-Relay-Signature": signature,
+    }
+    path = f"/api/relay/projects/{public_key}/refetch/"
+
+    response = relay.post(path, data=packed, headers=headers)
+    assert response.ok
+    assert response.json() == {"refetched": True, "ready": True, "state": "disabled"}
+
+    # Repeated refetches of the same project are debounced.
+    response = relay.post(path, data=packed, headers=headers)
+    assert response.ok
+    assert response.json() == {"refetched": False, "ready": True, "state": "disabled"}
@@ -98,3 +98,53 @@ def test_public_keys(mini_sentry, relay, caller, relays_to_fetch):
         }
     }
     assert resp.json() == expected
+
+
+def _post_public_keys(relay, caller, request):
+    packed, signature = caller.secret_key.pack(request)
+    return relay.post(
+        "/api/0/relays/publickeys/",
+        data=packed,
+        headers={"X-Sentry-Relay-Id": caller.id, "X-Sentry-Relay-Signature": signature},
+    )
+
+
+@pytest.fixture
+def static_caller():
+    sk, pk = generate_key_pair()
+    return RelayInfo(id=str(uuid.uuid4()), public_key=pk, secret_key=sk, internal=True)
+
+
+def test_public_keys_unknown_relays(mini_sentry, relay, static_caller):
+    static_relays = {static_caller.id: {"public_key": str(static_caller.public_key)}}
+    relay1 = relay(mini_sentry, static_relays=static_relays)
+
+    unknown_id = str(uuid.uuid4())
+    resp = _post_public_keys(relay1, static_caller, {"relay_ids": [unknown_id]})
+
+    # Older relays expect a successful response even if no relay is known.
+    assert resp.status_code == 200
+    assert resp.json()["relays"] == {unknown_id: None}
+
+    resp = _post_public_keys(
+        relay1, static_caller, {"relay_ids": [unknown_id, static_caller.id]}
+    )
+
+    assert resp.ok
+    assert resp.json()["relays"][unknown_id] is None
+    assert resp.json()["relays"][static_caller.id] is not None
+
+
+@pytest.mark.parametrize(
+    "request_body",
+    [{"relay_ids": []}, {"relay_ids": "invalid"}],
+    ids=["empty", "invalid"],
+)
+def test_public_keys_malformed_request(mini_sentry, relay, static_caller, request_body):
+    static_relays = {static_caller.id: {"public_key": str(static_caller.public_key)}}
+    relay1 = relay(mini_sentry, static_relays=static_relays)
+
+    resp = _post_public_keys(relay1, static_caller, request_body)
+
+    assert resp.status_code == 400
+    assert resp.json()["detail"].startswith("malformed request")
@@ -328,6 +328,56 @@ def test_security_report_preflight(mini_sentry, relay):
     }
 
 
+def test_preflight_per_route_group(mini_sentry, relay):
+    """
+    Test that preflight requests reflect the CORS policy of their endpoint group
+    """
+    relay = relay(
+        mini_sentry,
+        options={
+            "cors": {
+                "security": {
+                    "allowed_origins": ["https://reports.example.com"],
+                    "allowed_methods": ["POST"],
+                },
+                "envelope": {
+                    "allowed_origins": ["https://app.example.com"],
+                    "allowed_methods": ["POST", "PUT"],
+                },
+            }
+        },
+    )
+
+    def preflight(path, origin):
+        headers = {
+            "Origin": origin,
+            "Access-Control-Request-Method": "POST",
+            "Access-Control-Request-Headers": "content-type",
+        }
+        return relay.req_options(f"/api/42/{path}/", headers=headers)
+
+    resp = preflight("security", "https://reports.example.com")
+    assert resp.status_code == 200
+    assert resp.headers["access-control-allow-origin"] == "https://reports.example.com"
+    assert resp.headers["access-control-allow-methods"] == "POST"
+
+    resp = preflight("envelope", "https://app.example.com")
+    assert resp.status_code == 200
+    assert resp.headers["access-control-allow-origin"] == "https://app.example.com"
+    assert set(split_header(resp.headers["access-control-allow-methods"])) == {
+        "POST",
+        "PUT",
+    }
+
+    # Origins of one group are not allowed by another group.
+    resp = preflight("envelope", "https://reports.example.com")
+    assert "access-control-allow-origin" not in resp.headers
+
+    # Groups without a policy keep allowing any origin.
+    resp = preflight("store", "https://reports.example.com")
+    assert resp.headers["access-control-allow-origin"] == "*"
+
+
 def test_security_report_expose_headers(mini_sentry, relay):
     relay = relay(mini_sentry)
     mini_sentry.add_full_project_config(42)
@@ -2,11 +2,13 @@ import json
 import os
 import queue
 import socket
+import tempfile
 import threading
 import uuid
 from datetime import UTC, datetime, timedelta, timezone
 from time import sleep
 
+from sentry_relay.auth import SecretKey
 from sentry_relay.consts import DataCategory
 
 from .asserts import time_within_delta
@@ -1316,6 +1318,101 @@ def test_buffer_events_during_outage(relay, mini_sentry):
     assert event["logentry"] == {"formatted": "123"}
 
 
+def test_spool_drain(mini_sentry, relay, relay_credentials):
+    project_id = 42
+    mini_sentry.add_basic_project_config(project_id)
+    # Keep the project pending, so that its envelopes remain in the buffer.
+    mini_sentry.project_config_simulate_pending = True
+
+    credentials = relay_credentials()
+    relay = relay(
+        mini_sentry,
+        {
+            "spool": {
+                "envelopes": {"path": os.path.join(tempfile.mkdtemp(), "buffer.db")}
+            },
+        },
+        static_relays={
+            credentials["id"]: {
+                "public_key": credentials["public_key"],
+                "internal": True,
+            },
+        },
+    )
+
+    for i in range(3):
+        relay.send_event(project_id, {"message": f"drain {i}"})
+
+    def post(path, body=b""):
+        signature = SecretKey.parse(credentials["secret_key"]).sign(body)
+        response = relay.post(
+            path,
+            data=body,
+            headers={
+                "X-Sentry-Relay-Id": credentials["id"],
+                "X-Sentry-Relay-Signature": signature,
+            },
+        )
+        response.raise_for_status()
+        return response
+
+    def drain(count, visibility_timeout=60):
+        response = post(
+            f"/api/relay/spool/drain/?count={count}"
+            f"&visibility_timeout={visibility_timeout}"
+        )
+
+        drained = []
+        data = response.content
+        while data:
+            partition_id = data[0]
+            token = int.from_bytes(data[1:9], "big", signed=True)
+            length = int.from_bytes(data[9:13], "big")
+            envelope = Envelope.deserialize(data[13 : 13 + length])
+            drained.append(({"partition_id": partition_id, "token": token}, envelope))
+            data = data[13 + length :]
+        return drained
+
+    def ack(drained):
+        body = json.dumps({"acks": [ack for ack, _ in drained]}).encode()
+        return post("/api/relay/spool/ack/", body).json()["acked"]
+
+    # Envelopes that are not acknowledged in time are delivered again.
+    assert len(drain(2, visibility_timeout=0)) == 2
+
+    drained = drain(10)
+    assert len(drained) == 3
+    assert drain(10) == []
+    assert ack(drained) == 3
+    assert drain(10, visibility_timeout=0) == []
+
+    messages = {envelope.get_event()["message"] for _, envelope in drained}
+    assert messages == {"drain 0", "drain 1", "drain 2"}
+
+
+def test_spool_drain_requires_internal_relay(mini_sentry, relay, relay_credentials):
+    credentials = relay_credentials()
+    relay = relay(
+        mini_sentry,
+        static_relays={
+            credentials["id"]: {
+                "public_key": credentials["public_key"],
+                "internal": False,
+            },
+        },
+    )
+
+    signature = SecretKey.parse(credentials["secret_key"]).sign(b"")
+    response = relay.post(
+        "/api/relay/spool/drain/",
+        headers={
+            "X-Sentry-Relay-Id": credentials["id"],
+            "X-Sentry-Relay-Signature": signature,
+        },
+    )
+    assert response.status_code == 403
+
+
 def test_store_content_encodings(mini_sentry, relay):
     relay = relay(mini_sentry)
     project_id = 42
@@ -1493,3 +1590,32 @@ def test_error_with_type_transaction_fixed_by_inference_even_if_only_feature_fla
     ingested, _ = events_consumer.get_event(timeout=7)
     assert ingested["type"] == "error"
     events_consumer.assert_empty()
+
+
+def test_store_oversized_outcome(mini_sentry, relay):
+    project_id = 42
+    mini_sentry.add_basic_project_config(project_id)
+    relay = relay(
+        mini_sentry,
+        {
+            "limits": {"max_event_size": "1KB"},
+            "outcomes": {"emit_outcomes": True, "batch_size": 1, "batch_interval": 1},
+        },
+    )
+
+    with pytest.raises(HTTPError) as excinfo:
+        relay.send_event(project_id, {"message": "x" * 2048})
+    assert excinfo.value.response.status_code == 413
+
+    outcomes = mini_sentry.captured_outcomes.get(timeout=3)["outcomes"]
+    assert outcomes == [
+        {
+            "project_id": project_id,
+            "outcome": 3,  # invalid
+            "reason": "too_large:event",
+            "category": DataCategory.ERROR,
+            "quantity": 1,
+            "timestamp": time_within_delta(),
+        }
+    ]
+    assert mini_sentry.captured_events.empty()
@@ -1,6 +1,10 @@
+import gzip
 import os
 import pytest
 import json
+import zlib
+
+from requests.exceptions import HTTPError
 from .consts import TRANSACTION_EXTRACT_MAX_SUPPORTED_VERSION
 
 
@@ -45,6 +49,45 @@ def test_unreal_crash(mini_sentry, relay, dump_file_name, extract_metrics):
     assert unreal_item.payload is not None
 
 
+@pytest.mark.parametrize("payload_format", ["zlib", "gzip", "uncompressed"])
+def test_unreal_crash_formats(mini_sentry, relay, payload_format):
+    project_id = 42
+    relay = relay(mini_sentry)
+    mini_sentry.add_full_project_config(project_id)
+
+    unreal_content = load_dump_file("unreal_crash")
+    if payload_format == "gzip":
+        unreal_content = gzip.compress(zlib.decompress(unreal_content))
+    elif payload_format == "uncompressed":
+        unreal_content = zlib.decompress(unreal_content)
+
+    relay.send_unreal_request(project_id, unreal_content)
+
+    envelope = mini_sentry.captured_events.get(timeout=1)
+    unreal_item = envelope.items[0]
+    assert unreal_item.headers.get("type") == "unreal_report"
+    # All formats are forwarded as zlib compressed crash context.
+    assert zlib.decompress(unreal_item.payload.bytes) == zlib.decompress(
+        load_dump_file("unreal_crash")
+    )
+
+
+def test_unreal_crash_unknown_format(mini_sentry, relay):
+    project_id = 42
+    relay = relay(mini_sentry)
+    mini_sentry.add_full_project_config(project_id)
+
+    with pytest.raises(HTTPError) as excinfo:
+        relay.send_unreal_request(project_id, b"MDMP not an unreal crash report")
+
+    response = excinfo.value.response
+    assert response.status_code == 400
+    assert (
+        response.json()["detail"] == "invalid unreal crash report: unrecognized format"
+    )
+    assert mini_sentry.captured_events.empty()
+
+
 def test_unreal_minidump_with_processing(
     mini_sentry, relay_with_processing, attachments_consumer
 ):